@@ -0,0 +1,11 @@
+#!/bin/sh
+# Build, lint and test every supported feature combination
+set -eu
+
+cargo build --workspace
+cargo clippy --workspace --all-targets -- -D warnings
+cargo test --workspace
+
+# lyso-fastq without nom, using the handwritten parser
+cargo clippy -p lyso-fastq --all-targets --no-default-features -- -D warnings
+cargo test -p lyso-fastq --no-default-features
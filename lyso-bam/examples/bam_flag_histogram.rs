@@ -0,0 +1,28 @@
+//! Count the alignments of a BAM file by FLAG
+//!
+//! Usage: bam_flag_histogram <in.bam>
+//!
+//! Prints one `flag<TAB>hex<TAB>count` line per distinct FLAG, in FLAG order.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::process::exit;
+
+use lyso_bam::flags::Flags;
+use lyso_bam::reader::BamReader;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.len() != 2 {
+        eprintln!("usage: bam_flag_histogram <in.bam>");
+        exit(2);
+    }
+    let mut counts = BTreeMap::<u16, u64>::new();
+    for rec in BamReader::from_path(&args[1])? {
+        *counts.entry(rec?.flag()).or_default() += 1;
+    }
+    for (flag, count) in counts {
+        println!("{flag}\t{}\t{count}", Flags(flag));
+    }
+    Ok(())
+}
@@ -0,0 +1,309 @@
+//! A BGZF reader implementing `VirtualSeek`, and a writer compressing on several threads
+//!
+//! bgzip's `BGZFReader::bgzf_pos` leaves the first block's header out of its
+//! offsets, so every block after the first is reported 18 bytes early. Blocks
+//! are read here with bgzip's own `load_block` and `decompress_block`, keeping
+//! count of the compressed offset of each. `BgzfWriter` compresses with bgzip's
+//! `write_block`, one block per job of a `WorkerPool`.
+
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+
+use bgzip::deflate::{Compress, Decompress};
+use bgzip::read::{decompress_block, load_block};
+use bgzip::write::write_block;
+use bgzip::{BGZFError, Compression, EOF_MARKER};
+pub use lyso_common::bgzf::{VirtualOffset, VirtualSeek};
+use lyso_common::pool::WorkerPool;
+
+/// Uncompressed bytes per block, as htslib writes them
+const BLOCK_INPUT: usize = 0xff00;
+
+/// Blocks gathered per thread before compressing
+const BLOCKS_PER_THREAD: usize = 4;
+
+/// Decompresses a BGZF stream block by block, tracking virtual offsets
+pub struct VirtualReader<R: Read> {
+    inner: R,
+    decompress: Decompress,
+    compressed: Vec<u8>,
+    block: Vec<u8>,
+    /// Compressed offset of `block`
+    block_offset: u64,
+    /// Compressed offset of the block after it
+    next_offset: u64,
+    /// Position within `block`
+    pos: usize,
+    /// `inner` ended at a block boundary
+    eof: bool,
+}
+
+impl<R: Read> VirtualReader<R> {
+    /// Start reading at the current position of `inner`, which must be a block start
+    ///
+    /// Offsets count from there, so `inner` is normally at the start of the file.
+    pub fn new(inner: R) -> Result<Self, BGZFError> {
+        let mut reader = VirtualReader {
+            inner,
+            decompress: Decompress::new(),
+            compressed: Vec::new(),
+            block: Vec::new(),
+            block_offset: 0,
+            next_offset: 0,
+            pos: 0,
+            eof: false,
+        };
+        reader.load_next()?;
+        Ok(reader)
+    }
+
+    /// Replace the current block with the next one, or mark the end of the input
+    fn load_next(&mut self) -> Result<(), BGZFError> {
+        self.block.clear();
+        self.pos = 0;
+        self.block_offset = self.next_offset;
+        let mut first = [0];
+        if self.inner.read(&mut first)? == 0 {
+            self.eof = true;
+            return Ok(());
+        }
+        let header = load_block((&first[..]).chain(&mut self.inner), &mut self.compressed)?;
+        decompress_block(&mut self.block, &self.compressed, &mut self.decompress)?;
+        self.next_offset += u64::from(header.block_size()?);
+        Ok(())
+    }
+
+    /// The underlying reader, positioned after the current block
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> VirtualSeek for VirtualReader<R> {
+    fn seek_virtual(&mut self, pos: VirtualOffset) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(pos.coffset()))?;
+        self.next_offset = pos.coffset();
+        self.eof = false;
+        self.load_next().map_err(BGZFError::into_io_error)?;
+        if usize::from(pos.uoffset()) > self.block.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("virtual offset {pos} is past the end of its block"),
+            ));
+        }
+        self.pos = usize::from(pos.uoffset());
+        Ok(())
+    }
+
+    fn virtual_position(&self) -> VirtualOffset {
+        // a block holds at most 64KB, and no file has 2^48 bytes
+        VirtualOffset::from_raw(self.block_offset << 16 | self.pos as u64)
+    }
+}
+
+impl<R: Read> BufRead for VirtualReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // empty blocks, such as the EOF marker, are passed over
+        while self.pos >= self.block.len() && !self.eof {
+            self.load_next().map_err(BGZFError::into_io_error)?;
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.block.len());
+    }
+}
+
+impl<R: Read> Read for VirtualReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = buf.len().min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+/// Compresses to BGZF on the threads of a `WorkerPool`, writing blocks in order
+///
+/// Input is gathered until there is a full block for each job of a batch, so
+/// `flush` writes a short block for what is left. `finish` (or dropping the
+/// writer, which ignores errors) writes the rest and the EOF marker.
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use lyso_bam::bgzf::{BgzfWriter, VirtualReader};
+///
+/// let mut writer = BgzfWriter::new(Vec::new());
+/// writer.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+/// let compressed = writer.finish().unwrap();
+///
+/// let mut text = String::new();
+/// VirtualReader::new(&compressed[..]).unwrap().read_to_string(&mut text).unwrap();
+/// assert_eq!(text, "@r1\nACGT\n+\nIIII\n");
+/// ```
+pub struct BgzfWriter<W: Write> {
+    /// None once finished
+    inner: Option<W>,
+    pool: WorkerPool,
+    level: Compression,
+    /// Input not yet compressed
+    pending: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// A writer compressing on one thread at the default level
+    pub fn new(inner: W) -> Self {
+        Self::with_pool(inner, WorkerPool::default())
+    }
+
+    /// A writer compressing on the threads of `pool`
+    pub fn with_pool(inner: W, pool: WorkerPool) -> Self {
+        BgzfWriter {
+            inner: Some(inner),
+            pool,
+            level: Compression::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Compression level, see `bgzip::Compression`
+    #[must_use]
+    pub fn level(mut self, level: Compression) -> Self {
+        self.level = level;
+        self
+    }
+
+    fn batch_bytes(&self) -> usize {
+        BLOCK_INPUT * BLOCKS_PER_THREAD * self.pool.threads().get()
+    }
+
+    /// Compress and write the pending input, all of it or only full blocks
+    fn write_blocks(&mut self, all: bool) -> io::Result<()> {
+        let full = self.pending.len() - self.pending.len() % BLOCK_INPUT;
+        let end = if all { self.pending.len() } else { full };
+        let Some(inner) = self.inner.as_mut() else {
+            return Ok(());
+        };
+        let level = self.level;
+        let compress = |block: &[u8]| {
+            let mut out = Vec::with_capacity(block.len() / 2);
+            write_block(&mut out, block, &mut Compress::new(level)).map(|_| out)
+        };
+        let mut written = Ok(());
+        let result =
+            self.pool
+                .map_ordered(self.pending[..end].chunks(BLOCK_INPUT), compress, |out| {
+                    written = inner.write_all(&out);
+                    match written {
+                        Ok(()) => ControlFlow::Continue(()),
+                        Err(_) => ControlFlow::Break(()),
+                    }
+                });
+        written?;
+        result.map_err(io::Error::other)?;
+        self.pending.drain(..end);
+        Ok(())
+    }
+
+    /// Write the pending input and the EOF marker, and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.close()?;
+        Ok(self.inner.take().expect("finished twice"))
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.write_blocks(true)?;
+        if let Some(inner) = self.inner.as_mut() {
+            inner.write_all(&EOF_MARKER)?;
+            inner.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= self.batch_bytes() {
+            self.write_blocks(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_blocks(true)?;
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroUsize;
+
+    fn decompress(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        VirtualReader::new(bytes)
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = (0..200_000u32)
+            .map(|i| format!("line {i}\n"))
+            .collect::<String>();
+        for threads in [1, 4] {
+            let pool = WorkerPool::new(NonZeroUsize::new(threads).unwrap());
+            let mut writer = BgzfWriter::with_pool(Vec::new(), pool);
+            // uneven writes, so batches end mid-block
+            for piece in text.as_bytes().chunks(1000 + threads) {
+                writer.write_all(piece).unwrap();
+            }
+            let compressed = writer.finish().unwrap();
+            assert!(compressed.ends_with(&EOF_MARKER));
+            assert_eq!(
+                decompress(&compressed),
+                text.as_bytes(),
+                "{threads} threads"
+            );
+
+            // bgzip's own reader agrees
+            let mut blocks = bgzip::BGZFReader::new(&compressed[..]).unwrap();
+            let mut out = Vec::new();
+            blocks.read_to_end(&mut out).unwrap();
+            assert_eq!(out, text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_flush_and_drop() {
+        let mut out = Vec::new();
+        {
+            let mut writer = BgzfWriter::new(&mut out);
+            writer.write_all(b"abc").unwrap();
+            writer.flush().unwrap();
+            writer.write_all(b"def").unwrap();
+        }
+        assert!(out.ends_with(&EOF_MARKER));
+        assert_eq!(decompress(&out), b"abcdef");
+
+        let empty = BgzfWriter::new(Vec::new()).finish().unwrap();
+        assert_eq!(empty, EOF_MARKER);
+    }
+}
@@ -0,0 +1,219 @@
+//! Build `Record`s in memory
+//!
+//! Length fields, bin and block_size are derived from the fields given, so a
+//! built record looks like one read from a file. `bin` can be overridden to
+//! produce records a writer would not.
+
+use lyso_common::CigarOp;
+
+use crate::indexer::reg2bin;
+use crate::writer::encode_aux_value;
+use crate::{fit_read_name, AuxMap, BamAuxField, BamAuxValue, Record};
+
+/// A builder for `Record`, starting from an unplaced, unmapped record
+///
+/// ```
+/// use lyso_bam::builder::RecordBuilder;
+/// use lyso_bam::flags;
+/// use lyso_common::CigarOp;
+///
+/// let rec = RecordBuilder::new("r1")
+///     .flag(flags::REVERSE)
+///     .position(0, "chr1", 99)
+///     .mapq(60)
+///     .cigar(vec![CigarOp::M(4)])
+///     .seq(b"ACGT")
+///     .build();
+/// assert_eq!(rec.to_string(), "r1\t16\tchr1\t100\t60\t4M\t*\t0\t0\tACGT\t*");
+/// assert!(rec.validate(None).is_empty());
+/// ```
+pub struct RecordBuilder {
+    rec: Record,
+    bin: Option<u16>,
+}
+
+impl RecordBuilder {
+    /// An unplaced, unmapped record called `read_name`, with no SEQ, QUAL or aux fields
+    #[must_use]
+    pub fn new(read_name: impl Into<Vec<u8>>) -> Self {
+        RecordBuilder {
+            rec: Record {
+                ref_id: -1,
+                ref_name: String::from("*"),
+                pos: -1,
+                next_ref_id: -1,
+                next_ref_name: String::from("*"),
+                next_pos: -1,
+                read_name: read_name.into(),
+                ..Record::default()
+            },
+            bin: None,
+        }
+    }
+
+    /// FLAG bits, see `flags`
+    #[must_use]
+    pub fn flag(mut self, flag: u16) -> Self {
+        self.rec.flag = flag;
+        self
+    }
+
+    /// Place the record at 0-based `pos` on reference `ref_id`, called `name`
+    #[must_use]
+    pub fn position(mut self, ref_id: i32, name: &str, pos: i32) -> Self {
+        self.rec.ref_id = ref_id;
+        self.rec.ref_name = name.to_string();
+        self.rec.pos = pos;
+        self
+    }
+
+    /// Mapping quality, 255 when unavailable
+    #[must_use]
+    pub fn mapq(mut self, mapq: u8) -> Self {
+        self.rec.mapq = mapq;
+        self
+    }
+
+    /// CIGAR operations; the bin is computed from them unless set
+    #[must_use]
+    pub fn cigar(mut self, cigar: Vec<CigarOp>) -> Self {
+        self.rec.cigar = cigar;
+        self
+    }
+
+    /// Place the mate at 0-based `pos` on reference `ref_id`, called `name`
+    #[must_use]
+    pub fn mate(mut self, ref_id: i32, name: &str, pos: i32) -> Self {
+        self.rec.next_ref_id = ref_id;
+        self.rec.next_ref_name = name.to_string();
+        self.rec.next_pos = pos;
+        self
+    }
+
+    /// Observed template length, signed by the leftmost mate
+    #[must_use]
+    pub fn tlen(mut self, tlen: i32) -> Self {
+        self.rec.tlen = tlen;
+        self
+    }
+
+    /// IUPAC bases; QUAL stays absent unless set
+    #[must_use]
+    pub fn seq(mut self, seq: &[u8]) -> Self {
+        self.rec.seq = seq.to_vec();
+        self
+    }
+
+    /// Raw Phred scores
+    #[must_use]
+    pub fn qual(mut self, qual: Vec<u8>) -> Self {
+        self.rec.qual = Some(qual);
+        self
+    }
+
+    /// Append an aux field, replacing one with the same tag
+    #[must_use]
+    pub fn aux(mut self, tag: &str, value: impl Into<BamAuxValue>) -> Self {
+        let mut chars = tag.chars();
+        let tag_chars = [chars.next().unwrap_or(' '), chars.next().unwrap_or(' ')];
+        let field = BamAuxField {
+            tag: tag_chars,
+            value: value.into(),
+        };
+        self.rec
+            .aux
+            .get_or_insert_with(AuxMap::default)
+            .insert(tag_chars.iter().collect(), field);
+        self
+    }
+
+    /// Append a parsed aux field, replacing one with the same tag
+    #[must_use]
+    pub fn aux_field(mut self, field: BamAuxField) -> Self {
+        self.rec
+            .aux
+            .get_or_insert_with(AuxMap::default)
+            .insert(field.tag.iter().collect(), field);
+        self
+    }
+
+    /// Store this bin instead of the one computed from the placement
+    #[must_use]
+    pub fn bin(mut self, bin: u16) -> Self {
+        self.bin = Some(bin);
+        self
+    }
+
+    /// The record, with its length fields and bin filled in
+    ///
+    /// A read name longer than `MAX_QNAME_LEN` is cut to that length.
+    pub fn build(self) -> Record {
+        let mut rec = self.rec;
+        rec.l_read_name = fit_read_name(&mut rec.read_name);
+        rec.n_cigar_op = u32::try_from(rec.cigar.len()).unwrap_or(u32::MAX);
+        rec.l_seq = u32::try_from(rec.seq.len()).unwrap_or(u32::MAX);
+        rec.bin = self.bin.unwrap_or_else(|| {
+            let end = rec.ref_end().map_or(0, |e| e.get() as i64);
+            reg2bin(i64::from(rec.pos), end)
+        });
+        let mut aux_bytes = Vec::new();
+        for field in rec.aux.iter().flat_map(|a| a.values()) {
+            aux_bytes.extend([0, 0]);
+            // only fails for arrays longer than u32::MAX
+            let _ = encode_aux_value(&field.value, &mut aux_bytes);
+        }
+        let l_seq = rec.seq.len();
+        let var = usize::from(rec.l_read_name)
+            + 4 * rec.cigar.len()
+            + l_seq.div_ceil(2)
+            + l_seq
+            + aux_bytes.len();
+        rec.block_size = u32::try_from(32 + var).unwrap_or(u32::MAX);
+        rec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags;
+
+    #[test]
+    fn test_build() {
+        let rec = RecordBuilder::new("r1")
+            .flag(flags::PAIRED)
+            .position(0, "chr1", 99)
+            .mapq(60)
+            .cigar(vec![CigarOp::S(2), CigarOp::M(4)])
+            .mate(0, "chr1", 199)
+            .tlen(150)
+            .seq(b"ACGTAC")
+            .qual(vec![30; 6])
+            .aux("NM", 1u8)
+            .build();
+        assert_eq!(
+            rec.to_string(),
+            "r1\t1\tchr1\t100\t60\t2S4M\tchr1\t200\t150\tACGTAC\t??????\tNM:i:1"
+        );
+        assert_eq!(
+            (rec.l_read_name(), rec.n_cigar_op(), rec.l_seq()),
+            (3, 2, 6)
+        );
+        assert_eq!(rec.bin(), 4681);
+        // 32 fixed, 3 name, 8 CIGAR, 3 SEQ, 6 QUAL, 4 aux
+        assert_eq!(rec.block_size(), 56);
+
+        let unplaced = RecordBuilder::new("u").flag(flags::UNMAPPED).build();
+        assert_eq!(unplaced.bin(), 4680);
+        assert_eq!(unplaced.aux(), None);
+        assert_eq!(RecordBuilder::new("b").bin(7).build().bin(), 7);
+
+        let rg = BamAuxField::from_sam_str("RG:Z:S1").unwrap();
+        let rec = RecordBuilder::new("r")
+            .aux("RG", String::from("S0"))
+            .aux_field(rg)
+            .build();
+        assert_eq!(rec.to_string(), "r\t0\t*\t0\t0\t*\t*\t0\t0\t*\t*\tRG:Z:S1");
+        assert_eq!(rec.block_size(), 32 + 2 + 6);
+    }
+}
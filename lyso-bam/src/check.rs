@@ -0,0 +1,395 @@
+//! Consistency checks of a single record
+//!
+//! `Record::validate` looks for fields that contradict each other without making
+//! the record unreadable: a CIGAR that disagrees with SEQ, an alignment running
+//! off its reference, mate fields on an unpaired read and so on. Problems come
+//! back as warnings, so a whole file can be checked and summarized.
+//!
+//! `validator` offers each category as a rule of a `Validator`, the form in
+//! which `lyso check` applies them alongside any rules of its own.
+
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use lyso_common::validate::{Severity, ValidationRule, Validator, Violation};
+use lyso_common::CigarOp;
+
+use crate::indexer::reg2bin;
+use crate::parser::qname_problem;
+use crate::{flags, BamReference, Record};
+
+/// MAPQ meaning "mapping quality is not available"
+pub const MAPQ_UNAVAILABLE: u8 = 255;
+
+/// A way in which a record is inconsistent
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RecordWarning {
+    /// A read name that is not a valid SAM QNAME, see `parser::check_qname`
+    ReadName(String),
+    /// The query length implied by the CIGAR is not l_seq
+    QueryLength {
+        /// Query bases consumed by the CIGAR
+        cigar: u64,
+        /// l_seq as stored
+        l_seq: u32,
+    },
+    /// ref_id names no reference in the header
+    UnknownReference(i32),
+    /// The alignment ends past the end of its reference
+    PastReferenceEnd {
+        /// 0-based, exclusive end of the alignment
+        end: u64,
+        /// Length of the reference
+        l_ref: u32,
+    },
+    /// A mapped record with MAPQ 255
+    MapqUnavailable,
+    /// Mate fields that disagree with the PAIRED and MATE_UNMAPPED flags
+    Mate(&'static str),
+    /// The stored bin is not the one computed from POS and the CIGAR
+    Bin {
+        /// Bin in the record
+        stored: u16,
+        /// Bin computed by `reg2bin`
+        expected: u16,
+    },
+    /// NM is negative or exceeds the aligned, inserted and deleted bases
+    Nm {
+        /// NM value
+        nm: i64,
+        /// Largest NM the alignment allows
+        max: u64,
+    },
+}
+
+impl RecordWarning {
+    /// Short name used to group warnings in a report
+    pub fn category(&self) -> &'static str {
+        match self {
+            RecordWarning::ReadName(_) => "read_name",
+            RecordWarning::QueryLength { .. } => "query_length",
+            RecordWarning::UnknownReference(_) => "unknown_reference",
+            RecordWarning::PastReferenceEnd { .. } => "past_reference_end",
+            RecordWarning::MapqUnavailable => "mapq_unavailable",
+            RecordWarning::Mate(_) => "mate",
+            RecordWarning::Bin { .. } => "bin",
+            RecordWarning::Nm { .. } => "nm",
+        }
+    }
+
+    /// Every category, in report order
+    pub const CATEGORIES: [&'static str; 8] = [
+        "read_name",
+        "query_length",
+        "unknown_reference",
+        "past_reference_end",
+        "mapq_unavailable",
+        "mate",
+        "bin",
+        "nm",
+    ];
+}
+
+impl Display for RecordWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordWarning::ReadName(detail) => write!(f, "invalid read name ({detail})"),
+            RecordWarning::QueryLength { cigar, l_seq } => {
+                write!(f, "CIGAR implies {cigar} query bases but SEQ has {l_seq}")
+            }
+            RecordWarning::UnknownReference(id) => write!(f, "reference {id} is not in the header"),
+            RecordWarning::PastReferenceEnd { end, l_ref } => {
+                write!(
+                    f,
+                    "alignment ends at {end}, past the reference length {l_ref}"
+                )
+            }
+            RecordWarning::MapqUnavailable => write!(f, "mapped with MAPQ 255 (unavailable)"),
+            RecordWarning::Mate(detail) => write!(f, "{detail}"),
+            RecordWarning::Bin { stored, expected } => {
+                write!(f, "bin is {stored}, expected {expected}")
+            }
+            RecordWarning::Nm { nm, max } => write!(f, "NM is {nm}, outside 0..={max}"),
+        }
+    }
+}
+
+/// A check of one category, given the references when they are known
+type Check = fn(&Record, Option<&[BamReference]>) -> Option<RecordWarning>;
+
+/// The check of each category, in `CATEGORIES` order
+const CHECKS: [Check; 8] = [
+    |rec, _| qname_problem(&rec.read_name).map(RecordWarning::ReadName),
+    |rec, _| rec.query_length_warning(),
+    Record::unknown_reference_warning,
+    Record::past_reference_end_warning,
+    |rec, _| {
+        (rec.is_mapped_at_pos() && rec.mapq == MAPQ_UNAVAILABLE)
+            .then_some(RecordWarning::MapqUnavailable)
+    },
+    |rec, _| rec.mate_inconsistency().map(RecordWarning::Mate),
+    |rec, _| rec.bin_warning(),
+    |rec, _| rec.nm_warning(),
+];
+
+impl Record {
+    /// Check the record for internally inconsistent fields
+    ///
+    /// Reference bounds are only checked when `references` is given. Returns an
+    /// empty list for a consistent record.
+    pub fn validate(&self, references: Option<&[BamReference]>) -> Vec<RecordWarning> {
+        CHECKS
+            .iter()
+            .filter_map(|check| check(self, references))
+            .collect()
+    }
+
+    fn is_mapped_at_pos(&self) -> bool {
+        !self.is_unmapped() && self.pos >= 0
+    }
+
+    fn query_length_warning(&self) -> Option<RecordWarning> {
+        let query_len: u64 = self
+            .cigar
+            .iter()
+            .filter(|op| op.consumes_query())
+            .map(|op| u64::from(op.len()))
+            .sum();
+        (!self.cigar.is_empty() && self.l_seq > 0 && query_len != u64::from(self.l_seq)).then_some(
+            RecordWarning::QueryLength {
+                cigar: query_len,
+                l_seq: self.l_seq,
+            },
+        )
+    }
+
+    fn reference<'a>(&self, refs: &'a [BamReference]) -> Option<&'a BamReference> {
+        usize::try_from(self.ref_id).ok().and_then(|i| refs.get(i))
+    }
+
+    fn unknown_reference_warning(&self, refs: Option<&[BamReference]>) -> Option<RecordWarning> {
+        (self.ref_id >= 0 && self.reference(refs?).is_none())
+            .then_some(RecordWarning::UnknownReference(self.ref_id))
+    }
+
+    fn past_reference_end_warning(&self, refs: Option<&[BamReference]>) -> Option<RecordWarning> {
+        let r = self.reference(refs?)?;
+        let end = self.ref_end()?.get();
+        (self.is_mapped_at_pos() && end > u64::from(r.l_ref)).then_some(
+            RecordWarning::PastReferenceEnd {
+                end,
+                l_ref: r.l_ref,
+            },
+        )
+    }
+
+    fn bin_warning(&self) -> Option<RecordWarning> {
+        let end = self.ref_end().map_or(0, |e| e.get() as i64);
+        let expected = reg2bin(i64::from(self.pos), end);
+        (self.bin != expected).then_some(RecordWarning::Bin {
+            stored: self.bin,
+            expected,
+        })
+    }
+
+    fn nm_warning(&self) -> Option<RecordWarning> {
+        if !self.is_mapped_at_pos() || self.cigar.is_empty() {
+            return None;
+        }
+        let nm = self.aux_int("NM")?;
+        let max = self
+            .cigar
+            .iter()
+            .filter(|op| op.is_aligned() || matches!(op, CigarOp::I(_) | CigarOp::D(_)))
+            .map(|op| u64::from(op.len()))
+            .sum();
+        (nm < 0 || nm as u64 > max).then_some(RecordWarning::Nm { nm, max })
+    }
+
+    fn mate_inconsistency(&self) -> Option<&'static str> {
+        const MATE_FLAGS: u16 = flags::PROPER_PAIR
+            | flags::MATE_UNMAPPED
+            | flags::MATE_REVERSE
+            | flags::READ1
+            | flags::READ2;
+        if !self.is_paired() {
+            if self.flag & MATE_FLAGS != 0 {
+                return Some("mate flags set on an unpaired read");
+            }
+            if self.next_ref_id >= 0 || self.next_pos >= 0 {
+                return Some("mate position set on an unpaired read");
+            }
+        } else if !self.is_mate_unmapped() && (self.next_ref_id < 0 || self.next_pos < 0) {
+            return Some("mate is mapped but has no position");
+        }
+        None
+    }
+}
+
+/// One category of `Record::validate` as a `ValidationRule`, named after it
+#[derive(Debug, Clone)]
+pub struct CategoryRule {
+    index: usize,
+    references: Option<Arc<[BamReference]>>,
+}
+
+impl CategoryRule {
+    /// The rule for `category`, one of `RecordWarning::CATEGORIES`
+    ///
+    /// Reference bounds are only checked when `references` is given.
+    pub fn new(category: &str, references: Option<Arc<[BamReference]>>) -> Option<Self> {
+        let index = RecordWarning::CATEGORIES
+            .iter()
+            .position(|c| *c == category)?;
+        Some(CategoryRule { index, references })
+    }
+}
+
+impl ValidationRule<Record> for CategoryRule {
+    fn name(&self) -> &str {
+        RecordWarning::CATEGORIES[self.index]
+    }
+
+    fn check(&self, rec: &Record) -> Option<Violation> {
+        let warning = CHECKS[self.index](rec, self.references.as_deref())?;
+        Some(Violation::new(warning.to_string()))
+    }
+}
+
+/// Every category of `Record::validate` as a warning-level rule, as `lyso check`
+/// applies them
+pub fn validator(references: Option<Arc<[BamReference]>>) -> Validator<Record> {
+    RecordWarning::CATEGORIES
+        .iter()
+        .filter_map(|c| CategoryRule::new(c, references.clone()))
+        .fold(Validator::new(), |v, rule| v.rule(rule, Severity::Warning))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::RecordBuilder;
+    use lyso_common::rename::RecordId;
+
+    fn refs() -> Vec<BamReference> {
+        vec![BamReference {
+            name: String::from("chr1"),
+            l_ref: 1000,
+        }]
+    }
+
+    /// A consistent, mapped, unpaired record of 10M at `pos`
+    fn mapped(pos: i32) -> RecordBuilder {
+        RecordBuilder::new("r")
+            .position(0, "chr1", pos)
+            .mapq(60)
+            .cigar(vec![CigarOp::M(10)])
+            .seq(b"ACGTACGTAC")
+    }
+
+    fn warnings(builder: RecordBuilder) -> Vec<RecordWarning> {
+        builder.build().validate(Some(&refs()))
+    }
+
+    #[test]
+    fn test_consistent() {
+        assert_eq!(warnings(mapped(100).aux("NM", 2u8)), []);
+        assert_eq!(warnings(RecordBuilder::new("u").flag(flags::UNMAPPED)), []);
+        // hard clips and absent SEQ are fine
+        let rec = mapped(100).cigar(vec![CigarOp::H(5), CigarOp::M(10)]);
+        assert_eq!(warnings(rec), []);
+        assert_eq!(warnings(mapped(100).seq(b"")), []);
+    }
+
+    #[test]
+    fn test_each_rule() {
+        let cases: [(RecordBuilder, RecordWarning); 8] = [
+            (
+                mapped(100).cigar(vec![CigarOp::S(2), CigarOp::M(10)]),
+                RecordWarning::QueryLength {
+                    cigar: 12,
+                    l_seq: 10,
+                },
+            ),
+            (
+                mapped(100).position(3, "chr4", 100),
+                RecordWarning::UnknownReference(3),
+            ),
+            (
+                mapped(995),
+                RecordWarning::PastReferenceEnd {
+                    end: 1005,
+                    l_ref: 1000,
+                },
+            ),
+            (mapped(100).mapq(255), RecordWarning::MapqUnavailable),
+            (
+                mapped(100).flag(flags::READ1),
+                RecordWarning::Mate("mate flags set on an unpaired read"),
+            ),
+            (
+                mapped(100).flag(flags::PAIRED),
+                RecordWarning::Mate("mate is mapped but has no position"),
+            ),
+            (
+                mapped(100).bin(4680),
+                RecordWarning::Bin {
+                    stored: 4680,
+                    expected: 4681,
+                },
+            ),
+            (
+                mapped(100).aux("NM", 11u8),
+                RecordWarning::Nm { nm: 11, max: 10 },
+            ),
+        ];
+        for (builder, expected) in cases {
+            let found = warnings(builder);
+            assert!(found.contains(&expected), "{expected:?} not in {found:?}");
+            assert!(RecordWarning::CATEGORIES.contains(&expected.category()));
+        }
+        let unpaired_mate = mapped(100).mate(0, "chr1", 300).build();
+        assert_eq!(
+            unpaired_mate.validate(None),
+            [RecordWarning::Mate("mate position set on an unpaired read")]
+        );
+        // reference bounds need the references
+        assert_eq!(mapped(995).build().validate(None), []);
+        let mut renamed = mapped(100).build();
+        renamed.set_record_id(b"r 1".to_vec());
+        assert_eq!(
+            renamed.validate(None),
+            [RecordWarning::ReadName(String::from(
+                "invalid character 0x20"
+            ))]
+        );
+        assert_eq!(renamed.block_size(), mapped(100).build().block_size() + 2);
+    }
+
+    #[test]
+    fn test_validator() {
+        let validator = validator(Some(refs().into()));
+        assert_eq!(
+            validator.rules().map(|(name, _)| name).collect::<Vec<_>>(),
+            RecordWarning::CATEGORIES
+        );
+        let found = validator.check(&mapped(995).mapq(255).build());
+        assert_eq!(
+            found
+                .iter()
+                .map(|v| (v.rule.as_str(), v.severity, v.record.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("past_reference_end", Severity::Warning, "r"),
+                ("mapq_unavailable", Severity::Warning, "r")
+            ]
+        );
+        assert_eq!(
+            found[0].to_string(),
+            "alignment ends at 1005, past the reference length 1000"
+        );
+        assert!(CategoryRule::new("nm", None).is_some());
+        assert!(CategoryRule::new("bogus", None).is_none());
+    }
+}
@@ -0,0 +1,208 @@
+//! Majority-base consensus over a reference
+//!
+//! `ConsensusBuilder` takes the pileup columns of one reference and calls, per
+//! position, the most common base among reads passing the MAPQ and base quality
+//! thresholds. Deletions take part in the vote and drop the position when they
+//! win; insertions are ignored.
+
+use crate::pileup::{PileupBase, PileupColumn};
+
+/// Thresholds for `ConsensusBuilder`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsensusOptions {
+    /// Reads below this mapping quality are ignored
+    pub min_mapq: u8,
+    /// Bases below this quality are ignored; deletions have no quality and always count
+    pub min_baseq: u8,
+    /// Below this depth the reference base is used instead of the majority
+    pub min_depth: u32,
+    /// Below this depth the call is written in lower case
+    pub confident_depth: u32,
+}
+
+impl Default for ConsensusOptions {
+    fn default() -> Self {
+        ConsensusOptions {
+            min_mapq: 0,
+            min_baseq: 13,
+            min_depth: 3,
+            confident_depth: 10,
+        }
+    }
+}
+
+/// Vote columns: A, C, G, T, anything else, deletion
+const VOTES: [u8; 6] = [b'A', b'C', b'G', b'T', b'N', b'*'];
+
+/// Call at one reference position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Call {
+    Uncovered,
+    Base(u8),
+    Deleted,
+}
+
+/// Consensus sequence of one reference
+///
+/// Positions without passing reads are N. Lower case marks a call below
+/// `confident_depth`, or one where the top votes tie; ties go to the reference
+/// base when it is among them, otherwise to the first of A, C, G, T, N.
+pub struct ConsensusBuilder {
+    options: ConsensusOptions,
+    reference: Vec<u8>,
+    calls: Vec<Call>,
+}
+
+impl ConsensusBuilder {
+    /// Calls against `reference`, every base uncovered until reads are added
+    #[must_use]
+    pub fn new(reference: Vec<u8>, options: ConsensusOptions) -> Self {
+        ConsensusBuilder {
+            options,
+            calls: vec![Call::Uncovered; reference.len()],
+            reference,
+        }
+    }
+
+    /// Call the position of `column`; columns past the end of the reference are ignored
+    pub fn add(&mut self, column: &PileupColumn) {
+        let Ok(pos) = usize::try_from(column.pos.get()) else {
+            return;
+        };
+        let Some(ref_base) = self.reference.get(pos).map(u8::to_ascii_uppercase) else {
+            return;
+        };
+        let mut votes = [0u32; VOTES.len()];
+        for e in &column.entries {
+            if e.mapq < self.options.min_mapq {
+                continue;
+            }
+            let vote = match e.base {
+                PileupBase::Deletion => VOTES.len() - 1,
+                PileupBase::Base(_) if e.qual.is_some_and(|q| q < self.options.min_baseq) => {
+                    continue
+                }
+                PileupBase::Base(b) => {
+                    let b = if b == b'=' { ref_base } else { b };
+                    VOTES[..4].iter().position(|v| *v == b).unwrap_or(4)
+                }
+            };
+            votes[vote] += 1;
+        }
+        self.calls[pos] = self.call(&votes, ref_base);
+    }
+
+    fn call(&self, votes: &[u32; VOTES.len()], ref_base: u8) -> Call {
+        let depth = votes.iter().sum::<u32>();
+        if depth == 0 {
+            return Call::Uncovered;
+        }
+        let low = depth < self.options.confident_depth;
+        if depth < self.options.min_depth {
+            return Call::Base(if low {
+                ref_base.to_ascii_lowercase()
+            } else {
+                ref_base
+            });
+        }
+        let top = votes.iter().max().copied().unwrap_or(0);
+        let tied = votes.iter().filter(|v| **v == top).count() > 1;
+        let winner = match VOTES.iter().position(|v| *v == ref_base) {
+            Some(r) if votes[r] == top => r,
+            _ => votes.iter().position(|v| *v == top).unwrap_or(4),
+        };
+        match VOTES[winner] {
+            b'*' => Call::Deleted,
+            b if low || tied => Call::Base(b.to_ascii_lowercase()),
+            b => Call::Base(b),
+        }
+    }
+
+    /// The consensus sequence, shorter than the reference by the deleted positions
+    pub fn finish(self) -> Vec<u8> {
+        self.calls
+            .into_iter()
+            .filter_map(|c| match c {
+                Call::Uncovered => Some(b'N'),
+                Call::Base(b) => Some(b),
+                Call::Deleted => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pileup::{Pileup, PileupEntry};
+    use crate::reader::BamReader;
+
+    fn consensus(options: ConsensusOptions) -> String {
+        let reader = BamReader::from_path("../resources/test_data/consensus.bam").unwrap();
+        let mut builder = ConsensusBuilder::new(b"ACGTACGTACGTACGTACGTACGT".to_vec(), options);
+        for col in Pileup::new(reader) {
+            builder.add(&col.unwrap());
+        }
+        String::from_utf8(builder.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_consensus() {
+        let options = ConsensusOptions {
+            min_mapq: 10,
+            min_baseq: 20,
+            min_depth: 2,
+            confident_depth: 3,
+        };
+        // 2: G/T split 2-2 goes to the reference; 1: a low-quality T is ignored
+        // 9-10: deleted by three reads; 14-15: one read, so the reference base
+        // 16-17: only a MAPQ 5 read; 19: skipped by the only read over it
+        assert_eq!(consensus(options), "ACgTACGTATACgtNNgNacNN");
+    }
+
+    #[test]
+    fn test_thresholds() {
+        // the MAPQ 5 reads now count and single reads make a full-confidence call
+        let options = ConsensusOptions {
+            min_mapq: 0,
+            min_baseq: 0,
+            min_depth: 1,
+            confident_depth: 1,
+        };
+        assert_eq!(consensus(options), "ACgTACGTATACCCAAGNACNN");
+    }
+
+    #[test]
+    fn test_eq_bases_vote_for_the_reference() {
+        let entry = |base| PileupEntry {
+            base: PileupBase::Base(base),
+            qual: Some(30),
+            mapq: 60,
+            is_reverse: false,
+        };
+        let options = ConsensusOptions {
+            min_depth: 1,
+            confident_depth: 1,
+            ..ConsensusOptions::default()
+        };
+        let mut builder = ConsensusBuilder::new(b"GaT".to_vec(), options);
+        builder.add(&PileupColumn {
+            ref_id: 0,
+            pos: lyso_common::pos::ZeroBased::new(1),
+            entries: vec![entry(b'='), entry(b'='), entry(b'C')],
+        });
+        assert_eq!(builder.finish(), b"NAN");
+    }
+
+    #[test]
+    fn test_uncovered_reference() {
+        // a column past the end of the reference is ignored
+        let mut builder = ConsensusBuilder::new(b"GGGG".to_vec(), ConsensusOptions::default());
+        builder.add(&PileupColumn {
+            ref_id: 0,
+            pos: lyso_common::pos::ZeroBased::new(10),
+            entries: Vec::new(),
+        });
+        assert_eq!(builder.finish(), b"NNNN");
+    }
+}
@@ -0,0 +1,77 @@
+//! Counting records without decoding them
+
+use std::io::BufRead;
+
+use crate::reader::BamReader;
+use crate::BamError;
+
+/// The number of alignment records in decompressed BAM, or the first error reading it
+///
+/// Each record is passed over by its block_size, as `BamReader::skip_records`
+/// does, so a record whose contents are corrupt is still counted, as iterating
+/// yields an item for it. A truncated block is an error rather than a short count.
+///
+/// ```
+/// use std::fs::File;
+///
+/// use bgzip::read::BGZFReader;
+/// use lyso_bam::count::fast_count;
+///
+/// let f = File::open("../resources/test_data/name_sorted.bam").unwrap();
+/// assert_eq!(fast_count(BGZFReader::new(f).unwrap()).unwrap(), 10);
+/// ```
+pub fn fast_count<R: BufRead>(reader: R) -> Result<u64, BamError> {
+    BamReader::new(reader)
+        .skip_records(usize::MAX)
+        .map(|n| n as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::BamReaderState;
+    use bgzip::read::BGZFReader;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+
+    fn decompressed(fixture: &str) -> Vec<u8> {
+        let f = File::open(format!("../resources/test_data/{fixture}")).unwrap();
+        let mut bytes = Vec::new();
+        BGZFReader::new(f).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_fixtures() {
+        for fixture in [
+            "absent_seq.bam",
+            "bwa_h500.bam",
+            "check_warnings.bam",
+            "consensus.bam",
+            "corrupt_record.bam",
+            "coverage.bam",
+            "latin1_name.bam",
+            "name_sorted.bam",
+            "stats.bam",
+            "unaligned.bam",
+        ] {
+            let bytes = decompressed(fixture);
+            let mut reader = BamReader::new(Cursor::new(&bytes));
+            let expected = reader.by_ref().count() as u64;
+            assert_eq!(reader.state(), BamReaderState::Complete, "{fixture}");
+            assert_eq!(fast_count(&bytes[..]).unwrap(), expected, "{fixture}");
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = decompressed("name_sorted.bam");
+        for cut in [2, 30, bytes.len() - 10] {
+            assert!(fast_count(&bytes[..cut]).is_err(), "cut at {cut}");
+        }
+        assert!(matches!(
+            fast_count(&b"@HD\tVN:1.6\n"[..]),
+            Err(BamError::WrongFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,531 @@
+//! Per-reference coverage from a coordinate-sorted BAM stream
+//!
+//! Depth is kept as a run-length profile, one run per stretch of equal depth,
+//! from the current record to the end of the reads overlapping it. Because
+//! records arrive in coordinate order, every run left of the current record is
+//! final and is folded into the breadth counters and dropped. Memory is
+//! proportional to the depth changes within the reads overlapping the current
+//! position, so at most to reference length and never to read count.
+//!
+//! Like `samtools coverage`, unmapped, secondary, QC-fail and duplicate records are
+//! ignored, and only M, = and X bases count towards depth (not deletions or skips).
+//!
+//! With `CoverageBuilder::with_targets`, the same depth is also summed over each
+//! of a set of BED intervals as the runs holding it are folded.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{self, Display};
+
+use lyso_common::intervals::{IntervalSet, NamedInterval};
+
+use crate::sort::CoordinateOrder;
+use crate::{Alignment, BamError, BamReference};
+
+/// Depths reported as breadth columns
+pub const BREADTH_DEPTHS: [u32; 3] = [1, 10, 30];
+
+/// Coverage summary of one reference
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefCoverage {
+    /// Reference name
+    pub name: String,
+    /// Reference length
+    pub length: u64,
+    /// Records counted towards depth
+    pub reads: u64,
+    /// Sum of M, = and X lengths
+    pub aligned_bases: u64,
+    /// Bases with depth of at least 1, 10 and 30 (see `BREADTH_DEPTHS`)
+    pub covered: [u64; 3],
+}
+
+impl RefCoverage {
+    /// Header row for the `Display` output
+    pub const TSV_HEADER: &'static str =
+        "rname\tlength\treads\taligned_bases\tmean_depth\tbreadth_1x\tbreadth_10x\tbreadth_30x";
+
+    /// Aligned bases divided by reference length
+    pub fn mean_depth(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        self.aligned_bases as f64 / self.length as f64
+    }
+
+    /// Fraction of the reference at `BREADTH_DEPTHS[i]` or deeper
+    pub fn breadth(&self, i: usize) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        self.covered[i] as f64 / self.length as f64
+    }
+}
+
+/// One TSV row, matching `RefCoverage::TSV_HEADER`
+impl Display for RefCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}",
+            self.name,
+            self.length,
+            self.reads,
+            self.aligned_bases,
+            self.mean_depth(),
+            self.breadth(0),
+            self.breadth(1),
+            self.breadth(2)
+        )
+    }
+}
+
+/// Coverage summary of one target interval
+///
+/// Bases of the interval past the end of its reference count as depth 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetCoverage {
+    /// The interval, as given in the BED file
+    pub interval: NamedInterval,
+    /// Sum of the depth of every base of the interval
+    pub depth_sum: u64,
+    /// Bases with depth of at least 1, 10 and 30 (see `BREADTH_DEPTHS`)
+    pub covered: [u64; 3],
+}
+
+impl TargetCoverage {
+    /// Header row for the `Display` output
+    pub const TSV_HEADER: &'static str =
+        "chrom\tstart\tend\tname\tmean_depth\tbreadth_1x\tbreadth_10x\tbreadth_30x";
+
+    fn new(interval: &NamedInterval) -> Self {
+        TargetCoverage {
+            interval: interval.clone(),
+            depth_sum: 0,
+            covered: [0; 3],
+        }
+    }
+
+    /// Depth summed over the interval divided by its length
+    pub fn mean_depth(&self) -> f64 {
+        match self.interval.len() {
+            0 => 0.0,
+            len => self.depth_sum as f64 / len as f64,
+        }
+    }
+
+    /// Fraction of the interval at `BREADTH_DEPTHS[i]` or deeper
+    pub fn breadth(&self, i: usize) -> f64 {
+        match self.interval.len() {
+            0 => 0.0,
+            len => self.covered[i] as f64 / len as f64,
+        }
+    }
+}
+
+/// One TSV row, matching `TargetCoverage::TSV_HEADER`; targets without a name
+/// column get "."
+impl Display for TargetCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let i = &self.interval;
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}",
+            i.name,
+            i.start.get(),
+            i.end.get(),
+            i.label.as_deref().unwrap_or("."),
+            self.mean_depth(),
+            self.breadth(0),
+            self.breadth(1),
+            self.breadth(2)
+        )
+    }
+}
+
+/// Add a run of `len` bases at `depth` to breadth counters
+fn add_covered(covered: &mut [u64; 3], len: u64, depth: u32) {
+    for (covered, min) in covered.iter_mut().zip(BREADTH_DEPTHS) {
+        if depth >= min {
+            *covered += len;
+        }
+    }
+}
+
+/// The targets of the reference being swept that the folded runs have reached
+#[derive(Debug, Default)]
+struct TargetSweep {
+    /// First target not yet reached
+    next: usize,
+    /// Reached targets that may still overlap later runs
+    active: Vec<usize>,
+}
+
+impl TargetSweep {
+    /// Add a folded run to the targets it overlaps; runs must come in order
+    fn visit(&mut self, targets: &mut [TargetCoverage], start: u64, end: u64, depth: u32) {
+        while self.next < targets.len() && targets[self.next].interval.start.get() < end {
+            self.active.push(self.next);
+            self.next += 1;
+        }
+        self.active
+            .retain(|&i| targets[i].interval.end.get() > start);
+        for &i in &self.active {
+            let t = &mut targets[i];
+            let start = start.max(t.interval.start.get());
+            let end = end.min(t.interval.end.get());
+            if start < end {
+                t.depth_sum += u64::from(depth) * (end - start);
+                add_covered(&mut t.covered, end - start, depth);
+            }
+        }
+    }
+}
+
+/// Accumulates coverage for every reference in a header
+pub struct CoverageBuilder {
+    refs: Vec<RefCoverage>,
+    order: CoordinateOrder,
+    /// Reference currently being swept
+    current: Option<usize>,
+    /// Depth from the current record onwards
+    depth: DepthRuns,
+    /// Targets of each reference, sorted by start
+    targets: Vec<Vec<TargetCoverage>>,
+    /// Targets on sequences missing from the header, which stay at depth 0
+    missing: Vec<TargetCoverage>,
+    sweep: TargetSweep,
+}
+
+/// A run-length depth profile: runs of equal depth from `start`, each stored as
+/// (end, depth); depth is 0 past the last run
+#[derive(Debug, Default)]
+struct DepthRuns {
+    start: u64,
+    runs: VecDeque<(u64, u32)>,
+}
+
+impl DepthRuns {
+    /// Where the last run ends
+    fn end(&self) -> u64 {
+        self.runs.back().map_or(self.start, |r| r.0)
+    }
+
+    /// Split the run containing `at` so that a run ends there, for `at` at or
+    /// after `start`; returns the index of the first run starting at `at`
+    fn split(&mut self, at: u64) -> usize {
+        if at >= self.end() {
+            if at > self.end() {
+                self.runs.push_back((at, 0));
+            }
+            return self.runs.len();
+        }
+        let i = self.runs.partition_point(|r| r.0 <= at);
+        let run_start = if i == 0 {
+            self.start
+        } else {
+            self.runs[i - 1].0
+        };
+        if run_start < at {
+            self.runs.insert(i, (at, self.runs[i].1));
+            return i + 1;
+        }
+        i
+    }
+
+    /// Raise depth by one over `start..end`, which starts at or after `self.start`
+    fn add(&mut self, start: u64, end: u64) {
+        let first = self.split(start);
+        let last = self.split(end);
+        self.runs.range_mut(first..last).for_each(|r| r.1 += 1);
+    }
+
+    /// Remove everything left of `upto`, passing each run removed to `visit` as
+    /// (start, end, depth)
+    fn drain(&mut self, upto: u64, mut visit: impl FnMut(u64, u64, u32)) {
+        if upto <= self.start {
+            return;
+        }
+        let n = self.split(upto);
+        for (end, depth) in self.runs.drain(..n) {
+            visit(self.start, end, depth);
+            self.start = end;
+        }
+        self.start = upto;
+    }
+}
+
+impl CoverageBuilder {
+    /// Zero coverage for each of `references`
+    #[must_use]
+    pub fn new(references: &[BamReference]) -> Self {
+        CoverageBuilder {
+            refs: references
+                .iter()
+                .map(|r| RefCoverage {
+                    name: r.name().to_string(),
+                    length: u64::from(r.l_ref()),
+                    reads: 0,
+                    aligned_bases: 0,
+                    covered: [0; 3],
+                })
+                .collect(),
+            order: CoordinateOrder::new(),
+            current: None,
+            depth: DepthRuns::default(),
+            targets: vec![Vec::new(); references.len()],
+            missing: Vec::new(),
+            sweep: TargetSweep::default(),
+        }
+    }
+
+    /// Also summarize depth over each of `targets`, see `finish_with_targets`
+    ///
+    /// Overlapping targets are each summarized in full.
+    #[must_use]
+    pub fn with_targets(mut self, targets: &IntervalSet) -> Self {
+        for (cov, list) in self.refs.iter().zip(&mut self.targets) {
+            *list = targets
+                .get(&cov.name)
+                .iter()
+                .map(TargetCoverage::new)
+                .collect();
+        }
+        let known = self
+            .refs
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<HashSet<_>>();
+        let mut missing = targets
+            .names()
+            .filter(|n| !known.contains(n))
+            .collect::<Vec<_>>();
+        missing.sort_unstable();
+        self.missing = missing
+            .into_iter()
+            .flat_map(|n| targets.get(n))
+            .map(TargetCoverage::new)
+            .collect();
+        self
+    }
+
+    /// Add one record
+    ///
+    /// Fails with `NotCoordinateSorted` if `rec` sorts before the previous record,
+    /// and with `CorruptRecord` if it names a reference missing from the header.
+    pub fn update(&mut self, rec: &impl Alignment) -> Result<(), BamError> {
+        self.order.check(rec)?;
+        if rec.is_unmapped() || rec.is_secondary() || rec.is_qc_fail() || rec.is_duplicate() {
+            return Ok(());
+        }
+        let (Ok(ref_idx), Some(pos)) = (usize::try_from(rec.ref_id()), rec.pos()) else {
+            return Ok(());
+        };
+        if ref_idx >= self.refs.len() {
+            return Err(BamError::CorruptRecord {
+                field: "ref_id",
+                detail: match self.refs.len() {
+                    1 => format!("{ref_idx} but the header has 1 reference"),
+                    n => format!("{ref_idx} but the header has {n} references"),
+                },
+            });
+        }
+        if self.current != Some(ref_idx) {
+            self.flush(u64::MAX);
+            self.current = Some(ref_idx);
+            self.depth = DepthRuns::default();
+            self.sweep = TargetSweep::default();
+        }
+        // nothing to the left of this record can change any more
+        self.flush(pos.get());
+
+        let length = self.refs[ref_idx].length;
+        let cov = &mut self.refs[ref_idx];
+        cov.reads += 1;
+        for (start, end) in rec.aligned_blocks() {
+            cov.aligned_bases += end - start;
+            let (start, end) = (start.min(length), end.min(length));
+            if start < end {
+                self.depth.add(start, end);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fold the depth left of `upto` into the breadth of the current reference
+    /// and its targets
+    fn flush(&mut self, upto: u64) {
+        if let Some(idx) = self.current {
+            let upto = upto.min(self.depth.end());
+            let covered = &mut self.refs[idx].covered;
+            let (targets, sweep) = (&mut self.targets[idx], &mut self.sweep);
+            self.depth.drain(upto, |start, end, depth| {
+                add_covered(covered, end - start, depth);
+                sweep.visit(targets, start, end, depth);
+            });
+        }
+    }
+
+    /// Summaries for every reference in header order, including those without reads
+    pub fn finish(self) -> Vec<RefCoverage> {
+        self.finish_with_targets().0
+    }
+
+    /// Summaries for every reference as from `finish`, and for every target given
+    /// to `with_targets`
+    ///
+    /// Targets come in header order of their reference, then by start; targets on
+    /// sequences missing from the header come last, by sequence name.
+    pub fn finish_with_targets(mut self) -> (Vec<RefCoverage>, Vec<TargetCoverage>) {
+        self.flush(u64::MAX);
+        let mut targets = self.targets.into_iter().flatten().collect::<Vec<_>>();
+        targets.append(&mut self.missing);
+        (self.refs, targets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::BamReader;
+    use std::fs::File;
+
+    fn coverage(name: &str) -> Result<Vec<RefCoverage>, BamError> {
+        let f = File::open(format!("../resources/test_data/{name}")).unwrap();
+        let mut reader = BamReader::new(bgzip::read::BGZFReader::new(f).unwrap());
+        let first = reader.next();
+        let mut builder = CoverageBuilder::new(reader.references());
+        for rec in first.into_iter().chain(reader) {
+            builder.update(&rec?)?;
+        }
+        Ok(builder.finish())
+    }
+
+    #[test]
+    fn test_coverage() {
+        // chr1: 10M at 0, 5M2D5M at 5, 3S4M1I3M at 8, plus a duplicate
+        // chr2: ten 10M reads at 40; chr3: no reads
+        let covs = coverage("coverage.bam").unwrap();
+        assert_eq!(covs.len(), 3);
+
+        let chr1 = &covs[0];
+        assert_eq!((chr1.reads, chr1.aligned_bases), (3, 27));
+        // depth 1,1,1,1,1,2,2,2,3,3,1,1,2,2,2,1,1 over 0..17
+        assert_eq!(chr1.covered, [17, 0, 0]);
+        assert_eq!(chr1.mean_depth(), 0.27);
+
+        let chr2 = &covs[1];
+        assert_eq!((chr2.reads, chr2.aligned_bases), (10, 100));
+        assert_eq!(chr2.covered, [10, 10, 0]);
+        assert_eq!(chr2.mean_depth(), 2.0);
+        assert_eq!(
+            chr2.to_string(),
+            "chr2\t50\t10\t100\t2.0000\t0.2000\t0.2000\t0.0000"
+        );
+
+        assert_eq!(covs[2].reads, 0);
+        assert_eq!(covs[2].covered, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_matches_samtools() {
+        let expected = std::fs::read_to_string("../resources/test_data/coverage.bam.cov").unwrap();
+        let rows = expected.lines().filter(|l| !l.starts_with('#'));
+        let covs = coverage("coverage.bam").unwrap();
+        assert_eq!(rows.clone().count(), covs.len());
+        for (row, cov) in rows.zip(&covs) {
+            let f = row.split('\t').collect::<Vec<_>>();
+            assert_eq!(
+                (f[0], f[2]),
+                (cov.name.as_str(), cov.length.to_string().as_str())
+            );
+            assert_eq!(f[3].parse::<u64>().unwrap(), cov.reads, "{row}");
+            assert_eq!(f[4].parse::<u64>().unwrap(), cov.covered[0], "{row}");
+            assert_eq!(f[6].parse::<f64>().unwrap(), cov.mean_depth(), "{row}");
+        }
+    }
+
+    #[test]
+    fn test_depth_runs() {
+        // overlapping blocks in start order, against a depth per base
+        let mut seed = 17u64;
+        let mut next = |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        let mut depth = vec![0u32; 1200];
+        let mut runs = DepthRuns::default();
+        let mut covered = [0; 3];
+        let mut start = 0;
+        for _ in 0..400 {
+            start += next(4);
+            let end = start + 1 + next(60);
+            depth[start as usize..end as usize]
+                .iter_mut()
+                .for_each(|d| *d += 1);
+            runs.drain(start.min(runs.end()), |s, e, d| {
+                add_covered(&mut covered, e - s, d)
+            });
+            runs.add(start, end);
+        }
+        runs.drain(runs.end(), |s, e, d| add_covered(&mut covered, e - s, d));
+        assert!(runs.runs.is_empty());
+        let expected = BREADTH_DEPTHS.map(|min| depth.iter().filter(|d| **d >= min).count() as u64);
+        assert_eq!(covered, expected);
+        assert!(expected[2] > 0);
+    }
+
+    #[test]
+    fn test_targets() {
+        let bed =
+            "chrX\t0\t10\nchr2\t45\t60\tt2\nchr1\t4\t10\nchr1\t0\t17\nchr1\t8\t9\nchr3\t0\t5\n";
+        let targets = IntervalSet::from_bed(bed.as_bytes(), false).unwrap();
+        let f = File::open("../resources/test_data/coverage.bam").unwrap();
+        let mut reader = BamReader::new(bgzip::read::BGZFReader::new(f).unwrap());
+        let first = reader.next();
+        let mut builder = CoverageBuilder::new(reader.references()).with_targets(&targets);
+        for rec in first.into_iter().chain(reader) {
+            builder.update(&rec.unwrap()).unwrap();
+        }
+        let (refs, targets) = builder.finish_with_targets();
+        assert_eq!(refs, coverage("coverage.bam").unwrap());
+        let summary = targets
+            .iter()
+            .map(|t| {
+                (
+                    t.interval.name.as_str(),
+                    t.interval.start.get(),
+                    t.depth_sum,
+                    t.covered,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                // depth 1,1,1,1,1,2,2,2,3,3,1,1,2,2,2,1,1 over 0..17
+                ("chr1", 0, 27, [17, 0, 0]),
+                ("chr1", 4, 13, [6, 0, 0]),
+                ("chr1", 8, 3, [1, 0, 0]),
+                // depth 10 over 40..50, then nothing
+                ("chr2", 45, 50, [5, 5, 0]),
+                ("chr3", 0, 0, [0, 0, 0]),
+                ("chrX", 0, 0, [0, 0, 0]),
+            ]
+        );
+        assert_eq!(
+            targets[3].to_string(),
+            "chr2\t45\t60\tt2\t3.3333\t0.3333\t0.3333\t0.0000"
+        );
+        assert!(targets[0].to_string().starts_with("chr1\t0\t17\t.\t"));
+    }
+
+    #[test]
+    fn test_unsorted_input() {
+        assert!(matches!(
+            coverage("name_sorted.bam"),
+            Err(BamError::NotCoordinateSorted { .. })
+        ));
+    }
+}
@@ -0,0 +1,517 @@
+//! Record filters on FLAG bits and aux tags
+//!
+//! A `RecordFilter` keeps the records passing every one of its tests. Tag tests
+//! use the typed aux values: a value test on a tag of another type fails, and
+//! one on a record without the tag passes or fails as set by `MissingTag`. With
+//! `LazyRecord`s, aux is only decoded for records whose flags pass, and only
+//! when there is a tag test.
+//!
+//! ```
+//! use lyso_bam::builder::RecordBuilder;
+//! use lyso_bam::filter::{MissingTag, RecordFilter};
+//! use lyso_bam::flags::{self, Flags};
+//!
+//! let filter = RecordFilter::new()
+//!     .exclude_flags(Flags(flags::UNMAPPED))
+//!     .tag_int_max("NM", 2)
+//!     .tag_equals_str("RG", "sample1");
+//! let rec = RecordBuilder::new("r1")
+//!     .aux("NM", 1u8)
+//!     .aux("RG", "sample1".to_string())
+//!     .build();
+//! assert!(filter.matches(&rec));
+//!
+//! let no_nm = RecordBuilder::new("r2").aux("RG", "sample1".to_string()).build();
+//! assert!(!filter.matches(&no_nm));
+//! assert!(filter.missing_tag(MissingTag::Pass).matches(&no_nm));
+//! ```
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::flags::Flags;
+use crate::lazy::LazyRecord;
+use crate::{AuxMap, BamAuxValue, BamError, Record};
+
+/// Errors parsing a `TagPredicate`
+#[derive(Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TagPredicateError {
+    /// Not TAG:OP:VALUE, TAG:present or TAG:absent
+    #[error("invalid tag filter '{0}': expected TAG:OP:VALUE, e.g. NM:le:2 or RG:eq:sample1, or TAG:present or TAG:absent")]
+    Malformed(String),
+    /// Not a two character SAM tag
+    #[error("invalid tag '{0}': expected a letter then a letter or digit")]
+    InvalidTag(String),
+    /// An operator other than le, ge, eq or str
+    #[error("unknown tag filter operator '{0}' (expected le, ge, eq or str)")]
+    UnknownOp(String),
+    /// le or ge with a value that is not an integer
+    #[error("invalid tag filter value '{0}': le and ge need an integer")]
+    InvalidNumber(String),
+}
+
+/// What a `TagPredicate` asks of its tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TagTest {
+    /// An integer value no greater than this
+    IntMax(i64),
+    /// An integer value no less than this
+    IntMin(i64),
+    /// An integer value equal to this
+    IntEquals(i64),
+    /// A string (Z) value equal to this
+    StrEquals(String),
+    /// The tag is present, with any value
+    Present,
+    /// The tag is absent
+    Absent,
+}
+
+/// A test of one aux tag
+///
+/// Parses from `TAG:le:N`, `TAG:ge:N`, `TAG:eq:VALUE`, `TAG:str:VALUE`,
+/// `TAG:present` or `TAG:absent`; `eq` compares integers when VALUE is one, and
+/// strings otherwise, while `str` always compares strings. Parsing the `Display`
+/// output gives back the same predicate.
+///
+/// ```
+/// use lyso_bam::filter::{TagPredicate, TagTest};
+///
+/// let pred: TagPredicate = "NM:le:2".parse().unwrap();
+/// assert_eq!((pred.tag(), pred.test()), ("NM", &TagTest::IntMax(2)));
+/// assert_eq!(pred.to_string(), "NM:le:2");
+///
+/// let pred = TagPredicate::new("BC", TagTest::StrEquals("5".to_string()));
+/// assert_eq!(pred.to_string(), "BC:str:5");
+/// assert_eq!(pred.to_string().parse(), Ok(pred));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagPredicate {
+    tag: String,
+    test: TagTest,
+}
+
+impl TagPredicate {
+    /// A test of `tag`
+    pub fn new(tag: &str, test: TagTest) -> Self {
+        TagPredicate {
+            tag: tag.to_string(),
+            test,
+        }
+    }
+
+    /// The tag tested
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// What is asked of it
+    pub fn test(&self) -> &TagTest {
+        &self.test
+    }
+
+    /// Whether `aux` passes; `missing` decides value tests when the tag is absent
+    pub fn passes(&self, aux: Option<&AuxMap>, missing: MissingTag) -> bool {
+        let value = aux
+            .and_then(|aux| aux.get(self.tag.as_str()))
+            .map(|f| &f.value);
+        match (&self.test, value) {
+            (TagTest::Present, value) => value.is_some(),
+            (TagTest::Absent, value) => value.is_none(),
+            (_, None) => missing == MissingTag::Pass,
+            (TagTest::IntMax(max), Some(v)) => v.as_int().is_some_and(|v| v <= *max),
+            (TagTest::IntMin(min), Some(v)) => v.as_int().is_some_and(|v| v >= *min),
+            (TagTest::IntEquals(n), Some(v)) => v.as_int() == Some(*n),
+            (TagTest::StrEquals(s), Some(BamAuxValue::Z(v))) => v == s,
+            (TagTest::StrEquals(_), Some(_)) => false,
+        }
+    }
+}
+
+/// Whether `tag` is a SAM tag: a letter, then a letter or digit
+pub fn is_valid_tag(tag: &str) -> bool {
+    matches!(tag.as_bytes(), [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric())
+}
+
+impl FromStr for TagPredicate {
+    type Err = TagPredicateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(tag), Some(op)) = (parts.next(), parts.next()) else {
+            return Err(TagPredicateError::Malformed(s.to_string()));
+        };
+        if !is_valid_tag(tag) {
+            return Err(TagPredicateError::InvalidTag(tag.to_string()));
+        }
+        let value = match (op, parts.next()) {
+            ("present", None) => return Ok(TagPredicate::new(tag, TagTest::Present)),
+            ("absent", None) => return Ok(TagPredicate::new(tag, TagTest::Absent)),
+            (_, Some(value)) => value,
+            (_, None) => return Err(TagPredicateError::Malformed(s.to_string())),
+        };
+        let int = || {
+            value
+                .parse::<i64>()
+                .map_err(|_| TagPredicateError::InvalidNumber(value.to_string()))
+        };
+        let test = match op {
+            "le" => TagTest::IntMax(int()?),
+            "ge" => TagTest::IntMin(int()?),
+            "eq" => match value.parse::<i64>() {
+                Ok(n) => TagTest::IntEquals(n),
+                Err(_) => TagTest::StrEquals(value.to_string()),
+            },
+            "str" => TagTest::StrEquals(value.to_string()),
+            _ => return Err(TagPredicateError::UnknownOp(op.to_string())),
+        };
+        Ok(TagPredicate::new(tag, test))
+    }
+}
+
+/// `TAG:OP:VALUE`, or `TAG:present` / `TAG:absent`, in a form that parses back;
+/// a string that looks like an integer is written with `str`
+impl Display for TagPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = &self.tag;
+        match &self.test {
+            TagTest::IntMax(n) => write!(f, "{tag}:le:{n}"),
+            TagTest::IntMin(n) => write!(f, "{tag}:ge:{n}"),
+            TagTest::IntEquals(n) => write!(f, "{tag}:eq:{n}"),
+            TagTest::StrEquals(s) if s.parse::<i64>().is_ok() => write!(f, "{tag}:str:{s}"),
+            TagTest::StrEquals(s) => write!(f, "{tag}:eq:{s}"),
+            TagTest::Present => write!(f, "{tag}:present"),
+            TagTest::Absent => write!(f, "{tag}:absent"),
+        }
+    }
+}
+
+/// Whether a value test passes a record that lacks its tag
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingTag {
+    /// Keep the record
+    Pass,
+    /// Drop the record
+    #[default]
+    Fail,
+}
+
+impl FromStr for MissingTag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass" => Ok(MissingTag::Pass),
+            "fail" => Ok(MissingTag::Fail),
+            _ => Err(format!(
+                "invalid missing tag policy '{s}', expected pass or fail"
+            )),
+        }
+    }
+}
+
+impl Display for MissingTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingTag::Pass => write!(f, "pass"),
+            MissingTag::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// Keeps the records whose flags and tags pass every test
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordFilter {
+    require: Flags,
+    exclude: Flags,
+    tags: Vec<TagPredicate>,
+    missing: MissingTag,
+}
+
+impl RecordFilter {
+    /// A filter keeping every record
+    #[must_use]
+    pub fn new() -> Self {
+        RecordFilter::default()
+    }
+
+    /// Keep only records with all of these flags set
+    #[must_use]
+    pub fn require_flags(mut self, flags: Flags) -> Self {
+        self.require = Flags(self.require.bits() | flags.bits());
+        self
+    }
+
+    /// Drop records with any of these flags set
+    #[must_use]
+    pub fn exclude_flags(mut self, flags: Flags) -> Self {
+        self.exclude = Flags(self.exclude.bits() | flags.bits());
+        self
+    }
+
+    /// Add a tag test
+    #[must_use]
+    pub fn tag(mut self, predicate: TagPredicate) -> Self {
+        self.tags.push(predicate);
+        self
+    }
+
+    /// Keep only records whose integer `tag` is at most `max`
+    #[must_use]
+    pub fn tag_int_max(self, tag: &str, max: i64) -> Self {
+        self.tag(TagPredicate::new(tag, TagTest::IntMax(max)))
+    }
+
+    /// Keep only records whose integer `tag` is at least `min`
+    #[must_use]
+    pub fn tag_int_min(self, tag: &str, min: i64) -> Self {
+        self.tag(TagPredicate::new(tag, TagTest::IntMin(min)))
+    }
+
+    /// Keep only records whose string `tag` is `value`
+    #[must_use]
+    pub fn tag_equals_str(self, tag: &str, value: &str) -> Self {
+        self.tag(TagPredicate::new(
+            tag,
+            TagTest::StrEquals(value.to_string()),
+        ))
+    }
+
+    /// Keep only records with `tag`, whatever its value
+    #[must_use]
+    pub fn has_tag(self, tag: &str) -> Self {
+        self.tag(TagPredicate::new(tag, TagTest::Present))
+    }
+
+    /// Keep only records without `tag`
+    #[must_use]
+    pub fn lacks_tag(self, tag: &str) -> Self {
+        self.tag(TagPredicate::new(tag, TagTest::Absent))
+    }
+
+    /// What value tests do with records lacking their tag (default: fail)
+    #[must_use]
+    pub fn missing_tag(mut self, policy: MissingTag) -> Self {
+        self.missing = policy;
+        self
+    }
+
+    /// Whether the filter keeps every record
+    pub fn is_empty(&self) -> bool {
+        self.require == Flags::default() && self.exclude == Flags::default() && self.tags.is_empty()
+    }
+
+    /// Whether the filter tests any aux tag
+    pub fn has_tag_tests(&self) -> bool {
+        !self.tags.is_empty()
+    }
+
+    /// Whether `flag` passes the flag tests
+    pub fn flags_pass(&self, flag: u16) -> bool {
+        self.require.all_set(flag) && !self.exclude.any_set(flag)
+    }
+
+    /// Whether `aux` passes the tag tests
+    pub fn tags_pass(&self, aux: Option<&AuxMap>) -> bool {
+        self.tags.iter().all(|t| t.passes(aux, self.missing))
+    }
+
+    /// Whether `rec` passes every test
+    pub fn matches(&self, rec: &Record) -> bool {
+        self.flags_pass(rec.flag()) && self.tags_pass(rec.aux())
+    }
+
+    /// Whether `rec` passes every test, decoding its aux only if needed
+    ///
+    /// Fails if the aux fields have to be decoded and cannot be.
+    pub fn matches_lazy(&self, rec: &LazyRecord) -> Result<bool, BamError> {
+        if !self.flags_pass(rec.flag()) {
+            return Ok(false);
+        }
+        if self.tags.is_empty() {
+            return Ok(true);
+        }
+        Ok(self.tags_pass(rec.aux()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::RecordBuilder;
+    use crate::flags;
+    use crate::reader::BamReader;
+
+    fn records() -> Vec<Record> {
+        vec![
+            RecordBuilder::new("nm0_rg1")
+                .aux("NM", 0u8)
+                .aux("RG", "sample1".to_string())
+                .build(),
+            RecordBuilder::new("nm3_rg1_dup")
+                .flag(flags::DUPLICATE)
+                .aux("NM", 3u8)
+                .aux("RG", "sample1".to_string())
+                .build(),
+            RecordBuilder::new("nm2_rg2_sa")
+                .aux("NM", 2i32)
+                .aux("RG", "sample2".to_string())
+                .aux("SA", "chr2,100,+,50M,60,0;".to_string())
+                .build(),
+            RecordBuilder::new("rg1_unmapped")
+                .flag(flags::UNMAPPED)
+                .aux("RG", "sample1".to_string())
+                .build(),
+            RecordBuilder::new("nm_text")
+                .aux("NM", "1".to_string())
+                .build(),
+        ]
+    }
+
+    fn kept(filter: &RecordFilter) -> Vec<String> {
+        records()
+            .iter()
+            .filter(|r| filter.matches(r))
+            .map(|r| r.read_name_str_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_tests_combine_with_and() {
+        assert_eq!(kept(&RecordFilter::new()).len(), 5);
+        let filter = RecordFilter::new().tag_int_max("NM", 2);
+        assert_eq!(kept(&filter), ["nm0_rg1", "nm2_rg2_sa"]);
+        let filter = filter.tag_equals_str("RG", "sample1");
+        assert_eq!(kept(&filter), ["nm0_rg1"]);
+
+        // flags and tags must both pass
+        let filter = RecordFilter::new()
+            .tag_equals_str("RG", "sample1")
+            .exclude_flags(Flags(flags::UNMAPPED));
+        assert_eq!(kept(&filter), ["nm0_rg1", "nm3_rg1_dup"]);
+        let filter = filter.require_flags(Flags(flags::DUPLICATE));
+        assert_eq!(kept(&filter), ["nm3_rg1_dup"]);
+
+        let filter = RecordFilter::new().tag_int_min("NM", 2).lacks_tag("SA");
+        assert_eq!(kept(&filter), ["nm3_rg1_dup"]);
+        assert_eq!(kept(&RecordFilter::new().has_tag("SA")), ["nm2_rg2_sa"]);
+        // contradictory tests keep nothing
+        let filter = RecordFilter::new()
+            .tag_int_min("NM", 3)
+            .tag_int_max("NM", 2);
+        assert!(kept(&filter).is_empty());
+    }
+
+    #[test]
+    fn test_missing_tag_policy() {
+        let filter = RecordFilter::new().tag_int_max("NM", 5);
+        assert_eq!(kept(&filter), ["nm0_rg1", "nm3_rg1_dup", "nm2_rg2_sa"]);
+        // a tag of the wrong type fails either way
+        let filter = filter.missing_tag(MissingTag::Pass);
+        assert_eq!(
+            kept(&filter),
+            ["nm0_rg1", "nm3_rg1_dup", "nm2_rg2_sa", "rg1_unmapped"]
+        );
+
+        // presence tests ignore the policy
+        for policy in [MissingTag::Pass, MissingTag::Fail] {
+            let filter = RecordFilter::new().has_tag("NM").missing_tag(policy);
+            assert_eq!(kept(&filter).len(), 4);
+            let filter = RecordFilter::new().lacks_tag("NM").missing_tag(policy);
+            assert_eq!(kept(&filter), ["rg1_unmapped"]);
+        }
+        // so does a record without any aux
+        let bare = RecordBuilder::new("bare").build();
+        assert!(RecordFilter::new().lacks_tag("SA").matches(&bare));
+        let filter = RecordFilter::new().tag_equals_str("RG", "x");
+        assert!(!filter.matches(&bare));
+        assert!(filter.missing_tag(MissingTag::Pass).matches(&bare));
+    }
+
+    #[test]
+    fn test_parse_predicates() {
+        for (s, tag, test) in [
+            ("NM:le:2", "NM", TagTest::IntMax(2)),
+            ("AS:ge:-5", "AS", TagTest::IntMin(-5)),
+            ("NM:eq:0", "NM", TagTest::IntEquals(0)),
+            (
+                "RG:eq:sample1",
+                "RG",
+                TagTest::StrEquals("sample1".to_string()),
+            ),
+            (
+                "XA:eq:chr1:100",
+                "XA",
+                TagTest::StrEquals("chr1:100".to_string()),
+            ),
+            ("X0:eq:", "X0", TagTest::StrEquals(String::new())),
+            ("BC:str:5", "BC", TagTest::StrEquals("5".to_string())),
+            ("SA:present", "SA", TagTest::Present),
+            ("XS:absent", "XS", TagTest::Absent),
+        ] {
+            let pred = s.parse::<TagPredicate>().unwrap();
+            assert_eq!(pred, TagPredicate::new(tag, test), "{s}");
+            assert_eq!(pred.to_string(), s);
+        }
+        let err = |s: &str| s.parse::<TagPredicate>().unwrap_err();
+        assert_eq!(err("NM"), TagPredicateError::Malformed("NM".to_string()));
+        assert_eq!(
+            err("NM:le"),
+            TagPredicateError::Malformed("NM:le".to_string())
+        );
+        assert_eq!(
+            err("N:le:2"),
+            TagPredicateError::InvalidTag("N".to_string())
+        );
+        assert_eq!(
+            err("1M:le:2"),
+            TagPredicateError::InvalidTag("1M".to_string())
+        );
+        assert_eq!(
+            err("NM:lt:2"),
+            TagPredicateError::UnknownOp("lt".to_string())
+        );
+        assert_eq!(
+            err("SA:present:1"),
+            TagPredicateError::UnknownOp("present".to_string())
+        );
+        assert_eq!(
+            err("NM:le:x"),
+            TagPredicateError::InvalidNumber("x".to_string())
+        );
+        assert_eq!("pass".parse(), Ok(MissingTag::Pass));
+        assert!("keep".parse::<MissingTag>().is_err());
+    }
+
+    #[test]
+    fn test_lazy_agrees() {
+        let path = "../resources/test_data/stats.bam";
+        for filter in [
+            RecordFilter::new(),
+            RecordFilter::new().tag_int_max("NM", 1),
+            RecordFilter::new()
+                .tag_int_max("NM", 1)
+                .missing_tag(MissingTag::Pass)
+                .exclude_flags(Flags(flags::DUPLICATE)),
+            RecordFilter::new()
+                .has_tag("AS")
+                .require_flags(Flags(flags::UNMAPPED)),
+            RecordFilter::new().lacks_tag("NM"),
+        ] {
+            let full = BamReader::from_path(path)
+                .unwrap()
+                .map(|r| filter.matches(&r.unwrap()))
+                .collect::<Vec<_>>();
+            let mut reader = BamReader::from_path(path).unwrap();
+            let lazy = reader
+                .lazy_records()
+                .map(|r| filter.matches_lazy(&r.unwrap()).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(full, lazy, "{filter:?}");
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Bitwise FLAG values
+//!
+//! See SAM v1 section 1.4.2. `Flags` is a mask of them parsed from a number or names.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// template having multiple segments in sequencing
+pub const PAIRED: u16 = 0x1;
+/// each segment properly aligned according to the aligner
+pub const PROPER_PAIR: u16 = 0x2;
+/// segment unmapped
+pub const UNMAPPED: u16 = 0x4;
+/// next segment in the template unmapped
+pub const MATE_UNMAPPED: u16 = 0x8;
+/// SEQ being reverse complemented
+pub const REVERSE: u16 = 0x10;
+/// SEQ of the next segment in the template being reverse complemented
+pub const MATE_REVERSE: u16 = 0x20;
+/// the first segment in the template
+pub const READ1: u16 = 0x40;
+/// the last segment in the template
+pub const READ2: u16 = 0x80;
+/// secondary alignment
+pub const SECONDARY: u16 = 0x100;
+/// not passing filters, such as platform/vendor quality controls
+pub const QC_FAIL: u16 = 0x200;
+/// PCR or optical duplicate
+pub const DUPLICATE: u16 = 0x400;
+/// supplementary alignment
+pub const SUPPLEMENTARY: u16 = 0x800;
+
+/// Flag names accepted by `Flags::from_str`, as used by samtools
+const NAMES: [(&str, u16); 12] = [
+    ("PAIRED", PAIRED),
+    ("PROPER_PAIR", PROPER_PAIR),
+    ("UNMAP", UNMAPPED),
+    ("MUNMAP", MATE_UNMAPPED),
+    ("REVERSE", REVERSE),
+    ("MREVERSE", MATE_REVERSE),
+    ("READ1", READ1),
+    ("READ2", READ2),
+    ("SECONDARY", SECONDARY),
+    ("QCFAIL", QC_FAIL),
+    ("DUP", DUPLICATE),
+    ("SUPPLEMENTARY", SUPPLEMENTARY),
+];
+
+/// Errors from parsing a flag mask
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum FlagsError {
+    /// Nothing to parse
+    #[error("empty flag mask")]
+    Empty,
+    /// A number that is not a valid FLAG
+    #[error("invalid flag value '{0}': expected a number below 0x10000")]
+    InvalidNumber(String),
+    /// A name that is not a samtools flag name
+    #[error("unknown flag name '{0}' (expected e.g. UNMAP,SECONDARY or 0x104)")]
+    UnknownName(String),
+}
+
+/// A set of FLAG bits
+///
+/// Parses from a decimal or `0x` hex number, or from comma-separated samtools
+/// names such as `UNMAP,SECONDARY` (case-insensitive).
+///
+/// ```
+/// use lyso_bam::flags::{self, Flags};
+///
+/// let mask: Flags = "unmap,secondary".parse().unwrap();
+/// assert_eq!(mask, "0x104".parse().unwrap());
+/// assert!(mask.any_set(flags::UNMAPPED));
+/// assert!(!mask.all_set(flags::UNMAPPED));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags(pub u16);
+
+impl Flags {
+    /// The raw FLAG bits
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Whether every bit of this mask is set in `flag`
+    pub fn all_set(self, flag: u16) -> bool {
+        flag & self.0 == self.0
+    }
+
+    /// Whether any bit of this mask is set in `flag`
+    pub fn any_set(self, flag: u16) -> bool {
+        flag & self.0 != 0
+    }
+}
+
+impl FromStr for Flags {
+    type Err = FlagsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(FlagsError::Empty);
+        }
+        if s.starts_with(|c: char| c.is_ascii_digit()) {
+            let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => s.parse::<u16>(),
+            };
+            return n
+                .map(Flags)
+                .map_err(|_| FlagsError::InvalidNumber(s.to_string()));
+        }
+        let mut bits = 0;
+        for name in s.split(',').map(str::trim) {
+            match NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                Some((_, bit)) => bits |= bit,
+                None => return Err(FlagsError::UnknownName(name.to_string())),
+            }
+        }
+        Ok(Flags(bits))
+    }
+}
+
+/// `0x` hex, as samtools prints masks
+impl Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!("0x904".parse(), Ok(Flags(0x904)));
+        assert_eq!("2308".parse(), Ok(Flags(0x904)));
+        assert_eq!(
+            "secondary,SUPPLEMENTARY, UNMAP".parse(),
+            Ok(Flags(SECONDARY | SUPPLEMENTARY | UNMAPPED))
+        );
+        assert_eq!(Flags(0x904).to_string(), "0x904");
+        assert!(Flags(0x4).all_set(0x14) && !Flags(0x104).all_set(0x4));
+        assert!(Flags(0x104).any_set(0x4));
+    }
+
+    #[test]
+    fn test_parse_flags_errors() {
+        let err = |s: &str| s.parse::<Flags>().unwrap_err().to_string();
+        assert_eq!(err(""), "empty flag mask");
+        assert_eq!(
+            err("0x10000"),
+            "invalid flag value '0x10000': expected a number below 0x10000"
+        );
+        assert_eq!(
+            err("0xZZ"),
+            "invalid flag value '0xZZ': expected a number below 0x10000"
+        );
+        assert_eq!(
+            err("UNMAP,DUPE"),
+            "unknown flag name 'DUPE' (expected e.g. UNMAP,SECONDARY or 0x104)"
+        );
+    }
+}
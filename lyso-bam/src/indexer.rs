@@ -0,0 +1,37 @@
+//! BAI binning
+
+/// BAI bin of the 0-based, half-open region `[beg, end)`, as in SAMv1 5.3
+///
+/// An unplaced record (`beg == -1`, `end == 0`) gets bin 4680.
+pub fn reg2bin(beg: i64, end: i64) -> u16 {
+    let end = end - 1;
+    // each level starts at ((1 << 3k) - 1) / 7
+    let bin = if beg >> 14 == end >> 14 {
+        4681 + (beg >> 14)
+    } else if beg >> 17 == end >> 17 {
+        585 + (beg >> 17)
+    } else if beg >> 20 == end >> 20 {
+        73 + (beg >> 20)
+    } else if beg >> 23 == end >> 23 {
+        9 + (beg >> 23)
+    } else if beg >> 26 == end >> 26 {
+        1 + (beg >> 26)
+    } else {
+        0
+    };
+    bin as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reg2bin() {
+        assert_eq!(reg2bin(-1, 0), 4680);
+        assert_eq!(reg2bin(0, 1), 4681);
+        assert_eq!(reg2bin(16383, 16384), 4681);
+        assert_eq!(reg2bin(16383, 16385), 585);
+        assert_eq!(reg2bin(0, 1 << 29), 0);
+    }
+}
@@ -0,0 +1,425 @@
+//! Alignment records that decode on demand
+//!
+//! Counting, flag and region work never looks at SEQ, QUAL or aux, yet unpacking
+//! those is most of the cost of a full `Record`. A `LazyRecord` parses only the
+//! fixed-length fields up front and keeps the rest of the block as raw bytes;
+//! CIGAR, SEQ, QUAL and aux are decoded the first time they are asked for and
+//! cached from then on.
+
+use std::borrow::Cow;
+use std::cell::OnceCell;
+
+use lyso_common::pos::ZeroBased;
+use lyso_common::CigarOp;
+
+use crate::parser::{self, Corruption, FixedFields};
+use crate::{Alignment, AuxMap, BamError, BamReference, DecodeError, Record};
+
+/// A BAM alignment record whose variable-length fields are decoded on first access
+#[derive(Debug)]
+pub struct LazyRecord {
+    fixed: FixedFields,
+    /// Read name through aux, exactly as stored
+    block: Vec<u8>,
+    ref_name: String,
+    next_ref_name: String,
+    cigar: OnceCell<Result<Vec<CigarOp>, DecodeError>>,
+    seq: OnceCell<Vec<u8>>,
+    qual: OnceCell<Option<Vec<u8>>>,
+    /// Decoded aux fields, or the field and detail of a `CorruptRecord` error
+    aux: OnceCell<Result<Option<AuxMap>, Corruption>>,
+    /// Treat aux types the spec does not define as corruption
+    strict_aux: bool,
+}
+
+impl LazyRecord {
+    /// Parse the fixed fields of the alignment block at the start of `input`
+    ///
+    /// Returns the input following the block. Length fields are checked against
+    /// block_size here, so later decoding cannot read past the block.
+    pub fn from_block<'a>(
+        input: &'a [u8],
+        references: &[BamReference],
+    ) -> Result<(&'a [u8], Self), BamError> {
+        let (rest, fixed, var) = parser::read_fixed(input)?;
+        let (ref_name, next_ref_name) = parser::ref_names(&fixed, references);
+        Ok((
+            rest,
+            LazyRecord {
+                fixed,
+                block: var.to_vec(),
+                ref_name,
+                next_ref_name,
+                cigar: OnceCell::new(),
+                seq: OnceCell::new(),
+                qual: OnceCell::new(),
+                aux: OnceCell::new(),
+                strict_aux: false,
+            },
+        ))
+    }
+
+    /// See `BamReader::strict_aux`
+    pub(crate) fn strict_aux(mut self, strict: bool) -> Self {
+        self.strict_aux = strict;
+        self
+    }
+
+    fn cigar_start(&self) -> usize {
+        usize::from(self.fixed.l_read_name)
+    }
+
+    fn seq_start(&self) -> usize {
+        self.cigar_start() + usize::from(self.fixed.n_cigar_op) * 4
+    }
+
+    fn qual_start(&self) -> usize {
+        self.seq_start() + self.l_seq_usize().div_ceil(2)
+    }
+
+    fn aux_start(&self) -> usize {
+        self.qual_start() + self.l_seq_usize()
+    }
+
+    fn l_seq_usize(&self) -> usize {
+        // read_fixed has checked that l_seq fits in the block
+        self.fixed.l_seq as usize
+    }
+
+    /// CIGAR as stored, before any long CIGAR correction
+    fn raw_cigar(&self) -> Result<Vec<CigarOp>, DecodeError> {
+        match parser::read_cigar(&self.block[self.cigar_start()..], &self.fixed.n_cigar_op) {
+            Ok((_, cigar)) => Ok(cigar),
+            Err(nom::Err::Failure(parser::RecordError::Decode(e))) => Err(e),
+            // read_fixed has checked that the ops fit in the block
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Whether the stored CIGAR is a placeholder for one kept in the CG aux field
+    fn may_have_long_cigar(&self) -> bool {
+        self.fixed.n_cigar_op == 2
+            && self.raw_cigar().is_ok_and(
+                |c| matches!(c[..], [CigarOp::S(k), CigarOp::N(_)] if k == self.fixed.l_seq),
+            )
+    }
+
+    fn decoded_aux(&self) -> &Result<Option<AuxMap>, Corruption> {
+        self.aux.get_or_init(|| {
+            let mut aux = parser::read_aux(&self.block[self.aux_start()..])?;
+            if self.strict_aux {
+                parser::check_aux_types(aux.as_ref())?;
+            }
+            if let Some(map) = aux.as_mut() {
+                if self.fixed.n_cigar_op == 2 && map.contains_key("CG") {
+                    let mut n_cigar_op = u32::from(self.fixed.n_cigar_op);
+                    let mut cigar = self.raw_cigar().map_err(parser::corruption)?;
+                    parser::maybe_correct_cigar(
+                        &mut n_cigar_op,
+                        self.l_seq_usize(),
+                        &mut cigar,
+                        map,
+                    )
+                    .map_err(parser::corruption)?;
+                    if !map.contains_key("CG") {
+                        let _ = self.cigar.set(Ok(cigar));
+                    }
+                }
+            }
+            Ok(aux)
+        })
+    }
+
+    /// The read name, without its NUL terminator
+    pub fn read_name(&self) -> &[u8] {
+        // read_fixed has checked that the name ends in a NUL
+        &self.block[..self.cigar_start() - 1]
+    }
+
+    /// The read name as text, with invalid UTF-8 replaced by U+FFFD
+    pub fn read_name_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.read_name())
+    }
+
+    /// FLAG bits, see `flags`
+    pub fn flag(&self) -> u16 {
+        self.fixed.flag
+    }
+
+    /// Index of the reference in the header, -1 when unplaced
+    pub fn ref_id(&self) -> i32 {
+        self.fixed.ref_id
+    }
+
+    /// RNAME, "*" when unplaced
+    pub fn ref_name(&self) -> &str {
+        &self.ref_name
+    }
+
+    /// Leftmost mapping position, None when unset (-1 in BAM)
+    pub fn pos(&self) -> Option<ZeroBased> {
+        ZeroBased::try_from(self.fixed.pos).ok()
+    }
+
+    /// POS exactly as stored in the BAM record
+    pub fn raw_pos(&self) -> i32 {
+        self.fixed.pos
+    }
+
+    /// Mapping quality, 255 when unavailable
+    pub fn mapq(&self) -> u8 {
+        self.fixed.mapq
+    }
+
+    /// BAI bin stored in the record
+    pub fn bin(&self) -> u16 {
+        self.fixed.bin
+    }
+
+    /// Index of the mate's reference, -1 when unplaced
+    pub fn next_ref_id(&self) -> i32 {
+        self.fixed.next_ref_id
+    }
+
+    /// RNEXT, "*" when unplaced
+    pub fn next_ref_name(&self) -> &str {
+        &self.next_ref_name
+    }
+
+    /// Mate's leftmost mapping position, None when unset (-1 in BAM)
+    pub fn next_pos(&self) -> Option<ZeroBased> {
+        ZeroBased::try_from(self.fixed.next_pos).ok()
+    }
+
+    /// Observed template length, signed by the leftmost mate
+    pub fn tlen(&self) -> i32 {
+        self.fixed.tlen
+    }
+
+    /// Length of SEQ, 0 when absent
+    pub fn l_seq(&self) -> u32 {
+        self.fixed.l_seq
+    }
+
+    /// Bytes of the record after its block_size field
+    pub fn block_size(&self) -> u32 {
+        self.fixed.block_size
+    }
+
+    /// CIGAR, decoded on first access
+    ///
+    /// A placeholder CIGAR is replaced by the one in the CG aux field, which
+    /// decodes the aux fields as well. Empty if an op is invalid; `try_cigar`
+    /// and `into_record` report that as an error.
+    pub fn cigar(&self) -> &[CigarOp] {
+        self.try_cigar().unwrap_or_default()
+    }
+
+    /// CIGAR, failing with `CorruptRecord` if it holds an invalid op
+    pub fn try_cigar(&self) -> Result<&[CigarOp], BamError> {
+        if self.cigar.get().is_none() && self.may_have_long_cigar() {
+            let _ = self.decoded_aux();
+        }
+        match self.cigar.get_or_init(|| self.raw_cigar()) {
+            Ok(cigar) => Ok(cigar),
+            Err(e) => Err((*e).into()),
+        }
+    }
+
+    /// SEQ as IUPAC bases, decoded on first access
+    pub fn seq(&self) -> &[u8] {
+        self.seq.get_or_init(|| {
+            parser::read_sequence(&self.block[self.seq_start()..], &self.fixed.l_seq)
+                .map(|(_, seq)| seq)
+                .unwrap_or_default()
+        })
+    }
+
+    /// Raw PHRED scores, or None if QUAL is absent ('*')
+    pub fn qual(&self) -> Option<&[u8]> {
+        self.qual
+            .get_or_init(|| {
+                let raw = &self.block[self.qual_start()..self.aux_start()];
+                // absent QUAL is stored as 0xff bytes, or as nothing at all when SEQ is absent
+                if raw.is_empty() || raw.iter().all(|q| q == &255) {
+                    None
+                } else {
+                    Some(raw.to_vec())
+                }
+            })
+            .as_deref()
+    }
+
+    /// Aux fields, decoded on first access
+    ///
+    /// Fails with `CorruptRecord` if they cannot be parsed; the error is
+    /// returned again on every call.
+    pub fn aux(&self) -> Result<Option<&AuxMap>, BamError> {
+        match self.decoded_aux() {
+            Ok(aux) => Ok(aux.as_ref()),
+            Err(e) => Err(parser::corrupt(e.clone())),
+        }
+    }
+
+    /// Decode every remaining field into a full `Record`
+    pub fn into_record(self) -> Result<Record, BamError> {
+        // aux first, since it may replace the CIGAR
+        self.aux()?;
+        self.try_cigar()?;
+        self.seq();
+        self.qual();
+        let read_name = self.read_name().to_vec();
+        let LazyRecord {
+            fixed,
+            ref_name,
+            next_ref_name,
+            cigar,
+            seq,
+            qual,
+            aux,
+            ..
+        } = self;
+        let cigar = cigar.into_inner().and_then(Result::ok).unwrap_or_default();
+        Ok(Record {
+            block_size: fixed.block_size,
+            ref_id: fixed.ref_id,
+            ref_name,
+            pos: fixed.pos,
+            l_read_name: fixed.l_read_name,
+            mapq: fixed.mapq,
+            bin: fixed.bin,
+            n_cigar_op: u32::try_from(cigar.len()).unwrap_or(u32::MAX),
+            flag: fixed.flag,
+            l_seq: fixed.l_seq,
+            next_ref_id: fixed.next_ref_id,
+            next_ref_name,
+            next_pos: fixed.next_pos,
+            tlen: fixed.tlen,
+            read_name,
+            cigar,
+            seq: seq.into_inner().unwrap_or_default(),
+            qual: qual.into_inner().flatten(),
+            aux: aux.into_inner().and_then(Result::ok).flatten(),
+        })
+    }
+}
+
+impl Alignment for LazyRecord {
+    fn flag(&self) -> u16 {
+        self.fixed.flag
+    }
+
+    fn ref_id(&self) -> i32 {
+        self.fixed.ref_id
+    }
+
+    fn next_ref_id(&self) -> i32 {
+        self.fixed.next_ref_id
+    }
+
+    fn raw_pos(&self) -> i32 {
+        self.fixed.pos
+    }
+
+    fn read_name(&self) -> &[u8] {
+        LazyRecord::read_name(self)
+    }
+
+    fn cigar(&self) -> &[CigarOp] {
+        LazyRecord::cigar(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::BamReader;
+
+    const FIXTURES: [&str; 5] = [
+        "bwa_h500.bam",
+        "coverage.bam",
+        "name_sorted.bam",
+        "absent_seq.bam",
+        "latin1_name.bam",
+    ];
+
+    fn open(name: &str) -> BamReader<crate::bgzf::VirtualReader<std::fs::File>> {
+        BamReader::from_path(format!("../resources/test_data/{name}")).unwrap()
+    }
+
+    #[test]
+    fn test_matches_eager_records() {
+        for name in FIXTURES {
+            let eager = open(name).map(|r| r.unwrap()).collect::<Vec<Record>>();
+            let mut reader = open(name);
+            let lazy = reader
+                .lazy_records()
+                .map(|r| r.unwrap())
+                .collect::<Vec<LazyRecord>>();
+            assert_eq!(eager.len(), lazy.len(), "{name}");
+            for (e, l) in eager.iter().zip(lazy) {
+                assert_eq!(e.read_name(), l.read_name(), "{name}");
+                assert_eq!(
+                    (e.flag(), e.ref_id(), e.raw_pos(), e.mapq(), e.tlen()),
+                    (l.flag(), l.ref_id(), l.raw_pos(), l.mapq(), l.tlen())
+                );
+                assert_eq!(
+                    (e.ref_name(), e.next_ref_name()),
+                    (l.ref_name(), l.next_ref_name())
+                );
+                assert_eq!(e.cigar(), l.cigar());
+                assert_eq!(e.seq(), l.seq());
+                assert_eq!(e.qual(), l.qual());
+                assert_eq!(e.aux(), l.aux().unwrap());
+                assert_eq!(e.aligned_blocks(), Alignment::aligned_blocks(&l));
+
+                let full = l.into_record().unwrap();
+                assert_eq!(e, &full);
+                assert_eq!(e.to_string(), full.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_fields_decode_independently() {
+        let mut reader = open("bwa_h500.bam");
+        let rec = reader.lazy_records().next().unwrap().unwrap();
+        assert!(rec.cigar.get().is_none() && rec.seq.get().is_none());
+        rec.flag();
+        rec.read_name();
+        assert!(rec.cigar.get().is_none() && rec.aux.get().is_none());
+        assert_eq!(rec.seq().len(), rec.l_seq() as usize);
+        assert!(rec.cigar.get().is_none() && rec.aux.get().is_none());
+    }
+
+    #[test]
+    fn test_corrupt_aux_is_reported_on_access() {
+        let mut block = Vec::new();
+        let var = b"r\0XBBi\xff\xff\xff\x7f";
+        block.extend((32 + var.len() as u32).to_le_bytes());
+        block.extend((-1i32).to_le_bytes());
+        block.extend((-1i32).to_le_bytes());
+        block.push(2);
+        block.push(0);
+        block.extend(4680u16.to_le_bytes());
+        block.extend(0u16.to_le_bytes());
+        block.extend(4u16.to_le_bytes());
+        block.extend(0u32.to_le_bytes());
+        block.extend((-1i32).to_le_bytes());
+        block.extend((-1i32).to_le_bytes());
+        block.extend(0i32.to_le_bytes());
+        block.extend(var);
+
+        let (rest, rec) = LazyRecord::from_block(&block, &[]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(rec.read_name(), b"r");
+        assert!(rec.cigar().is_empty());
+        for _ in 0..2 {
+            assert!(matches!(
+                rec.aux(),
+                Err(BamError::CorruptRecord { field: "aux", .. })
+            ));
+        }
+        assert!(rec.into_record().is_err());
+    }
+}
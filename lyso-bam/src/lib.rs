@@ -1,38 +1,126 @@
+//! Reading and writing BAM files
+//!
+//! `reader::BamReader` streams alignment records from decompressed BAM;
+//! `BamReader::from_path` opens a BGZF-compressed file. Records decode in full as
+//! `Record`, or field by field as `lazy::LazyRecord`, and `writer::BamWriter`
+//! writes them back out.
+//!
+//! ```
+//! use lyso_bam::reader::BamReader;
+//!
+//! let mut reader = BamReader::from_path("../resources/test_data/stats.bam").unwrap();
+//! let rec = reader.next().unwrap().unwrap();
+//! // the header and references are read along with the first record
+//! assert!(reader.header().is_some());
+//! assert_eq!(rec.ref_name(), reader.references()[0].name());
+//! ```
+
+#![deny(missing_docs)]
+
+pub mod bgzf;
+pub mod builder;
+pub mod check;
+pub mod consensus;
+pub mod count;
+pub mod coverage;
+pub mod filter;
+pub mod flags;
 pub mod indexer;
+pub mod lazy;
+pub mod mismatch;
+pub mod pairs;
 pub mod parser;
+pub mod pileup;
 pub mod reader;
+pub mod sort;
+pub mod split;
+pub mod stats;
+pub mod writer;
 
-use fxhash::FxHashMap;
+use fxhash::FxBuildHasher;
+use indexmap::IndexMap;
+use lyso_common::codec::phred_to_ascii;
+pub use lyso_common::codec::{guess_phred_encoding, PhredEncoding};
+use lyso_common::detect::{Compression, Format};
+use lyso_common::diff::{CanonicalHash, FieldDiff, RecordDiff};
+use lyso_common::error::LysoError;
+use lyso_common::filter::SeqRecord;
+use lyso_common::format::RecordFormatter;
+use lyso_common::pos::{OneBased, ZeroBased};
+use lyso_common::rename::RecordId;
+use lyso_common::validate::Violation;
 use lyso_common::CigarOp;
-use nom::error::ParseError;
+use std::borrow::Cow;
 use std::fmt::{self, Display};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use thiserror::Error;
 
+use crate::parser::MAX_QNAME_LEN;
+
 const BAM_MAGIC_STR: [u8; 4] = [66, 65, 77, 1];
-const MAX_BLOCK_SIZE: usize = 65536;
 
-#[derive(Debug)]
 /// Sequence primitives
 /// See SAM v1 section 4.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BamSeq {
+    /// Matches the reference base (`=`)
     Eq,
+    /// Adenine
     A,
+    /// Cytosine
     C,
+    /// A or C
     M,
+    /// Guanine
     G,
+    /// A or G
     R,
+    /// C or G
     S,
+    /// A, C or G
     V,
+    /// Thymine
     T,
+    /// A or T
     W,
+    /// C or T
     Y,
+    /// A, C or T
     H,
+    /// G or T
     K,
+    /// A, G or T
     D,
+    /// C, G or T
     B,
+    /// Any base
     N,
 }
 
+impl BamSeq {
+    /// IUPAC code as an ASCII byte, `=` for a base matching the reference
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            BamSeq::Eq => b'=',
+            BamSeq::A => b'A',
+            BamSeq::C => b'C',
+            BamSeq::M => b'M',
+            BamSeq::G => b'G',
+            BamSeq::R => b'R',
+            BamSeq::S => b'S',
+            BamSeq::V => b'V',
+            BamSeq::T => b'T',
+            BamSeq::W => b'W',
+            BamSeq::Y => b'Y',
+            BamSeq::H => b'H',
+            BamSeq::K => b'K',
+            BamSeq::D => b'D',
+            BamSeq::B => b'B',
+            BamSeq::N => b'N',
+        }
+    }
+}
+
 impl Display for BamSeq {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -56,27 +144,152 @@ impl Display for BamSeq {
     }
 }
 
+/// Errors reading or decoding BAM
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum BamError {
+    /// Input ended inside an alignment record
     #[error("Unexpected EOF")]
     EofError,
+    /// The input does not start with `BAM\1`
     #[error("Missing BAM Magic String")]
     MissingMagicString,
+    /// Compressed bytes where decompressed BAM was expected
+    #[error("input appears to be {0}-compressed; BAM readers expect decompressed bytes")]
+    Compressed(Compression),
+    /// Input in another format
+    #[error("input looks like {0}, not BAM")]
+    WrongFormat(Format),
+    /// Reading the input failed
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
+    /// Text that should be UTF-8 is not
     #[error("File encoding error")]
     EncodeError(#[from] std::string::FromUtf8Error),
+    /// Input that could not be parsed
     #[error("Parse error")]
     ParseError,
+    /// A count or length that does not fit its type
     #[error("TryFromInt Error")]
     TryFromInt(#[from] std::num::TryFromIntError),
+    /// A record the caller asked to be treated as an error, e.g. an unpaired read
+    #[error("Unexpected {kind} record {name}")]
+    UnexpectedRecord {
+        /// What kind of record it is
+        kind: &'static str,
+        /// Its read name
+        name: String,
+    },
+    /// Too many reads waiting for their mate; holds the limit
+    #[error("More than {0} unmatched mates buffered, input does not appear to be name-sorted")]
+    NotNameSorted(usize),
+    /// A record before its predecessor in coordinate order
+    #[error(
+        "Input is not coordinate-sorted: {name} at {ref_id}:{pos} follows {prev_ref_id}:{prev_pos}"
+    )]
+    NotCoordinateSorted {
+        /// Read name of the out-of-order record
+        name: String,
+        /// Its ref_id
+        ref_id: i32,
+        /// Its 0-based POS
+        pos: i32,
+        /// ref_id of the record before it
+        prev_ref_id: i32,
+        /// POS of the record before it
+        prev_pos: i32,
+    },
+    /// Input with no bytes at all, not even a header
+    #[error("Empty file")]
+    EmptyFile,
+    /// Input ended inside the named part of the file
+    #[error("Truncated file: input ended inside the {0}")]
+    TruncatedFile(&'static str),
+    /// An alignment block longer than `ReaderConfig::max_record_size`
+    #[error("Alignment record longer than {limit} bytes")]
+    RecordTooLarge {
+        /// The limit, in bytes
+        limit: usize,
+    },
+    /// A reference name listed twice in the header
+    #[error("Duplicate reference name {0}")]
+    DuplicateReference(String),
+    /// A record whose fields cannot be decoded
+    #[error("Corrupt record: invalid {field} ({detail})")]
+    CorruptRecord {
+        /// The field at fault
+        field: &'static str,
+        /// What is wrong with it
+        detail: String,
+    },
+    /// A record failing an error-level rule of the reader's `Validator`
+    #[error("record {} fails {}: {0}", .0.record, .0.rule)]
+    RuleViolation(Violation),
+    /// A SEQ with '=' bases that cannot be replaced by reference bases
+    #[error("Cannot resolve '=' bases of {name}: {reason}")]
+    UnresolvedBase {
+        /// Read name of the record
+        name: String,
+        /// Why its bases cannot be placed
+        reason: String,
+    },
+    /// An MD tag that is missing, malformed or disagrees with the CIGAR
+    #[error("Invalid MD tag of {name}: {reason}")]
+    InvalidMdTag {
+        /// Read name of the record
+        name: String,
+        /// What is wrong with the tag
+        reason: String,
+    },
+    /// A SAM `TAG:TYPE:VALUE` aux field that does not follow the spec
+    #[error("Invalid SAM aux field '{field}': {reason}")]
+    InvalidAuxField {
+        /// The field as given
+        field: String,
+        /// What is wrong with it
+        reason: &'static str,
+    },
+}
+
+impl From<BamError> for LysoError {
+    fn from(value: BamError) -> Self {
+        LysoError::format(Format::Bam, value)
+    }
+}
+
+impl From<flags::FlagsError> for LysoError {
+    fn from(value: flags::FlagsError) -> Self {
+        LysoError::format(Format::Bam, value)
+    }
+}
+
+/// A record field holding a code with no meaning in the BAM spec
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("invalid {field}: unexpected byte {code:#04x}")]
+pub struct DecodeError {
+    /// Name of the field, as in the SAM spec
+    pub field: &'static str,
+    /// The byte found there
+    pub code: u8,
+}
+
+impl From<DecodeError> for BamError {
+    fn from(value: DecodeError) -> Self {
+        parser::corrupt(parser::corruption(value))
+    }
+}
+
+impl<'a> From<nom::Err<nom::error::Error<&'a [u8]>>> for BamError {
+    fn from(_: nom::Err<nom::error::Error<&'a [u8]>>) -> Self {
+        BamError::ParseError
+    }
 }
 
 /// Auxilliary BAM field
 ///
 /// arbitrary tag names are supported but must be of length 2
 /// See BamAuxValue for possible value types.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BamAuxField {
     tag: [char; 2],
     value: BamAuxValue,
@@ -88,30 +301,203 @@ impl Display for BamAuxField {
     }
 }
 
+impl BamAuxField {
+    /// Parse a SAM aux field such as `NM:i:5` or `ML:B:C,1,2,3`
+    ///
+    /// The value must match the SAM v1 section 1.5 regex for its type. An `i`
+    /// value takes the smallest integer type that holds it, unsigned when it is
+    /// not negative, as htslib does.
+    pub fn from_sam_str(field: &str) -> Result<Self, BamError> {
+        let fail = |reason| BamError::InvalidAuxField {
+            field: field.to_string(),
+            reason,
+        };
+        let (tag, rest) = field
+            .split_at_checked(2)
+            .ok_or_else(|| fail("missing tag"))?;
+        let tag = match tag.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => {
+                [char::from(*a), char::from(*b)]
+            }
+            _ => return Err(fail("tag must be a letter followed by a letter or digit")),
+        };
+        let (dtype, value) = match rest.as_bytes() {
+            [b':', dtype, b':', ..] => (*dtype, &rest[3..]),
+            _ => return Err(fail("expected TAG:TYPE:VALUE")),
+        };
+        let value = match dtype {
+            b'A' => match value.as_bytes() {
+                [c @ b'!'..=b'~'] => BamAuxValue::A(char::from(*c)),
+                _ => return Err(fail("A value must be one printable character")),
+            },
+            b'i' => {
+                if !is_sam_int(value) {
+                    return Err(fail("i value must be an integer"));
+                }
+                value
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(smallest_int)
+                    .ok_or_else(|| fail("i value is out of 32-bit range"))?
+            }
+            b'f' => BamAuxValue::f(
+                parse_sam_float(value).ok_or_else(|| fail("f value must be a number"))?,
+            ),
+            b'Z' => {
+                if !value.bytes().all(|b| matches!(b, b' '..=b'~')) {
+                    return Err(fail("Z value must be printable"));
+                }
+                BamAuxValue::Z(value.to_string())
+            }
+            b'H' => BamAuxValue::H(
+                decode_hex(value.as_bytes())
+                    .ok()
+                    .ok_or_else(|| fail("H value must be pairs of uppercase hex digits"))?,
+            ),
+            b'B' => {
+                let mut elems = value.split(',');
+                let sub = elems.next().unwrap_or_default();
+                fn array<T: std::str::FromStr>(
+                    elems: std::str::Split<'_, char>,
+                    valid: fn(&str) -> bool,
+                ) -> Option<Vec<T>> {
+                    elems
+                        .map(|e| valid(e).then(|| e.parse().ok()).flatten())
+                        .collect()
+                }
+                let bad = || fail("B value has an element its subtype cannot hold");
+                match sub {
+                    "c" => BamAuxValue::Bc(array(elems, is_sam_int).ok_or_else(bad)?),
+                    "C" => BamAuxValue::BC(array(elems, is_sam_int).ok_or_else(bad)?),
+                    "s" => BamAuxValue::Bs(array(elems, is_sam_int).ok_or_else(bad)?),
+                    "S" => BamAuxValue::BS(array(elems, is_sam_int).ok_or_else(bad)?),
+                    "i" => BamAuxValue::Bi(array(elems, is_sam_int).ok_or_else(bad)?),
+                    "I" => BamAuxValue::BI(array(elems, is_sam_int).ok_or_else(bad)?),
+                    "f" => BamAuxValue::Bf(array(elems, is_sam_float).ok_or_else(bad)?),
+                    _ => return Err(fail("B subtype must be one of cCsSiIf")),
+                }
+            }
+            _ => return Err(fail("type must be one of AifZHB")),
+        };
+        Ok(BamAuxField { tag, value })
+    }
+
+    /// The field in SAM format, as written by `Display`
+    ///
+    /// `from_sam_str` reads this back to an equal field, except that integers
+    /// take their smallest type and `Unknown` values cannot be read at all.
+    pub fn to_sam_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// `[-+]?[0-9]+`
+fn is_sam_int(v: &str) -> bool {
+    let digits = v.strip_prefix(['-', '+']).unwrap_or(v);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// `[-+]?[0-9]*\.?[0-9]+([eE][-+]?[0-9]+)?`
+fn is_sam_float(v: &str) -> bool {
+    let (mantissa, exponent) = match v.split_once(['e', 'E']) {
+        Some((m, e)) => (m, Some(e)),
+        None => (v, None),
+    };
+    let mantissa = mantissa.strip_prefix(['-', '+']).unwrap_or(mantissa);
+    let (int, frac) = mantissa.split_once('.').unwrap_or(("", mantissa));
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    !frac.is_empty() && digits(int) && digits(frac) && exponent.is_none_or(is_sam_int)
+}
+
+fn parse_sam_float(v: &str) -> Option<f32> {
+    is_sam_float(v).then(|| v.parse().ok()).flatten()
+}
+
+/// An `i` value in the smallest type that holds it, `None` past 32 bits
+fn smallest_int(v: i64) -> Option<BamAuxValue> {
+    let value = if v < 0 {
+        i8::try_from(v)
+            .map(BamAuxValue::c)
+            .or_else(|_| i16::try_from(v).map(BamAuxValue::s))
+            .or_else(|_| i32::try_from(v).map(BamAuxValue::i))
+    } else {
+        u8::try_from(v)
+            .map(BamAuxValue::C)
+            .or_else(|_| u16::try_from(v).map(BamAuxValue::S))
+            .or_else(|_| u32::try_from(v).map(BamAuxValue::I))
+    };
+    value.ok()
+}
+
+/// Bytes of uppercase hex digit pairs, or the first byte that is not one
+pub(crate) fn decode_hex(hex: &[u8]) -> Result<Vec<u8>, u8> {
+    let digit = |b: u8| match b {
+        b'0'..=b'9' => Ok(b - b'0'),
+        b'A'..=b'F' => Ok(b - b'A' + 10),
+        _ => Err(b),
+    };
+    if let Some(last) = hex.chunks_exact(2).remainder().first() {
+        // an odd digit count is reported at the unpaired digit
+        digit(*last)?;
+        return Err(*last);
+    }
+    hex.chunks_exact(2)
+        .map(|p| Ok(digit(p[0])? << 4 | digit(p[1])?))
+        .collect()
+}
+
 /// Auxilliary BAM value encodings
 ///
 /// Display implementation will write in SAM format.
 /// See SAM v1 section 4.2.4
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BamAuxValue {
+    /// Printable character
     A(char),
+    /// int8_t
     c(i8),
+    /// uint8_t
     C(u8),
+    /// int16_t
     s(i16),
+    /// uint16_t
     S(u16),
+    /// int32_t
     i(i32),
+    /// uint32_t
     I(u32),
+    /// Single-precision float
     f(f32),
+    /// Printable string
     Z(String),
-    H(Vec<u32>),
+    /// Byte array, written as pairs of hex digits
+    H(Vec<u8>),
+    /// int8_t array
     Bc(Vec<i8>),
+    /// uint8_t array
     BC(Vec<u8>),
+    /// int16_t array
     Bs(Vec<i16>),
+    /// uint16_t array
     BS(Vec<u16>),
+    /// int32_t array
     Bi(Vec<i32>),
+    /// uint32_t array
     BI(Vec<u32>),
+    /// float array
     Bf(Vec<f32>),
+    /// A type code (or `B` array subtype) the spec does not define
+    ///
+    /// The size of such a value cannot be known, so `raw` holds everything after
+    /// `dtype` to the end of the block, including any fields that follow. For an
+    /// array, `dtype` is `b'B'` and `raw` starts at the subtype.
+    Unknown {
+        /// The type code
+        dtype: u8,
+        /// The bytes after it, to the end of the block
+        raw: Vec<u8>,
+    },
 }
 
 /// All integer types are 'i' in SAM format
@@ -125,8 +511,48 @@ impl Display for BamAuxValue {
             BamAuxValue::S(v) => write!(f, "i:{v}"),
             BamAuxValue::i(v) => write!(f, "i:{v}"),
             BamAuxValue::I(v) => write!(f, "i:{v}"),
+            BamAuxValue::f(v) => write!(f, "f:{v}"),
             BamAuxValue::Z(v) => write!(f, "Z:{v}"),
-            _ => todo!(),
+            BamAuxValue::H(v) => {
+                write!(f, "H:")?;
+                v.iter().try_for_each(|x| write!(f, "{x:02X}"))
+            }
+            BamAuxValue::Bc(v) => write_array(f, 'c', v),
+            BamAuxValue::BC(v) => write_array(f, 'C', v),
+            BamAuxValue::Bs(v) => write_array(f, 's', v),
+            BamAuxValue::BS(v) => write_array(f, 'S', v),
+            BamAuxValue::Bi(v) => write_array(f, 'i', v),
+            BamAuxValue::BI(v) => write_array(f, 'I', v),
+            BamAuxValue::Bf(v) => write_array(f, 'f', v),
+            BamAuxValue::Unknown { dtype, raw } => {
+                write!(
+                    f,
+                    "{}:<{} undecoded bytes>",
+                    dtype.escape_ascii(),
+                    raw.len()
+                )
+            }
+        }
+    }
+}
+
+/// `B:<subtype>,v1,v2,...`
+fn write_array<T: Display>(f: &mut fmt::Formatter<'_>, subtype: char, values: &[T]) -> fmt::Result {
+    write!(f, "B:{subtype}")?;
+    values.iter().try_for_each(|v| write!(f, ",{v}"))
+}
+
+impl BamAuxValue {
+    /// Value of any integer type (c, C, s, S, i or I)
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BamAuxValue::c(v) => Some(i64::from(*v)),
+            BamAuxValue::C(v) => Some(i64::from(*v)),
+            BamAuxValue::s(v) => Some(i64::from(*v)),
+            BamAuxValue::S(v) => Some(i64::from(*v)),
+            BamAuxValue::i(v) => Some(i64::from(*v)),
+            BamAuxValue::I(v) => Some(i64::from(*v)),
+            _ => None,
         }
     }
 }
@@ -227,8 +653,11 @@ impl From<Vec<f32>> for BamAuxValue {
     }
 }
 
+/// Aux fields keyed by tag, in the order they appear in the record
+pub type AuxMap = IndexMap<String, BamAuxField, FxBuildHasher>;
+
 /// A BAM alignment record
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Record {
     block_size: u32,
     ref_id: i32,
@@ -237,18 +666,19 @@ pub struct Record {
     l_read_name: u8,
     mapq: u8,
     bin: u16,
-    n_cigar_op: u16,
+    n_cigar_op: u32,
     flag: u16,
     l_seq: u32,
     next_ref_id: i32,
     next_ref_name: String,
     next_pos: i32,
     tlen: i32,
-    read_name: String,
+    read_name: Vec<u8>,
     cigar: Vec<CigarOp>,
-    seq: Vec<BamSeq>,
+    /// IUPAC bases
+    seq: Vec<u8>,
     qual: Option<Vec<u8>>,
-    aux: Option<FxHashMap<String, BamAuxField>>, // everything else
+    aux: Option<AuxMap>, // everything else
 }
 
 impl Display for Record {
@@ -256,41 +686,621 @@ impl Display for Record {
         write!(
             f,
             "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-            self.read_name,
+            self.read_name_str_lossy(),
             self.flag,
             self.ref_name,
-            self.pos + 1, // SAM is 1-based
+            i64::from(self.pos) + 1, // SAM is 1-based
             self.mapq,
-            self.cigar.iter().map(|x| x.to_string()).collect::<String>(),
+            self.cigar_string(),
             self.next_ref_name,
-            self.next_pos + 1,
+            i64::from(self.next_pos) + 1,
             self.tlen,
-            self.seq.iter().map(|x| x.to_string()).collect::<String>(),
-            std::str::from_utf8(
-                self.qual
-                    .as_ref()
-                    .unwrap_or(&vec![42u8; 1])
-                    .iter()
-                    .map(|x| x + 33)
-                    .collect::<Vec<u8>>()
-                    .as_ref()
-            )
-            .unwrap_or("*")
-        )
-        .unwrap();
-        if self.aux.is_some() {
-            for val in self.aux.as_ref().unwrap().values() {
-                write!(f, "\t{val}").unwrap();
+            self.seq_string(),
+            self.qual_string(),
+        )?;
+        if let Some(aux) = &self.aux {
+            for val in aux.values() {
+                write!(f, "\t{val}")?;
             }
         }
         Ok(())
     }
 }
 
+impl Record {
+    /// SEQ as text, "*" when absent (l_seq == 0)
+    pub fn seq_string(&self) -> String {
+        if self.seq.is_empty() {
+            return String::from("*");
+        }
+        String::from_utf8_lossy(&self.seq).into_owned()
+    }
+
+    /// QUAL as Phred+33 text, "*" when absent; scores above 93 are written as '~'
+    pub fn qual_string(&self) -> String {
+        match &self.qual {
+            Some(q) if !q.is_empty() => {
+                let mut text = Vec::with_capacity(q.len());
+                phred_to_ascii(q, &mut text);
+                String::from_utf8_lossy(&text).into_owned()
+            }
+            _ => String::from("*"),
+        }
+    }
+
+    /// CIGAR as text, "*" when there are no operations
+    pub fn cigar_string(&self) -> String {
+        if self.cigar.is_empty() {
+            return String::from("*");
+        }
+        self.cigar.iter().map(|x| x.to_string()).collect()
+    }
+
+    /// The read name as stored in the file, without its NUL terminator
+    pub fn read_name(&self) -> &[u8] {
+        &self.read_name
+    }
+
+    /// The read name as text, with invalid UTF-8 replaced by U+FFFD
+    pub fn read_name_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.read_name)
+    }
+
+    /// FLAG bits, see `flags`
+    pub fn flag(&self) -> u16 {
+        self.flag
+    }
+
+    /// Index of the reference in the header, -1 when unplaced
+    pub fn ref_id(&self) -> i32 {
+        self.ref_id
+    }
+
+    /// RNAME, "*" when unplaced
+    pub fn ref_name(&self) -> &str {
+        self.ref_name.as_ref()
+    }
+
+    /// Leftmost mapping position, None when unset (-1 in BAM)
+    pub fn pos(&self) -> Option<ZeroBased> {
+        ZeroBased::try_from(self.pos).ok()
+    }
+
+    /// `pos()` as it would appear in the SAM POS column
+    pub fn pos_1based(&self) -> Option<OneBased> {
+        self.pos().and_then(|p| p.to_one_based().ok())
+    }
+
+    /// POS exactly as stored in the BAM record
+    pub fn raw_pos(&self) -> i32 {
+        self.pos
+    }
+
+    /// Length of the stored read name, counting its NUL terminator
+    pub fn l_read_name(&self) -> u8 {
+        self.l_read_name
+    }
+
+    /// Mapping quality, 255 when unavailable
+    pub fn mapq(&self) -> u8 {
+        self.mapq
+    }
+
+    /// BAI bin stored in the record
+    pub fn bin(&self) -> u16 {
+        self.bin
+    }
+
+    /// Number of CIGAR operations, counting those restored from a CG tag
+    pub fn n_cigar_op(&self) -> u32 {
+        self.n_cigar_op
+    }
+
+    /// CIGAR operations
+    pub fn cigar(&self) -> &[CigarOp] {
+        &self.cigar
+    }
+
+    /// Index of the mate's reference, -1 when unplaced
+    pub fn next_ref_id(&self) -> i32 {
+        self.next_ref_id
+    }
+
+    /// RNEXT, "*" when unplaced
+    pub fn next_ref_name(&self) -> &str {
+        self.next_ref_name.as_ref()
+    }
+
+    /// Mate's leftmost mapping position, None when unset (-1 in BAM)
+    pub fn next_pos(&self) -> Option<ZeroBased> {
+        ZeroBased::try_from(self.next_pos).ok()
+    }
+
+    /// `next_pos()` as it would appear in the SAM PNEXT column
+    pub fn next_pos_1based(&self) -> Option<OneBased> {
+        self.next_pos().and_then(|p| p.to_one_based().ok())
+    }
+
+    /// PNEXT exactly as stored in the BAM record
+    pub fn raw_next_pos(&self) -> i32 {
+        self.next_pos
+    }
+
+    /// Observed template length, signed by the leftmost mate
+    pub fn tlen(&self) -> i32 {
+        self.tlen
+    }
+
+    /// Length of SEQ, 0 when absent
+    pub fn l_seq(&self) -> u32 {
+        self.l_seq
+    }
+
+    /// SEQ as upper-case IUPAC bases, with '=' for bases matching the reference
+    pub fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+
+    /// SEQ with every '=' replaced by the reference base it stands for
+    ///
+    /// `reference_bases` is the whole sequence of the record's reference; bases are
+    /// placed on it through POS and the CIGAR. A SEQ without '=' needs no reference.
+    /// Fails when a '=' can't be placed: no reference was given, the record is
+    /// unplaced, or the base falls past the reference or outside an M or = operation.
+    pub fn seq_resolved(&self, reference_bases: Option<&[u8]>) -> Result<Vec<u8>, BamError> {
+        let mut seq = self.seq.clone();
+        if !seq.contains(&b'=') {
+            return Ok(seq);
+        }
+        let fail = |reason: String| BamError::UnresolvedBase {
+            name: self.read_name_str_lossy().into_owned(),
+            reason,
+        };
+        let reference = reference_bases.ok_or_else(|| fail("no reference given".into()))?;
+        let mut r = self
+            .pos()
+            .ok_or_else(|| fail("record is unplaced".into()))?
+            .get() as usize;
+        let mut q = 0;
+        for op in &self.cigar {
+            let len = op.len() as usize;
+            let end = (q + len).min(seq.len());
+            if matches!(op, CigarOp::M(_) | CigarOp::Eq(_)) {
+                for (i, b) in seq[q.min(end)..end].iter_mut().enumerate() {
+                    if *b == b'=' {
+                        let at = r + i;
+                        let base = reference.get(at).ok_or_else(|| {
+                            fail(format!("base {} maps past the reference end ({at})", q + i))
+                        })?;
+                        *b = base.to_ascii_uppercase();
+                    }
+                }
+            } else if op.consumes_query() {
+                if let Some(i) = seq[q.min(end)..end].iter().position(|b| *b == b'=') {
+                    return Err(fail(format!("base {} lies in {op}", q + i)));
+                }
+            }
+            if op.consumes_query() {
+                q += len;
+            }
+            if op.consumes_ref() {
+                r += len;
+            }
+        }
+        match seq.iter().position(|b| *b == b'=') {
+            Some(i) => Err(fail(format!("base {i} is past the end of the CIGAR"))),
+            None => Ok(seq),
+        }
+    }
+
+    /// Raw PHRED scores, or None if QUAL is absent ('*')
+    pub fn qual(&self) -> Option<&[u8]> {
+        self.qual.as_deref()
+    }
+
+    /// Aux fields in record order, None when the record has none
+    pub fn aux(&self) -> Option<&AuxMap> {
+        self.aux.as_ref()
+    }
+
+    /// Remove aux field `tag`, keeping the order of the others
+    pub fn remove_aux(&mut self, tag: &str) -> Option<BamAuxField> {
+        self.aux.as_mut()?.shift_remove(tag)
+    }
+
+    /// Integer value of aux field `tag`, None if absent or not an integer
+    pub fn aux_int(&self, tag: &str) -> Option<i64> {
+        self.aux.as_ref()?.get(tag)?.value.as_int()
+    }
+
+    /// String value of aux field `tag`, None if absent or not a string (Z)
+    pub fn aux_str(&self, tag: &str) -> Option<&str> {
+        match &self.aux.as_ref()?.get(tag)?.value {
+            BamAuxValue::Z(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Bytes of the record after its block_size field
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// 0x1, the template has several segments
+    pub fn is_paired(&self) -> bool {
+        self.flag & flags::PAIRED != 0
+    }
+
+    /// 0x2, every segment is properly aligned
+    pub fn is_proper_pair(&self) -> bool {
+        self.flag & flags::PROPER_PAIR != 0
+    }
+
+    /// 0x4, the read is unmapped
+    pub fn is_unmapped(&self) -> bool {
+        self.flag & flags::UNMAPPED != 0
+    }
+
+    /// 0x8, the mate is unmapped
+    pub fn is_mate_unmapped(&self) -> bool {
+        self.flag & flags::MATE_UNMAPPED != 0
+    }
+
+    /// 0x10, the read is reverse complemented
+    pub fn is_reverse(&self) -> bool {
+        self.flag & flags::REVERSE != 0
+    }
+
+    /// 0x20, the mate is reverse complemented
+    pub fn is_mate_reverse(&self) -> bool {
+        self.flag & flags::MATE_REVERSE != 0
+    }
+
+    /// 0x40, first segment of the template
+    pub fn is_read1(&self) -> bool {
+        self.flag & flags::READ1 != 0
+    }
+
+    /// 0x80, last segment of the template
+    pub fn is_read2(&self) -> bool {
+        self.flag & flags::READ2 != 0
+    }
+
+    /// 0x100, a secondary alignment
+    pub fn is_secondary(&self) -> bool {
+        self.flag & flags::SECONDARY != 0
+    }
+
+    /// 0x200, failed quality checks
+    pub fn is_qc_fail(&self) -> bool {
+        self.flag & flags::QC_FAIL != 0
+    }
+
+    /// 0x400, a PCR or optical duplicate
+    pub fn is_duplicate(&self) -> bool {
+        self.flag & flags::DUPLICATE != 0
+    }
+
+    /// 0x800, a supplementary alignment
+    pub fn is_supplementary(&self) -> bool {
+        self.flag & flags::SUPPLEMENTARY != 0
+    }
+
+    /// Neither secondary nor supplementary
+    pub fn is_primary(&self) -> bool {
+        !self.is_secondary() && !self.is_supplementary()
+    }
+
+    /// Position one past the last reference base covered, None when unplaced
+    ///
+    /// A placed record without reference-consuming operations covers one base, as in
+    /// the BAI binning scheme.
+    pub fn ref_end(&self) -> Option<ZeroBased> {
+        Alignment::ref_end(self)
+    }
+
+    /// Reference intervals `[start, end)` of the M, = and X operations
+    ///
+    /// Adjacent blocks (e.g. either side of an insertion) are merged.
+    pub fn aligned_blocks(&self) -> Vec<(u64, u64)> {
+        Alignment::aligned_blocks(self)
+    }
+}
+
+/// Placement fields shared by `Record` and `lazy::LazyRecord`
+///
+/// Code that only needs flags, position and CIGAR can take either record type.
+pub trait Alignment {
+    /// FLAG bits, see `flags`
+    fn flag(&self) -> u16;
+
+    /// Index of the reference in the header, -1 when unplaced
+    fn ref_id(&self) -> i32;
+
+    /// Index of the mate's reference in the header, -1 when unplaced
+    ///
+    /// Defaults to -1, for alignments that do not track their mate.
+    fn next_ref_id(&self) -> i32 {
+        -1
+    }
+
+    /// POS exactly as stored in the BAM record
+    fn raw_pos(&self) -> i32;
+
+    /// Read name without its NUL terminator
+    fn read_name(&self) -> &[u8];
+
+    /// CIGAR operations
+    fn cigar(&self) -> &[CigarOp];
+
+    /// Leftmost mapping position, None when unset (-1 in BAM)
+    fn pos(&self) -> Option<ZeroBased> {
+        ZeroBased::try_from(self.raw_pos()).ok()
+    }
+
+    /// The read is unmapped
+    fn is_unmapped(&self) -> bool {
+        self.flag() & flags::UNMAPPED != 0
+    }
+
+    /// A secondary alignment
+    fn is_secondary(&self) -> bool {
+        self.flag() & flags::SECONDARY != 0
+    }
+
+    /// Failed quality checks
+    fn is_qc_fail(&self) -> bool {
+        self.flag() & flags::QC_FAIL != 0
+    }
+
+    /// A PCR or optical duplicate
+    fn is_duplicate(&self) -> bool {
+        self.flag() & flags::DUPLICATE != 0
+    }
+
+    /// A supplementary alignment
+    fn is_supplementary(&self) -> bool {
+        self.flag() & flags::SUPPLEMENTARY != 0
+    }
+
+    /// Position one past the last reference base covered, None when unplaced
+    fn ref_end(&self) -> Option<ZeroBased> {
+        let span = lyso_common::ref_span(self.cigar()).max(1);
+        self.pos().map(|p| ZeroBased::new(p.get() + span))
+    }
+
+    /// Reference intervals `[start, end)` of the M, = and X operations, adjacent blocks merged
+    fn aligned_blocks(&self) -> Vec<(u64, u64)> {
+        let mut blocks: Vec<(u64, u64)> = Vec::new();
+        let mut at = match self.pos() {
+            Some(p) => p.get(),
+            None => return blocks,
+        };
+        for op in self.cigar() {
+            let len = u64::from(op.len());
+            if op.is_aligned() && len > 0 {
+                match blocks.last_mut() {
+                    Some(last) if last.1 == at => last.1 += len,
+                    _ => blocks.push((at, at + len)),
+                }
+            }
+            if op.consumes_ref() {
+                at += len;
+            }
+        }
+        blocks
+    }
+}
+
+impl Alignment for Record {
+    fn flag(&self) -> u16 {
+        self.flag
+    }
+
+    fn ref_id(&self) -> i32 {
+        self.ref_id
+    }
+
+    fn next_ref_id(&self) -> i32 {
+        self.next_ref_id
+    }
+
+    fn raw_pos(&self) -> i32 {
+        self.pos
+    }
+
+    fn read_name(&self) -> &[u8] {
+        &self.read_name
+    }
+
+    fn cigar(&self) -> &[CigarOp] {
+        &self.cigar
+    }
+}
+
+/// The read name; setting it keeps l_read_name and block_size in step
+///
+/// A name longer than `MAX_QNAME_LEN`, which l_read_name cannot count, is cut
+/// to that length.
+impl RecordId for Record {
+    fn record_id(&self) -> &[u8] {
+        &self.read_name
+    }
+
+    fn set_record_id(&mut self, mut id: Vec<u8>) {
+        let l_read_name = fit_read_name(&mut id);
+        self.block_size = self.block_size - u32::from(self.l_read_name) + u32::from(l_read_name);
+        self.l_read_name = l_read_name;
+        self.read_name = id;
+    }
+}
+
+/// Cut `name` to `MAX_QNAME_LEN` bytes and return its l_read_name, NUL included
+pub(crate) fn fit_read_name(name: &mut Vec<u8>) -> u8 {
+    name.truncate(MAX_QNAME_LEN);
+    u8::try_from(name.len() + 1).expect("MAX_QNAME_LEN + 1 fits in a u8")
+}
+
+/// SEQ as IUPAC bases; QUAL is stored as raw scores, not Phred+33, so is left out
+impl SeqRecord for Record {
+    fn seq_bytes(&self) -> &[u8] {
+        &self.seq
+    }
+
+    fn qual_bytes(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// The first six SAM columns; POS is 1-based (0 when unset) and an empty CIGAR is "*"
+impl RecordFormatter for Record {
+    const COLUMNS: &'static [&'static str] = &["qname", "flag", "rname", "pos", "mapq", "cigar"];
+
+    /// Records without a position print "*" for pos
+    fn write_tsv(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        write!(
+            out,
+            "{}\t{}\t{}\t",
+            self.read_name_str_lossy(),
+            self.flag,
+            self.ref_name
+        )?;
+        match self.pos_1based() {
+            Some(pos) => write!(out, "{}\t", pos.get())?,
+            None => out.write_str("*\t")?,
+        }
+        write!(out, "{}\t{}", self.mapq, self.cigar_string())
+    }
+}
+
+/// Semantic equality
+///
+/// Compares the content a SAM line would show; fields derived from others
+/// (block_size, bin, the length fields and resolved reference names) are ignored,
+/// and aux fields compare as a set regardless of their order in the record.
+impl PartialEq for Record {
+    fn eq(&self, other: &Self) -> bool {
+        self.read_name == other.read_name
+            && self.flag == other.flag
+            && self.ref_id == other.ref_id
+            && self.pos == other.pos
+            && self.mapq == other.mapq
+            && self.cigar == other.cigar
+            && self.next_ref_id == other.next_ref_id
+            && self.next_pos == other.next_pos
+            && self.tlen == other.tlen
+            && self.seq == other.seq
+            && self.qual == other.qual
+            && self.aux_eq(other)
+    }
+}
+
+impl Record {
+    /// Missing aux and an empty aux map are equivalent
+    fn aux_eq(&self, other: &Self) -> bool {
+        match (&self.aux, &other.aux) {
+            (Some(a), Some(b)) => a == b,
+            (Some(a), None) | (None, Some(a)) => a.is_empty(),
+            (None, None) => true,
+        }
+    }
+
+    fn aux_diffs(&self, other: &Self) -> Vec<FieldDiff> {
+        let empty = AuxMap::default();
+        let a = self.aux.as_ref().unwrap_or(&empty);
+        let b = other.aux.as_ref().unwrap_or(&empty);
+        let mut tags = a.keys().chain(b.keys()).collect::<Vec<&String>>();
+        tags.sort();
+        tags.dedup();
+        tags.into_iter()
+            .filter(|t| a.get(*t) != b.get(*t))
+            .map(|t| {
+                let show =
+                    |f: Option<&BamAuxField>| f.map_or("<absent>".to_string(), |f| f.to_string());
+                FieldDiff::new("aux", show(a.get(t)), show(b.get(t)))
+            })
+            .collect()
+    }
+}
+
+impl RecordDiff for Record {
+    fn diff(&self, other: &Self) -> Vec<FieldDiff> {
+        let mut diffs = Vec::new();
+        let mut cmp = |field, l: String, r: String| {
+            if l != r {
+                diffs.push(FieldDiff::new(field, l, r));
+            }
+        };
+        cmp("flag", self.flag.to_string(), other.flag.to_string());
+        cmp("ref_id", self.ref_id.to_string(), other.ref_id.to_string());
+        cmp("pos", self.pos.to_string(), other.pos.to_string());
+        cmp("mapq", self.mapq.to_string(), other.mapq.to_string());
+        cmp("cigar", self.cigar_string(), other.cigar_string());
+        cmp(
+            "next_ref_id",
+            self.next_ref_id.to_string(),
+            other.next_ref_id.to_string(),
+        );
+        cmp(
+            "next_pos",
+            self.next_pos.to_string(),
+            other.next_pos.to_string(),
+        );
+        cmp("tlen", self.tlen.to_string(), other.tlen.to_string());
+
+        let mut diffs = [
+            FieldDiff::bytes("read_name", &self.read_name, &other.read_name),
+            FieldDiff::bytes(
+                "seq",
+                self.seq_string().as_bytes(),
+                other.seq_string().as_bytes(),
+            ),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(diffs)
+        .collect::<Vec<FieldDiff>>();
+        if self.qual != other.qual {
+            diffs.push(match (&self.qual, &other.qual) {
+                (Some(a), Some(b)) => FieldDiff::bytes("qual", a, b).unwrap(),
+                (a, b) => FieldDiff::new(
+                    "qual",
+                    if a.is_some() { "<present>" } else { "*" },
+                    if b.is_some() { "<present>" } else { "*" },
+                ),
+            });
+        }
+        diffs.extend(self.aux_diffs(other));
+        diffs
+    }
+
+    fn label(&self) -> String {
+        self.read_name_str_lossy().into_owned()
+    }
+}
+
+/// Hashes the fields equality compares, with aux fields in tag order as their SAM text
+impl CanonicalHash for Record {
+    fn canonical_hash(&self) -> u64 {
+        let mut h = DefaultHasher::new();
+        (&self.read_name, self.flag, self.ref_id, self.pos, self.mapq).hash(&mut h);
+        (&self.cigar, self.next_ref_id, self.next_pos, self.tlen).hash(&mut h);
+        (&self.seq, &self.qual).hash(&mut h);
+        if let Some(aux) = &self.aux {
+            let mut fields = aux.values().collect::<Vec<&BamAuxField>>();
+            fields.sort_by_key(|f| f.tag);
+            for f in fields {
+                f.to_string().hash(&mut h);
+            }
+        }
+        h.finish()
+    }
+}
+
 /// Representation of BAM Reference record
 ///
 /// Display implementation will write in SAM format.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BamReference {
     name: String,
     l_ref: u32,
@@ -298,29 +1308,515 @@ pub struct BamReference {
 
 /// Representation of BAM header field
 ///
-/// Display implementation will write in SAM format.
-#[derive(Debug)]
+/// The text is kept exactly as read (l_text bytes, including any trailing NULs or
+/// non-UTF-8 bytes) so a writer can reproduce it byte for byte.
+#[derive(Debug, Clone, PartialEq)]
 pub struct BamHeader {
-    text: String,
+    text: Vec<u8>,
     n_ref: u32,
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-pub enum PhredEncoding {
-    #[default]
-    Phred33 = 33,
-    Phred64 = 64,
-    Unknown = 0,
+impl BamReference {
+    /// A reference called `name`, `l_ref` bases long
+    #[must_use]
+    pub fn new(name: impl Into<String>, l_ref: u32) -> Self {
+        BamReference {
+            name: name.into(),
+            l_ref,
+        }
+    }
+
+    /// Reference sequence name
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
+    }
+
+    /// Reference sequence length
+    pub fn l_ref(&self) -> u32 {
+        self.l_ref
+    }
 }
 
-pub fn guess_phred_encoding(scores: &[u8]) -> PhredEncoding {
-    let min = scores.iter().min().unwrap_or(&0);
-    let max = scores.iter().max().unwrap_or(&0);
-    if min < &59 && max <= &74 {
-        return PhredEncoding::Phred33;
+impl BamHeader {
+    /// A header with `text` of l_text bytes and `n_ref` references to follow
+    #[must_use]
+    pub fn new(text: Vec<u8>, n_ref: u32) -> Self {
+        BamHeader { text, n_ref }
+    }
+
+    /// Raw header text, all l_text bytes
+    pub fn text(&self) -> &[u8] {
+        self.text.as_ref()
+    }
+
+    /// Header text for display, with invalid UTF-8 replaced and NUL padding dropped
+    pub fn text_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(self.trimmed_text())
+    }
+
+    /// Logical header lines without their "\n" or "\r\n" terminators
+    pub fn lines(&self) -> impl Iterator<Item = &[u8]> {
+        let text = self.trimmed_text();
+        let text = text.strip_suffix(b"\n").unwrap_or(text);
+        text.split(|b| *b == b'\n')
+            .filter(move |_| !text.is_empty())
+            .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+    }
+
+    /// IDs of the @RG lines, in header order; a line without an ID is skipped
+    pub fn read_group_ids(&self) -> impl Iterator<Item = &[u8]> {
+        self.lines().filter_map(read_group_id)
+    }
+
+    fn trimmed_text(&self) -> &[u8] {
+        let end = self.text.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        &self.text[..end]
+    }
+
+    /// Number of references declared after the text
+    pub fn n_ref(&self) -> u32 {
+        self.n_ref
+    }
+}
+
+/// The ID of an @RG header line, or None for other lines
+pub(crate) fn read_group_id(line: &[u8]) -> Option<&[u8]> {
+    line.strip_prefix(b"@RG\t")?
+        .split(|b| *b == b'\t')
+        .find_map(|field| field.strip_prefix(b"ID:"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::BamReader;
+    use lyso_common::assert_records_eq;
+    use std::fs::File;
+
+    fn fixture() -> Vec<Record> {
+        let f = File::open("../resources/test_data/name_sorted.bam").unwrap();
+        BamReader::new(bgzip::read::BGZFReader::new(f).unwrap())
+            .collect::<Result<Vec<Record>, BamError>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_clone_keeps_every_field() {
+        let f = File::open("../resources/test_data/mismatches.bam").unwrap();
+        let recs = BamReader::new(bgzip::read::BGZFReader::new(f).unwrap())
+            .collect::<Result<Vec<Record>, BamError>>()
+            .unwrap();
+        for rec in &recs {
+            let copy = rec.clone();
+            // Debug shows the derived fields and aux order that `==` ignores
+            assert_eq!(format!("{copy:?}"), format!("{rec:?}"));
+            assert_eq!(&copy, rec);
+        }
+        assert!(recs.iter().any(|r| r.aux().is_some_and(|a| a.len() > 1)));
+        assert!(recs.iter().any(|r| r.qual().is_none()));
+
+        let codes = (0..16u8)
+            .map(|c| parser::to_sequence(&c))
+            .collect::<Vec<BamSeq>>();
+        assert_eq!(codes[1..3], [BamSeq::A, BamSeq::C]);
+        let distinct = codes
+            .iter()
+            .copied()
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(distinct.len(), 16);
+    }
+
+    #[test]
+    fn test_semantic_eq_ignores_derived_fields() {
+        let recs = fixture();
+        let mut a = fixture().remove(0);
+        a.block_size = 0;
+        a.bin = 0;
+        a.ref_name = String::from("renamed");
+        assert_eq!(a, recs[0]);
+        assert_records_eq!(a, recs[0]);
+        assert_ne!(recs[0], recs[1]);
+    }
+
+    #[test]
+    fn test_set_long_record_id() {
+        let mut rec = fixture().remove(0);
+        rec.set_record_id(vec![b'r'; 300]);
+        assert_eq!(rec.read_name(), &[b'r'; MAX_QNAME_LEN][..]);
+        assert_eq!(usize::from(rec.l_read_name()), MAX_QNAME_LEN + 1);
+
+        // the length fields describe the bytes written
+        let mut writer = crate::writer::BamWriter::new(Vec::new());
+        writer.write_record(&rec).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), rec.block_size() as usize + 4);
+        assert_eq!(bytes[12], rec.l_read_name());
+    }
+
+    #[test]
+    fn test_aux_compares_as_set() {
+        let mut a = fixture().remove(0);
+        let mut b = fixture().remove(0);
+        let as_field = |tag: &str, value: BamAuxValue| {
+            let t = tag.chars().collect::<Vec<char>>();
+            BamAuxField {
+                tag: [t[0], t[1]],
+                value,
+            }
+        };
+        a.aux
+            .as_mut()
+            .unwrap()
+            .insert("RG".into(), as_field("RG", "s1".to_string().into()));
+        a.aux
+            .as_mut()
+            .unwrap()
+            .insert("AS".into(), as_field("AS", 5u8.into()));
+        b.aux
+            .as_mut()
+            .unwrap()
+            .insert("AS".into(), as_field("AS", 5u8.into()));
+        b.aux
+            .as_mut()
+            .unwrap()
+            .insert("RG".into(), as_field("RG", "s1".to_string().into()));
+        assert_eq!(a, b);
+        assert_eq!(a.canonical_hash(), b.canonical_hash());
+
+        assert!(b.remove_aux("RG").is_some());
+        assert_ne!(a.canonical_hash(), b.canonical_hash());
+        let diffs = a.diff(&b);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            diffs[0].to_string(),
+            "aux differs: \"RG:Z:s1\" != \"<absent>\""
+        );
+    }
+
+    #[test]
+    fn test_diff_names_fields() {
+        let recs = fixture();
+        // pairA read1 vs read2 differ in flag, pos, pnext and tlen
+        let diffs = recs[0].diff(&recs[1]);
+        assert_eq!(
+            diffs.iter().map(|d| d.field).collect::<Vec<&str>>(),
+            vec!["flag", "pos", "next_pos", "tlen"]
+        );
+
+        let mut b = fixture().remove(0);
+        b.seq[3] = b'N';
+        b.qual = None;
+        let diffs = recs[0].diff(&b);
+        assert_eq!(diffs[0].field, "seq");
+        assert_eq!(diffs[0].index, Some(3));
+        assert_eq!(diffs[1].to_string(), "qual differs: \"<present>\" != \"*\"");
+    }
+
+    #[test]
+    fn test_tsv() {
+        assert_eq!(Record::tsv_header(), "qname\tflag\trname\tpos\tmapq\tcigar");
+        let rows = fixture()
+            .iter()
+            .take(2)
+            .map(|r| r.to_tsv())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            rows,
+            vec![
+                "pairA\t99\tchr1\t101\t60\t10M",
+                "pairA\t147\tchr1\t291\t60\t10M",
+            ]
+        );
+        assert_eq!(
+            rows[0].split('\t').count(),
+            <Record as RecordFormatter>::COLUMNS.len()
+        );
+
+        let mut unmapped = fixture().remove(0);
+        unmapped.pos = -1;
+        assert_eq!(unmapped.to_tsv(), "pairA\t99\tchr1\t*\t60\t10M");
+    }
+
+    #[test]
+    fn test_seq_resolved() {
+        let reference = b"TTTTacgtACGTACGTAC";
+        let mut rec = fixture().remove(0);
+        rec.pos = 4;
+        // 2S 3M 1I 2= 1D 2M over reference 4..12
+        rec.cigar = vec![
+            CigarOp::S(2),
+            CigarOp::M(3),
+            CigarOp::I(1),
+            CigarOp::Eq(2),
+            CigarOp::D(1),
+            CigarOp::M(2),
+        ];
+        rec.seq = b"GGA=GTC=T=".to_vec();
+        assert_eq!(rec.seq_resolved(Some(reference)).unwrap(), b"GGACGTCATT");
+        assert_eq!(rec.seq_string(), "GGA=GTC=T=");
+
+        let reason = |rec: &Record, reference: Option<&[u8]>| match rec.seq_resolved(reference) {
+            Err(BamError::UnresolvedBase { reason, .. }) => reason,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(reason(&rec, None), "no reference given");
+        assert_eq!(
+            reason(&rec, Some(&reference[..10])),
+            "base 9 maps past the reference end (11)"
+        );
+        rec.seq[0] = b'=';
+        assert_eq!(reason(&rec, Some(reference)), "base 0 lies in 2S");
+        rec.seq[0] = b'G';
+        rec.cigar[1] = CigarOp::X(3);
+        assert_eq!(reason(&rec, Some(reference)), "base 3 lies in 3X");
+        rec.pos = -1;
+        assert_eq!(reason(&rec, Some(reference)), "record is unplaced");
+
+        // nothing to resolve, so no reference is needed
+        rec.seq = b"ACGT".to_vec();
+        assert_eq!(rec.seq_resolved(None).unwrap(), b"ACGT");
+    }
+
+    /// The records of absent_seq.bam: a primary, its minimap2-style secondary
+    /// with SEQ and QUAL omitted, and an unmapped read without QUAL
+    fn absent_seq_records() -> [Record; 3] {
+        use crate::builder::RecordBuilder;
+
+        let primary = RecordBuilder::new("read1")
+            .position(0, "chr1", 99)
+            .mapq(60)
+            .cigar(vec![CigarOp::M(10)])
+            .seq(b"ACGTACGTAC")
+            .qual(vec![40; 10])
+            .aux("NM", 0u8)
+            .aux("AS", 20u8)
+            .aux("tp", 'P')
+            .build();
+        let secondary = RecordBuilder::new("read1")
+            .flag(flags::SECONDARY)
+            .position(0, "chr1", 4999)
+            .cigar(vec![CigarOp::M(10)])
+            .aux("NM", 1u8)
+            .aux("AS", 18u8)
+            .aux("tp", 'S')
+            .build();
+        let unmapped = RecordBuilder::new("read2")
+            .flag(flags::UNMAPPED)
+            .seq(b"ACGT")
+            .build();
+        [primary, secondary, unmapped]
     }
-    if min >= &64 && max > &73 {
-        return PhredEncoding::Phred64;
+
+    #[test]
+    fn test_absent_seq_fixture() {
+        use crate::writer::BamWriter;
+        use std::io::Read;
+
+        let path = "../resources/test_data/absent_seq.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        reader.by_ref().for_each(drop);
+        let mut writer = BamWriter::new(Vec::new());
+        writer
+            .write_header(reader.header().unwrap(), reader.references())
+            .unwrap();
+        let built = absent_seq_records();
+        for rec in &built {
+            writer.write_record(rec).unwrap();
+        }
+        let mut expected = Vec::new();
+        bgzip::read::BGZFReader::new(File::open(path).unwrap())
+            .unwrap()
+            .read_to_end(&mut expected)
+            .unwrap();
+        assert_eq!(writer.into_inner(), expected);
+
+        // without SEQ the CIGAR is not held to a query length
+        let refs = reader.references();
+        for rec in &built {
+            assert_eq!(rec.validate(Some(refs)), [], "{rec}");
+        }
+        let mut short = built[1].clone();
+        short.seq = b"ACGT".to_vec();
+        short.l_seq = 4;
+        assert_eq!(
+            short.validate(Some(refs)),
+            [check::RecordWarning::QueryLength {
+                cigar: 10,
+                l_seq: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn test_absent_seq_and_qual() {
+        let f = File::open("../resources/test_data/absent_seq.bam").unwrap();
+        let recs = BamReader::new(bgzip::read::BGZFReader::new(f).unwrap())
+            .collect::<Result<Vec<Record>, BamError>>()
+            .unwrap();
+        assert_eq!(recs, absent_seq_records());
+        let secondary = &recs[1];
+        assert!(secondary.is_secondary());
+        assert_eq!(secondary.l_seq(), 0);
+        assert!(secondary.seq().is_empty());
+        assert_eq!(secondary.qual(), None);
+        assert_eq!(secondary.seq_string(), "*");
+        assert_eq!(secondary.qual_string(), "*");
+        let cols = secondary.to_string();
+        assert_eq!(
+            cols.split('\t').take(11).collect::<Vec<&str>>(),
+            vec!["read1", "256", "chr1", "5000", "0", "10M", "*", "0", "0", "*", "*"]
+        );
+
+        // unmapped read with SEQ but no QUAL, and no CIGAR
+        assert_eq!(recs[2].qual(), None);
+        assert_eq!(recs[2].cigar_string(), "*");
+        assert_eq!(
+            recs[2].to_string(),
+            "read2\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*"
+        );
+        assert_eq!(recs[0].qual_string(), "IIIIIIIIII");
+    }
+
+    #[test]
+    fn test_cigar_spans() {
+        let f = File::open("../resources/test_data/coverage.bam").unwrap();
+        let recs = BamReader::new(bgzip::read::BGZFReader::new(f).unwrap())
+            .collect::<Result<Vec<Record>, BamError>>()
+            .unwrap();
+        // 5M2D5M at 5
+        assert_eq!(recs[2].cigar_string(), "5M2D5M");
+        assert_eq!(recs[2].ref_end(), Some(ZeroBased::new(17)));
+        assert_eq!(recs[2].aligned_blocks(), [(5, 10), (12, 17)]);
+        // 3S4M1I3M at 8: the insertion doesn't split the block
+        assert_eq!(recs[3].cigar_string(), "3S4M1I3M");
+        assert_eq!(recs[3].aligned_blocks(), [(8, 15)]);
+        let unmapped = recs.last().unwrap();
+        assert_eq!(unmapped.ref_end(), None);
+        assert!(unmapped.aligned_blocks().is_empty());
+    }
+
+    /// SAM aux fields in the form `Display` writes them
+    pub(crate) const SAM_AUX: &[&str] = &[
+        "NM:i:5",
+        "AS:i:-12",
+        "XS:i:0",
+        "NH:i:300",
+        "XI:i:-2147483648",
+        "XU:i:4294967295",
+        "tp:A:P",
+        "ts:A:+",
+        "MD:Z:10A5^AC6",
+        "RG:Z:sample 1",
+        "SA:Z:chr1,100,+,50M,60,0;",
+        "MM:Z:C+m?,0,1;",
+        "XE:Z:",
+        "de:f:0.0123",
+        "XF:f:-1.5",
+        "XG:f:100000000000000000000",
+        "XH:H:1AE301",
+        "XJ:H:",
+        "ML:B:C,1,2,255",
+        "XB:B:c,-128,127",
+        "BQ:B:s,-300,300",
+        "XC:B:S,65535",
+        "cs:B:i,-1,-100000",
+        "pa:B:I,4294967295",
+        "XD:B:f,-0.5,0.001,2",
+        "XK:B:C",
+    ];
+
+    #[test]
+    fn test_aux_sam_round_trip() {
+        for text in SAM_AUX {
+            let field = BamAuxField::from_sam_str(text).unwrap();
+            assert_eq!(field.to_sam_string(), *text);
+            assert_eq!(field.to_sam_string(), field.to_string());
+        }
+    }
+
+    #[test]
+    fn test_aux_sam_float_exponent() {
+        let cases = [
+            ("XF:f:1e-5", "XF:f:0.00001"),
+            ("XF:f:-2.5E3", "XF:f:-2500"),
+            ("XF:f:+.5e+1", "XF:f:5"),
+            ("XB:B:f,1E2,-3e-1", "XB:B:f,100,-0.3"),
+        ];
+        for (text, canonical) in cases {
+            let field = BamAuxField::from_sam_str(text).unwrap();
+            assert_eq!(field.to_sam_string(), canonical);
+            let again = BamAuxField::from_sam_str(canonical).unwrap();
+            assert_eq!(again, field);
+        }
+    }
+
+    #[test]
+    fn test_aux_sam_int_width() {
+        let cases = [
+            ("0", BamAuxValue::C(0)),
+            ("+7", BamAuxValue::C(7)),
+            ("255", BamAuxValue::C(255)),
+            ("256", BamAuxValue::S(256)),
+            ("65535", BamAuxValue::S(65535)),
+            ("65536", BamAuxValue::I(65536)),
+            ("-1", BamAuxValue::c(-1)),
+            ("-128", BamAuxValue::c(-128)),
+            ("-129", BamAuxValue::s(-129)),
+            ("-32768", BamAuxValue::s(-32768)),
+            ("-32769", BamAuxValue::i(-32769)),
+        ];
+        for (v, expected) in cases {
+            let field = BamAuxField::from_sam_str(&format!("XX:i:{v}")).unwrap();
+            assert_eq!(field.value, expected, "{v}");
+        }
+        assert_eq!(
+            BamAuxField::from_sam_str("B1:B:c,-5").unwrap(),
+            BamAuxField {
+                tag: ['B', '1'],
+                value: BamAuxValue::Bc(vec![-5])
+            }
+        );
+    }
+
+    #[test]
+    fn test_aux_sam_invalid() {
+        let cases = [
+            "NMi5",
+            "N:i:5",
+            "1M:i:5",
+            "N_:i:5",
+            "NM:i",
+            "NM:x:1",
+            "NM:i:",
+            "NM:i:5.0",
+            "NM:i:4294967296",
+            "NM:i:-2147483649",
+            "XA:A:ab",
+            "XA:A: ",
+            "XF:f:1.",
+            "XF:f:inf",
+            "XF:f:NaN",
+            "XZ:Z:tab\there",
+            "XH:H:1AE",
+            "XH:H:1ae3",
+            "XH:H:GG",
+            "XB:B:c,200",
+            "XB:B:C,-1",
+            "XB:B:q,1",
+            "XB:B:i,1,,2",
+            "XB:B:",
+        ];
+        for text in cases {
+            let err = BamAuxField::from_sam_str(text).unwrap_err();
+            assert!(
+                matches!(err, BamError::InvalidAuxField { ref field, .. } if field == text),
+                "{text}: {err}"
+            );
+        }
+        let err = BamAuxField::from_sam_str("XB:B:c,200").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid SAM aux field 'XB:B:c,200': B value has an element its subtype cannot hold"
+        );
     }
-    PhredEncoding::Unknown
 }
@@ -0,0 +1,348 @@
+//! Point mismatches against the reference, from the MD tag
+//!
+//! MD lists the reference bases of the aligned positions that mismatch, and the
+//! bases a deletion removes, without the reference itself. `Record::mismatch_sites`
+//! walks it together with the CIGAR: soft clips and insertions advance only the
+//! read, deletions and skips only the reference. Indels themselves are not reported.
+
+use lyso_common::pos::ZeroBased;
+use lyso_common::CigarOp;
+
+use crate::{BamError, Record};
+
+/// A read base aligned to a different reference base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Position on the record's reference
+    pub ref_pos: ZeroBased,
+    /// Reference base, as written in MD
+    pub ref_base: u8,
+    /// Read base from SEQ
+    pub read_base: u8,
+    /// Quality of the read base, None when QUAL is absent
+    pub base_qual: Option<u8>,
+}
+
+/// Reads MD one aligned or deleted reference base at a time
+struct MdCursor<'a> {
+    md: &'a [u8],
+    at: usize,
+    /// Matching bases left in the current run
+    matches: u64,
+    /// Inside the bases of a `^` deletion
+    deleting: bool,
+}
+
+impl<'a> MdCursor<'a> {
+    fn new(md: &'a str) -> Self {
+        MdCursor {
+            md: md.as_bytes(),
+            at: 0,
+            matches: 0,
+            deleting: false,
+        }
+    }
+
+    /// Parse a run of digits at the cursor into `matches`, if there is one
+    fn read_number(&mut self) -> Result<bool, String> {
+        let digits = self.md[self.at..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count();
+        if digits == 0 {
+            return Ok(false);
+        }
+        let run = &self.md[self.at..self.at + digits];
+        self.matches = std::str::from_utf8(run)
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| format!("match count at {} is too large", self.at))?;
+        self.at += digits;
+        self.deleting = false;
+        Ok(true)
+    }
+
+    /// The next aligned base: None if it matches, else the reference base
+    fn aligned(&mut self) -> Result<Option<u8>, String> {
+        loop {
+            if self.matches > 0 {
+                self.matches -= 1;
+                return Ok(None);
+            }
+            if self.read_number()? {
+                continue;
+            }
+            return match self.md.get(self.at) {
+                Some(b) if self.deleting && b.is_ascii_alphabetic() => Err(format!(
+                    "deletion at {} is longer than the CIGAR's",
+                    self.at
+                )),
+                Some(b) if b.is_ascii_alphabetic() => {
+                    self.at += 1;
+                    Ok(Some(b.to_ascii_uppercase()))
+                }
+                Some(b'^') => Err(format!(
+                    "deletion at {} where the CIGAR aligns a base",
+                    self.at
+                )),
+                Some(b) => Err(format!("unexpected '{}' at {}", char::from(*b), self.at)),
+                None => Err("MD covers fewer bases than the CIGAR".to_string()),
+            };
+        }
+    }
+
+    /// Step over the next deleted reference base
+    fn deleted(&mut self) -> Result<(), String> {
+        loop {
+            match self.md.get(self.at) {
+                Some(b) if self.deleting && b.is_ascii_alphabetic() => {
+                    self.at += 1;
+                    return Ok(());
+                }
+                Some(b'^') if self.matches == 0 => {
+                    self.at += 1;
+                    self.deleting = true;
+                }
+                // a 0 may separate a deletion from what follows
+                _ if self.matches == 0 && self.read_number()? => {}
+                _ => {
+                    return Err(format!(
+                        "no deleted base at {} where the CIGAR deletes",
+                        self.at
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Fail unless everything left in MD is empty match runs
+    fn finish(&mut self) -> Result<(), String> {
+        while self.matches == 0 && self.read_number()? {}
+        if self.matches > 0 || self.at < self.md.len() {
+            return Err("MD covers more bases than the CIGAR".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Record {
+    /// SNV-like mismatches of the alignment, in reference order, from its MD tag
+    ///
+    /// Walks MD alongside the CIGAR; bases of insertions and soft clips are never
+    /// reported, and deleted bases are skipped. Unmapped records have none. Fails
+    /// when MD is absent, malformed or disagrees with the CIGAR, or when SEQ is
+    /// absent.
+    pub fn mismatch_sites(&self) -> Result<Vec<Mismatch>, BamError> {
+        if self.is_unmapped() || self.cigar.is_empty() {
+            return Ok(Vec::new());
+        }
+        let fail = |reason: String| BamError::InvalidMdTag {
+            name: self.read_name_str_lossy().into_owned(),
+            reason,
+        };
+        let md = self
+            .aux_str("MD")
+            .ok_or_else(|| fail("no MD tag".to_string()))?;
+        let mut r = self
+            .pos()
+            .ok_or_else(|| fail("record is unplaced".to_string()))?
+            .get();
+        let mut cursor = MdCursor::new(md);
+        let mut q = 0;
+        let mut sites = Vec::new();
+        for op in &self.cigar {
+            let len = op.len();
+            match op {
+                CigarOp::M(_) | CigarOp::Eq(_) | CigarOp::X(_) => {
+                    for _ in 0..len {
+                        if let Some(ref_base) = cursor.aligned().map_err(fail)? {
+                            let read_base = *self.seq.get(q).ok_or_else(|| {
+                                fail(format!("SEQ has no base {q} for a mismatch"))
+                            })?;
+                            sites.push(Mismatch {
+                                ref_pos: ZeroBased::from(r),
+                                ref_base,
+                                read_base,
+                                base_qual: self.qual().and_then(|qual| qual.get(q).copied()),
+                            });
+                        }
+                        q += 1;
+                        r += 1;
+                    }
+                }
+                CigarOp::D(_) => {
+                    for _ in 0..len {
+                        cursor.deleted().map_err(fail)?;
+                    }
+                    r += u64::from(len);
+                }
+                CigarOp::N(_) => r += u64::from(len),
+                CigarOp::I(_) | CigarOp::S(_) => q += len as usize,
+                CigarOp::H(_) | CigarOp::P(_) => {}
+            }
+        }
+        cursor.finish().map_err(fail)?;
+        Ok(sites)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::RecordBuilder;
+    use crate::flags;
+
+    fn record(pos: i32, cigar: Vec<CigarOp>, seq: &[u8], md: &str) -> Record {
+        let qual = (0..seq.len() as u8).map(|i| 20 + i).collect();
+        RecordBuilder::new("r1")
+            .position(0, "chr1", pos)
+            .cigar(cigar)
+            .seq(seq)
+            .qual(qual)
+            .aux("MD", md.to_string())
+            .build()
+    }
+
+    /// Mismatches found by laying the read on `reference` through the CIGAR by hand
+    fn by_hand(rec: &Record, reference: &[u8]) -> Vec<Mismatch> {
+        let (mut q, mut r) = (0, rec.pos().unwrap().get() as usize);
+        let mut sites = Vec::new();
+        for op in rec.cigar() {
+            let len = op.len() as usize;
+            if op.is_aligned() {
+                for i in 0..len {
+                    if rec.seq()[q + i] != reference[r + i] {
+                        sites.push(Mismatch {
+                            ref_pos: ZeroBased::from((r + i) as u64),
+                            ref_base: reference[r + i],
+                            read_base: rec.seq()[q + i],
+                            base_qual: rec.qual().map(|qual| qual[q + i]),
+                        });
+                    }
+                }
+            }
+            if op.consumes_query() {
+                q += len;
+            }
+            if op.consumes_ref() {
+                r += len;
+            }
+        }
+        sites
+    }
+
+    #[test]
+    fn test_soft_clip_and_deletion() {
+        //                 0         1         2
+        //                 0123456789012345678901234
+        let reference = b"GGGGACGTACGTTTCCAGGTACGTAA";
+        // 3 clipped bases, then from ref 4: ACGTACGT matches bar a C>G at 9,
+        // TT deleted at 12-13, CCAGG with A>T at 16, an insertion, then TACG with C>A at 21
+        let rec = record(
+            4,
+            vec![
+                CigarOp::S(3),
+                CigarOp::M(8),
+                CigarOp::D(2),
+                CigarOp::M(5),
+                CigarOp::I(2),
+                CigarOp::M(4),
+            ],
+            b"NNNACGTAGGTCCTGGGGTAAG",
+            "5C2^TT2A4C1",
+        );
+        let sites = rec.mismatch_sites().unwrap();
+        assert_eq!(sites, by_hand(&rec, reference));
+        let summary = sites
+            .iter()
+            .map(|m| {
+                (
+                    m.ref_pos.get(),
+                    char::from(m.ref_base),
+                    char::from(m.read_base),
+                    m.base_qual,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (9, 'C', 'G', Some(28)),
+                (16, 'A', 'T', Some(33)),
+                (21, 'C', 'A', Some(40)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skips_and_clips() {
+        let reference = b"ACGTACGTACGTACGTACGT";
+        // hard clip, then 3 aligned, a 4 base intron, 3 aligned with the last mismatching
+        let rec = record(
+            1,
+            vec![
+                CigarOp::H(5),
+                CigarOp::M(3),
+                CigarOp::N(4),
+                CigarOp::M(3),
+                CigarOp::S(1),
+            ],
+            b"CGTACTA",
+            "5G0",
+        );
+        assert_eq!(rec.mismatch_sites().unwrap(), by_hand(&rec, reference));
+        assert_eq!(rec.mismatch_sites().unwrap()[0].ref_pos.get(), 10);
+
+        // adjacent deletions, a lowercase base and QUAL absent
+        let rec = RecordBuilder::new("r2")
+            .position(0, "chr1", 0)
+            .cigar(vec![
+                CigarOp::M(2),
+                CigarOp::D(1),
+                CigarOp::D(1),
+                CigarOp::M(2),
+            ])
+            .seq(b"ACAT")
+            .aux("MD", "2^GT0c1".to_string())
+            .build();
+        let sites = rec.mismatch_sites().unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!((sites[0].ref_pos.get(), sites[0].ref_base), (4, b'C'));
+        assert_eq!((sites[0].read_base, sites[0].base_qual), (b'A', None));
+    }
+
+    #[test]
+    fn test_no_mismatches() {
+        let rec = record(0, vec![CigarOp::M(4)], b"ACGT", "4");
+        assert!(rec.mismatch_sites().unwrap().is_empty());
+        let unmapped = RecordBuilder::new("u").flag(flags::UNMAPPED).build();
+        assert!(unmapped.mismatch_sites().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_md() {
+        let cigar = || vec![CigarOp::M(2), CigarOp::D(1), CigarOp::M(2)];
+        for (md, reason) in [
+            ("2^A1", "fewer bases"),
+            ("2^A3", "more bases"),
+            ("2^AC2", "deletion at 3 is longer"),
+            ("5", "no deleted base"),
+            ("1^A3", "deletion at 1 where"),
+            ("2^A1+1", "unexpected '+'"),
+            ("99999999999999999999999", "too large"),
+        ] {
+            let rec = record(0, cigar(), b"ACGT", md);
+            let err = rec.mismatch_sites().unwrap_err().to_string();
+            assert!(err.contains(reason), "{md}: {err}");
+        }
+        let rec = RecordBuilder::new("r1")
+            .position(0, "chr1", 0)
+            .cigar(vec![CigarOp::M(4)])
+            .seq(b"ACGT")
+            .build();
+        assert_eq!(
+            rec.mismatch_sites().unwrap_err().to_string(),
+            "Invalid MD tag of r1: no MD tag"
+        );
+    }
+}
@@ -0,0 +1,560 @@
+//! Mate pairing over name-sorted BAM streams
+//!
+//! `PairIter` wraps any iterator of BAM records and joins primary mates
+//! sharing a read name into a single item. Everything that cannot be paired
+//! (unpaired reads, orphaned mates, secondary and supplementary alignments)
+//! is routed according to a `SideChannel` policy.
+//!
+//! `SegmentDedup` goes in front of it when secondary alignments are wanted, so
+//! that each read comes out once.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use fxhash::FxHashMap;
+
+use crate::flags::{READ1, READ2};
+use crate::{BamError, Record};
+
+/// Default bound on the number of unmatched mates held in memory
+pub const DEFAULT_MAX_PENDING: usize = 100_000;
+
+/// What to do with a record that is not part of a primary pair
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SideChannel {
+    /// Silently drop the record
+    Skip,
+    /// Yield the record as `Pair::Single`
+    Yield,
+    /// Yield `BamError::UnexpectedRecord`
+    Error,
+}
+
+/// Item produced by `PairIter`
+// Pairs are the common case, so don't box them to even out the variants
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Pair {
+    /// Both primary mates, ordered (read1, read2)
+    Paired(Record, Record),
+    /// A record routed through a `SideChannel::Yield` policy
+    Single(Record),
+}
+
+/// Joins mates from a name-sorted BAM record stream
+///
+/// Primary records flagged as paired are buffered by read name until their mate arrives.
+/// In a name-sorted stream mates are adjacent, so the buffer should stay tiny;
+/// if more than `max_pending` names are waiting for a mate the input is assumed
+/// not to be name-sorted and `BamError::NotNameSorted` is returned.
+///
+/// Mates still waiting at the end of the stream are routed as singletons.
+pub struct PairIter<I> {
+    inner: I,
+    pending: FxHashMap<Vec<u8>, (u64, Record)>,
+    /// Names in `pending` by arrival
+    arrivals: BTreeMap<u64, Vec<u8>>,
+    n_seen: u64,
+    max_pending: usize,
+    evict: bool,
+    singletons: SideChannel,
+    secondary: SideChannel,
+    supplementary: SideChannel,
+    orphans: Option<std::vec::IntoIter<Record>>,
+    failed: bool,
+}
+
+impl<I> PairIter<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    /// Wrap `inner`, skipping secondary and supplementary alignments and
+    /// yielding singletons.
+    #[must_use]
+    pub fn new(inner: I) -> Self {
+        PairIter {
+            inner,
+            pending: FxHashMap::default(),
+            arrivals: BTreeMap::new(),
+            n_seen: 0,
+            max_pending: DEFAULT_MAX_PENDING,
+            evict: false,
+            singletons: SideChannel::Yield,
+            secondary: SideChannel::Skip,
+            supplementary: SideChannel::Skip,
+            orphans: None,
+            failed: false,
+        }
+    }
+
+    /// Maximum number of names allowed to wait for a mate
+    #[must_use]
+    pub fn max_pending(mut self, n: usize) -> Self {
+        self.max_pending = n;
+        self
+    }
+
+    /// Route the longest-waiting mate as a singleton once `max_pending` names are
+    /// waiting, instead of failing with `NotNameSorted`
+    ///
+    /// Suits name-sorted input with many orphaned mates, where a mate that has
+    /// waited that long will not find its partner. Off by default.
+    #[must_use]
+    pub fn evict_oldest(mut self, evict: bool) -> Self {
+        self.evict = evict;
+        self
+    }
+
+    /// Policy for unpaired reads and mates whose partner never appears
+    #[must_use]
+    pub fn singletons(mut self, policy: SideChannel) -> Self {
+        self.singletons = policy;
+        self
+    }
+
+    /// Policy for secondary alignments
+    #[must_use]
+    pub fn secondary(mut self, policy: SideChannel) -> Self {
+        self.secondary = policy;
+        self
+    }
+
+    /// Policy for supplementary alignments
+    #[must_use]
+    pub fn supplementary(mut self, policy: SideChannel) -> Self {
+        self.supplementary = policy;
+        self
+    }
+
+    /// Number of mates currently waiting for a partner
+    pub fn n_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn route(
+        policy: SideChannel,
+        kind: &'static str,
+        rec: Record,
+    ) -> Option<Result<Pair, BamError>> {
+        match policy {
+            SideChannel::Skip => None,
+            SideChannel::Yield => Some(Ok(Pair::Single(rec))),
+            SideChannel::Error => Some(Err(BamError::UnexpectedRecord {
+                kind,
+                name: rec.read_name_str_lossy().into_owned(),
+            })),
+        }
+    }
+
+    /// Route a single record, returning an item if one is ready
+    fn accept(&mut self, rec: Record) -> Option<Result<Pair, BamError>> {
+        if rec.is_secondary() {
+            return Self::route(self.secondary, "secondary", rec);
+        }
+        if rec.is_supplementary() {
+            return Self::route(self.supplementary, "supplementary", rec);
+        }
+        if !rec.is_paired() {
+            return Self::route(self.singletons, "singleton", rec);
+        }
+        match self.pending.remove(rec.read_name()) {
+            Some((n, mate)) => {
+                self.arrivals.remove(&n);
+                // keep arrival order unless flags say otherwise
+                if rec.is_read1() && !mate.is_read1() {
+                    Some(Ok(Pair::Paired(rec, mate)))
+                } else {
+                    Some(Ok(Pair::Paired(mate, rec)))
+                }
+            }
+            None => {
+                let mut evicted = None;
+                if self.pending.len() >= self.max_pending {
+                    if !self.evict {
+                        self.failed = true;
+                        return Some(Err(BamError::NotNameSorted(self.max_pending)));
+                    }
+                    evicted = self.evict_one();
+                }
+                self.n_seen += 1;
+                self.arrivals.insert(self.n_seen, rec.read_name().to_vec());
+                self.pending
+                    .insert(rec.read_name().to_vec(), (self.n_seen, rec));
+                evicted.and_then(|old| Self::route(self.singletons, "singleton", old))
+            }
+        }
+    }
+
+    /// Remove the mate that has waited longest
+    fn evict_one(&mut self) -> Option<Record> {
+        let (_, name) = self.arrivals.pop_first()?;
+        self.pending.remove(&name).map(|(_, rec)| rec)
+    }
+
+    /// Drain mates that never found a partner, in arrival order
+    fn take_orphans(&mut self) -> std::vec::IntoIter<Record> {
+        let mut orphans = Vec::with_capacity(self.pending.len());
+        while let Some(rec) = self.evict_one() {
+            orphans.push(rec);
+        }
+        orphans.into_iter()
+    }
+}
+
+impl<I> Iterator for PairIter<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    type Item = Result<Pair, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.orphans.is_none() {
+            loop {
+                match self.inner.next() {
+                    Some(Ok(rec)) => {
+                        if let Some(item) = self.accept(rec) {
+                            return Some(item);
+                        }
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => break,
+                }
+            }
+            self.orphans = Some(self.take_orphans());
+        }
+        while let Some(rec) = self.orphans.as_mut().and_then(|o| o.next()) {
+            if let Some(item) = Self::route(self.singletons, "singleton", rec) {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+/// Passes a name-sorted record stream through, keeping one record per segment
+///
+/// Records are held one read name at a time. A secondary or supplementary record
+/// is dropped when its segment (READ1, READ2, or neither) has a primary record,
+/// and otherwise only the first one for the segment is kept. Kept records come
+/// out in input order.
+pub struct SegmentDedup<I> {
+    inner: I,
+    /// First record of the next name
+    next: Option<Record>,
+    ready: VecDeque<Record>,
+    /// An error to return once `ready` is empty
+    error: Option<BamError>,
+}
+
+impl<I> SegmentDedup<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    /// Wrap `inner`, which must be name-sorted or at least grouped by name
+    #[must_use]
+    pub fn new(inner: I) -> Self {
+        SegmentDedup {
+            inner,
+            next: None,
+            ready: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// Read the records of the next name into `ready`, stopping at an error
+    fn fill(&mut self) -> Option<BamError> {
+        let mut group = self.next.take().into_iter().collect::<Vec<_>>();
+        loop {
+            match self.inner.next() {
+                Some(Ok(rec)) if group.is_empty() || rec.read_name() == group[0].read_name() => {
+                    group.push(rec);
+                }
+                Some(Ok(rec)) => {
+                    self.next = Some(rec);
+                    break;
+                }
+                Some(Err(e)) => {
+                    self.keep_once(group);
+                    return Some(e);
+                }
+                None => break,
+            }
+        }
+        self.keep_once(group);
+        None
+    }
+
+    fn keep_once(&mut self, group: Vec<Record>) {
+        let segment = |rec: &Record| rec.flag() & (READ1 | READ2);
+        let primary = group
+            .iter()
+            .filter(|r| r.is_primary())
+            .map(segment)
+            .collect::<Vec<_>>();
+        let mut kept_other = Vec::new();
+        for rec in group {
+            let seg = segment(&rec);
+            if !rec.is_primary() {
+                if primary.contains(&seg) || kept_other.contains(&seg) {
+                    continue;
+                }
+                kept_other.push(seg);
+            }
+            self.ready.push_back(rec);
+        }
+    }
+}
+
+impl<I> Iterator for SegmentDedup<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    type Item = Result<Record, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ready.is_empty() && self.error.is_none() {
+            self.error = self.fill();
+        }
+        // records read before an error come out first
+        match self.ready.pop_front() {
+            Some(rec) => Some(Ok(rec)),
+            None => self.error.take().map(Err),
+        }
+    }
+}
+
+/// Summary of absolute template lengths
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct InsertSizeSummary {
+    /// Number of template lengths
+    #[cfg_attr(feature = "serde", serde(rename = "pairs"))]
+    pub count: usize,
+    /// Mean absolute template length
+    pub mean: f64,
+    /// Median absolute template length
+    pub median: f64,
+    /// Population standard deviation
+    pub stddev: f64,
+}
+
+/// Summarize abs(TLEN) over properly paired mates
+///
+/// Only `Pair::Paired` items whose first mate has the PROPER_PAIR flag are counted.
+/// Standard deviation is the population standard deviation.
+pub fn insert_size_summary<I>(pairs: I) -> Result<InsertSizeSummary, BamError>
+where
+    I: Iterator<Item = Result<Pair, BamError>>,
+{
+    let mut sizes: Vec<u64> = Vec::new();
+    for p in pairs {
+        if let Pair::Paired(r1, _) = p? {
+            if r1.is_proper_pair() {
+                sizes.push(u64::from(r1.tlen().unsigned_abs()));
+            }
+        }
+    }
+    if sizes.is_empty() {
+        return Ok(InsertSizeSummary::default());
+    }
+    sizes.sort_unstable();
+    let n = sizes.len();
+    let mean = sizes.iter().sum::<u64>() as f64 / n as f64;
+    let median = if n.is_multiple_of(2) {
+        (sizes[n / 2 - 1] + sizes[n / 2]) as f64 / 2.0
+    } else {
+        sizes[n / 2] as f64
+    };
+    let var = sizes
+        .iter()
+        .map(|s| (*s as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n as f64;
+    Ok(InsertSizeSummary {
+        count: n,
+        mean,
+        median,
+        stddev: var.sqrt(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::RecordBuilder;
+    use crate::flags::{PAIRED, SECONDARY, SUPPLEMENTARY};
+    use crate::reader::BamReader;
+    use std::fs::File;
+
+    const NAME_SORTED_PATH: &str = "../resources/test_data/name_sorted.bam";
+
+    fn reader() -> BamReader<bgzip::read::BGZFReader<File>> {
+        let f = File::open(NAME_SORTED_PATH).unwrap();
+        BamReader::new(bgzip::read::BGZFReader::new(f).unwrap())
+    }
+
+    fn describe(p: &Pair) -> String {
+        match p {
+            Pair::Paired(a, b) => format!("{}:{}+{}", a.read_name_str_lossy(), a.flag(), b.flag()),
+            Pair::Single(a) => format!("{}:{}", a.read_name_str_lossy(), a.flag()),
+        }
+    }
+
+    #[test]
+    fn test_default_routing() {
+        let items = PairIter::new(reader())
+            .map(|p| describe(&p.unwrap()))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            items,
+            vec![
+                "pairA:99+147",
+                "pairB:83+163",
+                "singleC:0",
+                "pairE:99+147",
+                "orphanD:73",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_yield_side_channels() {
+        let items = PairIter::new(reader())
+            .secondary(SideChannel::Yield)
+            .supplementary(SideChannel::Yield)
+            .singletons(SideChannel::Skip)
+            .map(|p| describe(&p.unwrap()))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            items,
+            vec![
+                "pairA:99+147",
+                "pairB:2145",
+                "pairB:83+163",
+                "pairE:355",
+                "pairE:99+147",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_side_channel() {
+        let err = PairIter::new(reader())
+            .supplementary(SideChannel::Error)
+            .find_map(|p| p.err())
+            .unwrap();
+        assert!(matches!(
+            err,
+            BamError::UnexpectedRecord {
+                kind: "supplementary",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_pending_bound() {
+        let mut iter = PairIter::new(reader()).max_pending(0);
+        assert!(matches!(iter.next(), Some(Err(BamError::NotNameSorted(0)))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        // with room for one waiting mate, orphanD is pushed out by pairE
+        let items = PairIter::new(reader())
+            .max_pending(1)
+            .evict_oldest(true)
+            .map(|p| describe(&p.unwrap()))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            items,
+            vec![
+                "pairA:99+147",
+                "pairB:83+163",
+                "singleC:0",
+                "orphanD:73",
+                "pairE:99+147",
+            ]
+        );
+    }
+
+    fn record(name: &str, flag: u16) -> Result<Record, BamError> {
+        Ok(RecordBuilder::new(name).flag(flag).build())
+    }
+
+    #[test]
+    fn test_segment_dedup() {
+        let records = vec![
+            // a secondary before its primary, and a supplementary after it
+            record("a", PAIRED | READ1 | SECONDARY),
+            record("a", PAIRED | READ1),
+            record("a", PAIRED | READ2),
+            record("a", PAIRED | READ1 | SUPPLEMENTARY),
+            // only secondaries for READ2: the first stands in for the primary
+            record("b", PAIRED | READ1),
+            record("b", PAIRED | READ2 | SECONDARY),
+            record("b", PAIRED | READ2 | SUPPLEMENTARY),
+            record("c", 0),
+            record("c", SECONDARY),
+        ];
+        let kept = SegmentDedup::new(records.into_iter())
+            .map(|r| {
+                let r = r.unwrap();
+                format!("{}:{}", r.read_name_str_lossy(), r.flag())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kept, ["a:65", "a:129", "b:65", "b:385", "c:0"]);
+
+        // the fixture has a primary for every segment
+        let kept = SegmentDedup::new(reader()).count();
+        assert_eq!(
+            kept,
+            reader()
+                .filter(|r| r.as_ref().unwrap().is_primary())
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_segment_dedup_error() {
+        let records = vec![
+            record("a", READ1),
+            Err(BamError::EofError),
+            record("b", READ1),
+        ];
+        let items = SegmentDedup::new(records.into_iter())
+            .map(|r| r.map(|r| r.read_name().to_vec()))
+            .collect::<Vec<_>>();
+        assert!(
+            matches!(&items[..], [Ok(a), Err(BamError::EofError), Ok(b)] if a == b"a" && b == b"b")
+        );
+    }
+
+    #[test]
+    fn test_positions() {
+        let recs = reader().collect::<Result<Vec<Record>, BamError>>().unwrap();
+        assert_eq!(recs[0].pos().unwrap().get(), 100);
+        assert_eq!(recs[0].pos_1based().unwrap().get(), 101);
+        assert_eq!(recs[0].next_pos_1based().unwrap().get(), 291);
+        // singleC has no mate
+        assert_eq!(recs[5].next_pos(), None);
+        assert_eq!(recs[5].raw_next_pos(), -1);
+        // SAM output is unchanged by the typed accessors
+        assert_eq!(
+            recs[0].to_string(),
+            "pairA\t99\tchr1\t101\t60\t10M\tchr1\t291\t200\tACGTACGTAC\tFFFFFFFFFF\tNM:i:0"
+        );
+    }
+
+    #[test]
+    fn test_insert_size_summary() {
+        let summary = insert_size_summary(PairIter::new(reader())).unwrap();
+        assert_eq!(summary.count, 3);
+        assert_eq!(summary.mean, 250.0);
+        assert_eq!(summary.median, 250.0);
+        assert!((summary.stddev - 40.8248).abs() < 1e-4);
+    }
+}
@@ -1,8 +1,10 @@
-use fxhash::FxHashMap;
+//! nom parsers for the BAM header, reference list and alignment records
+
 use nom::{
     bytes::complete::take_until,
     bytes::streaming::{tag, take},
     combinator::{map, map_parser},
+    error::{Error, ErrorKind, ParseError},
     multi::{count, fill, length_data, many1},
     number::complete,
     number::streaming,
@@ -10,7 +12,11 @@ use nom::{
     IResult,
 };
 
-use crate::{BamAuxField, BamAuxValue, BamHeader, BamReference, BamSeq, Record, BAM_MAGIC_STR};
+use crate::{
+    decode_hex, AuxMap, BamAuxField, BamAuxValue, BamError, BamHeader, BamReference, BamSeq,
+    DecodeError, Record, BAM_MAGIC_STR,
+};
+use lyso_common::codec::unpack_4bit;
 use lyso_common::CigarOp;
 
 // ============================== //
@@ -40,7 +46,7 @@ pub fn read_header(input: &[u8]) -> IResult<&[u8], BamHeader> {
         Ok((_i, (text_bytes, n_ref))) => IResult::Ok((
             _i,
             BamHeader {
-                text: String::from_utf8_lossy(text_bytes).into_owned(),
+                text: text_bytes.to_vec(),
                 n_ref,
             },
         )),
@@ -116,22 +122,66 @@ pub fn read_references<'a>(input: &'a [u8], buf: &mut [BamReference]) -> IResult
 //   BEGIN BAM CIGAR PARSING   //
 // =========================== //
 
+/// Error type of the alignment record parsers
+///
+/// nom's own error, or a field whose value has no meaning in the spec. Decode
+/// errors are raised as `nom::Err::Failure` so that `many1` and friends stop on
+/// them instead of backtracking.
+#[derive(Debug, PartialEq)]
+pub enum RecordError<I> {
+    /// An error from a nom combinator
+    Nom(Error<I>),
+    /// A field value the spec does not allow
+    Decode(DecodeError),
+}
+
+impl<I> ParseError<I> for RecordError<I> {
+    fn from_error_kind(input: I, kind: ErrorKind) -> Self {
+        RecordError::Nom(Error::new(input, kind))
+    }
+
+    fn append(_: I, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+/// Result of the alignment record parsers
+pub type RecordResult<'a, O> = IResult<&'a [u8], O, RecordError<&'a [u8]>>;
+
+impl<'a> From<nom::Err<RecordError<&'a [u8]>>> for BamError {
+    fn from(value: nom::Err<RecordError<&'a [u8]>>) -> Self {
+        match value {
+            nom::Err::Error(RecordError::Decode(e)) | nom::Err::Failure(RecordError::Decode(e)) => {
+                e.into()
+            }
+            _ => BamError::ParseError,
+        }
+    }
+}
+
+fn decode_failure<I>(field: &'static str, code: u8) -> nom::Err<RecordError<I>> {
+    nom::Err::Failure(RecordError::Decode(DecodeError { field, code }))
+}
+
 /// Converts unpacked CIGAR data into a single CigarOp
 ///
 /// This expects a [u32; 2], as obtained by `unpack_cigar_op` parser.
-
-fn to_cigar(input: [u32; 2]) -> CigarOp {
+/// Fails on op codes above 8 (X).
+pub fn to_cigar(input: [u32; 2]) -> Result<CigarOp, DecodeError> {
     match input[0] {
-        0 => CigarOp::M(input[1]),
-        1 => CigarOp::I(input[1]),
-        2 => CigarOp::D(input[1]),
-        3 => CigarOp::N(input[1]),
-        4 => CigarOp::S(input[1]),
-        5 => CigarOp::H(input[1]),
-        6 => CigarOp::P(input[1]),
-        7 => CigarOp::Eq(input[1]),
-        8 => CigarOp::X(input[1]),
-        otherwise => panic!("Invalid CigarOp {}{otherwise}", input[1]),
+        0 => Ok(CigarOp::M(input[1])),
+        1 => Ok(CigarOp::I(input[1])),
+        2 => Ok(CigarOp::D(input[1])),
+        3 => Ok(CigarOp::N(input[1])),
+        4 => Ok(CigarOp::S(input[1])),
+        5 => Ok(CigarOp::H(input[1])),
+        6 => Ok(CigarOp::P(input[1])),
+        7 => Ok(CigarOp::Eq(input[1])),
+        8 => Ok(CigarOp::X(input[1])),
+        otherwise => Err(DecodeError {
+            field: "CIGAR op",
+            code: u8::try_from(otherwise).unwrap_or(u8::MAX),
+        }),
     }
 }
 
@@ -139,21 +189,29 @@ fn to_cigar(input: [u32; 2]) -> CigarOp {
 ///
 /// Reads a single u32 and unpacks operation + length.
 /// See SAM v1 4.2
-
 pub fn unpack_cigar_op(input: &[u8]) -> IResult<&[u8], [u32; 2]> {
     let (_i, v) = complete::le_u32(input)?;
-    Ok((_i, [v & 4, v >> 4 | (v & 4)]))
+    Ok((_i, unpack_cigar(v)))
+}
+
+/// Split a packed CIGAR operation, `len << 4 | op`, into `[op, len]`
+fn unpack_cigar(packed: u32) -> [u32; 2] {
+    [packed & 0xf, packed >> 4]
 }
 
 /// Read bytes into vector of `CigarOp`s
 ///
 /// Reads and unpacks `n_op` bytes, converting each to corresponding CigarOp variant.
-
-pub fn read_cigar<'a>(input: &'a [u8], n_op: &u16) -> IResult<&'a [u8], Vec<CigarOp>> {
-    let mut ops: Vec<CigarOp> = Vec::with_capacity(usize::try_from(*n_op).unwrap());
+pub fn read_cigar<'a>(input: &'a [u8], n_op: &u16) -> RecordResult<'a, Vec<CigarOp>> {
+    ensure_available(input, usize::from(*n_op).checked_mul(4))?;
+    let mut ops: Vec<CigarOp> = Vec::with_capacity(usize::from(*n_op));
     let mut _i: &[u8] = input;
     for _ in 0..(*n_op) {
-        (_i, _) = map(unpack_cigar_op, |v| ops.push(to_cigar(v)))(_i)?;
+        let (i, v) = complete::le_u32(_i)?;
+        let op =
+            to_cigar(unpack_cigar(v)).map_err(|e| nom::Err::Failure(RecordError::Decode(e)))?;
+        ops.push(op);
+        _i = i;
     }
     Ok((_i, ops))
 }
@@ -165,7 +223,6 @@ pub fn read_cigar<'a>(input: &'a [u8], n_op: &u16) -> IResult<&'a [u8], Vec<Ciga
 /// Parse byte into BAM sequence
 ///
 /// See SAM v1 4.2.3
-
 pub fn to_sequence(input: &u8) -> BamSeq {
     match input {
         0 => BamSeq::Eq,
@@ -187,34 +244,18 @@ pub fn to_sequence(input: &u8) -> BamSeq {
     }
 }
 
-/// Unpacks compressed BAM sequence
+/// Read the 4-bit packed sequence as IUPAC ASCII bases
 ///
-/// Each byte contains two sequence values.
-/// Returns the new values as bytes.
-
-fn unpack_sequence(input: &[u8]) -> IResult<&[u8], [u8; 2]> {
-    let (_i, v) = complete::le_u8(input)?;
-    Ok((_i, [v >> 4, v & 0x0F]))
-}
-
-/// Read bytes into vector of `Seq`s
-///
-/// The sequence field is bit-packed, thus the length of the returned vec is not `l_seq`
-/// but rather (`l_seq` + 1) / 2. In the event that `l_seq` is odd, the final 4 bits are garbage
-/// and automatically discarded.
-pub fn read_sequence<'a>(input: &'a [u8], l_seq: &u32) -> IResult<&'a [u8], Vec<BamSeq>> {
-    let mut seq: Vec<BamSeq> = Vec::with_capacity(usize::try_from((*l_seq + 1) / 2).unwrap());
-    let mut _i: &[u8] = input;
-    for _ in 0..seq.capacity() {
-        (_i, _) = map(unpack_sequence, |v| {
-            seq.push(to_sequence(&v[0]));
-            seq.push(to_sequence(&v[1]));
-        })(_i)?;
-    }
-    if l_seq % 2 != 0 {
-        seq.pop();
-    }
-    Ok((_i, seq))
+/// The sequence field is bit-packed into (`l_seq` + 1) / 2 bytes. In the event that `l_seq` is
+/// odd, the final 4 bits are garbage and automatically discarded.
+pub fn read_sequence<'a>(input: &'a [u8], l_seq: &u32) -> IResult<&'a [u8], Vec<u8>> {
+    let n_bases = usize::try_from(*l_seq).ok();
+    let n_bytes = n_bases.map(|n| n.div_ceil(2));
+    ensure_available(input, n_bytes)?;
+    let (n_bases, n_bytes) = (n_bases.unwrap_or_default(), n_bytes.unwrap_or_default());
+    let mut seq = Vec::with_capacity(n_bytes * 2);
+    unpack_4bit(input, &mut seq, n_bases);
+    Ok((&input[n_bytes..], seq))
 }
 
 // ============================== //
@@ -225,7 +266,28 @@ pub fn read_sequence<'a>(input: &'a [u8], l_seq: &u32) -> IResult<&'a [u8], Vec<
 ///
 /// `n` is expected to be the value of BAM `seq_len` field.
 fn read_quality(input: &[u8], n: u32) -> IResult<&[u8], Vec<u8>> {
-    count(complete::le_u8, usize::try_from(n).unwrap())(input)
+    let n = usize::try_from(n).ok();
+    ensure_available(input, n)?;
+    let (qual, rest) = input.split_at(n.unwrap_or_default());
+    Ok((rest, qual.to_vec()))
+}
+
+/// Fail unless `input` holds at least `n` bytes
+///
+/// Length fields come straight from the file, so they are checked against the
+/// data actually present before anything is allocated from them. `None` means
+/// the length computation overflowed.
+fn ensure_available<'a, E: ParseError<&'a [u8]>>(
+    input: &'a [u8],
+    n: Option<usize>,
+) -> IResult<&'a [u8], (), E> {
+    match n {
+        Some(n) if n <= input.len() => Ok((input, ())),
+        _ => Err(nom::Err::Failure(E::from_error_kind(
+            input,
+            ErrorKind::TooLarge,
+        ))),
+    }
 }
 
 // ============================== //
@@ -235,7 +297,7 @@ fn read_quality(input: &[u8], n: u32) -> IResult<&[u8], Vec<u8>> {
 // TODO evaluate if there's really any benefit to returning [char; 2]
 // instead of String.
 /// Reads a two-character bam tag
-fn bam_tag(input: &[u8]) -> IResult<&[u8], [char; 2]> {
+fn bam_tag(input: &[u8]) -> RecordResult<'_, [char; 2]> {
     let mut buf: [u8; 2] = [0; 2];
     let (i, _) = fill(complete::le_u8, &mut buf)(input)?;
     Ok((i, [buf[0] as char, buf[1] as char]))
@@ -244,58 +306,84 @@ fn bam_tag(input: &[u8]) -> IResult<&[u8], [char; 2]> {
 /// Parse bytes until encountering NULL (\0)
 ///
 /// Consumes but does not return NULL.
-fn null_terminated_bytes(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    let (mut i, r) = take_until(&[0u8] as &[u8])(input)?;
-    (i, _) = take::<usize, &[u8], nom::error::Error<_>>(1usize)(i).unwrap();
+fn null_terminated_bytes<'a, E: ParseError<&'a [u8]>>(
+    input: &'a [u8],
+) -> IResult<&'a [u8], &'a [u8], E> {
+    let (i, r) = take_until(&[0u8] as &[u8])(input)?;
+    let (i, _) = take(1usize)(i)?;
     Ok((i, r))
 }
 
-/// Read a hex values into vector of u32s
-///
-/// Consumes all valid hex values.
-fn hex_vec(input: &[u8]) -> IResult<&[u8], Vec<u32>> {
-    many1(complete::hex_u32)(input)
+/// Read a NUL-terminated string of hex digit pairs into bytes
+fn hex_bytes(input: &[u8]) -> RecordResult<'_, Vec<u8>> {
+    let (i, v) = null_terminated_bytes(input)?;
+    match decode_hex(v) {
+        Ok(bytes) => Ok((i, bytes)),
+        Err(code) => Err(decode_failure("aux hex string", code)),
+    }
 }
 
 /// Read variable-length auxilliary fields into BamAuxValue
 ///
-/// Consumes subtype, length, and field, returning BamAuxValue.
-fn aux_vec(input: &[u8]) -> IResult<&[u8], BamAuxValue> {
+/// Consumes subtype, length, and field, returning BamAuxValue. An unknown
+/// subtype gives `BamAuxValue::Unknown`, consuming the rest of the input.
+fn aux_vec(input: &[u8]) -> RecordResult<'_, BamAuxValue> {
     let (i, (sub, len)) = tuple((complete::le_u8, complete::le_u32))(input)?;
-    let len = usize::try_from(len).unwrap();
+    if !b"cCsSiIf".contains(&sub) {
+        return Ok(unknown_aux(b'B', input));
+    }
+    let len = usize::try_from(len).ok();
+    let elem_size = match sub {
+        b'c' | b'C' => 1,
+        b's' | b'S' => 2,
+        _ => 4,
+    };
+    ensure_available(i, len.and_then(|n| n.checked_mul(elem_size)))?;
+    let len = len.unwrap_or_default();
     match sub {
-        b'c' => map(count(complete::le_i8, len), |v| BamAuxValue::Bc(v))(i),
-        b'C' => map(count(complete::le_u8, len), |v| BamAuxValue::BC(v))(i),
-        b's' => map(count(complete::le_i16, len), |v| BamAuxValue::Bs(v))(i),
-        b'S' => map(count(complete::le_u16, len), |v| BamAuxValue::BS(v))(i),
-        b'i' => map(count(complete::le_i32, len), |v| BamAuxValue::Bi(v))(i),
-        b'I' => map(count(complete::le_u32, len), |v| BamAuxValue::BI(v))(i),
-        b'f' => map(count(complete::le_f32, len), |v| BamAuxValue::Bf(v))(i),
-        otherwise => panic!("Unknown BAM auxilliary field subtype {otherwise}"),
+        b'c' => map(count(complete::le_i8, len), BamAuxValue::Bc)(i),
+        b'C' => map(count(complete::le_u8, len), BamAuxValue::BC)(i),
+        b's' => map(count(complete::le_i16, len), BamAuxValue::Bs)(i),
+        b'S' => map(count(complete::le_u16, len), BamAuxValue::BS)(i),
+        b'i' => map(count(complete::le_i32, len), BamAuxValue::Bi)(i),
+        b'I' => map(count(complete::le_u32, len), BamAuxValue::BI)(i),
+        b'f' => map(count(complete::le_f32, len), BamAuxValue::Bf)(i),
+        _ => unreachable!("subtype checked above"),
     }
 }
 
+/// A value of unknown size, taking all of `input`
+fn unknown_aux(dtype: u8, input: &[u8]) -> (&[u8], BamAuxValue) {
+    let raw = input.to_vec();
+    (&input[input.len()..], BamAuxValue::Unknown { dtype, raw })
+}
+
 /// Read BAM auxilliary fields into BamAuxField
 ///
-/// Consumes tag, dtype, and value, returning BamAuxField
-fn read_aux_field(input: &[u8]) -> IResult<&[u8], BamAuxField> {
+/// Consumes tag, dtype, and value, returning BamAuxField. An unknown dtype gives
+/// `BamAuxValue::Unknown`, consuming the rest of the input.
+fn read_aux_field(input: &[u8]) -> RecordResult<'_, BamAuxField> {
     let (i, tag) = bam_tag(input)?;
     let (i, dtype) = complete::le_u8(i)?;
     let (i, value) = match dtype {
         b'A' => map(complete::le_u8, |v| BamAuxValue::from(v as char))(i)?,
-        b'c' => map(complete::le_i8, |v| BamAuxValue::from(v))(i)?,
-        b'C' => map(complete::le_u8, |v| BamAuxValue::from(v))(i)?,
-        b's' => map(complete::le_i16, |v| BamAuxValue::from(v))(i)?,
-        b'S' => map(complete::le_u16, |v| BamAuxValue::from(v))(i)?,
-        b'i' => map(complete::le_i32, |v| BamAuxValue::from(v))(i)?,
-        b'I' => map(complete::le_u32, |v| BamAuxValue::from(v))(i)?,
-        b'f' => map(complete::le_f32, |v| BamAuxValue::from(v))(i)?,
-        b'Z' => map(null_terminated_bytes, |v| {
-            BamAuxValue::from(std::str::from_utf8(v).unwrap().to_owned())
-        })(i)?,
-        b'H' => map(hex_vec, |v| BamAuxValue::H(v))(i)?,
+        b'c' => map(complete::le_i8, BamAuxValue::from)(i)?,
+        b'C' => map(complete::le_u8, BamAuxValue::from)(i)?,
+        b's' => map(complete::le_i16, BamAuxValue::from)(i)?,
+        b'S' => map(complete::le_u16, BamAuxValue::from)(i)?,
+        b'i' => map(complete::le_i32, BamAuxValue::from)(i)?,
+        b'I' => map(complete::le_u32, BamAuxValue::from)(i)?,
+        b'f' => map(complete::le_f32, BamAuxValue::from)(i)?,
+        b'Z' => {
+            let (i, v) = null_terminated_bytes(i)?;
+            match std::str::from_utf8(v) {
+                Ok(v) => (i, BamAuxValue::from(v.to_owned())),
+                Err(e) => return Err(decode_failure("aux string", v[e.valid_up_to()])),
+            }
+        }
+        b'H' => map(hex_bytes, BamAuxValue::H)(i)?,
         b'B' => aux_vec(i)?,
-        otherwise => panic!("Invalid BAM auxilliary field {otherwise}"),
+        otherwise => unknown_aux(otherwise, i),
     };
     Ok((i, BamAuxField { tag, value }))
 }
@@ -367,19 +455,67 @@ fn tlen(input: &[u8]) -> IResult<&[u8], i32> {
     complete::le_i32(input)
 }
 
-/// parse read_name
+/// parse read_name, dropping its NUL terminator
 ///
-/// n is expected to be value parsed from `l_read_name`
+/// n is expected to be value parsed from `l_read_name`, which `read_fixed` has
+/// checked with `check_read_name`
 fn read_name(input: &[u8], n: u8) -> IResult<&[u8], &[u8]> {
-    take(n)(input)
+    let (i, name) = take(n)(input)?;
+    Ok((i, &name[..name.len().saturating_sub(1)]))
 }
 
-/// Convert Vec<BamAuxField> to HashMap
-///
-/// Maps BamAuxField.tag (as String) to BamAuxField.
+/// Check that a stored read name ends in its only NUL
+pub(crate) fn check_read_name(stored: &[u8]) -> Result<(), BamError> {
+    let detail = match stored.split_last() {
+        None => String::from("l_read_name is 0"),
+        Some((0, name)) => match name.iter().position(|b| *b == 0) {
+            None => return Ok(()),
+            Some(i) => format!("NUL at byte {i}"),
+        },
+        Some(_) => String::from("not NUL-terminated"),
+    };
+    Err(BamError::CorruptRecord {
+        field: "read_name",
+        detail,
+    })
+}
+
+/// Longest QNAME allowed by SAMv1 1.4
+pub const MAX_QNAME_LEN: usize = 254;
+
+/// Check a read name against the SAM QNAME pattern `[!-?A-~]{1,254}`
+pub fn check_qname(name: &[u8]) -> Result<(), BamError> {
+    match qname_problem(name) {
+        None => Ok(()),
+        Some(detail) => Err(BamError::CorruptRecord {
+            field: "read_name",
+            detail,
+        }),
+    }
+}
+
+/// What keeps `name` from matching the SAM QNAME pattern, if anything
+pub(crate) fn qname_problem(name: &[u8]) -> Option<String> {
+    if name.is_empty() {
+        Some(String::from("empty"))
+    } else if name.len() > MAX_QNAME_LEN {
+        Some(format!(
+            "{} characters, more than {MAX_QNAME_LEN}",
+            name.len()
+        ))
+    } else {
+        let b = name
+            .iter()
+            .find(|b| !matches!(b, b'!'..=b'?' | b'A'..=b'~'))?;
+        Some(format!("invalid character {b:#04x}"))
+    }
+}
 
-fn aux_to_hash(fields: Vec<BamAuxField>) -> FxHashMap<String, BamAuxField> {
-    let mut hmap = FxHashMap::default();
+/// Convert Vec<BamAuxField> to an `AuxMap`
+///
+/// Maps BamAuxField.tag (as String) to BamAuxField, keeping the fields in order.
+fn aux_to_hash(fields: Vec<BamAuxField>) -> AuxMap {
+    let mut hmap = AuxMap::default();
     for f in fields {
         hmap.insert(f.tag.iter().collect(), f);
     }
@@ -388,47 +524,111 @@ fn aux_to_hash(fields: Vec<BamAuxField>) -> FxHashMap<String, BamAuxField> {
 
 /// Maybe correct for long CIGAR fields
 ///
-/// If the criteria described in SAMv1 4.2.2 are met,
-/// update `n_cigar_op` and `cigar_op` fields, and remove the
-/// "CG" aux field.
-
-fn maybe_correct_cigar(
-    n_cigar_op: &mut u16,
-    seq_len: &usize,
+/// If the criteria described in SAMv1 4.2.2 are met (a `kSmN` placeholder CIGAR,
+/// `k` being the read length, and a CG:B:I field), update `n_cigar_op` and
+/// `cigar` from CG, and remove the CG aux field. CG holds one op per element,
+/// packed as in the CIGAR itself. Fails if the CG field holds an invalid op,
+/// leaving everything unchanged.
+pub(crate) fn maybe_correct_cigar(
+    n_cigar_op: &mut u32,
+    seq_len: usize,
     cigar: &mut Vec<CigarOp>,
-    aux_hash: &mut FxHashMap<String, BamAuxField>,
-    reference: &BamReference,
-) {
-    if *n_cigar_op == 2
-        && aux_hash.contains_key("CG")
-        && cigar
-            == &[
-                CigarOp::S(u32::try_from(*seq_len).unwrap()),
-                CigarOp::N(reference.l_ref),
-            ]
+    aux_hash: &mut AuxMap,
+) -> Result<(), DecodeError> {
+    let placeholder = match cigar.as_slice() {
+        [CigarOp::S(k), CigarOp::N(_)] => usize::try_from(*k).is_ok_and(|k| k == seq_len),
+        _ => false,
+    };
+    if !placeholder {
+        return Ok(());
+    }
+    if let Some(BamAuxField {
+        tag: _,
+        value: BamAuxValue::BI(v),
+    }) = aux_hash.get("CG")
     {
-        match aux_hash.get("CG") {
-            Some(BamAuxField {
-                tag: _,
-                value: BamAuxValue::BI(v),
-            }) => {
-                *n_cigar_op = u16::try_from(v.len()).unwrap();
-                *cigar = v
-                    .chunks_exact(2)
-                    .map(|v: &[u32]| to_cigar(v.try_into().unwrap()))
-                    .collect::<Vec<CigarOp>>();
-                aux_hash.remove("CG");
+        let long_cigar = v
+            .iter()
+            .map(|&packed| to_cigar(unpack_cigar(packed)))
+            .collect::<Result<Vec<CigarOp>, DecodeError>>()?;
+        *n_cigar_op = u32::try_from(long_cigar.len()).unwrap_or(u32::MAX);
+        *cigar = long_cigar;
+        aux_hash.shift_remove("CG");
+    }
+    Ok(())
+}
+
+/// Describe a nom error without dumping the input it failed on
+fn nom_detail(e: &nom::Err<RecordError<&[u8]>>) -> String {
+    match e {
+        nom::Err::Incomplete(_) => String::from("truncated"),
+        nom::Err::Error(e) | nom::Err::Failure(e) => match e {
+            RecordError::Nom(e) if e.code == ErrorKind::TooLarge => {
+                String::from("length exceeds the remaining block")
             }
-            _ => {}
-        }
+            RecordError::Nom(e) => format!("{:?}", e.code),
+            RecordError::Decode(e) => corruption(*e).1,
+        },
     }
 }
 
-/// Read a complete alignment record
-pub fn read_alignment<'a>(
-    input: &'a [u8],
-    references: &Vec<BamReference>,
-) -> IResult<&'a [u8], Record> {
+/// Field and detail of a `BamError::CorruptRecord`, kept apart since `BamError` is not `Clone`
+pub(crate) type Corruption = (&'static str, String);
+
+pub(crate) fn corrupt((field, detail): Corruption) -> BamError {
+    BamError::CorruptRecord { field, detail }
+}
+
+pub(crate) fn corruption(e: DecodeError) -> Corruption {
+    (e.field, format!("unexpected byte {:#04x}", e.code))
+}
+
+/// Size of the fixed-length fields following block_size
+const FIXED_FIELDS_SIZE: usize = 32;
+
+/// Check a length field against the bytes left in the block, returning what remains after it
+fn consume_len(
+    field: &'static str,
+    len: Option<usize>,
+    remaining: usize,
+) -> Result<usize, BamError> {
+    match len {
+        Some(n) if n <= remaining => Ok(remaining - n),
+        Some(n) => Err(BamError::CorruptRecord {
+            field,
+            detail: format!("needs {n} bytes but only {remaining} remain in the block"),
+        }),
+        None => Err(BamError::CorruptRecord {
+            field,
+            detail: String::from("length overflows"),
+        }),
+    }
+}
+
+/// The fixed-length fields at the start of an alignment block
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FixedFields {
+    pub block_size: u32,
+    pub ref_id: i32,
+    pub pos: i32,
+    pub l_read_name: u8,
+    pub mapq: u8,
+    pub bin: u16,
+    pub n_cigar_op: u16,
+    pub flag: u16,
+    pub l_seq: u32,
+    pub next_ref_id: i32,
+    pub next_pos: i32,
+    pub tlen: i32,
+}
+
+/// Read the fixed-length fields of an alignment block
+///
+/// Variable-length fields are checked against block_size but not parsed, so a corrupt
+/// length yields `BamError::CorruptRecord` rather than a huge allocation later on.
+/// Returns the input following the block, the fixed fields and the variable-length
+/// part of the block (read name through aux).
+pub(crate) fn read_fixed(input: &[u8]) -> Result<(&[u8], FixedFields, &[u8]), BamError> {
     let (
         i,
         (
@@ -438,7 +638,7 @@ pub fn read_alignment<'a>(
             l_read_name,
             mapq,
             bin,
-            mut n_cigar_op,
+            n_cigar_op,
             flag,
             l_seq,
             next_ref_id,
@@ -460,53 +660,39 @@ pub fn read_alignment<'a>(
         tlen,
     ))(input)?;
 
-    // each of these requires one of the above items
-    let (i, read_name_bytes) = read_name(i, l_read_name)?;
-    let read_name = String::from_utf8_lossy(read_name_bytes).to_string();
-    let (i, mut cigar) = read_cigar(i, &n_cigar_op)?;
-    let (i, seq) = read_sequence(i, &l_seq)?;
-
-    let (mut i, raw_qual) = read_quality(i, l_seq)?;
-    let qual = if raw_qual.iter().all(|q| q == &255) {
-        None
-    } else {
-        Some(raw_qual)
-    };
-
-    let mut aux_fields: Vec<BamAuxField> = Vec::with_capacity(4);
-    if !i.is_empty() {
-        (i, aux_fields) = many1(read_aux_field)(i).unwrap();
-    }
-    let mut aux_hash: Option<FxHashMap<String, BamAuxField>> = if aux_fields.len() > 0 {
-        Some(aux_to_hash(aux_fields))
-    } else {
-        None
-    };
-
-    let mut ref_name = String::from("*");
-    if ref_id >= 0 {
-        let reference = &references[usize::try_from(ref_id).unwrap()];
-        ref_name = reference.name.clone();
-        maybe_correct_cigar(
-            &mut n_cigar_op,
-            &seq.len(),
-            &mut cigar,
-            aux_hash.as_mut().unwrap(),
-            reference,
-        );
+    let block_len = usize::try_from(block_size)
+        .ok()
+        .filter(|b| *b >= FIXED_FIELDS_SIZE)
+        .ok_or_else(|| BamError::CorruptRecord {
+            field: "block_size",
+            detail: format!("{block_size} is smaller than the fixed fields"),
+        })?;
+    let var_len = block_len - FIXED_FIELDS_SIZE;
+    if var_len > i.len() {
+        return Err(BamError::CorruptRecord {
+            field: "block_size",
+            detail: format!("{block_size} extends past the end of the input"),
+        });
     }
+    let (var, rest) = i.split_at(var_len);
 
-    let mut next_ref_name = String::from("*");
-    if next_ref_id >= 0 {
-        next_ref_name = references[usize::try_from(ref_id).unwrap()].name.clone();
-    }
+    let remaining = consume_len("l_read_name", Some(usize::from(l_read_name)), var_len)?;
+    check_read_name(&var[..usize::from(l_read_name)])?;
+    let remaining = consume_len(
+        "n_cigar_op",
+        usize::from(n_cigar_op).checked_mul(4),
+        remaining,
+    )?;
+    let seq_bytes = usize::try_from(l_seq)
+        .ok()
+        .and_then(|n| n.checked_add(n.div_ceil(2)));
+    consume_len("l_seq", seq_bytes, remaining)?;
 
     Ok((
-        i,
-        Record {
+        rest,
+        FixedFields {
             block_size,
             ref_id,
-            ref_name,
             pos,
             l_read_name,
             mapq,
@@ -515,9 +701,261 @@ pub fn read_alignment<'a>(
             flag,
             l_seq,
             next_ref_id,
-            next_ref_name,
             next_pos,
             tlen,
+        },
+        var,
+    ))
+}
+
+/// Decode the aux fields at the end of a block, None when there are none
+///
+/// Fails on the field holding an invalid type code, or on "aux" when the fields
+/// cannot be parsed at all.
+pub(crate) fn read_aux(input: &[u8]) -> Result<Option<AuxMap>, Corruption> {
+    if input.is_empty() {
+        return Ok(None);
+    }
+    many1(read_aux_field)(input)
+        .map(|(_, fields)| Some(aux_to_hash(fields)))
+        .map_err(|e| match &e {
+            nom::Err::Failure(RecordError::Decode(d)) => (d.field, nom_detail(&e)),
+            _ => ("aux", nom_detail(&e)),
+        })
+}
+
+/// Fail on the first aux value of a type the spec does not define
+///
+/// Reports the same field and byte as a parser that rejects unknown types outright.
+pub(crate) fn check_aux_types(aux: Option<&AuxMap>) -> Result<(), Corruption> {
+    let unknown = aux
+        .into_iter()
+        .flat_map(|m| m.values())
+        .find_map(|f| match &f.value {
+            BamAuxValue::Unknown { dtype: b'B', raw } => Some(DecodeError {
+                field: "aux array subtype",
+                code: raw[0],
+            }),
+            BamAuxValue::Unknown { dtype, .. } => Some(DecodeError {
+                field: "aux type",
+                code: *dtype,
+            }),
+            _ => None,
+        });
+    unknown.map_or(Ok(()), |e| Err(corruption(e)))
+}
+
+/// The reference a ref_id points to, None when unplaced (-1) or not in the header
+pub(crate) fn reference_of(references: &[BamReference], ref_id: i32) -> Option<&BamReference> {
+    usize::try_from(ref_id).ok().and_then(|i| references.get(i))
+}
+
+/// Names for RNAME and RNEXT, "*" when unplaced
+pub(crate) fn ref_names(fixed: &FixedFields, references: &[BamReference]) -> (String, String) {
+    let name = |id: i32| {
+        reference_of(references, id).map_or_else(|| String::from("*"), |r| r.name.clone())
+    };
+    (name(fixed.ref_id), name(fixed.next_ref_id))
+}
+
+/// Offset of l_seq in an alignment block, block_size included
+const L_SEQ_OFFSET: usize = 20;
+
+/// Which length wins when l_seq disagrees with the sequence stored in a block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RepairPolicy {
+    /// Keep the stored bases and qualities, and set l_seq to their number
+    TrustSequence,
+    /// Keep l_seq, truncating the stored bases and qualities or padding them with
+    /// N and quality 0
+    TrustLseq,
+}
+
+/// Whether `aux` holds whole fields with valid tags and types only
+///
+/// Walks the fields without decoding them, since it runs on every record.
+fn clean_aux(mut aux: &[u8]) -> bool {
+    while !aux.is_empty() {
+        let [t0, t1, dtype, rest @ ..] = aux else {
+            return false;
+        };
+        if !t0.is_ascii_alphabetic() || !t1.is_ascii_alphanumeric() {
+            return false;
+        }
+        let size = match dtype {
+            b'A' | b'c' | b'C' => Some(1),
+            b's' | b'S' => Some(2),
+            b'i' | b'I' | b'f' => Some(4),
+            b'Z' | b'H' => rest
+                .iter()
+                .position(|b| *b == 0)
+                .filter(|&n| {
+                    let v = &rest[..n];
+                    match dtype {
+                        b'Z' => std::str::from_utf8(v).is_ok(),
+                        _ => n % 2 == 0 && v.iter().all(u8::is_ascii_hexdigit),
+                    }
+                })
+                .map(|n| n + 1),
+            b'B' => match rest {
+                [sub, n0, n1, n2, n3, ..] => {
+                    let elem = match sub {
+                        b'c' | b'C' => Some(1),
+                        b's' | b'S' => Some(2),
+                        b'i' | b'I' | b'f' => Some(4),
+                        _ => None,
+                    };
+                    let n = u32::from_le_bytes([*n0, *n1, *n2, *n3]) as usize;
+                    elem.and_then(|e| n.checked_mul(e))
+                        .and_then(|len| len.checked_add(5))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        match size {
+            Some(size) if size <= rest.len() => aux = &rest[size..],
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// l_seq of the alignment block `block` (exactly block_size + 4 bytes) and the
+/// number of bases it actually stores, when the two are off by one
+///
+/// The stored length is the one, of l_seq - 1 and l_seq + 1, whose SEQ and QUAL
+/// fit in the block and leave aux fields that decode cleanly, trying the CIGAR's
+/// query length first. A CIGAR agreeing with l_seq, or l_seq leaving no bytes or
+/// clean aux fields, is taken as consistent.
+pub(crate) fn l_seq_mismatch(block: &[u8]) -> Option<(u32, u32)> {
+    if block.len() < FIXED_FIELDS_SIZE + 4 {
+        return None;
+    }
+    let l_read_name = usize::from(block[12]);
+    let n_cigar_op = usize::from(u16::from_le_bytes([block[16], block[17]]));
+    let l_seq = u32::from_le_bytes(block[L_SEQ_OFFSET..L_SEQ_OFFSET + 4].try_into().ok()?);
+    let var = &block[FIXED_FIELDS_SIZE + 4..];
+    let head = l_read_name + 4 * n_cigar_op;
+    let cigar = var.get(l_read_name..head)?;
+    let seq_end = |n: u32| head + n as usize + (n as usize).div_ceil(2);
+    let fits = |n: u32| seq_end(n) <= var.len() && clean_aux(&var[seq_end(n)..]);
+
+    // ops M, I, S, = and X consume query bases
+    let query_len = (n_cigar_op > 0).then(|| {
+        cigar
+            .chunks_exact(4)
+            .map(|op| u32::from_le_bytes([op[0], op[1], op[2], op[3]]))
+            .filter(|op| matches!(op & 0xf, 0 | 1 | 4 | 7 | 8))
+            .map(|op| op >> 4)
+            .sum::<u32>()
+    });
+    if query_len == Some(l_seq) || seq_end(l_seq) == var.len() || fits(l_seq) {
+        return None;
+    }
+    let neighbours = [l_seq.checked_sub(1), l_seq.checked_add(1)];
+    let stored = query_len
+        .filter(|q| neighbours.contains(&Some(*q)))
+        .into_iter()
+        .chain(neighbours.into_iter().flatten())
+        .find(|n| fits(*n))?;
+    Some((l_seq, stored))
+}
+
+/// The alignment block `block` rewritten so that l_seq agrees with the `stored`
+/// bases it holds, as `policy` says
+pub(crate) fn repair_l_seq(block: &[u8], stored: u32, policy: RepairPolicy) -> Vec<u8> {
+    let l_read_name = usize::from(block[12]);
+    let n_cigar_op = usize::from(u16::from_le_bytes([block[16], block[17]]));
+    let head = FIXED_FIELDS_SIZE + 4 + l_read_name + 4 * n_cigar_op;
+    let mut out = block[..head].to_vec();
+    let (seq, rest) = block[head..].split_at((stored as usize).div_ceil(2));
+    let (qual, aux) = rest.split_at(stored as usize);
+    match policy {
+        RepairPolicy::TrustSequence => {
+            out[L_SEQ_OFFSET..L_SEQ_OFFSET + 4].copy_from_slice(&stored.to_le_bytes());
+            out.extend(seq);
+            out.extend(qual);
+        }
+        RepairPolicy::TrustLseq => {
+            let n = u32::from_le_bytes(block[L_SEQ_OFFSET..L_SEQ_OFFSET + 4].try_into().unwrap())
+                as usize;
+            // 4-bit codes, padded with N (15)
+            let base = |i: usize| match seq.get(i / 2) {
+                Some(b) if i < stored as usize && i.is_multiple_of(2) => b >> 4,
+                Some(b) if i < stored as usize => b & 0xf,
+                _ => 15,
+            };
+            out.extend((0..n).step_by(2).map(|i| {
+                let lo = if i + 1 < n { base(i + 1) } else { 0 };
+                base(i) << 4 | lo
+            }));
+            // a missing QUAL stays missing
+            let pad = if qual.iter().all(|q| *q == 0xff) {
+                0xff
+            } else {
+                0
+            };
+            out.extend(qual.iter().copied().chain(std::iter::repeat(pad)).take(n));
+        }
+    }
+    out.extend(aux);
+    let block_size = (out.len() - 4) as u32;
+    out[..4].copy_from_slice(&block_size.to_le_bytes());
+    out
+}
+
+/// Read a complete alignment record
+///
+/// Lengths are checked by `read_fixed` before anything is parsed, and parsing never
+/// reads past the end of the block.
+pub fn read_alignment<'a>(
+    input: &'a [u8],
+    references: &[BamReference],
+) -> Result<(&'a [u8], Record), BamError> {
+    let (rest, fixed, var) = read_fixed(input)?;
+    let mut n_cigar_op = u32::from(fixed.n_cigar_op);
+
+    // each of these requires one of the fixed fields
+    let (i, read_name_bytes) = read_name(var, fixed.l_read_name)?;
+    let read_name = read_name_bytes.to_vec();
+    let (i, mut cigar) = read_cigar(i, &fixed.n_cigar_op)?;
+    let (i, seq) = read_sequence(i, &fixed.l_seq)?;
+
+    let (i, raw_qual) = read_quality(i, fixed.l_seq)?;
+    // absent QUAL is stored as 0xff bytes, or as nothing at all when SEQ is absent
+    let qual = if raw_qual.is_empty() || raw_qual.iter().all(|q| q == &255) {
+        None
+    } else {
+        Some(raw_qual)
+    };
+
+    let mut aux_hash = read_aux(i).map_err(corrupt)?;
+
+    let (ref_name, next_ref_name) = ref_names(&fixed, references);
+    // a record without aux fields has no CG to restore the CIGAR from
+    if let Some(aux) = &mut aux_hash {
+        maybe_correct_cigar(&mut n_cigar_op, seq.len(), &mut cigar, aux)?;
+    }
+
+    Ok((
+        rest,
+        Record {
+            block_size: fixed.block_size,
+            ref_id: fixed.ref_id,
+            ref_name,
+            pos: fixed.pos,
+            l_read_name: fixed.l_read_name,
+            mapq: fixed.mapq,
+            bin: fixed.bin,
+            n_cigar_op,
+            flag: fixed.flag,
+            l_seq: fixed.l_seq,
+            next_ref_id: fixed.next_ref_id,
+            next_ref_name,
+            next_pos: fixed.next_pos,
+            tlen: fixed.tlen,
             read_name,
             cigar,
             seq,
@@ -526,3 +964,347 @@ pub fn read_alignment<'a>(
         },
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Raw alignment block for an unmapped record with the given length fields
+    fn record_bytes(block_size: u32, n_cigar_op: u16, l_seq: u32, body: &[u8]) -> Vec<u8> {
+        let name = b"r\0";
+        let mut b = Vec::new();
+        b.extend(block_size.to_le_bytes());
+        b.extend((-1i32).to_le_bytes());
+        b.extend((-1i32).to_le_bytes());
+        b.push(name.len() as u8);
+        b.push(0);
+        b.extend(4680u16.to_le_bytes());
+        b.extend(n_cigar_op.to_le_bytes());
+        b.extend(4u16.to_le_bytes());
+        b.extend(l_seq.to_le_bytes());
+        b.extend((-1i32).to_le_bytes());
+        b.extend((-1i32).to_le_bytes());
+        b.extend(0i32.to_le_bytes());
+        b.extend(name);
+        b.extend(body);
+        b
+    }
+
+    fn corrupt_field(input: &[u8]) -> &'static str {
+        match read_alignment(input, &[]) {
+            Err(BamError::CorruptRecord { field, .. }) => field,
+            other => panic!("expected CorruptRecord, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_valid_block_stops_at_block_end() {
+        let mut body = Vec::new();
+        body.extend((4u32 << 4).to_le_bytes());
+        body.extend([0x12, 0x48]);
+        body.extend([30; 4]);
+        let mut input = record_bytes(32 + 2 + body.len() as u32, 1, 4, &body);
+        input.extend(b"next");
+        let (rest, rec) = read_alignment(&input, &[]).unwrap();
+        assert_eq!(rest, b"next");
+        assert_eq!(rec.l_seq(), 4);
+        assert_eq!(rec.aux(), None);
+    }
+
+    /// Block of a record with more CIGAR ops than n_cigar_op can hold, laid out
+    /// as htslib's `bam_write1` stores it: a `kSmN` placeholder, `m` being the
+    /// reference span, and the real ops packed one per element in CG:B:I
+    fn long_cigar_block(pairs: u32) -> Vec<u8> {
+        let l_seq = 2 * pairs;
+        let mut body = Vec::new();
+        body.extend((l_seq << 4 | 4).to_le_bytes());
+        body.extend((pairs << 4 | 3).to_le_bytes());
+        body.extend(std::iter::repeat_n(0x12, pairs as usize));
+        body.extend(std::iter::repeat_n(30, l_seq as usize));
+        body.extend(b"NMC\0CGBI");
+        body.extend((2 * pairs).to_le_bytes());
+        for _ in 0..pairs {
+            body.extend((1u32 << 4).to_le_bytes());
+            body.extend((1u32 << 4 | 1).to_le_bytes());
+        }
+        let mut input = record_bytes(32 + 2 + body.len() as u32, 2, l_seq, &body);
+        input[4..8].copy_from_slice(&0i32.to_le_bytes());
+        input[8..12].copy_from_slice(&99i32.to_le_bytes());
+        input[18..20].copy_from_slice(&0u16.to_le_bytes());
+        input
+    }
+
+    #[test]
+    fn test_long_cigar() {
+        // 70000 ops, more than fit in n_cigar_op
+        let input = long_cigar_block(35_000);
+        let (rest, rec) = read_alignment(&input, &[]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(rec.n_cigar_op(), 70_000);
+        assert_eq!(rec.cigar().len(), 70_000);
+        assert!(rec
+            .cigar()
+            .chunks(2)
+            .all(|c| c == [CigarOp::M(1), CigarOp::I(1)]));
+        assert_eq!(rec.aux().unwrap().keys().collect::<Vec<_>>(), ["NM"]);
+
+        let (_, lazy) = crate::lazy::LazyRecord::from_block(&input, &[]).unwrap();
+        assert_eq!(lazy.cigar(), rec.cigar());
+        assert_eq!(lazy.into_record().unwrap().n_cigar_op(), 70_000);
+
+        // an invalid op in CG is corruption, not a panic
+        let mut input = long_cigar_block(2);
+        let last = input.len() - 4;
+        input[last..].copy_from_slice(&(1u32 << 4 | 9).to_le_bytes());
+        assert_eq!(corrupt_field(&input), "CIGAR op");
+
+        // without the placeholder, CG is an ordinary tag
+        let mut input = long_cigar_block(2);
+        input[36 + 2..36 + 6].copy_from_slice(&(3u32 << 4 | 4).to_le_bytes());
+        let (_, rec) = read_alignment(&input, &[]).unwrap();
+        assert_eq!(rec.n_cigar_op(), 2);
+        assert!(rec.aux().unwrap().contains_key("CG"));
+    }
+
+    #[test]
+    fn test_huge_cigar_count() {
+        let input = record_bytes(100, 65535, 0, &[0; 66]);
+        assert_eq!(corrupt_field(&input), "n_cigar_op");
+    }
+
+    #[test]
+    fn test_huge_seq_len() {
+        let input = record_bytes(40, 0, u32::MAX, &[0; 6]);
+        assert_eq!(corrupt_field(&input), "l_seq");
+    }
+
+    #[test]
+    fn test_huge_aux_array() {
+        let mut body = b"XBBi".to_vec();
+        body.extend(1_000_000_000u32.to_le_bytes());
+        body.extend([0; 8]);
+        let input = record_bytes(32 + 2 + body.len() as u32, 0, 0, &body);
+        assert_eq!(corrupt_field(&input), "aux");
+    }
+
+    #[test]
+    fn test_read_name_terminator() {
+        let input = record_bytes(34, 0, 0, &[]);
+        let (_, rec) = read_alignment(&input, &[]).unwrap();
+        assert_eq!((rec.read_name(), rec.l_read_name()), (&b"r"[..], 2));
+
+        // l_read_name at byte 12, name at bytes 36..38
+        let cases: [(usize, u8, &str); 3] = [
+            (12, 0, "l_read_name is 0"),
+            (36, 0, "NUL at byte 0"),
+            (37, b'x', "not NUL-terminated"),
+        ];
+        for (at, b, detail) in cases {
+            let mut input = input.clone();
+            input[at] = b;
+            match read_alignment(&input, &[]) {
+                Err(BamError::CorruptRecord { field, detail: d }) => {
+                    assert_eq!((field, d.as_str()), ("read_name", detail))
+                }
+                other => panic!("expected CorruptRecord, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_qname() {
+        assert!(check_qname(b"SRR1.1/1").is_ok());
+        assert!(check_qname(b"*").is_ok());
+        assert!(check_qname(&[b'a'; MAX_QNAME_LEN]).is_ok());
+        for name in [
+            &b""[..],
+            b"r@1",
+            b"r 1",
+            b"r\xe9",
+            &[b'a'; MAX_QNAME_LEN + 1],
+        ] {
+            assert!(
+                matches!(
+                    check_qname(name),
+                    Err(BamError::CorruptRecord {
+                        field: "read_name",
+                        ..
+                    })
+                ),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_ref_id_past_header() {
+        let mut input = record_bytes(34, 0, 0, &[]);
+        input[4..8].copy_from_slice(&3i32.to_le_bytes());
+        input[24..28].copy_from_slice(&7i32.to_le_bytes());
+        let (_, rec) = read_alignment(&input, &[]).unwrap();
+        assert_eq!(rec.ref_id, 3);
+        assert!(rec.to_string().contains("\t*\t"));
+    }
+
+    #[test]
+    fn test_mate_on_other_reference() {
+        let references = [
+            BamReference::new("chr1", 1000),
+            BamReference::new("chr2", 1000),
+        ];
+        let mut input = record_bytes(34, 0, 0, &[]);
+        input[4..8].copy_from_slice(&0i32.to_le_bytes());
+        input[24..28].copy_from_slice(&1i32.to_le_bytes());
+        let (_, rec) = read_alignment(&input, &references).unwrap();
+        assert_eq!((rec.ref_name(), rec.next_ref_name()), ("chr1", "chr2"));
+        assert!(rec.to_string().contains("\tchr1\t") && rec.to_string().contains("\tchr2\t"));
+
+        let (_, lazy) = crate::lazy::LazyRecord::from_block(&input, &references).unwrap();
+        assert_eq!(lazy.into_record().unwrap().next_ref_name(), "chr2");
+    }
+
+    #[test]
+    fn test_aux_display() {
+        let mut body = b"XFf".to_vec();
+        body.extend(1.5f32.to_le_bytes());
+        body.extend(b"XBBs\x02\0\0\0");
+        body.extend((-3i16).to_le_bytes());
+        body.extend(7i16.to_le_bytes());
+        let input = record_bytes(32 + 2 + body.len() as u32, 0, 0, &body);
+        let (_, rec) = read_alignment(&input, &[]).unwrap();
+        let aux = rec.aux.unwrap();
+        assert_eq!(aux["XF"].to_string(), "XF:f:1.5");
+        assert_eq!(aux["XB"].to_string(), "XB:B:s,-3,7");
+        let h = BamAuxValue::H(vec![0x1a, 0x2b, 0xff]);
+        assert_eq!(h.to_string(), "H:1A2BFF");
+    }
+
+    #[test]
+    fn test_bad_block_size() {
+        assert_eq!(corrupt_field(&record_bytes(8, 0, 0, &[])), "block_size");
+        assert_eq!(corrupt_field(&record_bytes(500, 0, 0, &[])), "block_size");
+    }
+
+    #[test]
+    fn test_invalid_codes() {
+        let cases: [(&[u8], &str, &str); 3] = [
+            (b"XXQ\x01", "aux type", "unexpected byte 0x51"),
+            (
+                b"XXBq\x01\0\0\0\x01",
+                "aux array subtype",
+                "unexpected byte 0x71",
+            ),
+            (b"NMC\0XXQ\x01", "aux type", "unexpected byte 0x51"),
+        ];
+        for (body, field, detail) in cases {
+            let input = record_bytes(32 + 2 + body.len() as u32, 0, 0, body);
+            let (_, rec) = read_alignment(&input, &[]).unwrap();
+            assert_eq!(
+                check_aux_types(rec.aux.as_ref()),
+                Err((field, String::from(detail)))
+            );
+        }
+        let body = b"XXZr\xe9ad\0";
+        let input = record_bytes(32 + 2 + body.len() as u32, 0, 0, body);
+        match read_alignment(&input, &[]) {
+            Err(BamError::CorruptRecord { field, detail }) => {
+                assert_eq!(
+                    (field, detail.as_str()),
+                    ("aux string", "unexpected byte 0xe9")
+                )
+            }
+            other => panic!("expected CorruptRecord, got {other:?}"),
+        }
+        assert_eq!(
+            to_cigar([15, 3]),
+            Err(DecodeError {
+                field: "CIGAR op",
+                code: 15
+            })
+        );
+        assert_eq!(to_cigar([8, 3]), Ok(CigarOp::X(3)));
+    }
+
+    #[test]
+    fn test_unknown_aux_types() {
+        let body = b"NMC\x02XXQ\x01\x02ASC\x03";
+        let input = record_bytes(32 + 2 + body.len() as u32, 0, 0, body);
+        let (rest, rec) = read_alignment(&input, &[]).unwrap();
+        assert!(rest.is_empty());
+        let aux = rec.aux.as_ref().unwrap();
+        assert_eq!(aux.len(), 2);
+        assert_eq!(aux["NM"].value, BamAuxValue::C(2));
+        // the size of Q is unknown, so the AS field after it is swallowed
+        assert_eq!(
+            aux["XX"].value,
+            BamAuxValue::Unknown {
+                dtype: b'Q',
+                raw: b"\x01\x02ASC\x03".to_vec()
+            }
+        );
+        assert_eq!(aux["XX"].to_string(), "XX:Q:<6 undecoded bytes>");
+
+        let body = b"XXBq\x02\0\0\0ab";
+        let input = record_bytes(32 + 2 + body.len() as u32, 0, 0, body);
+        let (_, rec) = read_alignment(&input, &[]).unwrap();
+        assert_eq!(
+            rec.aux.unwrap()["XX"].value,
+            BamAuxValue::Unknown {
+                dtype: b'B',
+                raw: b"q\x02\0\0\0ab".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn test_aux_sam_binary_round_trip() {
+        for text in crate::tests::SAM_AUX {
+            let field = BamAuxField::from_sam_str(text).unwrap();
+            let mut bytes = field.tag.map(|c| c as u8).to_vec();
+            crate::writer::encode_aux_value(&field.value, &mut bytes).unwrap();
+            let (rest, back) = read_aux_field(&bytes).unwrap();
+            assert!(rest.is_empty(), "{text}");
+            assert_eq!(back.to_sam_string(), *text);
+        }
+    }
+
+    #[test]
+    fn test_clean_aux() {
+        for aux in [
+            &b""[..],
+            b"RGZrun1\0NMC\x02",
+            b"XHH1AE3\0",
+            b"XBBs\x02\0\0\0\x01\0\x02\0",
+        ] {
+            assert!(clean_aux(aux), "{aux:?}");
+            assert!(read_aux(aux).is_ok());
+        }
+        for aux in [
+            &b"RGZrun1"[..],
+            b"1GZrun1\0",
+            b"XXQ\x01",
+            b"NMi\x01",
+            b"XHH1AE\0",
+            b"XBBs\x02\0\0\0\x01\0",
+            b"XBBq\x01\0\0\0\x01",
+            b"XXZr\xe9ad\0",
+        ] {
+            assert!(!clean_aux(aux), "{aux:?}");
+        }
+    }
+
+    #[test]
+    fn test_aux_hex() {
+        let (rest, field) = read_aux_field(b"XHH1AE3\0NMC\x05").unwrap();
+        assert_eq!(field.value, BamAuxValue::H(vec![0x1a, 0xe3]));
+        assert_eq!(rest, b"NMC\x05");
+        let err = read_aux_field(b"XHH1AE\0").unwrap_err();
+        assert!(matches!(
+            err,
+            nom::Err::Failure(RecordError::Decode(DecodeError {
+                field: "aux hex string",
+                code: b'E'
+            }))
+        ));
+    }
+}
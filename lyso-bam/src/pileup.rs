@@ -0,0 +1,282 @@
+//! Pileup columns from a coordinate-sorted BAM stream
+//!
+//! `Pileup` yields, for every reference position covered by at least one read,
+//! the base each read aligns there. Only reads overlapping the current position
+//! are held in memory.
+//!
+//! As in `coverage`, unmapped, secondary, QC-fail and duplicate records are
+//! ignored. Deletions are reported as `PileupBase::Deletion`; insertions, clips
+//! and skipped (N) regions are not reported.
+
+use std::collections::VecDeque;
+
+use lyso_common::pos::ZeroBased;
+use lyso_common::CigarOp;
+
+use crate::sort::CoordinateOrder;
+use crate::{BamError, Record};
+
+/// What a read shows at a pileup position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PileupBase {
+    /// IUPAC code from SEQ, upper case; N when SEQ is absent
+    Base(u8),
+    /// A deletion from the reference
+    Deletion,
+}
+
+/// One read at one pileup position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PileupEntry {
+    /// Base or deletion
+    pub base: PileupBase,
+    /// Phred base quality, None for deletions and reads without QUAL
+    pub qual: Option<u8>,
+    /// Mapping quality of the read
+    pub mapq: u8,
+    /// The read is reverse complemented
+    pub is_reverse: bool,
+}
+
+/// Every read aligned at one reference position
+#[derive(Debug, Clone, PartialEq)]
+pub struct PileupColumn {
+    /// Index of the reference in the header
+    pub ref_id: usize,
+    /// Reference position
+    pub pos: ZeroBased,
+    /// One entry per read covering `pos`
+    pub entries: Vec<PileupEntry>,
+}
+
+impl PileupColumn {
+    /// Number of reads, deletions included
+    pub fn depth(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// A read's entries by reference position, consumed from the front
+struct ActiveRead {
+    ref_id: usize,
+    entries: VecDeque<(u64, PileupEntry)>,
+}
+
+impl ActiveRead {
+    /// None for reads that are not part of a pileup
+    fn from_record(rec: &Record) -> Option<ActiveRead> {
+        if rec.is_unmapped() || rec.is_secondary() || rec.is_qc_fail() || rec.is_duplicate() {
+            return None;
+        }
+        let (Ok(ref_id), Some(pos)) = (usize::try_from(rec.ref_id()), rec.pos()) else {
+            return None;
+        };
+        let (seq, qual) = (rec.seq(), rec.qual());
+        let entry = |base, qual| PileupEntry {
+            base,
+            qual,
+            mapq: rec.mapq(),
+            is_reverse: rec.is_reverse(),
+        };
+        let mut entries = VecDeque::new();
+        let (mut at, mut qpos) = (pos.get(), 0);
+        for op in rec.cigar() {
+            let len = op.len() as usize;
+            match op {
+                CigarOp::M(_) | CigarOp::Eq(_) | CigarOp::X(_) => {
+                    for i in qpos..qpos + len {
+                        let base = seq.get(i).copied().unwrap_or(b'N');
+                        let q = qual.and_then(|q| q.get(i)).copied();
+                        entries.push_back((at, entry(PileupBase::Base(base), q)));
+                        at += 1;
+                    }
+                }
+                CigarOp::D(_) => {
+                    for _ in 0..len {
+                        entries.push_back((at, entry(PileupBase::Deletion, None)));
+                        at += 1;
+                    }
+                }
+                CigarOp::N(_) => at += len as u64,
+                CigarOp::I(_) | CigarOp::S(_) | CigarOp::H(_) | CigarOp::P(_) => {}
+            }
+            if op.consumes_query() {
+                qpos += len;
+            }
+        }
+        (!entries.is_empty()).then_some(ActiveRead { ref_id, entries })
+    }
+
+    fn start(&self) -> u64 {
+        self.entries.front().map_or(u64::MAX, |(at, _)| *at)
+    }
+}
+
+/// Iterator over the pileup columns of a coordinate-sorted record stream
+///
+/// Fails with `NotCoordinateSorted` if a record sorts before the previous one.
+/// Iteration ends after the first error.
+pub struct Pileup<I> {
+    inner: I,
+    order: CoordinateOrder,
+    /// Next read to join the pileup
+    pending: Option<ActiveRead>,
+    active: Vec<ActiveRead>,
+    ref_id: usize,
+    pos: u64,
+    done: bool,
+}
+
+impl<I> Pileup<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    /// Columns of the records of `inner`, which must be coordinate-sorted
+    #[must_use]
+    pub fn new(inner: I) -> Self {
+        Pileup {
+            inner,
+            order: CoordinateOrder::new(),
+            pending: None,
+            active: Vec::new(),
+            ref_id: 0,
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Fill `pending` with the next pileup read, if there is one
+    fn pull(&mut self) -> Result<(), BamError> {
+        while self.pending.is_none() {
+            let Some(rec) = self.inner.next() else {
+                return Ok(());
+            };
+            let rec = rec?;
+            self.order.check(&rec)?;
+            self.pending = ActiveRead::from_record(&rec);
+        }
+        Ok(())
+    }
+
+    fn next_column(&mut self) -> Result<Option<PileupColumn>, BamError> {
+        loop {
+            self.pull()?;
+            if self.active.is_empty() {
+                let Some(next) = &self.pending else {
+                    return Ok(None);
+                };
+                (self.ref_id, self.pos) = (next.ref_id, next.start());
+            }
+            while let Some(next) = self.pending.take() {
+                if next.ref_id != self.ref_id || next.start() > self.pos {
+                    self.pending = Some(next);
+                    break;
+                }
+                self.active.push(next);
+                self.pull()?;
+            }
+
+            let pos = self.pos;
+            let entries = self
+                .active
+                .iter_mut()
+                .filter(|read| read.start() == pos)
+                .filter_map(|read| read.entries.pop_front().map(|(_, e)| e))
+                .collect::<Vec<PileupEntry>>();
+            self.active.retain(|read| !read.entries.is_empty());
+            self.pos += 1;
+            if !entries.is_empty() {
+                return Ok(Some(PileupColumn {
+                    ref_id: self.ref_id,
+                    pos: ZeroBased::new(pos),
+                    entries,
+                }));
+            }
+            // every active read is skipping (N) past this position
+            let pending_start = match &self.pending {
+                Some(next) if next.ref_id == self.ref_id => next.start(),
+                _ => u64::MAX,
+            };
+            if let Some(start) = self.active.iter().map(ActiveRead::start).min() {
+                self.pos = start.min(pending_start);
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Pileup<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    type Item = Result<PileupColumn, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.next_column().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::BamReader;
+
+    fn columns() -> Vec<PileupColumn> {
+        let reader = BamReader::from_path("../resources/test_data/consensus.bam").unwrap();
+        Pileup::new(reader)
+            .collect::<Result<Vec<PileupColumn>, BamError>>()
+            .unwrap()
+    }
+
+    fn bases(col: &PileupColumn) -> String {
+        col.entries
+            .iter()
+            .map(|e| match e.base {
+                PileupBase::Base(b) => char::from(b),
+                PileupBase::Deletion => '*',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_columns() {
+        let cols = columns();
+        let positions = cols.iter().map(|c| c.pos.get()).collect::<Vec<u64>>();
+        // the only read over 19 skips it (1M1N2M)
+        assert_eq!(
+            positions,
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 20, 21]
+        );
+        assert!(cols.iter().all(|c| c.ref_id == 0));
+        // the duplicate's T is not counted
+        assert_eq!(bases(&cols[2]), "GTTG");
+        assert_eq!(cols[1].entries[3].qual, Some(2));
+        assert_eq!(bases(&cols[8]), "AAAG");
+        assert_eq!(bases(&cols[9]), "***G");
+        assert_eq!(cols[9].entries[0].qual, None);
+        assert_eq!(cols[9].entries[3].mapq, 5);
+        assert_eq!(bases(&cols[11]), "TTTG");
+        assert_eq!(bases(&cols[12]), "AAA");
+        assert_eq!(bases(&cols[19]), "A");
+        assert_eq!(cols.iter().map(PileupColumn::depth).max(), Some(4));
+    }
+
+    #[test]
+    fn test_unsorted_input_fails() {
+        let mut recs = BamReader::from_path("../resources/test_data/consensus.bam")
+            .unwrap()
+            .collect::<Vec<_>>();
+        recs.swap(0, 6);
+        let results = Pileup::new(recs.into_iter()).collect::<Vec<_>>();
+        assert!(matches!(
+            results.last(),
+            Some(Err(BamError::NotCoordinateSorted { .. }))
+        ));
+    }
+}
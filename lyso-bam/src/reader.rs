@@ -1,19 +1,78 @@
+//! Streaming BAM reader
+
+use fxhash::{FxHashMap, FxHashSet};
 use nom::{Err::Incomplete, Needed};
-use std::io::{BufRead, Read};
+use std::cell::OnceCell;
+use std::fs::File;
+use std::io::{BufRead, Read, Seek};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::Arc;
 
+use crate::bgzf::{VirtualOffset, VirtualReader, VirtualSeek};
+use crate::lazy::LazyRecord;
+pub use crate::parser::RepairPolicy;
 use crate::*;
+use lyso_common::config::ReaderConfig;
+use lyso_common::detect::{find_mismatch, DetectError, Mismatch};
+use lyso_common::stream::RecordBytes;
+use lyso_common::validate::Validator;
+
+/// Consecutive `Needed::Unknown` requests tolerated before giving up
+const MAX_UNKNOWN_RETRIES: usize = 1024;
+/// Leading bytes checked for another format when the BAM magic is missing
+const SNIFF_BYTES: usize = 1024;
+
 /// Represents the state of the BAM Reader
-///
-/// Header => Next call to `read()` will parse BAM header
-/// Reference => Next call to `read()` will parse references
-/// Alignment => Next call to `read()` will parse an alignment record
-/// Complete => Reader has been exhausted. Subsequent calls will only produce Complete.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum BamReaderState {
+    /// Next call to `read()` will parse BAM header
     Header,
+    /// Next call to `read()` will parse references
     Reference,
+    /// Next call to `read()` will parse an alignment record
     Alignment,
+    /// Reader has been exhausted. Subsequent calls will only produce Complete.
     Complete,
+    /// The header or reference list could not be read. Subsequent calls return None.
+    Failed,
+}
+
+/// What `BamReader` does with a reference name that appears more than once
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DuplicateRefPolicy {
+    /// Stop with `BamError::DuplicateReference`
+    #[default]
+    Error,
+    /// Accept the file, listing the names in `BamReader::duplicate_references`
+    Warn,
+}
+
+/// Records reconciled by `BamReader::with_repair`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairStats {
+    /// Records whose l_seq was one more than the bases they store
+    pub l_seq_over: u64,
+    /// Records whose l_seq was one less than the bases they store
+    pub l_seq_under: u64,
+}
+
+impl RepairStats {
+    /// Records repaired
+    pub fn total(&self) -> u64 {
+        self.l_seq_over + self.l_seq_under
+    }
+}
+
+/// A record read ahead by `BamReader::peek_record`
+struct Peeked {
+    next: Option<Result<Record, BamError>>,
+    /// Length of its block, still at the start of the buffer; 0 when there is none
+    block_len: usize,
+    /// The reader state before it was read
+    state: BamReaderState,
 }
 
 /// A streaming BAM Reader
@@ -22,30 +81,94 @@ pub enum BamReaderState {
 /// Assumes input is uncompressed so must be coupled with a blocked gzip reader for compressed data.
 /// Can handle incomplete input for header and references, but must be able to read
 /// an entire alignment block into memory at once.
+///
+/// Alignment blocks are decoded in place from the input's own buffer, which for
+/// a BGZF reader is the decompressed block. Only a block running past the end of
+/// that buffer is copied, into a spill buffer, before it is decoded.
 pub struct BamReader<T>
 where
     T: BufRead,
 {
     inner: T,
+    /// Header bytes, and alignment blocks that straddled the end of `inner`'s buffer;
+    /// when empty, the current block is at the front of `inner.fill_buf()`
     buffer: Vec<u8>,
     offset: usize,
+    /// Decompressed bytes of the alignment blocks consumed so far
+    record_bytes: u64,
+    /// `inner` has returned EOF; some readers (e.g. BGZF) fail if read again
+    inner_eof: bool,
     state: BamReaderState,
-    pub header: Option<BamHeader>,
-    pub references: Vec<BamReference>,
+    header: Option<BamHeader>,
+    references: Vec<BamReference>,
+    duplicate_policy: DuplicateRefPolicy,
+    strict_read_names: bool,
+    strict_aux: bool,
+    lenient_ref_ids: bool,
+    /// Rules every full record is checked against, see `BamReader::validator`
+    validator: Option<Arc<Validator<Record>>>,
+    repair: Option<RepairPolicy>,
+    repairs: RepairStats,
+    duplicates: Vec<String>,
+    ref_lookup: OnceCell<FxHashMap<String, usize>>,
+    peeked: Option<Peeked>,
+    config: ReaderConfig,
 }
 
 impl<T> BamReader<T>
 where
     T: BufRead,
 {
+    /// A reader in the `Header` state, reading from `handle`
+    #[must_use]
     pub fn new(handle: T) -> Self {
+        Self::with_config(handle, ReaderConfig::bam())
+    }
+
+    /// A reader in the `Header` state, buffering as set by `config`
+    ///
+    /// `read_chunk_size` is what is read each time a parser needs more of the header
+    /// or references but cannot say how much; known lengths are read exactly.
+    /// An alignment block over `max_record_size` fails before it is buffered.
+    /// Blocks are dropped as they are consumed, so `compact_threshold` is not used.
+    ///
+    /// ```
+    /// use bgzip::read::BGZFReader;
+    /// use lyso_bam::{reader::BamReader, BamError};
+    /// use lyso_common::config::ReaderConfig;
+    ///
+    /// let f = std::fs::File::open("../resources/test_data/stats.bam").unwrap();
+    /// let config = ReaderConfig {
+    ///     max_record_size: 32,
+    ///     ..ReaderConfig::bam()
+    /// };
+    /// let mut reader = BamReader::with_config(BGZFReader::new(f).unwrap(), config);
+    /// let err = reader.next().unwrap().unwrap_err();
+    /// assert!(matches!(err, BamError::RecordTooLarge { limit: 32 }));
+    /// assert!(reader.next().is_none());
+    /// ```
+    #[must_use]
+    pub fn with_config(handle: T, config: ReaderConfig) -> Self {
         BamReader {
             inner: handle,
-            buffer: Vec::with_capacity(MAX_BLOCK_SIZE),
+            buffer: Vec::with_capacity(config.initial_capacity),
             offset: 0,
+            record_bytes: 0,
+            inner_eof: false,
             state: BamReaderState::Header,
             header: None,
             references: Vec::with_capacity(1),
+            duplicate_policy: DuplicateRefPolicy::default(),
+            strict_read_names: false,
+            strict_aux: false,
+            lenient_ref_ids: false,
+            validator: None,
+            repair: None,
+            repairs: RepairStats::default(),
+            duplicates: Vec::new(),
+            ref_lookup: OnceCell::new(),
+            peeked: None,
+            config,
         }
     }
 
@@ -53,106 +176,829 @@ where
         &self.buffer[self.offset..]
     }
 
-    fn read_header(&mut self) -> BamReaderState {
-        self.read_to_buffer(8).unwrap();
+    /// The state as of the last record returned; a pending peek does not change it
+    ///
+    /// ```
+    /// use lyso_bam::reader::{BamReader, BamReaderState};
+    ///
+    /// let mut reader = BamReader::from_path("../resources/test_data/stats.bam").unwrap();
+    /// assert_eq!(reader.state(), BamReaderState::Header);
+    /// assert!(reader.peek_record().is_some());
+    /// assert_eq!(reader.state(), BamReaderState::Header);
+    /// while reader.next().is_some() {}
+    /// assert_eq!(reader.state(), BamReaderState::Complete);
+    /// ```
+    pub fn state(&self) -> BamReaderState {
+        self.peeked.as_ref().map_or(self.state, |p| p.state)
+    }
+
+    /// The header, None until the first record has been read or peeked
+    pub fn header(&self) -> Option<&BamHeader> {
+        self.header.as_ref()
+    }
+
+    /// The reference sequences listed after the header, in ref_id order
+    ///
+    /// Empty until the first record has been read or peeked.
+    pub fn references(&self) -> &[BamReference] {
+        &self.references
+    }
+
+    /// Decompressed bytes of the records read or skipped so far, header excluded
+    ///
+    /// Divided by the number of records, this is the average record size.
+    pub fn record_bytes(&self) -> u64 {
+        self.record_bytes
+    }
+
+    /// How to treat reference names that appear more than once (default: error)
+    #[must_use]
+    pub fn duplicate_refs(mut self, policy: DuplicateRefPolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    /// Fail records whose read name is not a valid SAM QNAME (default: off)
+    ///
+    /// QNAMEs are 1 to 254 printable characters other than '@'. Names are always
+    /// checked for a single, terminating NUL.
+    #[must_use]
+    pub fn strict_read_names(mut self, strict: bool) -> Self {
+        self.strict_read_names = strict;
+        self
+    }
+
+    /// Fail records holding an aux type the spec does not define (default: off)
+    ///
+    /// By default such values are kept as `BamAuxValue::Unknown`. Lazy records
+    /// report the error when their aux fields are accessed.
+    #[must_use]
+    pub fn strict_aux(mut self, strict: bool) -> Self {
+        self.strict_aux = strict;
+        self
+    }
+
+    /// Read records whose ref_id or next_ref_id names no reference as unplaced (default: off)
+    ///
+    /// Such records get RNAME or RNEXT '*' and no reference-dependent CIGAR fix.
+    /// By default they fail with `CorruptRecord`, and reading continues with the next.
+    /// Unaligned BAM, whose records all have ref_id -1, reads the same either way.
+    #[must_use]
+    pub fn lenient_ref_ids(mut self, lenient: bool) -> Self {
+        self.lenient_ref_ids = lenient;
+        self
+    }
+
+    /// Fail records that break an error-level rule of `validator` (default: none)
+    ///
+    /// A failing record comes back as `RuleViolation`, and reading continues with
+    /// the next. Lazy records are not checked, since rules need the whole record.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use lyso_bam::reader::BamReader;
+    /// use lyso_common::validate::{Severity, Validator, Violation};
+    ///
+    /// let validator = Validator::new().rule_fn("min_mapq", Severity::Error, |r: &lyso_bam::Record| {
+    ///     (r.mapq() < 10).then(|| Violation::new(format!("MAPQ {}", r.mapq())))
+    /// });
+    /// let mut reader = BamReader::from_path("../resources/test_data/stats.bam")
+    ///     .unwrap()
+    ///     .validator(Arc::new(validator));
+    /// let err = reader.next().unwrap().unwrap_err();
+    /// assert_eq!(err.to_string(), "record r1 fails min_mapq: MAPQ 0");
+    /// ```
+    #[must_use]
+    pub fn validator(mut self, validator: Arc<Validator<Record>>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Reconcile records whose l_seq is one off from the sequence they store (default: off)
+    ///
+    /// Such a record fails with `CorruptRecord` by default, since its QUAL and aux
+    /// fields cannot be found. With a policy, the block is rewritten before it is
+    /// decoded, and each repair is counted in `repairs`.
+    #[must_use]
+    pub fn with_repair(mut self, policy: RepairPolicy) -> Self {
+        self.repair = Some(policy);
+        self
+    }
+
+    /// Records repaired so far, see `with_repair`
+    pub fn repairs(&self) -> &RepairStats {
+        &self.repairs
+    }
+
+    /// Reference names seen more than once, when accepted by `DuplicateRefPolicy::Warn`
+    pub fn duplicate_references(&self) -> &[String] {
+        &self.duplicates
+    }
+
+    /// Index and entry of the reference called `name`
+    ///
+    /// The lookup table is built on first use, after the references have been read.
+    /// With duplicated names, the first entry wins.
+    pub fn reference_by_name(&self, name: &str) -> Option<(usize, &BamReference)> {
+        let by_name = self.ref_lookup.get_or_init(|| {
+            let mut map = FxHashMap::default();
+            for (i, r) in self.references.iter().enumerate().rev() {
+                map.insert(r.name().to_string(), i);
+            }
+            map
+        });
+        by_name.get(name).map(|i| (*i, &self.references[*i]))
+    }
+
+    /// Read at least `amt` more bytes, failing if the input ends first
+    fn fill(&mut self, amt: usize, section: &'static str) -> Result<(), BamError> {
+        let amt = u64::try_from(amt)?;
+        if self.read_to_buffer(amt)? < amt {
+            return Err(BamError::TruncatedFile(section));
+        }
+        Ok(())
+    }
+
+    /// Read what a streaming parser asked for
+    ///
+    /// `Needed::Unknown` reads up to `read_chunk_size` bytes, failing once the
+    /// input ends or after `MAX_UNKNOWN_RETRIES` consecutive requests.
+    fn fill_needed(
+        &mut self,
+        needed: Needed,
+        retries: &mut usize,
+        section: &'static str,
+    ) -> Result<(), BamError> {
+        match needed {
+            Needed::Size(s) => self.fill(s.get(), section),
+            Needed::Unknown => {
+                *retries += 1;
+                if *retries > MAX_UNKNOWN_RETRIES {
+                    return Err(BamError::ParseError);
+                }
+                let chunk = u64::try_from(self.config.read_chunk_size.max(1))?;
+                if self.read_to_buffer(chunk)? == 0 {
+                    return Err(BamError::TruncatedFile(section));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Drop the parsed bytes, keeping any that were read ahead
+    fn consume(&mut self, amt: usize) {
+        self.buffer.drain(..amt);
+        self.offset = 0;
+    }
+
+    /// Drop an alignment block, from the spill buffer or from `inner`
+    fn consume_block(&mut self, len: usize) {
+        self.record_bytes += len as u64;
+        if self.buffer.is_empty() {
+            self.inner.consume(len);
+        } else {
+            self.consume(len);
+        }
+    }
+
+    /// Decode the current block of `len` bytes, found by `read_block`, where it lies
+    fn decode_block<R>(
+        &mut self,
+        len: usize,
+        decode: impl FnOnce(&[u8], &[BamReference]) -> R,
+    ) -> Result<R, BamError> {
+        let block = if self.buffer.is_empty() {
+            // still the buffer `read_block` found the block in, as nothing was consumed
+            self.inner.fill_buf()?
+        } else {
+            &self.buffer
+        };
+        Ok(decode(&block[..len], &self.references))
+    }
+
+    fn read_header(&mut self) -> Result<BamReaderState, BamError> {
+        match self.buffer_at_least(8)? {
+            0 => return Err(BamError::EmptyFile),
+            n if n < 8 => return Err(BamError::TruncatedFile("header")),
+            _ => {}
+        }
+        let mut retries = 0;
         while self.header.is_none() {
             match parser::read_header(self.get_slice()) {
-                Ok((_, res)) => {
+                Ok((i, res)) => {
+                    let used = self.buffer.len() - i.len();
                     self.header = Some(res);
+                    self.consume(used);
                 }
-                Err(Incomplete(Needed::Size(s))) => {
-                    self.read_to_buffer(u64::try_from(s.get()).unwrap())
-                        .unwrap();
+                Err(Incomplete(needed)) => self.fill_needed(needed, &mut retries, "header")?,
+                Err(_) if parser::bam_magic(self.get_slice()).is_err() => {
+                    self.buffer_at_least(SNIFF_BYTES)?;
+                    return Err(match find_mismatch(self.get_slice(), Format::Bam) {
+                        Some(Mismatch::Compressed(c)) => BamError::Compressed(c),
+                        Some(Mismatch::Format(f)) => BamError::WrongFormat(f),
+                        _ => BamError::MissingMagicString,
+                    });
                 }
-                Err(e) => panic!("Unable to parse BAM header: {e}"),
+                Err(_) => return Err(BamError::ParseError),
             }
         }
 
-        if self.header.as_ref().unwrap().n_ref > 0 {
+        if self.header.as_ref().is_some_and(|h| h.n_ref > 0) {
             self.state = BamReaderState::Reference;
         } else {
             self.state = BamReaderState::Alignment;
         }
-        self.buffer.clear();
-        self.state
+        Ok(self.state)
     }
 
-    fn read_references(&mut self) -> BamReaderState {
-        let n_ref = usize::try_from(self.header.as_ref().unwrap().n_ref).unwrap();
-        self.references = Vec::with_capacity(n_ref);
+    fn read_references(&mut self) -> Result<BamReaderState, BamError> {
+        let n_ref = self
+            .header
+            .as_ref()
+            .map_or(Ok(0), |h| usize::try_from(h.n_ref))?;
+        // n_ref is untrusted: let the vector grow as references actually arrive
+        self.references = Vec::with_capacity(n_ref.min(1024));
+        let mut seen = FxHashSet::default();
+        let mut retries = 0;
         while self.references.len() < n_ref {
             match parser::read_reference(self.get_slice()) {
                 Ok((i, bref)) => {
                     self.offset = self.buffer.len() - i.len();
+                    retries = 0;
+                    if !seen.insert(bref.name().to_string()) {
+                        match self.duplicate_policy {
+                            DuplicateRefPolicy::Error => {
+                                return Err(BamError::DuplicateReference(bref.name().to_string()))
+                            }
+                            DuplicateRefPolicy::Warn => {
+                                self.duplicates.push(bref.name().to_string())
+                            }
+                        }
+                    }
                     self.references.push(bref);
                 }
-                Err(Incomplete(Needed::Size(s))) => {
-                    self.read_to_buffer(u64::try_from(s.get()).unwrap())
-                        .unwrap();
+                Err(Incomplete(needed)) => {
+                    self.fill_needed(needed, &mut retries, "reference list")?
                 }
-                Err(e) => panic!("Malformed BAM reference: {e}"),
+                Err(_) => return Err(BamError::ParseError),
             }
         }
-        self.buffer.clear();
-        self.offset = 0;
+        self.consume(self.offset);
         self.state = BamReaderState::Alignment;
-        self.state
+        Ok(self.state)
     }
 
     fn read_to_buffer(&mut self, amt: u64) -> Result<u64, std::io::Error> {
-        std::io::copy(&mut self.inner.by_ref().take(amt), &mut self.buffer)
+        if self.inner_eof {
+            return Ok(0);
+        }
+        let n = std::io::copy(&mut self.inner.by_ref().take(amt), &mut self.buffer)?;
+        self.inner_eof = n < amt;
+        Ok(n)
+    }
+
+    /// Read until the buffer holds at least `len` bytes, returning how many it holds
+    fn buffer_at_least(&mut self, len: usize) -> Result<usize, std::io::Error> {
+        if let Some(missing) = len.checked_sub(self.buffer.len()) {
+            self.read_to_buffer(missing as u64)?;
+        }
+        Ok(self.buffer.len())
     }
 
-    /// Attempt to read a full alignment block into buffer.
+    /// Find the next full alignment block
     ///
-    /// Returns amount read if it is equal to the block_size field.
+    /// Returns the length of the block, block_size field included. When nothing
+    /// is buffered and `inner`'s buffer holds the whole block, it is left there;
+    /// otherwise it is read into buffer, which it starts. Bytes read ahead while
+    /// parsing the header may follow it.
     /// If there is no more input to be read from inner reader, returns Ok(0), signaling EOF.
-    fn read_block(&mut self) -> Result<u64, BamError> {
-        match self.read_to_buffer(4u64) {
-            Ok(4u64) => {}
+    fn read_block(&mut self) -> Result<usize, BamError> {
+        if self.buffer.is_empty() && !self.inner_eof {
+            let available = self.inner.fill_buf()?;
+            if available.is_empty() {
+                self.inner_eof = true;
+                return Ok(0);
+            }
+            if let Ok((_, bsize)) = parser::block_size(available) {
+                let len = usize::try_from(bsize)? + 4;
+                if len > self.config.max_record_size {
+                    return Err(BamError::RecordTooLarge {
+                        limit: self.config.max_record_size,
+                    });
+                }
+                if available.len() >= len {
+                    return Ok(len);
+                }
+            }
+            // the block straddles the end of inner's buffer: spill it
+        }
+        match self.buffer_at_least(4) {
             Ok(0) => return Ok(0),
-            Ok(_) => return Err(BamError::EofError),
+            Ok(n) if n < 4 => return Err(BamError::EofError),
+            Ok(_) => {}
             Err(e) => return Err(BamError::IoError(e)),
         }
         match parser::block_size(self.get_slice()) {
-            Ok((_, bsize)) => match self.read_to_buffer(u64::from(bsize)) {
-                Ok(v) if v == u64::from(bsize) => Ok(v),
-                Ok(_) => Err(BamError::EofError),
-                Err(e) => Err(BamError::IoError(e)),
-            },
+            Ok((_, bsize)) => {
+                let len = usize::try_from(bsize)? + 4;
+                if len > self.config.max_record_size {
+                    return Err(BamError::RecordTooLarge {
+                        limit: self.config.max_record_size,
+                    });
+                }
+                match self.buffer_at_least(len) {
+                    Ok(n) if n >= len => Ok(len),
+                    Ok(_) => Err(BamError::EofError),
+                    Err(e) => Err(BamError::IoError(e)),
+                }
+            }
             Err(_) => Err(BamError::ParseError),
         }
     }
 
-    fn read_record(&mut self) -> Option<Result<Record, BamError>> {
+    fn fail<R>(&mut self, e: BamError) -> Option<Result<R, BamError>> {
+        self.buffer.clear();
+        self.offset = 0;
+        self.state = BamReaderState::Failed;
+        Some(Err(e))
+    }
+
+    /// Read the next alignment block into buffer, first reading the header and
+    /// references if that has not happened yet
+    ///
+    /// Returns the block length, or None once the input is exhausted.
+    fn next_block(&mut self) -> Option<Result<usize, BamError>> {
         match self.state {
-            BamReaderState::Alignment => {
-                match self.read_block() {
-                    Ok(0) => {
-                        self.state = BamReaderState::Complete;
-                        return None;
-                    }
-                    Err(e) => return Some(Err(e)),
-                    _ => {}
+            BamReaderState::Alignment => match self.read_block() {
+                Ok(0) => {
+                    self.state = BamReaderState::Complete;
+                    None
                 }
-                match parser::read_alignment(self.get_slice(), &self.references) {
-                    Ok((_, aln)) => {
-                        self.buffer.clear();
-                        Some(Ok(aln))
-                    }
-                    Err(_) => Some(Err(BamError::ParseError)),
+                Ok(len) => Some(Ok(len)),
+                // the block is not read, so there is no record after it to resume from
+                Err(e @ BamError::RecordTooLarge { .. }) => self.fail(e),
+                Err(e) => {
+                    self.buffer.clear();
+                    Some(Err(e))
+                }
+            },
+            BamReaderState::Complete | BamReaderState::Failed => None,
+            BamReaderState::Header => match self.read_header() {
+                Ok(_) => self.next_block(),
+                Err(e) => self.fail(e),
+            },
+            BamReaderState::Reference => match self.read_references() {
+                Ok(_) => self.next_block(),
+                Err(e) => self.fail(e),
+            },
+        }
+    }
+
+    /// Apply the read name and reference checks the reader was built with
+    fn check_alignment<A: Alignment>(&self, aln: A) -> Result<A, BamError> {
+        if self.strict_read_names {
+            parser::check_qname(aln.read_name())?;
+        }
+        if !self.lenient_ref_ids {
+            for (field, id) in [("ref_id", aln.ref_id()), ("next_ref_id", aln.next_ref_id())] {
+                if id >= 0 && parser::reference_of(&self.references, id).is_none() {
+                    return Err(BamError::CorruptRecord {
+                        field,
+                        detail: match self.references.len() {
+                            1 => format!("{id}, but the header lists 1 reference"),
+                            n => format!("{id}, but the header lists {n} references"),
+                        },
+                    });
                 }
             }
-            BamReaderState::Complete => None,
-            BamReaderState::Header => {
-                self.read_header();
-                self.read_record()
+        }
+        Ok(aln)
+    }
+
+    /// Decode the current block of `len` bytes with `decode`, after checking its
+    /// l_seq against the sequence it stores and repairing it if the reader was
+    /// built to
+    fn decode_repaired<R>(
+        &mut self,
+        len: usize,
+        decode: impl FnOnce(&[u8], &[BamReference]) -> Result<R, BamError>,
+    ) -> Result<R, BamError> {
+        let policy = self.repair;
+        let (aln, mismatch) = self.decode_block(len, |block, refs| {
+            match (parser::l_seq_mismatch(block), policy) {
+                (None, _) => (decode(block, refs), None),
+                (Some((l_seq, stored)), None) => (
+                    Err(BamError::CorruptRecord {
+                        field: "l_seq",
+                        detail: format!(
+                            "{l_seq}, but the record stores {stored} bases; \
+                             a repair policy can reconcile them"
+                        ),
+                    }),
+                    None,
+                ),
+                (Some((l_seq, stored)), Some(policy)) => {
+                    let fixed = parser::repair_l_seq(block, stored, policy);
+                    (decode(&fixed, refs), Some(l_seq > stored))
+                }
             }
-            BamReaderState::Reference => {
-                self.read_references();
-                self.read_record()
+        })?;
+        match mismatch {
+            Some(true) if aln.is_ok() => self.repairs.l_seq_over += 1,
+            Some(false) if aln.is_ok() => self.repairs.l_seq_under += 1,
+            _ => {}
+        }
+        aln
+    }
+
+    /// Decode the current block of `len` bytes without consuming it
+    fn parse_block(&mut self, len: usize) -> Result<Record, BamError> {
+        let aln = self.decode_repaired(len, |block, refs| {
+            parser::read_alignment(block, refs).map(|(_, aln)| aln)
+        })?;
+        if self.strict_aux {
+            parser::check_aux_types(aln.aux.as_ref()).map_err(parser::corrupt)?;
+        }
+        let aln = self.check_alignment(aln)?;
+        match self.validator.as_ref().and_then(|v| v.first_error(&aln)) {
+            Some(v) => Err(BamError::RuleViolation(v)),
+            None => Ok(aln),
+        }
+    }
+
+    /// The length of the next block, which may have been buffered by a peek
+    fn take_block(&mut self) -> Option<Result<usize, BamError>> {
+        match self.peeked.take() {
+            None => self.next_block(),
+            Some(Peeked { next: None, .. }) => None,
+            Some(Peeked {
+                next: Some(Err(e)),
+                block_len: 0,
+                ..
+            }) => Some(Err(e)),
+            Some(Peeked { block_len, .. }) => Some(Ok(block_len)),
+        }
+    }
+
+    fn read_record(&mut self) -> Option<Result<Record, BamError>> {
+        if let Some(peeked) = self.peeked.take() {
+            self.consume_block(peeked.block_len);
+            return peeked.next;
+        }
+        let len = match self.next_block()? {
+            Ok(len) => len,
+            Err(e) => return Some(Err(e)),
+        };
+        let aln = self.parse_block(len);
+        self.consume_block(len);
+        Some(aln)
+    }
+
+    /// The result the next call to `next()` will return, without consuming it
+    ///
+    /// Errors are held like records, so peeking never loses one. The record's
+    /// block stays unconsumed, so `lazy_records` can still decode it lazily.
+    pub fn peek_record(&mut self) -> Option<&Result<Record, BamError>> {
+        if self.peeked.is_none() {
+            let state = self.state;
+            let (next, block_len) = match self.next_block() {
+                None => (None, 0),
+                Some(Err(e)) => (Some(Err(e)), 0),
+                Some(Ok(len)) => (Some(self.parse_block(len)), len),
+            };
+            self.peeked = Some(Peeked {
+                next,
+                block_len,
+                state,
+            });
+        }
+        self.peeked.as_ref().and_then(|p| p.next.as_ref())
+    }
+
+    /// Pass over the next `n` records by their block_size, without decoding them
+    ///
+    /// Corrupt records are skipped like any other, as long as their block_size
+    /// holds. Returns the number skipped, fewer than `n` at the end of the input,
+    /// or the first error reading a block.
+    pub fn skip_records(&mut self, n: usize) -> Result<usize, BamError> {
+        for skipped in 0..n {
+            match self.take_block() {
+                None => return Ok(skipped),
+                Some(Err(e)) => return Err(e),
+                Some(Ok(len)) => self.consume_block(len),
             }
         }
+        Ok(n)
+    }
+
+    fn read_lazy_record(&mut self) -> Option<Result<LazyRecord, BamError>> {
+        let len = match self.take_block()? {
+            Ok(len) => len,
+            Err(e) => return Some(Err(e)),
+        };
+        let aln = self
+            .decode_repaired(len, |block, refs| {
+                LazyRecord::from_block(block, refs).map(|(_, aln)| aln)
+            })
+            .and_then(|aln| self.check_alignment(aln.strict_aux(self.strict_aux)));
+        self.consume_block(len);
+        Some(aln)
+    }
+
+    /// Iterate over records that decode CIGAR, SEQ, QUAL and aux only when accessed
+    ///
+    /// Much faster than full records when only flags or positions are needed.
+    /// Can be mixed with `next()`; both continue from the same place in the input.
+    pub fn lazy_records(&mut self) -> LazyRecords<'_, T> {
+        LazyRecords { reader: self }
+    }
+
+    /// Visit the remaining records as `LazyRecord`s, without collecting them
+    ///
+    /// Each record is decoded only as far as `on_record` asks. Returning
+    /// `ControlFlow::Break` stops the scan after that record; reading continues
+    /// from the next one. Returns the number of records visited, or the first
+    /// error, after which reading also continues unless the input is truncated.
+    ///
+    /// ```
+    /// use std::ops::ControlFlow;
+    ///
+    /// use lyso_bam::reader::BamReader;
+    ///
+    /// let mut reader = BamReader::from_path("../resources/test_data/name_sorted.bam").unwrap();
+    /// let mut flags = Vec::new();
+    /// let visited = reader.scan_records(|rec| {
+    ///     flags.push(rec.flag());
+    ///     ControlFlow::Continue(())
+    /// });
+    /// assert_eq!(visited.unwrap(), 10);
+    /// assert_eq!(flags.len(), 10);
+    /// ```
+    pub fn scan_records(
+        &mut self,
+        mut on_record: impl FnMut(&LazyRecord) -> ControlFlow<()>,
+    ) -> Result<u64, BamError> {
+        let mut visited = 0;
+        while let Some(rec) = self.read_lazy_record() {
+            let rec = rec?;
+            visited += 1;
+            if on_record(&rec).is_break() {
+                break;
+            }
+        }
+        Ok(visited)
+    }
+}
+
+/// Iterator over `LazyRecord`s, see `BamReader::lazy_records`
+pub struct LazyRecords<'a, T>
+where
+    T: BufRead,
+{
+    reader: &'a mut BamReader<T>,
+}
+
+impl<T> Iterator for LazyRecords<'_, T>
+where
+    T: BufRead,
+{
+    type Item = Result<LazyRecord, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_lazy_record()
+    }
+}
+
+impl<T> BamReader<T>
+where
+    T: BufRead,
+{
+    /// Read the header of `handle`, leaving its references to be parsed on demand
+    ///
+    /// Nothing past the reference count is read, so callers that only need the
+    /// text or a few references can stop early. Duplicate reference names are
+    /// not checked until `ReferenceIter::into_reader`, which goes on to the
+    /// alignments.
+    pub fn header_only(mut handle: T) -> Result<(BamHeader, ReferenceIter<T>), BamError> {
+        let head = handle.fill_buf()?;
+        if head.len() >= BAM_MAGIC_STR.len() && !head.starts_with(&BAM_MAGIC_STR) {
+            return Err(match find_mismatch(head, Format::Bam) {
+                Some(Mismatch::Compressed(c)) => BamError::Compressed(c),
+                Some(Mismatch::Format(f)) => BamError::WrongFormat(f),
+                _ => BamError::MissingMagicString,
+            });
+        }
+        let mut magic = [0; 4];
+        read_exact(&mut handle, &mut magic, "header")?;
+        if magic != BAM_MAGIC_STR {
+            return Err(BamError::MissingMagicString);
+        }
+        let l_text = read_u32(&mut handle, "header")?;
+        // l_text is untrusted: read what is there rather than allocating it up front
+        let mut text = Vec::new();
+        if (&mut handle).take(l_text.into()).read_to_end(&mut text)? < l_text as usize {
+            return Err(BamError::TruncatedFile("header"));
+        }
+        let n_ref = read_u32(&mut handle, "header")?;
+        let header = BamHeader { text, n_ref };
+        let refs = ReferenceIter {
+            handle,
+            header: header.clone(),
+            remaining: n_ref,
+            read: Vec::new(),
+            buf: Vec::new(),
+        };
+        Ok((header, refs))
+    }
+}
+
+/// Fill `buf` from `handle`, naming `section` if the input ends first
+fn read_exact(
+    handle: &mut impl Read,
+    buf: &mut [u8],
+    section: &'static str,
+) -> Result<(), BamError> {
+    handle.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => BamError::TruncatedFile(section),
+        _ => e.into(),
+    })
+}
+
+fn read_u32(handle: &mut impl Read, section: &'static str) -> Result<u32, BamError> {
+    let mut bytes = [0; 4];
+    read_exact(handle, &mut bytes, section)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// The references after a BAM header, parsed one at a time
+///
+/// Returned by `BamReader::header_only`. Stops after the first error.
+pub struct ReferenceIter<T> {
+    handle: T,
+    header: BamHeader,
+    remaining: u32,
+    /// References returned so far, handed on by `into_reader`
+    read: Vec<BamReference>,
+    buf: Vec<u8>,
+}
+
+impl<T> ReferenceIter<T>
+where
+    T: BufRead,
+{
+    /// References not yet read or skipped
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+
+    /// Check the references not yet read are all there, using only their length prefixes
+    ///
+    /// For callers done with the file once the header is read; the iterator is
+    /// consumed, as no reader can follow without the skipped references.
+    pub fn skip_remaining(mut self) -> Result<(), BamError> {
+        while self.remaining > 0 {
+            let skip = u64::from(read_u32(&mut self.handle, "reference list")?) + 4;
+            let skipped = std::io::copy(&mut (&mut self.handle).take(skip), &mut std::io::sink());
+            if skipped? < skip {
+                return Err(BamError::TruncatedFile("reference list"));
+            }
+            self.remaining -= 1;
+        }
+        Ok(())
+    }
+
+    /// A reader over the alignments, after parsing the references not yet read
+    ///
+    /// The reader holds the header and every reference, as if it had read them
+    /// itself, and fails on a duplicate reference name like `BamReader::new`.
+    /// After the iterator has returned an error, the reference list counts as
+    /// truncated.
+    pub fn into_reader(mut self) -> Result<BamReader<T>, BamError> {
+        for bref in self.by_ref() {
+            bref?;
+        }
+        if self.read.len() as u64 != u64::from(self.header.n_ref) {
+            return Err(BamError::TruncatedFile("reference list"));
+        }
+        let mut seen = FxHashSet::default();
+        if let Some(dup) = self.read.iter().find(|r| !seen.insert(r.name())) {
+            return Err(BamError::DuplicateReference(dup.name().to_string()));
+        }
+        let mut reader = BamReader::new(self.handle);
+        reader.header = Some(self.header);
+        reader.references = self.read;
+        reader.state = BamReaderState::Alignment;
+        Ok(reader)
+    }
+
+    fn read_reference(&mut self) -> Result<BamReference, BamError> {
+        let l_name = read_u32(&mut self.handle, "reference list")?;
+        self.buf.clear();
+        self.buf.extend(l_name.to_le_bytes());
+        let rest = u64::from(l_name) + 4;
+        if (&mut self.handle).take(rest).read_to_end(&mut self.buf)? < rest as usize {
+            return Err(BamError::TruncatedFile("reference list"));
+        }
+        match parser::read_reference(&self.buf) {
+            Ok((_, bref)) => Ok(bref),
+            Err(_) => Err(BamError::ParseError),
+        }
+    }
+}
+
+impl<T> Iterator for ReferenceIter<T>
+where
+    T: BufRead,
+{
+    type Item = Result<BamReference, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let bref = self.read_reference();
+        match &bref {
+            Ok(bref) => self.read.push(bref.clone()),
+            Err(_) => self.remaining = 0,
+        }
+        Some(bref)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, usize::try_from(self.remaining).ok())
+    }
+}
+
+impl<T> BamReader<T>
+where
+    T: BufRead + VirtualSeek,
+{
+    /// Virtual offset of the first byte not yet consumed
+    ///
+    /// Between records this is where the next one starts, including one held by
+    /// `peek_record`. Before the first record it is the header, unless peeked.
+    /// None if the bytes buffered ahead began in an earlier BGZF block.
+    pub fn virtual_position(&self) -> Option<VirtualOffset> {
+        let buffered = u16::try_from(self.buffer.len()).ok()?;
+        self.inner
+            .virtual_position()
+            .checked_sub_within_block(buffered)
+    }
+
+    /// Continue reading records at `pos`, as returned by `virtual_position`
+    ///
+    /// The header and references are read first if that has not happened yet.
+    pub fn seek_virtual(&mut self, pos: VirtualOffset) -> Result<(), BamError> {
+        while matches!(
+            self.state,
+            BamReaderState::Header | BamReaderState::Reference
+        ) {
+            let read = match self.state {
+                BamReaderState::Header => self.read_header(),
+                _ => self.read_references(),
+            };
+            if let Err(e) = read {
+                self.buffer.clear();
+                self.state = BamReaderState::Failed;
+                return Err(e);
+            }
+        }
+        if self.state == BamReaderState::Failed {
+            return Err(BamError::ParseError);
+        }
+        self.inner.seek_virtual(pos)?;
+        self.buffer.clear();
+        self.offset = 0;
+        self.peeked = None;
+        self.inner_eof = false;
+        self.state = BamReaderState::Alignment;
+        Ok(())
+    }
+}
+
+impl BamReader<VirtualReader<File>> {
+    /// Open the BGZF-compressed BAM at `path`; errors name the file
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, LysoError> {
+        let path = path.as_ref();
+        let mut f = File::open(path).map_err(|e| LysoError::from_path(path, e))?;
+        // plain text is not BGZF, so name its format before decompression fails
+        let mut head = Vec::with_capacity(SNIFF_BYTES);
+        (&mut f)
+            .take(SNIFF_BYTES as u64)
+            .read_to_end(&mut head)
+            .and_then(|_| f.rewind())
+            .map_err(|e| LysoError::from_path(path, e))?;
+        if head.is_empty() {
+            return Err(LysoError::from_path(path, DetectError::Empty));
+        }
+        if let Some(Mismatch::Format(found)) = find_mismatch(&head, Format::Bam) {
+            return Err(LysoError::from_path(path, BamError::WrongFormat(found)));
+        }
+        let bgzf = VirtualReader::new(f)
+            .map_err(|e| LysoError::from_path(path, LysoError::format(Format::Bam, e)))?;
+        Ok(BamReader::new(bgzf))
     }
 }
 
@@ -166,3 +1012,947 @@ where
         self.read_record()
     }
 }
+
+impl<B> RecordBytes for BamReader<B>
+where
+    B: BufRead,
+{
+    fn record_bytes(&self) -> u64 {
+        self.record_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lyso_common::io::testing::OneByte;
+    use std::io::Cursor;
+
+    /// Uncompressed header claiming `n_ref` references followed by `refs`
+    fn header_bytes(n_ref: u32, refs: &[&str]) -> Vec<u8> {
+        let text = b"@HD\tVN:1.6\n";
+        let mut out = BAM_MAGIC_STR.to_vec();
+        out.extend((text.len() as u32).to_le_bytes());
+        out.extend(text);
+        out.extend(n_ref.to_le_bytes());
+        for name in refs {
+            out.extend((name.len() as u32 + 1).to_le_bytes());
+            out.extend(name.as_bytes());
+            out.push(0);
+            out.extend(100u32.to_le_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn test_fewer_references_than_claimed() {
+        let mut reader = BamReader::new(Cursor::new(header_bytes(5, &["chr1", "chr2", "chr3"])));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(BamError::TruncatedFile("reference list")))
+        ));
+        assert_eq!(reader.state(), BamReaderState::Failed);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let mut bytes = header_bytes(0, &[]);
+        bytes.truncate(10);
+        let mut reader = BamReader::new(Cursor::new(bytes));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(BamError::TruncatedFile("header")))
+        ));
+        let mut reader = BamReader::new(Cursor::new(b"BAI\x01\0\0\0\0\0\0\0\0".to_vec()));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(BamError::MissingMagicString))
+        ));
+    }
+
+    #[test]
+    fn test_wrong_format() {
+        use lyso_common::detect::Compression;
+
+        let first = |input: &[u8]| BamReader::new(Cursor::new(input.to_vec())).next();
+        for (input, format) in [
+            (&b"@r1\nACGTACGT\n+\nIIIIIIII\n"[..], Format::Fastq),
+            (b">chr1\nACGTACGT\n", Format::Fasta),
+            (b"@HD\tVN:1.6\tSO:unsorted\n", Format::Sam),
+        ] {
+            assert!(matches!(
+                first(input),
+                Some(Err(BamError::WrongFormat(f))) if f == format
+            ));
+        }
+        // raw BGZF given to a reader that expects it decompressed
+        let bgzf = std::fs::read("../resources/test_data/name_sorted.bam").unwrap();
+        assert!(matches!(
+            first(&bgzf),
+            Some(Err(BamError::Compressed(Compression::Bgzf)))
+        ));
+
+        let Err(err) = BamReader::from_path("../resources/test_data/test.fastq") else {
+            panic!("FASTQ opened as BAM");
+        };
+        assert_eq!(
+            err.to_string(),
+            "../resources/test_data/test.fastq: BAM: input looks like FASTQ, not BAM"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_reference() {
+        let bytes = header_bytes(3, &["chr1", "chr2", "chr1"]);
+        let mut reader = BamReader::new(Cursor::new(bytes.clone()));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(BamError::DuplicateReference(name))) if name == "chr1"
+        ));
+
+        let mut reader =
+            BamReader::new(Cursor::new(bytes)).duplicate_refs(DuplicateRefPolicy::Warn);
+        assert!(reader.next().is_none());
+        assert_eq!(reader.state(), BamReaderState::Complete);
+        assert_eq!(reader.duplicate_references(), ["chr1"]);
+        assert_eq!(reader.reference_by_name("chr1").map(|(i, _)| i), Some(0));
+        let (i, chr2) = reader.reference_by_name("chr2").unwrap();
+        assert_eq!((i, chr2.name()), (1, "chr2"));
+        assert!(reader.reference_by_name("chrM").is_none());
+    }
+
+    #[test]
+    fn test_from_path() {
+        let mut reader = BamReader::from_path("../resources/test_data/coverage.bam").unwrap();
+        assert!(reader.next().unwrap().is_ok());
+
+        // plain text is not BGZF
+        let Err(err) = BamReader::from_path("../resources/test_data/test.fa") else {
+            panic!("opened test.fa");
+        };
+        assert!(err
+            .to_string()
+            .starts_with("../resources/test_data/test.fa: BAM: "));
+        let Err(err) = BamReader::from_path("../resources/test_data/missing.bam") else {
+            panic!("opened missing.bam");
+        };
+        assert!(err.to_string().contains("missing.bam"));
+        let mut source: &dyn std::error::Error = &err;
+        while let Some(next) = source.source() {
+            source = next;
+        }
+        assert!(source.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    /// Names, SEQ and QUAL of each record, or the error's field
+    fn read_lseq(fixture: &str, policy: Option<RepairPolicy>) -> (Vec<String>, RepairStats) {
+        let path = format!("../resources/test_data/{fixture}");
+        let mut reader = BamReader::from_path(path).unwrap();
+        if let Some(policy) = policy {
+            reader = reader.with_repair(policy);
+        }
+        let rows = reader
+            .by_ref()
+            .map(|r| match r {
+                Ok(r) => format!(
+                    "{} {} {} {}",
+                    r.read_name_str_lossy(),
+                    r.l_seq(),
+                    r.seq_string(),
+                    r.qual_string()
+                ),
+                Err(BamError::CorruptRecord { field, .. }) => format!("error {field}"),
+                Err(e) => panic!("{e}"),
+            })
+            .collect();
+        (rows, reader.repairs().clone())
+    }
+
+    #[test]
+    fn test_l_seq_strict() {
+        // without a policy the three records with an off-by-one l_seq fail
+        for fixture in ["lseq_over.bam", "lseq_under.bam"] {
+            let (rows, repairs) = read_lseq(fixture, None);
+            assert_eq!(
+                rows,
+                [
+                    "good1 10 ACGTACGTAC 56789:;<=>",
+                    "error l_seq",
+                    "error l_seq",
+                    "error l_seq",
+                    "good2 10 CCCCGGGGAA 56789:;<=>",
+                ],
+                "{fixture}"
+            );
+            assert_eq!(repairs.total(), 0);
+        }
+    }
+
+    #[test]
+    fn test_l_seq_trust_sequence() {
+        // the stored bases win, whichever way l_seq was off
+        let expected = [
+            "good1 10 ACGTACGTAC 56789:;<=>",
+            "even 10 GGCCAATTGG 56789:;<=>",
+            "odd 11 ACGTTGCAACG 56789:;<=>?",
+            "noaux 10 TTTTACGTAA 56789:;<=>",
+            "good2 10 CCCCGGGGAA 56789:;<=>",
+        ];
+        let (rows, repairs) = read_lseq("lseq_over.bam", Some(RepairPolicy::TrustSequence));
+        assert_eq!(rows, expected);
+        assert_eq!((repairs.l_seq_over, repairs.l_seq_under), (3, 0));
+        let (rows, repairs) = read_lseq("lseq_under.bam", Some(RepairPolicy::TrustSequence));
+        assert_eq!(rows, expected);
+        assert_eq!((repairs.l_seq_over, repairs.l_seq_under), (0, 3));
+    }
+
+    #[test]
+    fn test_l_seq_trust_l_seq() {
+        // l_seq wins: a base and quality 0 are added, or the last ones dropped
+        let (rows, repairs) = read_lseq("lseq_over.bam", Some(RepairPolicy::TrustLseq));
+        assert_eq!(
+            &rows[1..4],
+            [
+                "even 11 GGCCAATTGGN 56789:;<=>!",
+                "odd 12 ACGTTGCAACGN 56789:;<=>?!",
+                "noaux 11 TTTTACGTAAN 56789:;<=>!",
+            ]
+        );
+        assert_eq!(repairs.total(), 3);
+        let (rows, repairs) = read_lseq("lseq_under.bam", Some(RepairPolicy::TrustLseq));
+        assert_eq!(
+            &rows[1..4],
+            [
+                "even 9 GGCCAATTG 56789:;<=",
+                "odd 10 ACGTTGCAAC 56789:;<=>",
+                "noaux 9 TTTTACGTA 56789:;<=",
+            ]
+        );
+        assert_eq!(repairs.l_seq_under, 3);
+
+        // aux fields survive the repair, and lazy records agree
+        let path = "../resources/test_data/lseq_under.bam";
+        let mut reader = BamReader::from_path(path)
+            .unwrap()
+            .with_repair(RepairPolicy::TrustLseq);
+        let lazy = reader
+            .lazy_records()
+            .map(|r| r.unwrap().into_record().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lazy[1].aux_str("RG"), Some("run1"));
+        assert_eq!(lazy[1].to_string().split('\t').nth(9), Some("GGCCAATTG"));
+        assert_eq!(reader.repairs().total(), 3);
+    }
+
+    #[test]
+    fn test_empty_and_header_only() {
+        let mut reader = BamReader::new(Cursor::new(Vec::new()));
+        assert!(matches!(reader.next(), Some(Err(BamError::EmptyFile))));
+        let Err(err) = BamReader::from_path("../resources/test_data/empty.bam") else {
+            panic!("opened empty.bam");
+        };
+        assert_eq!(
+            err.to_string(),
+            "../resources/test_data/empty.bam: empty file"
+        );
+
+        // a header and no alignments is a complete, valid file
+        let mut reader = BamReader::from_path("../resources/test_data/header_only.bam").unwrap();
+        assert!(reader.next().is_none());
+        assert_eq!(reader.state(), BamReaderState::Complete);
+        assert_eq!(reader.references().len(), 2);
+        let mut reader = BamReader::from_path("../resources/test_data/header_only.bam").unwrap();
+        assert!(reader.lazy_records().next().is_none());
+        assert!(reader.header().is_some());
+    }
+
+    #[test]
+    fn test_non_utf8_read_name() {
+        let mut reader = BamReader::from_path("../resources/test_data/latin1_name.bam").unwrap();
+        let rec = reader.next().unwrap().unwrap();
+        assert_eq!(rec.read_name(), b"r\xe9ad1");
+        assert!(rec.to_string().starts_with("r\u{FFFD}ad1"));
+        let rec = reader.next().unwrap().unwrap();
+        assert_eq!(rec.read_name(), b"read2");
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_read_names_match_samtools() {
+        // as printed by `samtools view bwa_h500.bam | head -3 | cut -f1`
+        let mut reader = BamReader::from_path("../resources/test_data/bwa_h500.bam").unwrap();
+        let names = reader
+            .by_ref()
+            .take(3)
+            .map(|r| r.unwrap().read_name().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [&b"8"[..], b"12", b"19"]);
+        let lazy = reader.lazy_records().next().unwrap().unwrap();
+        assert_eq!(lazy.read_name(), b"34");
+    }
+
+    #[test]
+    fn test_strict_read_names() {
+        let path = "../resources/test_data/latin1_name.bam";
+        let lax = BamReader::from_path(path).unwrap().collect::<Vec<_>>();
+        assert!(lax.iter().all(|r| r.is_ok()));
+        let strict = BamReader::from_path(path)
+            .unwrap()
+            .strict_read_names(true)
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            strict[0],
+            Err(BamError::CorruptRecord {
+                field: "read_name",
+                ..
+            })
+        ));
+        assert_eq!(strict[1].as_ref().unwrap().read_name(), b"read2");
+    }
+
+    #[test]
+    fn test_strict_aux() {
+        let mut input = header_bytes(0, &[]);
+        let var = b"r\0NMC\x01XX?\x07\x08";
+        input.extend((32 + var.len() as u32).to_le_bytes());
+        input.extend((-1i32).to_le_bytes());
+        input.extend((-1i32).to_le_bytes());
+        input.push(2);
+        input.push(0);
+        input.extend(4680u16.to_le_bytes());
+        input.extend(0u16.to_le_bytes());
+        input.extend(4u16.to_le_bytes());
+        input.extend(0u32.to_le_bytes());
+        input.extend((-1i32).to_le_bytes());
+        input.extend((-1i32).to_le_bytes());
+        input.extend(0i32.to_le_bytes());
+        input.extend(var);
+
+        let lenient = BamReader::new(Cursor::new(input.clone()))
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            lenient.aux().unwrap()["XX"].to_string(),
+            "XX:?:<2 undecoded bytes>"
+        );
+
+        let mut strict = BamReader::new(Cursor::new(input)).strict_aux(true);
+        let lazy = strict.lazy_records().next().unwrap().unwrap();
+        assert_eq!(lazy.read_name(), b"r");
+        for _ in 0..2 {
+            match lazy.aux() {
+                Err(BamError::CorruptRecord { field, detail }) => {
+                    assert_eq!(
+                        (field, detail.as_str()),
+                        ("aux type", "unexpected byte 0x3f")
+                    )
+                }
+                other => panic!("expected CorruptRecord, got {other:?}"),
+            }
+        }
+        assert!(strict.next().is_none());
+    }
+
+    /// A record block named `name` with no CIGAR, SEQ or aux fields
+    fn ref_block(name: &str, ref_id: i32, next_ref_id: i32) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend((33 + name.len() as u32).to_le_bytes());
+        block.extend(ref_id.to_le_bytes());
+        block.extend(9i32.to_le_bytes());
+        block.push(name.len() as u8 + 1);
+        block.push(60);
+        block.extend(4680u16.to_le_bytes());
+        block.extend(0u16.to_le_bytes());
+        block.extend(0u16.to_le_bytes());
+        block.extend(0u32.to_le_bytes());
+        block.extend(next_ref_id.to_le_bytes());
+        block.extend(19i32.to_le_bytes());
+        block.extend(0i32.to_le_bytes());
+        block.extend(name.as_bytes());
+        block.push(0);
+        block
+    }
+
+    #[test]
+    fn test_unaligned_bam() {
+        let path = "../resources/test_data/unaligned.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        assert!(reader.references().is_empty());
+        let records = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.len(), 4);
+        assert!(records.iter().all(|r| r.is_unmapped() && r.ref_id() == -1));
+        assert_eq!(
+            records[0].to_string(),
+            "read_1\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGTTAGC\tIIIIHHHGG###\tRG:Z:run1\tqs:i:31\tch:i:112"
+        );
+        assert_eq!(reader.state(), BamReaderState::Complete);
+
+        let mut reader = BamReader::from_path(path).unwrap();
+        assert_eq!(reader.lazy_records().filter(|r| r.is_ok()).count(), 4);
+    }
+
+    #[test]
+    fn test_unknown_ref_ids() {
+        let mut input = header_bytes(1, &["chr1"]);
+        input.extend(ref_block("a", 0, 0));
+        input.extend(ref_block("b", 1, -1));
+        input.extend(ref_block("c", 0, 5));
+        input.extend(ref_block("d", -1, -1));
+
+        let results = BamReader::new(Cursor::new(input.clone())).collect::<Vec<_>>();
+        assert_eq!(
+            render(results.into_iter()),
+            [
+                "a\t0\tchr1\t10\t60\t*\tchr1\t20\t0\t*\t*",
+                "Corrupt record: invalid ref_id (1, but the header lists 1 reference)",
+                "Corrupt record: invalid next_ref_id (5, but the header lists 1 reference)",
+                "d\t0\t*\t10\t60\t*\t*\t20\t0\t*\t*",
+            ]
+        );
+        let mut reader = BamReader::new(Cursor::new(input.clone()));
+        let lazy = reader.lazy_records().collect::<Vec<_>>();
+        assert_eq!(lazy.iter().filter(|r| r.is_err()).count(), 2);
+        assert_eq!(reader.state(), BamReaderState::Complete);
+
+        let lenient = BamReader::new(Cursor::new(input))
+            .lenient_ref_ids(true)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(lenient[1].to_string(), "b\t0\t*\t10\t60\t*\t*\t20\t0\t*\t*");
+        assert_eq!(lenient.len(), 4);
+        assert_eq!(lenient[2].next_ref_id, 5);
+
+        // without references, any placed record is unknown
+        let mut input = header_bytes(0, &[]);
+        input.extend(ref_block("a", 0, -1));
+        let mut reader = BamReader::new(Cursor::new(input.clone()));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(BamError::CorruptRecord {
+                field: "ref_id",
+                ..
+            }))
+        ));
+        assert!(reader.next().is_none());
+        let rec = BamReader::new(Cursor::new(input))
+            .lenient_ref_ids(true)
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(rec.ref_name, "*");
+    }
+
+    fn names(reader: &mut BamReader<impl BufRead>) -> Vec<String> {
+        reader
+            .map(|r| r.unwrap().read_name_str_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_peek_record() {
+        let path = "../resources/test_data/stats.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        assert_eq!(reader.state(), BamReaderState::Header);
+        let peeked = reader.peek_record().unwrap().as_ref().unwrap().to_string();
+        assert_eq!(reader.state(), BamReaderState::Header);
+        assert!(peeked.starts_with("r1\t"));
+        assert_eq!(reader.next().unwrap().unwrap().to_string(), peeked);
+
+        // a peeked block can still be read lazily
+        assert_eq!(
+            reader.peek_record().unwrap().as_ref().unwrap().read_name(),
+            b"r2"
+        );
+        let lazy = reader.lazy_records().next().unwrap().unwrap();
+        assert_eq!(lazy.read_name(), b"r2");
+        assert_eq!(reader.by_ref().take(10).count(), 10);
+
+        assert!(reader.peek_record().is_none());
+        assert_eq!(reader.state(), BamReaderState::Alignment);
+        assert!(reader.next().is_none());
+        assert_eq!(reader.state(), BamReaderState::Complete);
+    }
+
+    #[test]
+    fn test_peek_keeps_errors() {
+        let path = "../resources/test_data/corrupt_record.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        reader.skip_records(2).unwrap();
+        assert!(matches!(
+            reader.peek_record(),
+            Some(Err(BamError::CorruptRecord { .. }))
+        ));
+        assert!(reader.next().unwrap().is_err());
+        assert_eq!(names(&mut reader), ["read4", "read5"]);
+
+        let mut reader = BamReader::new(Cursor::new(header_bytes(5, &["chr1"])));
+        assert!(matches!(
+            reader.peek_record(),
+            Some(Err(BamError::TruncatedFile(_)))
+        ));
+        assert_eq!(reader.state(), BamReaderState::Header);
+        assert!(reader.next().unwrap().is_err());
+        assert_eq!(reader.state(), BamReaderState::Failed);
+    }
+
+    #[test]
+    fn test_skip_records() {
+        let path = "../resources/test_data/stats.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        assert_eq!(reader.skip_records(3).unwrap(), 3);
+        assert_eq!(reader.next().unwrap().unwrap().read_name(), b"r4");
+        reader.peek_record();
+        assert_eq!(reader.skip_records(1).unwrap(), 1);
+        assert_eq!(
+            reader.lazy_records().next().unwrap().unwrap().read_name(),
+            b"r6"
+        );
+        assert_eq!(reader.skip_records(100).unwrap(), 6);
+        assert!(reader.next().is_none());
+
+        // corrupt records are skipped without being decoded
+        let path = "../resources/test_data/corrupt_record.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        assert_eq!(reader.skip_records(3).unwrap(), 3);
+        assert_eq!(names(&mut reader), ["read4", "read5"]);
+    }
+
+    #[test]
+    fn test_scan_records() {
+        let path = "../resources/test_data/stats.bam";
+        let expected = BamReader::from_path(path)
+            .unwrap()
+            .map(|r| r.unwrap().read_name().to_vec())
+            .collect::<Vec<_>>();
+        let mut scanned = Vec::new();
+        let visited = BamReader::from_path(path).unwrap().scan_records(|rec| {
+            scanned.push(rec.read_name().to_vec());
+            ControlFlow::Continue(())
+        });
+        assert_eq!(visited.unwrap(), 12);
+        assert_eq!(scanned, expected);
+
+        // breaking leaves the reader at the next record, a peeked one included
+        let mut reader = BamReader::from_path(path).unwrap();
+        let mut seen = 0;
+        let visited = reader.scan_records(|_| {
+            seen += 1;
+            match seen {
+                10 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        assert_eq!(visited.unwrap(), 10);
+        reader.peek_record();
+        let mut first = None;
+        reader
+            .scan_records(|rec| {
+                first = Some(rec.read_name().to_vec());
+                ControlFlow::Break(())
+            })
+            .unwrap();
+        assert_eq!(first.as_ref(), Some(&expected[10]));
+        assert_eq!(reader.next().unwrap().unwrap().read_name(), expected[11]);
+
+        // fields are decoded only when asked for, so a corrupt CIGAR is visited
+        let path = "../resources/test_data/corrupt_record.bam";
+        let mut corrupt = Vec::new();
+        let visited = BamReader::from_path(path).unwrap().scan_records(|rec| {
+            corrupt.push(rec.try_cigar().is_err());
+            ControlFlow::Continue(())
+        });
+        assert_eq!(visited.unwrap(), 5);
+        assert_eq!(corrupt, [false, false, true, false, false]);
+    }
+
+    #[test]
+    fn test_record_bytes() {
+        let path = "../resources/test_data/stats.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        let first = reader.next().unwrap().unwrap();
+        assert_eq!(reader.record_bytes(), u64::from(first.block_size()) + 4);
+        // a peek is counted once its record is consumed
+        reader.peek_record();
+        assert_eq!(reader.record_bytes(), u64::from(first.block_size()) + 4);
+        let rest = reader.by_ref().map(|r| r.unwrap().block_size() as u64 + 4);
+        let total = u64::from(first.block_size()) + 4 + rest.sum::<u64>();
+        assert_eq!(reader.record_bytes(), total);
+    }
+
+    #[test]
+    fn test_corrupt_record_skipped() {
+        let path = "../resources/test_data/corrupt_record.bam";
+        let results = BamReader::from_path(path).unwrap().collect::<Vec<_>>();
+        assert_eq!(results.len(), 5);
+        let names = results
+            .iter()
+            .filter_map(|r| r.as_ref().ok())
+            .map(|r| r.read_name_str_lossy().into_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(names, ["read1", "read2", "read4", "read5"]);
+        let err = results[2].as_ref().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Corrupt record: invalid CIGAR op (unexpected byte 0x09)"
+        );
+
+        let mut reader = BamReader::from_path(path).unwrap();
+        let lazy = reader
+            .lazy_records()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(lazy[2].cigar().is_empty());
+        assert!(matches!(
+            lazy[2].try_cigar(),
+            Err(BamError::CorruptRecord {
+                field: "CIGAR op",
+                ..
+            })
+        ));
+        let records = lazy
+            .into_iter()
+            .map(LazyRecord::into_record)
+            .collect::<Vec<_>>();
+        assert_eq!(records.iter().filter(|r| r.is_err()).count(), 1);
+    }
+
+    fn render(results: impl Iterator<Item = Result<Record, BamError>>) -> Vec<String> {
+        results
+            .map(|r| r.map_or_else(|e| e.to_string(), |r| r.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_one_byte_reads() {
+        for fixture in [
+            "name_sorted.bam",
+            "coverage.bam",
+            "absent_seq.bam",
+            "corrupt_record.bam",
+            "latin1_name.bam",
+            "stats.bam",
+        ] {
+            let path = format!("../resources/test_data/{fixture}");
+            let expected = render(BamReader::from_path(&path).unwrap());
+            let bgzf = bgzip::read::BGZFReader::new(File::open(&path).unwrap()).unwrap();
+            let mut reader = BamReader::new(OneByte(bgzf));
+            assert_eq!(render(&mut reader), expected, "{fixture}");
+            assert_eq!(reader.state(), BamReaderState::Complete);
+        }
+
+        let mut bytes = header_bytes(3, &["chr1", "chr2", "chr3"]);
+        bytes.truncate(bytes.len() - 6);
+        let mut reader = BamReader::new(OneByte(Cursor::new(bytes)));
+        assert!(matches!(
+            reader.next(),
+            Some(Err(BamError::TruncatedFile("reference list")))
+        ));
+    }
+
+    /// BGZF of `raw` in blocks of `block` uncompressed bytes
+    fn small_blocks(raw: &[u8], block: usize) -> Vec<u8> {
+        use bgzip::deflate::Compress;
+        let mut out = Vec::new();
+        let mut compress = Compress::new(bgzip::Compression::fast());
+        for chunk in raw.chunks(block) {
+            bgzip::write::write_block(&mut out, chunk, &mut compress).unwrap();
+        }
+        out.extend(bgzip::EOF_MARKER);
+        out
+    }
+
+    #[test]
+    fn test_records_straddling_blocks() {
+        let raw = lyso_common::synth::generate_bam(40, 101, 3);
+        let expected = render(BamReader::new(Cursor::new(&raw)));
+        assert_eq!(expected.len(), 40);
+        // blocks smaller than a block_size field, than a record, and holding several
+        for block in [1, 3, 50, 231, 1000, 0xff00] {
+            let bgzf = small_blocks(&raw, block);
+            let reader = BamReader::new(VirtualReader::new(&bgzf[..]).unwrap());
+            assert_eq!(render(reader), expected, "{block}");
+            let reader = BamReader::new(bgzip::BGZFReader::new(&bgzf[..]).unwrap());
+            assert_eq!(render(reader), expected, "{block}");
+
+            // peeking, lazy records and skips over the same blocks
+            let mut reader = BamReader::new(VirtualReader::new(&bgzf[..]).unwrap());
+            let mut mixed = Vec::new();
+            while let Some(peeked) = reader.peek_record() {
+                let name = peeked.as_ref().unwrap().read_name().to_vec();
+                let lazy = reader.lazy_records().next().unwrap().unwrap();
+                assert_eq!(lazy.read_name(), name, "{block}");
+                if let Some(full) = reader.next() {
+                    mixed.push(full.unwrap().to_string());
+                }
+                reader.skip_records(1).unwrap();
+            }
+            let every_third = expected.iter().skip(1).step_by(3).cloned();
+            let every_third = every_third.collect::<Vec<_>>();
+            assert_eq!(mixed, every_third, "{block}");
+            assert_eq!(reader.state(), BamReaderState::Complete);
+        }
+    }
+
+    #[test]
+    fn test_spill_only_across_blocks() {
+        let raw = lyso_common::synth::generate_bam(30, 75, 5);
+        let mut reader = BamReader::new(Cursor::new(&raw));
+        while reader.next().is_some() {}
+        let header_len = raw.len() as u64 - reader.record_bytes();
+
+        for block in [97, 400, 0xff00] {
+            let bgzf = small_blocks(&raw, block);
+            let mut reader = BamReader::new(VirtualReader::new(&bgzf[..]).unwrap());
+            let mut spilled = 0;
+            while let Some(rec) = reader.peek_record() {
+                let len = u64::from(rec.as_ref().unwrap().block_size()) + 4;
+                let start = header_len + reader.record_bytes();
+                let block = block as u64;
+                let straddles = start / block != (start + len - 1) / block;
+                // a block is copied exactly when it runs into the next BGZF block
+                assert_eq!(!reader.buffer.is_empty(), straddles, "{start}");
+                spilled += usize::from(straddles);
+                reader.next();
+            }
+            assert!(reader.buffer.is_empty());
+            if block == 0xff00 {
+                assert_eq!(spilled, 0);
+            } else {
+                assert!(spilled > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_read_chunks() {
+        for fixture in [
+            "absent_seq.bam",
+            "bwa_h500.bam",
+            "check_warnings.bam",
+            "consensus.bam",
+            "corrupt_record.bam",
+            "coverage.bam",
+            "latin1_name.bam",
+            "name_sorted.bam",
+            "stats.bam",
+        ] {
+            let path = format!("../resources/test_data/{fixture}");
+            let expected = render(BamReader::from_path(&path).unwrap());
+            let config = ReaderConfig {
+                initial_capacity: 0,
+                read_chunk_size: 64,
+                ..ReaderConfig::bam()
+            };
+            let bgzf = bgzip::read::BGZFReader::new(File::open(&path).unwrap()).unwrap();
+            let reader = BamReader::with_config(OneByte(bgzf), config);
+            assert_eq!(render(reader), expected, "{fixture}");
+        }
+    }
+
+    #[test]
+    fn test_max_record_size() {
+        let path = "../resources/test_data/stats.bam";
+        let sizes = BamReader::from_path(path)
+            .unwrap()
+            .map(|r| r.unwrap().block_size() as usize + 4)
+            .collect::<Vec<_>>();
+        let largest = sizes
+            .iter()
+            .position(|s| s == sizes.iter().max().unwrap())
+            .unwrap();
+        let limit = sizes[largest] - 1;
+        let config = ReaderConfig {
+            max_record_size: limit,
+            ..ReaderConfig::bam()
+        };
+        let bgzf = bgzip::read::BGZFReader::new(File::open(path).unwrap()).unwrap();
+        let mut reader = BamReader::with_config(bgzf, config);
+        let results = reader.by_ref().collect::<Vec<_>>();
+        assert_eq!(results.len(), largest + 1);
+        assert!(results[..largest].iter().all(|r| r.is_ok()));
+        assert!(matches!(
+            results[largest],
+            Err(BamError::RecordTooLarge { limit: l }) if l == limit
+        ));
+        assert_eq!(reader.state(), BamReaderState::Failed);
+    }
+
+    #[test]
+    fn test_unknown_need() {
+        let mut reader = BamReader::new(Cursor::new(vec![0; 10_000]));
+        let mut retries = 0;
+        reader
+            .fill_needed(Needed::Unknown, &mut retries, "header")
+            .unwrap();
+        assert_eq!(reader.buffer.len(), ReaderConfig::bam().read_chunk_size);
+        reader
+            .fill_needed(Needed::Unknown, &mut retries, "header")
+            .unwrap();
+        assert_eq!(reader.buffer.len(), 10_000);
+        assert!(matches!(
+            reader.fill_needed(Needed::Unknown, &mut retries, "header"),
+            Err(BamError::TruncatedFile("header"))
+        ));
+
+        // endless input still gives up
+        let mut reader = BamReader::new(std::io::BufReader::new(std::io::repeat(0)));
+        let mut retries = 0;
+        let err = loop {
+            if let Err(e) = reader.fill_needed(Needed::Unknown, &mut retries, "header") {
+                break e;
+            }
+            reader.buffer.clear();
+        };
+        assert!(matches!(err, BamError::ParseError));
+        assert_eq!(retries, MAX_UNKNOWN_RETRIES + 1);
+    }
+
+    #[test]
+    fn test_read_ahead_is_kept() {
+        // as if an unknown need had pulled the whole file in with the header
+        let path = "../resources/test_data/name_sorted.bam";
+        let expected = render(BamReader::from_path(path).unwrap());
+        let mut reader = BamReader::from_path(path).unwrap();
+        reader.read_to_buffer(u64::MAX).unwrap();
+        assert_eq!(render(&mut reader), expected);
+        assert_eq!(reader.references().len(), 1);
+    }
+
+    #[test]
+    fn test_seek_virtual() {
+        let path = "../resources/test_data/bwa_h500.bam";
+        let mut reader = BamReader::from_path(path).unwrap();
+        assert_eq!(reader.virtual_position(), Some(VirtualOffset::default()));
+        let mut recorded = Vec::new();
+        while reader.peek_record().is_some() {
+            let pos = reader.virtual_position().unwrap();
+            recorded.push((pos, reader.next().unwrap().unwrap()));
+        }
+        assert!(recorded.windows(2).all(|w| w[0].0 < w[1].0));
+        // records span several BGZF blocks
+        let last = recorded.last().unwrap().0;
+        assert!(last.coffset() > recorded[0].0.coffset());
+        // offsets are true block starts; the third block starts at byte 16665
+        assert!(recorded.iter().any(|(p, _)| p.coffset() == 16665));
+
+        let mut reader = BamReader::from_path(path).unwrap();
+        for i in [recorded.len() - 1, 0, recorded.len() / 2, 1] {
+            let (pos, expected) = &recorded[i];
+            reader.seek_virtual(*pos).unwrap();
+            assert_eq!(reader.virtual_position(), Some(*pos));
+            assert_eq!(&reader.next().unwrap().unwrap(), expected);
+        }
+        // past the last record
+        reader.seek_virtual(last).unwrap();
+        reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+        reader.seek_virtual(recorded[2].0).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), recorded[2].1);
+    }
+
+    #[test]
+    fn test_header_only() {
+        for fixture in [
+            "bwa_h500.bam",
+            "coverage.bam",
+            "name_sorted.bam",
+            "stats.bam",
+        ] {
+            let path = format!("../resources/test_data/{fixture}");
+            let mut eager = BamReader::from_path(&path).unwrap();
+            eager.peek_record();
+            let mut all = Vec::new();
+            bgzip::read::BGZFReader::new(File::open(&path).unwrap())
+                .unwrap()
+                .read_to_end(&mut all)
+                .unwrap();
+
+            let (header, refs) = BamReader::header_only(&all[..]).unwrap();
+            assert_eq!(Some(&header), eager.header(), "{fixture}");
+            let refs = refs.collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(refs, eager.references(), "{fixture}");
+
+            BamReader::header_only(&all[..])
+                .unwrap()
+                .1
+                .skip_remaining()
+                .unwrap();
+
+            // the alignments follow, whether or not some references were read first
+            for taken in [0, 1] {
+                let (_, mut refs) = BamReader::header_only(&all[..]).unwrap();
+                refs.by_ref().take(taken).for_each(drop);
+                let mut reader = refs.into_reader().unwrap();
+                assert_eq!(reader.references(), eager.references(), "{fixture}");
+                let mut eager = BamReader::from_path(&path).unwrap();
+                loop {
+                    match (reader.next(), eager.next()) {
+                        (Some(a), Some(b)) => assert_eq!(a.unwrap(), b.unwrap(), "{fixture}"),
+                        (a, b) => {
+                            assert!(a.is_none() && b.is_none(), "{fixture}");
+                            break;
+                        }
+                    }
+                }
+                assert_eq!(reader.record_bytes(), eager.record_bytes(), "{fixture}");
+            }
+        }
+
+        // references past the one wanted are never read
+        let mut bytes = header_bytes(3, &["chr1", "chr2"]);
+        bytes.extend([0xff; 3]);
+        let (header, mut refs) = BamReader::header_only(Cursor::new(bytes)).unwrap();
+        assert_eq!(header.n_ref(), 3);
+        assert_eq!(
+            refs.next().unwrap().unwrap(),
+            BamReference::new("chr1", 100)
+        );
+        assert_eq!(refs.remaining(), 2);
+        assert_eq!(refs.next().unwrap().unwrap().name(), "chr2");
+        assert!(matches!(
+            refs.next(),
+            Some(Err(BamError::TruncatedFile("reference list")))
+        ));
+        assert!(refs.next().is_none());
+    }
+
+    #[test]
+    fn test_header_only_errors() {
+        let mut bytes = header_bytes(0, &[]);
+        bytes.truncate(10);
+        assert!(matches!(
+            BamReader::header_only(&bytes[..]),
+            Err(BamError::TruncatedFile("header"))
+        ));
+        assert!(matches!(
+            BamReader::header_only(&b"BAI\x01\0\0\0\0"[..]),
+            Err(BamError::MissingMagicString)
+        ));
+        assert!(matches!(
+            BamReader::header_only(&b">chr1\nACGT\n"[..]),
+            Err(BamError::WrongFormat(Format::Fasta))
+        ));
+
+        let mut bytes = header_bytes(3, &["chr1", "chr2", "chr3"]);
+        bytes.truncate(bytes.len() - 6);
+        let (_, refs) = BamReader::header_only(&bytes[..]).unwrap();
+        assert!(matches!(
+            refs.skip_remaining(),
+            Err(BamError::TruncatedFile("reference list"))
+        ));
+        let (_, refs) = BamReader::header_only(&bytes[..]).unwrap();
+        assert!(matches!(
+            refs.into_reader(),
+            Err(BamError::TruncatedFile("reference list"))
+        ));
+
+        let bytes = header_bytes(2, &["chr1", "chr1"]);
+        let (_, refs) = BamReader::header_only(&bytes[..]).unwrap();
+        assert!(matches!(
+            refs.into_reader(),
+            Err(BamError::DuplicateReference(name)) if name == "chr1"
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+//! Sort-order checks for BAM streams
+//!
+//! Coordinate order is ascending `(ref_id, pos)`, with unplaced records
+//! (ref_id -1) at the end, as samtools sort produces.
+
+use crate::{Alignment, BamError, Record};
+
+/// Tracks the last record seen and rejects one that goes backwards
+#[derive(Debug, Clone, Default)]
+pub struct CoordinateOrder {
+    last: Option<(i32, i32)>,
+}
+
+impl CoordinateOrder {
+    /// A tracker that has seen no records
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept `rec` if it does not sort before the previous record
+    pub fn check(&mut self, rec: &impl Alignment) -> Result<(), BamError> {
+        let key = (rec.ref_id(), rec.raw_pos());
+        if let Some(prev) = self.last {
+            if sort_key(key) < sort_key(prev) {
+                return Err(BamError::NotCoordinateSorted {
+                    name: String::from_utf8_lossy(rec.read_name()).into_owned(),
+                    ref_id: key.0,
+                    pos: key.1,
+                    prev_ref_id: prev.0,
+                    prev_pos: prev.1,
+                });
+            }
+        }
+        self.last = Some(key);
+        Ok(())
+    }
+}
+
+/// Unplaced (-1) references sort last
+fn sort_key((ref_id, pos): (i32, i32)) -> (u32, i32) {
+    (ref_id as u32, pos)
+}
+
+/// Passes records through, failing at the first one out of coordinate order
+///
+/// Iteration ends after the `NotCoordinateSorted` error.
+pub struct SortCheck<I> {
+    inner: I,
+    order: CoordinateOrder,
+    failed: bool,
+}
+
+impl<I> SortCheck<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    /// Check the order of the records of `inner`
+    #[must_use]
+    pub fn new(inner: I) -> Self {
+        SortCheck {
+            inner,
+            order: CoordinateOrder::new(),
+            failed: false,
+        }
+    }
+}
+
+impl<I> Iterator for SortCheck<I>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    type Item = Result<Record, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let rec = self.inner.next()?;
+        Some(rec.and_then(|r| match self.order.check(&r) {
+            Ok(()) => Ok(r),
+            Err(e) => {
+                self.failed = true;
+                Err(e)
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::BamReader;
+    use std::fs::File;
+
+    fn open(name: &str) -> BamReader<bgzip::read::BGZFReader<File>> {
+        let f = File::open(format!("../resources/test_data/{name}")).unwrap();
+        BamReader::new(bgzip::read::BGZFReader::new(f).unwrap())
+    }
+
+    #[test]
+    fn test_sorted_passes() {
+        let n = open("coverage.bam").count();
+        let checked = SortCheck::new(open("coverage.bam"))
+            .collect::<Result<Vec<Record>, BamError>>()
+            .unwrap();
+        assert_eq!(checked.len(), n);
+    }
+
+    #[test]
+    fn test_unsorted_fails() {
+        // pairs are adjacent, so mates at different positions go backwards
+        let results = SortCheck::new(open("name_sorted.bam")).collect::<Vec<_>>();
+        assert!(matches!(
+            results.last(),
+            Some(Err(BamError::NotCoordinateSorted { .. }))
+        ));
+    }
+}
@@ -0,0 +1,564 @@
+//! Records split into one BAM file per read group
+//!
+//! `RgSplitter` routes each record by its RG tag, with records lacking one going
+//! to the `UNASSIGNED` file, and gives the header for each output: the input's, with only
+//! the @RG line of that read group kept. `SplitWriters` writes the outputs into a
+//! directory, opening each file on its first record and keeping at most
+//! `max_open` open at once; the least recently used is closed when another is
+//! needed and reopened for appending when it gets more records. Outputs are
+//! written to temporary files beside them and renamed into place one by one in
+//! `SplitWriters::finish`, so an error or panic before then leaves the directory
+//! as it was.
+//!
+//! ```no_run
+//! use lyso_bam::reader::BamReader;
+//! use lyso_bam::split::{RgSplitter, SplitWriters};
+//!
+//! let mut reader = BamReader::from_path("aln.bam").unwrap();
+//! let first = reader.next();
+//! let splitter = RgSplitter::new(reader.header().unwrap());
+//! let mut writers = SplitWriters::new(splitter, reader.references().to_vec(), "by_rg", 64);
+//! for rec in first.into_iter().chain(reader) {
+//!     writers.write(&rec.unwrap()).unwrap();
+//! }
+//! for output in writers.finish().unwrap() {
+//!     println!("{}\t{}", output.path.display(), output.records);
+//! }
+//! ```
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use fxhash::{FxBuildHasher, FxHashSet};
+use indexmap::IndexMap;
+use lyso_common::error::LysoError;
+use lyso_common::io::atomic::{self, PendingFile};
+
+use crate::bgzf::BgzfWriter;
+use crate::writer::BamWriter;
+use crate::{read_group_id, BamHeader, BamReference, Record};
+
+/// File stem of the output for records without an RG tag
+///
+/// A read group of this name gets another file, so the two are kept apart.
+pub const UNASSIGNED: &str = "unassigned";
+
+/// Routes records to read groups and builds the header of each output
+#[derive(Debug, Clone)]
+pub struct RgSplitter {
+    header: BamHeader,
+}
+
+impl RgSplitter {
+    /// A splitter for records read with `header`
+    #[must_use]
+    pub fn new(header: &BamHeader) -> Self {
+        RgSplitter {
+            header: header.clone(),
+        }
+    }
+
+    /// The read group `rec` belongs to: its RG tag, or `None` without one
+    ///
+    /// An RG missing from the header still gets its own output.
+    pub fn route<'r>(&self, rec: &'r Record) -> Option<&'r str> {
+        rec.aux_str("RG")
+    }
+
+    /// The input header with only the @RG line of `rg` kept
+    ///
+    /// Other lines are unchanged; records without an RG (`None`) and read groups
+    /// missing from the header keep no @RG line.
+    pub fn header_for(&self, rg: Option<&str>) -> BamHeader {
+        let mut text = Vec::with_capacity(self.header.text().len());
+        for line in self.header.lines() {
+            if read_group_id(line).is_some_and(|id| Some(id) != rg.map(str::as_bytes)) {
+                continue;
+            }
+            text.extend(line);
+            text.push(b'\n');
+        }
+        BamHeader::new(text, self.header.n_ref())
+    }
+}
+
+/// `rg` made safe as a file name: characters other than letters, digits, '.', '-'
+/// and '_' become '_', and a name that would be hidden or empty gets a leading '_'
+pub fn sanitize(rg: &str) -> String {
+    let name = rg
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if name.is_empty() || name.starts_with('.') {
+        format!("_{name}")
+    } else {
+        name
+    }
+}
+
+/// One file written by `SplitWriters`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitOutput {
+    /// The read group, `None` for records without one
+    pub read_group: Option<String>,
+    /// Where its records were written
+    pub path: PathBuf,
+    /// Records written
+    pub records: u64,
+}
+
+type Writer = BamWriter<BgzfWriter<File>>;
+
+/// An output with its writer while open
+struct Output {
+    info: SplitOutput,
+    writer: Option<Writer>,
+    /// The temporary file, once created
+    pending: Option<PendingFile>,
+    /// Write number of the output's last write, for closing the least recently used
+    last_used: u64,
+}
+
+/// BGZF-compressed BAM outputs, one per read group, in a directory
+///
+/// Every @RG of the header has an output, in header order, followed by any other
+/// read groups as they are first seen. Files are named after the sanitized read
+/// group, with `_2`, `_3`, ... added when two sanitize to the same name, or to
+/// names differing only in case, which collide on case-insensitive filesystems.
+/// unassigned.bam (see `UNASSIGNED`) is kept for records without an RG. Files replace existing
+/// ones unless `overwrite(false)` is set. Read groups without records get a
+/// header-only file.
+pub struct SplitWriters {
+    splitter: RgSplitter,
+    references: Vec<BamReference>,
+    dir: PathBuf,
+    outputs: IndexMap<Option<String>, Output, FxBuildHasher>,
+    /// Lowercased names of the files so far
+    file_names: FxHashSet<String>,
+    writes: u64,
+    n_open: usize,
+    max_open: usize,
+    reopens: u64,
+    overwrite: bool,
+    /// Whether the files known before the first record were checked
+    checked: bool,
+}
+
+impl SplitWriters {
+    /// Outputs in `dir`, which must exist, with at most `max_open` (at least 1)
+    /// open at once
+    pub fn new(
+        splitter: RgSplitter,
+        references: Vec<BamReference>,
+        dir: impl Into<PathBuf>,
+        max_open: usize,
+    ) -> Self {
+        let mut writers = SplitWriters {
+            splitter,
+            references,
+            dir: dir.into(),
+            outputs: IndexMap::default(),
+            file_names: FxHashSet::from_iter([unassigned_file()]),
+            writes: 0,
+            n_open: 0,
+            max_open: max_open.max(1),
+            reopens: 0,
+            overwrite: true,
+            checked: false,
+        };
+        let ids = writers
+            .splitter
+            .header
+            .read_group_ids()
+            .map(|id| String::from_utf8_lossy(id).into_owned())
+            .collect::<Vec<_>>();
+        for id in ids {
+            writers.output_index(Some(&id));
+        }
+        writers
+    }
+
+    /// Whether to replace existing files; without it, an output whose file
+    /// exists is an `AlreadyExists` error
+    ///
+    /// The outputs of the header's read groups and of `UNASSIGNED` are checked
+    /// before anything is written, others when first seen.
+    #[must_use]
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Append `rec` to the output of its read group
+    pub fn write(&mut self, rec: &Record) -> Result<(), LysoError> {
+        self.check_existing()?;
+        let i = self.output_index(self.splitter.route(rec));
+        self.writes += 1;
+        self.outputs[i].last_used = self.writes;
+        if self.outputs[i].writer.is_none() {
+            self.open(i)?;
+        }
+        let output = &mut self.outputs[i];
+        let writer = output.writer.as_mut().expect("opened above");
+        writer
+            .write_record(rec)
+            .map_err(|e| LysoError::from_path(&output.info.path, e))?;
+        output.info.records += 1;
+        Ok(())
+    }
+
+    /// Times a closed output was reopened to append to it
+    pub fn reopens(&self) -> u64 {
+        self.reopens
+    }
+
+    /// Outputs currently open
+    pub fn open_files(&self) -> usize {
+        self.n_open
+    }
+
+    /// Create the outputs that were never written, close the rest, move them
+    /// all into place and return them
+    pub fn finish(mut self) -> Result<Vec<SplitOutput>, LysoError> {
+        self.check_existing()?;
+        for i in 0..self.outputs.len() {
+            if self.outputs[i].pending.is_none() {
+                self.open(i)?;
+            }
+            let output = &mut self.outputs[i];
+            if let Some(writer) = output.writer.take() {
+                self.n_open -= 1;
+                close(writer, &output.info.path)?;
+            }
+        }
+        self.outputs
+            .into_values()
+            .map(|o| {
+                let pending = o.pending.expect("created above");
+                pending
+                    .persist()
+                    .map_err(|e| LysoError::from_path(&o.info.path, e))?;
+                Ok(o.info)
+            })
+            .collect()
+    }
+
+    /// Fail, once, if overwriting is off and a file known so far exists
+    fn check_existing(&mut self) -> Result<(), LysoError> {
+        if self.checked || self.overwrite {
+            return Ok(());
+        }
+        self.checked = true;
+        let mut paths = self
+            .outputs
+            .values()
+            .map(|o| o.info.path.clone())
+            .collect::<Vec<_>>();
+        if !self.outputs.contains_key(&None) {
+            paths.push(self.dir.join(unassigned_file()));
+        }
+        match paths.into_iter().find(|p| p.exists()) {
+            Some(path) => Err(LysoError::from_path(path, atomic::exists_error())),
+            None => Ok(()),
+        }
+    }
+
+    /// The file name a new output for read group `rg` gets
+    fn file_name(&self, rg: &str) -> String {
+        let stem = sanitize(rg);
+        let mut name = format!("{stem}.bam");
+        let mut n = 1;
+        while self.file_names.contains(&name.to_lowercase()) {
+            n += 1;
+            name = format!("{stem}_{n}.bam");
+        }
+        name
+    }
+
+    /// Index of the output for `rg`, naming a new one if needed
+    fn output_index(&mut self, rg: Option<&str>) -> usize {
+        let key = rg.map(str::to_string);
+        if let Some(i) = self.outputs.get_index_of(&key) {
+            return i;
+        }
+        let name = match rg {
+            Some(rg) => self.file_name(rg),
+            None => unassigned_file(),
+        };
+        let output = Output {
+            info: SplitOutput {
+                read_group: key.clone(),
+                path: self.dir.join(&name),
+                records: 0,
+            },
+            writer: None,
+            pending: None,
+            last_used: 0,
+        };
+        self.file_names.insert(name.to_lowercase());
+        self.outputs.insert_full(key, output).0
+    }
+
+    /// Create output `i` with its header, or reopen it for appending, first
+    /// closing the least recently used output when `max_open` are open
+    fn open(&mut self, i: usize) -> Result<(), LysoError> {
+        if self.n_open == self.max_open {
+            self.close_least_recent()?;
+        }
+        let (rg, output) = self.outputs.get_index_mut(i).expect("valid output index");
+        let path = &output.info.path;
+        let created = output.pending.is_some();
+        if !created {
+            let pending = PendingFile::new(path, self.overwrite)
+                .map_err(|e| LysoError::from_path(path, e))?;
+            output.pending = Some(pending);
+        }
+        let file = output
+            .pending
+            .as_ref()
+            .expect("created above")
+            .open()
+            .map_err(|e| LysoError::from_path(path, e))?;
+        let mut writer = BamWriter::new(BgzfWriter::new(file));
+        if created {
+            self.reopens += 1;
+        } else {
+            writer
+                .write_header(&self.splitter.header_for(rg.as_deref()), &self.references)
+                .map_err(|e| LysoError::from_path(path, e))?;
+        }
+        output.writer = Some(writer);
+        self.n_open += 1;
+        debug_assert!(self.n_open <= self.max_open);
+        Ok(())
+    }
+
+    fn close_least_recent(&mut self) -> Result<(), LysoError> {
+        let lru = self
+            .outputs
+            .values_mut()
+            .filter(|o| o.writer.is_some())
+            .min_by_key(|o| o.last_used);
+        if let Some(output) = lru {
+            if let Some(writer) = output.writer.take() {
+                self.n_open -= 1;
+                close(writer, &output.info.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The file for records without an RG
+fn unassigned_file() -> String {
+    format!("{UNASSIGNED}.bam")
+}
+
+/// Flush `writer` and end its file with a BGZF EOF marker
+///
+/// A reopened file appends after the marker; an empty BGZF block is valid
+/// anywhere in the stream.
+fn close(writer: Writer, path: &Path) -> Result<(), LysoError> {
+    writer
+        .into_inner()
+        .finish()
+        .map(drop)
+        .map_err(|e| LysoError::from_path(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::RecordBuilder;
+    use crate::reader::BamReader;
+
+    const TEXT: &[u8] = b"@HD\tVN:1.6\tSO:unsorted\n@RG\tID:a\tSM:x\n@RG\tID:b\tSM:y\n\
+        @RG\tID:c/1\tSM:z\n@RG\tID:d\tSM:w\n@PG\tID:bwa\n";
+
+    fn rec(name: &str, rg: Option<&str>) -> Record {
+        let builder = RecordBuilder::new(name).seq(b"ACGT");
+        match rg {
+            Some(rg) => builder.aux("RG", rg.to_string()).build(),
+            None => builder.build(),
+        }
+    }
+
+    fn split(records: &[Record], max_open: usize) -> (Vec<SplitOutput>, u64, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let splitter = RgSplitter::new(&BamHeader::new(TEXT.to_vec(), 0));
+        let mut writers = SplitWriters::new(splitter, Vec::new(), dir.path(), max_open);
+        for r in records {
+            writers.write(r).unwrap();
+        }
+        let reopens = writers.reopens();
+        (writers.finish().unwrap(), reopens, dir)
+    }
+
+    fn read_back(path: &Path) -> (Vec<u8>, Vec<String>) {
+        let mut reader = BamReader::from_path(path).unwrap();
+        let names = reader
+            .by_ref()
+            .map(|r| r.unwrap().read_name_str_lossy().into_owned())
+            .collect();
+        (reader.header().unwrap().text().to_vec(), names)
+    }
+
+    #[test]
+    fn test_route_and_header() {
+        let splitter = RgSplitter::new(&BamHeader::new(TEXT.to_vec(), 0));
+        assert_eq!(splitter.route(&rec("r1", Some("b"))), Some("b"));
+        assert_eq!(splitter.route(&rec("r2", None)), None);
+        assert_eq!(
+            splitter.header_for(Some("b")).text(),
+            b"@HD\tVN:1.6\tSO:unsorted\n@RG\tID:b\tSM:y\n@PG\tID:bwa\n"
+        );
+        assert_eq!(
+            splitter.header_for(None).text(),
+            b"@HD\tVN:1.6\tSO:unsorted\n@PG\tID:bwa\n"
+        );
+        assert_eq!(sanitize("c/1"), "c_1");
+        assert_eq!(sanitize("..x"), "_..x");
+        assert_eq!(sanitize(""), "_");
+    }
+
+    #[test]
+    fn test_split_with_reopens() {
+        let records = [
+            rec("r1", Some("a")),
+            rec("r2", Some("b")),
+            rec("r3", None),
+            rec("r4", Some("c/1")),
+            rec("r5", Some("a")),
+            rec("r6", Some("c/1")),
+            rec("r7", Some("b")),
+            rec("r8", Some("a")),
+            // sanitizes to the same name as c/1
+            rec("r9", Some("c_1")),
+        ];
+        // d has no records; a generous cap never reopens, a tiny one reopens; both give the same files
+        let (all_open, reopens, _dir) = split(&records, 100);
+        assert_eq!(reopens, 0);
+        let (tiny, reopens, _tiny_dir) = split(&records, 1);
+        assert_eq!(reopens, 4);
+
+        for outputs in [&all_open, &tiny] {
+            let summary = outputs
+                .iter()
+                .map(|o| {
+                    let file = o.path.file_name().unwrap().to_string_lossy().into_owned();
+                    (o.read_group.as_deref(), file, o.records)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                summary,
+                [
+                    (Some("a"), "a.bam".to_string(), 3),
+                    (Some("b"), "b.bam".to_string(), 2),
+                    (Some("c/1"), "c_1.bam".to_string(), 2),
+                    (Some("d"), "d.bam".to_string(), 0),
+                    (None, "unassigned.bam".to_string(), 1),
+                    (Some("c_1"), "c_1_2.bam".to_string(), 1),
+                ]
+            );
+            let expected: [(&[u8], &[&str]); 6] = [
+                (b"@RG\tID:a\tSM:x\n", &["r1", "r5", "r8"]),
+                (b"@RG\tID:b\tSM:y\n", &["r2", "r7"]),
+                (b"@RG\tID:c/1\tSM:z\n", &["r4", "r6"]),
+                (b"@RG\tID:d\tSM:w\n", &[]),
+                (b"", &["r3"]),
+                (b"", &["r9"]),
+            ];
+            for (output, (rg_line, names)) in outputs.iter().zip(expected) {
+                let (text, read) = read_back(&output.path);
+                let header = [
+                    &b"@HD\tVN:1.6\tSO:unsorted\n"[..],
+                    rg_line,
+                    b"@PG\tID:bwa\n",
+                ]
+                .concat();
+                assert_eq!(text, header, "{:?}", output.read_group);
+                assert_eq!(read, names, "{:?}", output.read_group);
+            }
+        }
+    }
+
+    #[test]
+    fn test_name_clashes() {
+        // a read group called "unassigned", and two differing only in case
+        let text = b"@HD\tVN:1.6\n@RG\tID:unassigned\n@RG\tID:S1\n@RG\tID:s1\n";
+        let dir = tempfile::tempdir().unwrap();
+        let splitter = RgSplitter::new(&BamHeader::new(text.to_vec(), 0));
+        let mut writers = SplitWriters::new(splitter, Vec::new(), dir.path(), 1);
+        for r in [
+            rec("r1", Some("unassigned")),
+            rec("r2", None),
+            rec("r3", Some("s1")),
+            rec("r4", None),
+        ] {
+            writers.write(&r).unwrap();
+            assert_eq!(writers.open_files(), 1);
+        }
+        let outputs = writers.finish().unwrap();
+        let summary = outputs
+            .iter()
+            .map(|o| {
+                let (_, names) = read_back(&o.path);
+                let file = o.path.file_name().unwrap().to_string_lossy().into_owned();
+                (o.read_group.as_deref(), file, names)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (
+                    Some("unassigned"),
+                    "unassigned_2.bam".into(),
+                    vec!["r1".into()]
+                ),
+                (Some("S1"), "S1.bam".into(), vec![]),
+                (Some("s1"), "s1_2.bam".into(), vec!["r3".into()]),
+                (
+                    None,
+                    "unassigned.bam".into(),
+                    vec!["r2".into(), "r4".into()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("b.bam"), "old").unwrap();
+        let splitter = RgSplitter::new(&BamHeader::new(TEXT.to_vec(), 0));
+        let mut writers = SplitWriters::new(splitter, Vec::new(), dir.path(), 100).overwrite(false);
+        // refused before the first record of another read group is written
+        let err = writers.write(&rec("r1", Some("a"))).unwrap_err();
+        assert!(err.to_string().ends_with("b.bam: already exists"), "{err}");
+        // nothing of the failed run is left behind
+        drop(writers);
+        let names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["b.bam"]);
+        assert_eq!(std::fs::read(dir.path().join("b.bam")).unwrap(), b"old");
+
+        // records without an RG would go to an existing file too
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("unassigned.bam"), "old").unwrap();
+        let splitter = RgSplitter::new(&BamHeader::new(TEXT.to_vec(), 0));
+        let writers = SplitWriters::new(splitter, Vec::new(), dir.path(), 100).overwrite(false);
+        let err = writers.finish().unwrap_err();
+        assert!(
+            err.to_string().ends_with("unassigned.bam: already exists"),
+            "{err}"
+        );
+    }
+}
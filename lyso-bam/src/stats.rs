@@ -0,0 +1,291 @@
+//! Summary statistics over BAM records
+//!
+//! `BamStats` accumulates counts over primary alignments, in the spirit of the
+//! "SN" section of `samtools stats`. Secondary and supplementary records are
+//! counted but otherwise ignored, so each read contributes once.
+
+use lyso_common::CigarOp;
+
+use crate::Record;
+
+/// Lower bounds of the MAPQ bins: 0, 1-9, 10-29, 30-59 and 60 or more
+pub const MAPQ_BINS: [u8; 5] = [0, 1, 10, 30, 60];
+
+/// Bin index of a mapping quality
+fn mapq_bin(mapq: u8) -> usize {
+    MAPQ_BINS.iter().rposition(|lo| mapq >= *lo).unwrap_or(0)
+}
+
+/// Label of the `i`th bin of `MAPQ_BINS`: "0", "1-9", ..., "60+"
+pub fn mapq_bin_label(i: usize) -> String {
+    match MAPQ_BINS.get(i + 1) {
+        Some(hi) if hi - MAPQ_BINS[i] > 1 => format!("{}-{}", MAPQ_BINS[i], hi - 1),
+        Some(_) => MAPQ_BINS[i].to_string(),
+        None => format!("{}+", MAPQ_BINS[i]),
+    }
+}
+
+/// `mapq_hist` as an object keyed by bin label
+#[cfg(feature = "serde")]
+fn serialize_mapq_hist<S: serde::Serializer>(
+    hist: &[u64; MAPQ_BINS.len()],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(hist.iter().enumerate().map(|(i, n)| (mapq_bin_label(i), n)))
+}
+
+/// Accumulated statistics over a set of BAM records
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BamStats {
+    total: u64,
+    mapped: u64,
+    duplicates: u64,
+    secondary: u64,
+    supplementary: u64,
+    mapq: [u64; MAPQ_BINS.len()],
+    /// SEQ length of all primary reads
+    bases: u64,
+    /// SEQ length and soft-clipped bases of mapped primary reads
+    mapped_bases: u64,
+    soft_clipped: u64,
+    /// NM and M/I/=/X bases of mapped primary reads carrying NM
+    mismatches: u64,
+    nm_aligned: u64,
+}
+
+impl BamStats {
+    /// Statistics over no records
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add one record
+    pub fn update(&mut self, rec: &Record) {
+        if rec.is_secondary() {
+            self.secondary += 1;
+            return;
+        }
+        if rec.is_supplementary() {
+            self.supplementary += 1;
+            return;
+        }
+        self.total += 1;
+        self.bases += u64::from(rec.l_seq());
+        if rec.is_duplicate() {
+            self.duplicates += 1;
+        }
+        if rec.is_unmapped() {
+            return;
+        }
+        self.mapped += 1;
+        self.mapq[mapq_bin(rec.mapq())] += 1;
+        self.mapped_bases += u64::from(rec.l_seq());
+        self.soft_clipped += rec
+            .cigar()
+            .iter()
+            .filter_map(|op| match op {
+                CigarOp::S(n) => Some(u64::from(*n)),
+                _ => None,
+            })
+            .sum::<u64>();
+        // negative NM is corrupt; leave the record out of the rate
+        if let Some(nm) = rec.aux_int("NM").and_then(|nm| u64::try_from(nm).ok()) {
+            self.mismatches += nm;
+            self.nm_aligned += rec
+                .cigar()
+                .iter()
+                .filter(|op| {
+                    matches!(
+                        op,
+                        CigarOp::M(_) | CigarOp::I(_) | CigarOp::Eq(_) | CigarOp::X(_)
+                    )
+                })
+                .map(|op| u64::from(op.len()))
+                .sum::<u64>();
+        }
+    }
+
+    /// Fold another accumulator into this one, e.g. from a parallel worker
+    pub fn merge(&mut self, other: &BamStats) {
+        self.total += other.total;
+        self.mapped += other.mapped;
+        self.duplicates += other.duplicates;
+        self.secondary += other.secondary;
+        self.supplementary += other.supplementary;
+        self.mapq
+            .iter_mut()
+            .zip(&other.mapq)
+            .for_each(|(a, b)| *a += b);
+        self.bases += other.bases;
+        self.mapped_bases += other.mapped_bases;
+        self.soft_clipped += other.soft_clipped;
+        self.mismatches += other.mismatches;
+        self.nm_aligned += other.nm_aligned;
+    }
+
+    /// Compute the summary
+    pub fn finish(&self) -> BamSummary {
+        let ratio = |n: u64, d: u64| (d > 0).then(|| n as f64 / d as f64);
+        BamSummary {
+            total: self.total,
+            mapped: self.mapped,
+            unmapped: self.total - self.mapped,
+            secondary: self.secondary,
+            supplementary: self.supplementary,
+            duplicates: self.duplicates,
+            mapq_hist: self.mapq,
+            mapping_rate: ratio(self.mapped, self.total),
+            duplicate_rate: ratio(self.duplicates, self.total),
+            mean_length: ratio(self.bases, self.total),
+            soft_clipped_pct: ratio(self.soft_clipped * 100, self.mapped_bases),
+            mismatch_rate: ratio(self.mismatches, self.nm_aligned),
+        }
+    }
+}
+
+/// Final statistics computed by `BamStats::finish`
+///
+/// Counts other than `secondary` and `supplementary` are over primary records.
+/// Rates are None when their denominator is zero; `mismatch_rate` is also None
+/// when no mapped read carries an NM tag.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BamSummary {
+    /// Primary records
+    #[cfg_attr(feature = "serde", serde(rename = "reads"))]
+    pub total: u64,
+    /// Mapped primary records
+    pub mapped: u64,
+    /// Unmapped primary records
+    pub unmapped: u64,
+    /// Secondary alignments
+    pub secondary: u64,
+    /// Supplementary alignments
+    pub supplementary: u64,
+    /// Primary records flagged as duplicates
+    pub duplicates: u64,
+    /// Mapped reads per bin of `MAPQ_BINS`
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_mapq_hist"))]
+    pub mapq_hist: [u64; MAPQ_BINS.len()],
+    /// Mapped share of primary records
+    pub mapping_rate: Option<f64>,
+    /// Duplicate share of primary records
+    pub duplicate_rate: Option<f64>,
+    /// Mean SEQ length of primary records
+    pub mean_length: Option<f64>,
+    /// Soft-clipped share of the bases of mapped reads, in percent
+    pub soft_clipped_pct: Option<f64>,
+    /// Sum of NM over aligned (M, I, = and X) bases
+    pub mismatch_rate: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reader::BamReader, BamError};
+    use std::collections::HashMap;
+    use std::fs::File;
+
+    fn records(path: &str) -> Vec<Record> {
+        let f = File::open(path).unwrap();
+        BamReader::new(bgzip::read::BGZFReader::new(f).unwrap())
+            .collect::<Result<Vec<Record>, BamError>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_mapq_bins() {
+        let bins = [0, 1, 9, 10, 29, 30, 59, 60, 255].map(mapq_bin);
+        assert_eq!(bins, [0, 1, 1, 2, 2, 3, 3, 4, 4]);
+        let labels = (0..MAPQ_BINS.len()).map(mapq_bin_label).collect::<Vec<_>>();
+        assert_eq!(labels, ["0", "1-9", "10-29", "30-59", "60+"]);
+    }
+
+    /// The `SN` fields and `MAPQ` counts of a `samtools stats` report
+    fn samtools_stats(path: &str) -> (HashMap<String, f64>, Vec<(u8, u64)>) {
+        let (mut sn, mut mapq) = (HashMap::new(), Vec::new());
+        for line in std::fs::read_to_string(path).unwrap().lines() {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            match fields[..] {
+                ["SN", key, value, ..] => {
+                    sn.insert(
+                        key.trim_end_matches(':').to_string(),
+                        value.parse().unwrap(),
+                    );
+                }
+                ["MAPQ", q, n, ..] => mapq.push((q.parse().unwrap(), n.parse().unwrap())),
+                _ => {}
+            }
+        }
+        (sn, mapq)
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut stats = BamStats::new();
+        records("../resources/test_data/stats.bam")
+            .iter()
+            .for_each(|r| stats.update(r));
+        let s = stats.finish();
+        assert_eq!((s.total, s.mapped, s.unmapped), (10, 8, 2));
+        assert_eq!((s.secondary, s.supplementary, s.duplicates), (1, 1, 2));
+        assert_eq!(s.mapq_hist, [1, 2, 2, 2, 1]);
+        assert_eq!(s.mapping_rate, Some(0.8));
+        assert_eq!(s.duplicate_rate, Some(0.2));
+        assert_eq!(s.mean_length, Some(18.4));
+        assert_eq!(s.soft_clipped_pct, Some(8.75));
+        assert_eq!(s.mismatch_rate, Some(9.0 / 126.0));
+
+        // the same counts as samtools
+        let (sn, mapq) = samtools_stats("../resources/test_data/stats.bam.sn");
+        let count = |key: &str| sn[key] as u64;
+        assert_eq!(s.total, count("raw total sequences"));
+        assert_eq!(s.mapped, count("reads mapped"));
+        assert_eq!(s.unmapped, count("reads unmapped"));
+        assert_eq!(s.duplicates, count("reads duplicated"));
+        assert_eq!(s.secondary, count("non-primary alignments"));
+        assert_eq!(s.supplementary, count("supplementary alignments"));
+        assert_eq!(
+            s.mean_length,
+            Some(sn["total length"] / sn["raw total sequences"])
+        );
+        let mut hist = [0; MAPQ_BINS.len()];
+        mapq.iter().for_each(|(q, n)| hist[mapq_bin(*q)] += n);
+        assert_eq!(s.mapq_hist, hist);
+        // samtools also counts the 20 aligned bases of the mapped read without NM
+        assert_eq!(count("mismatches"), 9);
+        assert_eq!(count("bases mapped (cigar)"), 126 + 20);
+    }
+
+    #[test]
+    fn test_merge_matches_single_pass() {
+        let recs = records("../resources/test_data/stats.bam");
+        let mut whole = BamStats::new();
+        recs.iter().for_each(|r| whole.update(r));
+        let (a, b) = recs.split_at(5);
+        let mut left = BamStats::new();
+        a.iter().for_each(|r| left.update(r));
+        let mut right = BamStats::new();
+        b.iter().for_each(|r| right.update(r));
+        left.merge(&right);
+        assert_eq!(left, whole);
+    }
+
+    #[test]
+    fn test_empty_and_unmapped() {
+        let s = BamStats::new().finish();
+        assert_eq!(s.total, 0);
+        assert_eq!(s.mean_length, None);
+
+        let mut stats = BamStats::new();
+        records("../resources/test_data/latin1_name.bam")
+            .iter()
+            .for_each(|r| stats.update(r));
+        let s = stats.finish();
+        assert_eq!((s.total, s.unmapped), (2, 2));
+        assert_eq!(s.mapping_rate, Some(0.0));
+        assert_eq!(s.soft_clipped_pct, None);
+        assert_eq!(s.mismatch_rate, None);
+    }
+}
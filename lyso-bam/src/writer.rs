@@ -0,0 +1,365 @@
+//! Uncompressed BAM output
+
+use std::io::Write;
+
+use crate::*;
+
+/// A BAM writer
+///
+/// Writes uncompressed BAM, so must be coupled with a BGZF writer to produce a `.bam`
+/// file.
+pub struct BamWriter<W>
+where
+    W: Write,
+{
+    inner: W,
+}
+
+impl<W> BamWriter<W>
+where
+    W: Write,
+{
+    /// A writer to `inner`; nothing is written until `write_header`
+    #[must_use]
+    pub fn new(inner: W) -> Self {
+        BamWriter { inner }
+    }
+
+    /// Write magic, header text and reference list
+    ///
+    /// The header text is written exactly as stored, so a header read by `BamReader`
+    /// round-trips byte for byte.
+    pub fn write_header(
+        &mut self,
+        header: &BamHeader,
+        references: &[BamReference],
+    ) -> Result<(), BamError> {
+        let n_ref = u32::try_from(references.len())?;
+        self.inner.write_all(&BAM_MAGIC_STR)?;
+        self.inner
+            .write_all(&u32::try_from(header.text().len())?.to_le_bytes())?;
+        self.inner.write_all(header.text())?;
+        self.inner.write_all(&n_ref.to_le_bytes())?;
+        for r in references {
+            // l_name counts the NUL terminator
+            let l_name = u32::try_from(r.name().len() + 1)?;
+            self.inner.write_all(&l_name.to_le_bytes())?;
+            self.inner.write_all(r.name().as_bytes())?;
+            self.inner.write_all(&[0])?;
+            self.inner.write_all(&r.l_ref().to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Write aux fields in their BAM encoding, in map order
+    ///
+    /// `BamAuxValue::Unknown` is written back exactly as read, so a record with
+    /// nonstandard types round-trips unchanged.
+    pub fn write_aux(&mut self, aux: &AuxMap) -> Result<(), BamError> {
+        let mut out = Vec::new();
+        for field in aux.values() {
+            out.extend(field.tag.map(|c| c as u8));
+            encode_aux_value(&field.value, &mut out)?;
+        }
+        self.inner.write_all(&out)?;
+        Ok(())
+    }
+
+    /// Write one alignment record
+    ///
+    /// Length fields and block_size are derived from the fields themselves, so a
+    /// record whose CIGAR was restored from its CG tag is written as decoded.
+    pub fn write_record(&mut self, rec: &Record) -> Result<(), BamError> {
+        let l_seq = rec.seq.len();
+        let mut var = Vec::with_capacity(rec.read_name.len() + 1 + 4 * rec.cigar.len() + l_seq * 2);
+        // Record::read_name leaves out the NUL terminator
+        var.extend(&rec.read_name);
+        var.push(0);
+        for op in &rec.cigar {
+            var.extend((op.len() << 4 | cigar_code(op)).to_le_bytes());
+        }
+        for pair in rec.seq.chunks(2) {
+            let hi = seq_code(pair[0]);
+            let lo = pair.get(1).map_or(0, |&b| seq_code(b));
+            var.push(hi << 4 | lo);
+        }
+        match &rec.qual {
+            Some(qual) => var.extend(qual),
+            // absent QUAL is stored as 0xff bytes
+            None => var.resize(var.len() + l_seq, 0xff),
+        }
+        for field in rec.aux.iter().flat_map(|a| a.values()) {
+            var.extend(field.tag.map(|c| c as u8));
+            encode_aux_value(&field.value, &mut var)?;
+        }
+
+        let block_size = u32::try_from(32 + var.len())?;
+        let l_read_name = u8::try_from(rec.read_name.len() + 1)?;
+        let n_cigar_op = u16::try_from(rec.cigar.len())?;
+        let mut fixed = Vec::with_capacity(36);
+        fixed.extend(block_size.to_le_bytes());
+        fixed.extend(rec.ref_id.to_le_bytes());
+        fixed.extend(rec.pos.to_le_bytes());
+        fixed.push(l_read_name);
+        fixed.push(rec.mapq);
+        fixed.extend(rec.bin.to_le_bytes());
+        fixed.extend(n_cigar_op.to_le_bytes());
+        fixed.extend(rec.flag.to_le_bytes());
+        fixed.extend(u32::try_from(l_seq)?.to_le_bytes());
+        fixed.extend(rec.next_ref_id.to_le_bytes());
+        fixed.extend(rec.next_pos.to_le_bytes());
+        fixed.extend(rec.tlen.to_le_bytes());
+        self.inner.write_all(&fixed)?;
+        self.inner.write_all(&var)?;
+        Ok(())
+    }
+
+    /// Flush the underlying writer
+    pub fn flush(&mut self) -> Result<(), BamError> {
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// The underlying writer
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// BAM operation code of a CIGAR operation, see SAMv1 4.2
+fn cigar_code(op: &CigarOp) -> u32 {
+    match op {
+        CigarOp::M(_) => 0,
+        CigarOp::I(_) => 1,
+        CigarOp::D(_) => 2,
+        CigarOp::N(_) => 3,
+        CigarOp::S(_) => 4,
+        CigarOp::H(_) => 5,
+        CigarOp::P(_) => 6,
+        CigarOp::Eq(_) => 7,
+        CigarOp::X(_) => 8,
+    }
+}
+
+/// 4-bit code of an IUPAC base, as in `=ACMGRSVTWYHKDBN`; anything else is N
+fn seq_code(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'=' => 0,
+        b'A' => 1,
+        b'C' => 2,
+        b'M' => 3,
+        b'G' => 4,
+        b'R' => 5,
+        b'S' => 6,
+        b'V' => 7,
+        b'T' => 8,
+        b'W' => 9,
+        b'Y' => 10,
+        b'H' => 11,
+        b'K' => 12,
+        b'D' => 13,
+        b'B' => 14,
+        _ => 15,
+    }
+}
+
+/// Append the type code and value of one aux field
+pub(crate) fn encode_aux_value(value: &BamAuxValue, out: &mut Vec<u8>) -> Result<(), BamError> {
+    fn array<T, const N: usize>(
+        out: &mut Vec<u8>,
+        sub: u8,
+        v: &[T],
+        bytes: impl Fn(&T) -> [u8; N],
+    ) -> Result<(), BamError> {
+        out.extend([b'B', sub]);
+        out.extend(u32::try_from(v.len())?.to_le_bytes());
+        out.extend(v.iter().flat_map(bytes));
+        Ok(())
+    }
+
+    match value {
+        BamAuxValue::A(v) => out.extend([b'A', *v as u8]),
+        BamAuxValue::c(v) => out.extend([b'c', *v as u8]),
+        BamAuxValue::C(v) => out.extend([b'C', *v]),
+        BamAuxValue::s(v) => {
+            out.push(b's');
+            out.extend(v.to_le_bytes());
+        }
+        BamAuxValue::S(v) => {
+            out.push(b'S');
+            out.extend(v.to_le_bytes());
+        }
+        BamAuxValue::i(v) => {
+            out.push(b'i');
+            out.extend(v.to_le_bytes());
+        }
+        BamAuxValue::I(v) => {
+            out.push(b'I');
+            out.extend(v.to_le_bytes());
+        }
+        BamAuxValue::f(v) => {
+            out.push(b'f');
+            out.extend(v.to_le_bytes());
+        }
+        BamAuxValue::Z(v) => {
+            out.push(b'Z');
+            out.extend(v.as_bytes());
+            out.push(0);
+        }
+        BamAuxValue::H(v) => {
+            out.push(b'H');
+            for x in v {
+                out.extend(format!("{x:02X}").as_bytes());
+            }
+            out.push(0);
+        }
+        BamAuxValue::Bc(v) => array(out, b'c', v, |x| x.to_le_bytes())?,
+        BamAuxValue::BC(v) => array(out, b'C', v, |x| x.to_le_bytes())?,
+        BamAuxValue::Bs(v) => array(out, b's', v, |x| x.to_le_bytes())?,
+        BamAuxValue::BS(v) => array(out, b'S', v, |x| x.to_le_bytes())?,
+        BamAuxValue::Bi(v) => array(out, b'i', v, |x| x.to_le_bytes())?,
+        BamAuxValue::BI(v) => array(out, b'I', v, |x| x.to_le_bytes())?,
+        BamAuxValue::Bf(v) => array(out, b'f', v, |x| x.to_le_bytes())?,
+        BamAuxValue::Unknown { dtype, raw } => {
+            out.push(*dtype);
+            out.extend(raw);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::BamReader;
+    use std::io::Cursor;
+
+    fn header_block(text: &[u8], refs: &[(&str, u32)]) -> Vec<u8> {
+        let mut out = BAM_MAGIC_STR.to_vec();
+        out.extend((text.len() as u32).to_le_bytes());
+        out.extend(text);
+        out.extend((refs.len() as u32).to_le_bytes());
+        for (name, len) in refs {
+            out.extend((name.len() as u32 + 1).to_le_bytes());
+            out.extend(name.as_bytes());
+            out.push(0);
+            out.extend(len.to_le_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        // latin-1 sample name and NUL padding, as some writers emit
+        let text = b"@HD\tVN:1.6\r\n@SQ\tSN:chr1\tLN:100\n@RG\tID:a\tSM:Jos\xe9\n\0\0";
+        let input = header_block(text, &[("chr1", 100)]);
+        let mut reader = BamReader::new(Cursor::new(input.clone()));
+        assert!(reader.next().is_none());
+        let header = reader.header().unwrap();
+        assert_eq!(header.text(), text);
+
+        let mut writer = BamWriter::new(Vec::new());
+        writer.write_header(header, reader.references()).unwrap();
+        assert_eq!(writer.into_inner(), input);
+    }
+
+    #[test]
+    fn test_header_only_round_trip() {
+        // an aligner run with no hits: a header, references and no records
+        let input = header_block(
+            b"@HD\tVN:1.6\tSO:coordinate\n",
+            &[("chr1", 100), ("chr2", 50)],
+        );
+        let mut reader = BamReader::new(Cursor::new(input.clone()));
+        assert!(reader.next().is_none());
+
+        let mut writer = BamWriter::new(Vec::new());
+        writer
+            .write_header(reader.header().unwrap(), reader.references())
+            .unwrap();
+        let output = writer.into_inner();
+        assert_eq!(output, input);
+        let mut reread = BamReader::new(Cursor::new(output));
+        assert!(reread.next().is_none());
+        assert_eq!(reread.references().len(), 2);
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        for fixture in [
+            "stats.bam",
+            "bwa_h500.bam",
+            "unaligned.bam",
+            "latin1_name.bam",
+        ] {
+            let path = format!("../resources/test_data/{fixture}");
+            let mut reader = BamReader::from_path(&path).unwrap();
+            let records = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+
+            let mut writer = BamWriter::new(Vec::new());
+            writer
+                .write_header(reader.header().unwrap(), reader.references())
+                .unwrap();
+            for rec in &records {
+                writer.write_record(rec).unwrap();
+            }
+            let reread = BamReader::new(Cursor::new(writer.into_inner()))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(reread, records, "{fixture}");
+            for (a, b) in reread.iter().zip(&records) {
+                assert_eq!(a.to_string(), b.to_string());
+                assert_eq!(a.bin(), b.bin());
+            }
+        }
+    }
+
+    #[test]
+    fn test_aux_round_trip() {
+        let aux = b"NMC\x01ASs\xfe\xffRGZa\0XBBS\x02\0\0\0\x01\0\x02\0XXQ\x05\x06YYi\0\0\0\0";
+        let input = unplaced_record(aux);
+        let (_, rec) = crate::parser::read_alignment(&input, &[]).unwrap();
+        let map = rec.aux().unwrap();
+        assert_eq!(map.len(), 5);
+
+        let mut writer = BamWriter::new(Vec::new());
+        writer.write_aux(map).unwrap();
+        assert_eq!(writer.into_inner(), aux);
+    }
+
+    /// An unplaced alignment block named "r" with the given aux bytes
+    fn unplaced_record(aux: &[u8]) -> Vec<u8> {
+        let var = [&b"r\0"[..], aux].concat();
+        let mut b = (32 + var.len() as u32).to_le_bytes().to_vec();
+        b.extend((-1i32).to_le_bytes());
+        b.extend((-1i32).to_le_bytes());
+        b.extend([2, 0]);
+        b.extend(4680u16.to_le_bytes());
+        b.extend(0u16.to_le_bytes());
+        b.extend(4u16.to_le_bytes());
+        b.extend(0u32.to_le_bytes());
+        b.extend((-1i32).to_le_bytes());
+        b.extend((-1i32).to_le_bytes());
+        b.extend(0i32.to_le_bytes());
+        b.extend(var);
+        b
+    }
+
+    #[test]
+    fn test_header_lines() {
+        let header = BamHeader::new(b"@HD\tVN:1.6\r\n@CO\tSM:Jos\xe9\n\0".to_vec(), 0);
+        let lines = header.lines().collect::<Vec<&[u8]>>();
+        assert_eq!(lines, [&b"@HD\tVN:1.6"[..], &b"@CO\tSM:Jos\xe9"[..]]);
+        assert_eq!(header.text_lossy(), "@HD\tVN:1.6\r\n@CO\tSM:Jos\u{fffd}\n");
+        assert_eq!(BamHeader::new(vec![0; 4], 0).lines().count(), 0);
+        assert_eq!(BamHeader::new(b"@CO\tx".to_vec(), 0).lines().count(), 1);
+    }
+
+    #[test]
+    fn test_read_group_ids() {
+        let text = b"@HD\tVN:1.6\n@RG\tID:a\tSM:x\n@RG\tSM:y\tID:b\r\n@RG\tSM:z\n@CO\tID:c\n";
+        let header = BamHeader::new(text.to_vec(), 0);
+        let ids = header.read_group_ids().collect::<Vec<_>>();
+        assert_eq!(ids, [&b"a"[..], b"b"]);
+    }
+}
@@ -0,0 +1,23 @@
+//! Runs the programs in examples/ against the test data and checks their exit codes
+
+#[path = "../../resources/run_example.rs"]
+mod run_example;
+
+use run_example::run;
+
+#[test]
+fn test_bam_flag_histogram() {
+    let out = run(
+        "bam_flag_histogram",
+        &["../resources/test_data/mismatches.bam"],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(out.stdout, b"0\t0x0\t3\n4\t0x4\t1\n16\t0x10\t1\n");
+
+    assert_eq!(run("bam_flag_histogram", &[]).status.code(), Some(2));
+    let out = run(
+        "bam_flag_histogram",
+        &["../resources/test_data/missing.bam"],
+    );
+    assert_eq!(out.status.code(), Some(1));
+}
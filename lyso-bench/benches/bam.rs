@@ -0,0 +1,140 @@
+use std::io::Write;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use lyso::bam::{self, flags};
+use lyso::common::codec::{phred_to_ascii, unpack_4bit};
+use lyso::common::synth::generate_bam;
+use lyso_bench::SEED;
+
+const N_RECORDS: usize = 200_000;
+const READ_LEN: usize = 150;
+
+fn bench_read(c: &mut Criterion) {
+    let data = generate_bam(N_RECORDS, READ_LEN, SEED);
+    let mut group = c.benchmark_group("bam_read");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("records", |b| {
+        b.iter(|| {
+            let dups = bam::Reader::new(&data[..])
+                .filter(|rec| rec.as_ref().unwrap().is_duplicate())
+                .count();
+            assert_eq!(dups, N_RECORDS / 10);
+        })
+    });
+    group.bench_function("lazy_records", |b| {
+        b.iter(|| {
+            let dups = bam::Reader::new(&data[..])
+                .lazy_records()
+                .filter(|rec| rec.as_ref().unwrap().flag() & flags::DUPLICATE != 0)
+                .count();
+            assert_eq!(dups, N_RECORDS / 10);
+        })
+    });
+    // decode and format every record as SAM text, as `lyso view` does
+    let mut out = Vec::new();
+    group.bench_function("view", |b| {
+        b.iter(|| {
+            out.clear();
+            for rec in bam::Reader::new(&data[..]) {
+                writeln!(out, "{}", rec.unwrap()).unwrap();
+            }
+            black_box(&out);
+        })
+    });
+    // the same through BGZF, as `lyso view` reads a file
+    let mut writer = bam::BgzfWriter::new(Vec::new());
+    writer.write_all(&data).unwrap();
+    let compressed = writer.finish().unwrap();
+    group.bench_function("view_bgzf", |b| {
+        b.iter(|| {
+            out.clear();
+            let bgzf = bam::VirtualReader::new(&compressed[..]).unwrap();
+            for rec in bam::Reader::new(bgzf) {
+                writeln!(out, "{}", rec.unwrap()).unwrap();
+            }
+            black_box(&out);
+        })
+    });
+    group.finish();
+}
+
+/// `n` uncompressed BAM headers, the i-th listing 100 + i % 100 references
+fn synthetic_headers(n: usize) -> Vec<Vec<u8>> {
+    (0..n)
+        .map(|i| {
+            let text = format!("@HD\tVN:1.6\tSO:coordinate\n@RG\tID:sample{i}\n");
+            let n_ref = 100 + i % 100;
+            let mut out = b"BAM\x01".to_vec();
+            out.extend((text.len() as u32).to_le_bytes());
+            out.extend(text.as_bytes());
+            out.extend((n_ref as u32).to_le_bytes());
+            for j in 0..n_ref {
+                let name = format!("chr{j}_scaffold\0");
+                out.extend((name.len() as u32).to_le_bytes());
+                out.extend(name.as_bytes());
+                out.extend((1_000_000 + j as u32).to_le_bytes());
+            }
+            out
+        })
+        .collect()
+}
+
+fn bench_header(c: &mut Criterion) {
+    let headers = synthetic_headers(1000);
+    let mut group = c.benchmark_group("bam_header");
+    group.throughput(Throughput::Elements(headers.len() as u64));
+    group.bench_function("eager", |b| {
+        b.iter(|| {
+            for h in &headers {
+                let mut reader = bam::Reader::new(&h[..]);
+                reader.peek_record();
+                black_box(reader.header().unwrap());
+            }
+        })
+    });
+    group.bench_function("header_only", |b| {
+        b.iter(|| {
+            for h in &headers {
+                let (header, refs) = bam::Reader::header_only(&h[..]).unwrap();
+                refs.skip_remaining().unwrap();
+                black_box(header);
+            }
+        })
+    });
+    group.bench_function("header_only_refs", |b| {
+        b.iter(|| {
+            for h in &headers {
+                let (_, refs) = bam::Reader::header_only(&h[..]).unwrap();
+                black_box(refs.map(Result::unwrap).count());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bam_codec");
+    let packed = (0..=255u8).cycle().take(1 << 16).collect::<Vec<u8>>();
+    let mut out = Vec::with_capacity(2 * packed.len());
+    group.throughput(Throughput::Bytes(packed.len() as u64));
+    group.bench_function("unpack_4bit", |b| {
+        b.iter(|| {
+            out.clear();
+            unpack_4bit(black_box(&packed), &mut out, 2 * packed.len());
+            black_box(&out);
+        })
+    });
+    let quals = (0..=93u8).cycle().take(1 << 16).collect::<Vec<u8>>();
+    group.throughput(Throughput::Bytes(quals.len() as u64));
+    group.bench_function("phred_to_ascii", |b| {
+        b.iter(|| {
+            out.clear();
+            phred_to_ascii(black_box(&quals), &mut out);
+            black_box(&out);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_read, bench_header, bench_codec);
+criterion_main!(benches);
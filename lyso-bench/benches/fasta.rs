@@ -0,0 +1,45 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use lyso::common::synth::generate_fasta;
+use lyso::fasta;
+use lyso_bench::SEED;
+
+/// Many small records, and one chromosome-sized record (~100MB)
+const INPUTS: [(&str, usize, usize); 2] = [("small", 100_000, 300), ("huge", 1, 100_000_000)];
+
+fn bench_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fasta_read");
+    group.sample_size(20);
+    for (name, n, len) in INPUTS {
+        let fa = generate_fasta(n, len, SEED);
+        group.throughput(Throughput::Bytes(fa.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let bases = fasta::Reader::new(&fa[..])
+                    .map(|r| black_box(r.unwrap()).seq().len())
+                    .sum::<usize>();
+                assert_eq!(bases, n * len);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// `fast_count` against counting parsed records
+fn bench_count(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fasta_count");
+    group.sample_size(20);
+    for (name, n, len) in INPUTS {
+        let fa = generate_fasta(n, len, SEED);
+        group.throughput(Throughput::Bytes(fa.len() as u64));
+        group.bench_function(format!("{name}/fast_count"), |b| {
+            b.iter(|| assert_eq!(fasta::count::fast_count(&fa[..]).unwrap(), n as u64))
+        });
+        group.bench_function(format!("{name}/parsed"), |b| {
+            b.iter(|| assert_eq!(fasta::Reader::new(&fa[..]).count(), n))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_read, bench_count);
+criterion_main!(benches);
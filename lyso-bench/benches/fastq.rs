@@ -0,0 +1,68 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use lyso::common::synth::generate_fastq;
+use lyso::fastq;
+use lyso_bench::SEED;
+
+/// Short-read and long-read inputs of roughly 30MB each
+const INPUTS: [(&str, usize, usize); 2] = [("short", 100_000, 150), ("long", 1_500, 10_000)];
+
+fn read_owned(fq: &[u8]) -> usize {
+    fastq::Reader::new(fq)
+        .map(|r| black_box(r.unwrap()).seq().len())
+        .sum()
+}
+
+fn read_reused(fq: &[u8]) -> usize {
+    let mut reader = fastq::Reader::new(fq);
+    let mut rec = fastq::Record::new();
+    let mut total = 0;
+    while let Some(res) = reader.read_record_into(&mut rec) {
+        res.unwrap();
+        total += black_box(&rec).seq().len();
+    }
+    total
+}
+
+fn read_without_quality(fq: &[u8]) -> usize {
+    fastq::Reader::new(fq)
+        .without_quality()
+        .map(|r| black_box(r.unwrap()).seq().len())
+        .sum()
+}
+
+fn bench_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fastq_read");
+    for (name, n, len) in INPUTS {
+        let fq = generate_fastq(n, len, SEED);
+        group.throughput(Throughput::Bytes(fq.len() as u64));
+        group.bench_function(format!("{name}/owned"), |b| {
+            b.iter(|| assert_eq!(read_owned(&fq), n * len))
+        });
+        group.bench_function(format!("{name}/reused"), |b| {
+            b.iter(|| assert_eq!(read_reused(&fq), n * len))
+        });
+        group.bench_function(format!("{name}/without_quality"), |b| {
+            b.iter(|| assert_eq!(read_without_quality(&fq), n * len))
+        });
+    }
+    group.finish();
+}
+
+/// `fast_count` against counting parsed records
+fn bench_count(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fastq_count");
+    for (name, n, len) in INPUTS {
+        let fq = generate_fastq(n, len, SEED);
+        group.throughput(Throughput::Bytes(fq.len() as u64));
+        group.bench_function(format!("{name}/fast_count"), |b| {
+            b.iter(|| assert_eq!(fastq::count::fast_count(&fq[..]).unwrap(), n as u64))
+        });
+        group.bench_function(format!("{name}/parsed"), |b| {
+            b.iter(|| assert_eq!(fastq::Reader::new(&fq[..]).count(), n))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_read, bench_count);
+criterion_main!(benches);
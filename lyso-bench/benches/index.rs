@@ -0,0 +1,123 @@
+use std::io::{Cursor, Write};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use lyso::common::io::mmap::{Backend, MappedFile};
+use lyso::common::synth::{generate_fasta, generate_fastq};
+use lyso::{fasta, fastq};
+use lyso_bench::SEED;
+
+const N_IDS: usize = 10_000;
+
+/// Entries of the index loaded by `bench_fastq_load`
+const N_LOAD: usize = 10_000_000;
+
+/// Every id in a scrambled order, so consecutive lookups land far apart
+fn scrambled_ids(prefix: &str) -> Vec<String> {
+    (0..N_IDS)
+        .map(|i| format!("{prefix}{}", (i * 7919) % N_IDS))
+        .collect()
+}
+
+fn bench_fasta(c: &mut Criterion) {
+    let fa = generate_fasta(N_IDS, 240, SEED);
+    let ids = scrambled_ids("seq");
+    let mut group = c.benchmark_group("faidx");
+    group.throughput(Throughput::Bytes(fa.len() as u64));
+    group.bench_function("build", |b| {
+        b.iter(|| {
+            let idx =
+                fasta::FastaIndex::from_fasta_file(&mut Cursor::new(&fa[..]), Default::default())
+                    .unwrap();
+            assert_eq!(idx.len(), N_IDS);
+        })
+    });
+
+    let idx =
+        fasta::FastaIndex::from_fasta_file(&mut Cursor::new(&fa[..]), Default::default()).unwrap();
+    let mut reader = fasta::IndexedFasta::new(Cursor::new(&fa[..]), &idx);
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            for id in ids.iter() {
+                black_box(reader.get(id).unwrap());
+            }
+        })
+    });
+    group.bench_function("get_many", |b| b.iter(|| black_box(reader.get_many(&ids))));
+
+    // prefetching only does anything for a mapped file
+    let path = std::env::temp_dir().join(format!("lyso-bench-{}.fa", std::process::id()));
+    std::fs::write(&path, &fa).unwrap();
+    let mapped = MappedFile::open(&path, Backend::Mmap).unwrap();
+    let mut reader = fasta::IndexedFasta::new(Cursor::new(&mapped), &idx);
+    group.bench_function("get_many_mapped", |b| {
+        b.iter(|| black_box(reader.get_many(&ids)))
+    });
+    group.bench_function("prefetch_get_many_mapped", |b| {
+        b.iter(|| {
+            reader.prefetch(&ids).unwrap();
+            black_box(reader.get_many(&ids))
+        })
+    });
+    group.finish();
+    drop(mapped);
+    std::fs::remove_file(path).unwrap();
+}
+
+fn bench_fastq(c: &mut Criterion) {
+    let fq = generate_fastq(N_IDS, 150, SEED);
+    let ids = scrambled_ids("read");
+    let mut group = c.benchmark_group("fqidx");
+    group.throughput(Throughput::Bytes(fq.len() as u64));
+    group.bench_function("build", |b| {
+        b.iter(|| {
+            let idx =
+                fastq::FastqIndex::from_fastq_file(&mut Cursor::new(&fq[..]), Default::default())
+                    .unwrap();
+            assert_eq!(idx.len(), N_IDS);
+        })
+    });
+
+    let idx =
+        fastq::FastqIndex::from_fastq_file(&mut Cursor::new(&fq[..]), Default::default()).unwrap();
+    let mut reader = fastq::IndexedFastq::new(Cursor::new(&fq[..]), &idx);
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            for id in ids.iter() {
+                black_box(reader.get(id).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Text `.fai` parsing against the binary format, for the same index
+fn bench_fastq_load(c: &mut Criterion) {
+    let mut text = Vec::new();
+    for i in 0..N_LOAD {
+        let offset = i * 320 + 11;
+        writeln!(text, "read{i}\t150\t{offset}\t150\t151\t{}", offset + 153).unwrap();
+    }
+    let mut binary = Vec::new();
+    {
+        let mut idx = fastq::FastqIndex::new();
+        idx.read_index(&mut &text[..]).unwrap();
+        idx.write_binary(&mut binary).unwrap();
+    }
+    let mut group = c.benchmark_group("fqidx_load");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(N_LOAD as u64));
+    for (name, input) in [("text", &text), ("binary", &binary)] {
+        group.bench_function(name, |b| {
+            b.iter_with_large_drop(|| {
+                let mut idx = fastq::FastqIndex::new();
+                idx.read_index(&mut &input[..]).unwrap();
+                assert_eq!(idx.len(), N_LOAD);
+                idx
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_fasta, bench_fastq, bench_fastq_load);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+//! Bytes per entry held while building FASTQ indexes, in each build mode
+//!
+//! Not a criterion bench: a counting allocator records the peak and the final
+//! heap size of each build, and the table is printed once.
+//! `LYSO_BENCH_READS` sets the number of reads, 1 million by default.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{sink, Cursor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lyso::common::synth::generate_fastq;
+use lyso::fastq::{self, DedupMode, DuplicatePolicy, IndexBuildOptions};
+use lyso_bench::SEED;
+
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(now, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Peak and retained heap growth of `build`, per read
+fn measure<T>(n: usize, build: impl FnOnce() -> T) -> (f64, f64) {
+    let base = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let built = build();
+    let retained = CURRENT.load(Ordering::Relaxed) - base;
+    let peak = PEAK.load(Ordering::Relaxed) - base;
+    drop(built);
+    (peak as f64 / n as f64, retained as f64 / n as f64)
+}
+
+fn main() {
+    let n = std::env::var("LYSO_BENCH_READS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1_000_000);
+    let fq = &generate_fastq(n, 150, SEED)[..];
+    let build = |options: IndexBuildOptions| {
+        move || {
+            fastq::FastqIndex::from_fastq_file_with(
+                &mut Cursor::new(fq),
+                DuplicatePolicy::Error,
+                options,
+            )
+            .unwrap()
+        }
+    };
+    let offsets_only = |dedup| IndexBuildOptions {
+        dedup,
+        name_map: false,
+    };
+
+    let rows = [
+        (
+            "name map (default)",
+            measure(n, build(IndexBuildOptions::default())),
+        ),
+        (
+            "offsets-only, Exact",
+            measure(n, build(offsets_only(DedupMode::Exact))),
+        ),
+        (
+            "offsets-only, Hashed",
+            measure(n, build(offsets_only(DedupMode::Hashed))),
+        ),
+        (
+            "streamed to a .fai",
+            measure(n, || {
+                fastq::FastqIndexer::new(&mut Cursor::new(fq))
+                    .write_index(&DuplicatePolicy::Error, sink())
+                    .unwrap()
+            }),
+        ),
+    ];
+    println!("bytes per entry, {n} reads");
+    println!("{:<24}{:>8}{:>8}", "mode", "peak", "built");
+    for (mode, (peak, built)) in rows {
+        println!("{mode:<24}{peak:>8.1}{built:>8.1}");
+    }
+}
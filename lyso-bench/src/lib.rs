@@ -0,0 +1,7 @@
+//! Benches for the lyso readers; see `benches/`
+//!
+//! Inputs come from `lyso::common::synth` and are generated when a bench
+//! starts, so runs on different machines measure the same bytes.
+
+/// Seed shared by every bench input
+pub const SEED: u64 = 2148;
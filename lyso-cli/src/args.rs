@@ -0,0 +1,230 @@
+//! Argument parsing helpers shared by the subcommands
+
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use clap::{Args, CommandFactory};
+use clap_complete::Shell;
+use lyso::bam::filter::is_valid_tag;
+use lyso::common::pool::WorkerPool;
+use lyso::common::seqedit::{Replacement, SeqEdits};
+
+use crate::Cli;
+
+/// Value parser for input files: fail at parse time if `s` does not exist
+pub fn existing_path(s: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+    if path.exists() {
+        Ok(path)
+    } else {
+        Err(format!("{s}: no such file or directory"))
+    }
+}
+
+/// Value parser for sizes such as `512`, `64K` or `10M`, in bytes
+pub fn byte_size(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| {
+            format!("invalid size '{s}', expected a number of bytes such as 512, 64K or 10M")
+        })
+}
+
+/// Value parser for two character SAM aux tags such as `NM`
+pub fn sam_tag(s: &str) -> Result<String, String> {
+    if is_valid_tag(s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid tag '{s}', expected a letter then a letter or digit"
+        ))
+    }
+}
+
+/// Value parser for fractions from 0.0 to 1.0
+pub fn fraction(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|f| (0.0..=1.0).contains(f))
+        .ok_or_else(|| format!("invalid fraction '{s}', expected a number from 0.0 to 1.0"))
+}
+
+/// Sequence edits shared by the commands that print FASTA and FASTQ records
+#[derive(Args, Debug)]
+pub struct SeqEditArgs {
+    /// Uppercase sequences
+    #[arg(long, conflicts_with = "lower")]
+    pub upper: bool,
+    /// Lowercase sequences
+    #[arg(long)]
+    pub lower: bool,
+    /// Replace bases other than ACGT with BASE (A, C, G or T) or a random base
+    #[arg(long, value_name = "BASE")]
+    pub replace_n: Option<Replacement>,
+    /// Seed for --replace-n random
+    #[arg(long, default_value_t = 0, requires = "replace_n")]
+    pub seed: u64,
+}
+
+impl SeqEditArgs {
+    pub fn edits(&self) -> SeqEdits {
+        let mut edits = SeqEdits::new().seed(self.seed);
+        if self.upper {
+            edits = edits.uppercase();
+        } else if self.lower {
+            edits = edits.lowercase();
+        }
+        if let Some(with) = self.replace_n {
+            edits = edits.replace_ambiguous(with);
+        }
+        edits
+    }
+}
+
+/// Settings shared by the commands that write files
+#[derive(Args, Debug)]
+pub struct OutputArgs {
+    /// Replace output files that already exist
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Worker threads, for the commands that can spread their work over them
+#[derive(Args, Debug)]
+pub struct ThreadArgs {
+    /// Worker threads
+    #[arg(long, value_name = "N", default_value_t = NonZeroUsize::MIN)]
+    pub threads: NonZeroUsize,
+}
+
+impl ThreadArgs {
+    pub fn pool(&self) -> WorkerPool {
+        WorkerPool::new(self.threads)
+    }
+}
+
+/// Write a completion script for `shell` to `out`
+pub fn write_completions(shell: Shell, out: &mut impl Write) {
+    clap_complete::generate(shell, &mut Cli::command(), "lyso", out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Parser, ValueEnum};
+
+    const FA: &str = "../resources/test_data/test.fa";
+
+    /// The error message clap shows for `args`
+    fn parse_error(args: &[&str]) -> String {
+        let args = std::iter::once("lyso").chain(args.iter().copied());
+        Cli::try_parse_from(args).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_command_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_missing_path() {
+        assert_eq!(
+            existing_path("no/such.fa").unwrap_err(),
+            "no/such.fa: no such file or directory"
+        );
+        assert!(existing_path(FA).is_ok());
+        assert!(parse_error(&["view", "no/such.fa"])
+            .contains("invalid value 'no/such.fa' for '[F_PATH]': no/such.fa: no such file"));
+    }
+
+    #[test]
+    fn test_threads_scope() {
+        // only the commands that use worker threads take --threads
+        for args in [
+            &["view", "--threads", "2", FA][..],
+            &["--threads", "2", "filter", FA],
+        ] {
+            assert!(parse_error(args).contains("unexpected argument '--threads'"));
+        }
+        let cli = Cli::try_parse_from(["lyso", "filter", "--threads", "2", FA]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(crate::Commands::Filter { threads, .. }) if threads.threads.get() == 2
+        ));
+    }
+
+    #[test]
+    fn test_byte_size() {
+        assert_eq!(byte_size("512"), Ok(512));
+        assert_eq!(byte_size("64k"), Ok(64 << 10));
+        assert_eq!(byte_size("10M"), Ok(10 << 20));
+        assert_eq!(byte_size("2G"), Ok(2 << 30));
+        for bad in ["", "M", "1.5M", "-1", "10T", "99999999999G"] {
+            assert!(byte_size(bad).is_err(), "{bad}");
+        }
+        assert!(parse_error(&["head", "--bytes", "10X", FA])
+            .contains("invalid size '10X', expected a number of bytes such as 512, 64K or 10M"));
+    }
+
+    #[test]
+    fn test_region_error() {
+        assert!(parse_error(&["faidx", FA, "chr1:20-10"]).contains(
+            "invalid value 'chr1:20-10' for '[REGIONS]...': region start is after its end"
+        ));
+        assert!(parse_error(&["faidx", FA, "chr1:0-5"]).contains("invalid value 'chr1:0-5'"));
+        assert!(Cli::try_parse_from(["lyso", "faidx", FA, "chr1:1,000-2,000", "chr2"]).is_ok());
+    }
+
+    #[test]
+    fn test_flags_error() {
+        assert!(parse_error(&["view", "-f", "UNMAP,DUPE", FA])
+            .contains("unknown flag name 'DUPE' (expected e.g. UNMAP,SECONDARY or 0x104)"));
+        assert!(parse_error(&["view", "-F", "0x10000", FA])
+            .contains("invalid flag value '0x10000': expected a number below 0x10000"));
+        assert!(Cli::try_parse_from(["lyso", "view", "-f", "PAIRED", "-F", "0x904", FA]).is_ok());
+    }
+
+    #[test]
+    fn test_translate_args() {
+        assert!(Cli::try_parse_from(["lyso", "translate", "--frame", "-2", FA]).is_ok());
+        assert!(Cli::try_parse_from(["lyso", "translate", "--table", "11", FA]).is_ok());
+        assert!(parse_error(&["translate", "--frame", "0", FA])
+            .contains("invalid frame '0', expected 1, 2, 3, -1, -2 or -3"));
+        assert!(parse_error(&["translate", "--table", "std", FA])
+            .contains("invalid genetic code 'std', expected an NCBI table number"));
+    }
+
+    #[test]
+    fn test_consensus_args() {
+        let bam = "../resources/test_data/consensus.bam";
+        assert!(Cli::try_parse_from(["lyso", "consensus", bam, FA, "-o", "out.fa"]).is_ok());
+        assert!(parse_error(&["consensus", bam]).contains("<REF_PATH>"));
+        assert!(parse_error(&["consensus", "--min-mapq", "300", bam, FA]).contains("300"));
+    }
+
+    #[test]
+    fn test_getfasta_args() {
+        let bed = "../resources/test_data/getfasta.bed";
+        assert!(Cli::try_parse_from(["lyso", "getfasta", FA, bed, "-o", "out.fa"]).is_ok());
+        assert!(parse_error(&["getfasta", FA]).contains("<BED_PATH>"));
+    }
+
+    #[test]
+    fn test_completions() {
+        for shell in Shell::value_variants() {
+            let mut out = Vec::new();
+            write_completions(*shell, &mut out);
+            let script = String::from_utf8(out).unwrap();
+            assert!(script.contains("faidx"), "{shell}");
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Opening inputs of any supported format
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::path::Path;
+use std::rc::Rc;
+
+use flate2::read::MultiGzDecoder;
+use lyso::common::detect::{detect_compression, detect_format, Compression, DetectError, Format};
+use lyso::common::index_meta::{self, StaleIndex};
+use lyso::common::io::atomic::AtomicFile;
+use lyso::{fasta, fastq};
+
+/// Transparently decompress `inner` and detect the format of its contents
+///
+/// The returned reader is positioned at the start of the decompressed stream.
+pub fn open_input<R: Read + 'static>(inner: R) -> Result<(Format, Box<dyn BufRead>), DetectError> {
+    let mut raw = BufReader::new(inner);
+    let mut reader: Box<dyn BufRead> = match detect_compression(&mut raw)? {
+        Compression::None => Box::new(raw),
+        // BGZF is valid multi-member gzip
+        Compression::Gzip | Compression::Bgzf => Box::new(BufReader::new(MultiGzDecoder::new(raw))),
+    };
+    let format = detect_format(&mut reader)?;
+    Ok((format, reader))
+}
+
+/// A reader that counts the bytes read through it
+///
+/// The count is shared, so it can still be read after the reader is handed off.
+pub struct Counted<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R> Counted<R> {
+    pub fn new(inner: R) -> (Self, Rc<Cell<u64>>) {
+        let count = Rc::new(Cell::new(0));
+        let counted = Counted {
+            inner,
+            count: Rc::clone(&count),
+        };
+        (counted, count)
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// A `.fai` index of a FASTA or FASTQ file
+pub enum RecordIndex {
+    Fasta(fasta::FastaIndex),
+    Fastq(fastq::FastqIndex),
+}
+
+impl RecordIndex {
+    pub fn len(&self) -> usize {
+        match self {
+            RecordIndex::Fasta(idx) => idx.len(),
+            RecordIndex::Fastq(idx) => idx.len(),
+        }
+    }
+
+    /// File offset of the `n`-th record
+    pub fn record_start(&self, n: usize) -> Option<u64> {
+        match self {
+            RecordIndex::Fasta(idx) => idx.record_start(n),
+            RecordIndex::Fastq(idx) => idx.record_start(n),
+        }
+    }
+
+    fn validate_against(&self, path: &Path) -> Result<(), StaleIndex> {
+        match self {
+            RecordIndex::Fasta(idx) => idx.validate_against(path),
+            RecordIndex::Fastq(idx) => idx.validate_against(path),
+        }
+    }
+
+    /// Index the uncompressed FASTA or FASTQ file at `path`
+    pub fn build(path: &Path, format: Format) -> std::io::Result<Self> {
+        let mut raw = BufReader::new(File::open(path)?);
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        match format {
+            Format::Fasta => fasta::FastaIndex::from_fasta_file(&mut raw, Default::default())
+                .map(RecordIndex::Fasta)
+                .map_err(|e| invalid(e.to_string())),
+            Format::Fastq => fastq::FastqIndex::from_fastq_file(&mut raw, Default::default())
+                .map(RecordIndex::Fastq)
+                .map_err(|e| invalid(e.to_string())),
+            format => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("expected FASTA or FASTQ input, found {format}"),
+            )),
+        }
+    }
+
+    /// Replace `<path>.fai` and its `.fai.meta` sidecar, each whole or not at all
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let fai = index_meta::fai_path(path);
+        let mut out = BufWriter::new(AtomicFile::create(&fai, true)?);
+        match self {
+            RecordIndex::Fasta(idx) => idx.write_index(&mut out)?,
+            RecordIndex::Fastq(idx) => idx.write_index(&mut out)?,
+        }
+        out.into_inner().map_err(|e| e.into_error())?.commit()?;
+        index_meta::write_meta(path, &fai)
+    }
+}
+
+/// Load `<path>.fai` if it exists and `path` is an uncompressed FASTA or FASTQ file
+///
+/// Index offsets refer to the uncompressed bytes, so compressed inputs never use one.
+/// An unreadable or malformed index is ignored; one that no longer matches the file
+/// is an error, as it would fetch the wrong bytes.
+pub fn open_index(path: &Path, format: Format) -> Result<Option<RecordIndex>, StaleIndex> {
+    let Some(idx) = read_index(path, format) else {
+        return Ok(None);
+    };
+    idx.validate_against(path)?;
+    Ok(Some(idx))
+}
+
+/// Like `open_index`, but a stale index is rebuilt and rewritten when `rebuild` is set
+pub fn open_fetch_index(
+    path: &Path,
+    format: Format,
+    rebuild: bool,
+) -> Result<Option<RecordIndex>, StaleIndex> {
+    match open_index(path, format) {
+        Err(StaleIndex::Io(e)) => Err(StaleIndex::Io(e)),
+        Err(_) if rebuild => {
+            let idx = RecordIndex::build(path, format)?;
+            idx.write(path)?;
+            Ok(Some(idx))
+        }
+        other => other,
+    }
+}
+
+fn read_index(path: &Path, format: Format) -> Option<RecordIndex> {
+    let mut idx_in = BufReader::new(File::open(index_meta::fai_path(path)).ok()?);
+    let mut raw = BufReader::new(File::open(path).ok()?);
+    if detect_compression(&mut raw).ok()? != Compression::None {
+        return None;
+    }
+    match format {
+        Format::Fasta => {
+            let mut idx = fasta::FastaIndex::new();
+            idx.read_index(&mut idx_in).ok()?;
+            Some(RecordIndex::Fasta(idx))
+        }
+        Format::Fastq => {
+            let mut idx = fastq::FastqIndex::new();
+            idx.read_index(&mut idx_in).ok()?;
+            Some(RecordIndex::Fastq(idx))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+
+    const DATA: &str = "../resources/test_data";
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(bytes).unwrap();
+        enc.finish().unwrap()
+    }
+
+    fn format_of(bytes: Vec<u8>) -> Result<Format, DetectError> {
+        open_input(Cursor::new(bytes)).map(|(f, _)| f)
+    }
+
+    #[test]
+    fn test_open_each_format() {
+        for (file, format) in [("test.fa", Format::Fasta), ("test.fastq", Format::Fastq)] {
+            let bytes = std::fs::read(format!("{DATA}/{file}")).unwrap();
+            assert_eq!(format_of(gzip(&bytes)).unwrap(), format);
+            assert_eq!(format_of(bytes).unwrap(), format);
+        }
+        let sam = b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n".to_vec();
+        assert_eq!(format_of(gzip(&sam)).unwrap(), Format::Sam);
+        assert_eq!(format_of(sam).unwrap(), Format::Sam);
+
+        let bam = File::open(format!("{DATA}/name_sorted.bam")).unwrap();
+        let (format, mut reader) = open_input(bam).unwrap();
+        assert_eq!(format, Format::Bam);
+        // detection does not consume
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, b"BAM\x01");
+    }
+
+    #[test]
+    fn test_open_ambiguous() {
+        let text = b"@note\nthis is not a record\n".to_vec();
+        let err = format_of(gzip(&text)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "could not determine format: expected BAM, SAM, FASTA or FASTQ"
+        );
+    }
+}
@@ -1,23 +1,104 @@
+use std::collections::HashSet;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::stdout;
-use std::io::{self, Write};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::process::exit;
-
-use bgzip;
+use std::rc::Rc;
 
 use clap::{Parser, Subcommand};
+use clap_complete::Shell;
+
+use lyso::bam::consensus::{ConsensusBuilder, ConsensusOptions};
+use lyso::bam::coverage::{CoverageBuilder, RefCoverage, TargetCoverage};
+use lyso::bam::filter::{MissingTag, RecordFilter, TagPredicate};
+use lyso::bam::flags::Flags;
+use lyso::bam::pairs::{insert_size_summary, Pair, PairIter, SegmentDedup, SideChannel};
+use lyso::bam::pileup::Pileup;
+use lyso::bam::split::{RgSplitter, SplitWriters};
+use lyso::bam::stats::{mapq_bin_label, BamStats};
+use lyso::bam2fq::to_fastq;
+use lyso::common::align::{global_align, local_align, AlignParams, Alignment};
+use lyso::common::detect::{detect_compression, detect_format, Compression, Format};
+use lyso::common::dict::{dict_path, SequenceDictionary};
+use lyso::common::diff::{diff_lockstep, diff_unordered, find_hashes, CanonicalHash, RecordDiff};
+use lyso::common::filter::{LowComplexity, MinLength, MinMeanQuality, SlidingWindowQuality};
+use lyso::common::format::{gc_content, OutputMode};
+use lyso::common::index_meta::{self, StaleIndex};
+use lyso::common::intervals::{IntervalSet, NamedInterval, Strand};
+use lyso::common::io::atomic::AtomicFile;
+use lyso::common::pool::WorkerPool;
+use lyso::common::raw::RawRecord;
+use lyso::common::region::Region;
+use lyso::common::rename::{IdTemplate, Renamer};
+use lyso::common::runs::{find_runs, is_masked};
+use lyso::common::search::{reverse_complement, Motif};
+use lyso::common::seqedit::SeqEdits;
+use lyso::common::sort::{sort_records, SortBy, SortKey};
+use lyso::common::stream::{Bounded, RecordBytes, RecordStreamExt};
+use lyso::common::synth::{FastaSimulator, FastqSimulator, QualityProfile, SimRecord};
+use lyso::common::translate::{Frame, GeneticCode, Translator};
+use lyso::common::validate::{MaxHomopolymer, Severity, ValidationReport, Validator};
+use lyso::fasta::clean::AssemblyCleaner;
+use lyso::fasta::concat::concatenate;
+use lyso::fasta::export::ExportOrder;
+use lyso::fasta::extract::{extract_intervals, extract_intervals_streaming, ExtractError};
+use lyso::fasta::mask::{mask_records, MaskMode};
+use lyso::fasta::split::{gap_layout, layout_contigs, scaffold_name, Part};
+use lyso::fasta::store::{FastaStore, ReferenceSource, DEFAULT_MAX_BYTES};
+use lyso::fasta::windows::StreamWindows;
+use lyso::fastq::barcode::{is_mate_pair, BarcodePattern, Extractor, Placement};
+use lyso::fastq::demux::{read_sample_sheet, Demultiplexer, OutputFiles, Route};
+use lyso::fastq::merge::{merge_pair, MergeOptions, MergeResult};
+use lyso::fastq::requal::{guess_phred_encoding, quality_range, reencode_records, PhredEncoding};
+use lyso::fastq::stats::{CycleProfile, CycleStats};
+use lyso::fq2bam::{self, to_bam, DescPolicy};
+use lyso::inspect::{
+    bam_header, probe_index, sample_bam, sample_fasta, sample_fastq, InspectReport,
+};
+use lyso::prelude::*;
+use lyso::validate::{check_md5, check_reference, ValidateOptions};
+
+use args::{byte_size, existing_path, fraction, sam_tag, OutputArgs, SeqEditArgs, ThreadArgs};
+use input::RecordIndex;
+use outfiles::exit;
+use output::{FormatReport, PerCycle};
+
+mod args;
+mod input;
+mod outfiles;
+mod output;
+
+/// Which records `head`, `tail` and `range` print
+#[derive(Clone, Copy)]
+enum Slice {
+    /// Count, and input bytes to stop after
+    Head(usize, Option<u64>),
+    Tail(usize),
+    /// 0-based first record and count
+    Range(usize, usize),
+}
+
+/// An unparsed SAM alignment line
+struct SamLine(String);
+
+impl Display for SamLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-use lyso_bam::reader::BamReader;
-use lyso_bam::BamError;
-use lyso_bam::Record as BamRecord;
-use lyso_fasta::reader::FastaReader;
-use lyso_fasta::FastaError;
-use lyso_fastq::reader::FastqReader;
-use lyso_fastq::FastqError;
-use lyso_fastq::Record;
+impl RecordFormatter for SamLine {
+    const COLUMNS: &'static [&'static str] = &["qname", "flag", "rname", "pos", "mapq", "cigar"];
 
-use std::time::Instant;
+    /// POS 0, for records without a position, prints as "*"
+    fn write_tsv(&self, out: &mut impl std::fmt::Write) -> std::fmt::Result {
+        let fields = self.0.split('\t').take(Self::COLUMNS.len()).enumerate();
+        let fields = fields.map(|(i, f)| if i == 3 && f == "0" { "*" } else { f });
+        write!(out, "{}", fields.collect::<Vec<&str>>().join("\t"))
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -28,116 +109,4348 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Generate various file indices
+    /// Index a FASTA or FASTQ file, or print regions of an indexed FASTA file
+    ///
+    /// Without regions, writes <file>.fai and <file>.fai.meta, which records the
+    /// file's size and hashes so a stale index can be detected; an existing index
+    /// is only replaced with --force. With regions, prints
+    /// each one as a FASTA record, using <file>.fai when present and indexing in
+    /// memory otherwise. A stale index is an error unless --rebuild-if-stale is given.
+    #[command(after_long_help = "\
+Examples:
+  lyso faidx ref.fa                       write ref.fa.fai
+  lyso faidx reads.fq                     write reads.fq.fai
+  lyso faidx --force ref.fa               replace ref.fa.fai
+  lyso faidx ref.fa chr1:1,000-2,000      bases 1000 to 2000 of chr1 (1-based, inclusive)
+  lyso faidx ref.fa chr2 chr3:500         all of chr2, and chr3 from base 500 on
+  lyso faidx ref.fa 'HLA-A*01:01:1-10'    names may contain ':'
+  lyso faidx --rebuild-if-stale ref.fa chr1  reindex ref.fa first if it changed
+  lyso faidx --light reads.fq             index without holding the index in memory")]
     Faidx {
         /// Input file
+        #[arg(value_parser = existing_path)]
         f_path: Option<PathBuf>,
+        /// NAME, NAME:START or NAME:START-END, 1-based and inclusive
+        regions: Vec<Region>,
+        /// Rewrite <file>.fai if it no longer matches the file
+        #[arg(long)]
+        rebuild_if_stale: bool,
+        /// Write each line of <file>.fai as its record is indexed, keeping only a
+        /// hash and an offset per name to catch repeats
+        #[arg(long, conflicts_with = "regions")]
+        light: bool,
+        #[command(flatten)]
+        files: OutputArgs,
     },
+    /// Print records of a BAM, SAM, FASTA or FASTQ file (optionally gzipped)
+    ///
+    /// In the native output mode FASTA and FASTQ records are copied byte for byte,
+    /// keeping line wrapping, line endings and the text of FASTQ '+' lines.
+    ///
+    /// Regions after the file, or records named with --id, are fetched from an
+    /// uncompressed FASTA or FASTQ file through <file>.fai, which is built in memory
+    /// with a warning when missing. They are read in file order and printed in the
+    /// order given; if any is missing, all misses are listed and nothing is printed.
+    /// Whole records are copied as they are in the file, and regions are wrapped at
+    /// the width of their record.
+    ///
+    /// --upper, --lower and --replace-n edit FASTA and FASTQ sequences, which are
+    /// then printed from the parsed records; qualities are never changed.
+    ///
+    /// BAM records can be filtered on flags and aux tags; a record is printed when
+    /// it passes every filter given. --tag takes TAG:OP:VALUE: le and ge compare an
+    /// integer tag with an integer VALUE, and eq compares integers when VALUE is
+    /// one, or else the text of a string (Z) tag; str always compares text. A tag
+    /// of another type fails the test. A record lacking the tag fails too, unless
+    /// --missing-tag pass is given; --require-tag and --require-tag-missing test
+    /// for the tag itself.
+    #[command(after_long_help = "\
+Examples:
+  lyso view reads.bam
+  lyso view --output-mode tsv reads.fq.gz
+  lyso view ref.fa chr3 chr1:1000-2000                  fetch through ref.fa.fai
+  lyso view reads.fq --id SRR1.55 --id SRR1.56
+  lyso view --upper --replace-n random --seed 7 ref.fa  unmask, and resolve Ns
+  lyso view -f PAIRED,PROPER_PAIR -F 0x904 reads.bam    primary, properly paired reads
+  lyso view -F UNMAP,DUP --output-mode summary reads.bam
+  lyso view -H reads.bam                                header text only
+  lyso view --tag NM:le:2 --tag RG:eq:sample1 --require-tag-missing SA aln.bam
+  lyso view -F UNMAP --tag AS:ge:100 --missing-tag pass aln.bam
+
+Flag masks are a number (decimal or 0x hex) or comma-separated names:
+PAIRED PROPER_PAIR UNMAP MUNMAP REVERSE MREVERSE READ1 READ2
+SECONDARY QCFAIL DUP SUPPLEMENTARY")]
     View {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// NAME, NAME:START or NAME:START-END of a FASTA file, 1-based and inclusive
+        #[arg(conflicts_with_all = ["output_mode", "require_flags", "exclude_flags", "tags", "require_tag", "require_tag_missing", "header_only"])]
+        regions: Vec<Region>,
+        /// Fetch the FASTA or FASTQ record with this id; may be repeated
+        #[arg(long = "id", value_name = "ID", conflicts_with_all = ["output_mode", "require_flags", "exclude_flags", "tags", "require_tag", "require_tag_missing", "header_only"])]
+        ids: Vec<String>,
+        /// native, tsv (one row per record with a header) or summary (counts only)
+        #[arg(long, default_value_t = OutputMode::Native)]
+        output_mode: OutputMode,
+        /// Only BAM records with all of these flags set
+        #[arg(short = 'f', long)]
+        require_flags: Option<Flags>,
+        /// Only BAM records with none of these flags set
+        #[arg(short = 'F', long)]
+        exclude_flags: Option<Flags>,
+        /// Only BAM records whose aux tag passes TAG:OP:VALUE; may be repeated
+        #[arg(long = "tag", value_name = "TAG:OP:VALUE")]
+        tags: Vec<TagPredicate>,
+        /// Only BAM records with this aux tag; may be repeated
+        #[arg(long, value_name = "TAG", value_parser = sam_tag)]
+        require_tag: Vec<String>,
+        /// Only BAM records without this aux tag; may be repeated
+        #[arg(long, value_name = "TAG", value_parser = sam_tag)]
+        require_tag_missing: Vec<String>,
+        /// Whether a --tag test passes records lacking its tag: pass or fail
+        #[arg(long, default_value_t = MissingTag::Fail)]
+        missing_tag: MissingTag,
+        /// Print the header text and no records (BAM and SAM only)
+        #[arg(short = 'H', long, conflicts_with_all = ["output_mode", "require_flags", "exclude_flags", "tags", "require_tag", "require_tag_missing", "upper", "lower", "replace_n"])]
+        header_only: bool,
+        #[command(flatten)]
+        seq_edits: SeqEditArgs,
+    },
+    /// Summarize read qualities of a FASTQ file, or alignments of a BAM file
+    #[command(after_long_help = "\
+Examples:
+  lyso stats reads.fq.gz
+  lyso stats --per-cycle reads.fq > cycles.tsv
+  lyso stats --json aln.bam | jq .mapping_rate")]
+    Stats {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Print one row of quality quartiles and base composition per read position (FASTQ only)
+        #[arg(long)]
+        per_cycle: bool,
+        /// Print a JSON object instead of text, with a "lyso_schema_version" field
+        #[arg(long)]
+        json: bool,
+    },
+    /// Report the format, record count and integrity of a file
+    ///
+    /// Reads up to --sample records. When the file is longer, the record count is
+    /// estimated from their average size and marked with '~'. Also reports read
+    /// lengths, the quality encoding of FASTQ, the sort order and references of BAM,
+    /// and whether the .fai or .bai index next to the file is valid and up to date.
+    /// Exits with status 1 if a record fails to parse.
+    #[command(after_long_help = "\
+Examples:
+  lyso inspect reads.fq.gz
+  lyso inspect --sample 100000 aln.bam
+  lyso inspect --json reads.fq.gz | jq .records")]
+    Inspect {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Records to read before estimating the count
+        #[arg(long, default_value_t = 10_000)]
+        sample: usize,
+        /// Print a JSON object instead of text, with a "lyso_schema_version" field
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the number of records in a file
+    ///
+    /// Counts without building records: FASTQ by its line structure, FASTA by
+    /// its '>' headers and BAM by each record's block size, so input that would
+    /// fail to read still fails here. With an up-to-date index (<file>.fai) next
+    /// to an uncompressed FASTA or FASTQ file, reads only the index.
+    #[command(after_long_help = "\
+Examples:
+  lyso count reads.fq.gz
+  lyso faidx ref.fa && lyso count ref.fa    count the index entries")]
+    Count {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Print a JSON object instead of text, with a "lyso_schema_version" field
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check every record of a FASTA, FASTQ or BAM file against its format's rules
+    ///
+    /// FASTQ records must have IUPAC bases, a Phred+33 quality per base and a
+    /// printable id; FASTA records a printable header, with non-nucleotide bases
+    /// only warned about. BAM records are checked for an invalid read name, a CIGAR
+    /// that disagrees with the sequence length, alignments past the end of their
+    /// reference, mapped reads with MAPQ 255, mate fields that contradict the
+    /// flags, a wrong bin and an implausible NM, all as warnings. Prints the number
+    /// of violations per rule with the first offending record as an example, and
+    /// exits with status 1 on any error or more than --max-warnings warnings.
+    #[command(after_long_help = "\
+Examples:
+  lyso check aln.bam
+  lyso check --max-warnings 100 aln.bam
+  lyso check --max-homopolymer 30 reads.fq.gz")]
+    Check {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Warnings to allow before exiting with status 1
+        #[arg(long, default_value_t = 0)]
+        max_warnings: u64,
+        /// Also warn about runs of one base longer than this
+        #[arg(long, value_name = "N")]
+        max_homopolymer: Option<usize>,
+    },
+    /// Summarize insert sizes of proper pairs in a name-sorted BAM
+    #[command(after_long_help = "\
+Examples:
+  lyso isize name_sorted.bam")]
+    Isize {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Print a JSON object instead of text, with a "lyso_schema_version" field
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write the reads of a name-sorted BAM as FASTQ
+    ///
+    /// Mates go to --read1 and --read2 in matching order, or interleaved to stdout
+    /// with /1 and /2 appended to their names. Reads aligned to the reverse strand
+    /// are reverse complemented. Supplementary alignments are never written;
+    /// secondary ones only with --include-secondary, and only for a segment without
+    /// a primary record. As in samtools fastq, a mate whose partner is missing goes
+    /// to --singletons, or else with the mates, and a read with neither or both of
+    /// READ1 and READ2 goes to --unpaired, or else to stdout. A record without SEQ,
+    /// or with '=' bases, is an error. Files named *.gz or *.bgz are written
+    /// BGZF-compressed, on --threads threads.
+    #[command(after_long_help = "\
+Examples:
+  lyso bam2fq name_sorted.bam > interleaved.fq
+  lyso bam2fq -1 r1.fq -2 r2.fq -s singletons.fq -0 unpaired.fq name_sorted.bam
+  lyso bam2fq --threads 4 -1 r1.fq.gz -2 r2.fq.gz name_sorted.bam")]
+    Bam2fq {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Write first mates to FILE
+        #[arg(short = '1', long, value_name = "FILE", requires = "read2")]
+        read1: Option<PathBuf>,
+        /// Write second mates to FILE
+        #[arg(short = '2', long, value_name = "FILE", requires = "read1")]
+        read2: Option<PathBuf>,
+        /// Write mates whose partner is missing to FILE
+        #[arg(short, long, value_name = "FILE")]
+        singletons: Option<PathBuf>,
+        /// Write reads with neither or both of READ1 and READ2 to FILE
+        #[arg(short = '0', long, value_name = "FILE")]
+        unpaired: Option<PathBuf>,
+        /// Write a secondary alignment for a segment with no primary record
+        #[arg(long)]
+        include_secondary: bool,
+        /// Do not append /1 and /2 to interleaved read names
+        #[arg(short = 'n', long)]
+        no_suffix: bool,
+        #[command(flatten)]
+        threads: ThreadArgs,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Write the reads of a FASTQ file as unaligned BAM
+    ///
+    /// Each read becomes an unmapped record with MAPQ 0, in input order. Bases
+    /// are upper-cased and must be IUPAC codes; qualities must be Phred+33 (see
+    /// `lyso requal`). Read descriptions are kept in a CO tag by default; with
+    /// --desc tags, TAG:TYPE:VALUE words such as the BC and RX tags of `lyso
+    /// barcode` become aux fields. With --rg-id every record gets an RG tag and
+    /// the header an @RG line. Output is BGZF-compressed, on --threads threads.
+    #[command(after_long_help = "\
+Examples:
+  lyso fq2bam reads.fq -o reads.bam --rg-id S1
+  lyso fq2bam --desc tags barcoded.fq -o reads.bam
+  lyso fq2bam reads.fq -o reads.bam && lyso bam2fq -0 back.fq reads.bam")]
+    Fq2bam {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Write the BAM to FILE
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+        /// Read group of every read, added to the header as an @RG line
+        #[arg(long, value_name = "ID")]
+        rg_id: Option<String>,
+        /// What to do with read descriptions: drop, comment or tags
+        #[arg(long, default_value_t = DescPolicy::Comment)]
+        desc: DescPolicy,
+        #[command(flatten)]
+        threads: ThreadArgs,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Print the FASTA or FASTQ records that pass every given filter
+    ///
+    /// Filters see sequences as read; --upper, --lower and --replace-n edit the
+    /// records that pass. Without those, records are copied byte for byte. FASTQ
+    /// records are read and filtered on --threads threads.
+    #[command(after_long_help = "\
+Examples:
+  lyso filter --min-length 50 reads.fq
+  lyso filter --min-mean-qual 25 --window-qual 4:20 reads.fq.gz
+  lyso filter --low-complexity 0.5 contigs.fa
+  lyso filter --min-length 50 --replace-n A reads.fq")]
+    Filter {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Minimum read length
+        #[arg(long)]
+        min_length: Option<usize>,
+        /// Minimum mean Phred score over the whole read
+        #[arg(long)]
+        min_mean_qual: Option<f64>,
+        /// WINDOW:MIN_MEAN, e.g. 4:20; fail reads with any window below MIN_MEAN
+        #[arg(long)]
+        window_qual: Option<SlidingWindowQuality>,
+        /// Minimum dinucleotide entropy, 0.0 (homopolymer) to 1.0
+        #[arg(long)]
+        low_complexity: Option<f64>,
+        #[command(flatten)]
+        seq_edits: SeqEditArgs,
+        #[command(flatten)]
+        threads: ThreadArgs,
+    },
+    /// Split a BAM into one file per read group
+    ///
+    /// Each record goes to the output of its RG tag, and records without one to
+    /// unassigned.bam. Every output has the input's header with only the @RG line
+    /// of its read group kept, and each @RG of the header gets an output even
+    /// without records. Files are named after the read group, with characters
+    /// other than letters, digits, '.', '-' and '_' replaced by '_' and a number
+    /// added to names that would clash, even only in case, and written
+    /// BGZF-compressed to --out-dir. Each file is moved into place once every
+    /// record is written, replacing an existing one only with --force. Prints the
+    /// records written per output, with '*' for the records without an RG.
+    #[command(after_long_help = "\
+Examples:
+  lyso split-rg aln.bam --out-dir by_rg
+  lyso split-rg --max-open-files 64 aln.bam --out-dir by_rg")]
+    SplitRg {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Directory for the outputs, created if missing
+        #[arg(long, value_name = "DIR")]
+        out_dir: PathBuf,
+        /// Most output files to keep open at once
+        #[arg(long, default_value_t = 256)]
+        max_open_files: usize,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Check the references of a BAM header against a reference FASTA
+    ///
+    /// Reports BAM references missing from the FASTA, FASTA sequences missing
+    /// from the BAM header, length differences and references in a different
+    /// order. When the BAM header and a .dict (--dict, or ref.dict next to
+    /// ref.fa) both give M5 checksums for a sequence, those are compared too.
+    /// Exits 1 when any mismatch is not allowed by the flags.
+    #[command(after_long_help = "\
+Examples:
+  lyso validate-ref aln.bam ref.fa
+  lyso validate-ref --ignore-order --subset-ok aln.bam ref.fa")]
+    ValidateRef {
+        #[arg(value_parser = existing_path, requires = "ref_path")]
+        bam_path: Option<PathBuf>,
+        /// Uncompressed FASTA; its .fai is used when present and fresh
+        #[arg(value_parser = existing_path)]
+        ref_path: Option<PathBuf>,
+        /// Sequence dictionary with M5 checksums
+        #[arg(long, value_parser = existing_path)]
+        dict: Option<PathBuf>,
+        /// Accept the same sequences in a different order
+        #[arg(long)]
+        ignore_order: bool,
+        /// Accept FASTA sequences the BAM header does not declare
+        #[arg(long)]
+        subset_ok: bool,
+    },
+    /// Compare two FASTA, FASTQ or BAM files record by record
+    ///
+    /// Records are paired by position and the first differing pairs are shown
+    /// field by field. With --unordered the files are compared as multisets of
+    /// records instead; only a hash of each record is held, spilled to the
+    /// temporary directory past the memory limit. BAM records compare as their SAM
+    /// lines would, with aux fields in any order. Exits 0 when the files hold the
+    /// same records, 1 when they differ and 2 on an error.
+    #[command(after_long_help = "\
+Examples:
+  lyso diff original.fq roundtrip.fq
+  lyso diff --unordered -n 20 a.bam b.bam
+  lyso diff --ignore-tags PG,MD a.bam b.bam")]
+    Diff {
+        #[arg(value_parser = existing_path)]
+        left: PathBuf,
+        #[arg(value_parser = existing_path)]
+        right: PathBuf,
+        /// Differing records to show
+        #[arg(short = 'n', long, default_value_t = 10)]
+        max_shown: usize,
+        /// Compare the records as multisets, ignoring their order
+        #[arg(long)]
+        unordered: bool,
+        /// BAM aux tags to leave out of the comparison, comma-separated
+        #[arg(long, value_name = "TAGS", value_delimiter = ',')]
+        ignore_tags: Vec<String>,
+        /// Approximate memory for --unordered record hashes, in MiB
+        #[arg(long, default_value_t = 256)]
+        memory_limit: usize,
+    },
+    /// Per-reference read count, mean depth and breadth of a coordinate-sorted BAM
+    ///
+    /// With --targets, prints the mean depth and breadth of each BED interval
+    /// instead, in header order of its reference and then by start; overlapping
+    /// intervals are each reported in full. JSON output has both.
+    #[command(after_long_help = "\
+Examples:
+  lyso coverage sorted.bam
+  lyso coverage --targets exome.bed sorted.bam")]
+    Coverage {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Report depth over these BED intervals
+        #[arg(long, value_parser = existing_path)]
+        targets: Option<PathBuf>,
+        /// Print a JSON object instead of text, with a "lyso_schema_version" field
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the point mismatches of each mapped BAM record, found from its MD tag
+    ///
+    /// One TSV row per mismatching read base: reference, 1-based position,
+    /// reference base, read base, base quality ('.' without QUAL) and read name.
+    /// Indels are not reported. Records without an MD tag are skipped and counted
+    /// in a warning; an MD tag that disagrees with the CIGAR is an error.
+    #[command(after_long_help = "\
+Examples:
+  lyso mismatches aln.bam
+  lyso mismatches aln.bam | awk '$5 >= 30'    well-supported mismatches only")]
+    Mismatches {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+    },
+    /// Majority-base consensus of a coordinate-sorted BAM against its reference
+    ///
+    /// Writes one FASTA record per reference. Positions without passing reads are
+    /// N, deleted positions are dropped and insertions are ignored. Lower case
+    /// marks calls below --confident-depth and 50/50 splits.
+    #[command(after_long_help = "\
+Examples:
+  lyso consensus sorted.bam ref.fa -o consensus.fa
+  lyso consensus --min-mapq 20 --min-depth 5 sorted.bam ref.fa")]
+    Consensus {
+        #[arg(value_parser = existing_path)]
+        bam_path: PathBuf,
+        /// Reference FASTA, loaded into memory if small enough; a larger one must
+        /// be uncompressed, and ref.fa.fai is used if present
+        #[arg(value_parser = existing_path)]
+        ref_path: PathBuf,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Ignore reads below this mapping quality
+        #[arg(long, default_value_t = ConsensusOptions::default().min_mapq)]
+        min_mapq: u8,
+        /// Ignore bases below this quality
+        #[arg(long, default_value_t = ConsensusOptions::default().min_baseq)]
+        min_baseq: u8,
+        /// Use the reference base below this depth
+        #[arg(long, default_value_t = ConsensusOptions::default().min_depth)]
+        min_depth: u32,
+        /// Write calls below this depth in lower case
+        #[arg(long, default_value_t = ConsensusOptions::default().confident_depth)]
+        confident_depth: u32,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Extract the subsequences of a FASTA file covered by BED intervals
+    ///
+    /// Each interval becomes one record, named after the BED name column or
+    /// chrom:start-end (0-based, as in the BED file), in BED order. Minus-strand
+    /// intervals are reverse complemented. A reference of up to 64 MiB is loaded
+    /// into memory; a larger one is read through ref.fa.fai when present, and
+    /// otherwise in one pass. A stale ref.fa.fai is an error either way unless
+    /// --rebuild-if-stale is given, which rewrites it. Intervals past the end of a
+    /// sequence are clamped and empty ones skipped, with a warning; sequences
+    /// missing from the reference are listed at the end and make the exit status
+    /// nonzero.
+    #[command(after_long_help = "\
+Examples:
+  lyso getfasta ref.fa regions.bed
+  lyso getfasta ref.fa.gz regions.bed -o regions.fa")]
+    Getfasta {
+        /// Reference FASTA, optionally gzipped
+        #[arg(value_parser = existing_path)]
+        ref_path: PathBuf,
+        /// BED3 to BED6 intervals
+        #[arg(value_parser = existing_path)]
+        bed_path: PathBuf,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Rewrite ref.fa.fai if it no longer matches the reference
+        #[arg(long)]
+        rebuild_if_stale: bool,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Drop short contigs of an assembly, then order, rename and rewrap the rest
+    ///
+    /// Reads the FASTA file twice: lengths come from <file>.fai, or an index built
+    /// in memory when there is none, and each kept contig is then fetched and
+    /// written in turn, so only one is held in memory. The input must be
+    /// uncompressed. Contigs are written under their names, without descriptions,
+    /// and --map lists old name, new name and length in output order.
+    #[command(after_long_help = "\
+Examples:
+  lyso clean-asm contigs.fa --min-len 500 --sort-by-length --rename 'contig_{n}' --wrap 80
+  lyso clean-asm contigs.fa --min-len 1000 --map names.tsv -o clean.fa")]
+    CleanAsm {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Drop contigs shorter than this; a contig of exactly this length is kept
+        #[arg(long, default_value_t = 0)]
+        min_len: u64,
+        /// Write the longest contigs first, keeping file order between equal lengths
+        #[arg(long)]
+        sort_by_length: bool,
+        /// Rename contigs in output order from a template; placeholders: {n}, {id}, {hash8}
+        #[arg(long, value_name = "TEMPLATE")]
+        rename: Option<IdTemplate>,
+        /// Bases per sequence line; 0 writes each sequence on one line
+        #[arg(long, default_value_t = 0)]
+        wrap: usize,
+        /// Write an old<TAB>new<TAB>length line per written contig to this file
+        #[arg(long)]
+        map: Option<PathBuf>,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Rewrite <file>.fai if it no longer matches the file
+        #[arg(long)]
+        rebuild_if_stale: bool,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Write the records of a FASTA file in another order
+    ///
+    /// Reads the FASTA file twice, like clean-asm: the order comes from
+    /// <file>.fai, or an index built in memory when there is none, and each
+    /// record is then fetched and written in turn. The input must be
+    /// uncompressed. Headers are kept whole, and sequences are wrapped at the
+    /// length of their record's first line unless --wrap is given. Every line
+    /// ends in LF, so --by file copies the file byte for byte only when it has LF
+    /// line endings, full lines of one length within each record and no blank
+    /// lines.
+    #[command(after_long_help = "\
+Examples:
+  lyso reorder ref.fa --by length > sorted.fa
+  lyso reorder ref.fa --by natural --wrap 60 -o ref.sorted.fa")]
+    Reorder {
+        #[arg(value_parser = existing_path)]
+        f_path: PathBuf,
+        /// Order to write records in: file, name, natural (chr2 before chr10) or
+        /// length (longest first)
+        #[arg(long, default_value_t = ExportOrder::FileOrder)]
+        by: ExportOrder,
+        /// Rewrap sequences to this many bases per line; 0 writes each sequence on one line
+        #[arg(long)]
+        wrap: Option<usize>,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Rewrite <file>.fai if it no longer matches the file
+        #[arg(long)]
+        rebuild_if_stale: bool,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Join the records of a FASTA file into one "concat" record
+    ///
+    /// Records are separated by runs of N. With --map, also writes a TSV of the
+    /// name, 0-based start in the joined sequence and length of each record, for
+    /// mapping coordinates back. Record names (up to the first whitespace) must be
+    /// unique.
+    #[command(after_long_help = "\
+Examples:
+  lyso concat contigs.fa -o merged.fa --map merged.offsets.tsv --spacer 500
+  lyso concat --spacer 0 contigs.fa.gz > merged.fa")]
+    Concat {
+        /// FASTA input, optionally gzipped
+        #[arg(value_parser = existing_path)]
+        f_path: PathBuf,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Write the offset map here
+        #[arg(long)]
+        map: Option<PathBuf>,
+        /// Number of N's between records
+        #[arg(long, default_value_t = 100)]
+        spacer: usize,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Split the scaffolds of a FASTA file into contigs at runs of N
+    ///
+    /// A run of at least --min-gap N (either case) is a gap; shorter runs stay in
+    /// their contig, and gaps at either end leave no empty contig. Contigs are
+    /// named after the first word of the scaffold's header with a 1-based suffix,
+    /// scf1_1, scf1_2, ... With --layout, also writes a TSV with one row per contig
+    /// or gap of each scaffold: scaffold, 1-based start and end, then the contig
+    /// name or '.', and the gap length or '.'.
+    #[command(
+        name = "scaffold2contig",
+        after_long_help = "\
+Examples:
+  lyso scaffold2contig scaffolds.fa --min-gap 10 > contigs.fa
+  lyso scaffold2contig scaffolds.fa.gz --layout layout.tsv --wrap 80 -o contigs.fa"
+    )]
+    Scaffold2contig {
+        /// FASTA, optionally gzipped
+        #[arg(value_parser = existing_path)]
+        ref_path: PathBuf,
+        /// Shortest run of N that splits a scaffold
+        #[arg(long, default_value_t = 10)]
+        min_gap: usize,
+        /// Write the contig and gap layout of each scaffold here as TSV
+        #[arg(long)]
+        layout: Option<PathBuf>,
+        /// Bases per sequence line; 0 writes each sequence on one line
+        #[arg(long, default_value_t = 0)]
+        wrap: usize,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Soft-masked (lowercase) bases of each record of a FASTA file
+    ///
+    /// Prints the length, masked bases and masked fraction of each record, named by
+    /// the first word of its header, then a "total" row. With --bed, also writes
+    /// every masked run of at least --min-run bases as BED (0-based, half-open).
+    #[command(after_long_help = "\
+Examples:
+  lyso maskstats ref.fa
+  lyso maskstats --bed repeats.bed --min-run 100 ref.fa.gz")]
+    Maskstats {
+        /// FASTA, optionally gzipped
+        #[arg(value_parser = existing_path)]
+        ref_path: PathBuf,
+        /// Write masked runs here as BED
+        #[arg(long)]
+        bed: Option<PathBuf>,
+        /// Shortest masked run written to --bed
+        #[arg(long, default_value_t = 1)]
+        min_run: usize,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Mask the bases of a FASTA file covered by BED intervals
+    ///
+    /// Bases inside an interval are lowercased, or with --hard replaced by N;
+    /// everything else, including line wrapping and line endings, is copied as
+    /// read. Intervals are matched to records by the first word of the header and
+    /// end at the end of the record.
+    #[command(after_long_help = "\
+Examples:
+  lyso mask ref.fa repeats.bed -o masked.fa
+  lyso mask --hard ref.fa.gz gaps.bed")]
+    Mask {
+        /// FASTA, optionally gzipped
+        #[arg(value_parser = existing_path)]
+        ref_path: PathBuf,
+        /// BED3 to BED6 intervals
+        #[arg(value_parser = existing_path)]
+        bed_path: PathBuf,
+        /// Replace masked bases with N instead of lowercasing them
+        #[arg(long)]
+        hard: bool,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Fixed-size windows over each record of a FASTA file
+    ///
+    /// Prints the first word of each header with the 0-based, half-open bounds of
+    /// each window, then its bases, or with --gc its GC fraction. The first window
+    /// to run past the end of a record is cut short unless --skip-partial is given.
+    /// Records are read a line at a time, so they need not fit in memory.
+    #[command(after_long_help = "\
+Examples:
+  lyso windows --size 1000 --step 500 --gc ref.fa
+  lyso windows --size 21 --step 1 contigs.fa.gz      every 21-mer")]
+    Windows {
+        /// FASTA, optionally gzipped
+        #[arg(value_parser = existing_path)]
+        ref_path: PathBuf,
+        /// Bases per window
+        #[arg(long)]
+        size: NonZeroUsize,
+        /// Bases between window starts [default: --size]
+        #[arg(long)]
+        step: Option<NonZeroUsize>,
+        /// Print the GC fraction instead of the bases
+        #[arg(long)]
+        gc: bool,
+        /// Leave out windows shorter than --size
+        #[arg(long)]
+        skip_partial: bool,
+    },
+    /// Print the first records of a FASTA, FASTQ or BAM file
+    ///
+    /// With --bytes, stops before the record that would take the input read past
+    /// SIZE bytes (decompressed for BAM and gzipped input), and prints every record
+    /// up to there unless -n is also given. A FASTA record longer than SIZE is not
+    /// read in full.
+    #[command(after_long_help = "\
+Examples:
+  lyso head reads.fq
+  lyso head -n 100 reads.bam
+  lyso head --bytes 10M reads.fq")]
+    Head {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Records to print [default: 10, or all within --bytes]
+        #[arg(short)]
+        n: Option<usize>,
+        /// Read at most SIZE bytes of records; K, M and G multiply by powers of 1024
+        #[arg(long, value_name = "SIZE", value_parser = byte_size)]
+        bytes: Option<u64>,
+    },
+    /// Print the last records of a FASTA, FASTQ or BAM file
+    ///
+    /// Holds at most N records in memory. With an index (<file>.fai) next to an
+    /// uncompressed FASTA or FASTQ file, seeks straight to the first one instead.
+    #[command(after_long_help = "\
+Examples:
+  lyso tail -n 5 reads.fq
+  lyso faidx reads.fq && lyso tail -n 5 reads.fq    seek using the index")]
+    Tail {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        #[arg(short, default_value_t = 10)]
+        n: usize,
+    },
+    /// Print COUNT records starting at the 0-based record FROM
+    ///
+    /// Uses <file>.fai when present to seek directly to FROM.
+    #[command(after_long_help = "\
+Examples:
+  lyso range --from 1000 --count 10 reads.fq")]
+    Range {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        #[arg(long)]
+        from: usize,
+        #[arg(long)]
+        count: usize,
+    },
+    /// Rewrite FASTA or FASTQ read ids from a template
+    #[command(after_long_help = "\
+Examples:
+  lyso rename --template 'read{n}' reads.fq
+  lyso rename --template '{sample}_{hash8}' --sample S1 --map ids.tsv reads.fq
+  lyso rename --template 'r{n}' --keep-mate-suffix pairs.fa    r1/1, r2/2, ...")]
+    Rename {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Placeholders: {n} (1-based counter), {id}, {sample}, {hash8}
+        #[arg(long)]
+        template: IdTemplate,
+        /// Value for {sample}
+        #[arg(long, default_value = "")]
+        sample: String,
+        /// Keep a trailing /1 or /2 on renamed ids
+        #[arg(long)]
+        keep_mate_suffix: bool,
+        /// Write an old<TAB>new id mapping to this file
+        #[arg(long)]
+        map: Option<PathBuf>,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Find a motif (IUPAC codes allowed) on both strands of FASTA or FASTQ records
+    ///
+    /// Prints the record id, 1-based position, strand and the matched sequence as read
+    /// on that strand.
+    #[command(after_long_help = "\
+Examples:
+  lyso grep --motif GAATTC ref.fa              EcoRI sites
+  lyso grep --motif GGNNCC reads.fq             N matches any base
+  lyso grep --motif AGATCGGAAG --max-mismatches 1 reads.fq.gz")]
+    Grep {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        #[arg(long)]
+        motif: Motif,
+        /// Also report matches with up to this many substituted bases
+        #[arg(long, default_value_t = 0)]
+        max_mismatches: usize,
+    },
+    /// Sort FASTA or FASTQ records by id or sequence length
+    ///
+    /// Records with equal keys keep their input order. Inputs larger than the memory
+    /// limit are sorted in runs spilled to the temporary directory and then merged.
+    #[command(after_long_help = "\
+Examples:
+  lyso sortseq --natural ref.fa                chr2 before chr10
+  lyso sortseq --by length --reverse reads.fq  longest reads first
+  lyso sortseq --memory-limit 64 reads.fq.gz   spill runs past 64 MiB")]
+    Sortseq {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// Sort key: id or length
+        #[arg(long, default_value_t = SortBy::Id)]
+        by: SortBy,
+        /// Largest first
+        #[arg(long)]
+        reverse: bool,
+        /// Compare numbers inside ids by value
+        #[arg(long)]
+        natural: bool,
+        /// Approximate memory for buffered records, in MiB
+        #[arg(long, default_value_t = 512)]
+        memory_limit: usize,
+    },
+    /// Translate FASTA or FASTQ records to protein FASTA
+    ///
+    /// Reverse frames read the reverse complement. A trailing partial codon is
+    /// dropped, and codons with ambiguous bases become X unless every reading gives
+    /// the same amino acid.
+    #[command(after_long_help = "\
+Examples:
+  lyso translate cds.fa
+  lyso translate --frame -2 --to-stop contigs.fa
+  lyso translate --all-frames reads.fq          ids get _frame=1 ... _frame=-3
+  lyso translate --table 11 --init-met genes.fa  bacterial code, GTG/TTG starts as M")]
+    Translate {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// 1, 2, 3, or -1, -2, -3 for the reverse strand
+        #[arg(long, default_value_t = Frame::F1, allow_hyphen_values = true)]
+        frame: Frame,
+        /// Write all six frames, suffixing ids with the frame
+        #[arg(long, conflicts_with = "frame")]
+        all_frames: bool,
+        /// NCBI genetic code: 1-6 or 11
+        #[arg(long, default_value = "1")]
+        table: GeneticCode,
+        /// Stop before the first stop codon
+        #[arg(long)]
+        to_stop: bool,
+        /// Translate an alternative start codon at the start as M
+        #[arg(long)]
+        init_met: bool,
+    },
+    /// Align each read against a short reference, printing its identity and CIGAR
+    ///
+    /// Reads are FASTA or FASTQ. Each is aligned end to end (or with --local, its
+    /// best-matching part) against every record of --ref, and the best-scoring
+    /// reference is reported, with the percentage of alignment columns that match.
+    /// Meant for amplicons and other references of up to a few kb; longer pairs need
+    /// --band, which keeps the alignment near the diagonal.
+    #[command(after_long_help = "\
+Examples:
+  lyso ident --ref amplicon.fa reads.fq
+  lyso ident --ref amplicon.fa --local --band 20 reads.fq.gz")]
+    Ident {
+        #[arg(value_parser = existing_path)]
+        f_path: Option<PathBuf>,
+        /// FASTA of the expected sequences, optionally gzipped
+        #[arg(long = "ref", value_name = "FILE", value_parser = existing_path)]
+        ref_path: PathBuf,
+        /// Align the best-matching part of each read instead of all of it
+        #[arg(long)]
+        local: bool,
+        /// Only consider alignments within N diagonals of the main one
+        #[arg(long, value_name = "N")]
+        band: Option<usize>,
+    },
+    /// Convert FASTQ quality strings between Phred+64 and Phred+33
+    ///
+    /// With --from auto the input encoding is guessed from the quality characters of
+    /// the first --sample reads. When they fit both encodings, requal stops unless
+    /// --force is given, which takes Phred+64 if every character fits it and
+    /// Phred+33 otherwise. Solexa-era scores below 0 become 0; a character the input
+    /// encoding cannot hold is an error naming the record.
+    #[command(after_long_help = "\
+Examples:
+  lyso requal old_illumina.fq > reads.fq
+  lyso requal --from phred64 reads.fq.gz > reads.fq
+  lyso requal --to phred64 reads.fq          for tools that still want Phred+64")]
+    Requal {
+        #[arg(value_parser = existing_path)]
         f_path: Option<PathBuf>,
+        /// Encoding of the input: phred33, phred64 or auto
+        #[arg(long, default_value = "auto")]
+        from: PhredEncoding,
+        /// Encoding to write: phred33 or phred64
+        #[arg(long, default_value = "phred33")]
+        to: PhredEncoding,
+        /// Reads to look at when guessing the input encoding
+        #[arg(long, default_value_t = 10_000)]
+        sample: usize,
+        /// Pick an encoding even when the sampled reads fit both
+        #[arg(long)]
+        force: bool,
     },
-    FaPrint {
+    /// Move barcodes and UMIs from the start of FASTQ reads into their names
+    ///
+    /// --pattern lists the leading bases of each read: a length and B for sample
+    /// barcode, U for UMI or X for bases to drop, as in 8B12U. They are trimmed off
+    /// the sequence and quality and appended to the read name as _BARCODE_UMI, as
+    /// umi_tools does, or with --desc added to the description as BC:Z: and RX:Z:
+    /// fields. --casava starts the barcode with the index field of a Casava 1.8
+    /// description (1:N:0:ATCACG). Given a second mate file, both mates get the
+    /// barcode and UMI of the first followed by those of the second, and go to
+    /// --read1 and --read2. A read shorter than its pattern is an error.
+    #[command(after_long_help = "\
+Examples:
+  lyso extract-umi --pattern 16U reads.fq > tagged.fq
+  lyso extract-umi --pattern 8B12U --desc reads.fq.gz
+  lyso extract-umi --casava --pattern 8U -1 out_R1.fq -2 out_R2.fq r1.fq r2.fq
+  lyso extract-umi --pattern 6U --read2-pattern 6U -1 o1.fq -2 o2.fq r1.fq r2.fq")]
+    ExtractUmi {
+        #[arg(value_parser = existing_path)]
         f_path: Option<PathBuf>,
+        /// Second mates of the reads in the first file
+        #[arg(value_parser = existing_path, requires = "read1")]
+        mate: Option<PathBuf>,
+        /// Segments at the start of single reads and first mates, such as 8B12U
+        #[arg(long, required_unless_present = "casava")]
+        pattern: Option<BarcodePattern>,
+        /// Segments at the start of second mates, which are left whole by default
+        #[arg(long, requires = "mate")]
+        read2_pattern: Option<BarcodePattern>,
+        /// Take the barcode from the Casava index field of the description
+        #[arg(long)]
+        casava: bool,
+        /// Add BC:Z: and RX:Z: fields to the description instead of the name
+        #[arg(long)]
+        desc: bool,
+        /// Write first mates to FILE
+        #[arg(short = '1', long, value_name = "FILE", requires_all = ["read2", "mate"])]
+        read1: Option<PathBuf>,
+        /// Write second mates to FILE
+        #[arg(short = '2', long, value_name = "FILE", requires = "read1")]
+        read2: Option<PathBuf>,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Merge overlapping read pairs into single reads
+    ///
+    /// Reverse-complemented R2 is placed against R1 at the overlap of at least
+    /// --min-overlap bases with the most matching minus mismatching bases and at
+    /// most --max-mismatch-rate mismatches per base. Where the reads overlap,
+    /// agreeing bases get the sum of their qualities, capped at --max-qual, and a
+    /// disagreement is settled by the higher quality, which is lowered by the
+    /// other. A fragment shorter than a read has its adapter trimmed. Merged
+    /// reads keep R1's name. Pairs without an overlap are dropped with a count on
+    /// stderr unless --unmerged1 and --unmerged2 are given.
+    #[command(after_long_help = "\
+Examples:
+  lyso merge-pairs -1 R1.fq -2 R2.fq -o merged.fq --min-overlap 12
+  lyso merge-pairs -1 R1.fq.gz -2 R2.fq.gz --unmerged1 u1.fq --unmerged2 u2.fq > merged.fq")]
+    MergePairs {
+        /// First mates
+        #[arg(short = '1', long, value_name = "FILE", value_parser = existing_path)]
+        read1: PathBuf,
+        /// Second mates, in the same order
+        #[arg(short = '2', long, value_name = "FILE", value_parser = existing_path)]
+        read2: PathBuf,
+        /// Write merged reads to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        /// Write first mates of pairs that did not merge to FILE
+        #[arg(long, value_name = "FILE", requires = "unmerged2")]
+        unmerged1: Option<PathBuf>,
+        /// Write second mates of pairs that did not merge to FILE
+        #[arg(long, value_name = "FILE", requires = "unmerged1")]
+        unmerged2: Option<PathBuf>,
+        /// Fewest overlapping bases to merge on
+        #[arg(long, default_value_t = MergeOptions::default().min_overlap)]
+        min_overlap: usize,
+        /// Most mismatches per overlapping base
+        #[arg(long, default_value_t = MergeOptions::default().max_mismatch_rate, value_parser = fraction)]
+        max_mismatch_rate: f64,
+        /// Highest quality of a base both reads agree on
+        #[arg(long, default_value_t = MergeOptions::default().max_qual)]
+        max_qual: u8,
+        #[command(flatten)]
+        files: OutputArgs,
     },
-    FqPrint {
+    /// Split FASTQ reads into per-sample files by barcode
+    ///
+    /// --barcodes is a sample sheet of name<TAB>barcode lines. Each read's barcode
+    /// is taken from the BC:Z: field or Casava index of its description, or with
+    /// --pattern from the start of the read as extract-umi would take it. A read
+    /// goes to the sample with the closest barcode within --mismatches, provided
+    /// no other barcode is as close; others go to ambiguous and unmatched files.
+    /// Outputs are NAME.fastq, or NAME_R1.fastq and NAME_R2.fastq for pairs, in
+    /// --out-dir, along with summary.tsv of reads per sample. Each file is moved
+    /// into place once every read is written; without --force, none may exist.
+    #[command(after_long_help = "\
+Examples:
+  lyso demux --barcodes samples.tsv --out-dir demuxed reads.fq
+  lyso demux --barcodes samples.tsv --mismatches 1 --out-dir demuxed in_R1.fq in_R2.fq
+  lyso demux --barcodes samples.tsv --pattern 8B --out-dir demuxed reads.fq.gz")]
+    Demux {
+        #[arg(value_parser = existing_path)]
         f_path: Option<PathBuf>,
+        /// Second mates of the reads in the first file
+        #[arg(value_parser = existing_path)]
+        mate: Option<PathBuf>,
+        /// Sample sheet of name<TAB>barcode lines
+        #[arg(long, value_name = "FILE", value_parser = existing_path)]
+        barcodes: PathBuf,
+        /// Most mismatches between a read's barcode and its sample's
+        #[arg(long, default_value_t = 0)]
+        mismatches: usize,
+        /// Take barcodes from the start of first mates, trimming these segments
+        #[arg(long)]
+        pattern: Option<BarcodePattern>,
+        /// Directory for the outputs, created if missing
+        #[arg(long, value_name = "DIR")]
+        out_dir: PathBuf,
+        /// Most output files to keep open at once
+        #[arg(long, default_value_t = 256)]
+        max_open_files: usize,
+        #[command(flatten)]
+        files: OutputArgs,
     },
+    /// Write random FASTQ reads or FASTA sequences, for tests and benchmarks
+    ///
+    /// Reads are named read0, read1, ... Each base is G or C with probability
+    /// --gc, and every read gets the qualities of --quality: one Phred score, or
+    /// START:END for scores falling in a straight line along the read. The same
+    /// --seed always gives the same output.
+    ///
+    /// With --reference, reads are sampled from uniformly chosen positions of the
+    /// sequences of a FASTA file instead, each base substituted with probability
+    /// --subs. Their description names the 1-based source region and the number
+    /// of substitutions, as in chr2:1001-1150 subs=1. With --fasta, the output is
+    /// FASTA wrapped at 60 bases; without --reference, that is --reads sequences
+    /// named seq0, seq1, ..., to sample from later.
+    #[command(after_long_help = "\
+Examples:
+  lyso simulate --reads 10000 -o sim.fq
+  lyso simulate --reads 500 --length 100 --gc 0.42 --quality 38:20 --seed 7 > sim.fq
+  lyso simulate --fasta --reads 3 --length 100000 -o ref.fa
+  lyso simulate --reference ref.fa --subs 0.01 --reads 10000 -o sampled.fq")]
+    Simulate {
+        /// Number of reads
+        #[arg(long, default_value_t = 1000)]
+        reads: usize,
+        /// Bases per read
+        #[arg(long, default_value_t = NonZeroUsize::new(150).unwrap())]
+        length: NonZeroUsize,
+        /// Expected fraction of G and C, 0.0 to 1.0
+        #[arg(long, default_value_t = 0.5, value_parser = fraction)]
+        gc: f64,
+        /// Phred score of every base, or START:END
+        #[arg(long, default_value_t = QualityProfile::Uniform(30))]
+        quality: QualityProfile,
+        /// Seed for the random bases
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// Write FASTA, without qualities
+        #[arg(long, conflicts_with = "quality")]
+        fasta: bool,
+        /// Sample the reads from the sequences of this FASTA file
+        #[arg(long, value_name = "FILE", value_parser = existing_path, conflicts_with = "gc")]
+        reference: Option<PathBuf>,
+        /// Chance that each base of a sampled read is substituted, 0.0 to 1.0
+        #[arg(long, default_value_t = 0.0, value_parser = fraction, requires = "reference")]
+        subs: f64,
+        /// Write here instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        files: OutputArgs,
+    },
+    /// Print a shell completion script
+    #[command(hide = true)]
+    Completions { shell: Shell },
 }
 
 fn main() {
     let cli = Cli::parse();
+    let _discard = outfiles::Discard;
 
     match &cli.command {
-        Some(Commands::Faidx { f_path }) => {
+        Some(Commands::Faidx {
+            f_path,
+            regions,
+            rebuild_if_stale,
+            light,
+            files,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                match light {
+                    true => faidx_light(p, files),
+                    false => faidx(p, regions, *rebuild_if_stale, files),
+                }
+            }
+        }
+        Some(Commands::View {
+            f_path,
+            regions,
+            ids,
+            output_mode,
+            require_flags,
+            exclude_flags,
+            tags,
+            require_tag,
+            require_tag_missing,
+            missing_tag,
+            header_only,
+            seq_edits,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                let mut filter = RecordFilter::new()
+                    .require_flags(require_flags.unwrap_or_default())
+                    .exclude_flags(exclude_flags.unwrap_or_default())
+                    .missing_tag(*missing_tag);
+                for pred in tags {
+                    filter = filter.tag(pred.clone());
+                }
+                for tag in require_tag {
+                    filter = filter.has_tag(tag);
+                }
+                for tag in require_tag_missing {
+                    filter = filter.lacks_tag(tag);
+                }
+                if !regions.is_empty() || !ids.is_empty() {
+                    view_fetch(p, regions, ids, seq_edits.edits());
+                } else if *header_only {
+                    view_header(p);
+                } else {
+                    view(p, *output_mode, &filter, seq_edits.edits());
+                }
+            }
+        }
+        Some(Commands::Stats {
+            f_path,
+            per_cycle,
+            json,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                stats(p, *per_cycle, *json);
+            }
+        }
+        Some(Commands::Inspect {
+            f_path,
+            sample,
+            json,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                inspect(p, *sample, *json);
+            }
+        }
+        Some(Commands::Count { f_path, json }) => {
+            if let Some(p) = f_path.as_deref() {
+                count(p, *json);
+            }
+        }
+        Some(Commands::Check {
+            f_path,
+            max_warnings,
+            max_homopolymer,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                check(p, *max_warnings, *max_homopolymer);
+            }
+        }
+        Some(Commands::Isize { f_path, json }) => {
+            if let Some(p) = f_path.as_deref() {
+                insert_size_bam(p, *json);
+            }
+        }
+        Some(Commands::Bam2fq {
+            f_path,
+            read1,
+            read2,
+            singletons,
+            unpaired,
+            include_secondary,
+            no_suffix,
+            threads,
+            files,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                let mates = read1.as_deref().zip(read2.as_deref());
+                bam2fq(
+                    p,
+                    mates,
+                    (singletons.as_deref(), unpaired.as_deref()),
+                    *include_secondary,
+                    *no_suffix,
+                    threads.pool(),
+                    files,
+                );
+            }
+        }
+        Some(Commands::Fq2bam {
+            f_path,
+            output,
+            rg_id,
+            desc,
+            threads,
+            files,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                fq2bam(p, output, rg_id.as_deref(), *desc, threads.pool(), files);
+            }
+        }
+        Some(Commands::Filter {
+            f_path,
+            min_length,
+            min_mean_qual,
+            window_qual,
+            low_complexity,
+            seq_edits,
+            threads,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                let mut filters: Vec<Box<dyn RecordPredicate + Sync>> = Vec::new();
+                if let Some(n) = min_length {
+                    filters.push(Box::new(MinLength(*n)));
+                }
+                if let Some(q) = min_mean_qual {
+                    filters.push(Box::new(MinMeanQuality(*q)));
+                }
+                if let Some(w) = window_qual {
+                    filters.push(Box::new(*w));
+                }
+                if let Some(t) = low_complexity {
+                    filters.push(Box::new(LowComplexity { threshold: *t }));
+                }
+                filter(p, &filters, seq_edits.edits(), threads.pool());
+            }
+        }
+        Some(Commands::CleanAsm {
+            f_path,
+            min_len,
+            sort_by_length,
+            rename,
+            wrap,
+            map,
+            output,
+            rebuild_if_stale,
+            files,
+        }) => {
+            let mut cleaner = AssemblyCleaner::new()
+                .min_len(*min_len)
+                .sort_by_length(*sort_by_length);
+            if let Some(template) = rename {
+                cleaner = cleaner.rename(Renamer::new(template.clone(), "", false));
+            }
+            let paths = (map.as_deref(), output.as_deref());
+            if let Some(p) = f_path.as_deref() {
+                clean_asm(p, cleaner, *wrap, paths, *rebuild_if_stale, files);
+            }
+        }
+        Some(Commands::Reorder {
+            f_path,
+            by,
+            wrap,
+            output,
+            rebuild_if_stale,
+            files,
+        }) => reorder(
+            f_path,
+            *by,
+            *wrap,
+            output.as_deref(),
+            *rebuild_if_stale,
+            files,
+        ),
+        Some(Commands::Coverage {
+            f_path,
+            targets,
+            json,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                coverage_bam(p, targets.as_deref(), *json);
+            }
+        }
+        Some(Commands::SplitRg {
+            f_path,
+            out_dir,
+            max_open_files,
+            files,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                split_rg(p, out_dir, *max_open_files, files);
+            }
+        }
+        Some(Commands::ValidateRef {
+            bam_path,
+            ref_path,
+            dict,
+            ignore_order,
+            subset_ok,
+        }) => {
+            let options = ValidateOptions {
+                ignore_order: *ignore_order,
+                subset_ok: *subset_ok,
+            };
+            if let (Some(bam), Some(fa)) = (bam_path.as_deref(), ref_path.as_deref()) {
+                validate_ref(bam, fa, dict.as_deref(), options);
+            }
+        }
+        Some(Commands::Diff {
+            left,
+            right,
+            max_shown,
+            unordered,
+            ignore_tags,
+            memory_limit,
+        }) => {
+            let mode = if *unordered {
+                DiffMode::Unordered(memory_limit.saturating_mul(1 << 20))
+            } else {
+                DiffMode::Lockstep
+            };
+            diff_files(left, right, mode, *max_shown, ignore_tags);
+        }
+        Some(Commands::Mismatches { f_path }) => {
+            if let Some(p) = f_path.as_deref() {
+                mismatches(p);
+            }
+        }
+        Some(Commands::Consensus {
+            bam_path,
+            ref_path,
+            output,
+            min_mapq,
+            min_baseq,
+            min_depth,
+            confident_depth,
+            files,
+        }) => {
+            let options = ConsensusOptions {
+                min_mapq: *min_mapq,
+                min_baseq: *min_baseq,
+                min_depth: *min_depth,
+                confident_depth: *confident_depth,
+            };
+            consensus(bam_path, ref_path, output.as_deref(), options, files);
+        }
+        Some(Commands::Getfasta {
+            ref_path,
+            bed_path,
+            output,
+            rebuild_if_stale,
+            files,
+        }) => {
+            getfasta(
+                ref_path,
+                bed_path,
+                output.as_deref(),
+                *rebuild_if_stale,
+                files,
+            );
+        }
+        Some(Commands::Concat {
+            f_path,
+            output,
+            map,
+            spacer,
+            files,
+        }) => {
+            concat(f_path, output.as_deref(), map.as_deref(), *spacer, files);
+        }
+        Some(Commands::Scaffold2contig {
+            ref_path,
+            min_gap,
+            layout,
+            wrap,
+            output,
+            files,
+        }) => {
+            let paths = (layout.as_deref(), output.as_deref());
+            scaffold2contig(ref_path, *min_gap, *wrap, paths, files);
+        }
+        Some(Commands::Maskstats {
+            ref_path,
+            bed,
+            min_run,
+            files,
+        }) => {
+            maskstats(ref_path, bed.as_deref(), *min_run, files);
+        }
+        Some(Commands::Mask {
+            ref_path,
+            bed_path,
+            hard,
+            output,
+            files,
+        }) => {
+            let mode = if *hard {
+                MaskMode::Hard
+            } else {
+                MaskMode::Soft
+            };
+            mask(ref_path, bed_path, mode, output.as_deref(), files);
+        }
+        Some(Commands::Windows {
+            ref_path,
+            size,
+            step,
+            gc,
+            skip_partial,
+        }) => {
+            let step = step.unwrap_or(*size).get();
+            windows(ref_path, size.get(), step, *gc, !*skip_partial);
+        }
+        Some(Commands::Head { f_path, n, bytes }) => {
+            if let Some(p) = f_path.as_deref() {
+                let n = n.unwrap_or(if bytes.is_some() { usize::MAX } else { 10 });
+                slice(p, Slice::Head(n, *bytes));
+            }
+        }
+        Some(Commands::Tail { f_path, n }) => {
+            if let Some(p) = f_path.as_deref() {
+                slice(p, Slice::Tail(*n));
+            }
+        }
+        Some(Commands::Range {
+            f_path,
+            from,
+            count,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                slice(p, Slice::Range(*from, *count));
+            }
+        }
+        Some(Commands::Rename {
+            f_path,
+            template,
+            sample,
+            keep_mate_suffix,
+            map,
+            files,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                let renamer = Renamer::new(template.clone(), sample, *keep_mate_suffix);
+                rename(p, renamer, map.as_deref(), files);
+            }
+        }
+        Some(Commands::Grep {
+            f_path,
+            motif,
+            max_mismatches,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                match motif.clone().with_max_mismatches(*max_mismatches) {
+                    Ok(motif) => grep(p, &motif),
+                    Err(e) => {
+                        eprintln!("{e}");
+                        exit(1);
+                    }
+                }
+            }
+        }
+        Some(Commands::Sortseq {
+            f_path,
+            by,
+            reverse,
+            natural,
+            memory_limit,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                let key = SortKey {
+                    by: *by,
+                    natural: *natural,
+                    reverse: *reverse,
+                };
+                sortseq(p, key, memory_limit.saturating_mul(1 << 20));
+            }
+        }
+        Some(Commands::Translate {
+            f_path,
+            frame,
+            all_frames,
+            table,
+            to_stop,
+            init_met,
+        }) => {
             if let Some(p) = f_path.as_deref() {
-                unimplemented!();
+                let translator = Translator::new(table.clone())
+                    .to_stop(*to_stop)
+                    .init_met(*init_met);
+                let frames: &[Frame] = if *all_frames {
+                    &Frame::ALL
+                } else {
+                    std::slice::from_ref(frame)
+                };
+                translate(p, &translator, frames);
             }
         }
-        Some(Commands::View { f_path }) => {
+        Some(Commands::Ident {
+            f_path,
+            ref_path,
+            local,
+            band,
+        }) => {
             if let Some(p) = f_path.as_deref() {
-                view_bam(p);
+                let params = AlignParams {
+                    band: *band,
+                    ..AlignParams::default()
+                };
+                ident(p, ref_path, &params, *local);
             }
         }
-        Some(Commands::FaPrint { f_path }) => {
+        Some(Commands::Requal {
+            f_path,
+            from,
+            to,
+            sample,
+            force,
+        }) => {
             if let Some(p) = f_path.as_deref() {
-                test_read_fasta(p);
+                requal(p, *from, *to, *sample, *force);
+            }
+        }
+        Some(Commands::ExtractUmi {
+            f_path,
+            mate,
+            pattern,
+            read2_pattern,
+            casava,
+            desc,
+            read1,
+            read2,
+            files,
+        }) => {
+            if let Some(p) = f_path.as_deref() {
+                let extractor = Extractor::new(pattern.clone().unwrap_or_default())
+                    .read2_pattern(read2_pattern.clone().unwrap_or_default())
+                    .casava_barcode(*casava)
+                    .placement(if *desc {
+                        Placement::Desc
+                    } else {
+                        Placement::Id
+                    });
+                match (mate.as_deref(), read1.as_deref(), read2.as_deref()) {
+                    (Some(mate), Some(out1), Some(out2)) => {
+                        extract_umi_pairs([p, mate], [out1, out2], &extractor, files)
+                    }
+                    _ => extract_umi(p, &extractor),
+                }
             }
         }
-        Some(Commands::FqPrint { f_path }) => {
+        Some(Commands::MergePairs {
+            read1,
+            read2,
+            output,
+            unmerged1,
+            unmerged2,
+            min_overlap,
+            max_mismatch_rate,
+            max_qual,
+            files,
+        }) => {
+            let opts = MergeOptions {
+                min_overlap: *min_overlap,
+                max_mismatch_rate: *max_mismatch_rate,
+                max_qual: *max_qual,
+            };
+            let unmerged = unmerged1.as_deref().zip(unmerged2.as_deref());
+            merge_pairs([read1, read2], output.as_deref(), unmerged, &opts, files);
+        }
+        Some(Commands::Demux {
+            f_path,
+            mate,
+            barcodes,
+            mismatches,
+            pattern,
+            out_dir,
+            max_open_files,
+            files,
+        }) => {
             if let Some(p) = f_path.as_deref() {
-                test_read_fastq(p);
+                let fail = |e: &dyn Display| -> ! {
+                    eprintln!("{}: {e}", barcodes.display());
+                    exit(1);
+                };
+                let sheet = File::open(barcodes).unwrap_or_else(|e| fail(&e));
+                let samples = read_sample_sheet(BufReader::new(sheet)).unwrap_or_else(|e| fail(&e));
+                let demux = Demultiplexer::new(samples, *mismatches).unwrap_or_else(|e| fail(&e));
+                let extractor = pattern.clone().map(Extractor::new);
+                demux_reads(
+                    [p].into_iter().chain(mate.as_deref()).collect(),
+                    &demux,
+                    extractor.as_ref(),
+                    out_dir,
+                    *max_open_files,
+                    files,
+                );
             }
         }
+        Some(Commands::Simulate {
+            reads,
+            length,
+            gc,
+            quality,
+            seed,
+            fasta,
+            reference,
+            subs,
+            output,
+            files,
+        }) => {
+            let fail = |e: &dyn Display| -> ! {
+                eprintln!("{e}");
+                exit(1);
+            };
+            let records: Box<dyn Iterator<Item = SimRecord>> = match reference {
+                Some(p) => {
+                    let reference = sim_reference(p);
+                    let mut sim = FastaSimulator::new(*seed)
+                        .read_length(length.get())
+                        .substitution_rate(*subs)
+                        .unwrap_or_else(|e| fail(&e));
+                    Box::new(sim.sample_reads(&reference, *reads).into_iter())
+                }
+                None if *fasta => {
+                    let mut sim = FastaSimulator::new(*seed)
+                        .seq_length(length.get())
+                        .gc_content(*gc)
+                        .unwrap_or_else(|e| fail(&e));
+                    Box::new(sim.generate(*reads).into_iter())
+                }
+                None => Box::new(
+                    FastqSimulator::new(*seed)
+                        .read_length(length.get())
+                        .gc_content(*gc)
+                        .unwrap_or_else(|e| fail(&e))
+                        .quality_profile(*quality)
+                        .generate(*reads),
+                ),
+            };
+            let quality = (!*fasta).then_some(*quality);
+            simulate(records, quality, output.as_deref(), files);
+        }
+        Some(Commands::Completions { shell }) => {
+            // buffered so a closed pipe is handled like any other output
+            let mut script = Vec::new();
+            args::write_completions(*shell, &mut script);
+            let script = String::from_utf8_lossy(&script);
+            write_or_exit(&mut stdout().lock(), format_args!("{script}"));
+        }
         None => {}
     }
+    if let Err(e) = outfiles::commit() {
+        eprintln!("{e}");
+        exit(1);
+    }
+
+    fn stats<P: AsRef<Path>>(fpath: P, per_cycle: bool, json: bool) {
+        let in_file = File::open(&fpath).expect("unable to open file.");
+        match input::open_input(in_file) {
+            Ok((Format::Fastq, reader)) => stats_fastq(reader, per_cycle, json),
+            Ok((Format::Bam, _)) if per_cycle => {
+                eprintln!("--per-cycle needs FASTQ input, found BAM");
+                exit(1);
+            }
+            Ok((Format::Bam, reader)) => stats_bam(reader, json),
+            Ok((format, _)) => {
+                eprintln!("stats expects FASTQ or BAM input, found {format}");
+                exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.as_ref().display());
+                exit(1);
+            }
+        }
+    }
+
+    fn stats_fastq(reader: Box<dyn BufRead>, per_cycle: bool, json: bool) {
+        let mut profile = CycleProfile::new();
+        for rec in fastq::Reader::new(reader) {
+            if let Err(e) = rec.and_then(|r| profile.add(&r)) {
+                eprintln!("{e}");
+                exit(1);
+            }
+        }
+        if per_cycle {
+            let report = FormatReport {
+                format: Format::Fastq,
+                report: PerCycle {
+                    cycles: profile.cycles(),
+                },
+            };
+            emit_or_exit(json, &report, |r| {
+                let stdout = stdout();
+                let mut handle = stdout.lock();
+                write_or_exit(&mut handle, format_args!("{}\n", CycleStats::TSV_HEADER));
+                for c in &r.report.cycles {
+                    write_or_exit(&mut handle, format_args!("{c}\n"));
+                }
+            });
+        } else {
+            let report = FormatReport {
+                format: Format::Fastq,
+                report: profile.summary(),
+            };
+            emit_or_exit(json, &report, |r| {
+                println!("reads\t{}", r.report.reads);
+                println!("bases\t{}", r.report.bases);
+                println!("max_len\t{}", r.report.max_len);
+            });
+        }
+    }
+
+    fn stats_bam(reader: Box<dyn BufRead>, json: bool) {
+        let mut acc = BamStats::new();
+        for rec in bam::Reader::new(reader) {
+            match rec {
+                Ok(r) => acc.update(&r),
+                Err(e) => {
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            }
+        }
+        let report = FormatReport {
+            format: Format::Bam,
+            report: acc.finish(),
+        };
+        emit_or_exit(json, &report, |r| {
+            let s = &r.report;
+            let opt = |v: Option<f64>| v.map_or(String::from("NA"), |v| format!("{v:.4}"));
+            println!("reads\t{}", s.total);
+            println!("mapped\t{}", s.mapped);
+            println!("unmapped\t{}", s.unmapped);
+            println!("secondary\t{}", s.secondary);
+            println!("supplementary\t{}", s.supplementary);
+            println!("duplicates\t{}", s.duplicates);
+            println!("mapping_rate\t{}", opt(s.mapping_rate));
+            println!("duplicate_rate\t{}", opt(s.duplicate_rate));
+            println!("mean_length\t{}", opt(s.mean_length));
+            println!("soft_clipped_pct\t{}", opt(s.soft_clipped_pct));
+            println!("mismatch_rate\t{}", opt(s.mismatch_rate));
+            for (i, n) in s.mapq_hist.iter().enumerate() {
+                println!("mapq_{}\t{n}", mapq_bin_label(i));
+            }
+        });
+    }
+
+    fn insert_size_bam<P: AsRef<Path>>(fpath: P, json: bool) {
+        let reader = open_bam_or_exit(fpath);
+        let pairs = PairIter::new(reader);
+        match insert_size_summary(pairs) {
+            Ok(s) => {
+                let report = FormatReport {
+                    format: Format::Bam,
+                    report: s,
+                };
+                emit_or_exit(json, &report, |r| {
+                    let s = &r.report;
+                    println!("pairs\t{}", s.count);
+                    println!("mean\t{:.2}", s.mean);
+                    println!("median\t{:.1}", s.median);
+                    println!("stddev\t{:.2}", s.stddev);
+                });
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        }
+    }
+
+    fn bam2fq(
+        fpath: &Path,
+        mates: Option<(&Path, &Path)>,
+        (singletons, unpaired): (Option<&Path>, Option<&Path>),
+        include_secondary: bool,
+        no_suffix: bool,
+        pool: WorkerPool,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let create = |p: &Path| -> Box<dyn Write> {
+            let bgzf = p.extension().is_some_and(|ext| ext == "gz" || ext == "bgz");
+            let f = create_or_exit(p, files);
+            match bgzf {
+                true => Box::new(bam::BgzfWriter::with_pool(f, pool)),
+                false => Box::new(std::io::BufWriter::new(f)),
+            }
+        };
+        let write = |out: &mut dyn Write, rec: &bam::Record, suffix: bool| {
+            let read = to_fastq(rec, suffix).unwrap_or_else(|e| fail(&e));
+            if let Err(e) = read.write_to(&mut &mut *out) {
+                if e.kind() == std::io::ErrorKind::BrokenPipe {
+                    exit(141);
+                }
+                fail(&e);
+            }
+        };
+        let reader = open_bam_or_exit(fpath);
+        let (records, secondary): (Box<dyn Iterator<Item = _>>, _) = if include_secondary {
+            (Box::new(SegmentDedup::new(reader)), SideChannel::Yield)
+        } else {
+            (Box::new(reader), SideChannel::Skip)
+        };
+        let pairs = PairIter::new(records)
+            .secondary(secondary)
+            .evict_oldest(true);
+
+        // interleaved output marks mates by name
+        let suffix = mates.is_none() && !no_suffix;
+        let (mut out1, mut out2) = match mates {
+            Some((p1, p2)) => (create(p1), Some(create(p2))),
+            None => (
+                Box::new(std::io::BufWriter::new(stdout().lock())) as Box<dyn Write>,
+                None,
+            ),
+        };
+        let mut singles_out = singletons.map(create);
+        // with mate files, unpaired reads without --unpaired still go to stdout
+        let mut unpaired_out = match (unpaired, mates) {
+            (Some(p), _) => Some(create(p)),
+            (None, Some(_)) => Some(Box::new(std::io::BufWriter::new(stdout().lock())) as _),
+            (None, None) => None,
+        };
+        for pair in pairs {
+            match pair.unwrap_or_else(|e| fail(&e)) {
+                Pair::Paired(r1, r2) => {
+                    write(&mut out1, &r1, suffix);
+                    write(out2.as_mut().unwrap_or(&mut out1), &r2, suffix);
+                }
+                Pair::Single(rec) if rec.is_read1() != rec.is_read2() => {
+                    let out = match (&mut singles_out, &mut out2) {
+                        (Some(out), _) => out,
+                        (None, Some(out)) if rec.is_read2() => out,
+                        (None, _) => &mut out1,
+                    };
+                    write(out, &rec, suffix);
+                }
+                Pair::Single(rec) => {
+                    write(unpaired_out.as_mut().unwrap_or(&mut out1), &rec, suffix);
+                }
+            }
+        }
+        for out in [Some(out1), out2, singles_out, unpaired_out]
+            .iter_mut()
+            .flatten()
+        {
+            if let Err(e) = out.flush() {
+                if e.kind() == std::io::ErrorKind::BrokenPipe {
+                    exit(141);
+                }
+                fail(&e);
+            }
+        }
+    }
+
+    fn fq2bam(
+        fpath: &Path,
+        out_path: &Path,
+        rg_id: Option<&str>,
+        desc: DescPolicy,
+        pool: WorkerPool,
+        files: &OutputArgs,
+    ) {
+        let fail = |path: &Path, e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        };
+        let tags = match rg_id {
+            Some(id) => vec![bam::AuxField::from_sam_str(&format!("RG:Z:{id}"))
+                .unwrap_or_else(|e| fail(fpath, &format_args!("--rg-id: {e}")))],
+            None => Vec::new(),
+        };
+        let f = File::open(fpath).unwrap_or_else(|e| fail(fpath, &e));
+        let reader = match input::open_input(f).unwrap_or_else(|e| fail(fpath, &e)) {
+            (Format::Fastq, reader) => fastq::Reader::new(reader),
+            (format, _) => fail(fpath, &format_args!("expected FASTQ input, found {format}")),
+        };
+        let out = create_or_exit(out_path, files);
+        let mut writer = bam::Writer::new(bam::BgzfWriter::with_pool(out, pool));
+        writer
+            .write_header(&fq2bam::header(rg_id), &[])
+            .unwrap_or_else(|e| fail(out_path, &e));
+        for rec in reader {
+            let rec = rec.unwrap_or_else(|e| fail(fpath, &e));
+            let rec = to_bam(&rec, desc, &tags).unwrap_or_else(|e| fail(fpath, &e));
+            writer
+                .write_record(&rec)
+                .unwrap_or_else(|e| fail(out_path, &e));
+        }
+        writer
+            .into_inner()
+            .finish()
+            .unwrap_or_else(|e| fail(out_path, &e));
+    }
+
+    fn inspect(fpath: &Path, limit: usize, json: bool) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let open = || File::open(fpath).unwrap_or_else(|e| fail(&e));
+        let size = open().metadata().unwrap_or_else(|e| fail(&e)).len();
+        let compression =
+            detect_compression(&mut BufReader::new(open())).unwrap_or_else(|e| fail(&e));
+        let (counted, raw_bytes) = input::Counted::new(open());
+        let (format, reader) = input::open_input(counted).unwrap_or_else(|e| fail(&e));
+        let mut report = InspectReport {
+            file: fpath.to_path_buf(),
+            format,
+            compression,
+            records: None,
+            lengths: None,
+            quality_encoding: None,
+            header: None,
+            index: None,
+            error: None,
+        };
+
+        let (sample, header) = match format {
+            Format::Fastq => (sample_fastq(&mut fastq::Reader::new(reader), limit), None),
+            Format::Fasta => (sample_fasta(&mut fasta::Reader::new(reader), limit), None),
+            Format::Bam => {
+                // the header is complete before any record is sampled
+                let mut reader = bam::Reader::header_only(reader)
+                    .and_then(|(_, refs)| refs.into_reader())
+                    .unwrap_or_else(|e| fail(&e));
+                let header = bam_header(&reader);
+                (sample_bam(&mut reader, limit), header)
+            }
+            Format::Sam => {
+                return emit_or_exit(json, &report, print_inspect);
+            }
+        };
+        // estimate the decompressed size of all records from how far the sample got
+        let header_bytes = header.as_ref().map_or(0, |h| h.header_bytes);
+        let data_bytes = match compression {
+            Compression::None => size.saturating_sub(header_bytes),
+            Compression::Gzip | Compression::Bgzf => {
+                let ratio =
+                    (header_bytes + sample.record_bytes) as f64 / raw_bytes.get().max(1) as f64;
+                ((size as f64 * ratio) as u64).saturating_sub(header_bytes)
+            }
+        };
+        report.records = Some(sample.count(data_bytes));
+        report.index = probe_index(fpath, format, header.as_ref().map(|h| h.references.len()));
+        report.lengths = sample.lengths;
+        report.quality_encoding = sample.quality;
+        report.header = header;
+        report.error = sample.error;
+        emit_or_exit(json, &report, print_inspect);
+        if report.error.is_some() {
+            exit(1);
+        }
+    }
 
-    fn test_read_fasta<P: AsRef<Path>>(fpath: P) {
-        let mut in_file = File::open(&fpath).expect("unable to open file.");
-        let mut buf_in = std::io::BufReader::new(&mut in_file);
+    fn print_inspect(report: &InspectReport) {
         let stdout = stdout();
-        let mut handle = stdout.lock();
-        let fa_reader = FastaReader::new(&mut buf_in);
-        let now = Instant::now();
-        let reads = fa_reader.collect::<Vec<Result<lyso_fasta::Record, FastaError>>>();
-        eprintln!("Read {} records in {:?}", reads.len(), now.elapsed());
-        // for rec in fa_reader {
-        //     if let Err(e) = writeln!(handle, "{}", rec.unwrap()) {
-        //         match e.kind() {
-        //             std::io::ErrorKind::BrokenPipe => exit(141),
-        //             _ => panic!("{e}"),
-        //         }
-        //     }
-        // }
-    }
-
-    fn test_read_fastq<P: AsRef<Path>>(fpath: P) {
-        let mut in_file = File::open(&fpath).expect("unable to open file.");
-        let mut buf_in = std::io::BufReader::new(&mut in_file);
+        let mut out = stdout.lock();
+        write_or_exit(&mut out, format_args!("file\t{}\n", report.file.display()));
+        write_or_exit(&mut out, format_args!("format\t{}\n", report.format));
+        write_or_exit(
+            &mut out,
+            format_args!("compression\t{}\n", report.compression),
+        );
+        let Some(records) = report.records else {
+            write_or_exit(&mut out, format_args!("records\tNA\n"));
+            return;
+        };
+        write_or_exit(&mut out, format_args!("records\t{records}\n"));
+        if let Some(l) = report.lengths {
+            write_or_exit(&mut out, format_args!("length_min\t{}\n", l.min));
+            write_or_exit(&mut out, format_args!("length_median\t{}\n", l.median));
+            write_or_exit(&mut out, format_args!("length_mean\t{:.1}\n", l.mean));
+            write_or_exit(&mut out, format_args!("length_max\t{}\n", l.max));
+        }
+        if let Some(q) = report.quality_encoding {
+            write_or_exit(&mut out, format_args!("quality_encoding\t{q}\n"));
+        }
+        if let Some(h) = &report.header {
+            let order = h.sort_order.as_deref().unwrap_or("unknown");
+            write_or_exit(&mut out, format_args!("sort_order\t{order}\n"));
+            write_or_exit(
+                &mut out,
+                format_args!("references\t{}\n", h.references.len()),
+            );
+            for (name, len) in &h.references {
+                write_or_exit(&mut out, format_args!("reference\t{name}\t{len}\n"));
+            }
+        }
+        if let Some(idx) = &report.index {
+            write_or_exit(
+                &mut out,
+                format_args!("index\t{}\t{}\n", idx.path.display(), idx.state),
+            );
+        }
+        if let Some(e) = &report.error {
+            write_or_exit(&mut out, format_args!("error\t{e}\n"));
+        }
+    }
+
+    fn check(fpath: &Path, max_warnings: u64, max_homopolymer: Option<usize>) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let in_file = File::open(fpath).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(in_file).unwrap_or_else(|e| fail(&e));
+        let report = match format {
+            Format::Fastq => {
+                let validator = with_homopolymer(fastq::validator(), max_homopolymer);
+                check_records(fastq::Reader::new(reader), &validator).unwrap_or_else(|e| fail(&e))
+            }
+            Format::Fasta => {
+                let validator = with_homopolymer(fasta::validator(), max_homopolymer);
+                check_records(fasta::Reader::new(reader), &validator).unwrap_or_else(|e| fail(&e))
+            }
+            Format::Bam => {
+                drop(reader);
+                // unknown references are reported as warnings below, not read errors
+                let mut reader = open_bam_or_exit(fpath).lenient_ref_ids(true);
+                // the references are read along with the first record
+                reader.peek_record();
+                let references = Some(reader.references().into());
+                let validator =
+                    with_homopolymer(bam::check::validator(references), max_homopolymer);
+                check_records(reader, &validator).unwrap_or_else(|e| fail(&e))
+            }
+            Format::Sam => fail(&"check reads FASTA, FASTQ or BAM; convert SAM to BAM first"),
+        };
+        report
+            .write_tsv(&mut stdout().lock())
+            .unwrap_or_else(|e| fail(&e));
+        let errors = report.total(Severity::Error);
+        let warnings = report.total(Severity::Warning);
+        if errors > 0 {
+            fail(&format_args!("{errors} violations of error-level rules"));
+        }
+        if warnings > max_warnings {
+            fail(&format_args!(
+                "{warnings} warnings, more than --max-warnings {max_warnings}"
+            ));
+        }
+    }
+
+    fn with_homopolymer<R>(validator: Validator<R>, max: Option<usize>) -> Validator<R>
+    where
+        R: SeqRecord + RecordId,
+    {
+        match max {
+            Some(n) => validator.rule(MaxHomopolymer(n), Severity::Warning),
+            None => validator,
+        }
+    }
+
+    /// Every violation of `validator` in `records`, stopping at the first read error
+    fn check_records<R: RecordId, E>(
+        records: impl Iterator<Item = Result<R, E>>,
+        validator: &Validator<R>,
+    ) -> Result<ValidationReport, E> {
+        let mut report = ValidationReport::new(validator);
+        for rec in records {
+            report.add(&validator.check(&rec?));
+        }
+        Ok(report)
+    }
+
+    fn coverage_bam<P: AsRef<Path>>(fpath: P, targets: Option<&Path>, json: bool) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let intervals = targets.map(|p| {
+            let bed = File::open(p).unwrap_or_else(|e| fail(&e));
+            IntervalSet::from_bed(BufReader::new(bed), false)
+                .unwrap_or_else(|e| fail(&format_args!("{}: {e}", p.display())))
+        });
+        let mut reader = open_bam_or_exit(fpath);
+        // coverage needs only flags, position and CIGAR, so skip decoding the rest;
+        // references are loaded along with the first record
+        let first = reader.lazy_records().next();
+        let mut builder = CoverageBuilder::new(reader.references());
+        if let Some(intervals) = &intervals {
+            builder = builder.with_targets(intervals);
+        }
+        for rec in first.into_iter().chain(reader.lazy_records()) {
+            if let Err(e) = rec.and_then(|r| builder.update(&r)) {
+                fail(&e);
+            }
+        }
+        let (references, target_covs) = builder.finish_with_targets();
+        let names = reader
+            .references()
+            .iter()
+            .map(|r| r.name())
+            .collect::<HashSet<_>>();
+        let missing = target_covs
+            .iter()
+            .filter(|t| !names.contains(t.interval.name.as_str()))
+            .count();
+        if missing > 0 {
+            eprintln!(
+                "warning: {missing} target(s) on sequences missing from the BAM header, \
+                 reported at depth 0"
+            );
+        }
+        let report = FormatReport {
+            format: Format::Bam,
+            report: output::Coverage {
+                references: references.iter().map(Into::into).collect(),
+                targets: intervals
+                    .is_some()
+                    .then(|| target_covs.iter().map(Into::into).collect()),
+            },
+        };
+        emit_or_exit(json, &report, |_| {
+            let stdout = stdout();
+            let mut handle = stdout.lock();
+            if intervals.is_some() {
+                write_or_exit(
+                    &mut handle,
+                    format_args!("{}\n", TargetCoverage::TSV_HEADER),
+                );
+                for cov in &target_covs {
+                    write_or_exit(&mut handle, format_args!("{cov}\n"));
+                }
+                return;
+            }
+            write_or_exit(&mut handle, format_args!("{}\n", RefCoverage::TSV_HEADER));
+            for cov in &references {
+                write_or_exit(&mut handle, format_args!("{cov}\n"));
+            }
+        });
+    }
+
+    fn split_rg(fpath: &Path, dir: &Path, max_open: usize, files: &OutputArgs) {
+        let fail = |path: &Path, e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        };
+        let mut reader = open_bam_or_exit(fpath);
+        // the header and references are read along with the first record
+        let first = reader
+            .next()
+            .transpose()
+            .unwrap_or_else(|e| fail(fpath, &e));
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| fail(dir, &e));
+        let header = reader.header().expect("read along with the first record");
+        let splitter = RgSplitter::new(header);
+        let mut writers = SplitWriters::new(splitter, reader.references().to_vec(), dir, max_open)
+            .overwrite(files.force);
+        for rec in first.map(Ok).into_iter().chain(reader) {
+            let rec = rec.unwrap_or_else(|e| fail(fpath, &e));
+            writers.write(&rec).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                exit(1);
+            });
+        }
+        let outputs = writers.finish().unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+
         let stdout = stdout();
         let mut handle = stdout.lock();
-        let fa_reader = FastqReader::new(&mut buf_in);
-        let now = Instant::now();
-        let reads = fa_reader.collect::<Vec<Result<Record, FastqError>>>();
-        eprintln!("Read {} records in {:?}", reads.len(), now.elapsed());
-        // for rec in fa_reader {
-        //     if let Err(e) = writeln!(handle, "{}", rec.unwrap()) {
-        //         match e.kind() {
-        //             std::io::ErrorKind::BrokenPipe => exit(141),
-        //             _ => panic!("{e}"),
-        //         }
-        //     }
-        // }
-    }
-
-    // fn index_fastq<P: AsRef<Path>>(fpath: P) {
-    //     let mut in_file = File::open(&fpath).expect("unable to open file.");
-    //     let mut buf_in = std::io::BufReader::new(&mut in_file);
-    //     let fq_idxr = FastqIndexer::new(&mut buf_in);
-    //     let out_f = File::create(&"test.fai").unwrap();
-    //     let mut buf_out = std::io::BufWriter::new(out_f);
-    //     let mut idx_str;
-    //     for idx in fq_idxr {
-    //         idx_str = format!("{}\n", idx.unwrap());
-    //         buf_out.write(idx_str.as_bytes()).unwrap();
-    //     }
-    //     buf_out.flush().unwrap();
-    // }
-
-    fn view_bam<P: AsRef<Path>>(fpath: P) {
-        let in_file = File::open(&fpath).expect("unable to open file.");
-        let gunzip_in = bgzip::read::BGZFReader::new(in_file).unwrap();
+        write_or_exit(&mut handle, format_args!("read_group\tfile\trecords\n"));
+        for output in outputs {
+            let file = output
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            write_or_exit(
+                &mut handle,
+                format_args!(
+                    "{}\t{file}\t{}\n",
+                    output.read_group.as_deref().unwrap_or("*"),
+                    output.records
+                ),
+            );
+        }
+    }
+
+    fn validate_ref(
+        bam_path: &Path,
+        ref_path: &Path,
+        dict: Option<&Path>,
+        options: ValidateOptions,
+    ) {
+        let fail = |path: &Path, e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        };
+        let mut reader = open_bam_or_exit(bam_path);
+        // the header and references are read along with the first record
+        if let Some(Err(e)) = reader.next() {
+            fail(bam_path, &e);
+        }
+        let header = reader.header().expect("read along with the first record");
+        let fai = index_meta::fai_path(ref_path);
+        let found = match input::open_index(ref_path, Format::Fasta) {
+            Ok(Some(RecordIndex::Fasta(idx))) => Some(idx),
+            Ok(Some(_)) => unreachable!("opened as FASTA"),
+            Ok(None) => None,
+            Err(StaleIndex::Io(e)) => fail(&fai, &e),
+            // a stale index is not used
+            Err(e) => {
+                eprintln!("warning: {}: {e}; indexing in memory", fai.display());
+                None
+            }
+        };
+        let index = found.unwrap_or_else(|| match RecordIndex::build(ref_path, Format::Fasta) {
+            Ok(RecordIndex::Fasta(idx)) => idx,
+            Ok(_) => unreachable!("built as FASTA"),
+            Err(e) => fail(ref_path, &e),
+        });
+
+        let mut mismatches = check_reference(reader.references(), &index);
+        let default_dict = dict_path(ref_path);
+        let dict = dict.or(default_dict.exists().then_some(default_dict.as_path()));
+        if let Some(dict) = dict {
+            let bam_dict = SequenceDictionary::from_header_text(&header.text_lossy())
+                .unwrap_or_else(|e| fail(bam_path, &e));
+            let ref_dict = SequenceDictionary::from_path(dict).unwrap_or_else(|e| fail(dict, &e));
+            mismatches.extend(check_md5(&bam_dict, &ref_dict));
+        }
 
-        // automatically consume header and refs
-        let bam_reader = BamReader::new(gunzip_in);
+        let count = |n: usize, noun: &str| match n {
+            1 => format!("1 {noun}"),
+            n => format!("{n} {noun}s"),
+        };
         let stdout = stdout();
         let mut handle = stdout.lock();
-        //read alignments
-        for rec in bam_reader.into_iter() {
-            if let Err(e) = writeln!(handle, "{}", rec.unwrap()) {
-                match e.kind() {
-                    std::io::ErrorKind::BrokenPipe => exit(141),
-                    _ => panic!("{e}"),
-                }
+        write_or_exit(
+            &mut handle,
+            format_args!(
+                "{} in the BAM header, {} in the FASTA\n",
+                count(reader.references().len(), "reference"),
+                count(index.len(), "sequence")
+            ),
+        );
+        let mut failed = 0;
+        for m in &mismatches {
+            let status = if m.is_allowed(&options) {
+                "allowed"
+            } else {
+                failed += 1;
+                "mismatch"
+            };
+            write_or_exit(&mut handle, format_args!("{status}: {m}\n"));
+        }
+        if failed == 0 {
+            write_or_exit(&mut handle, format_args!("OK\n"));
+        } else {
+            write_or_exit(&mut handle, format_args!("FAILED: {failed} not allowed\n"));
+            exit(1);
+        }
+    }
+
+    enum DiffMode {
+        Lockstep,
+        /// With the memory limit in bytes
+        Unordered(usize),
+    }
+
+    /// Exits 1 when the files differ, 2 on an error, as diff(1) does
+    fn diff_files(
+        left: &Path,
+        right: &Path,
+        mode: DiffMode,
+        max_shown: usize,
+        ignore_tags: &[String],
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(2);
+        };
+        let open = |path: &Path| {
+            let f =
+                File::open(path).unwrap_or_else(|e| fail(&format_args!("{}: {e}", path.display())));
+            input::open_input(f).unwrap_or_else(|e| fail(&format_args!("{}: {e}", path.display())))
+        };
+        let format = open(left).0;
+        let right_format = open(right).0;
+        if format != right_format {
+            fail(&format_args!("cannot compare {format} with {right_format}"));
+        }
+        if format != Format::Bam && !ignore_tags.is_empty() {
+            fail(&format_args!(
+                "--ignore-tags needs BAM input, found {format}"
+            ));
+        }
+        // errors name the file they come from
+        let named = |path: &Path, e: &dyn Display| -> Box<dyn std::error::Error> {
+            format!("{}: {e}", path.display()).into()
+        };
+        let equal = match format {
+            Format::Fasta => diff_records(left, right, mode, max_shown, |p| {
+                fasta::Reader::new(open(p).1).map(move |r| r.map_err(|e| named(p, &e)))
+            }),
+            Format::Fastq => diff_records(left, right, mode, max_shown, |p| {
+                fastq::Reader::new(open(p).1).map(move |r| r.map_err(|e| named(p, &e)))
+            }),
+            Format::Bam => diff_records(left, right, mode, max_shown, |p| {
+                bam::Reader::new(open(p).1).map(move |r| {
+                    let mut r = r.map_err(|e| named(p, &e))?;
+                    for tag in ignore_tags {
+                        r.remove_aux(tag);
+                    }
+                    Ok(r)
+                })
+            }),
+            Format::Sam => fail(&"SAM input is not supported, convert it to BAM first"),
+        };
+        match equal {
+            Ok(true) => {}
+            Ok(false) => exit(1),
+            Err(e) => fail(&e),
+        }
+    }
+
+    /// Report the differences between the records `open` reads from `left` and
+    /// `right`, returning whether there were none
+    fn diff_records<'a, T, I>(
+        left: &'a Path,
+        right: &'a Path,
+        mode: DiffMode,
+        max_shown: usize,
+        open: impl Fn(&'a Path) -> I,
+    ) -> Result<bool, Box<dyn std::error::Error>>
+    where
+        T: RecordDiff + CanonicalHash,
+        I: Iterator<Item = Result<T, Box<dyn std::error::Error>>>,
+    {
+        let stdout = stdout();
+        let mut handle = std::io::BufWriter::new(stdout.lock());
+        let (l, r) = (left.display(), right.display());
+        let equal = match mode {
+            DiffMode::Lockstep => {
+                let d = diff_lockstep(open(left), open(right), max_shown)?;
+                for m in &d.shown {
+                    write_or_exit(
+                        &mut handle,
+                        format_args!("record {} ({})\n", m.index + 1, m.label),
+                    );
+                    for diff in &m.diffs {
+                        write_or_exit(&mut handle, format_args!("  {diff}\n"));
+                    }
+                }
+                write_or_exit(
+                    &mut handle,
+                    format_args!(
+                        "{} records in {l}, {} in {r}",
+                        d.left_records, d.right_records
+                    ),
+                );
+                if d.is_equal() {
+                    write_or_exit(&mut handle, format_args!(", identical\n"));
+                } else {
+                    write_or_exit(&mut handle, format_args!(", {} differing\n", d.differing));
+                }
+                d.is_equal()
+            }
+            DiffMode::Unordered(memory_limit) => {
+                let d = diff_unordered(open(left), open(right), max_shown, memory_limit)?;
+                for rec in find_hashes(open(left), &d.left_only_hashes)? {
+                    write_or_exit(&mut handle, format_args!("only in {l}: {}\n", rec.label()));
+                }
+                for rec in find_hashes(open(right), &d.right_only_hashes)? {
+                    write_or_exit(&mut handle, format_args!("only in {r}: {}\n", rec.label()));
+                }
+                write_or_exit(
+                    &mut handle,
+                    format_args!(
+                        "{} records in {l}, {} in {r}",
+                        d.left_records, d.right_records
+                    ),
+                );
+                if d.is_equal() {
+                    write_or_exit(&mut handle, format_args!(", identical in any order\n"));
+                } else {
+                    write_or_exit(
+                        &mut handle,
+                        format_args!(
+                            ", {} only in {l}, {} only in {r}\n",
+                            d.left_only, d.right_only
+                        ),
+                    );
+                }
+                d.is_equal()
+            }
+        };
+        if let Err(e) = handle.flush() {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                exit(141);
+            }
+            return Err(e.into());
+        }
+        Ok(equal)
+    }
+
+    fn mismatches(fpath: &Path) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let reader = open_bam_or_exit(fpath);
+        let stdout = stdout();
+        let mut handle = std::io::BufWriter::new(stdout.lock());
+        write_or_exit(
+            &mut handle,
+            format_args!("ref_name\tpos\tref_base\talt_base\tqual\tread_name\n"),
+        );
+        let mut without_md = 0u64;
+        for rec in reader {
+            let rec = rec.unwrap_or_else(|e| fail(&e));
+            if rec.is_unmapped() || rec.cigar().is_empty() {
+                continue;
+            }
+            if rec.aux_str("MD").is_none() {
+                without_md += 1;
+                continue;
+            }
+            for site in rec.mismatch_sites().unwrap_or_else(|e| fail(&e)) {
+                let qual = site.base_qual.map_or(".".to_string(), |q| q.to_string());
+                write_or_exit(
+                    &mut handle,
+                    format_args!(
+                        "{}\t{}\t{}\t{}\t{qual}\t{}\n",
+                        rec.ref_name(),
+                        site.ref_pos.get() + 1,
+                        char::from(site.ref_base),
+                        char::from(site.read_base),
+                        rec.read_name_str_lossy(),
+                    ),
+                );
+            }
+        }
+        if let Err(e) = handle.flush() {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                exit(141);
+            }
+            fail(&e);
+        }
+        if without_md > 0 {
+            eprintln!("warning: {without_md} mapped records without an MD tag, skipped");
+        }
+    }
+
+    fn consensus(
+        bam_path: &Path,
+        ref_path: &Path,
+        output: Option<&Path>,
+        options: ConsensusOptions,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let index;
+        let mut reference = match load_reference(ref_path) {
+            Some(store) => ReferenceSource::Memory(store),
+            None => {
+                let mut raw = BufReader::new(File::open(ref_path).unwrap_or_else(|e| fail(&e)));
+                if !matches!(detect_compression(&mut raw), Ok(Compression::None)) {
+                    fail(&format_args!(
+                        "{}: a reference too large to load must be an uncompressed FASTA file",
+                        ref_path.display()
+                    ));
+                }
+                // a stale index is not used
+                index = match input::open_index(ref_path, Format::Fasta) {
+                    Ok(Some(RecordIndex::Fasta(idx))) => idx,
+                    _ => fasta::FastaIndex::from_fasta_file(&mut raw, Default::default())
+                        .unwrap_or_else(|e| fail(&e)),
+                };
+                let ref_in = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+                ReferenceSource::Indexed(fasta::IndexedFasta::new(ref_in, &index))
+            }
+        };
+
+        let mut reader = bam::Reader::from_path(bam_path).unwrap_or_else(|e| fail(&e));
+        // references are loaded along with the first record
+        let first = reader.next();
+        let names = reader
+            .references()
+            .iter()
+            .map(|r| r.name().to_string())
+            .collect::<Vec<String>>();
+        let mut start = |i: usize| {
+            let rec = reference
+                .get(&names[i])
+                .unwrap_or_else(|e| fail(&format_args!("{}: {e}", ref_path.display())));
+            ConsensusBuilder::new(rec.seq().as_bytes().to_vec(), options)
+        };
+
+        let mut out: Box<dyn Write> = match output {
+            Some(p) => Box::new(std::io::BufWriter::new(create_or_exit(p, files))),
+            None => Box::new(stdout().lock()),
+        };
+        let mut emit = |i: usize, builder: ConsensusBuilder| {
+            let mut rec = fasta::Record::new();
+            rec.set_id(names[i].as_bytes());
+            rec.set_seq(String::from_utf8_lossy(&builder.finish()).into_owned());
+            write_or_exit(&mut out, format_args!("{rec}\n"));
+        };
+
+        // references come out in header order, uncovered ones as all N
+        let mut current: Option<(usize, ConsensusBuilder)> = None;
+        let mut next_ref = 0;
+        for col in Pileup::new(first.into_iter().chain(reader)) {
+            let col = col.unwrap_or_else(|e| fail(&e));
+            if current.as_ref().map(|(i, _)| *i) != Some(col.ref_id) {
+                if let Some((i, builder)) = current.take() {
+                    emit(i, builder);
+                }
+                if col.ref_id >= names.len() {
+                    fail(&format_args!(
+                        "reference {} is missing from the BAM header",
+                        col.ref_id
+                    ));
+                }
+                for i in next_ref..col.ref_id {
+                    emit(i, start(i));
+                }
+                current = Some((col.ref_id, start(col.ref_id)));
+                next_ref = col.ref_id + 1;
+            }
+            if let Some((_, builder)) = current.as_mut() {
+                builder.add(&col);
+            }
+        }
+        if let Some((i, builder)) = current.take() {
+            emit(i, builder);
+        }
+        for i in next_ref..names.len() {
+            emit(i, start(i));
+        }
+        if let Err(e) = out.flush() {
+            match e.kind() {
+                std::io::ErrorKind::BrokenPipe => exit(141),
+                _ => fail(&e),
+            }
+        }
+    }
+
+    fn concat(
+        fpath: &Path,
+        output: Option<&Path>,
+        map_path: Option<&Path>,
+        spacer: usize,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let f = File::open(fpath).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+        if format != Format::Fasta {
+            fail(&format_args!("expected FASTA input, found {format}"));
+        }
+        let (rec, map) =
+            concatenate(fasta::Reader::new(reader), spacer).unwrap_or_else(|e| fail(&e));
+
+        let out: Box<dyn Write> = match output {
+            Some(p) => Box::new(std::io::BufWriter::new(create_or_exit(p, files))),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        let mut writer = fasta::Writer::new(out);
+        if let Err(e) = writer.write_record(&rec).and_then(|_| writer.flush()) {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                exit(141);
+            }
+            fail(&e);
+        }
+        if let Some(p) = map_path {
+            let out = create_or_exit(p, files);
+            if let Err(e) = map.write_tsv(std::io::BufWriter::new(out)) {
+                fail(&format_args!("{}: {e}", p.display()));
+            }
+        }
+    }
+
+    fn requal(fpath: &Path, from: PhredEncoding, to: PhredEncoding, sample: usize, force: bool) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        if to == PhredEncoding::Unknown {
+            fail(&"--to must be phred33 or phred64");
+        }
+        let open = || {
+            let f = File::open(fpath).unwrap_or_else(|e| fail(&e));
+            match input::open_input(f).unwrap_or_else(|e| fail(&e)) {
+                (Format::Fastq, reader) => fastq::Reader::new(reader),
+                (format, _) => fail(&format_args!("expected FASTQ input, found {format}")),
+            }
+        };
+        let from = if from == PhredEncoding::Unknown {
+            let range = quality_range(open().take(sample)).unwrap_or_else(|e| fail(&e));
+            match range.map(|(lo, hi)| (lo, hi, guess_phred_encoding(&[lo, hi]))) {
+                // no quality characters, nothing to convert
+                None => to,
+                Some((lo, _, PhredEncoding::Unknown)) if force => {
+                    if PhredEncoding::Phred64.min_char() <= Some(lo) {
+                        PhredEncoding::Phred64
+                    } else {
+                        PhredEncoding::Phred33
+                    }
+                }
+                Some((lo, hi, PhredEncoding::Unknown)) => fail(&format_args!(
+                    "cannot tell the quality encoding from characters '{}' to '{}' in the \
+                     first {sample} reads; pass --from, or --force to pick one",
+                    char::from(lo),
+                    char::from(hi)
+                )),
+                Some((_, _, encoding)) => encoding,
+            }
+        } else {
+            from
+        };
+
+        let mut out = std::io::BufWriter::new(stdout().lock());
+        for rec in reencode_records(open(), from, to) {
+            let rec = rec.unwrap_or_else(|e| fail(&e));
+            if let Err(e) = rec.write_to(&mut out) {
+                if e.kind() == std::io::ErrorKind::BrokenPipe {
+                    exit(141);
+                }
+                fail(&e);
+            }
+        }
+        if let Err(e) = out.flush() {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                exit(141);
+            }
+            fail(&e);
+        }
+    }
+
+    fn open_fastq(fpath: &Path) -> fastq::Reader<Box<dyn BufRead>> {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let f = File::open(fpath).unwrap_or_else(|e| fail(&e));
+        match input::open_input(f).unwrap_or_else(|e| fail(&e)) {
+            (Format::Fastq, reader) => fastq::Reader::new(reader),
+            (format, _) => fail(&format_args!("expected FASTQ input, found {format}")),
+        }
+    }
+
+    fn extract_umi(fpath: &Path, extractor: &Extractor) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let mut out = std::io::BufWriter::new(stdout().lock());
+        for rec in open_fastq(fpath) {
+            let mut rec = rec.unwrap_or_else(|e| fail(&e));
+            extractor.extract(&mut rec).unwrap_or_else(|e| fail(&e));
+            if let Err(e) = rec.write_to(&mut out) {
+                if e.kind() == std::io::ErrorKind::BrokenPipe {
+                    exit(141);
+                }
+                fail(&e);
+            }
+        }
+        if let Err(e) = out.flush() {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                exit(141);
+            }
+            fail(&e);
+        }
+    }
+
+    fn extract_umi_pairs(
+        fpaths: [&Path; 2],
+        outputs: [&Path; 2],
+        extractor: &Extractor,
+        files: &OutputArgs,
+    ) {
+        let fail = |path: &Path, e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        };
+        let mut outs = outputs.map(|p| std::io::BufWriter::new(create_or_exit(p, files)));
+        let [mut reads1, mut reads2] = fpaths.map(open_fastq);
+        loop {
+            let (mut r1, mut r2) = match (reads1.next(), reads2.next()) {
+                (None, None) => break,
+                (Some(r1), Some(r2)) => (
+                    r1.unwrap_or_else(|e| fail(fpaths[0], &e)),
+                    r2.unwrap_or_else(|e| fail(fpaths[1], &e)),
+                ),
+                (_, None) => fail(fpaths[1], &"fewer reads than in the first mate file"),
+                (None, _) => fail(fpaths[1], &"more reads than in the first mate file"),
+            };
+            extractor
+                .extract_pair(&mut r1, &mut r2)
+                .unwrap_or_else(|e| fail(fpaths[0], &e));
+            for ((rec, out), path) in [&r1, &r2].into_iter().zip(&mut outs).zip(outputs) {
+                rec.write_to(out).unwrap_or_else(|e| fail(path, &e));
+            }
+        }
+        for (out, path) in outs.iter_mut().zip(outputs) {
+            out.flush().unwrap_or_else(|e| fail(path, &e));
+        }
+    }
+
+    fn merge_pairs(
+        fpaths: [&Path; 2],
+        output: Option<&Path>,
+        unmerged: Option<(&Path, &Path)>,
+        opts: &MergeOptions,
+        files: &OutputArgs,
+    ) {
+        let fail = |path: &Path, e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        };
+        let create = |p: &Path| std::io::BufWriter::new(create_or_exit(p, files));
+        let mut out: Box<dyn Write> = match output {
+            Some(p) => Box::new(create(p)),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        let mut unmerged_out = unmerged.map(|(p1, p2)| [(create(p1), p1), (create(p2), p2)]);
+        let write = |out: &mut dyn Write, rec: &fastq::Record, path: Option<&Path>| {
+            if let Err(e) = rec.write_to(&mut &mut *out) {
+                match path {
+                    Some(p) => fail(p, &e),
+                    None if e.kind() == std::io::ErrorKind::BrokenPipe => exit(141),
+                    None => fail(fpaths[0], &e),
+                }
+            }
+        };
+        let [mut reads1, mut reads2] = fpaths.map(open_fastq);
+        let (mut pairs, mut merged, mut trimmed) = (0u64, 0u64, 0u64);
+        loop {
+            let (r1, r2) = match (reads1.next(), reads2.next()) {
+                (None, None) => break,
+                (Some(r1), Some(r2)) => (
+                    r1.unwrap_or_else(|e| fail(fpaths[0], &e)),
+                    r2.unwrap_or_else(|e| fail(fpaths[1], &e)),
+                ),
+                (_, None) => fail(fpaths[1], &"fewer reads than in the first mate file"),
+                (None, _) => fail(fpaths[1], &"more reads than in the first mate file"),
+            };
+            pairs += 1;
+            match merge_pair(r1, r2, opts) {
+                MergeResult::Merged(m) => {
+                    merged += 1;
+                    trimmed += u64::from(m.adapter_trimmed);
+                    write(&mut out, &m.record, output);
+                }
+                MergeResult::Unmerged(r1, r2) => {
+                    if let Some(outs) = unmerged_out.as_mut() {
+                        for ((out, path), rec) in outs.iter_mut().zip([r1, r2]) {
+                            write(out, &rec, Some(path));
+                        }
+                    }
+                }
+            }
+        }
+        if let Err(e) = out.flush() {
+            match output {
+                Some(p) => fail(p, &e),
+                None if e.kind() == std::io::ErrorKind::BrokenPipe => exit(141),
+                None => fail(fpaths[0], &e),
+            }
+        }
+        for (out, path) in unmerged_out.iter_mut().flatten() {
+            out.flush().unwrap_or_else(|e| fail(path, &e));
+        }
+        eprintln!("merged {merged} of {pairs} pairs, {trimmed} with adapter trimmed");
+        if unmerged.is_none() && merged < pairs {
+            eprintln!(
+                "unmerged pairs not written: {} (see --unmerged1)",
+                pairs - merged
+            );
+        }
+    }
+
+    /// Route single reads or, given two inputs, pairs by the first mate into `dir`
+    fn demux_reads(
+        inputs: Vec<&Path>,
+        demux: &Demultiplexer,
+        extractor: Option<&Extractor>,
+        dir: &Path,
+        max_open: usize,
+        files: &OutputArgs,
+    ) {
+        let fail = |path: &Path, e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        };
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| fail(dir, &e));
+        let names = demux
+            .samples()
+            .iter()
+            .map(|s| s.name.as_str())
+            .chain(["ambiguous", "unmatched"])
+            .collect::<Vec<_>>();
+        let suffixes: &[&str] = if inputs.len() == 2 {
+            &["_R1.fastq", "_R2.fastq"]
+        } else {
+            &[".fastq"]
+        };
+        let paths = names
+            .iter()
+            .flat_map(|name| suffixes.iter().map(move |s| dir.join(format!("{name}{s}"))))
+            .collect::<Vec<_>>();
+        let summary_path = dir.join("summary.tsv");
+        for path in paths.iter().chain([&summary_path]) {
+            outfiles::check(path, files.force).unwrap_or_else(|e| fail(path, &e));
+        }
+        let mut outputs = OutputFiles::new(paths.clone(), max_open).overwrite(files.force);
+        let mut counts = vec![0u64; names.len()];
+
+        let mut readers = inputs.iter().map(|p| open_fastq(p)).collect::<Vec<_>>();
+        loop {
+            let mut recs = Vec::with_capacity(inputs.len());
+            for (reader, path) in readers.iter_mut().zip(&inputs) {
+                if let Some(rec) = reader.next() {
+                    recs.push(rec.unwrap_or_else(|e| fail(path, &e)));
+                }
+            }
+            match recs.len() {
+                0 => break,
+                n if n < inputs.len() => fail(inputs[1], &"mate files differ in number of reads"),
+                _ => {}
+            }
+            let route = match extractor {
+                Some(extractor) => {
+                    let found = match &mut recs[..] {
+                        [r1, r2] => extractor.extract_pair(r1, r2),
+                        [r1] => extractor.extract(r1),
+                        _ => unreachable!("one or two inputs"),
+                    };
+                    let found = found.unwrap_or_else(|e| fail(inputs[0], &e));
+                    demux.route_barcode(found.barcode.as_bytes())
+                }
+                None => {
+                    if let [r1, r2] = &recs[..] {
+                        if !is_mate_pair(r1.id(), r2.id()) {
+                            let id = String::from_utf8_lossy(r2.id());
+                            fail(
+                                inputs[1],
+                                &format_args!("invalid record {id}: id differs from its mate's"),
+                            );
+                        }
+                    }
+                    demux.route(&recs[0])
+                }
+            };
+            let out = match route {
+                Route::Sample(i) => i,
+                Route::Ambiguous => names.len() - 2,
+                Route::Unmatched => names.len() - 1,
+            };
+            counts[out] += 1;
+            for (j, rec) in recs.iter().enumerate() {
+                let i = out * suffixes.len() + j;
+                outputs
+                    .write(i, rec)
+                    .unwrap_or_else(|e| fail(&paths[i], &e));
+            }
+        }
+        outputs.finish().unwrap_or_else(|e| fail(dir, &e));
+
+        let mut summary = String::from("sample\tbarcode\treads\n");
+        for (i, name) in names.iter().enumerate() {
+            let barcode = demux.samples().get(i).map_or("-", |s| s.barcode.as_str());
+            summary.push_str(&format!("{name}\t{barcode}\t{}\n", counts[i]));
+        }
+        create_or_exit(&summary_path, files)
+            .write_all(summary.as_bytes())
+            .unwrap_or_else(|e| fail(&summary_path, &e));
+    }
+
+    /// The index of `fpath` to fetch from, exiting if it is stale and `rebuild` is not set
+    fn fetch_index(fpath: &Path, format: Format, rebuild: bool) -> Option<RecordIndex> {
+        input::open_fetch_index(fpath, format, rebuild).unwrap_or_else(|e| {
+            let fai = index_meta::fai_path(fpath);
+            match e {
+                StaleIndex::Io(e) => eprintln!("{}: {e}", fai.display()),
+                e => eprintln!(
+                    "{}: {e}; rerun with --rebuild-if-stale or `lyso faidx --force {}`",
+                    fai.display(),
+                    fpath.display()
+                ),
+            }
+            exit(1);
+        })
+    }
+
+    /// `ref_path` loaded into memory, or `None` if it is too large for that
+    fn load_reference(ref_path: &Path) -> Option<FastaStore> {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", ref_path.display());
+            exit(1);
+        };
+        let size = std::fs::metadata(ref_path)
+            .unwrap_or_else(|e| fail(&e))
+            .len();
+        if size > DEFAULT_MAX_BYTES {
+            return None;
+        }
+        let f = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+        if format != Format::Fasta {
+            fail(&format_args!("expected FASTA input, found {format}"));
+        }
+        match FastaStore::from_reader(fasta::Reader::new(reader)) {
+            Ok(store) => Some(store),
+            // a compressed reference can pass the cap once decompressed
+            Err(fasta::Error::TooLarge { .. }) => None,
+            Err(e) => fail(&e),
+        }
+    }
+
+    fn getfasta(
+        ref_path: &Path,
+        bed_path: &Path,
+        output: Option<&Path>,
+        rebuild: bool,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let bed = File::open(bed_path).unwrap_or_else(|e| fail(&e));
+        let intervals = IntervalSet::from_bed(BufReader::new(bed), false)
+            .unwrap_or_else(|e| fail(&format_args!("{}: {e}", bed_path.display())));
+        let out: Box<dyn Write> = match output {
+            Some(p) => Box::new(std::io::BufWriter::new(create_or_exit(p, files))),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        let mut writer = fasta::Writer::new(out);
+
+        // checked even for a reference loaded into memory, so a stale index is
+        // reported and --rebuild-if-stale rewrites it
+        let index = fetch_index(ref_path, Format::Fasta, rebuild);
+        let store = load_reference(ref_path);
+        let report = match (store, index) {
+            (Some(store), _) => extract_intervals(
+                &mut ReferenceSource::<File>::Memory(store),
+                &intervals,
+                &mut writer,
+            ),
+            (None, Some(RecordIndex::Fasta(index))) => {
+                let handle = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+                let indexed = fasta::IndexedFasta::new(BufReader::new(handle), &index);
+                extract_intervals(
+                    &mut ReferenceSource::Indexed(indexed),
+                    &intervals,
+                    &mut writer,
+                )
+            }
+            _ => {
+                let f = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+                let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+                if format != Format::Fasta {
+                    fail(&format_args!(
+                        "{}: expected FASTA input, found {format}",
+                        ref_path.display()
+                    ));
+                }
+                extract_intervals_streaming(
+                    &mut fasta::Reader::new(reader),
+                    &intervals,
+                    &mut writer,
+                )
+            }
+        }
+        .and_then(|report| writer.flush().map(|_| report).map_err(ExtractError::from));
+        let report = match report {
+            Ok(report) => report,
+            Err(ExtractError::IoError(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                exit(141)
+            }
+            Err(e) => fail(&format_args!("{}: {e}", ref_path.display())),
+        };
+
+        let show = |i: &NamedInterval| format!("{}:{}-{}", i.name, i.start.get(), i.end.get());
+        for i in &report.clamped {
+            eprintln!(
+                "warning: {} runs past the end of {}, clamped",
+                show(i),
+                i.name
+            );
+        }
+        for i in &report.skipped {
+            eprintln!("warning: {} has no bases in {}, skipped", show(i), i.name);
+        }
+        for name in &report.missing {
+            eprintln!("{name}: not found in {}", ref_path.display());
+        }
+        if !report.is_complete() {
+            // the records found are still written
+            if let Err(e) = outfiles::commit() {
+                eprintln!("{e}");
+            }
+            exit(1);
+        }
+    }
+
+    /// The index of an uncompressed FASTA file and a fresh handle on it, for
+    /// `command` to read it a second time
+    ///
+    /// A missing `.fai` is built in memory, and a stale one is an error unless
+    /// `rebuild` is set.
+    fn open_indexed_fasta(
+        fpath: &Path,
+        command: &str,
+        rebuild: bool,
+    ) -> (fasta::FastaIndex, BufReader<File>) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let mut raw = BufReader::new(File::open(fpath).unwrap_or_else(|e| fail(&e)));
+        match detect_compression(&mut raw) {
+            Ok(Compression::None) => {}
+            Ok(_) => fail(&format_args!(
+                "{command} reads the file twice, decompress it first"
+            )),
+            Err(e) => fail(&e),
+        }
+        match detect_format(&mut raw).unwrap_or_else(|e| fail(&e)) {
+            Format::Fasta => {}
+            format => fail(&format_args!("expected FASTA input, found {format}")),
+        }
+        let index = match fetch_index(fpath, Format::Fasta, rebuild) {
+            Some(index) => index,
+            None => RecordIndex::build(fpath, Format::Fasta).unwrap_or_else(|e| fail(&e)),
+        };
+        let RecordIndex::Fasta(index) = index else {
+            unreachable!("a FASTA file has a FASTA index")
+        };
+        let handle = BufReader::new(File::open(fpath).unwrap_or_else(|e| fail(&e)));
+        (index, handle)
+    }
+
+    /// `paths` are the (map, output) files, output defaulting to stdout
+    fn clean_asm(
+        fpath: &Path,
+        mut cleaner: AssemblyCleaner,
+        wrap: usize,
+        paths: (Option<&Path>, Option<&Path>),
+        rebuild: bool,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        // the first pass: lengths and offsets of every contig
+        let (index, handle) = open_indexed_fasta(fpath, "clean-asm", rebuild);
+
+        let (map_path, out_path) = paths;
+        let create = |p: &Path| std::io::BufWriter::new(create_or_exit(p, files));
+        let mut map = map_path.map(create);
+        let out: Box<dyn Write> = match out_path {
+            Some(p) => Box::new(create(p)),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        let mut writer = fasta::Writer::new(out).line_width(wrap);
+        let mut reader = fasta::IndexedFasta::new(handle, &index);
+        let map_out = map.as_mut().map(|m| m as &mut dyn Write);
+        let summary = cleaner
+            .run(&mut reader, &mut writer, map_out)
+            .and_then(|summary| {
+                writer.flush()?;
+                if let Some(m) = map.as_mut() {
+                    m.flush()?;
+                }
+                Ok(summary)
+            })
+            .unwrap_or_else(|e| match e {
+                fasta::FetchError::IoError(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    exit(141)
+                }
+                fasta::FetchError::IoError(e) => fail(&e),
+                e => fail(&e),
+            });
+        eprintln!(
+            "kept {} contigs ({} bases), dropped {} shorter than the minimum length",
+            summary.kept, summary.kept_bases, summary.dropped
+        );
+    }
+
+    /// `wrap` of None keeps each record's line length, Some(0) puts each
+    /// sequence on one line
+    fn reorder(
+        fpath: &Path,
+        order: ExportOrder,
+        wrap: Option<usize>,
+        out_path: Option<&Path>,
+        rebuild: bool,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let (index, handle) = open_indexed_fasta(fpath, "reorder", rebuild);
+
+        let out: Box<dyn Write> = match out_path {
+            Some(p) => Box::new(std::io::BufWriter::new(create_or_exit(p, files))),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        let width = match wrap {
+            None => 0,
+            Some(0) => usize::MAX,
+            Some(w) => w,
+        };
+        let mut writer = fasta::Writer::new(out).line_width(width);
+        fasta::IndexedFasta::new(handle, &index)
+            .export(order, &mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .unwrap_or_else(|e| match e {
+                fasta::FetchError::IoError(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    exit(141)
+                }
+                e => fail(&e),
+            });
+    }
+
+    /// `filter` only applies to BAM, and `edits` only to FASTA and FASTQ
+    fn view<P: AsRef<Path>>(fpath: P, mode: OutputMode, filter: &RecordFilter, edits: SeqEdits) {
+        let in_file = File::open(&fpath).expect("unable to open file.");
+        let (format, reader) = match input::open_input(in_file) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.as_ref().display());
+                exit(1);
+            }
+        };
+        if format != Format::Bam && !filter.is_empty() {
+            eprintln!("flag and tag filters need BAM input, found {format}");
+            exit(1);
+        }
+        if matches!(format, Format::Bam | Format::Sam) && !edits.is_empty() {
+            eprintln!("sequence edits need FASTA or FASTQ input, found {format}");
+            exit(1);
+        }
+        match format {
+            // plain output of text formats copies the input unchanged
+            Format::Fasta if mode == OutputMode::Native && edits.is_empty() => {
+                print_raw(&mut fasta::Reader::new(reader).raw_records())
+            }
+            Format::Fastq if mode == OutputMode::Native && edits.is_empty() => {
+                print_raw(&mut fastq::Reader::new(reader).raw_records())
+            }
+            // only records passing the flags have their aux decoded for tag tests
+            Format::Bam if filter.has_tag_tests() => print_records(
+                bam::Reader::new(reader).lazy_records().filter_map(|r| {
+                    match r.and_then(|r| Ok((filter.matches_lazy(&r)?, r))) {
+                        Ok((true, r)) => Some(r.into_record()),
+                        Ok((false, _)) => None,
+                        Err(e) => Some(Err(e)),
+                    }
+                }),
+                "\n",
+                mode,
+            ),
+            Format::Bam => print_records(
+                bam::Reader::new(reader).filter(|r| r.as_ref().map_or(true, |r| filter.matches(r))),
+                "\n",
+                mode,
+            ),
+            // there is no SAM parser yet, and SAM text is already its natural form
+            Format::Sam => print_records(
+                reader
+                    .lines()
+                    .filter(|l| {
+                        mode == OutputMode::Native
+                            || l.as_ref().map_or(true, |l| !l.starts_with('@'))
+                    })
+                    .map(|l| l.map(SamLine)),
+                "\n",
+                mode,
+            ),
+            Format::Fasta => print_records(fasta::Reader::new(reader).edit_seqs(edits), "\n", mode),
+            // FASTQ records end with their own newline
+            Format::Fastq => print_records(fastq::Reader::new(reader).edit_seqs(edits), "", mode),
+        }
+    }
+
+    /// Print `regions` and the records named `ids` through the index of `fpath`
+    fn view_fetch(fpath: &Path, regions: &[Region], ids: &[String], mut edits: SeqEdits) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let mut raw = BufReader::new(File::open(fpath).unwrap_or_else(|e| fail(&e)));
+        match detect_compression(&mut raw) {
+            Ok(Compression::None) => {}
+            Ok(_) => fail(&"cannot fetch from a compressed file, decompress it first"),
+            Err(e) => fail(&e),
+        }
+        let format = detect_format(&mut raw).unwrap_or_else(|e| fail(&e));
+        match format {
+            Format::Fasta => {}
+            Format::Fastq if regions.is_empty() => {}
+            Format::Fastq => fail(&"regions can only be fetched from FASTA files, use --id"),
+            format => fail(&format_args!(
+                "regions and ids can only be fetched from FASTA or FASTQ files, found {format}"
+            )),
+        }
+        let index = fetch_index(fpath, format, false).unwrap_or_else(|| {
+            eprintln!(
+                "{}: no index, indexing in memory (lyso faidx writes one)",
+                fpath.display()
+            );
+            RecordIndex::build(fpath, format).unwrap_or_else(|e| fail(&e))
+        });
+
+        let handle = File::open(fpath).unwrap_or_else(|e| fail(&e));
+        // whole records are copied as they are in the file unless edited, and
+        // regions are wrapped as their record is
+        let whole = |raw: RawRecord| -> Vec<u8> {
+            let mut bytes = raw.bytes;
+            if !bytes.ends_with(b"\n") {
+                bytes.push(b'\n');
+            }
+            bytes
+        };
+        let fetched: Vec<Result<Vec<u8>, String>> = match &index {
+            RecordIndex::Fasta(index) => {
+                let mut fasta = fasta::IndexedFasta::new(handle, index);
+                let parts: Vec<Region> =
+                    regions.iter().filter(|r| !r.is_whole()).cloned().collect();
+                let names: Vec<&str> = regions
+                    .iter()
+                    .filter(|r| r.is_whole())
+                    .map(Region::name)
+                    .chain(ids.iter().map(String::as_str))
+                    .collect();
+                let mut parts = fasta
+                    .get_regions(&parts)
+                    .into_iter()
+                    .zip(parts.iter())
+                    .map(|(r, region)| {
+                        let mut rec = r.map_err(|e| e.to_string())?;
+                        let width = match index.get(region.name()) {
+                            Some(entry) if edits.is_empty() => *entry.linebases(),
+                            _ => 0,
+                        };
+                        edits.apply(&mut rec);
+                        let mut out = fasta::Writer::new(Vec::new()).line_width(width as usize);
+                        out.write_record(&rec).map_err(|e| e.to_string())?;
+                        Ok(out.into_inner())
+                    })
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                let mut wholes = fasta
+                    .get_many_raw(&names)
+                    .into_iter()
+                    .map(|r| {
+                        let raw = r.map_err(|e| e.to_string())?;
+                        if edits.is_empty() {
+                            return Ok(whole(raw));
+                        }
+                        let mut rec = fasta::Reader::new(&raw.bytes[..])
+                            .next()
+                            .unwrap_or_else(|| Ok(fasta::Record::new()))
+                            .map_err(|e| e.to_string())?;
+                        edits.apply(&mut rec);
+                        Ok(format!("{rec}\n").into_bytes())
+                    })
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                // back in the order given, regions before ids
+                let mut fetched: Vec<_> = regions
+                    .iter()
+                    .filter_map(|r| {
+                        if r.is_whole() {
+                            wholes.next()
+                        } else {
+                            parts.next()
+                        }
+                    })
+                    .collect();
+                fetched.extend(wholes);
+                fetched
+            }
+            RecordIndex::Fastq(index) => fastq::IndexedFastq::new(handle, index)
+                .get_many_raw(ids)
+                .into_iter()
+                .map(|r| {
+                    let raw = r.map_err(|e| e.to_string())?;
+                    if edits.is_empty() {
+                        return Ok(whole(raw));
+                    }
+                    let mut rec = fastq::Reader::new(&raw.bytes[..])
+                        .next()
+                        .unwrap_or_else(|| Ok(fastq::Record::default()))
+                        .map_err(|e| e.to_string())?;
+                    edits.apply(&mut rec);
+                    Ok(rec.to_string().into_bytes())
+                })
+                .collect(),
+        };
+        let mut misses = 0;
+        for e in fetched.iter().filter_map(|r| r.as_ref().err()) {
+            eprintln!("{}: {e}", fpath.display());
+            misses += 1;
+        }
+        if misses > 0 {
+            // a second file name lands among the regions
+            for region in regions
+                .iter()
+                .filter(|r| Path::new(&r.to_string()).is_file())
+            {
+                eprintln!("note: {region} is a file, but view reads one file at a time");
+            }
+            exit(1);
+        }
+        let stdout = stdout();
+        let mut out = std::io::BufWriter::new(stdout.lock());
+        for rec in fetched.iter().flatten() {
+            write_bytes_or_exit(&mut out, rec);
+        }
+        if let Err(e) = out.flush() {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                exit(141);
+            }
+            fail(&e);
+        }
+    }
+
+    /// Print the header lines of a BAM or SAM file, reading nothing after them
+    fn view_header(fpath: &Path) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let in_file = File::open(fpath).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(in_file).unwrap_or_else(|e| fail(&e));
+        let stdout = stdout();
+        let mut handle = stdout.lock();
+        match format {
+            Format::Bam => {
+                // the references are never parsed, only the text is printed
+                let (header, _) = bam::Reader::header_only(reader).unwrap_or_else(|e| fail(&e));
+                for line in header.lines() {
+                    write_or_exit(
+                        &mut handle,
+                        format_args!("{}\n", String::from_utf8_lossy(line)),
+                    );
+                }
+            }
+            Format::Sam => {
+                for line in reader.lines() {
+                    let line = line.unwrap_or_else(|e| fail(&e));
+                    if !line.starts_with('@') {
+                        break;
+                    }
+                    write_or_exit(&mut handle, format_args!("{line}\n"));
+                }
+            }
+            _ => fail(&format_args!(
+                "--header-only needs BAM or SAM input, found {format}"
+            )),
+        }
+    }
+
+    /// Print the number of records in `fpath`
+    fn count(fpath: &Path, json: bool) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        let in_file = File::open(fpath).unwrap_or_else(|e| fail(&e));
+        let (format, mut reader) = input::open_input(in_file).unwrap_or_else(|e| fail(&e));
+        let n = match input::open_index(fpath, format) {
+            Ok(Some(idx)) => idx.len() as u64,
+            // without an index, or with a stale one, count the records themselves
+            _ => match format {
+                Format::Fasta => fasta::count::fast_count(reader).unwrap_or_else(|e| fail(&e)),
+                Format::Fastq => fastq::count::fast_count(reader).unwrap_or_else(|e| fail(&e)),
+                Format::Bam => bam::count::fast_count(reader).unwrap_or_else(|e| fail(&e)),
+                Format::Sam => {
+                    let mut n = 0;
+                    let mut line = Vec::new();
+                    loop {
+                        line.clear();
+                        match reader.read_until(b'\n', &mut line) {
+                            Ok(0) => break n,
+                            Ok(_) if line.starts_with(b"@") || line.trim_ascii().is_empty() => {}
+                            Ok(_) => n += 1,
+                            Err(e) => fail(&e),
+                        }
+                    }
+                }
+            },
+        };
+        let report = FormatReport {
+            format,
+            report: output::Count { records: n },
+        };
+        emit_or_exit(json, &report, |r| {
+            write_or_exit(&mut stdout().lock(), format_args!("{}\n", r.report.records));
+        });
+    }
+
+    /// Write `<fpath>.fai`, or print `regions` of a FASTA file
+    fn faidx(fpath: &Path, regions: &[Region], rebuild: bool, files: &OutputArgs) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        if regions.is_empty() {
+            check_fai(fpath, files);
+        }
+        let mut raw = BufReader::new(File::open(fpath).unwrap_or_else(|e| fail(&e)));
+        match detect_compression(&mut raw) {
+            Ok(Compression::None) => {}
+            Ok(_) => fail(&"cannot index a compressed file, decompress it first"),
+            Err(e) => fail(&e),
+        }
+        let format = detect_format(&mut raw).unwrap_or_else(|e| fail(&e));
+        let existing = match regions.is_empty() {
+            true => None,
+            false => fetch_index(fpath, format, rebuild),
+        };
+        let index = match existing {
+            Some(idx) => idx,
+            None => RecordIndex::build(fpath, format).unwrap_or_else(|e| fail(&e)),
+        };
+
+        if regions.is_empty() {
+            if let Err(e) = index.write(fpath) {
+                fail(&e);
+            }
+            return;
+        }
+
+        let RecordIndex::Fasta(index) = index else {
+            fail(&"regions can only be fetched from FASTA files");
+        };
+        let mut handle = File::open(fpath).unwrap_or_else(|e| fail(&e));
+        let stdout = stdout();
+        let mut out = stdout.lock();
+        for region in regions {
+            match index.fetch_region(&mut handle, region) {
+                Ok(rec) => write_or_exit(&mut out, format_args!("{rec}\n")),
+                Err(e) => fail(&e),
+            }
+        }
+    }
+
+    /// Write `<fpath>.fai` as the file is indexed, moving it into place once
+    /// indexing succeeds
+    fn faidx_light(fpath: &Path, files: &OutputArgs) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", fpath.display());
+            exit(1);
+        };
+        check_fai(fpath, files);
+        let mut raw = BufReader::new(File::open(fpath).unwrap_or_else(|e| fail(&e)));
+        match detect_compression(&mut raw) {
+            Ok(Compression::None) => {}
+            Ok(_) => fail(&"cannot index a compressed file, decompress it first"),
+            Err(e) => fail(&e),
+        }
+        let format = detect_format(&mut raw).unwrap_or_else(|e| fail(&e));
+        let fai = index_meta::fai_path(fpath);
+        let mut out = AtomicFile::create(&fai, true)
+            .map(std::io::BufWriter::new)
+            .unwrap_or_else(|e| {
+                eprintln!("{}: {e}", fai.display());
+                exit(1);
+            });
+        let policy = Default::default();
+        let written = match format {
+            Format::Fasta => fasta::FastaIndexer::new(&mut raw)
+                .write_index(&policy, &mut out)
+                .map_err(|e| e.to_string()),
+            Format::Fastq => fastq::FastqIndexer::new(&mut raw)
+                .write_index(&policy, &mut out)
+                .map_err(|e| e.to_string()),
+            format => Err(format!("expected FASTA or FASTQ input, found {format}")),
+        };
+        written.unwrap_or_else(|e| fail(&e));
+        out.into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(AtomicFile::commit)
+            .and_then(|()| index_meta::write_meta(fpath, &fai))
+            .unwrap_or_else(|e| fail(&e));
+    }
+
+    /// Exit if `<fpath>.fai` exists and --force was not given
+    fn check_fai(fpath: &Path, files: &OutputArgs) {
+        let fai = index_meta::fai_path(fpath);
+        if let Err(e) = outfiles::check(&fai, files.force) {
+            eprintln!("{}: {e}", fai.display());
+            exit(1);
+        }
+    }
+
+    fn filter<P: AsRef<Path>>(
+        fpath: P,
+        filters: &[Box<dyn RecordPredicate + Sync>],
+        mut edits: SeqEdits,
+        pool: WorkerPool,
+    ) {
+        let in_file = File::open(&fpath).expect("unable to open file.");
+        let (format, reader) = match input::open_input(in_file) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.as_ref().display());
+                exit(1);
+            }
+        };
+        let passes = |rec: &dyn SeqRecord| filters.iter().all(|f| f.test(rec));
+        // without edits the records that pass are copied as they were read
+        fn keep_raw<R: SeqRecord, E>(
+            next: Result<(R, RawRecord), E>,
+            passes: &dyn Fn(&dyn SeqRecord) -> bool,
+        ) -> Option<Result<RawRecord, E>> {
+            match next {
+                Ok((rec, raw)) => passes(&rec).then_some(Ok(raw)),
+                Err(e) => Some(Err(e)),
+            }
+        }
+        match format {
+            Format::Fasta if edits.is_empty() => {
+                let mut reader = fasta::Reader::new(reader);
+                let records = std::iter::from_fn(|| reader.read_record_and_raw());
+                print_raw(&mut records.filter_map(|r| keep_raw(r, &passes)))
+            }
+            Format::Fastq if edits.is_empty() && pool.threads().get() > 1 => {
+                let mut handle = std::io::BufWriter::new(stdout().lock());
+                let passing = |rec: fastq::Record, raw: RawRecord| passes(&rec).then_some(raw);
+                fastq::parallel::par_map_raw(
+                    &mut fastq::Reader::new(reader),
+                    &pool,
+                    passing,
+                    |rec| {
+                        match rec {
+                            Ok(Some(raw)) => write_bytes_or_exit(&mut handle, &raw.bytes),
+                            Ok(None) => {}
+                            Err(e) => {
+                                let _ = handle.flush();
+                                eprintln!("{e}");
+                                exit(1);
+                            }
+                        }
+                        std::ops::ControlFlow::Continue(())
+                    },
+                );
+                if let Err(e) = handle.flush() {
+                    match e.kind() {
+                        std::io::ErrorKind::BrokenPipe => exit(141),
+                        _ => panic!("{e}"),
+                    }
+                }
+            }
+            Format::Fastq if edits.is_empty() => {
+                let mut reader = fastq::Reader::new(reader);
+                let records = std::iter::from_fn(|| reader.read_record_and_raw());
+                print_raw(&mut records.filter_map(|r| keep_raw(r, &passes)))
+            }
+            Format::Fasta => print_records(
+                fasta::Reader::new(reader)
+                    .filter(|r| r.as_ref().map_or(true, |r| passes(r)))
+                    .edit_seqs(edits),
+                "\n",
+                OutputMode::Native,
+            ),
+            Format::Fastq if pool.threads().get() > 1 => {
+                let mut handle = stdout().lock();
+                let passing = |rec: fastq::Record| passes(&rec).then_some(rec);
+                fastq::parallel::par_map(&mut fastq::Reader::new(reader), &pool, passing, |rec| {
+                    match rec {
+                        // edited here, in input order, so random bases match a single thread
+                        Ok(Some(mut rec)) => {
+                            edits.apply(&mut rec);
+                            write_or_exit(&mut handle, format_args!("{rec}"))
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            eprintln!("{e}");
+                            exit(1);
+                        }
+                    }
+                    std::ops::ControlFlow::Continue(())
+                });
+            }
+            Format::Fastq => print_records(
+                fastq::Reader::new(reader)
+                    .filter(|r| r.as_ref().map_or(true, |r| passes(r)))
+                    .edit_seqs(edits),
+                "",
+                OutputMode::Native,
+            ),
+            format => {
+                eprintln!("filter expects FASTA or FASTQ input, found {format}");
+                exit(1);
+            }
+        }
+    }
+
+    fn slice(fpath: &Path, op: Slice) {
+        let in_file = File::open(fpath).expect("unable to open file.");
+        let (format, reader) = match input::open_input(in_file) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.display());
+                exit(1);
+            }
+        };
+        let index = match op {
+            Slice::Head(..) => None,
+            // a stale index is not used
+            _ => input::open_index(fpath, format).ok().flatten(),
+        };
+        // with an index, seek to the first wanted record and read forward from there
+        let (reader, op) = match index {
+            Some(idx) => {
+                let (first, count) = match op {
+                    Slice::Tail(n) => (idx.len().saturating_sub(n), n),
+                    Slice::Range(from, count) => (from.min(idx.len()), count),
+                    Slice::Head(n, _) => (0, n),
+                };
+                let mut f = File::open(fpath).expect("unable to open file.");
+                let start = idx.record_start(first).unwrap_or(0);
+                if let Err(e) = f.seek(SeekFrom::Start(start)) {
+                    eprintln!("{}: {e}", fpath.display());
+                    exit(1);
+                }
+                let reader: Box<dyn BufRead> = Box::new(BufReader::new(f));
+                (reader, Slice::Head(count, None))
+            }
+            None => (reader, op),
+        };
+        let native = |terminator| {
+            move |recs: &mut dyn Iterator<Item = Result<_, _>>| {
+                print_records(recs, terminator, OutputMode::Native)
+            }
+        };
+        match format {
+            Format::Bam => slice_records(bam::Reader::new(reader), op, native("\n")),
+            // FASTA and FASTQ records are printed exactly as they were read
+            Format::Fasta => slice_records(fasta::Reader::new(reader).raw_records(), op, print_raw),
+            Format::Fastq => slice_records(fastq::Reader::new(reader).raw_records(), op, print_raw),
+            Format::Sam => {
+                eprintln!("expected FASTA, FASTQ or BAM input, found {format}");
+                exit(1);
+            }
+        }
+    }
+
+    /// Pass the records selected by `op` to `print`
+    fn slice_records<T, E, I>(
+        records: I,
+        op: Slice,
+        print: impl FnOnce(&mut dyn Iterator<Item = Result<T, E>>),
+    ) where
+        E: Display,
+        I: RecordBytes + Iterator<Item = Result<T, E>>,
+    {
+        match op {
+            Slice::Head(n, None) => print(&mut records.head(n)),
+            Slice::Head(n, Some(bytes)) => {
+                print(&mut Bounded::new(records).max_records(n).max_bytes(bytes))
+            }
+            Slice::Range(from, count) => print(&mut records.range(from, count)),
+            Slice::Tail(n) => match records.tail(n) {
+                Ok(recs) => print(&mut recs.into_iter().map(Ok::<T, E>)),
+                Err(e) => {
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            },
+        }
+    }
+
+    fn rename<P: AsRef<Path>>(
+        fpath: P,
+        mut renamer: Renamer,
+        map: Option<&Path>,
+        files: &OutputArgs,
+    ) {
+        let in_file = File::open(&fpath).expect("unable to open file.");
+        let (format, reader) = match input::open_input(in_file) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.as_ref().display());
+                exit(1);
+            }
+        };
+        let mut map_out = map.map(|m| std::io::BufWriter::new(create_or_exit(m, files)));
+        let mut renamed = |rec: &mut dyn RecordId| {
+            let old = renamer.rename(rec);
+            if let Some(out) = map_out.as_mut() {
+                let line = [&old[..], b"\t", rec.record_id(), b"\n"].concat();
+                if let Err(e) = out.write_all(&line) {
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            }
+        };
+        match format {
+            Format::Fasta => print_records(
+                fasta::Reader::new(reader).map(|r| {
+                    r.map(|mut r| {
+                        renamed(&mut r);
+                        r
+                    })
+                }),
+                "\n",
+                OutputMode::Native,
+            ),
+            Format::Fastq => print_records(
+                fastq::Reader::new(reader).map(|r| {
+                    r.map(|mut r| {
+                        renamed(&mut r);
+                        r
+                    })
+                }),
+                "",
+                OutputMode::Native,
+            ),
+            format => {
+                eprintln!("rename expects FASTA or FASTQ input, found {format}");
+                exit(1);
+            }
+        }
+        if let Some(Err(e)) = map_out.map(|mut out| out.flush()) {
+            eprintln!("{e}");
+            exit(1);
+        }
+    }
+
+    fn grep<P: AsRef<Path>>(fpath: P, motif: &Motif) {
+        let in_file = File::open(&fpath).expect("unable to open file.");
+        let (format, reader) = match input::open_input(in_file) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.as_ref().display());
+                exit(1);
+            }
+        };
+        match format {
+            Format::Fasta => grep_records(fasta::Reader::new(reader), motif),
+            // matches are reported without qualities, so they are never copied
+            Format::Fastq => grep_records(fastq::Reader::new(reader).without_quality(), motif),
+            format => {
+                eprintln!("grep expects FASTA or FASTQ input, found {format}");
+                exit(1);
+            }
+        }
+    }
+
+    fn grep_records<T, E, I>(records: I, motif: &Motif)
+    where
+        T: SeqRecord + RecordId,
+        E: Display,
+        I: Iterator<Item = Result<T, E>>,
+    {
+        let stdout = stdout();
+        let mut handle = stdout.lock();
+        write_or_exit(&mut handle, format_args!("id\tpos\tstrand\tmatch\n"));
+        let width = motif.pattern().len();
+        for rec in records {
+            let rec = match rec {
+                Ok(r) => r,
+                Err(e) => {
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            };
+            let seq = rec.seq_bytes();
+            for hit in motif.find_all_with_rc(seq) {
+                let window = &seq[hit.pos..hit.pos + width];
+                let matched = match hit.strand {
+                    Strand::Reverse => reverse_complement(window),
+                    _ => window.to_vec(),
+                };
+                write_or_exit(
+                    &mut handle,
+                    format_args!(
+                        "{}\t{}\t{}\t{}\n",
+                        String::from_utf8_lossy(rec.record_id()),
+                        hit.pos + 1,
+                        hit.strand,
+                        String::from_utf8_lossy(&matched)
+                    ),
+                );
+            }
+        }
+    }
+
+    fn sortseq<P: AsRef<Path>>(fpath: P, key: SortKey, memory_limit: usize) {
+        let in_file = File::open(&fpath).expect("unable to open file.");
+        let (format, reader) = match input::open_input(in_file) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.as_ref().display());
+                exit(1);
+            }
+        };
+        let mut out = std::io::BufWriter::new(stdout().lock());
+        let res = match format {
+            Format::Fasta => sort_records(fasta::Reader::new(reader), &mut out, key, memory_limit)
+                .map_err(|e| sort_failed(&e)),
+            Format::Fastq => sort_records(fastq::Reader::new(reader), &mut out, key, memory_limit)
+                .map_err(|e| sort_failed(&e)),
+            format => {
+                eprintln!("sortseq expects FASTA or FASTQ input, found {format}");
+                exit(1);
+            }
+        };
+        if res.is_err() {
+            exit(1);
+        }
+    }
+
+    /// Report a sort error, exiting quietly if stdout was closed
+    fn sort_failed(e: &(dyn std::error::Error + 'static)) {
+        match e.source().and_then(|s| s.downcast_ref::<std::io::Error>()) {
+            Some(io) if io.kind() == std::io::ErrorKind::BrokenPipe => exit(141),
+            Some(io) => eprintln!("{e}: {io}"),
+            None => eprintln!("{e}"),
+        }
+    }
+
+    fn translate<P: AsRef<Path>>(fpath: P, translator: &Translator, frames: &[Frame]) {
+        let in_file = File::open(&fpath).expect("unable to open file.");
+        let (format, reader) = match input::open_input(in_file) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}: {e}", fpath.as_ref().display());
+                exit(1);
+            }
+        };
+        match format {
+            Format::Fasta => print_records(
+                translate_records(fasta::Reader::new(reader), translator, frames),
+                "\n",
+                OutputMode::Native,
+            ),
+            Format::Fastq => print_records(
+                translate_records(fastq::Reader::new(reader), translator, frames),
+                "\n",
+                OutputMode::Native,
+            ),
+            format => {
+                eprintln!("translate expects FASTA or FASTQ input, found {format}");
+                exit(1);
+            }
+        }
+    }
+
+    /// One protein record per input record and frame
+    ///
+    /// With several frames, the frame is appended to the first word of the id.
+    fn translate_records<'a, T, E, I>(
+        records: I,
+        translator: &'a Translator,
+        frames: &'a [Frame],
+    ) -> impl Iterator<Item = Result<fasta::Record, E>> + 'a
+    where
+        T: SeqRecord + RecordId,
+        E: 'a,
+        I: Iterator<Item = Result<T, E>> + 'a,
+    {
+        records.flat_map(move |rec| {
+            let rec = match rec {
+                Ok(r) => r,
+                Err(e) => return vec![Err(e)],
+            };
+            frames
+                .iter()
+                .map(|frame| {
+                    let mut protein = fasta::Record::new();
+                    protein.set_seq(translator.translate(rec.seq_bytes(), *frame));
+                    let id = rec.record_id();
+                    if frames.len() == 1 {
+                        protein.set_record_id(id.to_vec());
+                    } else {
+                        let word = id.iter().position(|b| *b == b' ').unwrap_or(id.len());
+                        let suffix = format!("_frame={frame}");
+                        protein
+                            .set_record_id([&id[..word], suffix.as_bytes(), &id[word..]].concat());
+                    }
+                    Ok(protein)
+                })
+                .collect()
+        })
+    }
+
+    fn print_records<T, E, I>(records: I, terminator: &str, mode: OutputMode)
+    where
+        T: Display + RecordFormatter,
+        E: Display,
+        I: Iterator<Item = Result<T, E>>,
+    {
+        let stdout = stdout();
+        let mut handle = stdout.lock();
+        if mode == OutputMode::Tsv {
+            write_or_exit(&mut handle, format_args!("{}\n", T::tsv_header()));
+        }
+        let mut n_records = 0u64;
+        for rec in records {
+            let rec = match rec {
+                Ok(r) => r,
+                Err(e) => {
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            };
+            n_records += 1;
+            match mode {
+                OutputMode::Native => write_or_exit(&mut handle, format_args!("{rec}{terminator}")),
+                OutputMode::Tsv => write_or_exit(&mut handle, format_args!("{}\n", rec.to_tsv())),
+                OutputMode::Summary => {}
+            }
+        }
+        if mode == OutputMode::Summary {
+            write_or_exit(&mut handle, format_args!("records\t{n_records}\n"));
+        }
+    }
+
+    /// Print records exactly as they were read
+    fn print_raw<E: Display>(records: &mut dyn Iterator<Item = Result<RawRecord, E>>) {
+        let mut handle = std::io::BufWriter::new(stdout().lock());
+        for rec in records {
+            match rec {
+                Ok(r) => write_bytes_or_exit(&mut handle, &r.bytes),
+                Err(e) => {
+                    let _ = handle.flush();
+                    eprintln!("{e}");
+                    exit(1);
+                }
+            }
+        }
+        if let Err(e) = handle.flush() {
+            match e.kind() {
+                std::io::ErrorKind::BrokenPipe => exit(141),
+                _ => panic!("{e}"),
+            }
+        }
+    }
+
+    /// Align every read of `fpath` to each record of `ref_path` and print the best
+    /// hit per read, exiting on unreadable input or a reference file without records
+    fn ident(fpath: &Path, ref_path: &Path, params: &AlignParams, local: bool) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let open = |path: &Path| {
+            let f = File::open(path).unwrap_or_else(|e| fail(&e));
+            input::open_input(f).unwrap_or_else(|e| fail(&format_args!("{}: {e}", path.display())))
+        };
+        let (format, reader) = open(ref_path);
+        if format != Format::Fasta {
+            fail(&format_args!(
+                "{}: expected FASTA input, found {format}",
+                ref_path.display()
+            ));
+        }
+        let refs = fasta::Reader::new(reader)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|e| fail(&format_args!("{}: {e}", ref_path.display())));
+        if refs.is_empty() {
+            fail(&format_args!(
+                "{}: no reference records",
+                ref_path.display()
+            ));
+        }
+        let (format, reader) = open(fpath);
+        match format {
+            Format::Fasta => ident_records(fasta::Reader::new(reader), &refs, params, local),
+            Format::Fastq => ident_records(fastq::Reader::new(reader), &refs, params, local),
+            format => fail(&format_args!(
+                "ident expects FASTA or FASTQ input, found {format}"
+            )),
+        }
+    }
+
+    /// Print the header and one row per record of `records` with its best-scoring
+    /// reference among `refs`
+    fn ident_records<T, E, I>(records: I, refs: &[fasta::Record], params: &AlignParams, local: bool)
+    where
+        T: SeqRecord + RecordId,
+        E: Display,
+        I: Iterator<Item = Result<T, E>>,
+    {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let first_word = |id: &[u8]| {
+            let word = id
+                .split(|b| b.is_ascii_whitespace())
+                .next()
+                .unwrap_or_default();
+            String::from_utf8_lossy(word).into_owned()
+        };
+        let stdout = stdout();
+        let mut handle = stdout.lock();
+        write_or_exit(
+            &mut handle,
+            format_args!("id\tref\tscore\tidentity\tcigar\n"),
+        );
+        for rec in records {
+            let rec = rec.unwrap_or_else(|e| fail(&e));
+            let name = first_word(rec.record_id());
+            let mut top: Option<(&fasta::Record, Alignment)> = None;
+            for r in refs {
+                let seq = r.seq().as_bytes();
+                let aln = match local {
+                    true => local_align(rec.seq_bytes(), seq, params),
+                    false => global_align(rec.seq_bytes(), seq, params),
+                }
+                .unwrap_or_else(|e| fail(&format_args!("{name}: {e}")));
+                if top.as_ref().is_none_or(|(_, t)| aln.score > t.score) {
+                    top = Some((r, aln));
+                }
+            }
+            let (r, aln) = top.expect("at least one reference");
+            write_or_exit(
+                &mut handle,
+                format_args!(
+                    "{name}\t{}\t{}\t{:.2}\t{}\n",
+                    first_word(r.record_id()),
+                    aln.score,
+                    aln.identity(),
+                    aln.cigar_string()
+                ),
+            );
+        }
+    }
+
+    /// `paths` are the (layout, output) files, output defaulting to stdout
+    fn scaffold2contig(
+        ref_path: &Path,
+        min_gap: usize,
+        wrap: usize,
+        paths: (Option<&Path>, Option<&Path>),
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let f = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+        if format != Format::Fasta {
+            fail(&format_args!(
+                "{}: expected FASTA input, found {format}",
+                ref_path.display()
+            ));
+        }
+        let (layout_path, out_path) = paths;
+        let create = |p: &Path| std::io::BufWriter::new(create_or_exit(p, files));
+        let mut layout = layout_path.map(create);
+        if let Some(out) = layout.as_mut() {
+            writeln!(out, "scaffold\tstart\tend\tcontig\tgap_length").unwrap_or_else(|e| fail(&e));
+        }
+        let out: Box<dyn Write> = match out_path {
+            Some(p) => Box::new(create(p)),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        let mut writer = fasta::Writer::new(out).line_width(wrap);
+        let written = |res: std::io::Result<()>| match res {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => exit(141),
+            Err(e) => fail(&e),
+            Ok(()) => {}
+        };
+        for rec in fasta::Reader::new(reader) {
+            let rec = rec.unwrap_or_else(|e| fail(&format_args!("{}: {e}", ref_path.display())));
+            let parts = gap_layout(rec.seq().as_bytes(), min_gap);
+            let mut contigs = layout_contigs(&rec, &parts).into_iter();
+            let scaffold = String::from_utf8_lossy(scaffold_name(&rec)).into_owned();
+            for part in parts {
+                let (start, end) = (part.range().start + 1, part.range().end);
+                let row = match part {
+                    Part::Contig(_) => {
+                        // contigs come in layout order, one per contig part
+                        let (contig, _) = contigs.next().expect("a contig for each contig part");
+                        written(writer.write_record(&contig));
+                        format!("{}\t.", contig.id_str_lossy())
+                    }
+                    Part::Gap(r) => format!(".\t{}", r.len()),
+                };
+                if let Some(out) = layout.as_mut() {
+                    writeln!(out, "{scaffold}\t{start}\t{end}\t{row}").unwrap_or_else(|e| fail(&e));
+                }
+            }
+        }
+        written(writer.flush());
+        if let Some(mut out) = layout {
+            out.flush().unwrap_or_else(|e| fail(&e));
+        }
+    }
+
+    fn mask(
+        ref_path: &Path,
+        bed_path: &Path,
+        mode: MaskMode,
+        output: Option<&Path>,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let bed = File::open(bed_path).unwrap_or_else(|e| fail(&e));
+        let intervals = IntervalSet::from_bed(BufReader::new(bed), false)
+            .unwrap_or_else(|e| fail(&format_args!("{}: {e}", bed_path.display())));
+        let f = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+        if format != Format::Fasta {
+            fail(&format_args!(
+                "{}: expected FASTA input, found {format}",
+                ref_path.display()
+            ));
+        }
+        let mut out: Box<dyn Write> = match output {
+            Some(p) => Box::new(std::io::BufWriter::new(create_or_exit(p, files))),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        mask_records(&mut fasta::Reader::new(reader), &intervals, mode, &mut out)
+            .unwrap_or_else(|e| fail(&e));
+        out.flush().unwrap_or_else(|e| fail(&e));
+    }
+
+    fn maskstats(ref_path: &Path, bed: Option<&Path>, min_run: usize, files: &OutputArgs) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let f = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+        if format != Format::Fasta {
+            fail(&format_args!(
+                "{}: expected FASTA input, found {format}",
+                ref_path.display()
+            ));
+        }
+        let mut bed = bed.map(|p| std::io::BufWriter::new(create_or_exit(p, files)));
+        let stdout = stdout();
+        let mut handle = stdout.lock();
+        write_or_exit(
+            &mut handle,
+            format_args!("id\tlength\tmasked\tmasked_frac\n"),
+        );
+        let frac = |masked: usize, len: usize| match len {
+            0 => 0.0,
+            _ => masked as f64 / len as f64,
+        };
+        let (mut total_len, mut total_masked) = (0, 0);
+        for rec in fasta::Reader::new(reader) {
+            let rec = rec.unwrap_or_else(|e| fail(&e));
+            let id = rec.id_str_lossy();
+            let name = id.split_ascii_whitespace().next().unwrap_or_default();
+            let (len, masked) = (rec.seq().len(), rec.masked_len());
+            total_len += len;
+            total_masked += masked;
+            write_or_exit(
+                &mut handle,
+                format_args!("{name}\t{len}\t{masked}\t{:.4}\n", frac(masked, len)),
+            );
+            if let Some(out) = bed.as_mut() {
+                for run in find_runs(rec.seq().as_bytes(), is_masked, min_run) {
+                    writeln!(out, "{name}\t{}\t{}", run.start, run.end)
+                        .unwrap_or_else(|e| fail(&e));
+                }
+            }
+        }
+        write_or_exit(
+            &mut handle,
+            format_args!(
+                "total\t{total_len}\t{total_masked}\t{:.4}\n",
+                frac(total_masked, total_len)
+            ),
+        );
+        if let Some(mut out) = bed {
+            out.flush().unwrap_or_else(|e| fail(&e));
+        }
+    }
+
+    fn windows(ref_path: &Path, size: usize, step: usize, gc: bool, partial: bool) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", ref_path.display());
+            exit(1);
+        };
+        let f = File::open(ref_path).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+        if format != Format::Fasta {
+            fail(&format_args!("expected FASTA input, found {format}"));
+        }
+        let mut handle = std::io::BufWriter::new(stdout().lock());
+        let column = if gc { "gc" } else { "seq" };
+        write_or_exit(&mut handle, format_args!("id\tstart\tend\t{column}\n"));
+        let mut name = String::new();
+        let mut id: Option<Rc<[u8]>> = None;
+        for window in StreamWindows::new(reader, size, step).partial(partial) {
+            let window = window.unwrap_or_else(|e| fail(&e));
+            // every window of a record shares its id
+            if !id.as_ref().is_some_and(|id| Rc::ptr_eq(id, &window.id)) {
+                let header = String::from_utf8_lossy(&window.id);
+                name = header
+                    .split_ascii_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                id = Some(window.id.clone());
+            }
+            let (start, end) = (window.start, window.end());
+            if gc {
+                let frac = gc_content(window.seq.as_bytes()).unwrap_or_default();
+                write_or_exit(
+                    &mut handle,
+                    format_args!("{name}\t{start}\t{end}\t{frac:.4}\n"),
+                );
+            } else {
+                write_or_exit(
+                    &mut handle,
+                    format_args!("{name}\t{start}\t{end}\t{}\n", window.seq),
+                );
+            }
+        }
+        if let Err(e) = handle.flush() {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                exit(141);
+            }
+            fail(&e);
+        }
+    }
+
+    /// Write `records` as FASTQ, giving reads without qualities those of
+    /// `quality`, or as FASTA when there is no `quality`
+    fn simulate(
+        records: impl Iterator<Item = SimRecord>,
+        quality: Option<QualityProfile>,
+        output: Option<&Path>,
+        files: &OutputArgs,
+    ) {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{e}");
+            exit(1);
+        };
+        let mut out: Box<dyn Write> = match output {
+            Some(p) => Box::new(std::io::BufWriter::new(create_or_exit(p, files))),
+            None => Box::new(std::io::BufWriter::new(stdout().lock())),
+        };
+        let written = |res: std::io::Result<()>| match res {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => exit(141),
+            Err(e) => fail(&e),
+            Ok(()) => {}
+        };
+        match quality {
+            Some(quality) => {
+                for mut read in records {
+                    read.qual
+                        .get_or_insert_with(|| quality.qualities(read.seq.len()));
+                    written(fastq::Record::from(read).write_to(&mut out));
+                }
+            }
+            None => {
+                let mut writer = fasta::Writer::new(&mut out).line_width(60);
+                for seq in records {
+                    written(writer.write_record(&fasta::Record::from(seq)));
+                }
+            }
+        }
+        written(out.flush());
+    }
+
+    /// The sequences of the FASTA file `path`, to sample reads from
+    ///
+    /// Names end at the first whitespace, and bases are upper-cased, with N for
+    /// anything but ACGT.
+    fn sim_reference(path: &Path) -> Vec<SimRecord> {
+        let fail = |e: &dyn Display| -> ! {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        };
+        let f = File::open(path).unwrap_or_else(|e| fail(&e));
+        let (format, reader) = input::open_input(f).unwrap_or_else(|e| fail(&e));
+        if format != Format::Fasta {
+            fail(&format_args!("expected FASTA input, found {format}"));
+        }
+        fasta::Reader::new(reader)
+            .map(|rec| {
+                let rec = rec.unwrap_or_else(|e| fail(&e));
+                let id = rec.id_str_lossy();
+                SimRecord {
+                    name: id.split_whitespace().next().unwrap_or_default().to_string(),
+                    desc: None,
+                    seq: rec
+                        .seq()
+                        .chars()
+                        .map(|c| match c.to_ascii_uppercase() {
+                            c @ ('A' | 'C' | 'G' | 'T') => c,
+                            _ => 'N',
+                        })
+                        .collect(),
+                    qual: None,
+                }
+            })
+            .collect()
+    }
+
+    /// `outfiles::create`, exiting with the error when `path` cannot be written
+    fn create_or_exit(path: &Path, files: &OutputArgs) -> File {
+        outfiles::create(path, files.force).unwrap_or_else(|e| {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        })
+    }
+
+    /// `bam::Reader::from_path`, exiting with the error, which names the file
+    fn open_bam_or_exit(path: impl AsRef<Path>) -> bam::Reader<bam::VirtualReader<File>> {
+        bam::Reader::from_path(path).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        })
+    }
+
+    /// `output::emit`, exiting with the error when stdout cannot be written
+    fn emit_or_exit<T: serde::Serialize>(json: bool, report: &T, text: impl FnOnce(&T)) {
+        output::emit(json, report, text).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        });
+    }
+
+    /// Exit quietly when the reader on the other end of stdout goes away
+    fn write_or_exit(handle: &mut impl Write, out: std::fmt::Arguments) {
+        if let Err(e) = handle.write_fmt(out) {
+            match e.kind() {
+                std::io::ErrorKind::BrokenPipe => exit(141),
+                _ => panic!("{e}"),
+            }
+        }
+    }
+
+    fn write_bytes_or_exit(handle: &mut impl Write, bytes: &[u8]) {
+        if let Err(e) = handle.write_all(bytes) {
+            match e.kind() {
+                std::io::ErrorKind::BrokenPipe => exit(141),
+                _ => panic!("{e}"),
             }
         }
     }
@@ -0,0 +1,105 @@
+//! Output files of the subcommands, written whole or not at all
+//!
+//! `create` hands out a temporary file beside the destination. `commit` renames
+//! every one into place once the subcommand has finished; `exit` and a panic
+//! remove them instead, so a failed run leaves no partial output and any earlier
+//! version untouched. Without --force, an existing destination is refused before
+//! anything is written. Special files such as /dev/null are written directly.
+//!
+//! Subcommands writing many files (demux, split-rg) move each into place on its
+//! own as they finish; see `OutputFiles` and `SplitWriters`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+
+use lyso::common::error::LysoError;
+use lyso::common::io::atomic::{self, PendingFile};
+
+/// Outputs created and not yet committed
+static PENDING: Mutex<Vec<PendingFile>> = Mutex::new(Vec::new());
+
+fn pending() -> std::sync::MutexGuard<'static, Vec<PendingFile>> {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Fail if `path` exists and `force` is not set
+pub fn check(path: &Path, force: bool) -> io::Result<()> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() && !force => Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "already exists, pass --force to replace it",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A file to write `path` through, moved into place by `commit`
+pub fn create(path: &Path, force: bool) -> io::Result<File> {
+    check(path, force)?;
+    if fs::metadata(path).is_ok_and(|meta| !meta.is_file() && !meta.is_dir()) {
+        return OpenOptions::new().write(true).open(path);
+    }
+    let file = PendingFile::new(path, force)?;
+    let handle = file.open()?;
+    pending().push(file);
+    Ok(handle)
+}
+
+/// Move every output created so far into place, in the order they were created
+pub fn commit() -> Result<(), LysoError> {
+    let files = std::mem::take(&mut *pending());
+    for file in files {
+        let dest = file.dest().to_path_buf();
+        file.persist().map_err(|e| LysoError::from_path(dest, e))?;
+    }
+    Ok(())
+}
+
+/// Remove the outputs not yet committed, and exit with `code`
+pub fn exit(code: i32) -> ! {
+    pending().clear();
+    atomic::remove_pending();
+    std::process::exit(code)
+}
+
+/// Removes the outputs not yet committed when dropped, as when unwinding from
+/// a panic
+pub struct Discard;
+
+impl Drop for Discard {
+    fn drop(&mut self) {
+        pending().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_create_and_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.fa"), dir.path().join("b.fa"));
+        fs::write(&b, "old\n").unwrap();
+        let err = create(&b, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "already exists, pass --force to replace it"
+        );
+
+        create(&a, false).unwrap().write_all(b">a\n").unwrap();
+        create(&b, true).unwrap().write_all(b">b\n").unwrap();
+        assert!(!a.exists());
+        assert_eq!(fs::read(&b).unwrap(), b"old\n");
+        commit().unwrap();
+        assert_eq!(fs::read(&a).unwrap(), b">a\n");
+        assert_eq!(fs::read(&b).unwrap(), b">b\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // special files are written in place
+        create(Path::new("/dev/null"), false).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! Text or JSON output for the reporting subcommands
+//!
+//! With `--json`, `stats`, `inspect`, `count`, `isize` and `coverage` print a
+//! single JSON object holding their report and a "lyso_schema_version" field.
+//! The version goes up when a field is removed, renamed or changes meaning;
+//! fields may be added without a bump. Every field is always present: absent
+//! values, and NaN or infinite numbers, are null. Fractional numbers are rounded
+//! to 4 decimal places, as in the text reports.
+//!
+//! - `stats` on FASTQ: `format`, `reads`, `bases`, `max_len`
+//! - `stats --per-cycle`: `format` and `cycles`, one object per read position with
+//!   the fields of `CycleStats`
+//! - `stats` on BAM: `format` and the fields of `BamSummary`, with `total` named
+//!   `reads` as in the text and `mapq_hist` keyed by bin label ("0", "1-9", ...,
+//!   "60+")
+//! - `inspect`: the fields of `InspectReport`
+//! - `count`: `format` and `records`
+//! - `isize`: `format` and the fields of `InsertSizeSummary`, with `count` named
+//!   `pairs` as in the text
+//! - `coverage`: `format` and `references`, one object per reference with the
+//!   columns of the text table
+
+use std::io::{stdout, ErrorKind, Write};
+use std::process::exit;
+
+use lyso::bam::coverage::{RefCoverage, TargetCoverage};
+use lyso::common::detect::Format;
+use lyso::fastq::stats::CycleStats;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Version of the JSON schema described above
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Decimal places kept in fractional numbers
+const DECIMALS: i32 = 4;
+
+/// A report on an input of one format
+#[derive(Serialize)]
+pub struct FormatReport<T> {
+    pub format: Format,
+    #[serde(flatten)]
+    pub report: T,
+}
+
+/// The rows of `stats --per-cycle`
+#[derive(Serialize)]
+pub struct PerCycle {
+    pub cycles: Vec<CycleStats>,
+}
+
+/// The record count of `count`
+#[derive(Serialize)]
+pub struct Count {
+    pub records: u64,
+}
+
+/// The rows of `coverage`
+#[derive(Serialize)]
+pub struct Coverage {
+    pub references: Vec<CoverageRow>,
+    /// Present with --targets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<TargetRow>>,
+}
+
+/// One row of `coverage`, with the columns of `RefCoverage::TSV_HEADER`
+#[derive(Serialize)]
+pub struct CoverageRow {
+    rname: String,
+    length: u64,
+    reads: u64,
+    aligned_bases: u64,
+    mean_depth: f64,
+    breadth_1x: f64,
+    breadth_10x: f64,
+    breadth_30x: f64,
+}
+
+impl From<&RefCoverage> for CoverageRow {
+    fn from(cov: &RefCoverage) -> Self {
+        CoverageRow {
+            mean_depth: cov.mean_depth(),
+            breadth_1x: cov.breadth(0),
+            breadth_10x: cov.breadth(1),
+            breadth_30x: cov.breadth(2),
+            rname: cov.name.clone(),
+            length: cov.length,
+            reads: cov.reads,
+            aligned_bases: cov.aligned_bases,
+        }
+    }
+}
+
+/// One target of `coverage --targets`, with the columns of `TargetCoverage::TSV_HEADER`
+#[derive(Serialize)]
+pub struct TargetRow {
+    chrom: String,
+    start: u64,
+    end: u64,
+    name: Option<String>,
+    mean_depth: f64,
+    breadth_1x: f64,
+    breadth_10x: f64,
+    breadth_30x: f64,
+}
+
+impl From<&TargetCoverage> for TargetRow {
+    fn from(cov: &TargetCoverage) -> Self {
+        TargetRow {
+            mean_depth: cov.mean_depth(),
+            breadth_1x: cov.breadth(0),
+            breadth_10x: cov.breadth(1),
+            breadth_30x: cov.breadth(2),
+            chrom: cov.interval.name.clone(),
+            start: cov.interval.start.get(),
+            end: cov.interval.end.get(),
+            name: cov.interval.label.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    lyso_schema_version: u32,
+    #[serde(flatten)]
+    report: &'a T,
+}
+
+/// Print `report` as JSON when `json` is set, or with `text` otherwise
+///
+/// Exits quietly when the reader on the other end of stdout goes away, as
+/// `write_or_exit` does; other write errors are returned.
+pub fn emit<T: Serialize>(json: bool, report: &T, text: impl FnOnce(&T)) -> std::io::Result<()> {
+    if !json {
+        text(report);
+        return Ok(());
+    }
+    let mut out = stdout().lock();
+    let written = serde_json::to_writer_pretty(&mut out, &to_json(report)?)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writeln!(out));
+    match written {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => exit(141),
+        written => written,
+    }
+}
+
+/// `report` with its schema version, and fractional numbers rounded
+fn to_json<T: Serialize>(report: &T) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(Versioned {
+        lyso_schema_version: SCHEMA_VERSION,
+        report,
+    })?;
+    round_floats(&mut value);
+    Ok(value)
+}
+
+fn round_floats(value: &mut Value) {
+    match value {
+        Value::Number(n) if n.is_f64() => {
+            let scale = 10f64.powi(DECIMALS);
+            let rounded = (n.as_f64().unwrap() * scale).round() / scale;
+            *value = Value::from(rounded);
+        }
+        Value::Array(items) => items.iter_mut().for_each(round_floats),
+        Value::Object(fields) => fields.values_mut().for_each(round_floats),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_finite_is_null() {
+        #[derive(Serialize)]
+        struct Rates {
+            a: f64,
+            b: f64,
+            c: Option<f64>,
+        }
+        let rates = Rates {
+            a: f64::NAN,
+            b: f64::INFINITY,
+            c: None,
+        };
+        assert_eq!(
+            to_json(&rates).unwrap().to_string(),
+            r#"{"lyso_schema_version":1,"a":null,"b":null,"c":null}"#
+        );
+    }
+
+    #[test]
+    fn test_rounded() {
+        #[derive(Serialize)]
+        struct Report {
+            rate: f64,
+            rates: Vec<Option<f64>>,
+            count: u64,
+        }
+        let report = Report {
+            rate: 1.0 / 14.0,
+            rates: vec![Some(2.0 / 3.0), None, Some(0.5)],
+            count: 7,
+        };
+        assert_eq!(
+            to_json(&report).unwrap().to_string(),
+            r#"{"lyso_schema_version":1,"rate":0.0714,"rates":[0.6667,null,0.5],"count":7}"#
+        );
+    }
+}
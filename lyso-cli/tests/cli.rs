@@ -0,0 +1,1748 @@
+//! End-to-end tests of the `lyso` binary against golden output
+//!
+//! Each test runs the binary from `resources/test_data` and compares its
+//! stdout with `tests/golden/<name>.stdout`, and its stderr with
+//! `tests/golden/<name>.stderr` (absent when nothing is written to stderr).
+//! Line endings are normalized before comparing.
+//!
+//! After an intended change in output, regenerate the golden files with
+//!
+//! ```text
+//! LYSO_UPDATE_GOLDEN=1 cargo test -p lyso-cli --test cli
+//! ```
+//!
+//! and review the diff.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const TEST_DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../resources/test_data");
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn updating() -> bool {
+    std::env::var_os("LYSO_UPDATE_GOLDEN").is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+fn normalize(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).replace("\r\n", "\n")
+}
+
+fn run_in(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lyso"))
+        .args(args)
+        .current_dir(dir)
+        .env_remove("RUST_BACKTRACE")
+        .output()
+        .expect("failed to run lyso")
+}
+
+/// Compare `actual` with the golden file `file`, or rewrite it when updating
+///
+/// An empty `actual` is expected to have no golden file.
+fn check(file: &str, actual: &str) {
+    let path = golden_dir().join(file);
+    if updating() {
+        if actual.is_empty() {
+            let _ = std::fs::remove_file(&path);
+        } else {
+            std::fs::write(&path, actual).unwrap();
+        }
+        return;
+    }
+    let expected = match std::fs::read(&path) {
+        Ok(bytes) => normalize(&bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => panic!("{}: {e}", path.display()),
+    };
+    assert!(
+        expected == actual,
+        "{file} differs from the golden file; rerun with LYSO_UPDATE_GOLDEN=1 to update it\n\
+         --- expected\n{expected}\n--- actual\n{actual}"
+    );
+}
+
+/// Run `lyso args` in the test data directory, check its exit code and output
+fn golden_with_code(name: &str, code: i32, args: &[&str]) {
+    let out = run_in(Path::new(TEST_DATA), args);
+    check(&format!("{name}.stdout"), &normalize(&out.stdout));
+    check(&format!("{name}.stderr"), &normalize(&out.stderr));
+    assert_eq!(out.status.code(), Some(code), "exit code of lyso {args:?}");
+}
+
+fn golden(name: &str, args: &[&str]) {
+    golden_with_code(name, 0, args)
+}
+
+#[test]
+fn test_view() {
+    golden("view_fastq", &["view", "small.fastq"]);
+    golden("view_fasta", &["view", "getfasta.fa"]);
+    golden(
+        "view_fasta_tsv",
+        &["view", "--output-mode", "tsv", "masked.fa"],
+    );
+    golden("view_bam", &["view", "stats.bam"]);
+    golden(
+        "view_bam_tsv",
+        &["view", "--output-mode", "tsv", "stats.bam"],
+    );
+    golden(
+        "view_bam_summary",
+        &["view", "--output-mode", "summary", "stats.bam"],
+    );
+    golden("view_bam_flags", &["view", "-F", "3332", "stats.bam"]);
+    // unaligned BAM has no references at all
+    golden("view_unaligned_bam", &["view", "unaligned.bam"]);
+    golden("view_bam_header", &["view", "-H", "bwa_h500.bam"]);
+    golden_with_code("view_fastq_header", 1, &["view", "-H", "small.fastq"]);
+    // aux fields come out in file order
+    golden("head_bam_aux", &["head", "-n", "3", "bwa_h500.bam"]);
+}
+
+#[test]
+fn test_view_tags() {
+    // r5 is a duplicate with NM 0; r8, r11 and r12 have no NM
+    golden("view_bam_tag", &["view", "--tag", "NM:le:2", "stats.bam"]);
+    golden(
+        "view_bam_tag_missing_pass",
+        &[
+            "view",
+            "--tag",
+            "NM:le:2",
+            "--missing-tag",
+            "pass",
+            "-F",
+            "DUP",
+            "stats.bam",
+        ],
+    );
+    golden(
+        "view_bam_tag_string",
+        &[
+            "view",
+            "--tag",
+            "RG:eq:run1",
+            "--require-tag-missing",
+            "SA",
+            "unaligned.bam",
+        ],
+    );
+    golden(
+        "view_bam_require_tag",
+        &[
+            "view",
+            "-f",
+            "SECONDARY",
+            "--require-tag",
+            "NM",
+            "--tag",
+            "NM:ge:3",
+            "stats.bam",
+        ],
+    );
+    golden_with_code(
+        "view_bam_tag_bad",
+        2,
+        &["view", "--tag", "NM:lt:2", "stats.bam"],
+    );
+    golden_with_code(
+        "view_fasta_tag",
+        1,
+        &["view", "--tag", "NM:le:2", "getfasta.fa"],
+    );
+}
+
+/// Every subcommand that reads BAM, with the arguments before and after the
+/// input
+///
+/// Run in a directory holding `ref.fa`, which matches header_only.bam.
+const BAM_COMMANDS: &[(&[&str], &[&str])] = &[
+    (&["view"], &[]),
+    (&["view", "-H"], &[]),
+    (&["view", "--tag", "NM:le:2"], &[]),
+    (&["view", "--output-mode", "summary"], &[]),
+    (&["head"], &[]),
+    (&["tail"], &[]),
+    (&["range", "--from", "1", "--count", "2"], &[]),
+    (&["stats"], &[]),
+    (&["stats", "--json"], &[]),
+    (&["inspect"], &[]),
+    (&["inspect", "--json"], &[]),
+    (&["count"], &[]),
+    (&["count", "--json"], &[]),
+    (&["check"], &[]),
+    (&["isize"], &[]),
+    (&["isize", "--json"], &[]),
+    (&["bam2fq"], &[]),
+    (&["split-rg", "--out-dir", "split"], &[]),
+    (&["validate-ref"], &["ref.fa"]),
+    (&["diff"], &["header_only.bam"]),
+    (&["coverage"], &[]),
+    (&["coverage", "--json"], &[]),
+    (&["mismatches"], &[]),
+    (&["consensus"], &["ref.fa"]),
+];
+
+#[test]
+fn test_empty_bam() {
+    let dir = tempfile::tempdir().unwrap();
+    for bam in ["header_only.bam", "empty.bam"] {
+        std::fs::copy(Path::new(TEST_DATA).join(bam), dir.path().join(bam)).unwrap();
+    }
+    let chr1 = format!(">chr1\n{}\n", "A".repeat(1000));
+    std::fs::write(
+        dir.path().join("ref.fa"),
+        chr1 + ">chr2\n" + &"C".repeat(500),
+    )
+    .unwrap();
+    for (before, after) in BAM_COMMANDS {
+        let run = |input: &'static str| {
+            let args = [before, &[input][..], after].concat();
+            (run_in(dir.path(), &args), args)
+        };
+
+        // a header and no alignments is valid everywhere
+        let (out, args) = run("header_only.bam");
+        let stderr = normalize(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "lyso {args:?}: {stderr}");
+        assert!(!stderr.contains("panicked"), "lyso {args:?}: {stderr}");
+
+        // a zero-byte file is an error naming it; diff's errors exit 2
+        let (out, args) = run("empty.bam");
+        let code = if args[0] == "diff" { 2 } else { 1 };
+        assert_eq!(out.status.code(), Some(code), "exit code of lyso {args:?}");
+        assert_eq!(
+            normalize(&out.stderr),
+            "empty.bam: empty file\n",
+            "lyso {args:?}"
+        );
+        assert!(out.stdout.is_empty(), "lyso {args:?}");
+    }
+
+    // reports come out zeroed
+    golden("stats_header_only", &["stats", "header_only.bam"]);
+    golden("coverage_header_only", &["coverage", "header_only.bam"]);
+    golden("check_header_only", &["check", "header_only.bam"]);
+}
+
+#[test]
+fn test_empty_text() {
+    let dir = tempfile::tempdir().unwrap();
+    for file in ["empty.fa", "empty.fastq", "empty.sam"] {
+        std::fs::write(dir.path().join(file), b"").unwrap();
+        for cmd in ["view", "stats", "inspect", "count", "check", "head", "tail"] {
+            let out = run_in(dir.path(), &[cmd, file]);
+            assert_eq!(out.status.code(), Some(1), "exit code of lyso {cmd} {file}");
+            assert_eq!(
+                normalize(&out.stderr),
+                format!("{file}: empty file\n"),
+                "lyso {cmd} {file}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_passthrough() {
+    for fixture in [
+        "crlf.fastq",
+        "no_final_newline.fastq",
+        "small.fastq",
+        "crlf.fa",
+        "no_final_newline.fa",
+        "getfasta.fa",
+    ] {
+        let input = std::fs::read(Path::new(TEST_DATA).join(fixture)).unwrap();
+        for args in [
+            &["view", fixture][..],
+            &["head", "-n", "1000", fixture],
+            &["tail", "-n", "1000", fixture],
+            &["filter", "--min-length", "0", fixture],
+            &["filter", "--threads", "3", "--min-length", "0", fixture],
+        ] {
+            let out = run_in(Path::new(TEST_DATA), args);
+            assert!(out.status.success(), "lyso {args:?}");
+            assert!(out.stdout == input, "lyso {args:?} changed the input");
+        }
+    }
+}
+
+#[test]
+fn test_faidx() {
+    golden(
+        "faidx_regions",
+        &["faidx", "getfasta.fa", "chr1:3-12", "chr3"],
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    for (input, golden) in [
+        ("small.fastq", "faidx_index.fai"),
+        ("getfasta.fa", "faidx_fasta_index.fai"),
+    ] {
+        std::fs::copy(Path::new(TEST_DATA).join(input), dir.path().join(input)).unwrap();
+        for args in [
+            &["faidx", input][..],
+            &["faidx", "--light", "--force", input],
+        ] {
+            let out = run_in(dir.path(), args);
+            assert!(out.status.success(), "{}", normalize(&out.stderr));
+            let fai = std::fs::read(dir.path().join(format!("{input}.fai"))).unwrap();
+            check(golden, &normalize(&fai));
+        }
+        let out = run_in(dir.path(), &["faidx", input]);
+        assert_eq!(out.status.code(), Some(1));
+        assert_eq!(
+            normalize(&out.stderr),
+            format!("{input}.fai: already exists, pass --force to replace it\n")
+        );
+    }
+
+    // a repeated name leaves no index behind
+    std::fs::write(dir.path().join("dup.fq"), "@a\nAC\n+\nII\n@a\nA\n+\nI\n").unwrap();
+    let out = run_in(dir.path(), &["faidx", "--light", "dup.fq"]);
+    assert_eq!(
+        normalize(&out.stderr),
+        "dup.fq: duplicate record name a at offsets 3 and 14\n"
+    );
+    assert!(!dir.path().join("dup.fq.fai").exists());
+}
+
+#[test]
+fn test_faidx_stale_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let fa = dir.path().join("ref.fa");
+    std::fs::copy(Path::new(TEST_DATA).join("getfasta.fa"), &fa).unwrap();
+    assert!(run_in(dir.path(), &["faidx", "ref.fa"]).status.success());
+    assert!(dir.path().join("ref.fa.fai.meta").exists());
+
+    let mut f = std::fs::OpenOptions::new().append(true).open(&fa).unwrap();
+    std::io::Write::write_all(&mut f, b">new\nACGTACGT\n").unwrap();
+    std::fs::write(dir.path().join("empty.bed"), b"").unwrap();
+    // a reference small enough to load into memory still has its index checked
+    let out = run_in(dir.path(), &["getfasta", "ref.fa", "empty.bed"]);
+    assert_eq!(out.status.code(), Some(1));
+    let out = run_in(dir.path(), &["faidx", "ref.fa", "new:2-5"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        normalize(&out.stderr),
+        "ref.fa.fai: index is stale: the file's size changed since it was built; \
+         rerun with --rebuild-if-stale or `lyso faidx --force ref.fa`\n"
+    );
+
+    let out = run_in(
+        dir.path(),
+        &["faidx", "--rebuild-if-stale", "ref.fa", "new:2-5"],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert_eq!(normalize(&out.stdout), ">new:2-5\nCGTA\n");
+    // the rewritten index is current
+    let out = run_in(dir.path(), &["getfasta", "ref.fa", "empty.bed"]);
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+
+    std::io::Write::write_all(&mut f, b">newer\nAC\n").unwrap();
+    let out = run_in(
+        dir.path(),
+        &["getfasta", "--rebuild-if-stale", "ref.fa", "empty.bed"],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let out = run_in(dir.path(), &["faidx", "ref.fa", "newer"]);
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert_eq!(normalize(&out.stdout), ">newer\nAC\n");
+}
+
+#[test]
+fn test_clean_asm() {
+    golden(
+        "clean_asm",
+        &[
+            "clean-asm",
+            "contigs.fa",
+            "--min-len",
+            "50",
+            "--sort-by-length",
+            "--rename",
+            "contig_{n}",
+            "--wrap",
+            "80",
+        ],
+    );
+    golden_with_code("clean_asm_fastq", 1, &["clean-asm", "test.fastq"]);
+
+    // the map lists the written contigs in output order, keeping one exactly at --min-len;
+    // an index next to the file gives the same result as one built in memory
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::copy(
+        Path::new(TEST_DATA).join("contigs.fa"),
+        dir.path().join("contigs.fa"),
+    )
+    .unwrap();
+    for faidx in [false, true] {
+        if faidx {
+            assert!(run_in(dir.path(), &["faidx", "contigs.fa"])
+                .status
+                .success());
+        }
+        let args = [
+            "clean-asm",
+            "contigs.fa",
+            "--min-len",
+            "50",
+            "--sort-by-length",
+            "--rename",
+            "ctg_{n}_{id}",
+            "--map",
+            "names.tsv",
+            "-o",
+            "clean.fa",
+            "--force",
+        ];
+        let out = run_in(dir.path(), &args);
+        assert!(out.status.success(), "{}", normalize(&out.stderr));
+        let map = std::fs::read_to_string(dir.path().join("names.tsv")).unwrap();
+        check("clean_asm_map.tsv", &map);
+        let fa = std::fs::read_to_string(dir.path().join("clean.fa")).unwrap();
+        let headers = fa
+            .lines()
+            .filter_map(|l| l.strip_prefix('>'))
+            .collect::<Vec<_>>();
+        let mapped = map
+            .lines()
+            .map(|l| l.split('\t').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(headers, mapped);
+        assert!(map.contains("ctg1\tctg_5_ctg1\t50\n"));
+    }
+}
+
+#[test]
+fn test_reorder() {
+    golden(
+        "reorder_length",
+        &["reorder", "reorder.fa", "--by", "length"],
+    );
+    golden(
+        "reorder_natural_wrap",
+        &["reorder", "reorder.fa", "--by", "natural", "--wrap", "8"],
+    );
+    golden_with_code("reorder_fastq", 1, &["reorder", "test.fastq"]);
+
+    // file order copies the file byte for byte
+    let out = run_in(Path::new(TEST_DATA), &["reorder", "reorder.fa"]);
+    assert!(out.status.success());
+    let original = std::fs::read(Path::new(TEST_DATA).join("reorder.fa")).unwrap();
+    assert_eq!(out.stdout, original);
+}
+
+#[test]
+fn test_scaffold2contig() {
+    let dir = tempfile::tempdir().unwrap();
+    let fa = Path::new(TEST_DATA).join("scaffolds.fa");
+    let args = [
+        "scaffold2contig",
+        fa.to_str().unwrap(),
+        "--min-gap",
+        "10",
+        "--layout",
+        "layout.tsv",
+    ];
+    let out = run_in(dir.path(), &args);
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let contigs = normalize(&out.stdout);
+    check("scaffold2contig.stdout", &contigs);
+    let layout = std::fs::read_to_string(dir.path().join("layout.tsv")).unwrap();
+    check("scaffold2contig_layout.tsv", &layout);
+
+    // the contigs put back with N for each gap, as the layout says, are the scaffolds
+    let mut seqs = std::collections::HashMap::new();
+    for rec in contigs.split('>').skip(1) {
+        let (id, seq) = rec.split_once('\n').unwrap();
+        seqs.insert(id.to_string(), seq.replace('\n', ""));
+    }
+    let mut rebuilt: Vec<(String, String)> = Vec::new();
+    for row in layout.lines().skip(1) {
+        let cols = row.split('\t').collect::<Vec<_>>();
+        let (start, end): (usize, usize) = (cols[1].parse().unwrap(), cols[2].parse().unwrap());
+        if rebuilt.last().is_none_or(|(s, _)| s != cols[0]) {
+            rebuilt.push((cols[0].to_string(), String::new()));
+        }
+        let seq = &mut rebuilt.last_mut().unwrap().1;
+        assert_eq!(seq.len() + 1, start, "{row}");
+        match (cols[3], cols[4]) {
+            (".", gap) => seq.push_str(&"N".repeat(gap.parse().unwrap())),
+            (contig, ".") => seq.push_str(&seqs.remove(contig).unwrap()),
+            _ => panic!("{row}"),
+        }
+        assert_eq!(seq.len(), end, "{row}");
+    }
+    assert!(seqs.is_empty());
+    let original = std::fs::read_to_string(&fa).unwrap();
+    let scaffolds = original
+        .split('>')
+        .skip(1)
+        .map(|rec| {
+            let (header, seq) = rec.split_once('\n').unwrap();
+            let name = header.split_whitespace().next().unwrap().to_string();
+            (name, seq.replace('\n', "").replace('n', "N"))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(rebuilt, scaffolds);
+}
+
+#[test]
+fn test_simulate() {
+    let args = [
+        "simulate",
+        "--reads",
+        "4",
+        "--length",
+        "30",
+        "--gc",
+        "0.42",
+        "--quality",
+        "38:20",
+        "--seed",
+        "7",
+    ];
+    golden("simulate", &args);
+    golden_with_code("simulate_bad_gc", 2, &["simulate", "--gc", "1.5"]);
+
+    // -o writes what stdout gets, and the reads parse back
+    let dir = tempfile::tempdir().unwrap();
+    let to_stdout = run_in(dir.path(), &args);
+    let out = run_in(dir.path(), &[&args[..], &["-o", "sim.fq"]].concat());
+    assert!(out.status.success() && out.stdout.is_empty());
+    let written = std::fs::read(dir.path().join("sim.fq")).unwrap();
+    assert_eq!(written, to_stdout.stdout);
+    let count = run_in(dir.path(), &["count", "sim.fq"]);
+    assert!(count.status.success(), "{}", normalize(&count.stderr));
+
+    // a FASTA reference, and reads sampled from it
+    let reference = [
+        "simulate", "--fasta", "--reads", "2", "--length", "130", "--seed", "3",
+    ];
+    golden("simulate_fasta", &reference);
+    let out = run_in(dir.path(), &[&reference[..], &["-o", "ref.fa"]].concat());
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let sample = [
+        "simulate",
+        "--reference",
+        "ref.fa",
+        "--subs",
+        "0.1",
+        "--reads",
+        "3",
+        "--length",
+        "20",
+        "--seed",
+        "1",
+    ];
+    let sampled = run_in(dir.path(), &sample);
+    assert!(sampled.status.success(), "{}", normalize(&sampled.stderr));
+    check("simulate_sampled.stdout", &normalize(&sampled.stdout));
+    // error-free reads are the region their description names
+    let out = run_in(
+        dir.path(),
+        &[
+            "simulate",
+            "--reference",
+            "ref.fa",
+            "--fasta",
+            "--reads",
+            "5",
+            "--length",
+            "70",
+        ],
+    );
+    let text = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(text.matches('>').count(), 5, "{text}");
+    for read in text.split('>').skip(1) {
+        let (header, seq) = read.split_once('\n').unwrap();
+        let region = header.split(' ').nth(1).unwrap();
+        assert!(header.ends_with(" subs=0"), "{header}");
+        let view = run_in(dir.path(), &["view", "ref.fa", region]);
+        let fetched = String::from_utf8(view.stdout).unwrap();
+        assert_eq!(
+            fetched.lines().skip(1).collect::<String>(),
+            seq.lines().collect::<String>(),
+            "{header}"
+        );
+    }
+    golden_with_code(
+        "simulate_subs_without_reference",
+        2,
+        &["simulate", "--subs", "0.1"],
+    );
+    golden_with_code("simulate_zero_length", 2, &["simulate", "--length", "0"]);
+    // bases other than ACGT, even non-ASCII ones, come out as N
+    std::fs::write(dir.path().join("odd.fa"), ">odd\nACGT\u{e9}\n").unwrap();
+    let out = run_in(
+        dir.path(),
+        &[
+            "simulate",
+            "--reference",
+            "odd.fa",
+            "--length",
+            "5",
+            "--reads",
+            "3",
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let text = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(text.lines().filter(|l| *l == "ACGTN").count(), 3, "{text}");
+}
+
+#[test]
+fn test_concat() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::copy(
+        Path::new(TEST_DATA).join("getfasta.fa"),
+        dir.path().join("ref.fa"),
+    )
+    .unwrap();
+    let out = run_in(
+        dir.path(),
+        &[
+            "concat",
+            "ref.fa",
+            "--spacer",
+            "3",
+            "--map",
+            "ref.offsets.tsv",
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    check("concat.stdout", &normalize(&out.stdout));
+    let map = std::fs::read(dir.path().join("ref.offsets.tsv")).unwrap();
+    check("concat_map.tsv", &normalize(&map));
+
+    std::fs::write(dir.path().join("dup.fa"), b">a\nAC\n>a x\nGT\n").unwrap();
+    let out = run_in(dir.path(), &["concat", "dup.fa"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(normalize(&out.stderr), "dup.fa: Duplicate record name a\n");
+}
+
+#[test]
+fn test_stats() {
+    golden("stats_fastq", &["stats", "small.fastq"]);
+    golden(
+        "stats_fastq_per_cycle",
+        &["stats", "--per-cycle", "small.fastq"],
+    );
+    golden("stats_bam", &["stats", "stats.bam"]);
+}
+
+#[test]
+fn test_json() {
+    golden("stats_fastq_json", &["stats", "--json", "small.fastq"]);
+    golden(
+        "stats_fastq_per_cycle_json",
+        &["stats", "--per-cycle", "--json", "ambiguous_qual.fastq"],
+    );
+    golden("stats_bam_json", &["stats", "--json", "stats.bam"]);
+    golden("inspect_bam_json", &["inspect", "--json", "stats.bam"]);
+    golden(
+        "inspect_fastq_estimated_json",
+        &["inspect", "--json", "--sample", "2", "small.fastq"],
+    );
+    golden_with_code(
+        "inspect_corrupt_json",
+        1,
+        &["inspect", "--json", "corrupt.fastq"],
+    );
+    golden("count_json", &["count", "--json", "small.fastq"]);
+    golden("isize_json", &["isize", "--json", "name_sorted.bam"]);
+    golden("coverage_json", &["coverage", "--json", "coverage.bam"]);
+}
+
+/// The schema version pipelines check; changing it must be deliberate
+const SCHEMA_VERSION: u64 = 1;
+
+#[test]
+fn test_json_schema_version() {
+    let commands: [&[&str]; 7] = [
+        &["stats", "--json", "small.fastq"],
+        &["stats", "--json", "--per-cycle", "small.fastq"],
+        &["stats", "--json", "stats.bam"],
+        &["inspect", "--json", "getfasta.fa"],
+        &["count", "--json", "getfasta.fa"],
+        &["isize", "--json", "name_sorted.bam"],
+        &["coverage", "--json", "coverage.bam"],
+    ];
+    for args in commands {
+        let out = run_in(Path::new(TEST_DATA), args);
+        assert!(out.status.success(), "{}", normalize(&out.stderr));
+        let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(
+            report["lyso_schema_version"].as_u64(),
+            Some(SCHEMA_VERSION),
+            "lyso {args:?}"
+        );
+    }
+}
+
+#[test]
+fn test_check() {
+    golden("check_clean", &["check", "stats.bam"]);
+    golden_with_code("check_warnings", 1, &["check", "check_warnings.bam"]);
+    golden(
+        "check_warnings_allowed",
+        &["check", "--max-warnings", "7", "check_warnings.bam"],
+    );
+    golden_with_code(
+        "check_fastq_rules",
+        1,
+        &[
+            "check",
+            "--max-homopolymer",
+            "30",
+            "--max-warnings",
+            "2",
+            "check_rules.fq",
+        ],
+    );
+    golden("check_fasta", &["check", "getfasta.fa"]);
+}
+
+#[test]
+fn test_requal() {
+    // phred64.phred33.fastq shifts every quality character down by 31
+    let out = run_in(Path::new(TEST_DATA), &["requal", "phred64.fastq"]);
+    assert!(out.status.success());
+    let expected = std::fs::read(Path::new(TEST_DATA).join("phred64.phred33.fastq")).unwrap();
+    assert!(out.stdout == expected, "{}", normalize(&out.stdout));
+
+    golden_with_code("requal_ambiguous", 1, &["requal", "ambiguous_qual.fastq"]);
+    golden(
+        "requal_ambiguous_forced",
+        &["requal", "--force", "ambiguous_qual.fastq"],
+    );
+    golden_with_code(
+        "requal_wrong_encoding",
+        1,
+        &["requal", "--from", "phred64", "small.fastq"],
+    );
+}
+
+#[test]
+fn test_inspect() {
+    golden("inspect_fastq", &["inspect", "small.fastq"]);
+    golden(
+        "inspect_fastq_estimated",
+        &["inspect", "--sample", "2", "small.fastq"],
+    );
+    golden("inspect_fasta", &["inspect", "getfasta.fa"]);
+    golden("inspect_bam", &["inspect", "stats.bam"]);
+    golden_with_code("inspect_corrupt", 1, &["inspect", "corrupt.fastq"]);
+
+    // an index older than its data is reported as stale
+    let dir = tempfile::tempdir().unwrap();
+    for file in ["test.fastq", "test.fastq.fai"] {
+        std::fs::copy(Path::new(TEST_DATA).join(file), dir.path().join(file)).unwrap();
+    }
+    std::fs::File::options()
+        .write(true)
+        .open(dir.path().join("test.fastq.fai"))
+        .unwrap()
+        .set_modified(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let out = run_in(dir.path(), &["inspect", "--sample", "5", "test.fastq"]);
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    check("inspect_stale_index.stdout", &normalize(&out.stdout));
+}
+
+#[test]
+fn test_count() {
+    golden("count_fastq", &["count", "test.fastq"]);
+    golden("count_fasta", &["count", "getfasta.fa"]);
+    golden("count_bam", &["count", "stats.bam"]);
+    golden("count_corrupt_bam", &["count", "corrupt_record.bam"]);
+    golden_with_code("count_corrupt_fasta", 1, &["count", "corrupt.fa"]);
+
+    // with an index, its entries are counted and the records are not read
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::copy(
+        Path::new(TEST_DATA).join("test.fastq"),
+        dir.path().join("test.fastq"),
+    )
+    .unwrap();
+    let fai = std::fs::read_to_string(Path::new(TEST_DATA).join("test.fastq.fai")).unwrap();
+    let first: String = fai.lines().take(3).map(|l| format!("{l}\n")).collect();
+    std::fs::write(dir.path().join("test.fastq.fai"), first).unwrap();
+    let out = run_in(dir.path(), &["count", "test.fastq"]);
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert_eq!(normalize(&out.stdout), "3\n");
+}
+
+#[test]
+fn test_isize() {
+    golden("isize", &["isize", "name_sorted.bam"]);
+    golden_with_code("isize_not_bam", 1, &["isize", "test.fastq"]);
+}
+
+#[test]
+fn test_bam2fq() {
+    golden("bam2fq", &["bam2fq", "name_sorted.bam"]);
+    golden(
+        "bam2fq_secondary",
+        &["bam2fq", "--include-secondary", "-n", "name_sorted.bam"],
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let bam = Path::new(TEST_DATA).join("name_sorted.bam");
+    let out = run_in(
+        dir.path(),
+        &[
+            "bam2fq",
+            "-1",
+            "r1.fq",
+            "-2",
+            "r2.fq",
+            "-s",
+            "singletons.fq",
+            "-0",
+            "unpaired.fq",
+            bam.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert!(out.stdout.is_empty() && out.stderr.is_empty());
+    for file in ["r1.fq", "r2.fq", "singletons.fq", "unpaired.fq"] {
+        let fq = std::fs::read(dir.path().join(file)).unwrap();
+        check(&format!("bam2fq_{file}"), &normalize(&fq));
+    }
+
+    // as in samtools fastq, singletons without -s go with the mates, and unpaired
+    // reads without -0 to stdout
+    let out = run_in(
+        dir.path(),
+        &[
+            "bam2fq",
+            "-1",
+            "m1.fq",
+            "-2",
+            "m2.fq",
+            bam.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert_eq!(
+        normalize(&out.stdout),
+        "@singleC\nACGTACGTAC\n+\nFFFFFFFFFF\n"
+    );
+    let r1 = std::fs::read_to_string(dir.path().join("m1.fq")).unwrap();
+    assert!(r1.contains("@orphanD\nACGTACGTAC\n+\nFFFFFFFFFF\n"), "{r1}");
+
+    // .gz names are written as BGZF, which any gzip reader can read
+    let out = run_in(
+        dir.path(),
+        &[
+            "bam2fq",
+            "--threads",
+            "2",
+            "-1",
+            "r1.fq.gz",
+            "-2",
+            "r2.fq.gz",
+            "-s",
+            "singletons.fq.gz",
+            "-0",
+            "unpaired.fq.gz",
+            bam.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    for file in ["r1.fq", "r2.fq", "singletons.fq", "unpaired.fq"] {
+        let gz = std::fs::File::open(dir.path().join(format!("{file}.gz"))).unwrap();
+        let mut fq = Vec::new();
+        flate2::read::MultiGzDecoder::new(gz)
+            .read_to_end(&mut fq)
+            .unwrap();
+        assert_eq!(fq, std::fs::read(dir.path().join(file)).unwrap(), "{file}");
+    }
+}
+
+#[test]
+fn test_fq2bam() {
+    let dir = tempfile::tempdir().unwrap();
+    let fq = Path::new(TEST_DATA).join("test.fastq");
+    let out = run_in(
+        dir.path(),
+        &[
+            "fq2bam",
+            fq.to_str().unwrap(),
+            "-o",
+            "reads.bam",
+            "--rg-id",
+            "S1",
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert!(out.stdout.is_empty() && out.stderr.is_empty());
+    let out = run_in(dir.path(), &["view", "-H", "reads.bam"]);
+    assert_eq!(
+        normalize(&out.stdout),
+        "@HD\tVN:1.6\tSO:unknown\n@RG\tID:S1\n"
+    );
+
+    // back to FASTQ, the reads are as they were apart from their descriptions
+    let out = run_in(dir.path(), &["bam2fq", "-0", "back.fq", "reads.bam"]);
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let expected = std::fs::read_to_string(&fq)
+        .unwrap()
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match i % 4 {
+            0 => line.split(' ').next().unwrap().to_string(),
+            2 => "+".to_string(),
+            _ => line.to_string(),
+        } + "\n")
+        .collect::<String>();
+    let back = std::fs::read(dir.path().join("back.fq")).unwrap();
+    assert_eq!(normalize(&back), expected);
+
+    std::fs::write(
+        dir.path().join("barcoded.fq"),
+        "@r1 BC:Z:ACGT RX:Z:TTG 1:N:0\nacgt\n+\nIIII\n@r2\nNN\n+\n!!\n",
+    )
+    .unwrap();
+    let out = run_in(
+        dir.path(),
+        &["fq2bam", "--desc", "tags", "barcoded.fq", "-o", "tags.bam"],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let out = run_in(dir.path(), &["view", "tags.bam"]);
+    check("fq2bam_tags.stdout", &normalize(&out.stdout));
+
+    std::fs::write(dir.path().join("bad.fq"), "@r1\nAC\n+\nI \n").unwrap();
+    let out = run_in(dir.path(), &["fq2bam", "bad.fq", "-o", "bad.bam"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        normalize(&out.stderr),
+        "bad.fq: read r1 has quality ' ', outside Phred+33\n"
+    );
+}
+
+#[test]
+fn test_atomic_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    // the last read cannot be stored, after many have been written
+    let mut fq = (0..20_000)
+        .map(|i| format!("@r{i}\nACGTACGTACGTACGTACGT\n+\nIIIIIIIIIIIIIIIIIIII\n"))
+        .collect::<String>();
+    fq.push_str("@bad\nAC.T\n+\nIIII\n");
+    std::fs::write(dir.path().join("reads.fq"), fq).unwrap();
+    let fq2bam = |args: &[&str]| run_in(dir.path(), &[&["fq2bam", "reads.fq"][..], args].concat());
+    let files = || {
+        let mut names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    // a failed run leaves neither the output nor its temporary file
+    let out = fq2bam(&["-o", "reads.bam"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        normalize(&out.stderr),
+        "reads.fq: read bad has base '.', which BAM cannot store\n"
+    );
+    assert_eq!(files(), ["reads.fq"]);
+
+    // an existing output is refused, and kept whole when --force fails
+    std::fs::write(dir.path().join("reads.bam"), "old").unwrap();
+    let out = fq2bam(&["-o", "reads.bam"]);
+    assert_eq!(
+        normalize(&out.stderr),
+        "reads.bam: already exists, pass --force to replace it\n"
+    );
+    let out = fq2bam(&["-o", "reads.bam", "--force"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(std::fs::read(dir.path().join("reads.bam")).unwrap(), b"old");
+    assert_eq!(files(), ["reads.bam", "reads.fq"]);
+
+    // demux checks every output before writing any
+    std::fs::write(dir.path().join("sheet.tsv"), "S1\tACGT\n").unwrap();
+    std::fs::create_dir(dir.path().join("demux")).unwrap();
+    std::fs::write(dir.path().join("demux/unmatched.fastq"), "old\n").unwrap();
+    let demux = |force: &[&str]| {
+        let args = [
+            &[
+                "demux",
+                "reads.fq",
+                "--barcodes",
+                "sheet.tsv",
+                "--out-dir",
+                "demux",
+            ][..],
+            force,
+        ];
+        run_in(dir.path(), &args.concat())
+    };
+    let out = demux(&[]);
+    assert_eq!(
+        normalize(&out.stderr),
+        "demux/unmatched.fastq: already exists, pass --force to replace it\n"
+    );
+    let out = demux(&["--force"]);
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let outputs = std::fs::read_dir(dir.path().join("demux"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect::<std::collections::BTreeSet<_>>();
+    assert_eq!(
+        outputs.into_iter().collect::<Vec<_>>(),
+        [
+            "S1.fastq",
+            "ambiguous.fastq",
+            "summary.tsv",
+            "unmatched.fastq"
+        ]
+    );
+}
+
+#[test]
+fn test_merge_pairs() {
+    golden(
+        "merge_pairs",
+        &[
+            "merge-pairs",
+            "-1",
+            "merge_R1.fq",
+            "-2",
+            "merge_R2.fq",
+            "--min-overlap",
+            "12",
+        ],
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let [r1, r2] = ["merge_R1.fq", "merge_R2.fq"].map(|f| Path::new(TEST_DATA).join(f));
+    let out = run_in(
+        dir.path(),
+        &[
+            "merge-pairs",
+            "-1",
+            r1.to_str().unwrap(),
+            "-2",
+            r2.to_str().unwrap(),
+            "-o",
+            "merged.fq",
+            "--unmerged1",
+            "u1.fq",
+            "--unmerged2",
+            "u2.fq",
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert_eq!(
+        normalize(&out.stderr),
+        "merged 3 of 4 pairs, 1 with adapter trimmed\n"
+    );
+    let merged = std::fs::read(dir.path().join("merged.fq")).unwrap();
+    check("merge_pairs.stdout", &normalize(&merged));
+    for (file, input) in [("u1.fq", &r1), ("u2.fq", &r2)] {
+        let unmerged = std::fs::read_to_string(dir.path().join(file)).unwrap();
+        let last = std::fs::read_to_string(input).unwrap();
+        let last = last.lines().skip(12).collect::<Vec<_>>().join("\n") + "\n";
+        assert_eq!(unmerged, last, "{file}");
+    }
+}
+
+#[test]
+fn test_filter() {
+    golden(
+        "filter",
+        &[
+            "filter",
+            "--min-length",
+            "100",
+            "--min-mean-qual",
+            "30",
+            "small.fastq",
+        ],
+    );
+    golden(
+        "filter_low_complexity",
+        &["filter", "--low-complexity", "0.5", "getfasta.fa"],
+    );
+
+    // FASTQ parsed on worker threads comes out the same, errors included
+    golden(
+        "filter",
+        &[
+            "filter",
+            "--threads",
+            "3",
+            "--min-length",
+            "100",
+            "--min-mean-qual",
+            "30",
+            "small.fastq",
+        ],
+    );
+    for fixture in ["test.fastq", "corrupt.fastq", "trunc.fastq"] {
+        let args = ["filter", "--min-length", "10", fixture];
+        let serial = run_in(Path::new(TEST_DATA), &args);
+        let threaded = run_in(
+            Path::new(TEST_DATA),
+            &[&args[..], &["--threads", "4"]].concat(),
+        );
+        assert_eq!(serial.stdout, threaded.stdout, "{fixture}");
+        assert_eq!(serial.stderr, threaded.stderr, "{fixture}");
+        assert_eq!(serial.status.code(), threaded.status.code(), "{fixture}");
+    }
+}
+
+#[test]
+fn test_seq_edits() {
+    golden(
+        "view_seq_edits_fasta",
+        &[
+            "view",
+            "--upper",
+            "--replace-n",
+            "random",
+            "--seed",
+            "7",
+            "masked.fa",
+        ],
+    );
+    golden(
+        "view_seq_edits_fastq",
+        &["view", "--lower", "--replace-n", "A", "mixed_iupac.fastq"],
+    );
+    golden(
+        "view_seq_edits_fetch",
+        &[
+            "view",
+            "masked.fa",
+            "chr1:1-8",
+            "--upper",
+            "--replace-n",
+            "C",
+        ],
+    );
+    golden_with_code(
+        "view_seq_edits_bam",
+        1,
+        &["view", "--upper", "bwa_h500.bam"],
+    );
+    golden(
+        "filter_seq_edits",
+        &[
+            "filter",
+            "--min-length",
+            "5",
+            "--upper",
+            "mixed_iupac.fastq",
+        ],
+    );
+
+    // random bases are drawn in input order, however many threads parse
+    let args = [
+        "filter",
+        "--replace-n",
+        "random",
+        "--seed",
+        "3",
+        "mixed_iupac.fastq",
+    ];
+    let serial = run_in(Path::new(TEST_DATA), &args);
+    let threaded = run_in(
+        Path::new(TEST_DATA),
+        &[&args[..], &["--threads", "4"]].concat(),
+    );
+    assert!(serial.status.success(), "{}", normalize(&serial.stderr));
+    assert_eq!(serial.stdout, threaded.stdout);
+}
+
+#[test]
+fn test_mismatches() {
+    golden("mismatches", &["mismatches", "mismatches.bam"]);
+    golden("mismatches_no_md", &["mismatches", "stats.bam"]);
+}
+
+#[test]
+fn test_coverage() {
+    golden("coverage", &["coverage", "coverage.bam"]);
+    // chrX is not in the header
+    let targets = [
+        "coverage",
+        "--targets",
+        "coverage_targets.bed",
+        "coverage.bam",
+    ];
+    golden("coverage_targets", &targets);
+    golden(
+        "coverage_targets_json",
+        &[&targets[..], &["--json"]].concat(),
+    );
+}
+
+#[test]
+fn test_consensus() {
+    golden(
+        "consensus",
+        &["consensus", "consensus.bam", "consensus_ref.fa"],
+    );
+}
+
+#[test]
+fn test_getfasta() {
+    // chrX is missing from the reference, which fails the run after writing the rest
+    golden_with_code("getfasta", 1, &["getfasta", "getfasta.fa", "getfasta.bed"]);
+}
+
+#[test]
+fn test_mask() {
+    golden("mask", &["mask", "getfasta.fa", "getfasta.bed"]);
+    golden(
+        "mask_hard",
+        &["mask", "--hard", "getfasta.fa", "getfasta.bed"],
+    );
+}
+
+#[test]
+fn test_maskstats() {
+    golden("maskstats", &["maskstats", "masked.fa"]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let bed = dir.path().join("masked.bed");
+    let out = run_in(
+        Path::new(TEST_DATA),
+        &[
+            "maskstats",
+            "--bed",
+            bed.to_str().unwrap(),
+            "--min-run",
+            "2",
+            "masked.fa",
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    check("maskstats.bed", &normalize(&std::fs::read(bed).unwrap()));
+}
+
+#[test]
+fn test_windows() {
+    golden(
+        "windows",
+        &["windows", "--size", "5", "--step", "3", "masked.fa"],
+    );
+    golden(
+        "windows_gc",
+        &[
+            "windows",
+            "--size",
+            "8",
+            "--gc",
+            "--skip-partial",
+            "masked.fa",
+        ],
+    );
+}
+
+#[test]
+fn test_head_tail_range() {
+    golden("head", &["head", "-n", "2", "small.fastq"]);
+    golden("head_bytes", &["head", "--bytes", "1K", "small.fastq"]);
+    golden(
+        "head_bytes_fasta",
+        &["head", "--bytes", "100", "-n", "1", "getfasta.fa"],
+    );
+    golden("tail", &["tail", "-n", "2", "getfasta.fa"]);
+    golden("tail_bam", &["tail", "-n", "1", "stats.bam"]);
+    golden(
+        "range",
+        &["range", "--from", "1", "--count", "2", "small.fastq"],
+    );
+}
+
+#[test]
+fn test_rename() {
+    golden(
+        "rename",
+        &[
+            "rename",
+            "--template",
+            "{sample}_{id}",
+            "--sample",
+            "s1",
+            "small.fastq",
+        ],
+    );
+}
+
+#[test]
+fn test_grep() {
+    golden("grep", &["grep", "--motif", "GGNC", "getfasta.fa"]);
+    golden(
+        "grep_mismatches",
+        &[
+            "grep",
+            "--motif",
+            "ACGTAC",
+            "--max-mismatches",
+            "1",
+            "getfasta.fa",
+        ],
+    );
+}
+
+#[test]
+fn test_sortseq() {
+    golden(
+        "sortseq_length",
+        &["sortseq", "--by", "length", "small.fastq"],
+    );
+    golden(
+        "sortseq_id_reverse",
+        &["sortseq", "--reverse", "getfasta.fa"],
+    );
+}
+
+#[test]
+fn test_ident() {
+    golden(
+        "ident",
+        &["ident", "--ref", "amplicon.fa", "amplicon.fastq"],
+    );
+    // the adapter is clipped rather than aligned
+    golden(
+        "ident_local",
+        &[
+            "ident",
+            "--local",
+            "--band",
+            "5",
+            "--ref",
+            "amplicon.fa",
+            "amplicon.fastq",
+        ],
+    );
+    golden_with_code(
+        "ident_ref_not_fasta",
+        1,
+        &["ident", "--ref", "small.fastq", "amplicon.fastq"],
+    );
+}
+
+#[test]
+fn test_view_fetch() {
+    golden(
+        "view_regions",
+        &["view", "getfasta.fa", "chr2:3-8", "chr1", "--id", "chr3"],
+    );
+    // regions are wrapped as their record is
+    golden("view_region_wrapped", &["view", "getfasta.fa", "chr1:3-27"]);
+    golden(
+        "view_fastq_ids",
+        &[
+            "view",
+            "test.fastq",
+            "--id",
+            "SRR22092847.2.1",
+            "--id",
+            "SRR22092847.1.1",
+        ],
+    );
+    golden_with_code(
+        "view_fetch_misses",
+        1,
+        &[
+            "view",
+            "getfasta.fa",
+            "chrX",
+            "chr1",
+            "small.fastq",
+            "chr1:900-950",
+            "--id",
+            "nope",
+        ],
+    );
+    golden_with_code(
+        "view_fastq_region",
+        1,
+        &["view", "test.fastq", "SRR22092847.1.1:1-5"],
+    );
+
+    // the same output through a .fai as through an index built in memory
+    let dir = tempfile::tempdir().unwrap();
+    for (fixture, args) in [
+        ("getfasta.fa", &["chr3", "chr1:5-12", "chr2:4"][..]),
+        (
+            "test.fastq",
+            &["--id", "SRR22092847.3.2", "--id", "SRR22092847.1.2"][..],
+        ),
+    ] {
+        std::fs::copy(Path::new(TEST_DATA).join(fixture), dir.path().join(fixture)).unwrap();
+        let view = || run_in(dir.path(), &[&["view", fixture][..], args].concat());
+        let unindexed = view();
+        assert!(unindexed.status.success(), "{fixture}");
+        assert!(
+            normalize(&unindexed.stderr).contains("no index"),
+            "{fixture}"
+        );
+        assert!(run_in(dir.path(), &["faidx", fixture]).status.success());
+        let indexed = view();
+        assert!(
+            indexed.status.success() && indexed.stderr.is_empty(),
+            "{fixture}"
+        );
+        assert_eq!(indexed.stdout, unindexed.stdout, "{fixture}");
+        assert!(!indexed.stdout.is_empty(), "{fixture}");
+    }
+}
+
+#[test]
+fn test_extract_umi() {
+    golden(
+        "extract_umi",
+        &["extract-umi", "--pattern", "8B12U", "umi_R1.fastq"],
+    );
+    golden(
+        "extract_umi_desc",
+        &[
+            "extract-umi",
+            "--casava",
+            "--pattern",
+            "2X6U",
+            "--desc",
+            "umi_R1.fastq",
+        ],
+    );
+    golden_with_code(
+        "extract_umi_short_read",
+        1,
+        &["extract-umi", "--pattern", "30B12U", "umi_R1.fastq"],
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let fixture = |name: &str| Path::new(TEST_DATA).join(name);
+    let (r1, r2) = (fixture("umi_R1.fastq"), fixture("umi_R2.fastq"));
+    let out = run_in(
+        dir.path(),
+        &[
+            "extract-umi",
+            "--casava",
+            "--pattern",
+            "4U",
+            "--read2-pattern",
+            "2X4U",
+            "-1",
+            "out_R1.fq",
+            "-2",
+            "out_R2.fq",
+            r1.to_str().unwrap(),
+            r2.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert!(out.stdout.is_empty() && out.stderr.is_empty());
+    for file in ["out_R1.fq", "out_R2.fq"] {
+        let fq = std::fs::read(dir.path().join(file)).unwrap();
+        check(&format!("extract_umi_{file}"), &normalize(&fq));
+    }
+}
+
+#[test]
+fn test_demux() {
+    let dir = tempfile::tempdir().unwrap();
+    let fixture = |name: &str| Path::new(TEST_DATA).join(name);
+    let sheet = fixture("demux_samples.tsv");
+    let (r1, r2) = (fixture("umi_R1.fastq"), fixture("umi_R2.fastq"));
+
+    // CGATGT is one mismatch from both S2 and S3
+    let out = run_in(
+        dir.path(),
+        &[
+            "demux",
+            "--barcodes",
+            sheet.to_str().unwrap(),
+            "--mismatches",
+            "1",
+            "--out-dir",
+            "paired",
+            "--max-open-files",
+            "3",
+            r1.to_str().unwrap(),
+            r2.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    assert!(out.stdout.is_empty() && out.stderr.is_empty());
+    for file in [
+        "summary.tsv",
+        "S1_R1.fastq",
+        "S1_R2.fastq",
+        "ambiguous_R2.fastq",
+    ] {
+        let written = std::fs::read(dir.path().join("paired").join(file)).unwrap();
+        check(&format!("demux_{file}"), &normalize(&written));
+    }
+    for file in ["S2_R1.fastq", "unmatched_R2.fastq"] {
+        let written = std::fs::read(dir.path().join("paired").join(file)).unwrap();
+        assert!(written.is_empty(), "{file}");
+    }
+
+    let out = run_in(
+        dir.path(),
+        &[
+            "demux",
+            "--barcodes",
+            sheet.to_str().unwrap(),
+            "--out-dir",
+            "single",
+            r1.to_str().unwrap(),
+        ],
+    );
+    assert!(out.status.success(), "{}", normalize(&out.stderr));
+    let summary = std::fs::read(dir.path().join("single/summary.tsv")).unwrap();
+    check("demux_exact_summary.tsv", &normalize(&summary));
+    let unmatched = std::fs::read(dir.path().join("single/unmatched.fastq")).unwrap();
+    assert_eq!(normalize(&unmatched).lines().count(), 8);
+
+    // mates out of step are an error, not a silent mix-up
+    let reads2 = std::fs::read_to_string(&r2).unwrap();
+    let lines = reads2.lines().collect::<Vec<_>>();
+    let swapped = [&lines[4..8], &lines[..4], &lines[8..]].concat().join("\n") + "\n";
+    let swapped_path = dir.path().join("swapped_R2.fastq");
+    std::fs::write(&swapped_path, swapped).unwrap();
+    let out = run_in(
+        dir.path(),
+        &[
+            "demux",
+            "--barcodes",
+            sheet.to_str().unwrap(),
+            "--out-dir",
+            "swapped",
+            r1.to_str().unwrap(),
+            swapped_path.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(out.status.code(), Some(1));
+    assert!(normalize(&out.stderr).contains("id differs from its mate's"));
+
+    golden_with_code(
+        "demux_bad_sheet",
+        1,
+        &[
+            "demux",
+            "--barcodes",
+            "umi_R1.fastq",
+            "--out-dir",
+            "unused",
+            "umi_R1.fastq",
+        ],
+    );
+}
+
+#[test]
+fn test_validate_ref() {
+    let fa = "validate_ref.fa";
+    golden(
+        "validate_ref_match",
+        &["validate-ref", "validate_match.bam", fa],
+    );
+    golden_with_code(
+        "validate_ref_md5",
+        1,
+        &["validate-ref", "validate_md5.bam", fa],
+    );
+    golden_with_code(
+        "validate_ref_renamed",
+        1,
+        &[
+            "validate-ref",
+            "--ignore-order",
+            "--subset-ok",
+            "validate_renamed.bam",
+            fa,
+        ],
+    );
+    golden_with_code(
+        "validate_ref_truncated",
+        1,
+        &["validate-ref", "validate_truncated.bam", fa],
+    );
+    golden_with_code(
+        "validate_ref_reordered",
+        1,
+        &["validate-ref", "validate_reordered.bam", fa],
+    );
+    golden(
+        "validate_ref_reordered_ignore",
+        &[
+            "validate-ref",
+            "--ignore-order",
+            "validate_reordered.bam",
+            fa,
+        ],
+    );
+    // validate_subset.bam lacks chrM and swaps chr1 and chr2
+    let subset = |flags: &[&'static str], code| {
+        let name = format!("validate_ref_subset{}", flags.concat().replace("--", "_"));
+        let mut args = vec!["validate-ref"];
+        args.extend(flags);
+        args.extend(["validate_subset.bam", fa]);
+        golden_with_code(&name, code, &args);
+    };
+    subset(&[], 1);
+    subset(&["--ignore-order"], 1);
+    subset(&["--subset-ok"], 1);
+    subset(&["--ignore-order", "--subset-ok"], 0);
+
+    // a BAM without a reference is a usage error
+    let out = run_in(
+        Path::new(TEST_DATA),
+        &["validate-ref", "validate_match.bam"],
+    );
+    assert_eq!(out.status.code(), Some(2));
+
+    // a stale index is reported, and the FASTA indexed in memory instead
+    let dir = tempfile::tempdir().unwrap();
+    for file in [fa, "validate_match.bam"] {
+        std::fs::copy(Path::new(TEST_DATA).join(file), dir.path().join(file)).unwrap();
+    }
+    assert!(run_in(dir.path(), &["faidx", fa]).status.success());
+    let mut stale = std::fs::read(dir.path().join(fa)).unwrap();
+    stale.push(b'\n');
+    std::fs::write(dir.path().join(fa), stale).unwrap();
+    let out = run_in(dir.path(), &["validate-ref", "validate_match.bam", fa]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(
+        normalize(&out.stderr),
+        "warning: validate_ref.fa.fai: index is stale: the file's size changed \
+         since it was built; indexing in memory\n"
+    );
+    assert!(normalize(&out.stdout).ends_with("OK\n"));
+}
+
+#[test]
+fn test_diff() {
+    golden("diff_identical", &["diff", "small.fastq", "small.fastq"]);
+    golden_with_code("diff_snp", 1, &["diff", "small.fastq", "diff_snp.fastq"]);
+    golden_with_code(
+        "diff_snp_unordered",
+        1,
+        &["diff", "--unordered", "small.fastq", "diff_snp.fastq"],
+    );
+    // diff_reordered.fastq holds the records of small.fastq in reverse
+    golden_with_code(
+        "diff_reordered",
+        1,
+        &["diff", "-n", "1", "small.fastq", "diff_reordered.fastq"],
+    );
+    golden(
+        "diff_reordered_unordered",
+        &["diff", "--unordered", "small.fastq", "diff_reordered.fastq"],
+    );
+    // the same aux fields in another order, then with a PG tag added
+    golden(
+        "diff_aux_order",
+        &["diff", "diff_aux_a.bam", "diff_aux_b.bam"],
+    );
+    golden_with_code(
+        "diff_aux_pg",
+        1,
+        &["diff", "diff_aux_a.bam", "diff_aux_pg.bam"],
+    );
+    golden(
+        "diff_aux_pg_ignored",
+        &[
+            "diff",
+            "--unordered",
+            "--ignore-tags",
+            "PG",
+            "diff_aux_a.bam",
+            "diff_aux_pg.bam",
+        ],
+    );
+    golden_with_code("diff_formats", 2, &["diff", "small.fastq", "test.fa"]);
+}
+
+#[test]
+fn test_split_rg() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = Path::new(TEST_DATA).join("read_groups.bam");
+    // one file open at a time reopens outputs as the read groups interleave
+    for max_open in ["1", "256"] {
+        let out = run_in(
+            dir.path(),
+            &[
+                "split-rg",
+                "--max-open-files",
+                max_open,
+                input.to_str().unwrap(),
+                "--out-dir",
+                "by_rg",
+                "--force",
+            ],
+        );
+        assert!(out.status.success(), "{}", normalize(&out.stderr));
+        check("split_rg.stdout", &normalize(&out.stdout));
+        for (file, golden_name) in [
+            ("s1.bam", "split_rg_s1.sam"),
+            ("lane_3.bam", "split_rg_lane_3.sam"),
+            ("unassigned.bam", "split_rg_unassigned.sam"),
+        ] {
+            let path = dir.path().join("by_rg").join(file);
+            let header = run_in(dir.path(), &["view", "-H", path.to_str().unwrap()]);
+            let records = run_in(dir.path(), &["view", path.to_str().unwrap()]);
+            let sam = [header.stdout, records.stdout].concat();
+            check(golden_name, &normalize(&sam));
+        }
+    }
+
+    let out = run_in(
+        dir.path(),
+        &[
+            "split-rg",
+            "--out-dir",
+            "none",
+            &format!("{TEST_DATA}/empty.bam"),
+        ],
+    );
+    assert_eq!(out.status.code(), Some(1));
+    assert!(!dir.path().join("none").exists());
+}
+
+#[test]
+fn test_translate() {
+    golden("translate", &["translate", "getfasta.fa"]);
+    golden(
+        "translate_all_frames",
+        &["translate", "--all-frames", "getfasta.fa"],
+    );
+}
+
+#[test]
+fn test_missing_input() {
+    golden_with_code("missing_input", 2, &["view", "no_such_file.fa"]);
+}
+
+#[test]
+fn test_completions() {
+    let out = run_in(Path::new(TEST_DATA), &["completions", "bash"]);
+    assert!(out.status.success());
+    let script = normalize(&out.stdout);
+    for cmd in ["view", "faidx", "getfasta", "translate"] {
+        assert!(script.contains(cmd), "{cmd}");
+    }
+}
+
+#[test]
+fn test_runs_are_deterministic() {
+    for args in [&["view", "stats.bam"][..], &["stats", "small.fastq"]] {
+        let first = run_in(Path::new(TEST_DATA), args);
+        let second = run_in(Path::new(TEST_DATA), args);
+        assert_eq!(first.stdout, second.stdout, "{args:?}");
+    }
+}
@@ -0,0 +1,610 @@
+//! Pairwise alignment of short sequences
+//!
+//! `global_align` (Needleman-Wunsch) aligns two sequences end to end, and
+//! `local_align` (Smith-Waterman) finds the best-scoring pair of substrings, both
+//! with affine gap costs. Bases are compared ignoring ASCII case.
+//!
+//! This is meant for reads against references of up to a few kb, not genomes: time
+//! grows with the product of the lengths and the traceback takes a byte per cell,
+//! so alignments of more than `MAX_CELLS` cells are refused. `AlignParams::band`
+//! keeps to the cells near the diagonal, for sequences of similar length.
+//!
+//! ```
+//! use lyso_common::align::{global_align, AlignParams};
+//!
+//! let aln = global_align(b"ACGTACGT", b"ACGTCCACGT", &AlignParams::default()).unwrap();
+//! assert_eq!(aln.cigar_string(), "4=2D4=");
+//! assert_eq!(aln.score, 8 * 2 - 5 - 2 * 2);
+//! assert_eq!(format!("{:.1}", aln.identity()), "80.0");
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use crate::CigarOp;
+
+/// Most traceback cells, one byte each, an alignment may take
+pub const MAX_CELLS: usize = 1 << 26;
+
+/// Low enough that no alignment reaches it, high enough not to overflow
+const NEG: i32 = i32::MIN / 2;
+
+// traceback states, two bits each
+const FROM_M: u8 = 0;
+const FROM_I: u8 = 1;
+const FROM_D: u8 = 2;
+/// A local alignment starts at this cell
+const FROM_START: u8 = 3;
+
+/// Scores for `global_align` and `local_align`
+///
+/// A gap of length k scores `gap_open + k * gap_extend`. The defaults are
+/// blastn's: match 2, mismatch -3, gap open -5 and gap extend -2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignParams {
+    pub match_score: i32,
+    pub mismatch: i32,
+    pub gap_open: i32,
+    pub gap_extend: i32,
+    /// Only fill cells within this many diagonals of the main one, widened by the
+    /// difference in length. Faster for similar sequences, but an alignment that
+    /// leaves the band is missed.
+    pub band: Option<usize>,
+}
+
+impl Default for AlignParams {
+    fn default() -> Self {
+        AlignParams {
+            match_score: 2,
+            mismatch: -3,
+            gap_open: -5,
+            gap_extend: -2,
+            band: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AlignError {
+    /// The alignment would take more than `MAX_CELLS` cells
+    TooLarge { cells: usize },
+}
+
+impl Display for AlignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlignError::TooLarge { cells } => write!(
+                f,
+                "alignment needs {cells} cells, more than the {MAX_CELLS} allowed; set a band or align shorter sequences"
+            ),
+        }
+    }
+}
+
+impl Error for AlignError {}
+
+/// An alignment of a query against a reference
+///
+/// The CIGAR uses '=' and 'X' for aligned bases, 'I' for query bases missing from
+/// the reference and 'D' for reference bases missing from the query. Local
+/// alignments soft clip the query bases outside the aligned part. Coordinates are
+/// 0-based and half-open.
+#[derive(Debug, PartialEq)]
+pub struct Alignment {
+    pub score: i32,
+    pub cigar: Vec<CigarOp>,
+    pub query_start: usize,
+    pub query_end: usize,
+    pub ref_start: usize,
+    pub ref_end: usize,
+    /// Aligned bases that are equal
+    pub matches: usize,
+}
+
+impl Alignment {
+    /// Percentage of alignment columns (=, X, I and D bases) that are matches; 0 without any
+    pub fn identity(&self) -> f64 {
+        let columns: u64 = self
+            .cigar
+            .iter()
+            .filter(|op| !matches!(op, CigarOp::S(_)))
+            .map(|op| u64::from(op.len()))
+            .sum();
+        if columns == 0 {
+            0.0
+        } else {
+            100.0 * self.matches as f64 / columns as f64
+        }
+    }
+
+    /// The CIGAR as text, "*" when empty
+    pub fn cigar_string(&self) -> String {
+        if self.cigar.is_empty() {
+            return String::from("*");
+        }
+        self.cigar.iter().map(ToString::to_string).collect()
+    }
+}
+
+/// Align all of `query` against all of `reference`
+pub fn global_align(
+    query: &[u8],
+    reference: &[u8],
+    params: &AlignParams,
+) -> Result<Alignment, AlignError> {
+    align(query, reference, params, false)
+}
+
+/// Align the best-scoring part of `query` against part of `reference`
+///
+/// When nothing scores above 0 the alignment is empty, with the whole query clipped.
+pub fn local_align(
+    query: &[u8],
+    reference: &[u8],
+    params: &AlignParams,
+) -> Result<Alignment, AlignError> {
+    align(query, reference, params, true)
+}
+
+/// Columns `lo..=hi` of each row that are filled
+struct Band {
+    /// Lowest and highest `j - i` filled
+    diagonals: (isize, isize),
+    ref_len: usize,
+}
+
+impl Band {
+    fn new(query_len: usize, ref_len: usize, band: Option<usize>) -> Self {
+        let diagonals = match band {
+            Some(b) => {
+                let diff = ref_len as isize - query_len as isize;
+                (diff.min(0) - b as isize, diff.max(0) + b as isize)
+            }
+            None => (-(query_len as isize), ref_len as isize),
+        };
+        Band { diagonals, ref_len }
+    }
+
+    fn row(&self, i: usize) -> (usize, usize) {
+        let lo = (i as isize + self.diagonals.0).max(0) as usize;
+        let hi = (i as isize + self.diagonals.1).min(self.ref_len as isize) as usize;
+        (lo, hi)
+    }
+}
+
+/// One row of scores for each state, `NEG` outside the band
+struct Row {
+    m: Vec<i32>,
+    i: Vec<i32>,
+    d: Vec<i32>,
+    lo: usize,
+    hi: usize,
+}
+
+impl Row {
+    fn new(width: usize) -> Self {
+        Row {
+            m: vec![NEG; width],
+            i: vec![NEG; width],
+            d: vec![NEG; width],
+            lo: 1,
+            hi: 0,
+        }
+    }
+
+    /// Scores in states M, I and D at column `j`
+    fn get(&self, j: usize) -> (i32, i32, i32) {
+        if j < self.lo || j > self.hi {
+            (NEG, NEG, NEG)
+        } else {
+            (self.m[j], self.i[j], self.d[j])
+        }
+    }
+}
+
+/// The best of `candidates`, the first on ties, with its state
+fn best(candidates: [(i32, u8); 3]) -> (i32, u8) {
+    let mut top = candidates[0];
+    for c in &candidates[1..] {
+        if c.0 > top.0 {
+            top = *c;
+        }
+    }
+    (top.0.max(NEG), top.1)
+}
+
+fn align(
+    query: &[u8],
+    reference: &[u8],
+    params: &AlignParams,
+    local: bool,
+) -> Result<Alignment, AlignError> {
+    let (n, m) = (query.len(), reference.len());
+    let band = Band::new(n, m, params.band);
+    let cells = (0..=n).fold(0usize, |sum, i| {
+        let (lo, hi) = band.row(i);
+        sum.saturating_add(hi - lo + 1)
+    });
+    if cells > MAX_CELLS {
+        return Err(AlignError::TooLarge { cells });
+    }
+    // index of column 0 of each row in `trace`, as if the row were full
+    let mut row_starts = Vec::with_capacity(n + 1);
+    let mut filled = 0;
+    for i in 0..=n {
+        let (lo, hi) = band.row(i);
+        row_starts.push(filled - lo);
+        filled += hi - lo + 1;
+    }
+    // M, I and D sources of each cell, two bits each
+    let mut trace = vec![0u8; cells];
+    let open = params.gap_open + params.gap_extend;
+    let ext = params.gap_extend;
+    let score = |a: u8, b: u8| {
+        if a.eq_ignore_ascii_case(&b) {
+            params.match_score
+        } else {
+            params.mismatch
+        }
+    };
+
+    let (mut prev, mut cur) = (Row::new(m + 1), Row::new(m + 1));
+    // best local cell so far
+    let mut top = (0, 0, 0);
+    for i in 0..=n {
+        let (lo, hi) = band.row(i);
+        (cur.lo, cur.hi) = (lo, hi);
+        for j in lo..=hi {
+            let (m_score, i_score, d_score, sources) = if i == 0 && j == 0 {
+                let start = if local { NEG } else { 0 };
+                (start, NEG, NEG, FROM_START)
+            } else {
+                let (m_up, i_up, d_up) = prev.get(j);
+                let (im, isrc) = best([
+                    (i_up + ext, FROM_I),
+                    (m_up + open, FROM_M),
+                    (d_up + open, FROM_D),
+                ]);
+                let (m_left, i_left, d_left) = if j > lo {
+                    cur.get(j - 1)
+                } else {
+                    (NEG, NEG, NEG)
+                };
+                let (dm, dsrc) = best([
+                    (d_left + ext, FROM_D),
+                    (m_left + open, FROM_M),
+                    (i_left + open, FROM_I),
+                ]);
+                let (mm, msrc) = if i > 0 && j > 0 {
+                    let (m_diag, i_diag, d_diag) = prev.get(j - 1);
+                    let (diag, src) = best([(m_diag, FROM_M), (i_diag, FROM_I), (d_diag, FROM_D)]);
+                    let s = score(query[i - 1], reference[j - 1]);
+                    if local && diag <= 0 {
+                        (s, FROM_START)
+                    } else {
+                        ((diag + s).max(NEG), src)
+                    }
+                } else {
+                    (NEG, FROM_M)
+                };
+                (mm, im, dm, msrc | isrc << 2 | dsrc << 4)
+            };
+            cur.m[j] = m_score;
+            cur.i[j] = i_score;
+            cur.d[j] = d_score;
+            trace[row_starts[i] + j] = sources;
+            if local && m_score > top.0 {
+                top = (m_score, i, j);
+            }
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    // `prev` now holds row n
+    let (score, end_state, (mut i, mut j)) = if local {
+        (top.0, FROM_M, (top.1, top.2))
+    } else {
+        let (m_end, i_end, d_end) = prev.get(m);
+        let (s, state) = best([(m_end, FROM_M), (i_end, FROM_I), (d_end, FROM_D)]);
+        (s, state, (n, m))
+    };
+    let (query_end, ref_end) = (i, j);
+    let mut ops = Vec::new();
+    let mut matches = 0;
+    let mut state = end_state;
+    if !local || score > 0 {
+        while i > 0 || j > 0 {
+            let sources = trace[row_starts[i] + j];
+            match state {
+                FROM_M => {
+                    let equal = query[i - 1].eq_ignore_ascii_case(&reference[j - 1]);
+                    matches += usize::from(equal);
+                    ops.push(if equal { b'=' } else { b'X' });
+                    state = sources & 3;
+                    (i, j) = (i - 1, j - 1);
+                }
+                FROM_I => {
+                    ops.push(b'I');
+                    state = sources >> 2 & 3;
+                    i -= 1;
+                }
+                _ => {
+                    ops.push(b'D');
+                    state = sources >> 4 & 3;
+                    j -= 1;
+                }
+            }
+            if state == FROM_START {
+                break;
+            }
+        }
+    }
+    // an empty local alignment has no position
+    let (query_start, query_end, ref_start, ref_end) = if ops.is_empty() && local {
+        (0, 0, 0, 0)
+    } else {
+        (i, query_end, j, ref_end)
+    };
+
+    let mut cigar = Vec::new();
+    if query_start > 0 {
+        cigar.push(CigarOp::S(query_start as u32));
+    }
+    let mut run: Option<(u8, u32)> = None;
+    for op in ops.into_iter().rev() {
+        run = match run {
+            Some((c, len)) if c == op => Some((c, len + 1)),
+            Some((c, len)) => {
+                cigar.push(cigar_op(c, len));
+                Some((op, 1))
+            }
+            None => Some((op, 1)),
+        };
+    }
+    if let Some((c, len)) = run {
+        cigar.push(cigar_op(c, len));
+    }
+    if query_end < n {
+        cigar.push(CigarOp::S((n - query_end) as u32));
+    }
+    Ok(Alignment {
+        score: if local { score.max(0) } else { score },
+        cigar,
+        query_start,
+        query_end,
+        ref_start,
+        ref_end,
+        matches,
+    })
+}
+
+fn cigar_op(c: u8, len: u32) -> CigarOp {
+    match c {
+        b'=' => CigarOp::Eq(len),
+        b'X' => CigarOp::X(len),
+        b'I' => CigarOp::I(len),
+        _ => CigarOp::D(len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(match_score: i32, mismatch: i32, gap_open: i32, gap_extend: i32) -> AlignParams {
+        AlignParams {
+            match_score,
+            mismatch,
+            gap_open,
+            gap_extend,
+            band: None,
+        }
+    }
+
+    /// The score `aln`'s CIGAR gives, checking it covers both sequences
+    fn rescore(query: &[u8], reference: &[u8], aln: &Alignment, p: &AlignParams) -> i32 {
+        let (mut i, mut j) = (0, aln.ref_start);
+        let mut score = 0;
+        for op in &aln.cigar {
+            let len = op.len() as usize;
+            match op {
+                CigarOp::S(_) => i += len,
+                CigarOp::Eq(_) | CigarOp::X(_) => {
+                    for _ in 0..len {
+                        let equal = query[i].eq_ignore_ascii_case(&reference[j]);
+                        assert_eq!(equal, matches!(op, CigarOp::Eq(_)));
+                        score += if equal { p.match_score } else { p.mismatch };
+                        (i, j) = (i + 1, j + 1);
+                    }
+                }
+                CigarOp::I(_) => {
+                    score += p.gap_open + len as i32 * p.gap_extend;
+                    i += len;
+                }
+                CigarOp::D(_) => {
+                    score += p.gap_open + len as i32 * p.gap_extend;
+                    j += len;
+                }
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!((i, j), (query.len(), aln.ref_end));
+        score
+    }
+
+    #[test]
+    fn test_global_known() {
+        let p = AlignParams::default();
+        let cases: [(&[u8], &[u8], &str, i32); 6] = [
+            (b"ACGT", b"ACGT", "4=", 8),
+            (b"ACGT", b"acgt", "4=", 8),
+            (b"ACGT", b"AGGT", "1=1X2=", 3),
+            (b"ACGTACGT", b"ACGTCCACGT", "4=2D4=", 7),
+            (b"ACGTCCACGT", b"ACGTACGT", "4=2I4=", 7),
+            (b"GATTACA", b"GCATGCT", "1=2X1=1X1=1X", -6),
+        ];
+        for (q, r, cigar, score) in cases {
+            let aln = global_align(q, r, &p).unwrap();
+            assert_eq!(
+                (aln.cigar_string().as_str(), aln.score),
+                (cigar, score),
+                "{q:?} {r:?}"
+            );
+            assert_eq!(rescore(q, r, &aln, &p), aln.score);
+            assert_eq!((aln.query_start, aln.query_end), (0, q.len()));
+            assert_eq!((aln.ref_start, aln.ref_end), (0, r.len()));
+        }
+        let aln = global_align(b"ACGT", b"AGGT", &p).unwrap();
+        assert_eq!((aln.matches, aln.identity()), (3, 75.0));
+    }
+
+    #[test]
+    fn test_gap_open_extend_tie() {
+        // with no opening cost, one long gap and two short ones score the same;
+        // the single gap is kept
+        let free_open = params(2, -3, 0, -2);
+        let aln = global_align(b"A", b"AAA", &free_open).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("2D1=", -2));
+        let aln = global_align(b"ACGT", b"AACCGGTT", &free_open).unwrap();
+        assert_eq!(aln.score, 0);
+        assert_eq!(rescore(b"ACGT", b"AACCGGTT", &aln, &free_open), 0);
+
+        // a gap of two costs open + 2 * extend, as do two mismatches here, and
+        // the mismatches win the tie
+        let p = params(2, -3, 0, -3);
+        let aln = global_align(b"ACGTTA", b"ACCATA", &p).unwrap();
+        assert_eq!(aln.cigar_string(), "2=2X2=");
+        // ...while opening a gap costs more than extending one
+        let p = params(2, -8, -4, -1);
+        let aln = global_align(b"AAAATTTT", b"AAAACCTTTT", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("4=2D4=", 10));
+    }
+
+    #[test]
+    fn test_empty() {
+        let p = AlignParams::default();
+        let aln = global_align(b"", b"ACG", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("3D", -11));
+        assert_eq!(aln.identity(), 0.0);
+        let aln = global_align(b"ACG", b"", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("3I", -11));
+        let aln = global_align(b"", b"", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("*", 0));
+
+        let aln = local_align(b"ACG", b"", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("3S", 0));
+        let aln = local_align(b"", b"ACG", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("*", 0));
+        let aln = local_align(b"AAAA", b"CCCC", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("4S", 0));
+        assert_eq!((aln.query_start, aln.query_end, aln.ref_start), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_local() {
+        let p = AlignParams::default();
+        let aln = local_align(b"TTTTACGTACGTCCCC", b"GGGACGTACGTGGG", &p).unwrap();
+        assert_eq!((aln.cigar_string().as_str(), aln.score), ("4S8=4S", 16));
+        assert_eq!((aln.query_start, aln.query_end), (4, 12));
+        assert_eq!((aln.ref_start, aln.ref_end), (3, 11));
+        assert_eq!(aln.identity(), 100.0);
+
+        // a gap inside the local alignment
+        let q = b"TTACGTACGTAACGTACGTTT";
+        let r = b"ACGTACGTACGTACGT";
+        let aln = local_align(q, r, &p).unwrap();
+        assert_eq!(aln.cigar_string(), "2S8=1I8=2S");
+        assert_eq!(rescore(q, r, &aln, &p), aln.score);
+    }
+
+    /// The best global score, trying every alignment
+    fn exhaustive(q: &[u8], r: &[u8], p: &AlignParams, last: u8) -> i32 {
+        if q.is_empty() && r.is_empty() {
+            return 0;
+        }
+        let gap = |state| p.gap_extend + if last == state { 0 } else { p.gap_open };
+        let mut top = NEG;
+        if let (Some(a), Some(b)) = (q.first(), r.first()) {
+            let s = if a == b { p.match_score } else { p.mismatch };
+            top = top.max(s + exhaustive(&q[1..], &r[1..], p, b'M'));
+        }
+        if !q.is_empty() {
+            top = top.max(gap(b'I') + exhaustive(&q[1..], r, p, b'I'));
+        }
+        if !r.is_empty() {
+            top = top.max(gap(b'D') + exhaustive(q, &r[1..], p, b'D'));
+        }
+        top
+    }
+
+    #[test]
+    fn test_rescore_random() {
+        // a small LCG, so the sequences are the same on every run
+        let mut state = 12345u64;
+        let mut seq = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    b"ACGT"[(state >> 33) as usize % 4]
+                })
+                .collect()
+        };
+        let p = AlignParams::default();
+        for k in 0..200 {
+            let q = seq(k % 23);
+            let r = seq(k % 17 + 1);
+            let global = global_align(&q, &r, &p).unwrap();
+            assert_eq!(rescore(&q, &r, &global, &p), global.score, "{k}");
+            if q.len() + r.len() <= 12 {
+                assert_eq!(global.score, exhaustive(&q, &r, &p, 0), "{k}");
+            }
+            let local = local_align(&q, &r, &p).unwrap();
+            assert_eq!(rescore(&q, &r, &local, &p), local.score, "{k}");
+            assert!(local.score >= global.score.max(0));
+        }
+    }
+
+    #[test]
+    fn test_band() {
+        let q = b"ACGTACGTTAGCTAGCTAGGATCCA";
+        let r = b"ACGTACGTAGCTAGCTAGGATCCAT";
+        let full = global_align(q, r, &AlignParams::default()).unwrap();
+        let banded = AlignParams {
+            band: Some(2),
+            ..AlignParams::default()
+        };
+        assert_eq!(global_align(q, r, &banded).unwrap(), full);
+
+        // the main diagonal only: no gaps are possible
+        let diagonal = AlignParams {
+            band: Some(0),
+            ..AlignParams::default()
+        };
+        let aln = global_align(q, r, &diagonal).unwrap();
+        assert!(aln
+            .cigar
+            .iter()
+            .all(|op| matches!(op, CigarOp::Eq(_) | CigarOp::X(_))));
+        assert!(aln.score < full.score);
+        // a length difference widens the band enough to finish
+        let aln = global_align(b"ACGTACGT", b"ACGT", &diagonal).unwrap();
+        assert_eq!(rescore(b"ACGTACGT", b"ACGT", &aln, &diagonal), aln.score);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let long = vec![b'A'; 10_000];
+        assert_eq!(
+            global_align(&long, &long, &AlignParams::default()),
+            Err(AlignError::TooLarge {
+                cells: 10_001 * 10_001
+            })
+        );
+        let banded = AlignParams {
+            band: Some(10),
+            ..AlignParams::default()
+        };
+        let aln = global_align(&long, &long, &banded).unwrap();
+        assert_eq!(aln.cigar_string(), "10000=");
+    }
+}
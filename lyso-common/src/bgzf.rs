@@ -0,0 +1,137 @@
+//! BGZF virtual file offsets
+//!
+//! A BGZF file is a series of independently compressed blocks, so a position in
+//! the decompressed stream is given as the offset of the block in the compressed
+//! file (`coffset`, 48 bits) and the offset within the decompressed block
+//! (`uoffset`, 16 bits), packed as `coffset << 16 | uoffset`. Virtual offsets of
+//! the same file order like the positions they point to. See SAMv1 4.1.1.
+
+use std::fmt::{self, Display};
+
+/// Largest compressed offset a virtual offset can hold
+pub const MAX_COFFSET: u64 = (1 << 48) - 1;
+
+/// A BGZF virtual file offset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtualOffset(u64);
+
+impl VirtualOffset {
+    /// None if `coffset` exceeds `MAX_COFFSET`
+    pub const fn new(coffset: u64, uoffset: u16) -> Option<Self> {
+        if coffset > MAX_COFFSET {
+            None
+        } else {
+            Some(VirtualOffset(coffset << 16 | uoffset as u64))
+        }
+    }
+
+    /// From the packed `coffset << 16 | uoffset` form
+    pub const fn from_raw(raw: u64) -> Self {
+        VirtualOffset(raw)
+    }
+
+    pub const fn raw(self) -> u64 {
+        self.0
+    }
+
+    /// Offset of the block in the compressed file
+    pub const fn coffset(self) -> u64 {
+        self.0 >> 16
+    }
+
+    /// Offset within the decompressed block
+    pub const fn uoffset(self) -> u16 {
+        self.0 as u16
+    }
+
+    /// The start of the same block
+    pub const fn block_start(self) -> Self {
+        VirtualOffset(self.0 & !0xffff)
+    }
+
+    /// `n` bytes further into the same block; None past the 64KB a block can hold
+    pub fn checked_add_within_block(self, n: u16) -> Option<Self> {
+        self.uoffset()
+            .checked_add(n)
+            .map(|u| VirtualOffset(self.block_start().0 | u64::from(u)))
+    }
+
+    /// `n` bytes back in the same block; None before its start
+    pub fn checked_sub_within_block(self, n: u16) -> Option<Self> {
+        self.uoffset()
+            .checked_sub(n)
+            .map(|u| VirtualOffset(self.block_start().0 | u64::from(u)))
+    }
+}
+
+impl From<VirtualOffset> for u64 {
+    fn from(value: VirtualOffset) -> Self {
+        value.0
+    }
+}
+
+impl From<u64> for VirtualOffset {
+    fn from(value: u64) -> Self {
+        VirtualOffset(value)
+    }
+}
+
+/// `coffset:uoffset`
+impl Display for VirtualOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.coffset(), self.uoffset())
+    }
+}
+
+/// A reader of decompressed BGZF data that can report and seek to virtual offsets
+pub trait VirtualSeek {
+    /// Continue reading from `pos`, which must point into a block of this file
+    fn seek_virtual(&mut self, pos: VirtualOffset) -> std::io::Result<()>;
+
+    /// Virtual offset of the next byte to be read
+    fn virtual_position(&self) -> VirtualOffset;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packing() {
+        let cases = [
+            (0, 0),
+            (0, u16::MAX),
+            (1, 0),
+            (MAX_COFFSET, 0),
+            (MAX_COFFSET, u16::MAX),
+            (0x1234_5678, 0x9abc),
+        ];
+        for (coffset, uoffset) in cases {
+            let v = VirtualOffset::new(coffset, uoffset).unwrap();
+            assert_eq!((v.coffset(), v.uoffset()), (coffset, uoffset));
+            assert_eq!(VirtualOffset::from_raw(v.raw()), v);
+        }
+        assert_eq!(VirtualOffset::new(MAX_COFFSET + 1, 0), None);
+        assert_eq!(VirtualOffset::from_raw(u64::MAX).coffset(), MAX_COFFSET);
+        assert_eq!(VirtualOffset::new(3, 7).unwrap().raw(), 3 << 16 | 7);
+    }
+
+    #[test]
+    fn test_order_and_display() {
+        let v = |c, u| VirtualOffset::new(c, u).unwrap();
+        assert!(v(0, u16::MAX) < v(1, 0));
+        assert!(v(5, 10) < v(5, 11));
+        assert_eq!(v(1024, 37).to_string(), "1024:37");
+        assert_eq!(VirtualOffset::default().to_string(), "0:0");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let v = VirtualOffset::new(9, 100).unwrap();
+        assert_eq!(v.block_start(), VirtualOffset::new(9, 0).unwrap());
+        assert_eq!(v.checked_add_within_block(5), VirtualOffset::new(9, 105));
+        assert_eq!(v.checked_add_within_block(u16::MAX), None);
+        assert_eq!(v.checked_sub_within_block(100), Some(v.block_start()));
+        assert_eq!(v.checked_sub_within_block(101), None);
+    }
+}
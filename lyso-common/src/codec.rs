@@ -0,0 +1,203 @@
+//! Bulk decoding of packed sequence and quality data, and quality offsets
+//!
+//! Both decoding routines work a slice at a time with fixed-size chunks and a
+//! table lookup, so the inner loops have no branches or bounds checks and
+//! auto-vectorize.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// IUPAC codes of the 4-bit sequence encoding (SAM v1 4.2.3)
+pub const SEQ_ALPHABET: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// Highest Phred score that has a printable Phred+33 character ('~')
+pub const MAX_PHRED: u8 = 93;
+
+/// Both bases of every packed byte, high nibble first
+static PAIRS: [[u8; 2]; 256] = {
+    let mut table = [[0u8; 2]; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = [SEQ_ALPHABET[i >> 4], SEQ_ALPHABET[i & 0xf]];
+        i += 1;
+    }
+    table
+};
+
+/// Append `n_bases` ASCII bases unpacked from 4-bit encoded `src`
+///
+/// Each byte holds two bases, high nibble first; when `n_bases` is odd the
+/// low nibble of the last byte is ignored.
+///
+/// # Panics
+///
+/// If `src` is shorter than `n_bases.div_ceil(2)` bytes.
+pub fn unpack_4bit(src: &[u8], dst: &mut Vec<u8>, n_bases: usize) {
+    let src = &src[..n_bases.div_ceil(2)];
+    let start = dst.len();
+    dst.resize(start + src.len() * 2, 0);
+    for (out, b) in dst[start..].chunks_exact_mut(2).zip(src) {
+        out.copy_from_slice(&PAIRS[usize::from(*b)]);
+    }
+    dst.truncate(start + n_bases);
+}
+
+/// Append the Phred+33 characters of the scores in `src`
+///
+/// Scores above `MAX_PHRED` are clamped to '~', so the output is always
+/// printable ASCII.
+pub fn phred_to_ascii(src: &[u8], dst: &mut Vec<u8>) {
+    let start = dst.len();
+    dst.resize(start + src.len(), 0);
+    for (out, q) in dst[start..].iter_mut().zip(src) {
+        *out = q.min(&MAX_PHRED) + 33;
+    }
+}
+
+/// The ASCII offset of quality characters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PhredEncoding {
+    #[default]
+    Phred33 = 33,
+    /// Illumina 1.3-1.7; Solexa-era files also hold characters down to ';'
+    Phred64 = 64,
+    Unknown = 0,
+}
+
+impl PhredEncoding {
+    /// Lowest character a quality string in this encoding holds, None if Unknown
+    ///
+    /// For Phred+64 this is ';', Solexa's score of -5.
+    pub fn min_char(self) -> Option<u8> {
+        match self {
+            PhredEncoding::Phred33 => Some(b'!'),
+            PhredEncoding::Phred64 => Some(b';'),
+            PhredEncoding::Unknown => None,
+        }
+    }
+}
+
+impl Display for PhredEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhredEncoding::Phred33 => write!(f, "Phred+33"),
+            PhredEncoding::Phred64 => write!(f, "Phred+64"),
+            PhredEncoding::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Serialized as its `Display` text
+#[cfg(feature = "serde")]
+impl serde::Serialize for PhredEncoding {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// `phred33` or `phred64`; `auto` is `Unknown`, to be detected
+impl FromStr for PhredEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "phred33" | "33" => Ok(PhredEncoding::Phred33),
+            "phred64" | "64" => Ok(PhredEncoding::Phred64),
+            "auto" => Ok(PhredEncoding::Unknown),
+            _ => Err(format!(
+                "unknown quality encoding '{s}', expected phred33, phred64 or auto"
+            )),
+        }
+    }
+}
+
+/// Guess the encoding of quality characters from their range
+///
+/// A character below ';', the lowest Solexa score, proves Phred+33, and one past
+/// 'J', the highest Phred+33 score in common use, proves Phred+64. Characters
+/// from ';' to 'J' fit both, as does a range with one of each.
+pub fn guess_phred_encoding(scores: &[u8]) -> PhredEncoding {
+    let min = scores.iter().min().copied().unwrap_or(0);
+    let max = scores.iter().max().copied().unwrap_or(0);
+    match (min < b';', max > b'J') {
+        (true, false) => PhredEncoding::Phred33,
+        (false, true) => PhredEncoding::Phred64,
+        _ => PhredEncoding::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unpack_every_byte() {
+        for b in 0..=255u8 {
+            let mut out = Vec::new();
+            unpack_4bit(&[b], &mut out, 2);
+            assert_eq!(
+                out,
+                [
+                    SEQ_ALPHABET[usize::from(b >> 4)],
+                    SEQ_ALPHABET[usize::from(b & 0xf)]
+                ]
+            );
+            out.clear();
+            unpack_4bit(&[b], &mut out, 1);
+            assert_eq!(out, [SEQ_ALPHABET[usize::from(b >> 4)]]);
+        }
+    }
+
+    #[test]
+    fn test_unpack_appends() {
+        let packed = (0..=255u8).collect::<Vec<u8>>();
+        let mut out = b"xy".to_vec();
+        unpack_4bit(&packed, &mut out, 511);
+        assert_eq!(out.len(), 513);
+        assert_eq!(&out[..6], b"xy===A");
+        assert_eq!(&out[out.len() - 3..], b"NBN");
+
+        // bytes past n_bases are not read
+        let mut out = Vec::new();
+        unpack_4bit(&[0x12, 0x48, 0xff], &mut out, 3);
+        assert_eq!(out, b"ACG");
+        unpack_4bit(&[], &mut out, 0);
+        assert_eq!(out, b"ACG");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unpack_short_input() {
+        unpack_4bit(&[0x12], &mut Vec::new(), 3);
+    }
+
+    #[test]
+    fn test_phred_to_ascii() {
+        let quals = (0..=MAX_PHRED).collect::<Vec<u8>>();
+        let mut out = b"@".to_vec();
+        phred_to_ascii(&quals, &mut out);
+        let expected = (b'!'..=b'~').collect::<Vec<u8>>();
+        assert_eq!(out[0], b'@');
+        assert_eq!(&out[1..], expected);
+
+        let mut out = Vec::new();
+        phred_to_ascii(&[94, 200, 255], &mut out);
+        assert_eq!(out, b"~~~");
+    }
+
+    #[test]
+    fn test_guess_phred_encoding() {
+        assert_eq!(guess_phred_encoding(b"!5?I"), PhredEncoding::Phred33);
+        assert_eq!(guess_phred_encoding(b"@Ih"), PhredEncoding::Phred64);
+        // Solexa scores below 0
+        assert_eq!(guess_phred_encoding(b";@h"), PhredEncoding::Phred64);
+        assert_eq!(guess_phred_encoding(b";@I"), PhredEncoding::Unknown);
+        assert_eq!(guess_phred_encoding(b"@AI"), PhredEncoding::Unknown);
+        // high-quality Phred+33 reads fit Phred+64 too
+        assert_eq!(guess_phred_encoding(b"@AJ"), PhredEncoding::Unknown);
+        assert_eq!(guess_phred_encoding(b"!h"), PhredEncoding::Unknown);
+        assert_eq!("auto".parse(), Ok(PhredEncoding::Unknown));
+        assert_eq!("Phred64".parse(), Ok(PhredEncoding::Phred64));
+        assert!("phred65".parse::<PhredEncoding>().is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! Buffer sizes and limits of the record readers
+//!
+//! Every reader parses records out of an internal buffer that it refills from its
+//! input. `ReaderConfig` sets how that buffer starts out, how much it reads at a
+//! time, when it drops the bytes of records already returned and how large a
+//! record may grow before reading gives up on it.
+
+/// Buffer tuning accepted by the `with_config` constructor of each reader
+///
+/// The knobs work together as follows:
+///
+/// - The buffer reserves `initial_capacity` bytes up front and grows as needed.
+/// - Each refill copies at most `read_chunk_size` bytes from the input. Small
+///   refills keep less input buffered beyond the record being read, at the cost
+///   of more of them.
+/// - A record longer than `max_record_size` bytes fails with a terminal error. The
+///   limit is checked between refills, so the buffer can pass it by one refill.
+/// - Bytes of records already returned are dropped from the front of the buffer
+///   once there are more than `compact_threshold` of them. A high threshold moves
+///   memory less often; a low one keeps the buffer near the size of one record.
+///
+/// `ReaderConfig::default()` is what the FASTA and FASTQ readers use, and
+/// `ReaderConfig::bam()` what the BAM reader uses.
+///
+/// ```
+/// use lyso_common::config::ReaderConfig;
+///
+/// // refuse records over 1 MiB, reading 4 KiB at a time
+/// let config = ReaderConfig {
+///     max_record_size: 1 << 20,
+///     read_chunk_size: 4096,
+///     ..ReaderConfig::default()
+/// };
+/// assert!(config.max_record_size < ReaderConfig::default().max_record_size);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReaderConfig {
+    /// Bytes the buffer reserves when the reader is created
+    pub initial_capacity: usize,
+    /// Longest record, in input bytes, a reader will buffer
+    pub max_record_size: usize,
+    /// Bytes of returned records the buffer holds before dropping them
+    pub compact_threshold: usize,
+    /// Most bytes copied from the input per refill
+    pub read_chunk_size: usize,
+}
+
+impl ReaderConfig {
+    /// The defaults of `BamReader`
+    ///
+    /// Room for one 64 KiB alignment block, refilled 8 KiB at a time where the
+    /// parser cannot tell how much more it needs. The BAM reader drops each block
+    /// as soon as it is consumed, so `compact_threshold` is 0.
+    pub const fn bam() -> Self {
+        ReaderConfig {
+            initial_capacity: 65536,
+            max_record_size: usize::MAX,
+            compact_threshold: 0,
+            read_chunk_size: 8192,
+        }
+    }
+}
+
+impl Default for ReaderConfig {
+    /// The defaults of the FASTA and FASTQ readers
+    ///
+    /// A 10 MB buffer, compacted once 10 MB of it has been returned, refilled up
+    /// to the end of the record being read, with no limit on record size.
+    fn default() -> Self {
+        ReaderConfig {
+            initial_capacity: 10_000_000,
+            max_record_size: usize::MAX,
+            compact_threshold: 10_000_000,
+            read_chunk_size: usize::MAX,
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Input format detection
+//!
+//! Both functions peek at the reader's buffer with `fill_buf` and never consume,
+//! so the same reader can be handed to the matching parser afterwards.
+//! `detect_format` expects decompressed bytes; use `detect_compression` first
+//! to decide whether a decoder is needed.
+
+use std::fmt::{self, Display};
+use std::io::BufRead;
+
+const BAM_MAGIC: &[u8] = b"BAM\x01";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const SAM_HEADER_TAGS: [&[u8]; 5] = [b"@HD\t", b"@SQ\t", b"@RG\t", b"@PG\t", b"@CO\t"];
+/// Mandatory SAM fields
+const SAM_N_FIELDS: usize = 11;
+/// Leading bytes checked by `is_binary`
+const TEXT_SAMPLE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Bam,
+    Sam,
+    Fasta,
+    Fastq,
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Bam => write!(f, "BAM"),
+            Format::Sam => write!(f, "SAM"),
+            Format::Fasta => write!(f, "FASTA"),
+            Format::Fastq => write!(f, "FASTQ"),
+        }
+    }
+}
+
+/// Serialized as its `Display` text
+#[cfg(feature = "serde")]
+impl serde::Serialize for Format {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Plain (possibly multi-member) gzip
+    Gzip,
+    /// Blocked gzip, as used by BAM and bgzip
+    Bgzf,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Bgzf => write!(f, "BGZF"),
+        }
+    }
+}
+
+/// Serialized as its `Display` text
+#[cfg(feature = "serde")]
+impl serde::Serialize for Compression {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DetectError {
+    IoError(std::io::Error),
+    Empty,
+    Unknown,
+}
+
+impl Display for DetectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetectError::IoError(e) => write!(f, "{e}"),
+            DetectError::Empty => write!(f, "empty file"),
+            DetectError::Unknown => write!(
+                f,
+                "could not determine format: expected BAM, SAM, FASTA or FASTQ"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DetectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DetectError::IoError(e) => Some(e),
+            DetectError::Empty | DetectError::Unknown => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DetectError {
+    fn from(value: std::io::Error) -> Self {
+        DetectError::IoError(value)
+    }
+}
+
+/// Detect gzip and BGZF from the leading bytes
+pub fn detect_compression(reader: &mut impl BufRead) -> Result<Compression, DetectError> {
+    let buf = reader.fill_buf()?;
+    if !buf.starts_with(GZIP_MAGIC) {
+        return Ok(Compression::None);
+    }
+    // BGZF sets FEXTRA and carries a 'BC' subfield first
+    let is_bgzf = buf.len() >= 14 && buf[3] & 0x04 != 0 && &buf[12..14] == b"BC";
+    Ok(if is_bgzf {
+        Compression::Bgzf
+    } else {
+        Compression::Gzip
+    })
+}
+
+/// Detect the record format from the leading (decompressed) bytes
+///
+/// '@' starts both FASTQ records and SAM header lines, so SAM is recognised by its
+/// header tags or by a first line with at least 11 tab-separated fields, and FASTQ
+/// by a sequence line followed by a '+' separator.
+pub fn detect_format(reader: &mut impl BufRead) -> Result<Format, DetectError> {
+    let buf = reader.fill_buf()?;
+    if buf.is_empty() {
+        return Err(DetectError::Empty);
+    }
+    if buf.starts_with(BAM_MAGIC) {
+        return Ok(Format::Bam);
+    }
+    if SAM_HEADER_TAGS.iter().any(|t| buf.starts_with(t)) {
+        return Ok(Format::Sam);
+    }
+    let mut lines = buf
+        .split(|b| *b == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l));
+    let first = lines.next().unwrap_or_default();
+    if first.split(|b| *b == b'\t').count() >= SAM_N_FIELDS {
+        return Ok(Format::Sam);
+    }
+    match first.first() {
+        Some(b'>') => Ok(Format::Fasta),
+        Some(b'@') if looks_like_fastq(lines) => Ok(Format::Fastq),
+        _ => Err(DetectError::Unknown),
+    }
+}
+
+/// What the leading bytes of an input are, when they rule out the expected format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// Compressed bytes where decompressed ones were expected
+    Compressed(Compression),
+    /// Another record format
+    Format(Format),
+    /// Mostly bytes that are not printable text
+    Binary,
+}
+
+/// Check the leading bytes of an input for the signature of something other than
+/// `expected`
+///
+/// Only unambiguous signatures count: gzip and BAM magic, SAM header lines, the
+/// first character of another sequence format, and a mostly non-printable sample
+/// where text is expected. `None` leaves the input to the parser, whose errors
+/// describe anything else.
+pub fn find_mismatch(buf: &[u8], expected: Format) -> Option<Mismatch> {
+    if buf.starts_with(GZIP_MAGIC) {
+        return detect_compression(&mut &buf[..])
+            .ok()
+            .map(Mismatch::Compressed);
+    }
+    let mut lines = buf
+        .split(|b| *b == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l));
+    let found = if buf.starts_with(BAM_MAGIC) {
+        Some(Format::Bam)
+    } else if SAM_HEADER_TAGS.iter().any(|t| buf.starts_with(t)) {
+        Some(Format::Sam)
+    } else {
+        match lines.next().and_then(|l| l.first()) {
+            Some(b'>') => Some(Format::Fasta),
+            Some(b'@') if looks_like_fastq(lines) => Some(Format::Fastq),
+            _ => None,
+        }
+    };
+    match found {
+        Some(format) if format != expected => Some(Mismatch::Format(format)),
+        Some(_) => None,
+        None if expected != Format::Bam && is_binary(buf) => Some(Mismatch::Binary),
+        None => None,
+    }
+}
+
+/// Whether more than 30% of the first bytes are neither printable nor whitespace
+fn is_binary(buf: &[u8]) -> bool {
+    let sample = &buf[..buf.len().min(TEXT_SAMPLE)];
+    let n = sample
+        .iter()
+        .filter(|b| !b.is_ascii_graphic() && !b.is_ascii_whitespace())
+        .count();
+    n * 10 > sample.len() * 3
+}
+
+/// Check the lines after an '@' header for the rest of a FASTQ record
+fn looks_like_fastq<'a>(mut lines: impl Iterator<Item = &'a [u8]>) -> bool {
+    let (seq, sep) = match (lines.next(), lines.next()) {
+        (Some(seq), Some(sep)) => (seq, sep),
+        _ => return false,
+    };
+    let seq_ok = !seq.is_empty() && seq.iter().all(|b| b.is_ascii_alphabetic() || *b == b'*');
+    // the quality line may be cut off by the end of the buffer
+    let qual_ok = match (lines.next(), lines.next()) {
+        (Some(qual), Some(_)) => qual.len() == seq.len(),
+        _ => true,
+    };
+    seq_ok && sep.starts_with(b"+") && qual_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    fn detect(bytes: &[u8]) -> Result<Format, DetectError> {
+        detect_format(&mut &bytes[..])
+    }
+
+    fn detect_file(path: &str) -> Result<Format, DetectError> {
+        let mut r = BufReader::new(File::open(path).unwrap());
+        detect_format(&mut r)
+    }
+
+    #[test]
+    fn test_find_mismatch() {
+        let fq = &b"@r1\nACGT\n+\nIIII\n"[..];
+        let fa = &b">chr1\nACGT\n"[..];
+        let sam = &b"@HD\tVN:1.6\n"[..];
+        let bam = &b"BAM\x01\x00\x00\x00\x00"[..];
+        let gz = std::fs::read("../resources/test_data/name_sorted.bam").unwrap();
+        let binary = (0..=255).collect::<Vec<u8>>();
+
+        assert_eq!(find_mismatch(fq, Format::Fastq), None);
+        assert_eq!(find_mismatch(fa, Format::Fasta), None);
+        assert_eq!(find_mismatch(bam, Format::Bam), None);
+        for expected in [Format::Fasta, Format::Fastq] {
+            assert_eq!(
+                find_mismatch(&gz, expected),
+                Some(Mismatch::Compressed(Compression::Bgzf))
+            );
+            assert_eq!(
+                find_mismatch(&[0x1f, 0x8b, 8, 0], expected),
+                Some(Mismatch::Compressed(Compression::Gzip))
+            );
+            assert_eq!(
+                find_mismatch(bam, expected),
+                Some(Mismatch::Format(Format::Bam))
+            );
+            assert_eq!(
+                find_mismatch(sam, expected),
+                Some(Mismatch::Format(Format::Sam))
+            );
+            assert_eq!(find_mismatch(&binary, expected), Some(Mismatch::Binary));
+        }
+        assert_eq!(
+            find_mismatch(fa, Format::Fastq),
+            Some(Mismatch::Format(Format::Fasta))
+        );
+        assert_eq!(
+            find_mismatch(fq, Format::Fasta),
+            Some(Mismatch::Format(Format::Fastq))
+        );
+        assert_eq!(
+            find_mismatch(fq, Format::Bam),
+            Some(Mismatch::Format(Format::Fastq))
+        );
+        assert_eq!(find_mismatch(&binary, Format::Bam), None);
+        // text the parser should judge
+        assert_eq!(find_mismatch(b"@r1\nAC", Format::Fasta), None);
+        assert_eq!(find_mismatch("ACGT é\n".as_bytes(), Format::Fasta), None);
+        assert_eq!(find_mismatch(b"", Format::Fastq), None);
+    }
+
+    #[test]
+    fn test_detect_fixtures() {
+        assert_eq!(
+            detect_file("../resources/test_data/test.fa").unwrap(),
+            Format::Fasta
+        );
+        assert_eq!(
+            detect_file("../resources/test_data/test.fastq").unwrap(),
+            Format::Fastq
+        );
+    }
+
+    #[test]
+    fn test_detect_fastq_vs_sam() {
+        assert_eq!(detect(b"@HD\tVN:1.6\n").unwrap(), Format::Sam);
+        let sam = b"r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\n";
+        assert_eq!(detect(sam).unwrap(), Format::Sam);
+        let fq = b"@r1 desc\nACGT\n+\nFFFF\n@r2\n";
+        assert_eq!(detect(fq).unwrap(), Format::Fastq);
+        // headerless SAM whose read name starts with '@'
+        let sam = b"@r1\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\tFFFF\n";
+        assert_eq!(detect(sam).unwrap(), Format::Sam);
+    }
+
+    #[test]
+    fn test_detect_bam_magic() {
+        assert_eq!(detect(b"BAM\x01\x00\x00\x00\x00").unwrap(), Format::Bam);
+    }
+
+    #[test]
+    fn test_detect_ambiguous() {
+        // an '@' line with nothing that looks like a FASTQ record after it
+        let err = detect(b"@comment\nsome text\nmore text\n").unwrap_err();
+        assert!(matches!(err, DetectError::Unknown));
+        assert!(err.to_string().starts_with("could not determine format"));
+        assert!(matches!(detect(b""), Err(DetectError::Empty)));
+        assert!(matches!(
+            detect(b"@r1\nACGT\n+\nFF\n"),
+            Err(DetectError::Unknown)
+        ));
+    }
+
+    #[test]
+    fn test_detect_compression() {
+        let mut bgzf = File::open("../resources/test_data/name_sorted.bam")
+            .map(BufReader::new)
+            .unwrap();
+        assert_eq!(detect_compression(&mut bgzf).unwrap(), Compression::Bgzf);
+        let gzip: &[u8] = &[0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0, 0xff];
+        assert_eq!(
+            detect_compression(&mut &gzip[..]).unwrap(),
+            Compression::Gzip
+        );
+        assert_eq!(
+            detect_compression(&mut &b">seq\nACGT\n"[..]).unwrap(),
+            Compression::None
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Sequence dictionaries: the @SQ lines of a Picard `.dict` or a SAM header
+//!
+//! Only SN, LN and M5 are kept; other header lines and @SQ fields are skipped.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DictError {
+    IoError(std::io::Error),
+    /// A malformed @SQ line, with its 1-based line number
+    Parse {
+        line: usize,
+        msg: String,
+    },
+}
+
+impl Display for DictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DictError::IoError(e) => write!(f, "{e}"),
+            DictError::Parse { line, msg } => write!(f, "line {line}: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for DictError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DictError::IoError(e) => Some(e),
+            DictError::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DictError {
+    fn from(value: std::io::Error) -> Self {
+        DictError::IoError(value)
+    }
+}
+
+/// One @SQ line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DictEntry {
+    pub name: String,
+    pub length: u64,
+    /// M5, lower-cased
+    pub md5: Option<String>,
+}
+
+/// @SQ entries in file order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SequenceDictionary {
+    entries: Vec<DictEntry>,
+}
+
+impl SequenceDictionary {
+    /// Parse the @SQ lines of `reader`
+    pub fn from_reader(reader: impl BufRead) -> Result<Self, DictError> {
+        let mut entries = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            if let Some(entry) = parse_sq(&line?, n + 1)? {
+                entries.push(entry);
+            }
+        }
+        Ok(SequenceDictionary { entries })
+    }
+
+    /// Parse the @SQ lines of SAM header text, such as a BAM header's
+    pub fn from_header_text(text: &str) -> Result<Self, DictError> {
+        Self::from_reader(text.as_bytes())
+    }
+
+    /// Read the `.dict` at `path`
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, DictError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Entries in file order
+    pub fn entries(&self) -> &[DictEntry] {
+        &self.entries
+    }
+
+    /// The entry called `name`
+    pub fn get(&self, name: &str) -> Option<&DictEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+}
+
+/// `ref.dict` for `ref.fa` or `ref.fasta`, as Picard names it
+pub fn dict_path(fasta: &Path) -> PathBuf {
+    fasta.with_extension("dict")
+}
+
+fn parse_sq(line: &str, n: usize) -> Result<Option<DictEntry>, DictError> {
+    let Some(fields) = line.strip_prefix("@SQ\t") else {
+        return Ok(None);
+    };
+    let parse_err = |msg: String| DictError::Parse { line: n, msg };
+    let (mut name, mut length, mut md5) = (None, None, None);
+    for field in fields.trim_end_matches('\r').split('\t') {
+        if let Some(v) = field.strip_prefix("SN:") {
+            name = Some(v.to_string());
+        } else if let Some(v) = field.strip_prefix("LN:") {
+            length = Some(
+                v.parse::<u64>()
+                    .map_err(|_| parse_err(format!("invalid LN '{v}'")))?,
+            );
+        } else if let Some(v) = field.strip_prefix("M5:") {
+            md5 = Some(v.to_ascii_lowercase());
+        }
+    }
+    let name = name.ok_or_else(|| parse_err("@SQ without SN".to_string()))?;
+    let length = length.ok_or_else(|| parse_err(format!("@SQ {name} without LN")))?;
+    Ok(Some(DictEntry { name, length, md5 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\tM5:ABC123\tUR:file:ref.fa\n\
+                    @SQ\tSN:chr2\tLN:50\r\n@PG\tID:x\n";
+        let dict = SequenceDictionary::from_header_text(text).unwrap();
+        assert_eq!(dict.entries().len(), 2);
+        assert_eq!(dict.entries()[0].md5.as_deref(), Some("abc123"));
+        assert_eq!(dict.get("chr2").unwrap().length, 50);
+        assert_eq!(dict.get("chr2").unwrap().md5, None);
+        assert_eq!(
+            dict_path(Path::new("ref/hg38.fa")),
+            PathBuf::from("ref/hg38.dict")
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = SequenceDictionary::from_header_text("@HD\n@SQ\tLN:5\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: @SQ without SN");
+        let err = SequenceDictionary::from_header_text("@SQ\tSN:c\tLN:x\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: invalid LN 'x'");
+    }
+}
@@ -0,0 +1,475 @@
+//! Field-level record comparison
+//!
+//! Equality tells you two records differ; `RecordDiff` tells you where.
+//! Used by round-trip tests through `assert_records_eq!`, and by `lyso diff`
+//! through `diff_lockstep` and `diff_unordered`.
+
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+
+use crate::sort::{RunDir, RunFormat, RunMerge, RunReader, MERGE_FAN_IN};
+
+/// One differing field between two records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    /// First differing byte within the field, for sequence-like fields
+    pub index: Option<usize>,
+    pub left: String,
+    pub right: String,
+}
+
+impl FieldDiff {
+    pub fn new(field: &'static str, left: impl ToString, right: impl ToString) -> Self {
+        FieldDiff {
+            field,
+            index: None,
+            left: left.to_string(),
+            right: right.to_string(),
+        }
+    }
+
+    /// Compare two byte strings, recording the first differing index
+    pub fn bytes(field: &'static str, left: &[u8], right: &[u8]) -> Option<Self> {
+        first_difference(left, right).map(|i| FieldDiff {
+            field,
+            index: Some(i),
+            left: String::from_utf8_lossy(left).into_owned(),
+            right: String::from_utf8_lossy(right).into_owned(),
+        })
+    }
+}
+
+impl Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(i) => write!(
+                f,
+                "{} differs at byte {}: {:?} != {:?}",
+                self.field, i, self.left, self.right
+            ),
+            None => write!(
+                f,
+                "{} differs: {:?} != {:?}",
+                self.field, self.left, self.right
+            ),
+        }
+    }
+}
+
+/// Index of the first differing byte, or of the end of the shorter slice if one is
+/// a prefix of the other. None if the slices are equal.
+pub fn first_difference(left: &[u8], right: &[u8]) -> Option<usize> {
+    match left.iter().zip(right).position(|(a, b)| a != b) {
+        Some(i) => Some(i),
+        None if left.len() != right.len() => Some(left.len().min(right.len())),
+        None => None,
+    }
+}
+
+/// Records that can name the fields in which they differ
+pub trait RecordDiff {
+    /// Empty when the records are semantically equal
+    fn diff(&self, other: &Self) -> Vec<FieldDiff>;
+
+    /// The record's name in a report, usually its id
+    ///
+    /// Empty by default, for records without a name worth showing.
+    fn label(&self) -> String {
+        String::new()
+    }
+}
+
+/// Records with a hash that agrees with their semantic equality
+///
+/// Equal records must hash alike; the hash should cover every field equality
+/// looks at, the id included, so that unequal records rarely collide.
+pub trait CanonicalHash {
+    fn canonical_hash(&self) -> u64;
+}
+
+/// A pair of records at the same position that differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordMismatch {
+    /// 0-based position in both inputs
+    pub index: u64,
+    /// Label of the left record
+    pub label: String,
+    pub diffs: Vec<FieldDiff>,
+}
+
+/// Two inputs compared record by record
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockstepDiff {
+    pub left_records: u64,
+    pub right_records: u64,
+    /// Positions whose records differ, not counting those past the shorter input
+    pub differing: u64,
+    /// The first differing positions, up to the `max_shown` asked for
+    pub shown: Vec<RecordMismatch>,
+}
+
+impl LockstepDiff {
+    pub fn is_equal(&self) -> bool {
+        self.differing == 0 && self.left_records == self.right_records
+    }
+}
+
+/// Compare `left` and `right` record by record, reading both to the end
+///
+/// Stops at the first error from either input.
+pub fn diff_lockstep<T, E, L, R>(left: L, right: R, max_shown: usize) -> Result<LockstepDiff, E>
+where
+    T: RecordDiff,
+    L: IntoIterator<Item = Result<T, E>>,
+    R: IntoIterator<Item = Result<T, E>>,
+{
+    let mut out = LockstepDiff::default();
+    let (mut left, mut right) = (left.into_iter(), right.into_iter());
+    loop {
+        match (left.next().transpose()?, right.next().transpose()?) {
+            (Some(l), Some(r)) => {
+                let diffs = l.diff(&r);
+                if !diffs.is_empty() {
+                    if out.shown.len() < max_shown {
+                        out.shown.push(RecordMismatch {
+                            index: out.left_records,
+                            label: l.label(),
+                            diffs,
+                        });
+                    }
+                    out.differing += 1;
+                }
+                out.left_records += 1;
+                out.right_records += 1;
+            }
+            (Some(_), None) => out.left_records += 1,
+            (None, Some(_)) => out.right_records += 1,
+            (None, None) => return Ok(out),
+        }
+    }
+}
+
+/// Two inputs compared as multisets of canonical record hashes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnorderedDiff {
+    pub left_records: u64,
+    pub right_records: u64,
+    /// Records of `left` without an equal record left over in `right`
+    pub left_only: u64,
+    /// Records of `right` without an equal record left over in `left`
+    pub right_only: u64,
+    /// Hashes of the first `left_only` records in hash order, up to `max_shown`;
+    /// `find_hashes` recovers the records
+    pub left_only_hashes: Vec<u64>,
+    pub right_only_hashes: Vec<u64>,
+}
+
+impl UnorderedDiff {
+    pub fn is_equal(&self) -> bool {
+        self.left_only == 0 && self.right_only == 0
+    }
+}
+
+/// Compare `left` and `right` as multisets, ignoring record order
+///
+/// Only a 64-bit hash of each record is kept. Each side holds up to half of
+/// `memory_limit` bytes of hashes, then spills sorted runs to a temporary directory
+/// that are merged back in hash order. Stops at the first error from either input.
+pub fn diff_unordered<T, E, L, R>(
+    left: L,
+    right: R,
+    max_shown: usize,
+    memory_limit: usize,
+) -> Result<UnorderedDiff, E>
+where
+    T: CanonicalHash,
+    E: From<io::Error>,
+    L: IntoIterator<Item = Result<T, E>>,
+    R: IntoIterator<Item = Result<T, E>>,
+{
+    let limit = (memory_limit / 2 / size_of::<u64>()).max(1);
+    let mut out = UnorderedDiff::default();
+    let mut left_hashes = HashRuns::new(limit);
+    for rec in left {
+        left_hashes.push(rec?.canonical_hash())?;
+        out.left_records += 1;
+    }
+    let mut right_hashes = HashRuns::new(limit);
+    for rec in right {
+        right_hashes.push(rec?.canonical_hash())?;
+        out.right_records += 1;
+    }
+
+    let mut left = left_hashes.into_sorted()?;
+    let mut right = right_hashes.into_sorted()?;
+    let (mut l, mut r) = (left.next().transpose()?, right.next().transpose()?);
+    loop {
+        let from_left = match (l, r) {
+            (None, None) => return Ok(out),
+            (Some(a), Some(b)) if a == b => {
+                l = left.next().transpose()?;
+                r = right.next().transpose()?;
+                continue;
+            }
+            (Some(a), Some(b)) => a < b,
+            (left_hash, _) => left_hash.is_some(),
+        };
+        let (hash, count, shown) = if from_left {
+            let hash = l.take();
+            l = left.next().transpose()?;
+            (hash, &mut out.left_only, &mut out.left_only_hashes)
+        } else {
+            let hash = r.take();
+            r = right.next().transpose()?;
+            (hash, &mut out.right_only, &mut out.right_only_hashes)
+        };
+        if shown.len() < max_shown {
+            shown.extend(hash);
+        }
+        *count += 1;
+    }
+}
+
+/// The first record of `records` for each of `hashes`, in input order
+///
+/// Stops reading once every hash is found.
+pub fn find_hashes<T, E, I>(records: I, hashes: &[u64]) -> Result<Vec<T>, E>
+where
+    T: CanonicalHash,
+    I: IntoIterator<Item = Result<T, E>>,
+{
+    let mut wanted = hashes.to_vec();
+    wanted.sort_unstable();
+    wanted.dedup();
+    let mut found = Vec::new();
+    for rec in records {
+        if wanted.is_empty() {
+            break;
+        }
+        let rec = rec?;
+        if let Ok(i) = wanted.binary_search(&rec.canonical_hash()) {
+            wanted.remove(i);
+            found.push(rec);
+        }
+    }
+    Ok(found)
+}
+
+/// Hashes buffered in memory up to `limit`, then spilled as sorted runs
+struct HashRuns {
+    buffer: Vec<u64>,
+    limit: usize,
+    runs: Option<RunDir>,
+}
+
+impl HashRuns {
+    fn new(limit: usize) -> Self {
+        HashRuns {
+            buffer: Vec::new(),
+            limit,
+            runs: None,
+        }
+    }
+
+    fn push(&mut self, hash: u64) -> io::Result<()> {
+        self.buffer.push(hash);
+        if self.buffer.len() >= self.limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        self.buffer.sort_unstable();
+        let dir = match self.runs.as_mut() {
+            Some(dir) => dir,
+            None => self.runs.insert(RunDir::new()?),
+        };
+        let mut out = dir.create_run()?;
+        for h in &self.buffer {
+            out.write_all(&h.to_le_bytes())?;
+        }
+        out.flush()?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Every hash pushed, in ascending order
+    fn into_sorted(mut self) -> io::Result<SortedHashes> {
+        if self.runs.is_some() && !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let Some(mut dir) = self.runs.take() else {
+            self.buffer.sort_unstable();
+            return Ok(SortedHashes::Memory(
+                std::mem::take(&mut self.buffer).into_iter(),
+            ));
+        };
+        let merge = dir.merge(MERGE_FAN_IN, &HASH_RUNS, u64::cmp as HashCmp)?;
+        Ok(SortedHashes::Runs { merge, _dir: dir })
+    }
+}
+
+type HashCmp = fn(&u64, &u64) -> Ordering;
+
+/// Hashes are spilled as 8 little-endian bytes each
+const HASH_RUNS: RunFormat<u64, io::Error> = RunFormat {
+    write: |hash, out| out.write_all(&hash.to_le_bytes()),
+    read: read_hashes,
+};
+
+fn read_hashes(mut input: BufReader<File>) -> RunReader<u64, io::Error> {
+    Box::new(std::iter::from_fn(move || {
+        let mut buf = [0; 8];
+        match input.read_exact(&mut buf) {
+            Ok(()) => Some(Ok(u64::from_le_bytes(buf))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }))
+}
+
+/// The in-memory buffer when nothing was spilled, or a merge over the spilled runs
+enum SortedHashes {
+    Memory(std::vec::IntoIter<u64>),
+    Runs {
+        merge: RunMerge<u64, io::Error, HashCmp>,
+        /// Removes the runs once merged
+        _dir: RunDir,
+    },
+}
+
+impl Iterator for SortedHashes {
+    type Item = io::Result<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedHashes::Memory(hashes) => hashes.next().map(Ok),
+            SortedHashes::Runs { merge, .. } => merge.next(),
+        }
+    }
+}
+
+/// Assert two records are equal, naming every differing field on failure
+#[macro_export]
+macro_rules! assert_records_eq {
+    ($left:expr, $right:expr $(,)?) => {{
+        let diffs = $crate::diff::RecordDiff::diff(&$left, &$right);
+        if !diffs.is_empty() {
+            panic!(
+                "records differ:\n{}",
+                diffs
+                    .iter()
+                    .map(|d| format!("  {d}"))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            );
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_difference() {
+        assert_eq!(first_difference(b"ACGT", b"ACGT"), None);
+        assert_eq!(first_difference(b"ACGT", b"ACTT"), Some(2));
+        assert_eq!(first_difference(b"ACGT", b"ACG"), Some(3));
+        assert_eq!(first_difference(b"", b"A"), Some(0));
+    }
+
+    #[test]
+    fn test_display() {
+        let d = FieldDiff::bytes("seq", b"ACGT", b"ACTT").unwrap();
+        assert_eq!(d.to_string(), "seq differs at byte 2: \"ACGT\" != \"ACTT\"");
+        let d = FieldDiff::new("flag", 99, 147);
+        assert_eq!(d.to_string(), "flag differs: \"99\" != \"147\"");
+    }
+
+    /// A record whose hash is its own value
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Num(u64);
+
+    impl RecordDiff for Num {
+        fn diff(&self, other: &Self) -> Vec<FieldDiff> {
+            (self != other)
+                .then(|| FieldDiff::new("n", self.0, other.0))
+                .into_iter()
+                .collect()
+        }
+
+        fn label(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    impl CanonicalHash for Num {
+        fn canonical_hash(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn nums(ns: &[u64]) -> impl Iterator<Item = io::Result<Num>> + '_ {
+        ns.iter().map(|n| Ok(Num(*n)))
+    }
+
+    #[test]
+    fn test_lockstep() {
+        let d = diff_lockstep(nums(&[1, 2, 3]), nums(&[1, 2, 3]), 5).unwrap();
+        assert!(d.is_equal());
+        let d = diff_lockstep(nums(&[1, 2, 3, 4, 5]), nums(&[1, 9, 3, 8]), 1).unwrap();
+        assert!(!d.is_equal());
+        assert_eq!((d.left_records, d.right_records, d.differing), (5, 4, 2));
+        assert_eq!(d.shown.len(), 1);
+        assert_eq!((d.shown[0].index, d.shown[0].label.as_str()), (1, "2"));
+        let d = diff_lockstep(nums(&[1, 2]), nums(&[2, 1]), 5).unwrap();
+        assert_eq!(d.differing, 2);
+    }
+
+    #[test]
+    fn test_unordered() {
+        let d = diff_unordered(nums(&[3, 1, 2, 1]), nums(&[1, 2, 1, 3]), 5, 1024).unwrap();
+        assert!(d.is_equal());
+        let d = diff_unordered(nums(&[3, 1, 2, 1]), nums(&[1, 2, 3, 4]), 5, 1024).unwrap();
+        assert_eq!((d.left_only, d.right_only), (1, 1));
+        assert_eq!(
+            (d.left_only_hashes, d.right_only_hashes),
+            (vec![1], vec![4])
+        );
+    }
+
+    #[test]
+    fn test_unordered_spills() {
+        // two hashes per side in memory, so every input spills several runs
+        let left: Vec<u64> = (0..50).map(|i| (i * 37) % 50).collect();
+        let mut right: Vec<u64> = (0..50).rev().collect();
+        let d = diff_unordered(nums(&left), nums(&right), 5, 32).unwrap();
+        assert!(d.is_equal());
+        right[10] = 99;
+        right.push(7);
+        let d = diff_unordered(nums(&left), nums(&right), 5, 32).unwrap();
+        assert_eq!((d.left_records, d.right_records), (50, 51));
+        assert_eq!(d.left_only_hashes, [39]);
+        assert_eq!(d.right_only_hashes, [7, 99]);
+
+        // more runs than are merged at once
+        let left: Vec<u64> = (0..300).map(|i| (i * 7) % 300).collect();
+        let right: Vec<u64> = (1..301).rev().collect();
+        let d = diff_unordered(nums(&left), nums(&right), 5, 32).unwrap();
+        assert_eq!(
+            (d.left_only_hashes, d.right_only_hashes),
+            (vec![0], vec![300])
+        );
+    }
+
+    #[test]
+    fn test_find_hashes() {
+        let found = find_hashes(nums(&[5, 3, 5, 8]), &[8, 5]).unwrap();
+        assert_eq!(found, [Num(5), Num(8)]);
+    }
+}
@@ -0,0 +1,166 @@
+//! A common error type for code that handles several formats
+//!
+//! lyso-common cannot depend on the format crates, so `LysoError` holds their errors
+//! boxed, tagged with the format they came from. Each format crate provides the
+//! `From` impls for its own errors, so `?` works across crates without `map_err`.
+//!
+//! Display messages include the wrapped error's message, and `source()` returns the
+//! wrapped error, so the chain can always be followed down to the root cause.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+use crate::detect::{DetectError, Format};
+use crate::dict::DictError;
+use crate::index_meta::StaleIndex;
+use crate::intervals::IntervalError;
+use crate::io::mmap::MmapError;
+use crate::pos::PosError;
+use crate::region::RegionError;
+use crate::rename::TemplateError;
+use crate::search::MotifError;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LysoError {
+    Io(std::io::Error),
+    /// An error from one of the format crates
+    Format {
+        format: Format,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// A region, interval, template or detection error from lyso-common
+    Input(Box<dyn Error + Send + Sync>),
+    /// Any of the above, with the file it concerns
+    File {
+        path: PathBuf,
+        source: Box<LysoError>,
+    },
+}
+
+impl LysoError {
+    /// Wrap a format crate's error
+    pub fn format(format: Format, err: impl Error + Send + Sync + 'static) -> Self {
+        LysoError::Format {
+            format,
+            source: Box::new(err),
+        }
+    }
+
+    /// Attach the path of the file `err` concerns
+    pub fn from_path(path: impl AsRef<Path>, err: impl Into<LysoError>) -> Self {
+        LysoError::File {
+            path: path.as_ref().to_path_buf(),
+            source: Box::new(err.into()),
+        }
+    }
+}
+
+/// Where in a text input an error was found: `reads.fq:37`, or `line 37` without a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineLocation<'a> {
+    pub path: Option<&'a Path>,
+    /// 1-based
+    pub line: u64,
+}
+
+impl<'a> LineLocation<'a> {
+    pub fn new(path: Option<&'a Path>, line: u64) -> Self {
+        LineLocation { path, line }
+    }
+}
+
+impl Display for LineLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path {
+            Some(path) => write!(f, "{}:{}", path.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+impl Display for LysoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LysoError::Io(e) => write!(f, "{e}"),
+            LysoError::Format { format, source } => write!(f, "{format}: {source}"),
+            LysoError::Input(e) => write!(f, "{e}"),
+            LysoError::File { path, source } => write!(f, "{}: {source}", path.display()),
+        }
+    }
+}
+
+impl Error for LysoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LysoError::Io(e) => Some(e),
+            LysoError::Format { source, .. } => Some(source.as_ref()),
+            LysoError::Input(e) => Some(e.as_ref()),
+            LysoError::File { source, .. } => Some(source.as_ref()),
+        }
+    }
+}
+
+impl From<std::io::Error> for LysoError {
+    fn from(value: std::io::Error) -> Self {
+        LysoError::Io(value)
+    }
+}
+
+macro_rules! input_error {
+    ($($t:ty),*) => {
+        $(impl From<$t> for LysoError {
+            fn from(value: $t) -> Self {
+                LysoError::Input(Box::new(value))
+            }
+        })*
+    };
+}
+
+input_error!(
+    DetectError,
+    DictError,
+    IntervalError,
+    MmapError,
+    MotifError,
+    PosError,
+    RegionError,
+    StaleIndex,
+    TemplateError
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    /// The last error in the `source()` chain
+    fn root<'a>(e: &'a (dyn Error + 'static)) -> &'a (dyn Error + 'static) {
+        let mut e = e;
+        while let Some(next) = e.source() {
+            e = next;
+        }
+        e
+    }
+
+    #[test]
+    fn test_chain_reaches_io_error() {
+        let io = std::io::Error::new(ErrorKind::NotFound, "gone");
+        let err = LysoError::from_path("reads.fq", io);
+        assert_eq!(err.to_string(), "reads.fq: gone");
+        let io = root(&err).downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), ErrorKind::NotFound);
+
+        let io = std::io::Error::new(ErrorKind::UnexpectedEof, "short");
+        let err = LysoError::from(IntervalError::from(io));
+        assert!(root(&err).downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[test]
+    fn test_input_errors() {
+        let err = LysoError::from("chr1:0-5".parse::<crate::region::Region>().unwrap_err());
+        assert!(matches!(err, LysoError::Input(_)));
+        assert!(root(&err).downcast_ref::<PosError>().is_some());
+    }
+}
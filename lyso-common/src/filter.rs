@@ -0,0 +1,273 @@
+//! Composable read filters
+//!
+//! A `RecordPredicate` decides whether a read passes. Predicates see reads through
+//! `SeqRecord`, so the same filter works on FASTA and FASTQ records, and combine with
+//! `and`, `or` and `not`. None of the predicates here allocate per read.
+//!
+//! Quality predicates pass records without qualities (e.g. FASTA).
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::format::mean_qual;
+
+const PHRED_OFFSET: u8 = 33;
+
+/// Sequence and (optional) Phred+33 quality bytes of a read
+pub trait SeqRecord {
+    fn seq_bytes(&self) -> &[u8];
+
+    fn qual_bytes(&self) -> Option<&[u8]>;
+}
+
+/// A pass/fail test on a single read
+pub trait RecordPredicate {
+    /// Whether `rec` passes
+    fn test(&self, rec: &dyn SeqRecord) -> bool;
+
+    fn and<P: RecordPredicate>(self, other: P) -> And<Self, P>
+    where
+        Self: Sized,
+    {
+        And(self, other)
+    }
+
+    fn or<P: RecordPredicate>(self, other: P) -> Or<Self, P>
+    where
+        Self: Sized,
+    {
+        Or(self, other)
+    }
+
+    fn not(self) -> Not<Self>
+    where
+        Self: Sized,
+    {
+        Not(self)
+    }
+}
+
+impl<P: RecordPredicate + ?Sized> RecordPredicate for Box<P> {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        (**self).test(rec)
+    }
+}
+
+/// Passes when both predicates pass
+#[derive(Debug, Clone)]
+pub struct And<A, B>(pub A, pub B);
+
+impl<A: RecordPredicate, B: RecordPredicate> RecordPredicate for And<A, B> {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        self.0.test(rec) && self.1.test(rec)
+    }
+}
+
+/// Passes when either predicate passes
+#[derive(Debug, Clone)]
+pub struct Or<A, B>(pub A, pub B);
+
+impl<A: RecordPredicate, B: RecordPredicate> RecordPredicate for Or<A, B> {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        self.0.test(rec) || self.1.test(rec)
+    }
+}
+
+/// Passes when the inner predicate fails
+#[derive(Debug, Clone)]
+pub struct Not<A>(pub A);
+
+impl<A: RecordPredicate> RecordPredicate for Not<A> {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        !self.0.test(rec)
+    }
+}
+
+/// Passes reads at least `min` bases long
+#[derive(Debug, Clone, Copy)]
+pub struct MinLength(pub usize);
+
+impl RecordPredicate for MinLength {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        rec.seq_bytes().len() >= self.0
+    }
+}
+
+/// Passes reads whose mean Phred score is at least `min`
+#[derive(Debug, Clone, Copy)]
+pub struct MinMeanQuality(pub f64);
+
+impl RecordPredicate for MinMeanQuality {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        rec.qual_bytes()
+            .and_then(mean_qual)
+            .is_none_or(|q| q >= self.0)
+    }
+}
+
+/// Fails reads where any `window` consecutive bases have a mean Phred score below `min_mean`
+///
+/// Reads shorter than the window are judged on their overall mean.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlidingWindowQuality {
+    pub window: usize,
+    pub min_mean: f64,
+}
+
+impl RecordPredicate for SlidingWindowQuality {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        let q = match rec.qual_bytes() {
+            Some(q) if !q.is_empty() => q,
+            _ => return true,
+        };
+        let phred = |i: usize| u64::from(q[i].saturating_sub(PHRED_OFFSET));
+        let window = self.window.clamp(1, q.len());
+        let min_sum = self.min_mean * window as f64;
+        let mut sum = (0..window).map(phred).sum::<u64>();
+        if (sum as f64) < min_sum {
+            return false;
+        }
+        for i in window..q.len() {
+            sum = sum + phred(i) - phred(i - window);
+            if (sum as f64) < min_sum {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parses "window:min_mean", e.g. "4:20"
+impl FromStr for SlidingWindowQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid window quality '{s}', expected WINDOW:MIN_MEAN");
+        let (w, q) = s.split_once(':').ok_or_else(err)?;
+        let window = w.parse::<usize>().map_err(|_| err())?;
+        let min_mean = q.parse::<f64>().map_err(|_| err())?;
+        if window == 0 {
+            return Err(err());
+        }
+        Ok(SlidingWindowQuality { window, min_mean })
+    }
+}
+
+impl Display for SlidingWindowQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.window, self.min_mean)
+    }
+}
+
+/// Fails reads whose dinucleotide entropy is below `threshold`
+///
+/// Entropy is the Shannon entropy of the 16 dinucleotide frequencies, scaled to
+/// 0.0 (a homopolymer) through 1.0 (all dinucleotides equally common). Dinucleotides
+/// containing anything other than A, C, G or T are ignored; a read with none left
+/// has entropy 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LowComplexity {
+    pub threshold: f64,
+}
+
+impl LowComplexity {
+    pub fn entropy(seq: &[u8]) -> f64 {
+        let code = |b: u8| match b.to_ascii_uppercase() {
+            b'A' => Some(0),
+            b'C' => Some(1),
+            b'G' => Some(2),
+            b'T' => Some(3),
+            _ => None,
+        };
+        let mut counts = [0u32; 16];
+        let mut n = 0u32;
+        for w in seq.windows(2) {
+            if let (Some(a), Some(b)) = (code(w[0]), code(w[1])) {
+                counts[a * 4 + b] += 1;
+                n += 1;
+            }
+        }
+        if n == 0 {
+            return 0.0;
+        }
+        let h = counts
+            .iter()
+            .filter(|c| **c > 0)
+            .map(|c| {
+                let p = f64::from(*c) / f64::from(n);
+                -p * p.log2()
+            })
+            .sum::<f64>();
+        // log2(16) bits is the maximum
+        h / 4.0
+    }
+}
+
+impl RecordPredicate for LowComplexity {
+    fn test(&self, rec: &dyn SeqRecord) -> bool {
+        LowComplexity::entropy(rec.seq_bytes()) >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Read(&'static [u8], Option<&'static [u8]>);
+
+    impl SeqRecord for Read {
+        fn seq_bytes(&self) -> &[u8] {
+            self.0
+        }
+
+        fn qual_bytes(&self) -> Option<&[u8]> {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_homopolymer_is_low_complexity() {
+        let filter = LowComplexity { threshold: 0.5 };
+        assert!(!filter.test(&Read(b"AAAAAAAAAAAAAAAAAAAA", None)));
+        assert!(!filter.test(&Read(b"ACACACACACACACACACAC", None)));
+        assert!(filter.test(&Read(b"GATTACACGTTGCAAGTCCATGAGCTTAGC", None)));
+        assert_eq!(LowComplexity::entropy(b"AAAA"), 0.0);
+        assert_eq!(LowComplexity::entropy(b"NN"), 0.0);
+    }
+
+    #[test]
+    fn test_bad_window_at_end() {
+        let filter: SlidingWindowQuality = "4:20".parse().unwrap();
+        // 'I' = Q40, '#' = Q2
+        let good = Read(b"ACGTACGTACGT", Some(b"IIIIIIIIIIII"));
+        let bad_end = Read(b"ACGTACGTACGT", Some(b"IIIIIIII####"));
+        let one_dip = Read(b"ACGTACGTACGT", Some(b"IIIII##IIIII"));
+        assert!(filter.test(&good));
+        assert!(!filter.test(&bad_end));
+        // a two-base dip only drags each 4-base window down to 21
+        assert!(filter.test(&one_dip));
+        // shorter than the window: judged as a whole
+        assert!(filter.test(&Read(b"AC", Some(b"I#"))));
+        assert!(filter.test(&Read(b"ACGT", None)));
+    }
+
+    #[test]
+    fn test_parse_window() {
+        let w = "5:12.5".parse::<SlidingWindowQuality>().unwrap();
+        assert_eq!((w.window, w.min_mean), (5, 12.5));
+        assert!("0:20".parse::<SlidingWindowQuality>().is_err());
+        assert!("20".parse::<SlidingWindowQuality>().is_err());
+    }
+
+    #[test]
+    fn test_combinators() {
+        let read = Read(b"AAAAAAAAAA", Some(b"IIIIIIIIII"));
+        let long = MinLength(5);
+        let complex = LowComplexity { threshold: 0.5 };
+        assert!(!long.and(complex).test(&read));
+        assert!(long.or(complex).test(&read));
+        assert!(complex.not().test(&read));
+        let boxed: Box<dyn RecordPredicate> = Box::new(MinMeanQuality(30.0));
+        assert!(boxed.and(MinLength(11).not()).test(&read));
+    }
+}
@@ -0,0 +1,129 @@
+//! Tabular record output
+//!
+//! `RecordFormatter` turns a record into one tab-separated row. Each format crate
+//! implements it for its own `Record`, so the CLI and library users share one layout.
+//! Numbers are written with Rust's formatting machinery, which never consults the
+//! locale, so the decimal separator is always '.'.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// How the CLI prints records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// The format's own text representation
+    #[default]
+    Native,
+    /// One row per record with a header row
+    Tsv,
+    /// Only counts
+    Summary,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(OutputMode::Native),
+            "tsv" => Ok(OutputMode::Tsv),
+            "summary" => Ok(OutputMode::Summary),
+            _ => Err(format!(
+                "unknown output mode '{s}', expected native, tsv or summary"
+            )),
+        }
+    }
+}
+
+impl Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputMode::Native => write!(f, "native"),
+            OutputMode::Tsv => write!(f, "tsv"),
+            OutputMode::Summary => write!(f, "summary"),
+        }
+    }
+}
+
+/// A record that can be written as a row of tab-separated columns
+pub trait RecordFormatter {
+    /// Column names, in the order `write_tsv` writes them
+    const COLUMNS: &'static [&'static str];
+
+    /// Write the row without a trailing newline
+    fn write_tsv(&self, out: &mut impl fmt::Write) -> fmt::Result;
+
+    fn tsv_header() -> String {
+        Self::COLUMNS.join("\t")
+    }
+
+    fn to_tsv(&self) -> String {
+        let mut s = String::new();
+        // writing to a String cannot fail
+        let _ = self.write_tsv(&mut s);
+        s
+    }
+}
+
+/// Fraction of G and C among all bases, None for an empty sequence
+pub fn gc_content(seq: &[u8]) -> Option<f64> {
+    if seq.is_empty() {
+        return None;
+    }
+    let gc = seq
+        .iter()
+        .filter(|b| matches!(b.to_ascii_uppercase(), b'G' | b'C'))
+        .count();
+    Some(gc as f64 / seq.len() as f64)
+}
+
+/// Mean Phred score of a Phred+33 quality string, None if empty
+pub fn mean_qual(qual: &[u8]) -> Option<f64> {
+    if qual.is_empty() {
+        return None;
+    }
+    let total = qual
+        .iter()
+        .map(|q| u64::from(q.saturating_sub(33)))
+        .sum::<u64>();
+    Some(total as f64 / qual.len() as f64)
+}
+
+/// Write `v` with `precision` decimals, or "." when missing
+pub fn write_opt(out: &mut impl fmt::Write, v: Option<f64>, precision: usize) -> fmt::Result {
+    match v {
+        Some(v) => write!(out, "{v:.precision$}"),
+        None => write!(out, "."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gc_and_qual() {
+        assert_eq!(gc_content(b"ACGT"), Some(0.5));
+        assert_eq!(gc_content(b"ggnn"), Some(0.5));
+        assert_eq!(gc_content(b""), None);
+        assert_eq!(mean_qual(b"I#"), Some(21.0));
+        assert_eq!(mean_qual(b""), None);
+    }
+
+    #[test]
+    fn test_write_opt() {
+        let mut s = String::new();
+        write_opt(&mut s, Some(36.25), 1).unwrap();
+        s.push('\t');
+        write_opt(&mut s, None, 1).unwrap();
+        assert_eq!(s, "36.2\t.");
+    }
+
+    #[test]
+    fn test_output_mode() {
+        for mode in [OutputMode::Native, OutputMode::Tsv, OutputMode::Summary] {
+            assert_eq!(mode.to_string().parse::<OutputMode>(), Ok(mode));
+        }
+        assert!("pretty".parse::<OutputMode>().is_err());
+    }
+}
@@ -0,0 +1,833 @@
+//! Building `.fai` indexes without holding every name
+//!
+//! FASTA and FASTQ indexers yield one entry per record; what differs between
+//! the two is the entry. This module holds what they share once the entries are
+//! made: finding names seen before, as a `DuplicatePolicy` says, either to build
+//! an offsets-only index in memory or to stream the `.fai` lines as they come.
+//!
+//! Names are found through 64-bit hashes (`HashedNames`), compared only when
+//! hashes match. Where the entries do not keep their names, the earlier name is
+//! read back from its header in the file (`reread_name`).
+//!
+//! Fetches through a built index use the same look back: `header_line` finds
+//! the header that ends where a sequence starts, and `read_raw` the whole record.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, BufRead, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+
+use crate::io::{span_len, RecordTooLarge};
+use crate::name_index::NameHasher;
+use crate::raw::RawRecord;
+
+/// What building an index does with a record name seen earlier in the file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Stop with an error naming the record and both offsets
+    #[default]
+    Error,
+    /// Keep the earlier entry, dropping the later one
+    KeepFirst,
+    /// Keep the later entry, in the place of the earlier one
+    KeepLast,
+    /// Index the later record as `<name><suffix><n>`, with the smallest free `n` from 1
+    Rename {
+        /// Text put between the name and the number
+        suffix: String,
+    },
+}
+
+/// How building an index finds repeated names
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupMode {
+    /// Compare names held in memory
+    #[default]
+    Exact,
+    /// Keep a 64-bit hash per name and compare names only when hashes match,
+    /// reading the earlier name back from the file
+    ///
+    /// An offsets-only index built this way keeps no names in its entries,
+    /// except those made up by `DuplicatePolicy::Rename`: `get` does not find
+    /// the others, nor can the index be written, but fetching a record by
+    /// position reads its name from the file.
+    Hashed,
+}
+
+/// How the indexes built from a file hold their names
+///
+/// The default keeps a map from names to entries, as `get` needs. Without it
+/// (`name_map: false`) the index is offsets-only: entries are reached by position,
+/// `get` falls back to a scan, and repeated names are found as `dedup` says; with
+/// the map, they are always found through it.
+///
+/// Heap bytes per entry for a 1 million read FASTQ, at peak while building and
+/// once built (`LYSO_BENCH_READS=1000000 cargo bench -p lyso-bench --bench
+/// index_memory`):
+///
+/// | mode                               | peak | built |
+/// |------------------------------------|------|-------|
+/// | name map (default)                 | 138  | 121   |
+/// | offsets-only, `DedupMode::Exact`   | 132  | 77    |
+/// | offsets-only, `DedupMode::Hashed`  | 131  | 67    |
+/// | streamed to a `.fai` (`--light`)   | 55   | -     |
+///
+/// In memory, the 64-byte entries and the hashes of the names seen so far make
+/// up most of the peak; only streaming the lines keeps neither.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexBuildOptions {
+    /// How repeated names are found without the name map
+    pub dedup: DedupMode,
+    /// Keep the map from names to entries
+    pub name_map: bool,
+}
+
+impl Default for IndexBuildOptions {
+    fn default() -> Self {
+        IndexBuildOptions {
+            dedup: DedupMode::Exact,
+            name_map: true,
+        }
+    }
+}
+
+/// An entry of a FASTA or FASTQ index
+pub trait IndexEntry {
+    /// Record name, empty where the index does not keep it
+    fn name(&self) -> &[u8];
+
+    /// Replace the record name
+    fn set_name(&mut self, name: Vec<u8>);
+
+    /// File offset of the first base, as reported for repeated names
+    fn seq_offset(&self) -> u64;
+
+    /// Write the entry as a `.fai` line
+    fn write_line(&self, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Yields the index entries of a file, one per record
+pub trait Indexer {
+    type Entry: IndexEntry;
+    type Error: From<io::Error> + From<RepeatedName>;
+    type Handle: BufRead + Seek;
+
+    /// The file being indexed, positioned at the header of the next record
+    fn handle(&mut self) -> &mut Self::Handle;
+
+    /// Entry of the next record, None at the end of the file
+    fn next_entry(&mut self) -> Result<Option<Self::Entry>, Self::Error>;
+}
+
+/// A record named like an earlier one, under `DuplicatePolicy::Error`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepeatedName {
+    pub name: Vec<u8>,
+    /// File offset of the first base of the earlier record
+    pub first: u64,
+    /// File offset of the first base of the later record
+    pub second: u64,
+}
+
+impl Display for RepeatedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "duplicate record name {} at offsets {} and {}",
+            String::from_utf8_lossy(&self.name),
+            self.first,
+            self.second
+        )
+    }
+}
+
+impl std::error::Error for RepeatedName {}
+
+/// What a policy makes of an entry whose name was seen before
+enum Seen {
+    /// Drop the entry
+    Drop,
+    /// Put the entry in place of the one under this key
+    Replace(u64),
+    /// Keep the entry, renamed
+    Renamed,
+}
+
+/// Apply `policy` to `entry`, whose name `seen` found under `key`
+///
+/// `lookup` gives the name under a key and the file offset of its first base.
+fn on_repeat<E: IndexEntry, H: Hasher + Default>(
+    policy: &DuplicatePolicy,
+    seen: &HashedNames<H>,
+    entry: &mut E,
+    key: u64,
+    mut lookup: impl FnMut(u64) -> io::Result<(Vec<u8>, u64)>,
+) -> Result<Seen, RepeatFailed> {
+    match policy {
+        DuplicatePolicy::Error => Err(RepeatFailed::Repeated(RepeatedName {
+            name: entry.name().to_vec(),
+            first: lookup(key)?.1,
+            second: entry.seq_offset(),
+        })),
+        DuplicatePolicy::KeepFirst => Ok(Seen::Drop),
+        DuplicatePolicy::KeepLast => Ok(Seen::Replace(key)),
+        DuplicatePolicy::Rename { suffix } => {
+            let mut failed = None;
+            let name = renamed(entry.name(), suffix, |n| {
+                match seen.find(n, |k| lookup(k).map(|(name, _)| name)) {
+                    Ok(found) => found.is_some(),
+                    Err(e) => {
+                        failed = Some(e);
+                        false
+                    }
+                }
+            });
+            if let Some(e) = failed {
+                return Err(e.into());
+            }
+            entry.set_name(name);
+            Ok(Seen::Renamed)
+        }
+    }
+}
+
+/// Why `on_repeat` failed, before conversion to the indexer's error
+enum RepeatFailed {
+    Io(io::Error),
+    Repeated(RepeatedName),
+}
+
+impl From<io::Error> for RepeatFailed {
+    fn from(value: io::Error) -> Self {
+        RepeatFailed::Io(value)
+    }
+}
+
+impl RepeatFailed {
+    fn into_error<E: From<io::Error> + From<RepeatedName>>(self) -> E {
+        match self {
+            RepeatFailed::Io(e) => e.into(),
+            RepeatFailed::Repeated(r) => r.into(),
+        }
+    }
+}
+
+/// Every entry `idxr` yields, repeated names handled by `policy` and found as
+/// `dedup` says, for an index without a name map
+///
+/// Under `DedupMode::Hashed` the entries come back without their names,
+/// except for names made up by `DuplicatePolicy::Rename`.
+pub fn offsets_only<I: Indexer>(
+    idxr: I,
+    policy: &DuplicatePolicy,
+    dedup: DedupMode,
+) -> Result<Vec<I::Entry>, I::Error> {
+    offsets_only_hashed::<I, NameHasher>(idxr, policy, dedup)
+}
+
+fn offsets_only_hashed<I: Indexer, H: Hasher + Default>(
+    mut idxr: I,
+    policy: &DuplicatePolicy,
+    dedup: DedupMode,
+) -> Result<Vec<I::Entry>, I::Error> {
+    let drop_names = dedup == DedupMode::Hashed;
+    let mut entries: Vec<I::Entry> = Vec::new();
+    // header offset of each entry, to read back names the entries dropped
+    let mut starts: Vec<u64> = Vec::new();
+    // keyed by position in `entries`
+    let mut seen = HashedNames::<H>::new();
+    loop {
+        let start = idxr.handle().stream_position()?;
+        let Some(mut entry) = idxr.next_entry()? else {
+            break;
+        };
+        let handle = idxr.handle();
+        let mut lookup = |i: u64| {
+            let e = &entries[i as usize];
+            let name = match e.name() {
+                [] => reread_name(handle, starts[i as usize])?.0,
+                name => name.to_vec(),
+            };
+            Ok((name, e.seq_offset()))
+        };
+        let mut renamed = false;
+        if let Some(i) = seen.find(entry.name(), |i| lookup(i).map(|(name, _)| name))? {
+            match on_repeat(policy, &seen, &mut entry, i, lookup) {
+                Err(e) => return Err(e.into_error()),
+                Ok(Seen::Drop) => continue,
+                Ok(Seen::Replace(i)) => {
+                    if drop_names {
+                        entry.set_name(Vec::new());
+                    }
+                    // the earlier header still names the record
+                    entries[i as usize] = entry;
+                    continue;
+                }
+                Ok(Seen::Renamed) => renamed = true,
+            }
+        }
+        seen.insert(entry.name(), entries.len() as u64);
+        if drop_names {
+            starts.push(start);
+            if !renamed {
+                entry.set_name(Vec::new());
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Flags the `HashedNames` keys of names made up by `DuplicatePolicy::Rename`
+const RENAMED: u64 = 1 << 63;
+
+/// Write a `.fai` line for each entry `idxr` yields, without holding the index
+///
+/// Repeated names are found as by `DedupMode::Hashed`, keyed by the offset of
+/// each header, which is read back from the file when hashes match; besides a
+/// name renamed by `policy`, that offset and hash are all that is kept per
+/// record. `DuplicatePolicy::KeepLast` would change a line already written, so
+/// it fails with `InvalidInput`. Returns the number of lines written.
+pub fn write_index<I: Indexer>(
+    idxr: I,
+    policy: &DuplicatePolicy,
+    out: impl Write,
+) -> Result<usize, I::Error> {
+    write_index_hashed::<I, NameHasher>(idxr, policy, out)
+}
+
+fn write_index_hashed<I: Indexer, H: Hasher + Default>(
+    mut idxr: I,
+    policy: &DuplicatePolicy,
+    mut out: impl Write,
+) -> Result<usize, I::Error> {
+    if *policy == DuplicatePolicy::KeepLast {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "keeping the last of repeated names needs the whole index in memory",
+        )
+        .into());
+    }
+    let mut seen = HashedNames::<H>::new();
+    // names made up by `Rename`, which cannot be read back from the file
+    let mut renames: Vec<Vec<u8>> = Vec::new();
+    let mut written = 0;
+    loop {
+        let start = idxr.handle().stream_position()?;
+        let Some(mut entry) = idxr.next_entry()? else {
+            break;
+        };
+        let handle = idxr.handle();
+        // a header ends where its sequence starts; made-up names are never
+        // repeated under `Error`, the only policy asking where they start
+        let mut lookup = |key: u64| match key & RENAMED {
+            0 => reread_name(handle, key),
+            _ => Ok((renames[(key ^ RENAMED) as usize].clone(), 0)),
+        };
+        let key = match seen.find(entry.name(), |k| lookup(k).map(|(name, _)| name))? {
+            None => start,
+            Some(key) => match on_repeat(policy, &seen, &mut entry, key, lookup) {
+                Err(e) => return Err(e.into_error()),
+                Ok(Seen::Drop) => continue,
+                Ok(Seen::Replace(_)) => unreachable!("rejected above"),
+                Ok(Seen::Renamed) => {
+                    renames.push(entry.name().to_vec());
+                    RENAMED | (renames.len() - 1) as u64
+                }
+            },
+        };
+        seen.insert(entry.name(), key);
+        entry.write_line(&mut out)?;
+        written += 1;
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+/// Append `new` to `entries`, for an index without a name map, failing on a
+/// name already in `entries` or repeated in `new`
+///
+/// Names are found through their hashes, in one pass rather than a scan per
+/// entry. Entries without a name are not checked. On failure nothing is
+/// appended, and the error is the first entry of `new` with a name already seen.
+pub fn append_unique<E: IndexEntry>(entries: &mut Vec<E>, new: Vec<E>) -> Result<(), E> {
+    // keyed by position in `entries`, then in `new` past the end of `entries`
+    let mut seen = HashedNames::<NameHasher>::new();
+    for (i, e) in entries.iter().enumerate() {
+        if !e.name().is_empty() {
+            seen.insert(e.name(), i as u64);
+        }
+    }
+    let first = entries.len();
+    let repeat = new.iter().enumerate().position(|(i, e)| {
+        if e.name().is_empty() {
+            return false;
+        }
+        let name_of = |key: u64| {
+            let key = key as usize;
+            let e = entries.get(key).unwrap_or_else(|| &new[key - first]);
+            Ok::<_, ()>(e.name().to_vec())
+        };
+        if let Ok(Some(_)) = seen.find(e.name(), name_of) {
+            return true;
+        }
+        seen.insert(e.name(), (first + i) as u64);
+        false
+    });
+    match repeat {
+        Some(i) => Err(new.into_iter().nth(i).unwrap()),
+        None => {
+            entries.extend(new);
+            Ok(())
+        }
+    }
+}
+
+/// `name` followed by `suffix` and the smallest number from 1 for which `taken` is false
+pub fn renamed(name: &[u8], suffix: &str, mut taken: impl FnMut(&[u8]) -> bool) -> Vec<u8> {
+    (1u64..)
+        .map(|n| [name, suffix.as_bytes(), n.to_string().as_bytes()].concat())
+        .find(|candidate| !taken(candidate))
+        .expect("ran out of numbers")
+}
+
+pub(crate) fn hash_with<H: Hasher + Default>(name: &[u8]) -> u64 {
+    let mut h = H::default();
+    h.write_usize(name.len());
+    h.write(name);
+    h.finish()
+}
+
+/// Names seen while building an index, kept as 64-bit hashes
+///
+/// Each name is stored as its hash and a key chosen by the caller, such as an
+/// entry number or the file offset of the record, so names themselves are never
+/// held. `find` compares names only when hashes match, asking the caller for the
+/// name under a key, which may mean reading it back from the file. `H` hashes the
+/// names; tests swap it to force collisions.
+#[derive(Debug, Clone)]
+pub struct HashedNames<H = NameHasher> {
+    /// Key of the first name with each hash
+    first: HashMap<u64, u64, BuildHasherDefault<NameHasher>>,
+    /// Keys of later, different names with a hash already in `first`
+    collided: HashMap<u64, Vec<u64>, BuildHasherDefault<NameHasher>>,
+    hasher: PhantomData<H>,
+}
+
+impl<H> Default for HashedNames<H> {
+    fn default() -> Self {
+        HashedNames {
+            first: HashMap::default(),
+            collided: HashMap::default(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<H: Hasher + Default> HashedNames<H> {
+    /// No names
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Key of the name equal to `name`, where `name_of` gives the name under a key
+    ///
+    /// `name_of` is only called for names with the same hash as `name`.
+    pub fn find<E>(
+        &self,
+        name: &[u8],
+        mut name_of: impl FnMut(u64) -> Result<Vec<u8>, E>,
+    ) -> Result<Option<u64>, E> {
+        let h = hash_with::<H>(name);
+        let Some(&first) = self.first.get(&h) else {
+            return Ok(None);
+        };
+        let more = self.collided.get(&h).map_or(&[][..], Vec::as_slice);
+        for &key in std::iter::once(&first).chain(more) {
+            if name_of(key)? == name {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record `name` under `key`; `find` must not have found it
+    pub fn insert(&mut self, name: &[u8], key: u64) {
+        let h = hash_with::<H>(name);
+        match self.first.entry(h) {
+            Entry::Vacant(slot) => {
+                slot.insert(key);
+            }
+            Entry::Occupied(_) => self.collided.entry(h).or_default().push(key),
+        }
+    }
+
+    /// Number of names recorded
+    pub fn len(&self) -> usize {
+        self.first.len() + self.collided.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// True if no name was recorded
+    pub fn is_empty(&self) -> bool {
+        self.first.is_empty()
+    }
+}
+
+/// The name on the header line that starts at `start`, and where that line ends
+///
+/// The name runs from after the '>' or '@' to the first whitespace, as in a
+/// `.fai`. `handle` is left where it was, so an indexer can look back at an
+/// earlier record, as `HashedNames::find` may need to.
+pub fn reread_name<R: BufRead + Seek>(handle: &mut R, start: u64) -> io::Result<(Vec<u8>, u64)> {
+    let resume = handle.stream_position()?;
+    handle.seek(SeekFrom::Start(start))?;
+    let mut line = Vec::new();
+    let read = handle.read_until(b'\n', &mut line)?;
+    handle.seek(SeekFrom::Start(resume))?;
+    let name = line
+        .get(1..)
+        .unwrap_or_default()
+        .split(u8::is_ascii_whitespace)
+        .next()
+        .unwrap_or_default();
+    Ok((name.to_vec(), start + read as u64))
+}
+
+/// The name on the header line that ends at `end`, where a record's sequence
+/// starts, for entries that do not keep their names
+pub fn header_name<R: Read + Seek>(handle: &mut R, end: u64) -> io::Result<Vec<u8>> {
+    let (_, line) = header_line(handle, end)?;
+    let name = line
+        .get(1..)
+        .unwrap_or_default()
+        .split(u8::is_ascii_whitespace)
+        .next()
+        .unwrap_or_default();
+    Ok(name.to_vec())
+}
+
+/// Where the header line that ends at `end` starts, and the line with its '>'
+/// or '@' but without its terminator
+///
+/// Reads backwards from `end` in growing chunks until the start of the line.
+pub fn header_line<R: Read + Seek>(handle: &mut R, end: u64) -> io::Result<(u64, Vec<u8>)> {
+    let mut bytes = Vec::new();
+    let mut from = end;
+    let mut chunk = 256;
+    let start = loop {
+        let to = from;
+        from = from.saturating_sub(chunk);
+        chunk *= 2;
+        let mut read = Vec::with_capacity((to - from) as usize);
+        handle.seek(SeekFrom::Start(from))?;
+        handle.take(to - from).read_to_end(&mut read)?;
+        read.extend_from_slice(&bytes);
+        bytes = read;
+        let line = bytes.trim_ascii_end();
+        match line.iter().rposition(|b| *b == b'\n') {
+            Some(i) => break i + 1,
+            None if from == 0 => break 0,
+            None => {}
+        }
+    };
+    let line = &bytes[start..];
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Ok((from + start as u64, line.to_vec()))
+}
+
+/// The bytes of a record as they are in the file, from the start of the header
+/// line that ends at `seq_start` up to `end`, if they span at most `limit` bytes
+///
+/// The last record of a file may end without its final line terminator.
+pub fn read_raw<R, E>(
+    handle: &mut R,
+    name: &[u8],
+    seq_start: u64,
+    end: u64,
+    limit: usize,
+) -> Result<RawRecord, E>
+where
+    R: Read + Seek,
+    E: From<io::Error> + From<RecordTooLarge>,
+{
+    let (start, header) = header_line(handle, seq_start)?;
+    let len = span_len(name, end.saturating_sub(start), limit)?;
+    handle.seek(SeekFrom::Start(start))?;
+    let mut bytes = Vec::new();
+    handle.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() + 2 < len || bytes.len() < header.len() {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    let id = header.get(1..).unwrap_or_default();
+    let id_len = id
+        .iter()
+        .position(u8::is_ascii_whitespace)
+        .unwrap_or(id.len());
+    let id_start = header.len() - id.len();
+    Ok(RawRecord::new(bytes, id_start..id_start + id_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Hashes every name to 0, so every name after the first collides
+    #[derive(Default)]
+    struct Colliding;
+
+    impl Hasher for Colliding {
+        fn write(&mut self, _: &[u8]) {}
+
+        fn finish(&self) -> u64 {
+            0
+        }
+    }
+
+    /// `name<TAB>length` after a '>' line, one record per line
+    #[derive(Debug, Clone, PartialEq)]
+    struct Line {
+        name: Vec<u8>,
+        offset: u64,
+    }
+
+    impl IndexEntry for Line {
+        fn name(&self) -> &[u8] {
+            &self.name
+        }
+
+        fn set_name(&mut self, name: Vec<u8>) {
+            self.name = name;
+        }
+
+        fn seq_offset(&self) -> u64 {
+            self.offset
+        }
+
+        fn write_line(&self, out: &mut dyn Write) -> io::Result<()> {
+            out.write_all(&self.name)?;
+            writeln!(out, "\t{}", self.offset)
+        }
+    }
+
+    /// Entries of ">name\n" lines, each record's offset just past its header
+    struct Lines<'a>(&'a mut Cursor<&'static [u8]>);
+
+    type TestError = Box<dyn std::error::Error>;
+
+    impl Indexer for Lines<'_> {
+        type Entry = Line;
+        type Error = TestError;
+        type Handle = Cursor<&'static [u8]>;
+
+        fn handle(&mut self) -> &mut Self::Handle {
+            &mut *self.0
+        }
+
+        fn next_entry(&mut self) -> Result<Option<Line>, TestError> {
+            let mut line = Vec::new();
+            self.0.read_until(b'\n', &mut line).unwrap();
+            Ok(line.strip_prefix(b">").map(|l| Line {
+                name: l.trim_ascii_end().to_vec(),
+                offset: self.0.position(),
+            }))
+        }
+    }
+
+    // r1 twice, then the name the second r1 is renamed to
+    const REPEATS: &[u8] = b">r1\n>r2\n>r1\n>r1.1\n";
+    const DISTINCT: &[u8] = b">a\n>b\n>ab\n";
+
+    fn stream<H: Hasher + Default>(
+        input: &'static [u8],
+        policy: &DuplicatePolicy,
+    ) -> Result<String, String> {
+        let mut out = Vec::new();
+        let mut input = Cursor::new(input);
+        write_index_hashed::<_, H>(Lines(&mut input), policy, &mut out)
+            .map_err(|e| e.to_string())?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn build<H: Hasher + Default>(
+        input: &'static [u8],
+        policy: &DuplicatePolicy,
+        dedup: DedupMode,
+    ) -> Result<Vec<Line>, String> {
+        offsets_only_hashed::<_, H>(Lines(&mut Cursor::new(input)), policy, dedup)
+            .map_err(|e| e.to_string())
+    }
+
+    fn names(entries: &[Line]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|e| std::str::from_utf8(&e.name).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_forced_collision() {
+        // with every hash equal, names are told apart by reading them back
+        let rename = DuplicatePolicy::Rename { suffix: ".".into() };
+        for policy in [DuplicatePolicy::Error, rename.clone()] {
+            assert_eq!(
+                stream::<Colliding>(DISTINCT, &policy).unwrap(),
+                "a\t3\nb\t6\nab\t10\n"
+            );
+        }
+        assert_eq!(
+            stream::<Colliding>(REPEATS, &DuplicatePolicy::Error).unwrap_err(),
+            "duplicate record name r1 at offsets 4 and 12"
+        );
+        let fai = stream::<Colliding>(REPEATS, &rename).unwrap();
+        assert_eq!(fai, "r1\t4\nr2\t8\nr1.1\t12\nr1.1.1\t18\n");
+        assert_eq!(stream::<NameHasher>(REPEATS, &rename).unwrap(), fai);
+        assert_eq!(
+            stream::<Colliding>(REPEATS, &DuplicatePolicy::KeepFirst).unwrap(),
+            "r1\t4\nr2\t8\nr1.1\t18\n"
+        );
+        assert!(stream::<Colliding>(REPEATS, &DuplicatePolicy::KeepLast).is_err());
+
+        for dedup in [DedupMode::Exact, DedupMode::Hashed] {
+            let built = build::<Colliding>(DISTINCT, &DuplicatePolicy::Error, dedup).unwrap();
+            assert_eq!(built.len(), 3);
+            let err = build::<Colliding>(REPEATS, &DuplicatePolicy::Error, dedup).unwrap_err();
+            assert_eq!(err, "duplicate record name r1 at offsets 4 and 12");
+        }
+    }
+
+    #[test]
+    fn test_offsets_only() {
+        let rename = DuplicatePolicy::Rename { suffix: ".".into() };
+        let exact = |policy: &DuplicatePolicy| {
+            build::<Colliding>(REPEATS, policy, DedupMode::Exact).unwrap()
+        };
+        assert_eq!(names(&exact(&rename)), ["r1", "r2", "r1.1", "r1.1.1"]);
+        let offsets = |e: Vec<Line>| e.iter().map(|e| e.offset).collect::<Vec<_>>();
+        assert_eq!(offsets(exact(&DuplicatePolicy::KeepFirst)), [4, 8, 18]);
+        assert_eq!(offsets(exact(&DuplicatePolicy::KeepLast)), [12, 8, 18]);
+
+        // hashed, only made-up names are kept, and the rest read back as needed
+        let hashed = |policy: &DuplicatePolicy| {
+            build::<Colliding>(REPEATS, policy, DedupMode::Hashed).unwrap()
+        };
+        assert_eq!(names(&hashed(&rename)), ["", "", "r1.1", "r1.1.1"]);
+        let kept = hashed(&DuplicatePolicy::KeepLast);
+        assert_eq!(names(&kept), ["", "", ""]);
+        assert_eq!(offsets(kept), [12, 8, 18]);
+        for policy in [
+            DuplicatePolicy::KeepFirst,
+            DuplicatePolicy::KeepLast,
+            rename,
+        ] {
+            assert_eq!(
+                offsets(build::<NameHasher>(REPEATS, &policy, DedupMode::Hashed).unwrap()),
+                offsets(hashed(&policy)),
+                "{policy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_append_unique() {
+        let line = |name: &str, offset| Line {
+            name: name.as_bytes().to_vec(),
+            offset,
+        };
+        let mut entries = vec![line("a", 1), line("", 2), line("b", 3)];
+        let new = vec![line("c", 4), line("", 5), line("d", 6)];
+        assert!(append_unique(&mut entries, new).is_ok());
+        let offsets = entries.iter().map(|e| e.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, [1, 2, 3, 4, 5, 6]);
+
+        // a name already there, or repeated among the new entries
+        let new = vec![line("e", 7), line("a", 8)];
+        assert_eq!(append_unique(&mut entries, new).unwrap_err().offset, 8);
+        let new = vec![line("", 7), line("f", 8), line("", 9), line("f", 10)];
+        assert_eq!(append_unique(&mut entries, new).unwrap_err().offset, 10);
+        assert_eq!(entries.len(), 6);
+    }
+
+    #[test]
+    fn test_hashed_names() {
+        let names: [&[u8]; 3] = [b"read1", b"read2", b"read10"];
+        let fetched = std::cell::Cell::new(0);
+        let name_of = |key: u64| {
+            fetched.set(fetched.get() + 1);
+            Ok::<_, ()>(names[key as usize].to_vec())
+        };
+
+        let mut seen = HashedNames::<NameHasher>::new();
+        for (key, name) in names.iter().enumerate() {
+            assert_eq!(seen.find(name, name_of), Ok(None));
+            seen.insert(name, key as u64);
+        }
+        assert_eq!(seen.find(b"read2", name_of), Ok(Some(1)));
+        assert_eq!(seen.find(b"read3", name_of), Ok(None));
+        // only the matching hash was compared
+        assert_eq!(fetched.get(), 1);
+
+        // with every hash equal, names are told apart by comparing them
+        let mut seen = HashedNames::<Colliding>::new();
+        for (key, name) in names.iter().enumerate() {
+            assert_eq!(seen.find(name, name_of), Ok(None));
+            seen.insert(name, key as u64);
+        }
+        assert_eq!(seen.len(), 3);
+        fetched.set(0);
+        assert_eq!(seen.find(b"read10", name_of), Ok(Some(2)));
+        assert_eq!(fetched.get(), 3);
+        assert_eq!(seen.find(b"read1", name_of), Ok(Some(0)));
+        assert_eq!(seen.find(b"read", name_of), Ok(None));
+        assert_eq!(
+            seen.find(b"read1", |_| Err("unreadable")),
+            Err("unreadable")
+        );
+    }
+
+    #[test]
+    fn test_reread_name() {
+        let mut input = Cursor::new(&b">a desc\nAC\n@read2\r\nGT\n"[..]);
+        input.seek(SeekFrom::Start(3)).unwrap();
+        assert_eq!(
+            reread_name(&mut input, 11).unwrap(),
+            (b"read2".to_vec(), 19)
+        );
+        assert_eq!(reread_name(&mut input, 0).unwrap(), (b"a".to_vec(), 8));
+        assert_eq!(input.position(), 3);
+    }
+
+    #[test]
+    fn test_header_name() {
+        let long = format!(">{} x\nAC\n", "n".repeat(1000));
+        let bytes = format!(">a desc\nAC\n\n>b x\r\nGT\n{long}").into_bytes();
+        let mut input = Cursor::new(&bytes[..]);
+        assert_eq!(header_name(&mut input, 18).unwrap(), b"b");
+        assert_eq!(header_name(&mut input, 8).unwrap(), b"a");
+        assert_eq!(header_name(&mut input, 0).unwrap(), b"");
+        let end = bytes.len() as u64 - 3;
+        assert_eq!(
+            header_name(&mut input, end).unwrap(),
+            "n".repeat(1000).as_bytes()
+        );
+        assert_eq!(header_line(&mut input, 18).unwrap(), (12, b">b x".to_vec()));
+        assert_eq!(
+            header_line(&mut input, 8).unwrap(),
+            (0, b">a desc".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_renamed() {
+        let taken = [b"chr1".to_vec(), b"chr1_1".to_vec()];
+        assert_eq!(
+            renamed(b"chr1", "_", |n| taken.iter().any(|t| t == n)),
+            b"chr1_2"
+        );
+        assert_eq!(renamed(b"chr2", ".", |_| false), b"chr2.1");
+    }
+}
@@ -0,0 +1,249 @@
+//! Staleness checks for `.fai` indexes
+//!
+//! A `.fai` says nothing about the file it was built from, so an index that
+//! outlived an edit to its FASTA/FASTQ silently points at the wrong bytes. The
+//! size, mtime and a hash of the first and last 64KB of the file are kept in a
+//! `.fai.meta` sidecar, leaving the `.fai` itself as samtools writes it.
+
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::io::atomic::AtomicFile;
+
+/// Bytes hashed at each end of the file
+const HASH_SPAN: u64 = 1 << 16;
+
+/// `<path>.fai`
+pub fn fai_path(path: &Path) -> PathBuf {
+    let mut fai = path.as_os_str().to_owned();
+    fai.push(".fai");
+    PathBuf::from(fai)
+}
+
+/// `<index>.meta`, the sidecar of an index file
+pub fn meta_path(index: &Path) -> PathBuf {
+    let mut meta = index.as_os_str().to_owned();
+    meta.push(".meta");
+    PathBuf::from(meta)
+}
+
+#[derive(Debug)]
+pub enum StaleIndex {
+    Io(std::io::Error),
+    /// The file's size or content differs from when the index was built
+    Changed(&'static str),
+    /// The index points past the end of the file
+    PastEnd {
+        end: u64,
+        size: u64,
+    },
+}
+
+impl Display for StaleIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StaleIndex::Io(e) => write!(f, "{e}"),
+            StaleIndex::Changed(what) => {
+                write!(
+                    f,
+                    "index is stale: the file's {what} changed since it was built"
+                )
+            }
+            StaleIndex::PastEnd { end, size } => write!(
+                f,
+                "index is stale: it points to byte {end} of a {size} byte file"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StaleIndex {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StaleIndex::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StaleIndex {
+    fn from(value: std::io::Error) -> Self {
+        StaleIndex::Io(value)
+    }
+}
+
+/// What an index knows about the file it was built from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexMeta {
+    pub size: u64,
+    /// Since the Unix epoch, None where the platform has no mtime; for reference only
+    pub mtime: Option<Duration>,
+    /// FNV-1a of the first 64KB
+    pub head: u64,
+    /// FNV-1a of the last 64KB
+    pub tail: u64,
+}
+
+impl IndexMeta {
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        let mut f = File::open(path)?;
+        let md = f.metadata()?;
+        let size = md.len();
+        let mtime = md
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+        let head = hash_span(&mut f, 0)?;
+        let tail = hash_span(&mut f, size.saturating_sub(HASH_SPAN))?;
+        Ok(IndexMeta {
+            size,
+            mtime,
+            head,
+            tail,
+        })
+    }
+
+    /// Write as `key\tvalue` lines
+    pub fn write(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "size\t{}", self.size)?;
+        if let Some(t) = self.mtime {
+            writeln!(out, "mtime\t{}.{:09}", t.as_secs(), t.subsec_nanos())?;
+        }
+        writeln!(out, "head\t{:016x}", self.head)?;
+        writeln!(out, "tail\t{:016x}", self.tail)?;
+        out.flush()
+    }
+
+    /// Read what `write` wrote; unknown keys are ignored
+    pub fn read(input: impl BufRead) -> std::io::Result<Self> {
+        let malformed = || std::io::Error::new(ErrorKind::InvalidData, "malformed index meta");
+        let (mut size, mut mtime, mut head, mut tail) = (None, None, None, None);
+        for line in input.lines() {
+            let line = line?;
+            let Some((key, value)) = line.split_once('\t') else {
+                return Err(malformed());
+            };
+            match key {
+                "size" => size = value.parse().ok(),
+                "mtime" => {
+                    let (secs, nanos) = value.split_once('.').ok_or_else(malformed)?;
+                    mtime = secs
+                        .parse()
+                        .ok()
+                        .zip(nanos.parse().ok())
+                        .map(|(s, n)| Duration::new(s, n));
+                }
+                "head" => head = u64::from_str_radix(value, 16).ok(),
+                "tail" => tail = u64::from_str_radix(value, 16).ok(),
+                _ => {}
+            }
+        }
+        Ok(IndexMeta {
+            size: size.ok_or_else(malformed)?,
+            mtime,
+            head: head.ok_or_else(malformed)?,
+            tail: tail.ok_or_else(malformed)?,
+        })
+    }
+
+    /// Whether `current` describes the same file
+    ///
+    /// The mtime is not compared: a copy changes it without changing the bytes,
+    /// and a quick rewrite may not change it at all.
+    pub fn check(&self, current: &IndexMeta) -> Result<(), StaleIndex> {
+        if self.size != current.size {
+            Err(StaleIndex::Changed("size"))
+        } else if (self.head, self.tail) != (current.head, current.tail) {
+            Err(StaleIndex::Changed("content"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// FNV-1a of up to `HASH_SPAN` bytes from `start`
+fn hash_span(f: &mut File, start: u64) -> std::io::Result<u64> {
+    f.seek(SeekFrom::Start(start))?;
+    let mut buf = Vec::new();
+    f.take(HASH_SPAN).read_to_end(&mut buf)?;
+    Ok(buf.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    }))
+}
+
+/// Record `path` in the sidecar of its index `index`
+pub fn write_meta(path: &Path, index: &Path) -> std::io::Result<()> {
+    let meta = IndexMeta::of_file(path)?;
+    let mut out = AtomicFile::create(meta_path(index), true)?;
+    meta.write(&mut out)?;
+    out.commit()
+}
+
+/// Check the index `index` of `path`, whose entries reach up to byte `data_end`
+///
+/// The sidecar is compared when there is one; either way the entries must fit in
+/// the file, which catches truncation even for indexes written by samtools.
+pub fn validate(path: &Path, index: &Path, data_end: u64) -> Result<(), StaleIndex> {
+    match File::open(meta_path(index)) {
+        Ok(f) => IndexMeta::read(BufReader::new(f))?.check(&IndexMeta::of_file(path)?)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let size = fs::metadata(path)?.len();
+    if data_end > size {
+        return Err(StaleIndex::PastEnd {
+            end: data_end,
+            size,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x.fa");
+        fs::write(&path, b">a\nACGT\n").unwrap();
+        let meta = IndexMeta::of_file(&path).unwrap();
+        assert_eq!(meta.size, 8);
+        let mut out = Vec::new();
+        meta.write(&mut out).unwrap();
+        assert_eq!(IndexMeta::read(&out[..]).unwrap(), meta);
+        assert!(IndexMeta::read(&b"size\t8\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x.fa");
+        let fai = fai_path(&path);
+        fs::write(&path, b">a\nACGT\n").unwrap();
+        // without a sidecar only the entries are checked against the size
+        assert!(validate(&path, &fai, 7).is_ok());
+        assert!(matches!(
+            validate(&path, &fai, 9),
+            Err(StaleIndex::PastEnd { end: 9, size: 8 })
+        ));
+
+        write_meta(&path, &fai).unwrap();
+        assert!(validate(&path, &fai, 7).is_ok());
+        fs::write(&path, b">a\nACGA\n").unwrap();
+        let err = validate(&path, &fai, 7).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "index is stale: the file's content changed since it was built"
+        );
+        fs::write(&path, b">a\nACGT\n>b\nTT\n").unwrap();
+        assert!(matches!(
+            validate(&path, &fai, 7),
+            Err(StaleIndex::Changed("size"))
+        ));
+    }
+}
@@ -0,0 +1,485 @@
+//! BED interval ingestion
+//!
+//! Parses BED3 through BED6 lines into 0-based half-open `NamedInterval`s and
+//! collects them into an `IntervalSet` for overlap queries by sequence name.
+//! Track, browser and comment lines are skipped.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::io::BufRead;
+use std::str::FromStr;
+
+use crate::pos::ZeroBased;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum IntervalError {
+    IoError(std::io::Error),
+    /// A malformed line, with its 1-based line number
+    Parse {
+        line: usize,
+        msg: String,
+    },
+}
+
+impl Display for IntervalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntervalError::IoError(e) => write!(f, "{e}"),
+            IntervalError::Parse { line, msg } => write!(f, "line {line}: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for IntervalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IntervalError::IoError(e) => Some(e),
+            IntervalError::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for IntervalError {
+    fn from(value: std::io::Error) -> Self {
+        IntervalError::IoError(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strand {
+    Forward,
+    Reverse,
+    #[default]
+    Unknown,
+}
+
+impl Display for Strand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strand::Forward => write!(f, "+"),
+            Strand::Reverse => write!(f, "-"),
+            Strand::Unknown => write!(f, "."),
+        }
+    }
+}
+
+impl FromStr for Strand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "+" => Ok(Strand::Forward),
+            "-" => Ok(Strand::Reverse),
+            "." => Ok(Strand::Unknown),
+            _ => Err(format!("invalid strand '{s}'")),
+        }
+    }
+}
+
+/// A 0-based, half-open interval on sequence `name`
+///
+/// `label`, `score` and `strand` are BED columns 4-6 and are absent for BED3.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedInterval {
+    pub name: String,
+    pub start: ZeroBased,
+    pub end: ZeroBased,
+    pub label: Option<String>,
+    pub score: Option<f64>,
+    pub strand: Strand,
+}
+
+impl NamedInterval {
+    pub fn new(name: &str, start: u64, end: u64) -> Self {
+        NamedInterval {
+            name: name.to_string(),
+            start: ZeroBased::new(start),
+            end: ZeroBased::new(end),
+            label: None,
+            score: None,
+            strand: Strand::Unknown,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end.get() - self.start.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether this interval shares at least one base with [start, end)
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start.get() < end && self.end.get() > start
+    }
+}
+
+fn is_skipped(line: &str) -> bool {
+    line.is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+}
+
+fn parse_coord(s: Option<&str>, column: &str) -> Result<u64, String> {
+    let s = s.ok_or_else(|| format!("missing {column} column"))?;
+    s.parse::<u64>()
+        .map_err(|_| format!("invalid {column} '{s}'"))
+}
+
+/// Parse a single BED line
+///
+/// Returns `Ok(None)` for blank, comment, track and browser lines.
+/// Columns beyond the sixth are ignored.
+pub fn parse_bed_line(line: &str) -> Result<Option<NamedInterval>, String> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    if is_skipped(line) {
+        return Ok(None);
+    }
+    let mut cols = line.split('\t');
+    let name = match cols.next() {
+        Some(n) if !n.is_empty() => n,
+        _ => return Err(String::from("missing chrom column")),
+    };
+    let start = parse_coord(cols.next(), "start")?;
+    let end = parse_coord(cols.next(), "end")?;
+    if end < start {
+        return Err(format!("end {end} is before start {start}"));
+    }
+    let mut interval = NamedInterval::new(name, start, end);
+    interval.label = cols.next().map(String::from);
+    interval.score = match cols.next() {
+        None | Some(".") => None,
+        Some(s) => Some(
+            s.parse::<f64>()
+                .map_err(|_| format!("invalid score '{s}'"))?,
+        ),
+    };
+    if let Some(s) = cols.next() {
+        interval.strand = s.parse()?;
+    }
+    Ok(Some(interval))
+}
+
+/// Streams intervals from a BED file
+pub struct BedReader<R> {
+    inner: R,
+    buf: String,
+    line: usize,
+    failed: bool,
+}
+
+impl<R: BufRead> BedReader<R> {
+    pub fn new(inner: R) -> Self {
+        BedReader {
+            inner,
+            buf: String::new(),
+            line: 0,
+            failed: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for BedReader<R> {
+    type Item = Result<NamedInterval, IntervalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            self.buf.clear();
+            match self.inner.read_line(&mut self.buf) {
+                Ok(0) => return None,
+                Ok(_) => self.line += 1,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e.into()));
+                }
+            }
+            match parse_bed_line(&self.buf) {
+                Ok(Some(interval)) => return Some(Ok(interval)),
+                Ok(None) => continue,
+                Err(msg) => {
+                    self.failed = true;
+                    return Some(Err(IntervalError::Parse {
+                        line: self.line,
+                        msg,
+                    }));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Intervals on a single sequence, sorted by start
+///
+/// `max_end[i]` is the greatest end among `intervals[..=i]`, which lets an overlap
+/// query binary search on start and still see long intervals that began earlier.
+/// `rank[i]` is where `intervals[i]` came in the input, across all sequences.
+#[derive(Debug, Default)]
+struct SortedIntervals {
+    intervals: Vec<NamedInterval>,
+    max_end: Vec<u64>,
+    rank: Vec<usize>,
+}
+
+impl SortedIntervals {
+    fn new(mut ranked: Vec<(usize, NamedInterval)>) -> Self {
+        ranked.sort_by_key(|(rank, i)| (i.start, i.end, *rank));
+        let (rank, intervals): (Vec<usize>, Vec<NamedInterval>) = ranked.into_iter().unzip();
+        let max_end = intervals
+            .iter()
+            .scan(0, |m, i| {
+                *m = (*m).max(i.end.get());
+                Some(*m)
+            })
+            .collect();
+        SortedIntervals {
+            intervals,
+            max_end,
+            rank,
+        }
+    }
+
+    /// Merge overlapping and book-ended intervals; BED columns 4-6 are dropped,
+    /// and each merged interval takes the earliest rank of those it covers
+    fn merged(self) -> Self {
+        let mut out: Vec<(usize, NamedInterval)> = Vec::with_capacity(self.intervals.len());
+        for (rank, i) in self.rank.into_iter().zip(self.intervals) {
+            match out.last_mut() {
+                Some((r, last)) if i.start <= last.end => {
+                    last.end = last.end.max(i.end);
+                    *r = (*r).min(rank);
+                }
+                _ => out.push((
+                    rank,
+                    NamedInterval::new(&i.name, i.start.get(), i.end.get()),
+                )),
+            }
+        }
+        SortedIntervals::new(out)
+    }
+
+    fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &NamedInterval> {
+        // intervals[..upper] start before `end`
+        let upper = self.intervals.partition_point(|i| i.start.get() < end);
+        // max_end is non-decreasing, so everything before `lower` ends at or before `start`
+        let lower = self.max_end[..upper].partition_point(|m| *m <= start);
+        self.intervals[lower..upper]
+            .iter()
+            .filter(move |i| i.overlaps(start, end))
+    }
+}
+
+/// Intervals grouped by sequence name, supporting overlap queries
+///
+/// Each sequence is currently a sorted vector searched by bisection; callers only
+/// see `overlaps` and `overlapping`, so the backing structure can change freely.
+#[derive(Debug, Default)]
+pub struct IntervalSet {
+    seqs: HashMap<String, SortedIntervals>,
+}
+
+impl IntervalSet {
+    /// Read every interval from a BED stream
+    ///
+    /// Input need not be sorted. With `merge`, overlapping and book-ended intervals
+    /// on the same sequence are collapsed.
+    pub fn from_bed<R: BufRead>(reader: R, merge: bool) -> Result<Self, IntervalError> {
+        let intervals = BedReader::new(reader).collect::<Result<Vec<NamedInterval>, _>>()?;
+        let set = IntervalSet::from_iter(intervals);
+        Ok(if merge { set.merged() } else { set })
+    }
+
+    /// Collapse overlapping and book-ended intervals on each sequence
+    pub fn merged(self) -> Self {
+        IntervalSet {
+            seqs: self
+                .seqs
+                .into_iter()
+                .map(|(k, v)| (k, v.merged()))
+                .collect(),
+        }
+    }
+
+    /// Whether any interval on `name` overlaps [start, end)
+    pub fn overlaps(&self, name: &str, start: u64, end: u64) -> bool {
+        self.overlapping(name, start, end).next().is_some()
+    }
+
+    /// All intervals on `name` overlapping [start, end), ordered by start
+    pub fn overlapping<'a>(
+        &'a self,
+        name: &str,
+        start: u64,
+        end: u64,
+    ) -> Box<dyn Iterator<Item = &'a NamedInterval> + 'a> {
+        match self.seqs.get(name) {
+            Some(s) => Box::new(s.overlapping(start, end)),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Intervals on `name`, ordered by start
+    pub fn get(&self, name: &str) -> &[NamedInterval] {
+        self.seqs.get(name).map_or(&[], |s| s.intervals.as_slice())
+    }
+
+    /// Every interval in input order, as read from the BED file or collected
+    ///
+    /// After `merged`, each merged interval takes the place of the first of the
+    /// intervals it covers.
+    pub fn in_input_order(&self) -> Vec<&NamedInterval> {
+        let mut all = self
+            .seqs
+            .values()
+            .flat_map(|s| s.rank.iter().zip(&s.intervals))
+            .collect::<Vec<_>>();
+        all.sort_unstable_by_key(|(rank, _)| **rank);
+        all.into_iter().map(|(_, i)| i).collect()
+    }
+
+    /// Sequence names with at least one interval, in arbitrary order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.seqs.keys().map(|k| k.as_str())
+    }
+
+    /// Total number of intervals
+    pub fn len(&self) -> usize {
+        self.seqs.values().map(|s| s.intervals.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl FromIterator<NamedInterval> for IntervalSet {
+    fn from_iter<T: IntoIterator<Item = NamedInterval>>(iter: T) -> Self {
+        let mut by_name: HashMap<String, Vec<(usize, NamedInterval)>> = HashMap::new();
+        for (rank, i) in iter.into_iter().enumerate() {
+            by_name.entry(i.name.clone()).or_default().push((rank, i));
+        }
+        IntervalSet {
+            seqs: by_name
+                .into_iter()
+                .map(|(k, v)| (k, SortedIntervals::new(v)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BED: &str = "track name=test\n\
+        # comment\n\
+        browser position chr1\n\
+        chr1\t500\t600\tb\t0\t-\n\
+        chr1\t100\t200\ta\t5.5\t+\n\
+        \n\
+        chr2\t0\t10\n\
+        chr1\t150\t300\tc\t.\t.\n\
+        chr1\t300\t350\n\
+        chr1\t0\t1000\tlong\n";
+
+    fn starts(set: &IntervalSet, name: &str, start: u64, end: u64) -> Vec<u64> {
+        set.overlapping(name, start, end)
+            .map(|i| i.start.get())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let i = parse_bed_line("chr1\t100\t200\tfeat\t12\t-\textra\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(i.name, "chr1");
+        assert_eq!((i.start.get(), i.end.get()), (100, 200));
+        assert_eq!(i.label.as_deref(), Some("feat"));
+        assert_eq!(i.score, Some(12.0));
+        assert_eq!(i.strand, Strand::Reverse);
+        assert!(parse_bed_line("track name=x").unwrap().is_none());
+        assert!(parse_bed_line("#chrom\tstart\tend").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unsorted_input() {
+        let set = IntervalSet::from_bed(Cursor::new(BED), false).unwrap();
+        assert_eq!(set.len(), 6);
+        let chr1 = set
+            .get("chr1")
+            .iter()
+            .map(|i| i.start.get())
+            .collect::<Vec<u64>>();
+        assert_eq!(chr1, vec![0, 100, 150, 300, 500]);
+        // the long interval starting at 0 is found even though it sorts first
+        assert_eq!(starts(&set, "chr1", 400, 450), vec![0]);
+        assert_eq!(starts(&set, "chr1", 190, 210), vec![0, 100, 150]);
+        // half-open: end is exclusive
+        assert_eq!(starts(&set, "chr2", 10, 20), Vec::<u64>::new());
+        assert!(set.overlaps("chr2", 9, 10));
+        assert!(!set.overlaps("chr3", 0, 100));
+
+        let order = set
+            .in_input_order()
+            .iter()
+            .map(|i| (i.name.as_str(), i.start.get()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                ("chr1", 500),
+                ("chr1", 100),
+                ("chr2", 0),
+                ("chr1", 150),
+                ("chr1", 300),
+                ("chr1", 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let bed = "chr1\t100\t200\nchr1\t150\t300\nchr1\t300\t350\nchr1\t400\t500\n";
+        let set = IntervalSet::from_bed(Cursor::new(bed), true).unwrap();
+        let merged = set
+            .get("chr1")
+            .iter()
+            .map(|i| (i.start.get(), i.end.get()))
+            .collect::<Vec<(u64, u64)>>();
+        assert_eq!(merged, vec![(100, 350), (400, 500)]);
+        assert!(!set.overlaps("chr1", 350, 400));
+
+        // a merged interval stands where the first interval it covers was
+        let bed = "chr1\t400\t500\nchr2\t0\t5\nchr1\t150\t300\nchr1\t100\t200\n";
+        let set = IntervalSet::from_bed(Cursor::new(bed), true).unwrap();
+        let order = set
+            .in_input_order()
+            .iter()
+            .map(|i| (i.start.get(), i.end.get()))
+            .collect::<Vec<_>>();
+        assert_eq!(order, [(400, 500), (0, 5), (100, 300)]);
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let cases = [
+            ("chr1\t1\t2\n# ok\nchr1\tx\t5\n", 3, "invalid start 'x'"),
+            ("chr1\t10\n", 1, "missing end column"),
+            ("chr1\t1\t2\nchr1\t20\t10\n", 2, "end 10 is before start 20"),
+            ("chr1\t1\t2\tn\t0\t*\n", 1, "invalid strand '*'"),
+        ];
+        for (bed, line, msg) in cases {
+            match IntervalSet::from_bed(Cursor::new(bed), false) {
+                Err(IntervalError::Parse { line: l, msg: m }) => {
+                    assert_eq!((l, m.as_str()), (line, msg))
+                }
+                other => panic!("expected parse error, got {other:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Output files that appear whole or not at all
+//!
+//! A `PendingFile` is a temporary file in the destination's directory, named
+//! `.NAME.PID-N.tmp`. `persist` syncs it to disk and renames it over the
+//! destination, so readers see either the old file or the complete new one,
+//! then syncs the directory so the rename survives a crash. Without overwriting,
+//! it is hard linked to the destination instead, which fails if a file appeared
+//! there meanwhile. Dropped without `persist`, on an error return or a panic, it
+//! is removed.
+//! `AtomicFile` is a `PendingFile` written through one handle.
+//!
+//! `std::process::exit` runs no destructors, so a process exiting that way calls
+//! `remove_pending` first to remove the temporary files still open.
+//!
+//! ```
+//! use std::io::Write;
+//! use lyso_common::io::atomic::AtomicFile;
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let dest = dir.path().join("out.txt");
+//! let mut out = AtomicFile::create(&dest, false).unwrap();
+//! out.write_all(b"done\n").unwrap();
+//! assert!(!dest.exists());
+//! out.commit().unwrap();
+//! assert_eq!(std::fs::read(&dest).unwrap(), b"done\n");
+//! assert!(AtomicFile::create(&dest, false).is_err());
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Temporary files not yet persisted or removed, for `remove_pending`
+static LIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Temporary files created by this process, for unique names
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Remove every temporary file of a `PendingFile` that is still pending
+pub fn remove_pending() {
+    let mut live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+    for temp in live.drain(..) {
+        let _ = fs::remove_file(temp);
+    }
+}
+
+fn forget(temp: &Path) {
+    let mut live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(i) = live.iter().position(|p| p == temp) {
+        live.swap_remove(i);
+    }
+}
+
+/// The error for a destination that exists when overwriting is off
+pub fn exists_error() -> io::Error {
+    io::Error::new(ErrorKind::AlreadyExists, "already exists")
+}
+
+/// A temporary file standing in for `dest` until it is persisted
+#[derive(Debug)]
+pub struct PendingFile {
+    temp: PathBuf,
+    dest: PathBuf,
+    overwrite: bool,
+}
+
+impl PendingFile {
+    /// Create an empty temporary file for `dest`, which must not exist unless
+    /// `overwrite` is set
+    ///
+    /// A symbolic link at `dest` is followed, so persisting replaces its target.
+    /// Without `overwrite`, persisting also fails if `dest` was created since.
+    pub fn new(dest: impl AsRef<Path>, overwrite: bool) -> io::Result<Self> {
+        let mut dest = dest.as_ref().to_path_buf();
+        if dest.is_symlink() {
+            dest = fs::canonicalize(&dest)?;
+        }
+        match fs::metadata(&dest) {
+            Ok(_) if !overwrite => return Err(exists_error()),
+            Ok(meta) if !meta.is_file() => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "not a regular file",
+                ))
+            }
+            _ => {}
+        }
+        let name = dest
+            .file_name()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "not a file name"))?;
+        let n = CREATED.fetch_add(1, Ordering::Relaxed);
+        let temp = dest.with_file_name(format!(
+            ".{}.{}-{n}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        LIVE.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(temp.clone());
+        Ok(PendingFile {
+            temp,
+            dest,
+            overwrite,
+        })
+    }
+
+    /// Where the file goes once persisted
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    /// The temporary file written meanwhile
+    pub fn temp(&self) -> &Path {
+        &self.temp
+    }
+
+    /// Open the temporary file to append to it
+    pub fn open(&self) -> io::Result<File> {
+        OpenOptions::new().append(true).open(&self.temp)
+    }
+
+    /// Sync the temporary file to disk, move it to the destination and sync the
+    /// directory
+    ///
+    /// Without overwriting, a destination that exists by now is an
+    /// `AlreadyExists` error and the temporary file is removed.
+    pub fn persist(self) -> io::Result<()> {
+        File::open(&self.temp)?.sync_all()?;
+        if self.overwrite {
+            fs::rename(&self.temp, &self.dest)?;
+        } else {
+            match fs::hard_link(&self.temp, &self.dest) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(exists_error()),
+                // a file system without hard links: checked in `new` only
+                Err(e) if e.kind() == ErrorKind::Unsupported => fs::rename(&self.temp, &self.dest)?,
+                Err(e) => return Err(e),
+            }
+            let _ = fs::remove_file(&self.temp);
+        }
+        forget(&self.temp);
+        let synced = sync_dir(&self.dest);
+        std::mem::forget(self);
+        synced
+    }
+}
+
+/// Sync the directory holding `path`, so a rename into it is on disk
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened as files here; the rename is left to the OS
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl Drop for PendingFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.temp);
+        forget(&self.temp);
+    }
+}
+
+/// A file written in place of its destination, which `commit` replaces
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    pending: PendingFile,
+}
+
+impl AtomicFile {
+    /// Start writing `dest`, which must not exist unless `overwrite` is set
+    pub fn create(dest: impl AsRef<Path>, overwrite: bool) -> io::Result<Self> {
+        let pending = PendingFile::new(dest, overwrite)?;
+        let file = pending.open()?;
+        Ok(AtomicFile { file, pending })
+    }
+
+    /// Where the file goes once committed
+    pub fn dest(&self) -> &Path {
+        self.pending.dest()
+    }
+
+    /// Replace the destination with everything written
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        drop(self.file);
+        self.pending.persist()
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufWriter;
+
+    /// Passes `limit` bytes through, then fails
+    struct FailAfter<W> {
+        inner: W,
+        limit: usize,
+    }
+
+    impl<W: Write> Write for FailAfter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.limit == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            let n = self.inner.write(&buf[..buf.len().min(self.limit)])?;
+            self.limit -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Write 1000 lines to `dest` through a writer failing after `limit` bytes
+    fn write_lines(dest: &Path, limit: usize) -> io::Result<()> {
+        let file = AtomicFile::create(dest, true)?;
+        let mut out = BufWriter::with_capacity(64, FailAfter { inner: file, limit });
+        for i in 0..1000 {
+            writeln!(out, "line {i}")?;
+        }
+        let out = out.into_inner().map_err(|e| e.into_error())?;
+        out.inner.commit()
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_failed_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("out.txt");
+        let err = write_lines(&dest, 500).unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        assert!(entries(dir.path()).is_empty());
+
+        write_lines(&dest, usize::MAX).unwrap();
+        let complete = fs::read(&dest).unwrap();
+        assert!(complete.ends_with(b"line 999\n"));
+
+        // the old version survives a failed rewrite
+        assert!(write_lines(&dest, 5000).is_err());
+        assert_eq!(fs::read(&dest).unwrap(), complete);
+        assert_eq!(entries(dir.path()), ["out.txt"]);
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("out.txt");
+        fs::write(&dest, "old\n").unwrap();
+        let err = AtomicFile::create(&dest, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        let mut out = AtomicFile::create(&dest, true).unwrap();
+        out.write_all(b"new\n").unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"old\n");
+        out.commit().unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"new\n");
+        assert_eq!(entries(dir.path()), ["out.txt"]);
+
+        let err = AtomicFile::create(dir.path(), true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // a destination created after the check is still not replaced
+        let late = dir.path().join("late.txt");
+        let mut out = AtomicFile::create(&late, false).unwrap();
+        out.write_all(b"ours\n").unwrap();
+        fs::write(&late, "theirs\n").unwrap();
+        let err = out.commit().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&late).unwrap(), b"theirs\n");
+        assert_eq!(entries(dir.path()), ["late.txt", "out.txt"]);
+    }
+
+    #[test]
+    fn test_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("a.fq");
+        let pending = PendingFile::new(&dest, false).unwrap();
+        // reopened for each batch, as when file handles are limited
+        for batch in ["@r1\n", "@r2\n"] {
+            pending.open().unwrap().write_all(batch.as_bytes()).unwrap();
+        }
+        let temp = pending.temp().to_path_buf();
+        assert!(temp.starts_with(dir.path()));
+        assert!(temp
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".a.fq."));
+        pending.persist().unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"@r1\n@r2\n");
+        assert!(!temp.exists());
+
+        let panicked = std::panic::catch_unwind(|| {
+            let pending = PendingFile::new(dir.path().join("b.fq"), false).unwrap();
+            pending.open().unwrap().write_all(b"partial").unwrap();
+            panic!("interrupted");
+        });
+        assert!(panicked.is_err());
+        assert_eq!(entries(dir.path()), ["a.fq"]);
+    }
+}
@@ -0,0 +1,229 @@
+//! Whole-file byte access
+//!
+//! `MappedFile::open` exposes a file's contents as a byte slice, either memory-mapped
+//! (feature `mmap`) or read into a Vec. Callers pick a `Backend` at runtime and never
+//! need to cfg-gate on the feature: without it, `Backend::Mmap` falls back to reading.
+//!
+//! Only regular files can be mapped. FIFOs, sockets and character devices such as
+//! /dev/stdin are refused with `MmapError::NotRegularFile`; use a streaming reader for those.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MmapError {
+    IoError(PathBuf, std::io::Error),
+    NotRegularFile(PathBuf),
+}
+
+impl Display for MmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MmapError::IoError(p, e) => write!(f, "{}: {e}", p.display()),
+            MmapError::NotRegularFile(p) => write!(
+                f,
+                "{} is not a regular file and cannot be mapped; use the streaming reader instead",
+                p.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MmapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MmapError::IoError(_, e) => Some(e),
+            MmapError::NotRegularFile(_) => None,
+        }
+    }
+}
+
+/// How `MappedFile::open` gets at the bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Memory-map the file; reads it instead when built without feature `mmap`
+    Mmap,
+    /// Read the whole file into memory
+    Read,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        if cfg!(feature = "mmap") {
+            Backend::Mmap
+        } else {
+            Backend::Read
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Inner {
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+    Buffer(Vec<u8>),
+}
+
+/// The contents of a file, mapped or buffered
+#[derive(Debug)]
+pub struct MappedFile {
+    inner: Inner,
+}
+
+impl MappedFile {
+    /// Open `path` with `backend`
+    ///
+    /// Zero-length files are never mapped (which fails on some platforms) and
+    /// yield an empty slice.
+    pub fn open<P: AsRef<Path>>(path: P, backend: Backend) -> Result<Self, MmapError> {
+        let path = path.as_ref();
+        let io_err = |e| MmapError::IoError(path.to_path_buf(), e);
+        let file = File::open(path).map_err(io_err)?;
+        let meta = file.metadata().map_err(io_err)?;
+        if !meta.is_file() {
+            return Err(MmapError::NotRegularFile(path.to_path_buf()));
+        }
+        if meta.len() == 0 {
+            return Ok(MappedFile {
+                inner: Inner::Buffer(Vec::new()),
+            });
+        }
+        let inner = match backend {
+            #[cfg(feature = "mmap")]
+            // SAFETY: the map is read-only. Truncation of the file by another process
+            // while mapped is undefined behaviour, which is the standard mmap caveat;
+            // use Backend::Read for files that may change underneath us.
+            Backend::Mmap => Inner::Mapped(unsafe { memmap2::Mmap::map(&file) }.map_err(io_err)?),
+            _ => Inner::Buffer(read_to_vec(file, meta.len()).map_err(io_err)?),
+        };
+        Ok(MappedFile { inner })
+    }
+
+    /// Whether the contents are memory-mapped rather than buffered
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.inner, Inner::Buffer(_))
+    }
+
+    /// Advise the OS that bytes `offset..offset + len` will be read soon
+    ///
+    /// Only a file mapped on Unix gets the advice (`Advice::WillNeed`); buffered
+    /// contents are in memory already. The range is clamped to the file.
+    #[cfg_attr(not(all(feature = "mmap", unix)), allow(unused_variables))]
+    pub fn will_need(&self, offset: usize, len: usize) -> std::io::Result<()> {
+        match &self.inner {
+            #[cfg(all(feature = "mmap", unix))]
+            Inner::Mapped(m) if offset < m.len() => {
+                let len = len.min(m.len() - offset);
+                m.advise_range(memmap2::Advice::WillNeed, offset, len)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A reader over the contents
+    pub fn cursor(&self) -> Cursor<&[u8]> {
+        Cursor::new(self)
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.inner {
+            #[cfg(feature = "mmap")]
+            Inner::Mapped(m) => m,
+            Inner::Buffer(b) => b,
+        }
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+fn read_to_vec(mut file: File, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(usize::try_from(len).unwrap_or(0));
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Write};
+
+    const BACKENDS: [Backend; 2] = [Backend::Mmap, Backend::Read];
+
+    #[test]
+    fn test_open_regular() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(b">a\nACGT\n").unwrap();
+        for backend in BACKENDS {
+            let m = MappedFile::open(f.path(), backend).unwrap();
+            assert_eq!(&m[..], b">a\nACGT\n");
+            assert_eq!(
+                m.is_mapped(),
+                cfg!(feature = "mmap") && backend == Backend::Mmap
+            );
+            let lines = m
+                .cursor()
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap();
+            assert_eq!(lines, vec![">a", "ACGT"]);
+            m.will_need(3, 4).unwrap();
+            // clamped to the file
+            m.will_need(3, 1 << 20).unwrap();
+            m.will_need(1 << 20, 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_open_empty() {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        for backend in BACKENDS {
+            let m = MappedFile::open(f.path(), backend).unwrap();
+            assert!(m.is_empty());
+            assert!(!m.is_mapped());
+        }
+    }
+
+    #[test]
+    fn test_open_missing() {
+        let err =
+            MappedFile::open("../resources/test_data/missing.fa", Backend::default()).unwrap_err();
+        match err {
+            MmapError::IoError(p, e) => {
+                assert!(p.ends_with("missing.fa"));
+                assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+            }
+            e => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refuse_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // keep a writer open so opening the read end doesn't block
+        let fifo_path = fifo.clone();
+        let writer = std::thread::spawn(move || File::options().write(true).open(fifo_path));
+        let err = MappedFile::open(&fifo, Backend::Mmap).unwrap_err();
+        writer.join().unwrap().unwrap();
+        assert!(matches!(err, MmapError::NotRegularFile(_)));
+        assert!(err.to_string().contains("use the streaming reader"));
+    }
+}
@@ -0,0 +1,177 @@
+//! File input and output helpers
+//!
+//! An index entry can describe more bytes than one fetch may buffer, on 32-bit
+//! platforms or under a caller's limit; `span_len` turns such entries into a
+//! `RecordTooLarge` error, and `read_span` reads the rest in bounded chunks.
+//! `fetch_in_order` runs a batch of fetches in file order, so handles only
+//! move forward.
+
+use std::fmt::{self, Display};
+use std::io::Read;
+
+pub mod atomic;
+pub mod mmap;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+/// Read and discard up to `n` bytes of `handle`
+///
+/// Returns the bytes skipped, fewer than `n` only at end of input.
+pub fn skip_fwd<R: Read>(handle: &mut R, n: u64) -> std::io::Result<u64> {
+    std::io::copy(&mut handle.by_ref().take(n), &mut std::io::sink())
+}
+
+/// Most bytes `read_span` reads at a time
+const READ_CHUNK: usize = 1 << 20;
+
+/// A fetch of more bytes than the platform can address or the caller allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordTooLarge {
+    /// Record or region being fetched
+    pub id: String,
+    /// Bytes its index entry spans, line terminators included
+    pub bytes: u64,
+    /// Most bytes the fetch could buffer
+    pub limit: usize,
+}
+
+impl Display for RecordTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {} spans {} bytes, too large for this platform or the limit of {} bytes",
+            self.id, self.bytes, self.limit
+        )
+    }
+}
+
+impl std::error::Error for RecordTooLarge {}
+
+/// `bytes` of the record `id` as a buffer length, if it fits in both `usize` and `limit`
+pub fn span_len(id: &[u8], bytes: u64, limit: usize) -> Result<usize, RecordTooLarge> {
+    usize::try_from(bytes)
+        .ok()
+        .filter(|&len| len <= limit)
+        .ok_or_else(|| RecordTooLarge {
+            id: String::from_utf8_lossy(id).into_owned(),
+            bytes,
+            limit,
+        })
+}
+
+/// Read exactly `len` bytes from `handle`, dropping line terminators
+///
+/// Reads at most 1 MiB at a time, so the buffer only grows as bytes arrive: an
+/// index entry describing more bytes than the file has fails with `UnexpectedEof`
+/// rather than allocating its whole span first.
+pub fn read_span(handle: &mut impl Read, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len.min(READ_CHUNK));
+    let mut chunk = vec![0u8; len.min(READ_CHUNK)];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(READ_CHUNK);
+        handle.read_exact(&mut chunk[..n])?;
+        out.extend(chunk[..n].iter().filter(|c| **c != b'\n' && **c != b'\r'));
+        left -= n;
+    }
+    Ok(out)
+}
+
+/// Fetch one result per item, in ascending key order, returning them in item order
+///
+/// Each item is the key a fetch sorts by, usually the file offset, and what the
+/// fetch needs, or the error to put in its slot. Items with equal keys are
+/// fetched in the order given, and a later one shares the earlier result rather
+/// than fetching again, so equal keys must mean equal fetches.
+pub fn fetch_in_order<K, V, T, E>(
+    items: impl IntoIterator<Item = Result<(K, V), E>>,
+    mut fetch: impl FnMut(V) -> Result<T, E>,
+) -> Vec<Result<T, E>>
+where
+    K: Ord,
+    T: Clone,
+{
+    let mut order = Vec::new();
+    let mut results = Vec::new();
+    for (i, item) in items.into_iter().enumerate() {
+        match item {
+            Ok((key, value)) => {
+                order.push((key, i, value));
+                results.push(None);
+            }
+            Err(e) => results.push(Some(Err(e))),
+        }
+    }
+    // stable, so equal keys keep their order
+    order.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut prev: Option<(K, usize)> = None;
+    for (key, i, value) in order {
+        let res = match &prev {
+            Some((k, j)) if *k == key => match &results[*j] {
+                Some(Ok(done)) => Ok(T::clone(done)),
+                _ => fetch(value),
+            },
+            _ => fetch(value),
+        };
+        results[i] = Some(res);
+        prev = Some((key, i));
+    }
+    results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_len() {
+        assert_eq!(span_len(b"r", 10, 10), Ok(10));
+        let err = span_len(b"r", 11, 10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "record r spans 11 bytes, too large for this platform or the limit of 10 bytes"
+        );
+        // five GiB only fits where usize is 64 bits wide
+        let five_gib = 5u64 << 30;
+        assert_eq!(
+            span_len(b"big", five_gib, usize::MAX).is_err(),
+            usize::BITS < 64
+        );
+        assert!(span_len(b"big", five_gib, 1 << 30).is_err());
+    }
+
+    #[test]
+    fn test_read_span() {
+        let text = "ACGT\n".repeat(READ_CHUNK / 2);
+        let mut input = text.as_bytes();
+        let bases = read_span(&mut input, text.len() - 5).unwrap();
+        assert_eq!(bases.len(), (READ_CHUNK / 2 - 1) * 4);
+        assert!(bases.iter().all(|b| b"ACGT".contains(b)));
+        assert_eq!(input, b"ACGT\n");
+
+        let err = read_span(&mut &b"AC\r\nGT"[..], usize::MAX).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_span(&mut &b"AC\r\nGT"[..], 6).unwrap(), b"ACGT");
+    }
+
+    #[test]
+    fn test_skip_fwd() {
+        let mut input: &[u8] = b"0123456789";
+        assert_eq!(skip_fwd(&mut input, 4).unwrap(), 4);
+        assert_eq!(input, b"456789");
+        assert_eq!(skip_fwd(&mut input, 10).unwrap(), 6);
+        assert_eq!(skip_fwd(&mut input, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_fetch_in_order() {
+        let items = [Ok((30, 'c')), Err("missing"), Ok((10, 'a')), Ok((30, 'c'))];
+        let mut visited = Vec::new();
+        let results = fetch_in_order(items, |v| {
+            visited.push(v);
+            Ok(v.to_ascii_uppercase())
+        });
+        assert_eq!(visited, ['a', 'c']);
+        assert_eq!(results, [Ok('C'), Err("missing"), Ok('A'), Ok('C')]);
+    }
+}
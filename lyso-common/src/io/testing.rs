@@ -0,0 +1,31 @@
+//! Readers for exercising parsers on awkward input, behind the `testing` feature
+
+use std::io::{BufRead, Read};
+
+/// Hands out at most one byte per `read` or `fill_buf` call
+pub struct OneByte<R>(pub R);
+
+impl<R: BufRead> Read for OneByte<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = match (self.0.fill_buf()?.first(), buf.first_mut()) {
+            (Some(b), Some(out)) => {
+                *out = *b;
+                1
+            }
+            _ => 0,
+        };
+        self.0.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for OneByte<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let buf = self.0.fill_buf()?;
+        Ok(&buf[..buf.len().min(1)])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
@@ -1,10 +1,40 @@
 use std::fmt::{self, Display};
 
+pub mod align;
+pub mod bgzf;
+pub mod codec;
+pub mod config;
+pub mod detect;
+pub mod dict;
+pub mod diff;
+pub mod error;
+pub mod filter;
+pub mod format;
+pub mod index_build;
+pub mod index_meta;
+pub mod intervals;
+pub mod io;
+pub mod name_index;
+pub mod pool;
+pub mod pos;
+pub mod raw;
+pub mod region;
+pub mod rename;
+pub mod runs;
+pub mod search;
+pub mod seqedit;
+pub mod sort;
+pub mod stream;
+pub mod synth;
+pub mod translate;
 pub mod util;
+pub mod validate;
 
-#[derive(Debug, PartialEq)]
-// CIGAR operations
-// See SAM v1 section 1.4.6
+/// CIGAR operations
+///
+/// Variants are in BAM op code order ("MIDNSHP=X"), so ops sort by operation
+/// first and length second. See SAM v1 section 1.4.6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum CigarOp {
     M(u32),
     I(u32),
@@ -17,6 +47,57 @@ pub enum CigarOp {
     X(u32),
 }
 
+impl CigarOp {
+    /// Operation length
+    pub fn len(&self) -> u32 {
+        match self {
+            CigarOp::M(v)
+            | CigarOp::I(v)
+            | CigarOp::D(v)
+            | CigarOp::N(v)
+            | CigarOp::S(v)
+            | CigarOp::H(v)
+            | CigarOp::P(v)
+            | CigarOp::Eq(v)
+            | CigarOp::X(v) => *v,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the operation consumes query (read) bases
+    pub fn consumes_query(&self) -> bool {
+        matches!(
+            self,
+            CigarOp::M(_) | CigarOp::I(_) | CigarOp::S(_) | CigarOp::Eq(_) | CigarOp::X(_)
+        )
+    }
+
+    /// Whether the operation consumes reference bases
+    pub fn consumes_ref(&self) -> bool {
+        matches!(
+            self,
+            CigarOp::M(_) | CigarOp::D(_) | CigarOp::N(_) | CigarOp::Eq(_) | CigarOp::X(_)
+        )
+    }
+
+    /// Whether the operation aligns a read base to a reference base (M, = or X)
+    pub fn is_aligned(&self) -> bool {
+        matches!(self, CigarOp::M(_) | CigarOp::Eq(_) | CigarOp::X(_))
+    }
+}
+
+/// Number of reference bases covered by an alignment, from its first to last aligned base
+pub fn ref_span(cigar: &[CigarOp]) -> u64 {
+    cigar
+        .iter()
+        .filter(|op| op.consumes_ref())
+        .map(|op| u64::from(op.len()))
+        .sum()
+}
+
 impl Display for CigarOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -32,3 +113,26 @@ impl Display for CigarOp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_cigar_op_set_and_order() {
+        let ops = [CigarOp::M(5), CigarOp::S(2), CigarOp::M(5), CigarOp::M(3)];
+        let distinct = ops.iter().copied().collect::<HashSet<CigarOp>>();
+        assert_eq!(distinct.len(), 3);
+        assert!(distinct.contains(&CigarOp::S(2)));
+
+        let mut sorted = ops.to_vec();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            [CigarOp::M(3), CigarOp::M(5), CigarOp::M(5), CigarOp::S(2)]
+        );
+        assert!(CigarOp::I(100) < CigarOp::D(1));
+        assert!(CigarOp::Eq(9) < CigarOp::X(0));
+    }
+}
@@ -0,0 +1,222 @@
+//! Lookups of record names, for index builders
+//!
+//! Index maps hold millions of names that differ only in a few trailing digits.
+//! `NameIndex` maps them to positions without storing them a second time, and
+//! `NameHasher` spreads such names over the hash bits hashbrown probes with.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::index_build::hash_with;
+
+/// Positions of record names, for index lookups
+///
+/// Keyed by a hash of the name rather than the name itself, so filling it
+/// copies no names; positions sharing a hash are chained through `next`, and
+/// `find` compares the names the caller stores.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameIndex {
+    /// Last position pushed with each hash
+    heads: HashMap<u64, usize, BuildHasherDefault<NameHasher>>,
+    /// Previous position with the same hash as each position, or `NO_POSITION`
+    next: Vec<usize>,
+}
+
+const NO_POSITION: usize = usize::MAX;
+
+impl NameIndex {
+    /// Room for `additional` more names
+    pub fn reserve(&mut self, additional: usize) {
+        self.heads.reserve(additional);
+        self.next.reserve(additional);
+    }
+
+    /// Position of `name`, where `name_at` gives the name stored at a position
+    pub fn find<'a>(&self, name: &[u8], name_at: impl Fn(usize) -> &'a [u8]) -> Option<usize> {
+        let mut pos = *self.heads.get(&hash_name(name))?;
+        while pos != NO_POSITION {
+            if name_at(pos) == name {
+                return Some(pos);
+            }
+            pos = self.next[pos];
+        }
+        None
+    }
+
+    /// Record `name` at the next position, `len()`
+    pub fn push(&mut self, name: &[u8]) {
+        let pos = self.next.len();
+        let prev = self.heads.insert(hash_name(name), pos);
+        self.next.push(prev.unwrap_or(NO_POSITION));
+    }
+
+    /// Record `names` at the next positions, failing on the first name already
+    /// recorded or repeated among them
+    ///
+    /// Much faster than `find` and `push` per name, as all names are hashed
+    /// before any is inserted. On failure nothing is recorded, and the error is
+    /// the position the repeated name would have had.
+    pub fn push_unique<'a>(
+        &mut self,
+        names: &[&[u8]],
+        name_at: impl Fn(usize) -> &'a [u8],
+    ) -> Result<(), usize> {
+        let start = self.next.len();
+        let hashes = names.iter().map(|n| hash_name(n)).collect::<Vec<u64>>();
+        self.reserve(names.len());
+        for (i, h) in hashes.into_iter().enumerate() {
+            let prev = self.heads.insert(h, start + i);
+            self.next.push(prev.unwrap_or(NO_POSITION));
+            let mut pos = prev.unwrap_or(NO_POSITION);
+            while pos != NO_POSITION {
+                let seen = if pos >= start {
+                    names[pos - start]
+                } else {
+                    name_at(pos)
+                };
+                if seen == names[i] {
+                    self.unpush(&names[..=i]);
+                    return Err(start + i);
+                }
+                pos = self.next[pos];
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo the pushes of `names`, the last names pushed
+    fn unpush(&mut self, names: &[&[u8]]) {
+        for name in names.iter().rev() {
+            let h = hash_name(name);
+            match self.next.pop() {
+                Some(NO_POSITION) | None => self.heads.remove(&h),
+                Some(prev) => self.heads.insert(h, prev),
+            };
+        }
+    }
+
+    /// Number of positions pushed
+    pub fn len(&self) -> usize {
+        self.next.len()
+    }
+
+    /// True if nothing was pushed
+    pub fn is_empty(&self) -> bool {
+        self.next.is_empty()
+    }
+}
+
+/// Position of the first of `names` equal to an earlier one
+///
+/// Cheaper than filling a `NameIndex` when no lookups are needed yet: the
+/// hashes are sorted rather than inserted, and only a repeated hash has the
+/// names compared.
+pub fn first_repeat(names: &[&[u8]]) -> Option<usize> {
+    let mut hashes = names.iter().map(|n| hash_name(n)).collect::<Vec<u64>>();
+    hashes.sort_unstable();
+    if hashes.windows(2).all(|w| w[0] != w[1]) {
+        return None;
+    }
+    NameIndex::default()
+        .push_unique(names, |_| unreachable!("nothing was pushed before"))
+        .err()
+}
+
+fn hash_name(name: &[u8]) -> u64 {
+    hash_with::<NameHasher>(name)
+}
+
+/// Folded multiply over 8-byte words, with a murmur3 finalizer
+///
+/// Plain FxHash leaves the high bits hashbrown probes by nearly constant for
+/// names that differ only in their trailing digits ("SRR1.1", "SRR1.2", ...),
+/// which made filling an index map several times slower than SipHash. Its
+/// rotate-xor-multiply step also lets such names collide outright (one in 40
+/// of three million "read{i}"), so each word is folded in through the full
+/// 128-bit product instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NameHasher(u64);
+
+impl NameHasher {
+    fn add(&mut self, word: u64) {
+        let m = u128::from(self.0 ^ word) * 0x517c_c1b7_2722_0a95;
+        self.0 = (m as u64) ^ (m >> 64) as u64;
+    }
+}
+
+impl Hasher for NameHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for c in &mut chunks {
+            self.add(u64::from_le_bytes(c.try_into().unwrap()));
+        }
+        let mut tail = [0u8; 8];
+        let rest = chunks.remainder();
+        tail[..rest.len()].copy_from_slice(rest);
+        self.add(u64::from_le_bytes(tail));
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.add(n);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.add(n as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_index() {
+        let names = (0..1000)
+            .map(|i| format!("SRR22092847.{i}.1").into_bytes())
+            .collect::<Vec<_>>();
+        let mut index = NameIndex::default();
+        for n in &names {
+            index.push(n);
+        }
+        let name_at = |i: usize| names[i].as_slice();
+        assert_eq!(index.len(), 1000);
+        assert_eq!(index.find(b"SRR22092847.999.1", name_at), Some(999));
+        assert_eq!(index.find(b"SRR22092847.999.2", name_at), None);
+        assert_ne!(hash_name(b"ab"), hash_name(b"ab\0"));
+
+        // of a repeated name, the last position pushed is found
+        let names = [b"a".to_vec(), b"b".to_vec(), b"a".to_vec()];
+        let mut index = NameIndex::default();
+        names.iter().for_each(|n| index.push(n));
+        let found = index.find(b"a", |i| names[i].as_slice());
+        assert_eq!(found, Some(2));
+
+        // a failed batch leaves the index as it was
+        let before = index.clone();
+        let batch: [&[u8]; 3] = [b"c", b"d", b"c"];
+        let name_at = |i: usize| names[i].as_slice();
+        assert_eq!(index.push_unique(&batch, name_at), Err(5));
+        assert_eq!(index, before);
+        assert_eq!(index.push_unique(&[b"c", b"b"], name_at), Err(4));
+        assert_eq!(index, before);
+        index.push_unique(&[b"c", b"d"], name_at).unwrap();
+        assert_eq!(index.len(), 5);
+        let all: [&[u8]; 5] = [b"a", b"b", b"a", b"c", b"d"];
+        assert_eq!(index.find(b"d", |i| all[i]), Some(4));
+
+        // the first name seen before, from hashes alone when nothing repeats
+        assert_eq!(first_repeat(&all), Some(2));
+        assert_eq!(first_repeat(&[b"a", b"b", b"c", b"b", b"a"]), Some(3));
+        assert_eq!(first_repeat(&[b"a", b"b"]), None);
+        assert_eq!(first_repeat(&[]), None);
+    }
+}
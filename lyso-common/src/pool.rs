@@ -0,0 +1,441 @@
+//! Running jobs on a fixed number of threads, collecting results in order
+//!
+//! `WorkerPool::scope` starts the threads, runs a body that submits jobs and
+//! collects their results, and joins the threads before returning. Each job is
+//! tagged with its submission index, and results come back in tag order however
+//! the threads finish. A job that returns an error or panics is reported at its
+//! place in that order; the jobs after it are not run, and none of its results
+//! are waited for, so a failing job never leaves a scope hanging.
+//!
+//! ```
+//! use std::num::NonZeroUsize;
+//! use std::ops::ControlFlow;
+//! use lyso_common::pool::WorkerPool;
+//!
+//! let pool = WorkerPool::new(NonZeroUsize::new(4).unwrap());
+//! let mut squares = Vec::new();
+//! pool.map_ordered(
+//!     1..=5u64,
+//!     |n| Ok::<_, String>(n * n),
+//!     |sq| {
+//!         squares.push(sq);
+//!         ControlFlow::Continue(())
+//!     },
+//! )
+//! .unwrap();
+//! assert_eq!(squares, [1, 4, 9, 16, 25]);
+//! ```
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::num::NonZeroUsize;
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+/// Jobs `map_ordered` keeps in flight per thread
+const JOBS_PER_THREAD: usize = 2;
+
+/// A job that did not produce a result
+#[derive(Debug)]
+pub enum PoolError<E> {
+    /// The job returned `error`
+    Job { tag: u64, error: E },
+    /// The job panicked, with the panic's message
+    Panicked { tag: u64, message: String },
+}
+
+impl<E> PoolError<E> {
+    /// The tag of the job that failed
+    pub fn tag(&self) -> u64 {
+        match self {
+            PoolError::Job { tag, .. } | PoolError::Panicked { tag, .. } => *tag,
+        }
+    }
+}
+
+impl<E: Display> Display for PoolError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Job { error, .. } => write!(f, "{error}"),
+            PoolError::Panicked { tag, message } => {
+                write!(f, "worker panicked on job {tag}: {message}")
+            }
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for PoolError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolError::Job { error, .. } => Some(error),
+            PoolError::Panicked { .. } => None,
+        }
+    }
+}
+
+/// The number of threads to run jobs on
+///
+/// Threads are started by each `scope` and joined before it returns, so a pool
+/// is only a setting and is cheap to copy around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerPool {
+    threads: NonZeroUsize,
+}
+
+impl Default for WorkerPool {
+    /// A single worker thread
+    fn default() -> Self {
+        WorkerPool::new(NonZeroUsize::MIN)
+    }
+}
+
+impl WorkerPool {
+    pub const fn new(threads: NonZeroUsize) -> Self {
+        WorkerPool { threads }
+    }
+
+    pub const fn threads(&self) -> NonZeroUsize {
+        self.threads
+    }
+
+    /// Run `body` with a `Jobs` handle whose jobs `work` runs on the pool's threads
+    ///
+    /// Jobs still queued when `body` returns are dropped without running, and the
+    /// threads are joined before `scope` returns, even if `body` panics.
+    pub fn scope<J, T, E, R>(
+        &self,
+        work: impl Fn(J) -> Result<T, E> + Sync,
+        body: impl FnOnce(&mut Jobs<J, T, E>) -> R,
+    ) -> R
+    where
+        J: Send,
+        T: Send,
+        E: Send,
+    {
+        let (job_tx, job_rx) = mpsc::channel();
+        let (result_tx, result_rx) = mpsc::channel();
+        let job_rx = Mutex::new(job_rx);
+        let stop_at = AtomicU64::new(u64::MAX);
+        thread::scope(|s| {
+            for _ in 0..self.threads.get() {
+                let result_tx = result_tx.clone();
+                let (work, job_rx, stop_at) = (&work, &job_rx, &stop_at);
+                s.spawn(move || run_worker(work, job_rx, result_tx, stop_at));
+            }
+            drop(result_tx);
+            let mut jobs = Jobs {
+                queue: job_tx,
+                results: result_rx,
+                waiting: BTreeMap::new(),
+                submitted: 0,
+                collected: 0,
+                failed: false,
+                stop_at: &stop_at,
+            };
+            body(&mut jobs)
+        })
+    }
+
+    /// Run `work` on each of `jobs`, passing the results to `sink` in order
+    ///
+    /// A few jobs per thread are kept in flight, so `jobs` is consumed as the
+    /// results are. Returning `ControlFlow::Break` from `sink` stops early and
+    /// drops the jobs not yet run. Returns the first job to fail.
+    pub fn map_ordered<J, T, E>(
+        &self,
+        jobs: impl IntoIterator<Item = J>,
+        work: impl Fn(J) -> Result<T, E> + Sync,
+        mut sink: impl FnMut(T) -> ControlFlow<()>,
+    ) -> Result<(), PoolError<E>>
+    where
+        J: Send,
+        T: Send,
+        E: Send,
+    {
+        let in_flight = JOBS_PER_THREAD * self.threads.get();
+        self.scope(work, |pool| {
+            for job in jobs {
+                if pool.pending() >= in_flight {
+                    if let Some(result) = pool.next_result() {
+                        if sink(result?).is_break() {
+                            return Ok(());
+                        }
+                    }
+                }
+                pool.submit(job);
+            }
+            while let Some(result) = pool.next_result() {
+                if sink(result?).is_break() {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Take jobs off the queue until it closes, skipping those at or after `stop_at`
+fn run_worker<J, T, E>(
+    work: &(impl Fn(J) -> Result<T, E> + Sync),
+    jobs: &Mutex<Receiver<(u64, J)>>,
+    results: Sender<(u64, Result<T, PoolError<E>>)>,
+    stop_at: &AtomicU64,
+) {
+    loop {
+        let next = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+        let Ok((tag, job)) = next else {
+            return;
+        };
+        if tag >= stop_at.load(Ordering::Acquire) {
+            continue;
+        }
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| work(job))) {
+            Ok(Ok(v)) => Ok(v),
+            Ok(Err(error)) => Err(PoolError::Job { tag, error }),
+            Err(payload) => Err(PoolError::Panicked {
+                tag,
+                message: panic_message(payload.as_ref()),
+            }),
+        };
+        if result.is_err() {
+            // later jobs would only be thrown away
+            stop_at.fetch_min(tag + 1, Ordering::AcqRel);
+        }
+        if results.send((tag, result)).is_err() {
+            return;
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("<non-string panic payload>")
+    }
+}
+
+/// Submits jobs to a `WorkerPool::scope` and collects their results in order
+pub struct Jobs<'s, J, T, E> {
+    queue: Sender<(u64, J)>,
+    results: Receiver<(u64, Result<T, PoolError<E>>)>,
+    /// Results that arrived before an earlier job's
+    waiting: BTreeMap<u64, Result<T, PoolError<E>>>,
+    submitted: u64,
+    collected: u64,
+    /// A failure has been collected, so no more results will be
+    failed: bool,
+    stop_at: &'s AtomicU64,
+}
+
+impl<J, T, E> Jobs<'_, J, T, E> {
+    /// Queue `job` and return its tag, the number of jobs submitted before it
+    ///
+    /// Never blocks: callers that submit much more than they collect should bound
+    /// `pending` themselves, as `WorkerPool::map_ordered` does.
+    pub fn submit(&mut self, job: J) -> u64 {
+        let tag = self.submitted;
+        self.submitted += 1;
+        // the workers only hang up when this handle is dropped
+        let _ = self.queue.send((tag, job));
+        tag
+    }
+
+    /// Jobs submitted whose results have not been collected
+    pub fn pending(&self) -> usize {
+        (self.submitted - self.collected) as usize
+    }
+
+    /// Wait for the result of the oldest job not yet collected
+    ///
+    /// None when every submitted job has been collected, or once a job has failed:
+    /// its error is returned once, and the jobs after it are never run.
+    pub fn next_result(&mut self) -> Option<Result<T, PoolError<E>>> {
+        if self.failed || self.collected == self.submitted {
+            return None;
+        }
+        let result = match self.waiting.remove(&self.collected) {
+            Some(r) => r,
+            None => loop {
+                let (tag, r) = self.results.recv().ok()?;
+                if tag == self.collected {
+                    break r;
+                }
+                self.waiting.insert(tag, r);
+            },
+        };
+        self.collected += 1;
+        if result.is_err() {
+            self.failed = true;
+            self.waiting.clear();
+        }
+        Some(result)
+    }
+}
+
+impl<J, T, E> Drop for Jobs<'_, J, T, E> {
+    fn drop(&mut self) {
+        // skip the queued jobs nobody will collect
+        self.stop_at.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn pool(threads: usize) -> WorkerPool {
+        WorkerPool::new(NonZeroUsize::new(threads).unwrap())
+    }
+
+    /// Sleep longer for earlier jobs, so they finish out of order
+    fn slow_first(n: u64) -> Result<u64, String> {
+        thread::sleep(Duration::from_millis(20u64.saturating_sub(n)));
+        Ok(n)
+    }
+
+    #[test]
+    fn test_results_in_tag_order() {
+        for threads in [1, 2, 8] {
+            let mut out = Vec::new();
+            pool(threads)
+                .map_ordered(0..40, slow_first, |n| {
+                    out.push(n);
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            assert_eq!(out, (0..40).collect::<Vec<_>>(), "{threads} threads");
+        }
+    }
+
+    #[test]
+    fn test_scope_submit_and_collect() {
+        let out = pool(4).scope(slow_first, |jobs| {
+            assert_eq!(jobs.next_result().map(|r| r.unwrap()), None);
+            let tags = (0..10).map(|n| jobs.submit(n)).collect::<Vec<_>>();
+            assert_eq!(tags, (0..10).collect::<Vec<_>>());
+            assert_eq!(jobs.pending(), 10);
+            let mut out = Vec::new();
+            while let Some(r) = jobs.next_result() {
+                out.push(r.unwrap());
+            }
+            assert_eq!(jobs.pending(), 0);
+            out
+        });
+        assert_eq!(out, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_job_error() {
+        let ran = AtomicUsize::new(0);
+        let work = |n: u64| {
+            ran.fetch_add(1, Ordering::Relaxed);
+            if n == 5 {
+                return Err(format!("bad job {n}"));
+            }
+            slow_first(n)
+        };
+        let mut out = Vec::new();
+        let err = pool(4)
+            .map_ordered(0..1000, work, |n| {
+                out.push(n);
+                ControlFlow::Continue(())
+            })
+            .unwrap_err();
+        assert!(matches!(&err, PoolError::Job { tag: 5, error } if error == "bad job 5"));
+        assert_eq!(err.to_string(), "bad job 5");
+        // everything before the failure is delivered, and little after it is run
+        assert_eq!(out, [0, 1, 2, 3, 4]);
+        assert!(ran.load(Ordering::Relaxed) < 50);
+    }
+
+    #[test]
+    fn test_job_panic() {
+        let work = |n: u64| {
+            if n == 3 {
+                panic!("job {n} blew up");
+            }
+            slow_first(n)
+        };
+        for threads in [1, 3] {
+            let mut out = Vec::new();
+            let err = pool(threads)
+                .map_ordered(0..100, work, |n| {
+                    out.push(n);
+                    ControlFlow::Continue(())
+                })
+                .unwrap_err();
+            assert_eq!(err.tag(), 3);
+            assert_eq!(err.to_string(), "worker panicked on job 3: job 3 blew up");
+            assert_eq!(out, [0, 1, 2]);
+        }
+
+        // the scope's own results stop at the panic too
+        let results = pool(2).scope(work, |jobs| {
+            for n in 0..6 {
+                jobs.submit(n);
+            }
+            std::iter::from_fn(|| jobs.next_result()).collect::<Vec<_>>()
+        });
+        assert_eq!(results.len(), 4);
+        assert!(matches!(
+            results[3],
+            Err(PoolError::Panicked { tag: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_stop_early() {
+        let ran = AtomicUsize::new(0);
+        let mut out = Vec::new();
+        pool(2)
+            .map_ordered(
+                0..1000u64,
+                |n| {
+                    ran.fetch_add(1, Ordering::Relaxed);
+                    Ok::<_, String>(n)
+                },
+                |n| {
+                    out.push(n);
+                    if n == 2 {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                },
+            )
+            .unwrap();
+        assert_eq!(out, [0, 1, 2]);
+        assert!(ran.load(Ordering::Relaxed) < 50);
+
+        // uncollected jobs do not keep the scope open
+        let n = pool(2).scope(slow_first, |jobs| {
+            for n in 0..1000 {
+                jobs.submit(n);
+            }
+            jobs.next_result().unwrap().unwrap()
+        });
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn test_body_panic_joins_workers() {
+        let caught = panic::catch_unwind(|| {
+            pool(2).scope(slow_first, |jobs| {
+                jobs.submit(1);
+                panic!("body failed");
+            })
+        });
+        assert!(caught.is_err());
+    }
+}
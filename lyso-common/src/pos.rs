@@ -0,0 +1,198 @@
+//! Typed sequence coordinates
+//!
+//! BAM positions and BED intervals are 0-based, while SAM text and region strings
+//! ("chr1:1000-2000") are 1-based. `ZeroBased` and `OneBased` keep the two apart so
+//! mixing them up is a compile error; conversions between them are explicit and checked.
+//! Raw integers remain in the binary parsing layers.
+
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PosError {
+    /// 0 is not a valid 1-based position
+    ZeroOneBased,
+    /// Negative raw position (e.g. BAM's -1 for unmapped)
+    Negative(i64),
+    /// Result does not fit in a u64
+    Overflow,
+}
+
+impl Display for PosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PosError::ZeroOneBased => write!(f, "1-based position must be greater than 0"),
+            PosError::Negative(v) => write!(f, "negative position {v}"),
+            PosError::Overflow => write!(f, "position overflow"),
+        }
+    }
+}
+
+impl std::error::Error for PosError {}
+
+/// A 0-based position
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ZeroBased(u64);
+
+/// A 1-based position, never 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OneBased(u64);
+
+impl ZeroBased {
+    pub const fn new(pos: u64) -> Self {
+        ZeroBased(pos)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Fails only for u64::MAX, which has no 1-based equivalent
+    pub fn to_one_based(self) -> Result<OneBased, PosError> {
+        self.0
+            .checked_add(1)
+            .map(OneBased)
+            .ok_or(PosError::Overflow)
+    }
+
+    pub fn checked_add(self, n: u64) -> Option<Self> {
+        self.0.checked_add(n).map(ZeroBased)
+    }
+
+    pub fn checked_sub(self, n: u64) -> Option<Self> {
+        self.0.checked_sub(n).map(ZeroBased)
+    }
+
+    /// Number of bases from `self` up to (not including) `end`
+    pub fn distance(self, end: ZeroBased) -> Option<u64> {
+        end.0.checked_sub(self.0)
+    }
+}
+
+impl OneBased {
+    /// Returns None for 0
+    pub const fn new(pos: u64) -> Option<Self> {
+        if pos == 0 {
+            None
+        } else {
+            Some(OneBased(pos))
+        }
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    pub const fn to_zero_based(self) -> ZeroBased {
+        ZeroBased(self.0 - 1)
+    }
+
+    pub fn checked_add(self, n: u64) -> Option<Self> {
+        self.0.checked_add(n).map(OneBased)
+    }
+
+    pub fn checked_sub(self, n: u64) -> Option<Self> {
+        self.0.checked_sub(n).and_then(OneBased::new)
+    }
+}
+
+impl From<u64> for ZeroBased {
+    fn from(value: u64) -> Self {
+        ZeroBased(value)
+    }
+}
+
+impl TryFrom<i32> for ZeroBased {
+    type Error = PosError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        u64::try_from(value)
+            .map(ZeroBased)
+            .map_err(|_| PosError::Negative(i64::from(value)))
+    }
+}
+
+impl TryFrom<i64> for ZeroBased {
+    type Error = PosError;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        u64::try_from(value)
+            .map(ZeroBased)
+            .map_err(|_| PosError::Negative(value))
+    }
+}
+
+impl TryFrom<u64> for OneBased {
+    type Error = PosError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        OneBased::new(value).ok_or(PosError::ZeroOneBased)
+    }
+}
+
+impl From<OneBased> for ZeroBased {
+    fn from(value: OneBased) -> Self {
+        value.to_zero_based()
+    }
+}
+
+impl TryFrom<ZeroBased> for OneBased {
+    type Error = PosError;
+
+    fn try_from(value: ZeroBased) -> Result<Self, Self::Error> {
+        value.to_one_based()
+    }
+}
+
+impl Display for ZeroBased {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Display for OneBased {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_based_rejects_zero() {
+        assert_eq!(OneBased::try_from(0u64), Err(PosError::ZeroOneBased));
+        assert!(OneBased::new(0).is_none());
+        assert_eq!(OneBased::try_from(1u64).unwrap().to_zero_based().get(), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for v in [0u64, 1, 999, u64::MAX - 1] {
+            let z = ZeroBased::new(v);
+            assert_eq!(ZeroBased::from(z.to_one_based().unwrap()), z);
+        }
+        let o = OneBased::new(u64::MAX).unwrap();
+        assert_eq!(o.to_zero_based().get(), u64::MAX - 1);
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!(
+            ZeroBased::new(u64::MAX).to_one_based(),
+            Err(PosError::Overflow)
+        );
+        assert!(ZeroBased::new(u64::MAX).checked_add(1).is_none());
+        assert!(OneBased::new(1).unwrap().checked_sub(1).is_none());
+        assert!(ZeroBased::new(0).checked_sub(1).is_none());
+    }
+
+    #[test]
+    fn test_from_raw() {
+        assert_eq!(ZeroBased::try_from(-1i32), Err(PosError::Negative(-1)));
+        assert_eq!(ZeroBased::try_from(i32::MAX).unwrap().get(), 2147483647);
+        assert_eq!(ZeroBased::new(10).distance(ZeroBased::new(15)), Some(5));
+        assert_eq!(ZeroBased::new(15).distance(ZeroBased::new(10)), None);
+    }
+}
@@ -0,0 +1,33 @@
+//! Records kept as the exact bytes they were read from
+//!
+//! The `raw_records` passthrough of the FASTA and FASTQ readers finds record
+//! boundaries and ids with the same parsers as the full readers, but hands back
+//! the input bytes untouched: line endings, wrapping, blank lines, the text of a
+//! FASTQ '+' line and a missing final newline all survive. Writing every raw
+//! record in order reproduces the input byte for byte.
+
+use std::ops::Range;
+
+/// One record exactly as it appeared in the input
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawRecord {
+    /// The record's bytes, from its first byte to the first byte of the next one
+    pub bytes: Vec<u8>,
+    /// Where the id lies in `bytes`
+    pub id_span: Range<usize>,
+}
+
+impl RawRecord {
+    /// A record of `bytes` whose id is `id_span`
+    ///
+    /// Panics if `id_span` is not within `bytes`.
+    pub fn new(bytes: Vec<u8>, id_span: Range<usize>) -> Self {
+        assert!(id_span.start <= id_span.end && id_span.end <= bytes.len());
+        RawRecord { bytes, id_span }
+    }
+
+    /// The id, without its '>' or '@'
+    pub fn id(&self) -> &[u8] {
+        &self.bytes[self.id_span.clone()]
+    }
+}
@@ -0,0 +1,200 @@
+//! Genomic regions
+//!
+//! Parses samtools-style region strings ("chr1", "chr1:1000", "chr1:1,000-2,000"),
+//! which are 1-based and inclusive, into a 0-based half-open `Region`.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::pos::{OneBased, PosError, ZeroBased};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RegionError {
+    Empty,
+    MissingName,
+    InvalidPosition(String),
+    Pos(PosError),
+    /// start is after end
+    Inverted,
+}
+
+impl Display for RegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegionError::Empty => write!(f, "empty region"),
+            RegionError::MissingName => write!(f, "region is missing a sequence name"),
+            RegionError::InvalidPosition(p) => write!(f, "invalid region position '{p}'"),
+            RegionError::Pos(e) => write!(f, "{e}"),
+            RegionError::Inverted => write!(f, "region start is after its end"),
+        }
+    }
+}
+
+impl std::error::Error for RegionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegionError::Pos(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<PosError> for RegionError {
+    fn from(value: PosError) -> Self {
+        RegionError::Pos(value)
+    }
+}
+
+/// A 0-based, half-open interval on a named sequence
+///
+/// `end == None` means "to the end of the sequence".
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Region {
+    name: String,
+    start: ZeroBased,
+    end: Option<ZeroBased>,
+}
+
+impl Region {
+    pub fn new(name: &str, start: ZeroBased, end: Option<ZeroBased>) -> Result<Self, RegionError> {
+        if name.is_empty() {
+            return Err(RegionError::MissingName);
+        }
+        if let Some(e) = end {
+            if e < start {
+                return Err(RegionError::Inverted);
+            }
+        }
+        Ok(Region {
+            name: name.to_string(),
+            start,
+            end,
+        })
+    }
+
+    /// The whole of sequence `name`
+    pub fn whole(name: &str) -> Result<Self, RegionError> {
+        Region::new(name, ZeroBased::new(0), None)
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
+    }
+
+    pub fn start(&self) -> ZeroBased {
+        self.start
+    }
+
+    pub fn end(&self) -> Option<ZeroBased> {
+        self.end
+    }
+
+    /// Whether the region covers the whole sequence
+    pub fn is_whole(&self) -> bool {
+        self.start.get() == 0 && self.end.is_none()
+    }
+}
+
+fn parse_pos(s: &str) -> Result<OneBased, RegionError> {
+    let digits = s.replace(',', "");
+    let v = digits
+        .parse::<u64>()
+        .map_err(|_| RegionError::InvalidPosition(s.to_string()))?;
+    Ok(OneBased::try_from(v)?)
+}
+
+/// Parse "start" or "start-end" (1-based, inclusive)
+fn parse_range(s: &str) -> Result<(ZeroBased, Option<ZeroBased>), RegionError> {
+    match s.split_once('-') {
+        Some((start, "")) => Ok((parse_pos(start)?.to_zero_based(), None)),
+        Some((start, end)) => {
+            let start = parse_pos(start)?;
+            let end = parse_pos(end)?;
+            if end < start {
+                return Err(RegionError::Inverted);
+            }
+            // inclusive 1-based end == exclusive 0-based end
+            Ok((start.to_zero_based(), Some(ZeroBased::new(end.get()))))
+        }
+        None => Ok((parse_pos(s)?.to_zero_based(), None)),
+    }
+}
+
+impl FromStr for Region {
+    type Err = RegionError;
+
+    /// Sequence names may themselves contain ':' (e.g. "HLA-A*01:01"), so the
+    /// suffix after the last ':' is only treated as a range if it parses as one.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(RegionError::Empty);
+        }
+        match s.rsplit_once(':') {
+            Some((name, range)) if range.starts_with(|c: char| c.is_ascii_digit()) => {
+                let (start, end) = parse_range(range)?;
+                Region::new(name, start, end)
+            }
+            _ => Region::whole(s),
+        }
+    }
+}
+
+/// Writes the region back out in 1-based samtools syntax
+impl Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.is_whole(), self.end) {
+            (true, _) => write!(f, "{}", self.name),
+            (false, Some(end)) => write!(f, "{}:{}-{}", self.name, self.start.get() + 1, end),
+            (false, None) => write!(f, "{}:{}", self.name, self.start.get() + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_region() {
+        let r = "chr1:1,000-2,000".parse::<Region>().unwrap();
+        assert_eq!(r.name(), "chr1");
+        assert_eq!(r.start().get(), 999);
+        assert_eq!(r.end().unwrap().get(), 2000);
+        assert_eq!(r.to_string(), "chr1:1000-2000");
+
+        let r = "chr2".parse::<Region>().unwrap();
+        assert!(r.is_whole());
+        assert_eq!(r.to_string(), "chr2");
+
+        let r = "chr3:5".parse::<Region>().unwrap();
+        assert_eq!(r.start().get(), 4);
+        assert_eq!(r.end(), None);
+    }
+
+    #[test]
+    fn test_parse_colon_in_name() {
+        let r = "HLA-A*01:01:01:01".parse::<Region>().unwrap();
+        assert_eq!(r.name(), "HLA-A*01:01:01");
+        let r = "HLA-A*01:01:01:01:10-20".parse::<Region>().unwrap();
+        assert_eq!(r.name(), "HLA-A*01:01:01:01");
+        assert_eq!(r.start().get(), 9);
+        let r = "weird:name".parse::<Region>().unwrap();
+        assert_eq!(r.name(), "weird:name");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!("".parse::<Region>(), Err(RegionError::Empty));
+        assert_eq!(":1-10".parse::<Region>(), Err(RegionError::MissingName));
+        assert_eq!(
+            "chr1:0-10".parse::<Region>(),
+            Err(RegionError::Pos(PosError::ZeroOneBased))
+        );
+        assert_eq!("chr1:20-10".parse::<Region>(), Err(RegionError::Inverted));
+        assert!(matches!(
+            "chr1:1x-10".parse::<Region>(),
+            Err(RegionError::InvalidPosition(_))
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+//! Template-based read renaming
+//!
+//! An `IdTemplate` such as `{sample}_{n}` is expanded once per record by a `Renamer`.
+//! Placeholders:
+//!
+//! - `{n}`: 1-based record counter
+//! - `{id}`: original id
+//! - `{sample}`: the renamer's sample name
+//! - `{hash8}`: first 8 hex digits of a 64-bit FNV-1a hash of the original id
+//!
+//! With `keep_mate_suffix`, a trailing "/1" or "/2" is removed before expansion and
+//! appended afterwards, so mates renamed by separate renamers keep matching names.
+//!
+//! Ids are bytes: `{id}` copies the original id unchanged, even if it is not UTF-8.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// A record whose id can be rewritten
+pub trait RecordId {
+    fn record_id(&self) -> &[u8];
+
+    fn set_record_id(&mut self, id: Vec<u8>);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Counter,
+    Id,
+    Sample,
+    Hash8,
+}
+
+/// A parsed renaming template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    Unclosed,
+    Empty,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownPlaceholder(p) => write!(
+                f,
+                "unknown placeholder '{{{p}}}', expected one of {{n}}, {{id}}, {{sample}}, {{hash8}}"
+            ),
+            TemplateError::Unclosed => write!(f, "unclosed '{{' in template"),
+            TemplateError::Empty => write!(f, "template is empty"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl FromStr for IdTemplate {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..].find('}').ok_or(TemplateError::Unclosed)? + open;
+            parts.push(match &rest[open + 1..close] {
+                "n" => Part::Counter,
+                "id" => Part::Id,
+                "sample" => Part::Sample,
+                "hash8" => Part::Hash8,
+                other => return Err(TemplateError::UnknownPlaceholder(other.to_string())),
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(IdTemplate { parts })
+    }
+}
+
+/// 64-bit FNV-1a, stable across platforms and releases
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Split a trailing "/1" or "/2" off a read id
+fn split_mate_suffix(id: &[u8]) -> (&[u8], &[u8]) {
+    match id.len().checked_sub(2).map(|i| id.split_at(i)) {
+        Some((base, suffix)) if suffix == b"/1" || suffix == b"/2" => (base, suffix),
+        _ => (id, b""),
+    }
+}
+
+/// Expands an `IdTemplate` for successive records
+#[derive(Debug, Clone)]
+pub struct Renamer {
+    template: IdTemplate,
+    sample: String,
+    keep_mate_suffix: bool,
+    n: u64,
+}
+
+impl Renamer {
+    pub fn new(template: IdTemplate, sample: impl Into<String>, keep_mate_suffix: bool) -> Self {
+        Renamer {
+            template,
+            sample: sample.into(),
+            keep_mate_suffix,
+            n: 0,
+        }
+    }
+
+    /// Number of ids renamed so far
+    pub fn count(&self) -> u64 {
+        self.n
+    }
+
+    /// New id for the next record
+    pub fn next_id(&mut self, id: &[u8]) -> Vec<u8> {
+        self.n += 1;
+        let (base, suffix) = if self.keep_mate_suffix {
+            split_mate_suffix(id)
+        } else {
+            (id, &b""[..])
+        };
+        let mut out = Vec::with_capacity(id.len() + 16);
+        for part in &self.template.parts {
+            match part {
+                Part::Literal(s) => out.extend_from_slice(s.as_bytes()),
+                Part::Counter => out.extend_from_slice(self.n.to_string().as_bytes()),
+                Part::Id => out.extend_from_slice(base),
+                Part::Sample => out.extend_from_slice(self.sample.as_bytes()),
+                Part::Hash8 => {
+                    out.extend_from_slice(&format!("{:016x}", fnv1a(base)).as_bytes()[..8])
+                }
+            }
+        }
+        out.extend_from_slice(suffix);
+        out
+    }
+
+    /// Rename `rec` in place, returning its old id
+    pub fn rename<R: RecordId + ?Sized>(&mut self, rec: &mut R) -> Vec<u8> {
+        let new = self.next_id(rec.record_id());
+        let old = rec.record_id().to_vec();
+        rec.set_record_id(new);
+        old
+    }
+
+    /// Wrap a record stream so every record comes out renamed
+    pub fn apply<I>(self, records: I) -> Renamed<I> {
+        Renamed {
+            inner: records,
+            renamer: self,
+        }
+    }
+}
+
+/// Iterator adapter returned by `Renamer::apply`
+///
+/// Errors pass through untouched and do not advance the counter.
+pub struct Renamed<I> {
+    inner: I,
+    renamer: Renamer,
+}
+
+impl<I> Renamed<I> {
+    pub fn renamer(&self) -> &Renamer {
+        &self.renamer
+    }
+}
+
+impl<I, R, E> Iterator for Renamed<I>
+where
+    I: Iterator<Item = Result<R, E>>,
+    R: RecordId,
+{
+    type Item = Result<R, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|r| {
+            r.map(|mut r| {
+                self.renamer.rename(&mut r);
+                r
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Named(Vec<u8>);
+
+    impl RecordId for Named {
+        fn record_id(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn set_record_id(&mut self, id: Vec<u8>) {
+            self.0 = id;
+        }
+    }
+
+    fn stream(ids: &[&str]) -> impl Iterator<Item = Result<Named, ()>> {
+        ids.iter()
+            .map(|i| Ok(Named(i.as_bytes().to_vec())))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_template_errors() {
+        assert_eq!(
+            "{sample}_{num}".parse::<IdTemplate>(),
+            Err(TemplateError::UnknownPlaceholder("num".to_string()))
+        );
+        assert_eq!(
+            "{sample".parse::<IdTemplate>(),
+            Err(TemplateError::Unclosed)
+        );
+        assert_eq!("".parse::<IdTemplate>(), Err(TemplateError::Empty));
+        assert!("read".parse::<IdTemplate>().is_ok());
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let t = "{sample}_{n}:{id}:{hash8}".parse().unwrap();
+        let mut r = Renamer::new(t, "S1", false);
+        let first = String::from_utf8(r.next_id(b"SRR1.1")).unwrap();
+        let (prefix, hash) = first.rsplit_once(':').unwrap();
+        assert_eq!(prefix, "S1_1:SRR1.1");
+        assert_eq!(hash, &format!("{:016x}", fnv1a(b"SRR1.1"))[..8]);
+        assert_eq!(r.next_id(b"a"), b"S1_2:a:af63dc4c");
+        assert_eq!(r.count(), 2);
+    }
+
+    #[test]
+    fn test_paired_files_stay_in_step() {
+        let t: IdTemplate = "{sample}_{n}_{hash8}".parse().unwrap();
+        let renamer = Renamer::new(t, "S1", true);
+        let r1 = renamer
+            .clone()
+            .apply(stream(&["a/1", "b/1", "c/1"]))
+            .map(|r| String::from_utf8(r.unwrap().0).unwrap())
+            .collect::<Vec<String>>();
+        let r2 = renamer
+            .apply(stream(&["a/2", "b/2", "c/2"]))
+            .map(|r| String::from_utf8(r.unwrap().0).unwrap())
+            .collect::<Vec<String>>();
+        for (i, (a, b)) in r1.iter().zip(&r2).enumerate() {
+            assert!(a.starts_with(&format!("S1_{}_", i + 1)));
+            assert!(a.ends_with("/1"));
+            assert_eq!(a.strip_suffix("/1"), b.strip_suffix("/2"));
+        }
+    }
+
+    #[test]
+    fn test_mate_suffix() {
+        let t: IdTemplate = "{sample}_{n}".parse().unwrap();
+        let mut keep = Renamer::new(t.clone(), "S1", true);
+        assert_eq!(keep.next_id(b"read/2"), b"S1_1/2");
+        assert_eq!(keep.next_id(b"read/3"), b"S1_2");
+        let mut drop = Renamer::new(t, "S1", false);
+        assert_eq!(drop.next_id(b"read/2"), b"S1_1");
+    }
+
+    #[test]
+    fn test_non_utf8_id() {
+        let t: IdTemplate = "{id}_{n}".parse().unwrap();
+        let mut r = Renamer::new(t, "", true);
+        assert_eq!(r.next_id(b"r\xe9ad/1"), b"r\xe9ad_1/1");
+    }
+}
@@ -0,0 +1,102 @@
+//! Runs of bases sharing a property, such as soft-masked or N stretches
+//!
+//! Runs are 0-based, half-open ranges, ready to be written as BED.
+
+use std::ops::Range;
+
+/// Whether a base is soft-masked (lowercase)
+pub fn is_masked(b: u8) -> bool {
+    b.is_ascii_lowercase()
+}
+
+/// Whether a base is an unknown base, N in either case
+pub fn is_gap(b: u8) -> bool {
+    matches!(b, b'N' | b'n')
+}
+
+/// Maximal runs of bytes in `seq` matching `pred`, at least `min_len` long
+///
+/// Runs are maximal for `pred` alone, so two runs are only reported separately
+/// if a non-matching byte lies between them.
+pub fn find_runs<P>(seq: &[u8], pred: P, min_len: usize) -> Runs<'_, P>
+where
+    P: Fn(u8) -> bool,
+{
+    Runs {
+        seq,
+        pred,
+        min_len: min_len.max(1),
+        pos: 0,
+    }
+}
+
+/// Iterator returned by `find_runs`
+pub struct Runs<'a, P> {
+    seq: &'a [u8],
+    pred: P,
+    min_len: usize,
+    pos: usize,
+}
+
+impl<P> Iterator for Runs<'_, P>
+where
+    P: Fn(u8) -> bool,
+{
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.seq.len() {
+            let rest = &self.seq[self.pos..];
+            let Some(skip) = rest.iter().position(|b| (self.pred)(*b)) else {
+                self.pos = self.seq.len();
+                break;
+            };
+            let start = self.pos + skip;
+            let len = self.seq[start..]
+                .iter()
+                .position(|b| !(self.pred)(*b))
+                .unwrap_or(self.seq.len() - start);
+            self.pos = start + len;
+            if len >= self.min_len {
+                return Some(start..self.pos);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQ: &[u8] = b"acgtNNNNACGTnnnTTaaCCGGNNNNNgg";
+
+    fn runs(pred: fn(u8) -> bool, min_len: usize) -> Vec<Range<usize>> {
+        find_runs(SEQ, pred, min_len).collect()
+    }
+
+    #[test]
+    fn test_masked_runs() {
+        // lowercase n is masked too; runs either side of N or uppercase stay apart
+        assert_eq!(runs(is_masked, 0), [0..4, 12..15, 17..19, 28..30]);
+        assert_eq!(runs(is_masked, 3), [0..4, 12..15]);
+        assert_eq!(runs(is_masked, 5), []);
+    }
+
+    #[test]
+    fn test_gap_runs() {
+        assert_eq!(runs(is_gap, 1), [4..8, 12..15, 23..28]);
+        assert_eq!(runs(is_gap, 4), [4..8, 23..28]);
+    }
+
+    #[test]
+    fn test_edges() {
+        assert_eq!(find_runs(b"", is_gap, 1).count(), 0);
+        assert_eq!(find_runs(b"ACGT", is_gap, 1).count(), 0);
+        assert_eq!(find_runs(b"NNNN", is_gap, 1).next(), Some(0..4));
+        assert_eq!(
+            find_runs(b"aAaA", is_masked, 1).collect::<Vec<_>>(),
+            [0..1, 2..3]
+        );
+    }
+}
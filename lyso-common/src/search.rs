@@ -0,0 +1,359 @@
+//! Nucleotide motif search with IUPAC codes
+//!
+//! `Motif` compiles a pattern of up to 64 IUPAC codes into one match mask per byte
+//! value and scans with shift-or (bitap): a table lookup, a shift and an OR per input
+//! byte. With `with_max_mismatches(k)` it keeps k + 1 shift-or states (the Wu-Manber
+//! extension), which allows substitutions but not insertions or deletions.
+//!
+//! A pattern code matches a base that is one of its nucleotides, ignoring case, and
+//! pattern `N` matches any byte. A degenerate code in the sequence only matches a
+//! pattern code covering all of its nucleotides, so a sequence `N` matches only `N`.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::intervals::Strand;
+
+/// Longest pattern that fits the 64-bit shift-or state
+pub const MAX_MOTIF_LEN: usize = 64;
+
+/// Nucleotides of an IUPAC code as a bit set: A=1, C=2, G=4, T=8
+fn iupac_bits(b: u8) -> Option<u8> {
+    Some(match b.to_ascii_uppercase() {
+        b'A' => 0b0001,
+        b'C' => 0b0010,
+        b'G' => 0b0100,
+        b'T' | b'U' => 0b1000,
+        b'M' => 0b0011,
+        b'R' => 0b0101,
+        b'W' => 0b1001,
+        b'S' => 0b0110,
+        b'Y' => 0b1010,
+        b'K' => 0b1100,
+        b'V' => 0b0111,
+        b'H' => 0b1011,
+        b'D' => 0b1101,
+        b'B' => 0b1110,
+        b'N' => 0b1111,
+        _ => return None,
+    })
+}
+
+/// Complement of an IUPAC code, keeping case; other bytes are returned unchanged
+pub fn complement(b: u8) -> u8 {
+    let c = match b.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'T' | b'U' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        other => other,
+    };
+    if b.is_ascii_lowercase() {
+        c.to_ascii_lowercase()
+    } else {
+        c
+    }
+}
+
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|b| complement(*b)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MotifError {
+    Empty,
+    TooLong(usize),
+    InvalidCode(char),
+    /// Every window would match with this many mismatches
+    TooManyMismatches {
+        max_mismatches: usize,
+        len: usize,
+    },
+}
+
+impl Display for MotifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MotifError::Empty => write!(f, "motif is empty"),
+            MotifError::TooLong(n) => {
+                write!(
+                    f,
+                    "motif is {n} bases long, at most {MAX_MOTIF_LEN} are supported"
+                )
+            }
+            MotifError::InvalidCode(c) => write!(f, "invalid IUPAC code {c:?} in motif"),
+            MotifError::TooManyMismatches {
+                max_mismatches,
+                len,
+            } => write!(
+                f,
+                "{max_mismatches} mismatches must be fewer than the motif length {len}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MotifError {}
+
+/// One occurrence found by `Motif::find_all_with_rc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+    /// 0-based start on the forward strand
+    pub pos: usize,
+    pub strand: Strand,
+    pub mismatches: usize,
+}
+
+/// Shift-or match masks for one pattern: bit i is clear where the byte matches code i
+#[derive(Clone)]
+struct Masks(Box<[u64; 256]>);
+
+impl Masks {
+    fn new(pattern: &[u8]) -> Self {
+        let mut masks = Box::new([!0u64; 256]);
+        for (i, code) in pattern.iter().enumerate() {
+            let want = iupac_bits(*code).unwrap_or_default();
+            for (b, mask) in masks.iter_mut().enumerate() {
+                let matches =
+                    want == 0b1111 || iupac_bits(b as u8).is_some_and(|have| have & !want == 0);
+                if matches {
+                    *mask &= !(1 << i);
+                }
+            }
+        }
+        Masks(masks)
+    }
+}
+
+impl fmt::Debug for Masks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Masks")
+    }
+}
+
+/// A compiled search pattern
+#[derive(Debug, Clone)]
+pub struct Motif {
+    pattern: String,
+    forward: Masks,
+    reverse: Masks,
+    max_mismatches: usize,
+}
+
+impl Motif {
+    pub fn new(pattern: &str) -> Result<Self, MotifError> {
+        if pattern.is_empty() {
+            return Err(MotifError::Empty);
+        }
+        if let Some(c) = pattern
+            .chars()
+            .find(|c| !c.is_ascii() || iupac_bits(*c as u8).is_none())
+        {
+            return Err(MotifError::InvalidCode(c));
+        }
+        if pattern.len() > MAX_MOTIF_LEN {
+            return Err(MotifError::TooLong(pattern.len()));
+        }
+        let pattern = pattern.to_ascii_uppercase();
+        Ok(Motif {
+            forward: Masks::new(pattern.as_bytes()),
+            reverse: Masks::new(&reverse_complement(pattern.as_bytes())),
+            pattern,
+            max_mismatches: 0,
+        })
+    }
+
+    /// Also report windows differing from the pattern at up to `k` positions
+    pub fn with_max_mismatches(mut self, k: usize) -> Result<Self, MotifError> {
+        if k >= self.pattern.len() {
+            return Err(MotifError::TooManyMismatches {
+                max_mismatches: k,
+                len: self.pattern.len(),
+            });
+        }
+        self.max_mismatches = k;
+        Ok(self)
+    }
+
+    /// The pattern, upper-cased
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn max_mismatches(&self) -> usize {
+        self.max_mismatches
+    }
+
+    /// 0-based start of every forward-strand occurrence, overlapping ones included
+    pub fn find_all(&self, seq: &[u8]) -> Vec<usize> {
+        let mut hits = Vec::new();
+        self.scan(&self.forward, seq, |pos, _| hits.push(pos));
+        hits
+    }
+
+    /// Occurrences on both strands, ordered by position with forward first
+    ///
+    /// Reverse-strand hits are matches of the reverse-complemented pattern and are
+    /// reported at their forward-strand start. A palindromic motif such as GAATTC
+    /// therefore reports each site once per strand.
+    pub fn find_all_with_rc(&self, seq: &[u8]) -> Vec<Hit> {
+        let mut hits = Vec::new();
+        for (masks, strand) in [
+            (&self.forward, Strand::Forward),
+            (&self.reverse, Strand::Reverse),
+        ] {
+            self.scan(masks, seq, |pos, mismatches| {
+                hits.push(Hit {
+                    pos,
+                    strand,
+                    mismatches,
+                })
+            });
+        }
+        // stable, so forward stays ahead of reverse at the same position
+        hits.sort_by_key(|h| h.pos);
+        hits
+    }
+
+    /// Call `hit(start, mismatches)` for every window within `max_mismatches`
+    fn scan(&self, masks: &Masks, seq: &[u8], mut hit: impl FnMut(usize, usize)) {
+        let m = self.pattern.len();
+        let last = 1u64 << (m - 1);
+        let masks = &masks.0;
+        if self.max_mismatches == 0 {
+            let mut state = !0u64;
+            for (i, b) in seq.iter().enumerate() {
+                state = (state << 1) | masks[*b as usize];
+                if state & last == 0 {
+                    hit(i + 1 - m, 0);
+                }
+            }
+            return;
+        }
+        // states[j]: bit i clear if pattern[..=i] ends here with at most j mismatches
+        let mut states = vec![!0u64; self.max_mismatches + 1];
+        for (i, b) in seq.iter().enumerate() {
+            let mask = masks[*b as usize];
+            let mut prev = states[0];
+            states[0] = (states[0] << 1) | mask;
+            for state in states.iter_mut().skip(1) {
+                let old = *state;
+                // extend a match at this level, or spend a mismatch on the level below
+                *state = ((old << 1) | mask) & (prev << 1);
+                prev = old;
+            }
+            if let Some(j) = states.iter().position(|s| s & last == 0) {
+                hit(i + 1 - m, j);
+            }
+        }
+    }
+}
+
+impl FromStr for Motif {
+    type Err = MotifError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Motif::new(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Direct window-by-window comparison
+    fn naive(pattern: &[u8], seq: &[u8], k: usize) -> Vec<usize> {
+        if seq.len() < pattern.len() {
+            return Vec::new();
+        }
+        (0..=seq.len() - pattern.len())
+            .filter(|&start| {
+                let mismatches = pattern
+                    .iter()
+                    .zip(&seq[start..])
+                    .filter(|(p, s)| {
+                        let want = iupac_bits(**p).unwrap();
+                        !(want == 0b1111 || iupac_bits(**s).is_some_and(|h| h & !want == 0))
+                    })
+                    .count();
+                mismatches <= k
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_positions() {
+        let m = Motif::new("ACG").unwrap();
+        assert_eq!(m.find_all(b"ACGTTTACG"), [0, 6]);
+        assert_eq!(m.find_all(b"acgt"), [0]);
+        assert!(m.find_all(b"AC").is_empty());
+        // overlapping occurrences
+        assert_eq!(Motif::new("AA").unwrap().find_all(b"AAAA"), [0, 1, 2]);
+        assert_eq!(Motif::new("ATA").unwrap().find_all(b"ATATA"), [0, 2]);
+    }
+
+    #[test]
+    fn test_degenerate() {
+        let m = Motif::new("GRN").unwrap();
+        assert_eq!(m.find_all(b"GAxGGTGCA"), [0, 3]);
+        // a sequence N only matches a pattern N
+        assert!(Motif::new("A").unwrap().find_all(b"N").is_empty());
+        assert_eq!(Motif::new("NN").unwrap().find_all(b"N-N"), [0, 1]);
+        assert_eq!(Motif::new("R").unwrap().find_all(b"RYA"), [0, 2]);
+        assert_eq!(Motif::new("AXG").unwrap_err(), MotifError::InvalidCode('X'));
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"AcGRN"), b"NYCgT");
+        let m = Motif::new("AAC").unwrap();
+        let hits = m.find_all_with_rc(b"AACGTT");
+        let found = hits.iter().map(|h| (h.pos, h.strand)).collect::<Vec<_>>();
+        assert_eq!(found, [(0, Strand::Forward), (3, Strand::Reverse)]);
+        // palindromes are reported on both strands
+        let hits = Motif::new("GAATTC").unwrap().find_all_with_rc(b"TGAATTCA");
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().all(|h| h.pos == 1));
+    }
+
+    #[test]
+    fn test_mismatches_match_naive() {
+        // deterministic pseudo-random sequence
+        let mut x = 12345u32;
+        let seq = (0..2000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b"ACGT"[(x >> 16) as usize % 4]
+            })
+            .collect::<Vec<u8>>();
+        for (pattern, k) in [("ACGTA", 1), ("GATTACA", 2), ("ACNNT", 1), ("TTT", 0)] {
+            let m = Motif::new(pattern).unwrap().with_max_mismatches(k).unwrap();
+            assert_eq!(m.find_all(&seq), naive(pattern.as_bytes(), &seq, k));
+        }
+        let m = Motif::new("ACGT").unwrap().with_max_mismatches(1).unwrap();
+        let hits = m.find_all_with_rc(b"ACGAxACGT");
+        assert_eq!(hits[0].mismatches, 1);
+        assert!(hits.iter().any(|h| h.pos == 5 && h.mismatches == 0));
+        assert!(Motif::new("AC").unwrap().with_max_mismatches(2).is_err());
+    }
+
+    #[test]
+    fn test_pattern_at_end_and_max_len() {
+        let seq = [b"T".repeat(100), b"A".repeat(64)].concat();
+        let m = Motif::new(&"A".repeat(64)).unwrap();
+        assert_eq!(m.find_all(&seq), [100]);
+        assert_eq!(
+            Motif::new(&"A".repeat(65)).unwrap_err(),
+            MotifError::TooLong(65)
+        );
+    }
+}
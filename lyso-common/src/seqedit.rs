@@ -0,0 +1,355 @@
+//! In-place edits of record sequences: case, and ambiguous bases
+//!
+//! `SeqEdit` is implemented by the FASTA and FASTQ records. Every edit works on the
+//! sequence's own buffer, keeps it the same length in bytes and leaves ids,
+//! descriptions and qualities alone. `SeqEdits` bundles the edits a command line
+//! asks for, and `RecordStreamExt::edit_seqs` applies them to a record stream.
+//!
+//! ```
+//! use lyso_common::seqedit::{Replacement, SeqEdits};
+//! use lyso_common::stream::RecordStreamExt;
+//! # use lyso_common::seqedit::SeqEdit;
+//! # struct Seq(String);
+//! # impl SeqEdit for Seq {
+//! #     fn seq_string_mut(&mut self) -> &mut String { &mut self.0 }
+//! # }
+//!
+//! let records = vec![Ok::<_, ()>(Seq(String::from("acgTNRyn")))];
+//! let edits = SeqEdits::new().uppercase().replace_ambiguous(Replacement::Base(b'A'));
+//! let edited = records.into_iter().edit_seqs(edits).next().unwrap().unwrap();
+//! assert_eq!(edited.0, "ACGTAAAA");
+//! ```
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::synth::Rng;
+
+const BASES: [u8; 4] = *b"ACGT";
+
+fn is_acgt(b: u8) -> bool {
+    matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T')
+}
+
+/// A record whose sequence can be edited in place
+pub trait SeqEdit {
+    /// The sequence, for editing in place; edits must keep its length
+    fn seq_string_mut(&mut self) -> &mut String;
+
+    /// Uppercase the sequence's ASCII letters
+    fn make_uppercase(&mut self) {
+        self.seq_string_mut().make_ascii_uppercase();
+    }
+
+    /// Lowercase the sequence's ASCII letters, as for soft-masking
+    fn make_lowercase(&mut self) {
+        self.seq_string_mut().make_ascii_lowercase();
+    }
+
+    /// Replace every byte other than ACGT (in either case) with `with`, returning how many
+    ///
+    /// Replacements take the case of the byte they replace.
+    ///
+    /// # Panics
+    ///
+    /// If `with` is not ASCII.
+    fn replace_ambiguous(&mut self, with: u8) -> usize {
+        assert!(with.is_ascii(), "replacement base must be ASCII");
+        self.replace_ambiguous_with(|| with)
+    }
+
+    /// Replace every byte other than ACGT with a base from `pick`, returning how many
+    ///
+    /// `pick` is called once per replaced byte, in order; each byte of a non-ASCII
+    /// character counts as one. It must return ASCII.
+    fn replace_ambiguous_with(&mut self, mut pick: impl FnMut() -> u8) -> usize {
+        let seq = self.seq_string_mut();
+        if seq.bytes().all(is_acgt) {
+            return 0;
+        }
+        let mut bytes = std::mem::take(seq).into_bytes();
+        let mut replaced = 0;
+        for b in bytes.iter_mut().filter(|b| !is_acgt(**b)) {
+            let base = pick();
+            *b = if b.is_ascii_lowercase() {
+                base.to_ascii_lowercase()
+            } else {
+                base.to_ascii_uppercase()
+            };
+            replaced += 1;
+        }
+        // every byte written over is ASCII, so whole characters were replaced
+        *seq = String::from_utf8(bytes).expect("replacement bases must be ASCII");
+        replaced
+    }
+}
+
+/// What `SeqEdits` turns ambiguous bases into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    /// Always this base
+    Base(u8),
+    /// A base drawn from the edits' seeded generator
+    Random,
+}
+
+impl FromStr for Replacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [b] if is_acgt(*b) => Ok(Replacement::Base(b.to_ascii_uppercase())),
+            _ if s.eq_ignore_ascii_case("random") => Ok(Replacement::Random),
+            _ => Err(format!(
+                "invalid replacement '{s}', expected A, C, G, T or random"
+            )),
+        }
+    }
+}
+
+impl Display for Replacement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Replacement::Base(b) => write!(f, "{}", char::from(*b)),
+            Replacement::Random => write!(f, "random"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+/// Edits applied to each record in turn: a change of case, then replacement of ambiguous bases
+///
+/// Random replacements come from a generator seeded with `seed` (0 by default),
+/// so the same input edited the same way gives the same output.
+#[derive(Debug, Clone)]
+pub struct SeqEdits {
+    case: Option<Case>,
+    replacement: Option<Replacement>,
+    rng: Rng,
+}
+
+impl Default for SeqEdits {
+    fn default() -> Self {
+        SeqEdits {
+            case: None,
+            replacement: None,
+            rng: Rng::new(0),
+        }
+    }
+}
+
+impl SeqEdits {
+    /// No edits
+    #[must_use]
+    pub fn new() -> Self {
+        SeqEdits::default()
+    }
+
+    /// Uppercase sequences
+    #[must_use]
+    pub fn uppercase(mut self) -> Self {
+        self.case = Some(Case::Upper);
+        self
+    }
+
+    /// Lowercase sequences
+    #[must_use]
+    pub fn lowercase(mut self) -> Self {
+        self.case = Some(Case::Lower);
+        self
+    }
+
+    /// Replace bases other than ACGT
+    #[must_use]
+    pub fn replace_ambiguous(mut self, with: Replacement) -> Self {
+        self.replacement = Some(with);
+        self
+    }
+
+    /// Seed the generator of `Replacement::Random`
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::new(seed);
+        self
+    }
+
+    /// Whether applying these edits changes nothing
+    pub fn is_empty(&self) -> bool {
+        self.case.is_none() && self.replacement.is_none()
+    }
+
+    /// Edit `rec` in place
+    pub fn apply<R: SeqEdit>(&mut self, rec: &mut R) {
+        match self.case {
+            Some(Case::Upper) => rec.make_uppercase(),
+            Some(Case::Lower) => rec.make_lowercase(),
+            None => {}
+        }
+        match self.replacement {
+            Some(Replacement::Base(b)) => {
+                rec.replace_ambiguous(b);
+            }
+            Some(Replacement::Random) => {
+                let rng = &mut self.rng;
+                rec.replace_ambiguous_with(|| BASES[(rng.next_u64() % 4) as usize]);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Records of a stream with `SeqEdits` applied, see `RecordStreamExt::edit_seqs`
+pub struct EditSeqs<I> {
+    pub(crate) inner: I,
+    pub(crate) edits: SeqEdits,
+}
+
+impl<I, R, E> Iterator for EditSeqs<I>
+where
+    I: Iterator<Item = Result<R, E>>,
+    R: SeqEdit,
+{
+    type Item = Result<R, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next = self.inner.next()?;
+        if let Ok(rec) = next.as_mut() {
+            self.edits.apply(rec);
+        }
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::RecordStreamExt;
+
+    /// A sequence with a quality and a name, to check edits leave them alone
+    #[derive(Debug, Clone, PartialEq)]
+    struct Read {
+        name: String,
+        seq: String,
+        qual: String,
+    }
+
+    impl SeqEdit for Read {
+        fn seq_string_mut(&mut self) -> &mut String {
+            &mut self.seq
+        }
+    }
+
+    fn read(seq: &str) -> Read {
+        Read {
+            name: String::from("r1 desc"),
+            seq: seq.to_string(),
+            qual: "I".repeat(seq.len()),
+        }
+    }
+
+    const IUPAC: &str = "ACGTacgtNnRYSWKMBDHVryswkmbdhv-.*";
+
+    #[test]
+    fn test_case() {
+        let mut r = read(IUPAC);
+        r.make_uppercase();
+        assert_eq!(r.seq, "ACGTACGTNNRYSWKMBDHVRYSWKMBDHV-.*");
+        r.make_lowercase();
+        assert_eq!(r.seq, "acgtacgtnnryswkmbdhvryswkmbdhv-.*");
+        assert_eq!(r.qual, "I".repeat(IUPAC.len()));
+    }
+
+    #[test]
+    fn test_replace_ambiguous() {
+        let mut r = read(IUPAC);
+        assert_eq!(r.replace_ambiguous(b'A'), IUPAC.len() - 8);
+        assert_eq!(r.seq, "ACGTacgtAaAAAAAAAAAAaaaaaaaaaaAAA");
+        assert_eq!(r.replace_ambiguous(b'C'), 0);
+
+        // each byte of a non-ASCII character is replaced
+        let mut r = read("AC\u{e9}GT");
+        assert_eq!(r.replace_ambiguous(b'N'), 2);
+        assert_eq!(r.seq, "ACNNGT");
+        assert_eq!(r.qual.len(), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be ASCII")]
+    fn test_replace_non_ascii() {
+        read("N").replace_ambiguous(0xe9);
+    }
+
+    #[test]
+    fn test_random_replacement() {
+        let edit = |seed| {
+            let mut r = read(&"N".repeat(200));
+            SeqEdits::new()
+                .replace_ambiguous(Replacement::Random)
+                .seed(seed)
+                .apply(&mut r);
+            r.seq
+        };
+        let seq = edit(7);
+        assert!(seq.bytes().all(|b| b"ACGT".contains(&b)));
+        for base in BASES {
+            assert!(seq.bytes().any(|b| b == base), "{}", char::from(base));
+        }
+        assert_eq!(seq, edit(7));
+        assert_ne!(seq, edit(8));
+    }
+
+    #[test]
+    fn test_replacement_parse() {
+        assert_eq!("a".parse(), Ok(Replacement::Base(b'A')));
+        assert_eq!("Random".parse(), Ok(Replacement::Random));
+        for bad in ["", "N", "AC", "x"] {
+            assert!(bad.parse::<Replacement>().is_err(), "{bad}");
+        }
+        assert_eq!(Replacement::Base(b'G').to_string(), "G");
+    }
+
+    #[test]
+    fn test_edits_keep_length_and_other_fields() {
+        // pseudo-random mixed-case IUPAC sequences through every combination of edits
+        let mut rng = Rng::new(11);
+        for i in 0..200 {
+            let len = (rng.next_u64() % 60) as usize;
+            let seq = (0..len)
+                .map(|_| char::from(IUPAC.as_bytes()[(rng.next_u64() % 33) as usize]))
+                .collect::<String>();
+            let before = read(&seq);
+            let mut edits = match i % 3 {
+                0 => SeqEdits::new().uppercase(),
+                1 => SeqEdits::new().lowercase(),
+                _ => SeqEdits::new(),
+            };
+            edits = match i % 4 {
+                0 => edits.replace_ambiguous(Replacement::Random).seed(i),
+                1 => edits.replace_ambiguous(Replacement::Base(b'T')),
+                _ => edits,
+            };
+            let after = vec![Ok::<_, ()>(before.clone())]
+                .into_iter()
+                .edit_seqs(edits.clone())
+                .next()
+                .unwrap()
+                .unwrap();
+            assert_eq!(after.seq.len(), before.seq.len(), "{seq}");
+            assert_eq!((&after.name, &after.qual), (&before.name, &before.qual));
+            // bases that were already ACGT keep their letter
+            for (a, b) in before.seq.bytes().zip(after.seq.bytes()) {
+                if is_acgt(a) {
+                    assert!(a.eq_ignore_ascii_case(&b), "{seq}");
+                }
+            }
+            if i % 4 < 2 {
+                assert!(after.seq.bytes().all(is_acgt), "{seq}");
+            }
+        }
+    }
+}
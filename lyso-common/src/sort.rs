@@ -0,0 +1,577 @@
+//! Sorting FASTA and FASTQ records by id or length
+//!
+//! `sort_records` holds records in memory until they exceed a memory limit, then
+//! spills sorted runs to temporary files and merges them. The sort is stable: records
+//! with equal keys come out in input order, with or without `reverse`.
+//!
+//! Runs are written and read back in the record's own format, so a format only has to
+//! implement `SortRecord` to be sortable. They are merged through a heap, at most
+//! `MERGE_FAN_IN` at a time: beyond that, groups of runs are first merged into longer
+//! ones, so a large sort never holds more than `MERGE_FAN_IN` files open.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::filter::SeqRecord;
+use crate::rename::RecordId;
+
+/// Bytes counted per record on top of its id, sequence and qualities
+const RECORD_OVERHEAD: usize = 64;
+
+/// Most runs merged at once, and so most run files open at once
+pub const MERGE_FAN_IN: usize = 64;
+
+/// A record that `sort_records` can spill to disk and read back
+pub trait SortRecord: RecordId + SeqRecord + Sized {
+    type Error: From<io::Error>;
+
+    /// Write the record in its native format
+    fn write_record(&self, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Read back the records of a run written with `write_record`
+    fn read_run(input: BufReader<File>) -> Box<dyn Iterator<Item = Result<Self, Self::Error>>>;
+}
+
+/// Which field records are sorted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortBy {
+    #[default]
+    Id,
+    /// Sequence length
+    Length,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(SortBy::Id),
+            "length" => Ok(SortBy::Length),
+            _ => Err(format!("unknown sort key '{s}', expected id or length")),
+        }
+    }
+}
+
+impl Display for SortBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortBy::Id => write!(f, "id"),
+            SortBy::Length => write!(f, "length"),
+        }
+    }
+}
+
+/// How to order records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SortKey {
+    pub by: SortBy,
+    /// Compare ids with `natural_cmp` (only affects `SortBy::Id`)
+    pub natural: bool,
+    /// Largest first; equal keys still keep their input order
+    pub reverse: bool,
+}
+
+impl SortKey {
+    pub fn compare<R: RecordId + SeqRecord>(&self, a: &R, b: &R) -> Ordering {
+        let ord = match (self.by, self.natural) {
+            (SortBy::Id, true) => natural_cmp(a.record_id(), b.record_id()),
+            (SortBy::Id, false) => a.record_id().cmp(b.record_id()),
+            (SortBy::Length, _) => a.seq_bytes().len().cmp(&b.seq_bytes().len()),
+        };
+        if self.reverse {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+}
+
+/// Compare byte strings with runs of ASCII digits ordered by their numeric value
+///
+/// "chr2" sorts before "chr10", and "read9" before "read10". Numbers that differ
+/// only in leading zeros ("r01", "r1") fall back to a plain byte comparison.
+pub fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let a_end = i + a[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let b_end = j + b[j..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let a_num = trim_zeros(&a[i..a_end]);
+            let b_num = trim_zeros(&b[j..b_end]);
+            let ord = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            (i, j) = (a_end, b_end);
+        } else {
+            match a[i].cmp(&b[j]) {
+                Ordering::Equal => (i, j) = (i + 1, j + 1),
+                ord => return ord,
+            }
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|c| **c == b'0').count();
+    &digits[zeros..]
+}
+
+/// Approximate memory held by a record while sorting
+fn record_size<R: RecordId + SeqRecord>(rec: &R) -> usize {
+    rec.record_id().len()
+        + rec.seq_bytes().len()
+        + rec.qual_bytes().map_or(0, <[u8]>::len)
+        + RECORD_OVERHEAD
+}
+
+/// A temporary directory for sorted runs, removed on drop
+pub(crate) struct RunDir {
+    path: PathBuf,
+    /// Runs not yet merged, in the order their records were read
+    pub(crate) runs: Vec<PathBuf>,
+    /// Runs created so far, for unique names
+    created: usize,
+}
+
+impl RunDir {
+    pub(crate) fn new() -> io::Result<Self> {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let path = std::env::temp_dir().join(format!("lyso-sort-{}-{nanos}", std::process::id()));
+        std::fs::create_dir(&path)?;
+        Ok(RunDir {
+            path,
+            runs: Vec::new(),
+            created: 0,
+        })
+    }
+
+    /// Create the next run, whose path is added to `runs`
+    pub(crate) fn create_run(&mut self) -> io::Result<BufWriter<File>> {
+        let path = self.path.join(format!("run{}", self.created));
+        let out = BufWriter::new(File::create(&path)?);
+        self.runs.push(path);
+        self.created += 1;
+        Ok(out)
+    }
+
+    /// Write `records` as the next run
+    fn spill<R: SortRecord>(&mut self, records: &[R]) -> io::Result<()> {
+        let mut out = self.create_run()?;
+        for rec in records {
+            rec.write_record(&mut out)?;
+        }
+        out.flush()
+    }
+
+    /// Merge the runs in passes of at most `fan_in` until no more than `fan_in`
+    /// are left, and return a merge over those
+    ///
+    /// Each group of consecutive runs becomes one run in its place, so ties still
+    /// go to the earliest record read.
+    pub(crate) fn merge<T, E, C>(
+        &mut self,
+        fan_in: usize,
+        format: &RunFormat<T, E>,
+        cmp: C,
+    ) -> Result<RunMerge<T, E, C>, E>
+    where
+        E: From<io::Error>,
+        C: Fn(&T, &T) -> Ordering + Copy,
+    {
+        let fan_in = fan_in.max(2);
+        while self.runs.len() > fan_in {
+            let runs = std::mem::take(&mut self.runs);
+            for group in runs.chunks(fan_in) {
+                let mut out = self.create_run()?;
+                for item in RunMerge::open(group, format.read, cmp)? {
+                    (format.write)(&item?, &mut out)?;
+                }
+                out.flush()?;
+                for path in group {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+        RunMerge::open(&self.runs, format.read, cmp)
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Items of one run, read back from its file
+pub(crate) type RunReader<T, E> = Box<dyn Iterator<Item = Result<T, E>>>;
+
+/// How the items of a run are written and read back
+pub(crate) struct RunFormat<T, E> {
+    pub(crate) write: fn(&T, &mut dyn Write) -> io::Result<()>,
+    pub(crate) read: fn(BufReader<File>) -> RunReader<T, E>,
+}
+
+/// The next item of one run, ordered so that `BinaryHeap` pops the smallest,
+/// from the earliest run on ties
+struct Head<T, C> {
+    item: T,
+    run: usize,
+    cmp: C,
+}
+
+impl<T, C: Fn(&T, &T) -> Ordering> Ord for Head<T, C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.cmp)(&other.item, &self.item).then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+impl<T, C: Fn(&T, &T) -> Ordering> PartialOrd for Head<T, C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, C: Fn(&T, &T) -> Ordering> PartialEq for Head<T, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T, C: Fn(&T, &T) -> Ordering> Eq for Head<T, C> {}
+
+/// The items of several sorted runs, merged in order
+pub(crate) struct RunMerge<T, E, C> {
+    readers: Vec<RunReader<T, E>>,
+    heads: BinaryHeap<Head<T, C>>,
+    cmp: C,
+}
+
+impl<T, E, C> RunMerge<T, E, C>
+where
+    E: From<io::Error>,
+    C: Fn(&T, &T) -> Ordering + Copy,
+{
+    fn open(
+        runs: &[PathBuf],
+        read: fn(BufReader<File>) -> RunReader<T, E>,
+        cmp: C,
+    ) -> Result<Self, E> {
+        let mut merge = RunMerge {
+            readers: Vec::with_capacity(runs.len()),
+            heads: BinaryHeap::with_capacity(runs.len()),
+            cmp,
+        };
+        for path in runs {
+            merge.readers.push(read(BufReader::new(File::open(path)?)));
+        }
+        for run in 0..merge.readers.len() {
+            merge.advance(run)?;
+        }
+        Ok(merge)
+    }
+
+    /// Queue the next item of `run`, if it has one
+    fn advance(&mut self, run: usize) -> Result<(), E> {
+        if let Some(item) = self.readers[run].next().transpose()? {
+            let cmp = self.cmp;
+            self.heads.push(Head { item, run, cmp });
+        }
+        Ok(())
+    }
+}
+
+impl<T, E, C> Iterator for RunMerge<T, E, C>
+where
+    E: From<io::Error>,
+    C: Fn(&T, &T) -> Ordering + Copy,
+{
+    type Item = Result<T, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Head { item, run, .. } = self.heads.pop()?;
+        Some(self.advance(run).map(|()| item))
+    }
+}
+
+/// Sort `records` by `key` and write them to `out`
+///
+/// Records are buffered until their approximate size passes `memory_limit` bytes;
+/// beyond that, sorted runs go to a temporary directory and are merged, holding one
+/// record per run in memory. Stops at the first error from `records`.
+pub fn sort_records<R, I, W>(
+    records: I,
+    out: &mut W,
+    key: SortKey,
+    memory_limit: usize,
+) -> Result<(), R::Error>
+where
+    R: SortRecord,
+    I: IntoIterator<Item = Result<R, R::Error>>,
+    W: Write,
+{
+    sort_records_with(records, out, key, memory_limit, MERGE_FAN_IN)
+}
+
+/// `sort_records`, merging at most `fan_in` runs at once
+fn sort_records_with<R, I, W>(
+    records: I,
+    out: &mut W,
+    key: SortKey,
+    memory_limit: usize,
+    fan_in: usize,
+) -> Result<(), R::Error>
+where
+    R: SortRecord,
+    I: IntoIterator<Item = Result<R, R::Error>>,
+    W: Write,
+{
+    let mut buffer: Vec<R> = Vec::new();
+    let mut buffered = 0;
+    let mut runs: Option<RunDir> = None;
+    for rec in records {
+        let rec = rec?;
+        buffered += record_size(&rec);
+        buffer.push(rec);
+        if buffered > memory_limit {
+            buffer.sort_by(|a, b| key.compare(a, b));
+            let dir = match runs.as_mut() {
+                Some(dir) => dir,
+                None => runs.insert(RunDir::new()?),
+            };
+            dir.spill(&buffer)?;
+            buffer.clear();
+            buffered = 0;
+        }
+    }
+    buffer.sort_by(|a, b| key.compare(a, b));
+
+    let Some(mut dir) = runs else {
+        for rec in &buffer {
+            rec.write_record(out)?;
+        }
+        return Ok(out.flush()?);
+    };
+    if !buffer.is_empty() {
+        dir.spill(&buffer)?;
+    }
+    drop(buffer);
+    let format = RunFormat {
+        write: R::write_record,
+        read: R::read_run,
+    };
+    for rec in dir.merge(fan_in, &format, |a: &R, b: &R| key.compare(a, b))? {
+        rec?.write_record(out)?;
+    }
+    Ok(out.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    /// Minimal record whose native format is one "id<TAB>seq" line
+    #[derive(Debug, Clone, PartialEq)]
+    struct Line {
+        id: Vec<u8>,
+        seq: Vec<u8>,
+    }
+
+    fn line(id: &str, seq: &str) -> Line {
+        Line {
+            id: id.as_bytes().to_vec(),
+            seq: seq.as_bytes().to_vec(),
+        }
+    }
+
+    impl RecordId for Line {
+        fn record_id(&self) -> &[u8] {
+            &self.id
+        }
+
+        fn set_record_id(&mut self, id: Vec<u8>) {
+            self.id = id;
+        }
+    }
+
+    impl SeqRecord for Line {
+        fn seq_bytes(&self) -> &[u8] {
+            &self.seq
+        }
+
+        fn qual_bytes(&self) -> Option<&[u8]> {
+            None
+        }
+    }
+
+    impl SortRecord for Line {
+        type Error = io::Error;
+
+        fn write_record(&self, out: &mut dyn Write) -> io::Result<()> {
+            out.write_all(&self.id)?;
+            out.write_all(b"\t")?;
+            out.write_all(&self.seq)?;
+            out.write_all(b"\n")
+        }
+
+        fn read_run(input: BufReader<File>) -> Box<dyn Iterator<Item = io::Result<Self>>> {
+            Box::new(input.split(b'\n').map(|l| {
+                let l = l?;
+                let tab = l.iter().position(|c| *c == b'\t').unwrap();
+                Ok(Line {
+                    id: l[..tab].to_vec(),
+                    seq: l[tab + 1..].to_vec(),
+                })
+            }))
+        }
+    }
+
+    fn sorted(records: &[Line], key: SortKey, memory_limit: usize) -> Vec<String> {
+        let mut out = Vec::new();
+        sort_records(records.iter().cloned().map(Ok), &mut out, key, memory_limit).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut ids = ["chr10", "chr2", "chrX", "chr1", "chr02", "chr1a", "c", ""];
+        ids.sort_by(|a, b| natural_cmp(a.as_bytes(), b.as_bytes()));
+        assert_eq!(
+            ids,
+            ["", "c", "chr1", "chr1a", "chr02", "chr2", "chr10", "chrX"]
+        );
+        assert_eq!(natural_cmp(b"read9.2", b"read10.1"), Ordering::Less);
+        assert_eq!(natural_cmp(b"r007", b"r7"), Ordering::Less);
+        assert_eq!(natural_cmp(b"a1b", b"a1b"), Ordering::Equal);
+        assert_eq!(
+            natural_cmp(b"x99999999999999999999999", b"x100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_sort_by_id() {
+        let recs = [line("chr10", "A"), line("chr2", "AC"), line("chr1", "ACG")];
+        let key = SortKey::default();
+        assert_eq!(
+            sorted(&recs, key, usize::MAX),
+            ["chr1\tACG", "chr10\tA", "chr2\tAC"]
+        );
+        let key = SortKey {
+            natural: true,
+            ..key
+        };
+        assert_eq!(
+            sorted(&recs, key, usize::MAX),
+            ["chr1\tACG", "chr2\tAC", "chr10\tA"]
+        );
+    }
+
+    #[test]
+    fn test_stable() {
+        let recs = [
+            line("a", "AA"),
+            line("b", "C"),
+            line("c", "GG"),
+            line("d", "T"),
+            line("e", "AA"),
+        ];
+        let key = SortKey {
+            by: SortBy::Length,
+            ..SortKey::default()
+        };
+        let expected = ["b\tC", "d\tT", "a\tAA", "c\tGG", "e\tAA"];
+        assert_eq!(sorted(&recs, key, usize::MAX), expected);
+        // a run per record
+        assert_eq!(sorted(&recs, key, 0), expected);
+
+        let key = SortKey {
+            reverse: true,
+            ..key
+        };
+        let expected = ["a\tAA", "c\tGG", "e\tAA", "b\tC", "d\tT"];
+        assert_eq!(sorted(&recs, key, usize::MAX), expected);
+        assert_eq!(sorted(&recs, key, 0), expected);
+    }
+
+    #[test]
+    fn test_external_matches_in_memory() {
+        // scrambled ids with repeated lengths, so ties cross run boundaries
+        let recs = (0..500)
+            .map(|i| {
+                let n = (i * 7919) % 500;
+                line(&format!("read{n}"), &"A".repeat(n % 7 + 1))
+            })
+            .collect::<Vec<Line>>();
+        for key in [
+            SortKey::default(),
+            SortKey {
+                natural: true,
+                ..SortKey::default()
+            },
+            SortKey {
+                by: SortBy::Length,
+                reverse: true,
+                ..SortKey::default()
+            },
+        ] {
+            let in_memory = sorted(&recs, key, usize::MAX);
+            // roughly 20 records per run
+            assert_eq!(sorted(&recs, key, 20 * (RECORD_OVERHEAD + 10)), in_memory);
+            assert_eq!(in_memory.len(), recs.len());
+        }
+    }
+
+    #[test]
+    fn test_merge_passes() {
+        let recs = (0..200)
+            .map(|i| line(&format!("r{}", (i * 37) % 200), &"A".repeat(i % 5 + 1)))
+            .collect::<Vec<Line>>();
+        let key = SortKey {
+            by: SortBy::Length,
+            ..SortKey::default()
+        };
+        let in_memory = sorted(&recs, key, usize::MAX);
+        // a run per record, merged 3 at a time over five passes
+        let mut out = Vec::new();
+        sort_records_with(recs.iter().cloned().map(Ok), &mut out, key, 0, 3).unwrap();
+        let merged = String::from_utf8(out).unwrap();
+        assert_eq!(merged.lines().collect::<Vec<_>>(), in_memory);
+    }
+
+    #[test]
+    fn test_sort_error() {
+        let recs = vec![
+            Ok(line("b", "A")),
+            Err(io::Error::other("bad record")),
+            Ok(line("a", "A")),
+        ];
+        let mut out = Vec::new();
+        let err = sort_records(recs, &mut out, SortKey::default(), 0).unwrap_err();
+        assert_eq!(err.to_string(), "bad record");
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_parse_sort_by() {
+        assert_eq!("length".parse(), Ok(SortBy::Length));
+        assert_eq!(
+            "size".parse::<SortBy>().unwrap_err(),
+            "unknown sort key 'size', expected id or length"
+        );
+    }
+}
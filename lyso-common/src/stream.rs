@@ -0,0 +1,419 @@
+//! Positional operations on record streams
+//!
+//! The readers yield `Result<Record, Error>`; these adapters count only the `Ok`
+//! records and pass errors straight through, so an error is never silently skipped.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::seqedit::{EditSeqs, SeqEdit, SeqEdits};
+
+/// Records `from..from + count` of a stream, see `RecordStreamExt::range`
+pub struct Range<I> {
+    inner: I,
+    skip: usize,
+    remaining: usize,
+}
+
+impl<I, R, E> Iterator for Range<I>
+where
+    I: Iterator<Item = Result<R, E>>,
+{
+    type Item = Result<R, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.skip > 0 {
+            match self.inner.next()? {
+                Ok(_) => self.skip -= 1,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if self.remaining == 0 {
+            // stop without pulling another record from the source
+            return None;
+        }
+        let next = self.inner.next()?;
+        if next.is_ok() {
+            self.remaining -= 1;
+        }
+        Some(next)
+    }
+}
+
+pub trait RecordStreamExt<R, E>: Iterator<Item = Result<R, E>> + Sized {
+    /// The first `n` records
+    ///
+    /// Stops pulling from the source as soon as `n` records have been seen.
+    fn head(self, n: usize) -> Range<Self> {
+        self.range(0, n)
+    }
+
+    /// `count` records, starting at the 0-based record `from`
+    fn range(self, from: usize, count: usize) -> Range<Self> {
+        Range {
+            inner: self,
+            skip: from,
+            remaining: count,
+        }
+    }
+
+    /// The last `n` records, in order
+    ///
+    /// Reads the whole stream, holding at most `n` records in memory. Returns the
+    /// first error encountered.
+    fn tail(self, n: usize) -> Result<VecDeque<R>, E> {
+        let mut ring = VecDeque::with_capacity(n);
+        for rec in self {
+            let rec = rec?;
+            if n == 0 {
+                continue;
+            }
+            if ring.len() == n {
+                ring.pop_front();
+            }
+            ring.push_back(rec);
+        }
+        Ok(ring)
+    }
+
+    /// Each record with `edits` applied to its sequence
+    fn edit_seqs(self, edits: SeqEdits) -> EditSeqs<Self>
+    where
+        R: SeqEdit,
+    {
+        EditSeqs { inner: self, edits }
+    }
+}
+
+impl<I, R, E> RecordStreamExt<R, E> for I where I: Iterator<Item = Result<R, E>> {}
+
+/// A record reader that counts the input bytes behind the records it returns
+pub trait RecordBytes: Iterator {
+    /// Input bytes of the records returned so far
+    fn record_bytes(&self) -> u64;
+
+    /// The next item, unless reading it would take more than about `limit` input bytes
+    ///
+    /// The default reads the next item whatever its size. Readers whose records can
+    /// be arbitrarily long stop at the limit instead, keeping what they read buffered
+    /// so that the next call carries on with the same record.
+    fn next_within(&mut self, limit: u64) -> Within<Self::Item> {
+        let _ = limit;
+        Within::Next(self.next())
+    }
+}
+
+/// Outcome of `RecordBytes::next_within`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Within<T> {
+    /// The next item, or `None` at the end of the stream
+    Next(Option<T>),
+    /// The next record runs past the limit
+    Limit,
+}
+
+/// The bound that stopped a `Bounded` stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+    /// `max_records` records were returned
+    Records,
+    /// `max_bytes` input bytes were consumed, or the next record would pass them
+    Bytes,
+    /// The deadline passed
+    Deadline,
+}
+
+/// Records of a stream up to a count, an amount of input or a deadline
+///
+/// Bounds are checked before each record is pulled, so the source is never read
+/// past the record that reached a bound. The byte bound needs a `RecordBytes`
+/// source: a record crossing it is returned when the reader reads it whole, as
+/// the FASTQ and BAM readers do, while `FastaReader` stops at the bound and keeps
+/// the partial record for its next read. The deadline cannot interrupt a read in
+/// progress. `into_inner` hands back the source to carry on reading.
+///
+/// ```
+/// use lyso_common::stream::{Bound, Bounded};
+///
+/// let mut records = Bounded::new((0..10).map(Ok::<_, ()>)).max_records(3);
+/// assert_eq!(records.by_ref().count(), 3);
+/// assert_eq!(records.stopped_by(), Some(Bound::Records));
+/// assert_eq!(records.into_inner().next(), Some(Ok(3)));
+/// ```
+pub struct Bounded<I: Iterator> {
+    inner: I,
+    max_records: Option<usize>,
+    max_bytes: Option<u64>,
+    deadline: Option<Instant>,
+    /// `RecordBytes` of `inner`, set by `max_bytes`
+    byte_source: Option<ByteSource<I>>,
+    /// `record_bytes` of `inner` when `max_bytes` was set
+    start_bytes: u64,
+    records: usize,
+    stopped_by: Option<Bound>,
+}
+
+type ByteSource<I> = (
+    fn(&I) -> u64,
+    fn(&mut I, u64) -> Within<<I as Iterator>::Item>,
+);
+
+impl<I: Iterator> Bounded<I> {
+    /// Wrap `inner` with no bounds
+    #[must_use]
+    pub fn new(inner: I) -> Self {
+        Bounded {
+            inner,
+            max_records: None,
+            max_bytes: None,
+            deadline: None,
+            byte_source: None,
+            start_bytes: 0,
+            records: 0,
+            stopped_by: None,
+        }
+    }
+
+    /// Stop after `n` records
+    #[must_use]
+    pub fn max_records(mut self, n: usize) -> Self {
+        self.max_records = Some(n);
+        self
+    }
+
+    /// Stop before pulling a record once `at` has passed
+    #[must_use]
+    pub fn deadline(mut self, at: Instant) -> Self {
+        self.deadline = Some(at);
+        self
+    }
+
+    /// Records returned so far, not counting errors
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// Input bytes consumed since `max_bytes` was set, `None` without a byte bound
+    pub fn bytes(&self) -> Option<u64> {
+        let (record_bytes, _) = self.byte_source?;
+        Some(record_bytes(&self.inner) - self.start_bytes)
+    }
+
+    /// The bound that ended the stream, `None` while reading or if the source ran out
+    pub fn stopped_by(&self) -> Option<Bound> {
+        self.stopped_by
+    }
+
+    /// The source, positioned after the last record returned
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+
+    fn stop(&mut self, bound: Bound) -> Option<I::Item> {
+        self.stopped_by = Some(bound);
+        None
+    }
+}
+
+impl<I: RecordBytes> Bounded<I> {
+    /// Stop once `n` input bytes have been consumed
+    #[must_use]
+    pub fn max_bytes(mut self, n: u64) -> Self {
+        self.max_bytes = Some(n);
+        self.byte_source = Some((I::record_bytes, I::next_within));
+        self.start_bytes = self.inner.record_bytes();
+        self
+    }
+}
+
+impl<I, R, E> Iterator for Bounded<I>
+where
+    I: Iterator<Item = Result<R, E>>,
+{
+    type Item = Result<R, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stopped_by.is_some() {
+            return None;
+        }
+        if self.max_records.is_some_and(|n| self.records >= n) {
+            return self.stop(Bound::Records);
+        }
+        if self.deadline.is_some_and(|at| Instant::now() >= at) {
+            return self.stop(Bound::Deadline);
+        }
+        let next = match (self.max_bytes, self.byte_source) {
+            (Some(max), Some((_, next_within))) => {
+                let used = self.bytes().unwrap_or_default();
+                if used >= max {
+                    return self.stop(Bound::Bytes);
+                }
+                match next_within(&mut self.inner, max - used) {
+                    Within::Next(next) => next,
+                    Within::Limit => return self.stop(Bound::Bytes),
+                }
+            }
+            _ => self.inner.next(),
+        };
+        if let Some(Ok(_)) = next {
+            self.records += 1;
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(n: usize) -> impl Iterator<Item = Result<usize, String>> {
+        (0..n).map(Ok)
+    }
+
+    #[test]
+    fn test_matches_naive_scan() {
+        let all = records(50).map(|r| r.unwrap()).collect::<Vec<usize>>();
+        for n in [0, 1, 10, 50, 60] {
+            let head = records(50).head(n).map(|r| r.unwrap()).collect::<Vec<_>>();
+            assert_eq!(head, all[..n.min(50)]);
+            let tail = records(50).tail(n).unwrap();
+            assert_eq!(Vec::from(tail), all[50 - n.min(50)..]);
+        }
+        let range = records(50)
+            .range(10, 5)
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(range, all[10..15]);
+        assert_eq!(records(50).range(48, 5).count(), 2);
+        assert_eq!(records(50).range(60, 5).count(), 0);
+    }
+
+    #[test]
+    fn test_head_stops_reading() {
+        let mut pulled = 0;
+        let source = (0..100).map(|i| {
+            pulled += 1;
+            Ok::<usize, String>(i)
+        });
+        assert_eq!(source.head(3).count(), 3);
+        assert_eq!(pulled, 3);
+    }
+
+    /// Records of `sizes` bytes each; `next_within` refuses one past the limit
+    struct Blocks {
+        sizes: std::vec::IntoIter<u64>,
+        consumed: u64,
+        pulled: usize,
+    }
+
+    impl Blocks {
+        fn new(sizes: &[u64]) -> Self {
+            Blocks {
+                sizes: Vec::from(sizes).into_iter(),
+                consumed: 0,
+                pulled: 0,
+            }
+        }
+    }
+
+    impl Iterator for Blocks {
+        type Item = Result<u64, String>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let size = self.sizes.next()?;
+            self.consumed += size;
+            self.pulled += 1;
+            Some(Ok(size))
+        }
+    }
+
+    impl RecordBytes for Blocks {
+        fn record_bytes(&self) -> u64 {
+            self.consumed
+        }
+
+        fn next_within(&mut self, limit: u64) -> Within<Self::Item> {
+            match self.sizes.as_slice().first() {
+                Some(size) if *size > limit => Within::Limit,
+                _ => Within::Next(self.next()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_bounded_records() {
+        let mut bounded = Bounded::new(records(10)).max_records(4);
+        assert_eq!(bounded.by_ref().map(|r| r.unwrap()).sum::<usize>(), 6);
+        assert_eq!((bounded.records(), bounded.bytes()), (4, None));
+        assert_eq!(bounded.stopped_by(), Some(Bound::Records));
+        assert_eq!(bounded.next(), None);
+        assert_eq!(bounded.into_inner().next(), Some(Ok(4)));
+
+        let mut bounded = Bounded::new(records(3)).max_records(4);
+        assert_eq!(bounded.by_ref().count(), 3);
+        assert_eq!(bounded.stopped_by(), None);
+    }
+
+    #[test]
+    fn test_bounded_bytes() {
+        // stops before a record that would pass the bound
+        let mut bounded = Bounded::new(Blocks::new(&[4, 4, 4, 4])).max_bytes(10);
+        assert_eq!(bounded.by_ref().count(), 2);
+        assert_eq!(bounded.bytes(), Some(8));
+        assert_eq!(bounded.stopped_by(), Some(Bound::Bytes));
+        let mut inner = bounded.into_inner();
+        assert_eq!(inner.pulled, 2);
+        assert_eq!(inner.next(), Some(Ok(4)));
+
+        // and as soon as the bound is reached
+        let mut bounded = Bounded::new(Blocks::new(&[5, 5, 5])).max_bytes(10);
+        assert_eq!(bounded.by_ref().count(), 2);
+        assert_eq!(bounded.stopped_by(), Some(Bound::Bytes));
+        assert_eq!(bounded.into_inner().pulled, 2);
+
+        // counted from where the source was when wrapped
+        let mut source = Blocks::new(&[5, 5, 5]);
+        source.next();
+        let mut bounded = Bounded::new(source).max_bytes(5).max_records(5);
+        assert_eq!(bounded.by_ref().count(), 1);
+        assert_eq!(bounded.stopped_by(), Some(Bound::Bytes));
+    }
+
+    #[test]
+    fn test_bounded_deadline() {
+        let mut bounded = Bounded::new(records(10)).deadline(Instant::now());
+        assert_eq!(bounded.next(), None);
+        assert_eq!(bounded.stopped_by(), Some(Bound::Deadline));
+        assert_eq!(bounded.into_inner().count(), 10);
+
+        let later = Instant::now() + std::time::Duration::from_secs(3600);
+        let mut bounded = Bounded::new(records(10)).deadline(later);
+        assert_eq!(bounded.by_ref().count(), 10);
+        assert_eq!(bounded.stopped_by(), None);
+    }
+
+    #[test]
+    fn test_bounded_errors_not_counted() {
+        let source = vec![Ok(0), Err("bad".to_string()), Ok(1), Ok(2)].into_iter();
+        let bounded = Bounded::new(source).max_records(2);
+        assert_eq!(
+            bounded.collect::<Vec<_>>(),
+            [Ok(0), Err("bad".to_string()), Ok(1)]
+        );
+    }
+
+    #[test]
+    fn test_errors_pass_through() {
+        let source = || {
+            vec![Ok(0), Err("bad".to_string()), Ok(1), Ok(2)]
+                .into_iter()
+                .map(|r: Result<usize, String>| r)
+        };
+        // errors are yielded and not counted
+        let head = source().head(2).collect::<Vec<_>>();
+        assert_eq!(head, [Ok(0), Err("bad".to_string()), Ok(1)]);
+        let range = source().range(1, 1).collect::<Vec<_>>();
+        assert_eq!(range, [Err("bad".to_string()), Ok(1)]);
+        assert_eq!(source().tail(2), Err("bad".to_string()));
+    }
+}
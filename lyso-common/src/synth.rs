@@ -0,0 +1,683 @@
+//! Deterministic synthetic inputs for benches and tests
+//!
+//! Every generator takes a seed and returns the same bytes for the same
+//! arguments, so inputs can be built on the fly instead of committed.
+//!
+//! `FastqSimulator` and `FastaSimulator` give control over read length, quality
+//! and GC content, and sample reads from a generated reference with the source
+//! position in the header, so tests can check their own results. They yield
+//! `SimRecord`s, which convert into the FASTQ and FASTA `Record`s with `From`.
+//!
+//! ```
+//! use lyso_common::synth::{FastaSimulator, FastqSimulator, QualityProfile};
+//!
+//! let reads = FastqSimulator::new(7)
+//!     .read_length(20)
+//!     .quality_profile(QualityProfile::Decreasing { start: 38, end: 20 })
+//!     .gc_content(0.42)
+//!     .unwrap()
+//!     .generate(3)
+//!     .collect::<Vec<_>>();
+//! assert_eq!(reads[2].name, "read2");
+//! assert_eq!(reads[0].qual.as_deref().map(str::len), Some(20));
+//!
+//! let mut sim = FastaSimulator::new(7).seq_length(500).read_length(50);
+//! let reference = sim.generate(2);
+//! let read = &sim.sample_reads(&reference, 1)[0];
+//! // desc is the 1-based, inclusive source region and the number of substitutions
+//! let (region, subs) = read.desc.as_deref().unwrap().split_once(' ').unwrap();
+//! let region = region.parse::<lyso_common::region::Region>().unwrap();
+//! let contig = reference.iter().find(|r| r.name == region.name()).unwrap();
+//! let (start, end) = (region.start().get() as usize, region.end().unwrap().get() as usize);
+//! assert_eq!((read.seq.as_str(), subs), (&contig.seq[start..end], "subs=0"));
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// Bases per line of generated FASTA
+pub const LINE_WIDTH: usize = 60;
+
+const BASES: [u8; 4] = *b"ACGT";
+
+/// splitmix64; small, fast and good enough for test data
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Append `len` values below 4, two bits at a time
+    fn push_2bit(&mut self, len: usize, out: &mut Vec<u8>, map: impl Fn(u8) -> u8) {
+        let mut left = len;
+        while left > 0 {
+            let mut bits = self.next_u64();
+            for _ in 0..left.min(32) {
+                out.push(map((bits & 3) as u8));
+                bits >>= 2;
+            }
+            left -= left.min(32);
+        }
+    }
+
+    /// Phred score in 2..=40
+    fn qual(&mut self) -> u8 {
+        2 + (self.next_u64() % 39) as u8
+    }
+
+    /// Uniform in [0, 1), from the top 53 bits
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Below `n`, which must not be 0
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// `len` bases, each G or C with probability `gc`
+    fn gc_seq(&mut self, len: usize, gc: f64) -> String {
+        (0..len)
+            .map(|_| {
+                let bits = self.next_u64();
+                let strong = (bits >> 11) as f64 / ((1u64 << 53) as f64) < gc;
+                char::from(match (strong, bits & 1) {
+                    (true, 0) => b'G',
+                    (true, _) => b'C',
+                    (false, 0) => b'A',
+                    (false, _) => b'T',
+                })
+            })
+            .collect()
+    }
+}
+
+/// `n` FASTQ records of `len` random bases, named `read0`, `read1`, ...
+pub fn generate_fastq(n: usize, len: usize, seed: u64) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    let mut out = Vec::with_capacity(n * (2 * len + 32));
+    for i in 0..n {
+        out.extend_from_slice(format!("@read{i} 1:N:0:ACGT\n").as_bytes());
+        rng.push_2bit(len, &mut out, |b| BASES[b as usize]);
+        out.extend_from_slice(b"\n+\n");
+        for _ in 0..len {
+            out.push(rng.qual() + 33);
+        }
+        out.push(b'\n');
+    }
+    out
+}
+
+/// `n` FASTA records of `len` random bases wrapped at `LINE_WIDTH`,
+/// named `seq0`, `seq1`, ...
+pub fn generate_fasta(n: usize, len: usize, seed: u64) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    let mut out = Vec::with_capacity(n * (len + len / LINE_WIDTH + 16));
+    for i in 0..n {
+        out.extend_from_slice(format!(">seq{i}\n").as_bytes());
+        let mut left = len;
+        while left > 0 {
+            let line = left.min(LINE_WIDTH);
+            rng.push_2bit(line, &mut out, |b| BASES[b as usize]);
+            out.push(b'\n');
+            left -= line;
+        }
+    }
+    out
+}
+
+/// An uncompressed BAM stream of `n` reads of `len` bases
+///
+/// Reads are mapped to the single reference `chr1` at 100bp steps, with a
+/// `{len}M` CIGAR and NM, AS, MD and RG aux fields. They are flagged paired
+/// without mate information, and every tenth is flagged as a duplicate. The
+/// bytes are what a BGZF reader would yield, so they can be fed to a BAM
+/// reader directly.
+pub fn generate_bam(n: usize, len: usize, seed: u64) -> Vec<u8> {
+    const PAIRED: u16 = 0x1;
+    const DUPLICATE: u16 = 0x400;
+
+    let mut rng = Rng::new(seed);
+    let text = b"@HD\tVN:1.6\tSO:coordinate\n@RG\tID:sample1\n";
+    let mut out = b"BAM\x01".to_vec();
+    out.extend((text.len() as u32).to_le_bytes());
+    out.extend(text);
+    out.extend(1u32.to_le_bytes());
+    out.extend(5u32.to_le_bytes());
+    out.extend(b"chr1\0");
+    out.extend(((n as u32).saturating_mul(100) + len as u32).to_le_bytes());
+
+    let mut seq = Vec::with_capacity(len + 1);
+    for i in 0..n {
+        let name = format!("read{i}\0");
+        seq.clear();
+        // 4-bit codes of A, C, G and T
+        rng.push_2bit(len, &mut seq, |b| 1 << b);
+        seq.push(0);
+
+        let mut var = name.as_bytes().to_vec();
+        var.extend(((len as u32) << 4).to_le_bytes());
+        var.extend(seq.chunks_exact(2).map(|p| p[0] << 4 | p[1]));
+        var.extend((0..len).map(|_| rng.qual()));
+        var.extend(b"NMC\x01");
+        var.extend(b"ASC\x8c");
+        var.extend(format!("MDZ{len}\0").as_bytes());
+        var.extend(b"RGZsample1\0");
+
+        let flag = PAIRED | if i % 10 == 0 { DUPLICATE } else { 0 };
+        out.extend((32 + var.len() as u32).to_le_bytes());
+        out.extend(0i32.to_le_bytes());
+        out.extend((i as i32).wrapping_mul(100).to_le_bytes());
+        out.push(name.len() as u8);
+        out.push(60);
+        out.extend(4681u16.to_le_bytes());
+        out.extend(1u16.to_le_bytes());
+        out.extend(flag.to_le_bytes());
+        out.extend((len as u32).to_le_bytes());
+        out.extend((-1i32).to_le_bytes());
+        out.extend((-1i32).to_le_bytes());
+        out.extend(0i32.to_le_bytes());
+        out.extend(var);
+    }
+    out
+}
+
+/// A generated read or sequence, before it becomes a format's `Record`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimRecord {
+    /// Id, without description
+    pub name: String,
+    /// Source of a sampled read, None for random sequences
+    pub desc: Option<String>,
+    /// Bases, uppercase ACGT or N
+    pub seq: String,
+    /// Phred+33 qualities, None for FASTA
+    pub qual: Option<String>,
+}
+
+/// Phred scores along a simulated read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityProfile {
+    /// The same score at every base
+    Uniform(u8),
+    /// A straight line from `start` at the first base to `end` at the last
+    Decreasing {
+        /// Score of the first base
+        start: u8,
+        /// Score of the last base
+        end: u8,
+    },
+}
+
+impl QualityProfile {
+    /// Score of base `i` of a `len` base read, rounded and capped at 93 so
+    /// it stays printable
+    pub fn phred(&self, i: usize, len: usize) -> u8 {
+        let q = match *self {
+            QualityProfile::Uniform(q) => q,
+            QualityProfile::Decreasing { start, end } if len > 1 => {
+                let step = (f64::from(end) - f64::from(start)) / (len - 1) as f64;
+                (f64::from(start) + step * i as f64).round() as u8
+            }
+            QualityProfile::Decreasing { start, .. } => start,
+        };
+        q.min(93)
+    }
+
+    /// Phred+33 qualities of a `len` base read
+    pub fn qualities(&self, len: usize) -> String {
+        (0..len)
+            .map(|i| char::from(self.phred(i, len) + 33))
+            .collect()
+    }
+}
+
+impl FromStr for QualityProfile {
+    type Err = String;
+
+    /// `30` for a uniform profile, `38:20` for one falling from 38 to 20
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let score = |q: &str| q.parse::<u8>().ok().filter(|q| *q <= 93);
+        let profile = match s.split_once(':') {
+            None => score(s).map(QualityProfile::Uniform),
+            Some((start, end)) => score(start)
+                .zip(score(end))
+                .map(|(start, end)| QualityProfile::Decreasing { start, end }),
+        };
+        profile.ok_or_else(|| {
+            format!("invalid quality profile '{s}', expected a score such as 30 or START:END such as 38:20, each at most 93")
+        })
+    }
+}
+
+impl Display for QualityProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QualityProfile::Uniform(q) => write!(f, "{q}"),
+            QualityProfile::Decreasing { start, end } => write!(f, "{start}:{end}"),
+        }
+    }
+}
+
+/// A simulator setting out of range
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum SimError {
+    /// A fraction, named by `what`, outside 0..=1
+    NotFraction { what: &'static str, value: f64 },
+}
+
+impl Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::NotFraction { what, value } => {
+                write!(f, "{what} must be between 0 and 1, not {value}")
+            }
+        }
+    }
+}
+
+impl Error for SimError {}
+
+fn check_fraction(what: &'static str, value: f64) -> Result<f64, SimError> {
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(SimError::NotFraction { what, value })
+    }
+}
+
+/// Random FASTQ reads of one length, quality profile and GC content
+#[derive(Debug, Clone)]
+pub struct FastqSimulator {
+    rng: Rng,
+    read_length: usize,
+    quality: QualityProfile,
+    gc: f64,
+}
+
+impl FastqSimulator {
+    /// 150 base reads of 50% GC, each base scored 30
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        FastqSimulator {
+            rng: Rng::new(seed),
+            read_length: 150,
+            quality: QualityProfile::Uniform(30),
+            gc: 0.5,
+        }
+    }
+
+    /// Bases per read
+    #[must_use]
+    pub fn read_length(mut self, len: usize) -> Self {
+        self.read_length = len;
+        self
+    }
+
+    /// Scores along each read; every read gets the same qualities
+    #[must_use]
+    pub fn quality_profile(mut self, profile: QualityProfile) -> Self {
+        self.quality = profile;
+        self
+    }
+
+    /// Expected fraction of G and C; fails outside 0..=1
+    pub fn gc_content(mut self, gc: f64) -> Result<Self, SimError> {
+        self.gc = check_fraction("GC content", gc)?;
+        Ok(self)
+    }
+
+    /// `n` reads named `read0`, `read1`, ...
+    pub fn generate(mut self, n: usize) -> impl Iterator<Item = SimRecord> {
+        let len = self.read_length;
+        let qual = self.quality.qualities(len);
+        (0..n).map(move |i| SimRecord {
+            name: format!("read{i}"),
+            desc: None,
+            seq: self.rng.gc_seq(len, self.gc),
+            qual: Some(qual.clone()),
+        })
+    }
+}
+
+/// Random FASTA sequences, and reads sampled from them with optional errors
+#[derive(Debug, Clone)]
+pub struct FastaSimulator {
+    rng: Rng,
+    seq_length: usize,
+    gc: f64,
+    read_length: usize,
+    substitution_rate: f64,
+}
+
+impl FastaSimulator {
+    /// 10kb sequences of 50% GC, sampled into error-free 150 base reads
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        FastaSimulator {
+            rng: Rng::new(seed),
+            seq_length: 10_000,
+            gc: 0.5,
+            read_length: 150,
+            substitution_rate: 0.0,
+        }
+    }
+
+    /// Bases per generated sequence
+    #[must_use]
+    pub fn seq_length(mut self, len: usize) -> Self {
+        self.seq_length = len;
+        self
+    }
+
+    /// Expected fraction of G and C; fails outside 0..=1
+    pub fn gc_content(mut self, gc: f64) -> Result<Self, SimError> {
+        self.gc = check_fraction("GC content", gc)?;
+        Ok(self)
+    }
+
+    /// Bases per sampled read
+    #[must_use]
+    pub fn read_length(mut self, len: usize) -> Self {
+        self.read_length = len;
+        self
+    }
+
+    /// Chance that each base of a sampled read is swapped for another; fails
+    /// outside 0..=1
+    pub fn substitution_rate(mut self, rate: f64) -> Result<Self, SimError> {
+        self.substitution_rate = check_fraction("substitution rate", rate)?;
+        Ok(self)
+    }
+
+    /// `n` sequences named `seq0`, `seq1`, ..., to use as a reference
+    pub fn generate(&mut self, n: usize) -> Vec<SimRecord> {
+        (0..n)
+            .map(|i| SimRecord {
+                name: format!("seq{i}"),
+                desc: None,
+                seq: self.rng.gc_seq(self.seq_length, self.gc),
+                qual: None,
+            })
+            .collect()
+    }
+
+    /// `n` forward-strand reads from uniformly chosen positions of `reference`
+    ///
+    /// Reads are named `read0`, `read1`, ..., with the description
+    /// `{seq}:{start}-{end} subs={k}`: the 1-based, inclusive region they were
+    /// taken from and how many of their bases were substituted. Sequences
+    /// shorter than a read are never sampled; with none long enough there are
+    /// no reads.
+    pub fn sample_reads(&mut self, reference: &[SimRecord], n: usize) -> Vec<SimRecord> {
+        let len = self.read_length;
+        let starts = |r: &SimRecord| (r.seq.len() + 1).saturating_sub(len) as u64;
+        let total = reference.iter().map(starts).sum::<u64>();
+        if total == 0 {
+            return Vec::new();
+        }
+        (0..n)
+            .map(|i| {
+                let mut at = self.rng.below(total);
+                let source = reference
+                    .iter()
+                    .find(|r| {
+                        let fits = at < starts(r);
+                        if !fits {
+                            at -= starts(r);
+                        }
+                        fits
+                    })
+                    .expect("a position below the total is in some sequence");
+                let start = at as usize;
+                // bases other than ACGT, even non-ASCII ones, are sampled as N
+                let mut seq = source.seq.as_bytes()[start..start + len]
+                    .iter()
+                    .map(|b| if BASES.contains(b) { *b } else { b'N' })
+                    .collect::<Vec<u8>>();
+                let mut subs = 0;
+                for base in &mut seq {
+                    if self.rng.unit() < self.substitution_rate {
+                        let from = BASES.iter().position(|b| b == base).unwrap_or(0);
+                        *base = BASES[(from + 1 + self.rng.below(3) as usize) % 4];
+                        subs += 1;
+                    }
+                }
+                SimRecord {
+                    name: format!("read{i}"),
+                    desc: Some(format!(
+                        "{}:{}-{} subs={subs}",
+                        source.name,
+                        start + 1,
+                        start + len
+                    )),
+                    seq: seq.into_iter().map(char::from).collect(),
+                    qual: None,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deterministic() {
+        assert_eq!(generate_fastq(10, 50, 1), generate_fastq(10, 50, 1));
+        assert_ne!(generate_fastq(10, 50, 1), generate_fastq(10, 50, 2));
+        assert_eq!(generate_fasta(3, 130, 7), generate_fasta(3, 130, 7));
+        assert_eq!(generate_bam(5, 20, 7), generate_bam(5, 20, 7));
+    }
+
+    #[test]
+    fn test_fastq_shape() {
+        let fq = generate_fastq(4, 37, 0);
+        let lines = fq.split(|b| *b == b'\n').collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4 * 4 + 1);
+        assert_eq!(lines[0], b"@read0 1:N:0:ACGT");
+        assert_eq!(lines[12], b"@read3 1:N:0:ACGT");
+        for rec in lines.chunks_exact(4) {
+            assert_eq!(rec[1].len(), 37);
+            assert!(rec[1].iter().all(|b| BASES.contains(b)));
+            assert_eq!(rec[2], b"+");
+            assert!(rec[3].iter().all(|q| (b'#'..=b'I').contains(q)));
+        }
+    }
+
+    #[test]
+    fn test_fasta_wrapping() {
+        let fa = String::from_utf8(generate_fasta(2, 130, 0)).unwrap();
+        let widths = fa.lines().map(str::len).collect::<Vec<_>>();
+        assert_eq!(widths, [5, 60, 60, 10, 5, 60, 60, 10]);
+        assert!(fa.starts_with(">seq0\n"));
+        assert!(generate_fasta(1, 0, 0) == b">seq0\n");
+    }
+
+    #[test]
+    fn test_bam_blocks() {
+        let bam = generate_bam(3, 11, 0);
+        assert_eq!(&bam[..4], b"BAM\x01");
+        let l_text = u32::from_le_bytes(bam[4..8].try_into().unwrap()) as usize;
+        // text, n_ref, then one reference: l_name, "chr1\0", l_ref
+        let mut pos = 8 + l_text + 4 + 4 + 5 + 4;
+        let mut names = Vec::new();
+        while pos < bam.len() {
+            let block_size = u32::from_le_bytes(bam[pos..pos + 4].try_into().unwrap()) as usize;
+            let l_name = bam[pos + 12] as usize;
+            names.push(&bam[pos + 36..pos + 36 + l_name]);
+            pos += 4 + block_size;
+        }
+        assert_eq!(pos, bam.len());
+        assert_eq!(names, [&b"read0\0"[..], b"read1\0", b"read2\0"]);
+    }
+
+    /// FNV-1a of every record, so a change to any generated byte shows
+    fn digest(records: &[SimRecord]) -> u64 {
+        let text = records
+            .iter()
+            .map(|r| format!("{} {:?} {} {:?}\n", r.name, r.desc, r.seq, r.qual))
+            .collect::<String>();
+        crate::rename::fnv1a(text.as_bytes())
+    }
+
+    #[test]
+    fn test_simulator_output_is_pinned() {
+        let reads = FastqSimulator::new(42)
+            .read_length(100)
+            .quality_profile(QualityProfile::Decreasing { start: 38, end: 12 })
+            .gc_content(0.42)
+            .unwrap()
+            .generate(50)
+            .collect::<Vec<_>>();
+        assert_eq!(digest(&reads), 0xa70a_f68a_dd3b_bd67);
+
+        let mut sim = FastaSimulator::new(42)
+            .seq_length(1000)
+            .read_length(80)
+            .substitution_rate(0.01)
+            .unwrap();
+        let reference = sim.generate(3);
+        assert_eq!(digest(&reference), 0x164f_f7a6_d617_cc7f);
+        assert_eq!(
+            digest(&sim.sample_reads(&reference, 50)),
+            0x4ed2_76ae_d20e_8974
+        );
+    }
+
+    #[test]
+    fn test_gc_content_is_approached() {
+        for gc in [0.0, 0.2, 0.42, 0.65, 1.0] {
+            let bases = FastqSimulator::new(3)
+                .gc_content(gc)
+                .unwrap()
+                .generate(200)
+                .flat_map(|r| r.seq.into_bytes())
+                .collect::<Vec<_>>();
+            assert_eq!(bases.len(), 200 * 150);
+            let strong = bases.iter().filter(|b| matches!(b, b'G' | b'C')).count();
+            let frac = strong as f64 / bases.len() as f64;
+            assert!((frac - gc).abs() < 0.01, "{gc}: {frac}");
+            assert!(bases.iter().all(|b| BASES.contains(b)));
+        }
+        let reference = FastaSimulator::new(3).gc_content(0.3).unwrap().generate(1);
+        let strong = reference[0]
+            .seq
+            .bytes()
+            .filter(|b| matches!(b, b'G' | b'C'));
+        assert!((strong.count() as f64 / 10_000.0 - 0.3).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_quality_profile() {
+        let falling = QualityProfile::Decreasing { start: 38, end: 20 };
+        let scores = (0..10).map(|i| falling.phred(i, 10)).collect::<Vec<_>>();
+        assert_eq!(scores, [38, 36, 34, 32, 30, 28, 26, 24, 22, 20]);
+        assert_eq!(falling.phred(0, 1), 38);
+        assert_eq!(QualityProfile::Uniform(120).phred(3, 10), 93);
+
+        let read = FastqSimulator::new(0)
+            .read_length(10)
+            .quality_profile(falling)
+            .generate(1)
+            .next()
+            .unwrap();
+        assert_eq!(read.qual.as_deref(), Some("GECA?=;975"));
+
+        for (s, profile) in [("30", QualityProfile::Uniform(30)), ("38:20", falling)] {
+            assert_eq!(s.parse::<QualityProfile>().unwrap(), profile);
+            assert_eq!(profile.to_string(), s);
+        }
+        for bad in ["", "x", "94", "38:", "38:20:1"] {
+            assert!(bad.parse::<QualityProfile>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_sampled_reads_come_from_their_region() {
+        let mut sim = FastaSimulator::new(9)
+            .seq_length(300)
+            .read_length(40)
+            .substitution_rate(0.05)
+            .unwrap();
+        let mut reference = sim.generate(4);
+        // too short to hold a read, so never sampled
+        reference[1].seq.truncate(39);
+        let reads = sim.sample_reads(&reference, 500);
+        let mut total_subs = 0;
+        for read in &reads {
+            let (region, subs) = read.desc.as_deref().unwrap().split_once(" subs=").unwrap();
+            let region = region.parse::<crate::region::Region>().unwrap();
+            let source = reference.iter().find(|r| r.name == region.name()).unwrap();
+            assert_ne!(source.name, "seq1");
+            let (start, end) = (
+                region.start().get() as usize,
+                region.end().unwrap().get() as usize,
+            );
+            let differ = source.seq[start..end]
+                .bytes()
+                .zip(read.seq.bytes())
+                .filter(|(a, b)| a != b)
+                .count();
+            assert_eq!(differ.to_string(), subs, "{}", read.name);
+            assert_eq!(read.seq.len(), 40);
+            total_subs += differ;
+        }
+        let rate = total_subs as f64 / (500.0 * 40.0);
+        assert!((rate - 0.05).abs() < 0.01, "{rate}");
+
+        let none = FastaSimulator::new(0)
+            .read_length(301)
+            .sample_reads(&reference, 5);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_sampled_reads_mask_other_bases() {
+        let reference = [SimRecord {
+            name: "chr1".into(),
+            desc: None,
+            seq: "ACGTé".into(),
+            qual: None,
+        }];
+        let reads = FastaSimulator::new(3)
+            .read_length(6)
+            .sample_reads(&reference, 20);
+        assert_eq!(reads.len(), 20);
+        for read in &reads {
+            assert_eq!(read.seq, "ACGTNN");
+        }
+    }
+
+    #[test]
+    fn test_bad_fractions() {
+        let err = FastqSimulator::new(0).gc_content(1.5).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "GC content must be between 0 and 1, not 1.5"
+        );
+        assert!(FastaSimulator::new(0).gc_content(-0.1).is_err());
+        assert!(FastaSimulator::new(0).gc_content(f64::NAN).is_err());
+        let err = FastaSimulator::new(0).substitution_rate(2.0).unwrap_err();
+        assert_eq!(
+            err,
+            SimError::NotFraction {
+                what: "substitution rate",
+                value: 2.0
+            }
+        );
+    }
+}
@@ -0,0 +1,436 @@
+//! Translation of nucleotide sequences to protein
+//!
+//! Codon tables follow NCBI's layout: 64 amino acids listed in TCAG order, so codon
+//! `TTT` is entry 0, `TTC` entry 1 and `GGG` entry 63. Ambiguous IUPAC codes in a
+//! codon are expanded; the codon translates to the amino acid all its expansions
+//! share, or to `X` when they differ.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::search::reverse_complement;
+
+/// Amino acid for codons that cannot be translated unambiguously
+pub const UNKNOWN_AA: u8 = b'X';
+
+/// (NCBI id, amino acids, starts) for the built-in tables
+const NCBI_TABLES: &[(u8, &str, &str)] = &[
+    (
+        1,
+        "FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+        "---M------**--*----M---------------M----------------------------",
+    ),
+    (
+        2,
+        "FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSS**VVVVAAAADDEEGGGG",
+        "----------**--------------------MMMM----------**---M------------",
+    ),
+    (
+        3,
+        "FFLLSSSSYY**CCWWTTTTPPPPHHQQRRRRIIMMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+        "----------**----------------------MM---------------M------------",
+    ),
+    (
+        4,
+        "FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+        "--MM------**-------M------------MMMM---------------M------------",
+    ),
+    (
+        5,
+        "FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSSSSVVVVAAAADDEEGGGG",
+        "---M------**--------------------MMMM---------------M------------",
+    ),
+    (
+        6,
+        "FFLLSSSSYYQQCC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+        "-----------------------------------M----------------------------",
+    ),
+    (
+        11,
+        "FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+        "---M------**--*----M------------MMMM---------------M------------",
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TranslateError {
+    UnknownTable(u8),
+    /// A table id that is not a number
+    InvalidTableId(String),
+    /// A custom table that is not 64 entries long
+    TableLength(usize),
+    InvalidFrame(String),
+}
+
+impl Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranslateError::UnknownTable(id) => {
+                let ids = NCBI_TABLES
+                    .iter()
+                    .map(|t| t.0.to_string())
+                    .collect::<Vec<String>>();
+                write!(
+                    f,
+                    "unknown genetic code {id}, expected one of {}",
+                    ids.join(", ")
+                )
+            }
+            TranslateError::InvalidTableId(s) => {
+                write!(
+                    f,
+                    "invalid genetic code '{s}', expected an NCBI table number"
+                )
+            }
+            TranslateError::TableLength(n) => {
+                write!(f, "codon table has {n} entries, expected 64")
+            }
+            TranslateError::InvalidFrame(s) => {
+                write!(f, "invalid frame '{s}', expected 1, 2, 3, -1, -2 or -3")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TranslateError {}
+
+/// A codon table: an amino acid and whether it can start translation, per codon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneticCode {
+    amino_acids: [u8; 64],
+    starts: [bool; 64],
+}
+
+impl Default for GeneticCode {
+    fn default() -> Self {
+        GeneticCode::standard()
+    }
+}
+
+impl GeneticCode {
+    /// NCBI table 1
+    pub fn standard() -> Self {
+        GeneticCode::from_ncbi_id(1).expect("table 1 is built in")
+    }
+
+    /// One of the built-in NCBI tables: 1-6 or 11
+    pub fn from_ncbi_id(id: u8) -> Result<Self, TranslateError> {
+        let (_, amino_acids, starts) = NCBI_TABLES
+            .iter()
+            .find(|t| t.0 == id)
+            .ok_or(TranslateError::UnknownTable(id))?;
+        GeneticCode::custom(amino_acids.as_bytes(), starts.as_bytes())
+    }
+
+    /// A table given as NCBI-style rows of 64 entries in TCAG order
+    ///
+    /// `starts` marks start codons with `M`; any other byte means not a start.
+    pub fn custom(amino_acids: &[u8], starts: &[u8]) -> Result<Self, TranslateError> {
+        let amino_acids: [u8; 64] = amino_acids
+            .try_into()
+            .map_err(|_| TranslateError::TableLength(amino_acids.len()))?;
+        let starts: [u8; 64] = starts
+            .try_into()
+            .map_err(|_| TranslateError::TableLength(starts.len()))?;
+        Ok(GeneticCode {
+            amino_acids: amino_acids.map(|a| a.to_ascii_uppercase()),
+            starts: starts.map(|s| s == b'M'),
+        })
+    }
+
+    /// Amino acid for a codon, `X` when it is ambiguous or not a codon
+    pub fn translate_codon(&self, codon: &[u8]) -> u8 {
+        let mut aa = None;
+        for i in codon_indices(codon) {
+            match (aa, self.amino_acids[i]) {
+                (None, a) => aa = Some(a),
+                (Some(prev), a) if prev != a => return UNKNOWN_AA,
+                _ => {}
+            }
+        }
+        aa.unwrap_or(UNKNOWN_AA)
+    }
+
+    /// Whether every reading of `codon` is a start codon
+    pub fn is_start(&self, codon: &[u8]) -> bool {
+        let mut indices = codon_indices(codon).peekable();
+        indices.peek().is_some() && indices.all(|i| self.starts[i])
+    }
+}
+
+/// Parses an NCBI table id
+impl FromStr for GeneticCode {
+    type Err = TranslateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = s
+            .parse()
+            .map_err(|_| TranslateError::InvalidTableId(s.to_string()))?;
+        GeneticCode::from_ncbi_id(id)
+    }
+}
+
+/// Bases of a nucleotide code in TCAG order
+fn tcag(b: u8) -> &'static [usize] {
+    match b.to_ascii_uppercase() {
+        b'T' | b'U' => &[0],
+        b'C' => &[1],
+        b'A' => &[2],
+        b'G' => &[3],
+        b'Y' => &[0, 1],
+        b'W' => &[0, 2],
+        b'K' => &[0, 3],
+        b'M' => &[1, 2],
+        b'S' => &[1, 3],
+        b'R' => &[2, 3],
+        b'H' => &[0, 1, 2],
+        b'B' => &[0, 1, 3],
+        b'D' => &[0, 2, 3],
+        b'V' => &[1, 2, 3],
+        b'N' => &[0, 1, 2, 3],
+        _ => &[],
+    }
+}
+
+/// Table indices of every codon a possibly ambiguous codon may stand for
+///
+/// Empty if `codon` is not three nucleotide codes.
+fn codon_indices(codon: &[u8]) -> impl Iterator<Item = usize> {
+    let [a, b, c] = match codon {
+        [a, b, c] => [tcag(*a), tcag(*b), tcag(*c)],
+        _ => [&[][..]; 3],
+    };
+    a.iter().flat_map(move |x| {
+        b.iter()
+            .flat_map(move |y| c.iter().map(move |z| x * 16 + y * 4 + z))
+    })
+}
+
+/// Reading frame: 1-3 start at the first, second or third base, -1 to -3 do the
+/// same on the reverse complement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Frame {
+    #[default]
+    F1,
+    F2,
+    F3,
+    R1,
+    R2,
+    R3,
+}
+
+impl Frame {
+    pub const ALL: [Frame; 6] = [
+        Frame::F1,
+        Frame::F2,
+        Frame::F3,
+        Frame::R1,
+        Frame::R2,
+        Frame::R3,
+    ];
+
+    /// Bases skipped before the first codon
+    fn offset(self) -> usize {
+        match self {
+            Frame::F1 | Frame::R1 => 0,
+            Frame::F2 | Frame::R2 => 1,
+            Frame::F3 | Frame::R3 => 2,
+        }
+    }
+
+    pub fn is_reverse(self) -> bool {
+        matches!(self, Frame::R1 | Frame::R2 | Frame::R3)
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.is_reverse() { "-" } else { "" };
+        write!(f, "{sign}{}", self.offset() + 1)
+    }
+}
+
+impl FromStr for Frame {
+    type Err = TranslateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Frame::ALL
+            .into_iter()
+            .find(|frame| frame.to_string() == s.trim_start_matches('+'))
+            .ok_or_else(|| TranslateError::InvalidFrame(s.to_string()))
+    }
+}
+
+/// Translation settings: the codon table and what to do at stops and starts
+#[derive(Debug, Clone, Default)]
+pub struct Translator {
+    code: GeneticCode,
+    to_stop: bool,
+    init_met: bool,
+}
+
+impl Translator {
+    pub fn new(code: GeneticCode) -> Self {
+        Translator {
+            code,
+            to_stop: false,
+            init_met: false,
+        }
+    }
+
+    /// End the protein before the first stop codon instead of translating through it
+    pub fn to_stop(mut self, to_stop: bool) -> Self {
+        self.to_stop = to_stop;
+        self
+    }
+
+    /// Translate a first codon that is a start codon in the table as M
+    pub fn init_met(mut self, init_met: bool) -> Self {
+        self.init_met = init_met;
+        self
+    }
+
+    pub fn code(&self) -> &GeneticCode {
+        &self.code
+    }
+
+    /// Translate `seq` in `frame`; a trailing partial codon is ignored
+    pub fn translate(&self, seq: &[u8], frame: Frame) -> String {
+        let rc;
+        let seq = if frame.is_reverse() {
+            rc = reverse_complement(seq);
+            &rc[..]
+        } else {
+            seq
+        };
+        let seq = seq.get(frame.offset()..).unwrap_or_default();
+        let mut protein = String::with_capacity(seq.len() / 3);
+        for (i, codon) in seq.chunks_exact(3).enumerate() {
+            let aa = match self.code.translate_codon(codon) {
+                b'*' if self.to_stop => break,
+                _ if i == 0 && self.init_met && self.code.is_start(codon) => b'M',
+                aa => aa,
+            };
+            protein.push(char::from(aa));
+        }
+        protein
+    }
+}
+
+/// Translate `seq` in `frame` with the standard code, through any stop codons
+pub fn translate(seq: &[u8], frame: Frame) -> String {
+    Translator::default().translate(seq, frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // human insulin (INS) coding sequence and its preproinsulin product
+    const INS_CDS: &[u8] =
+        b"ATGGCCCTGTGGATGCGCCTCCTGCCCCTGCTGGCGCTGCTGGCCCTCTGGGGACCTGACCCAGCCGCAGCC\
+TTTGTGAACCAACACCTGTGCGGCTCACACCTGGTGGAAGCTCTCTACCTAGTGTGCGGGGAACGAGGCTTCTTC\
+TACACACCCAAGACCCGCCGGGAGGCAGAGGACCTGCAGGTGGGGCAGGTGGAGCTGGGCGGGGGCCCTGGTGCA\
+GGCAGCCTGCAGCCCTTGGCCCTGGAGGGGTCCCTGCAGAAGCGTGGCATTGTGGAACAATGCTGTACCAGCATC\
+TGCTCCCTCTACCAGCTGGAGAACTACTGCAACTAG";
+    const INS_PROTEIN: &str = "MALWMRLLPLLALLALWGPDPAAAFVNQHLCGSHLVEALYLVCGERGFFYTPKTRREAEDLQVGQVELGGGPGAGSLQPLALEGSLQKRGIVEQCCTSICSLYQLENYCN";
+
+    #[test]
+    fn test_known_protein() {
+        assert_eq!(translate(INS_CDS, Frame::F1), format!("{INS_PROTEIN}*"));
+        let to_stop = Translator::default().to_stop(true);
+        assert_eq!(to_stop.translate(INS_CDS, Frame::F1), INS_PROTEIN);
+        // the same gene read from the opposite strand
+        let rc = reverse_complement(INS_CDS);
+        assert_eq!(to_stop.translate(&rc, Frame::R1), INS_PROTEIN);
+        assert_eq!(
+            translate(&INS_CDS.to_ascii_lowercase(), Frame::F1).len(),
+            111
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        // 11 bases: frames 1 and 2 end in a partial codon
+        let seq = b"ATGAAACCCGG";
+        assert_eq!(translate(seq, Frame::F1), "MKP");
+        assert_eq!(translate(seq, Frame::F2), "*NP");
+        assert_eq!(translate(seq, Frame::F3), "ETR");
+        // reverse complement: CCGGGTTTCAT
+        assert_eq!(translate(seq, Frame::R1), "PGF");
+        assert_eq!(translate(seq, Frame::R2), "RVS");
+        assert_eq!(translate(seq, Frame::R3), "GFH");
+        assert_eq!(translate(b"AT", Frame::F3), "");
+        assert_eq!(translate(b"", Frame::R2), "");
+    }
+
+    #[test]
+    fn test_ambiguity() {
+        let code = GeneticCode::standard();
+        // fourfold degenerate third position
+        assert_eq!(code.translate_codon(b"GCN"), b'A');
+        assert_eq!(code.translate_codon(b"CTN"), b'L');
+        // TTR is Leu either way, TTY is Phe, TTN is both
+        assert_eq!(code.translate_codon(b"TTR"), b'L');
+        assert_eq!(code.translate_codon(b"TTY"), b'F');
+        assert_eq!(code.translate_codon(b"TTN"), b'X');
+        assert_eq!(code.translate_codon(b"NNN"), b'X');
+        assert_eq!(code.translate_codon(b"TAR"), b'*');
+        assert_eq!(code.translate_codon(b"A-G"), b'X');
+        assert_eq!(code.translate_codon(b"AUG"), b'M');
+    }
+
+    #[test]
+    fn test_bacterial_code() {
+        let bacterial = GeneticCode::from_ncbi_id(11).unwrap();
+        // GTG is a start codon in table 11 but not in table 1
+        let orf = b"GTGAAATAA";
+        assert!(bacterial.is_start(b"GTG"));
+        assert!(!GeneticCode::standard().is_start(b"GTG"));
+        let bacterial = Translator::new(bacterial).init_met(true);
+        let standard = Translator::default().init_met(true);
+        assert_eq!(bacterial.translate(orf, Frame::F1), "MK*");
+        assert_eq!(standard.translate(orf, Frame::F1), "VK*");
+        // only the first codon is read as a start
+        assert_eq!(bacterial.translate(b"ATGGTG", Frame::F1), "MV");
+    }
+
+    #[test]
+    fn test_other_tables() {
+        let vert_mito = Translator::new(GeneticCode::from_ncbi_id(2).unwrap());
+        assert_eq!(vert_mito.translate(b"TGAAGAATA", Frame::F1), "W*M");
+        assert_eq!(translate(b"TGAAGAATA", Frame::F1), "*RI");
+        assert_eq!(
+            GeneticCode::from_ncbi_id(7).unwrap_err().to_string(),
+            "unknown genetic code 7, expected one of 1, 2, 3, 4, 5, 6, 11"
+        );
+        assert_eq!("11".parse(), GeneticCode::from_ncbi_id(11));
+        assert_eq!(
+            "bacterial".parse::<GeneticCode>(),
+            Err(TranslateError::InvalidTableId(String::from("bacterial")))
+        );
+        let mut all_ala = [b'A'; 64];
+        all_ala[0] = b'f';
+        let custom = GeneticCode::custom(&all_ala, &[b'-'; 64]).unwrap();
+        assert_eq!(custom.translate_codon(b"TTT"), b'F');
+        assert_eq!(custom.translate_codon(b"NNN"), b'X');
+        assert_eq!(custom.translate_codon(b"GNN"), b'A');
+        assert_eq!(
+            GeneticCode::custom(&all_ala[..63], &[b'-'; 64]),
+            Err(TranslateError::TableLength(63))
+        );
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        for frame in Frame::ALL {
+            assert_eq!(frame.to_string().parse(), Ok(frame));
+        }
+        assert_eq!("+2".parse(), Ok(Frame::F2));
+        assert_eq!(
+            "4".parse::<Frame>().unwrap_err().to_string(),
+            "invalid frame '4', expected 1, 2, 3, -1, -2 or -3"
+        );
+    }
+}
@@ -1,35 +1,9 @@
-use std::io::BufRead;
-
 pub static DNA: [char; 5] = ['A', 'T', 'G', 'C', 'N'];
 
 pub fn is_dna(c: char) -> bool {
     matches!(c, 'A' | 'T' | 'G' | 'C' | 'N')
 }
 
-// TODO accept an arbitrary number of validator functions
-pub trait Validate {
-    fn valid(&self) -> Result<bool, &'static str> {
-        let sv = self.seq_valid();
-        let svb = match sv {
-            Ok(val) => val,
-            Err(e) => return Err(e),
-        };
-
-        let qv = self.qual_valid();
-        let qvb = match qv {
-            Ok(val) => val,
-            Err(e) => return Err(e),
-        };
-
-        Ok(svb && qvb)
-    }
-
-    fn seq_valid(&self) -> Result<bool, &'static str>;
-
-    fn qual_valid(&self) -> Result<bool, &'static str>;
-}
-
-
 // --- BEGIN TESTS --- //
 
 #[cfg(test)]
@@ -46,7 +20,7 @@ mod tests {
         }
 
         for c in bad_dna.chars() {
-            assert_ne!(util::is_dna(c), true);
+            assert!(!util::is_dna(c));
         }
     }
 }
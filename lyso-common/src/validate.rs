@@ -0,0 +1,558 @@
+//! Record validation as a list of named rules
+//!
+//! A `ValidationRule` checks one thing about a record. A `Validator` holds rules,
+//! each with a `Severity`, and names the rule, its severity and the record in
+//! every `Violation` it returns. The format crates build their default
+//! validators from the rules here and their own, and the same validator can be
+//! given to a reader, which fails a record on an error, or summarized over a
+//! file with `ValidationReport`, as `lyso check` does.
+//!
+//! ```
+//! use lyso_common::validate::{MaxHomopolymer, Severity, Validator, Violation};
+//! # use lyso_common::filter::SeqRecord;
+//! # use lyso_common::rename::RecordId;
+//! # struct Read(Vec<u8>, Vec<u8>);
+//! # impl SeqRecord for Read {
+//! #     fn seq_bytes(&self) -> &[u8] { &self.1 }
+//! #     fn qual_bytes(&self) -> Option<&[u8]> { None }
+//! # }
+//! # impl RecordId for Read {
+//! #     fn record_id(&self) -> &[u8] { &self.0 }
+//! #     fn set_record_id(&mut self, id: Vec<u8>) { self.0 = id }
+//! # }
+//!
+//! let validator = Validator::new()
+//!     .rule(MaxHomopolymer(4), Severity::Warning)
+//!     .rule_fn("no_gaps", Severity::Error, |r: &Read| {
+//!         let i = r.1.iter().position(|b| *b == b'-')?;
+//!         Some(Violation::new("gap").at(i))
+//!     });
+//! let found = validator.check(&Read(b"r1".to_vec(), b"AC-TTTTT".to_vec()));
+//! assert_eq!(found.len(), 2);
+//! assert_eq!(found[0].to_string(), "run of 5 T at base 4");
+//! assert_eq!((found[1].rule.as_str(), found[1].record.as_str()), ("no_gaps", "r1"));
+//! ```
+
+use std::fmt::{self, Debug, Display};
+use std::io::{self, Write};
+
+use crate::filter::SeqRecord;
+use crate::rename::RecordId;
+
+/// How much a violation matters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Reported, but the record is still read
+    Warning,
+    /// Fails the record
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A record breaking a rule
+///
+/// Rules fill in `message` and `position`; the `Validator` fills in the rest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Name of the rule broken
+    pub rule: String,
+    pub severity: Severity,
+    /// Id of the record
+    pub record: String,
+    /// 0-based position in the sequence, for rules that point at a base
+    pub position: Option<usize>,
+    pub message: String,
+}
+
+impl Violation {
+    /// A violation described by `message`, for a rule to return
+    pub fn new(message: impl Into<String>) -> Self {
+        Violation {
+            rule: String::new(),
+            severity: Severity::Error,
+            record: String::new(),
+            position: None,
+            message: message.into(),
+        }
+    }
+
+    /// Point at 0-based `position` in the sequence
+    #[must_use]
+    pub fn at(mut self, position: usize) -> Self {
+        self.position = Some(position);
+        self
+    }
+}
+
+/// The message, with the 1-based position when there is one
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(p) = self.position {
+            write!(f, " at base {}", p + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// One check of a record
+pub trait ValidationRule<R> {
+    /// Short name, used in reports and errors
+    fn name(&self) -> &str;
+
+    /// A violation when `rec` breaks the rule
+    fn check(&self, rec: &R) -> Option<Violation>;
+}
+
+/// A rule made from a closure, see `Validator::rule_fn`
+struct FnRule<F> {
+    name: String,
+    check: F,
+}
+
+impl<R, F> ValidationRule<R> for FnRule<F>
+where
+    F: Fn(&R) -> Option<Violation>,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self, rec: &R) -> Option<Violation> {
+        (self.check)(rec)
+    }
+}
+
+type BoxedRule<R> = Box<dyn ValidationRule<R> + Send + Sync>;
+
+/// Rules applied together, in the order they were added
+pub struct Validator<R> {
+    rules: Vec<(BoxedRule<R>, Severity)>,
+}
+
+impl<R> Default for Validator<R> {
+    fn default() -> Self {
+        Validator { rules: Vec::new() }
+    }
+}
+
+impl<R> Debug for Validator<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.rules.iter().map(|(r, s)| (r.name(), s)))
+            .finish()
+    }
+}
+
+impl<R: RecordId> Validator<R> {
+    /// A validator with no rules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `rule`, reported at `severity`
+    #[must_use]
+    pub fn rule(
+        mut self,
+        rule: impl ValidationRule<R> + Send + Sync + 'static,
+        severity: Severity,
+    ) -> Self {
+        self.rules.push((Box::new(rule), severity));
+        self
+    }
+
+    /// Add a rule called `name` that fails a record when `check` returns a violation
+    #[must_use]
+    pub fn rule_fn(
+        self,
+        name: impl Into<String>,
+        severity: Severity,
+        check: impl Fn(&R) -> Option<Violation> + Send + Sync + 'static,
+    ) -> Self {
+        let rule = FnRule {
+            name: name.into(),
+            check,
+        };
+        self.rule(rule, severity)
+    }
+
+    /// Names and severities of the rules, in the order they run
+    pub fn rules(&self) -> impl Iterator<Item = (&str, Severity)> {
+        self.rules.iter().map(|(r, s)| (r.name(), *s))
+    }
+
+    /// Every rule `rec` breaks, in rule order
+    pub fn check(&self, rec: &R) -> Vec<Violation> {
+        self.rules
+            .iter()
+            .filter_map(|(rule, severity)| self.run(rule.as_ref(), *severity, rec))
+            .collect()
+    }
+
+    /// The first error-level rule `rec` breaks; warnings are not checked
+    pub fn first_error(&self, rec: &R) -> Option<Violation> {
+        self.rules
+            .iter()
+            .filter(|(_, severity)| *severity == Severity::Error)
+            .find_map(|(rule, severity)| self.run(rule.as_ref(), *severity, rec))
+    }
+
+    fn run(
+        &self,
+        rule: &(dyn ValidationRule<R> + Send + Sync),
+        severity: Severity,
+        rec: &R,
+    ) -> Option<Violation> {
+        let mut v = rule.check(rec)?;
+        v.rule = rule.name().to_string();
+        v.severity = severity;
+        v.record = String::from_utf8_lossy(rec.record_id()).into_owned();
+        Some(v)
+    }
+}
+
+/// Bases outside an alphabet, case-insensitively
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alphabet(pub &'static [u8]);
+
+impl Alphabet {
+    /// IUPAC nucleotide codes, as FASTQ and FASTA sequences use
+    pub const NUCLEOTIDE: Alphabet = Alphabet(b"ACGTUNRYSWKMBDHV");
+}
+
+impl<R: SeqRecord> ValidationRule<R> for Alphabet {
+    fn name(&self) -> &str {
+        "alphabet"
+    }
+
+    fn check(&self, rec: &R) -> Option<Violation> {
+        let seq = rec.seq_bytes();
+        let i = seq
+            .iter()
+            .position(|b| !self.0.contains(&b.to_ascii_uppercase()))?;
+        Some(Violation::new(format!("base {:?} is not allowed", char::from(seq[i]))).at(i))
+    }
+}
+
+/// A quality string of a different length from the sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqQualLength;
+
+impl<R: SeqRecord> ValidationRule<R> for SeqQualLength {
+    fn name(&self) -> &str {
+        "seq_qual_length"
+    }
+
+    fn check(&self, rec: &R) -> Option<Violation> {
+        let (seq, qual) = (rec.seq_bytes().len(), rec.qual_bytes()?.len());
+        (seq != qual).then(|| Violation::new(format!("{seq} bases but {qual} qualities")))
+    }
+}
+
+/// A quality character outside Phred+33 `!` to `~`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualRange;
+
+impl<R: SeqRecord> ValidationRule<R> for QualRange {
+    fn name(&self) -> &str {
+        "qual_range"
+    }
+
+    fn check(&self, rec: &R) -> Option<Violation> {
+        let qual = rec.qual_bytes()?;
+        let i = qual.iter().position(|q| !(b'!'..=b'~').contains(q))?;
+        Some(
+            Violation::new(format!(
+                "quality {:?} is outside Phred+33",
+                char::from(qual[i])
+            ))
+            .at(i),
+        )
+    }
+}
+
+/// An empty id, or one with bytes other than printable ASCII
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdCharset {
+    /// Allow spaces, for ids that are whole header lines, as in FASTA
+    pub allow_space: bool,
+}
+
+impl IdCharset {
+    /// A read name, such as a FASTQ id or BAM QNAME
+    pub const NAME: IdCharset = IdCharset { allow_space: false };
+    /// A header line with a description after the name
+    pub const HEADER: IdCharset = IdCharset { allow_space: true };
+}
+
+impl<R: RecordId> ValidationRule<R> for IdCharset {
+    fn name(&self) -> &str {
+        "id_charset"
+    }
+
+    fn check(&self, rec: &R) -> Option<Violation> {
+        let id = rec.record_id();
+        if id.is_empty() {
+            return Some(Violation::new("empty id"));
+        }
+        let b = id
+            .iter()
+            .find(|b| !(b.is_ascii_graphic() || self.allow_space && **b == b' '))?;
+        Some(Violation::new(format!(
+            "id has byte {b:#04x}, which is not printable ASCII"
+        )))
+    }
+}
+
+/// A run of one base longer than the limit, such as a homopolymer of 30 As
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxHomopolymer(pub usize);
+
+impl<R: SeqRecord> ValidationRule<R> for MaxHomopolymer {
+    fn name(&self) -> &str {
+        "homopolymer"
+    }
+
+    fn check(&self, rec: &R) -> Option<Violation> {
+        let seq = rec.seq_bytes();
+        let mut start = 0;
+        for i in 1..=seq.len() {
+            if i == seq.len() || !seq[i].eq_ignore_ascii_case(&seq[start]) {
+                if i - start > self.0 {
+                    let base = char::from(seq[start].to_ascii_uppercase());
+                    return Some(Violation::new(format!("run of {} {base}", i - start)).at(start));
+                }
+                start = i;
+            }
+        }
+        None
+    }
+}
+
+/// Violations per rule over many records, as printed by `lyso check`
+#[derive(Debug, Clone)]
+pub struct ValidationReport {
+    /// Records added
+    pub records: u64,
+    rules: Vec<(String, Severity)>,
+    counts: Vec<u64>,
+    /// The first violation of each rule, as `record: violation`
+    examples: Vec<Option<String>>,
+}
+
+impl ValidationReport {
+    /// An empty report with a row for each rule of `validator`
+    pub fn new<R: RecordId>(validator: &Validator<R>) -> Self {
+        let rules = validator
+            .rules()
+            .map(|(name, severity)| (name.to_string(), severity))
+            .collect::<Vec<_>>();
+        ValidationReport {
+            records: 0,
+            counts: vec![0; rules.len()],
+            examples: vec![None; rules.len()],
+            rules,
+        }
+    }
+
+    /// Count one record and the violations found in it
+    pub fn add(&mut self, violations: &[Violation]) {
+        self.records += 1;
+        for v in violations {
+            let Some(i) = self.rules.iter().position(|(name, _)| *name == v.rule) else {
+                continue;
+            };
+            self.counts[i] += 1;
+            self.examples[i].get_or_insert_with(|| format!("{}: {v}", v.record));
+        }
+    }
+
+    /// Violations of rules at `severity`
+    pub fn total(&self, severity: Severity) -> u64 {
+        self.rules
+            .iter()
+            .zip(&self.counts)
+            .filter(|((_, s), _)| *s == severity)
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// Write `records`, a `rule count example` row per rule, then the
+    /// warning and error totals, tab-separated
+    pub fn write_tsv(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "records\t{}", self.records)?;
+        for (((name, _), count), example) in self.rules.iter().zip(&self.counts).zip(&self.examples)
+        {
+            match example {
+                Some(example) => writeln!(out, "{name}\t{count}\t{example}")?,
+                None => writeln!(out, "{name}\t0")?,
+            }
+        }
+        writeln!(out, "warnings\t{}", self.total(Severity::Warning))?;
+        writeln!(out, "errors\t{}", self.total(Severity::Error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Read {
+        id: Vec<u8>,
+        seq: Vec<u8>,
+        qual: Option<Vec<u8>>,
+    }
+
+    impl SeqRecord for Read {
+        fn seq_bytes(&self) -> &[u8] {
+            &self.seq
+        }
+
+        fn qual_bytes(&self) -> Option<&[u8]> {
+            self.qual.as_deref()
+        }
+    }
+
+    impl RecordId for Read {
+        fn record_id(&self) -> &[u8] {
+            &self.id
+        }
+
+        fn set_record_id(&mut self, id: Vec<u8>) {
+            self.id = id;
+        }
+    }
+
+    fn read(id: &str, seq: &str, qual: Option<&str>) -> Read {
+        Read {
+            id: id.into(),
+            seq: seq.into(),
+            qual: qual.map(Into::into),
+        }
+    }
+
+    fn validator() -> Validator<Read> {
+        Validator::new()
+            .rule(Alphabet::NUCLEOTIDE, Severity::Error)
+            .rule(SeqQualLength, Severity::Error)
+            .rule(QualRange, Severity::Error)
+            .rule(IdCharset::NAME, Severity::Error)
+            .rule(MaxHomopolymer(3), Severity::Warning)
+    }
+
+    fn broken(rec: &Read) -> Vec<(String, Option<usize>, String)> {
+        validator()
+            .check(rec)
+            .into_iter()
+            .map(|v| (v.rule, v.position, v.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_rules() {
+        assert_eq!(broken(&read("r", "acgTN", Some("IIIII"))), []);
+        assert_eq!(broken(&read("r", "ACGT", None)), []);
+        let v = |rule: &str, pos, msg: &str| (rule.to_string(), pos, msg.to_string());
+        assert_eq!(
+            broken(&read("r", "AC=T", Some("II"))),
+            [
+                v("alphabet", Some(2), "base '=' is not allowed"),
+                v("seq_qual_length", None, "4 bases but 2 qualities")
+            ]
+        );
+        assert_eq!(
+            broken(&read("r 1", "AC", Some("I "))),
+            [
+                v("qual_range", Some(1), "quality ' ' is outside Phred+33"),
+                v(
+                    "id_charset",
+                    None,
+                    "id has byte 0x20, which is not printable ASCII"
+                )
+            ]
+        );
+        assert_eq!(
+            broken(&read("", "ACaaaaG", None)),
+            [
+                v("id_charset", None, "empty id"),
+                v("homopolymer", Some(2), "run of 4 A")
+            ]
+        );
+        // a run at the very end
+        assert_eq!(
+            broken(&read("r", "CGGGG", None)),
+            [v("homopolymer", Some(1), "run of 4 G")]
+        );
+    }
+
+    #[test]
+    fn test_validator() {
+        let validator = validator().rule_fn("no_n", Severity::Warning, |r: &Read| {
+            r.seq.contains(&b'N').then(|| Violation::new("has N"))
+        });
+        assert_eq!(
+            validator.rules().map(|(n, _)| n).collect::<Vec<_>>(),
+            [
+                "alphabet",
+                "seq_qual_length",
+                "qual_range",
+                "id_charset",
+                "homopolymer",
+                "no_n"
+            ]
+        );
+        let rec = read("r1", "NAAAA", None);
+        let found = validator.check(&rec);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].rule, "no_n");
+        assert_eq!(
+            (found[1].severity, found[1].record.as_str()),
+            (Severity::Warning, "r1")
+        );
+        // only errors stop a record
+        assert_eq!(validator.first_error(&rec), None);
+        let err = validator.first_error(&read("r2", "AXA", None)).unwrap();
+        assert_eq!(
+            (err.rule.as_str(), err.to_string()),
+            ("alphabet", "base 'X' is not allowed at base 2".into())
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let validator = validator();
+        let mut report = ValidationReport::new(&validator);
+        for rec in [
+            read("a", "ACGT", Some("IIII")),
+            read("b", "AAAAC", None),
+            read("c", "TTTTT", Some("II")),
+        ] {
+            report.add(&validator.check(&rec));
+        }
+        assert_eq!(
+            (
+                report.total(Severity::Warning),
+                report.total(Severity::Error)
+            ),
+            (2, 1)
+        );
+        let mut out = Vec::new();
+        report.write_tsv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "records\t3\nalphabet\t0\nseq_qual_length\t1\tc: 5 bases but 2 qualities\n\
+             qual_range\t0\nid_charset\t0\nhomopolymer\t2\tb: run of 4 A at base 1\n\
+             warnings\t2\nerrors\t1\n"
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! Index a FASTA file in memory and print the sequence of a region
+//!
+//! Usage: fasta_index_fetch <ref.fa> <region>
+//!
+//! The region is samtools-style, e.g. `chr1`, `chr1:100` or `chr1:100-200`.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{stdout, BufReader};
+use std::process::exit;
+
+use lyso_common::region::Region;
+use lyso_fasta::indexer::{FastaIndex, IndexedFasta};
+use lyso_fasta::writer::FastaWriter;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.len() != 3 {
+        eprintln!("usage: fasta_index_fetch <ref.fa> <region>");
+        exit(2);
+    }
+    let region = args[2].parse::<Region>()?;
+    let index = FastaIndex::from_fasta_file(
+        &mut BufReader::new(File::open(&args[1])?),
+        Default::default(),
+    )?;
+    let mut fasta = IndexedFasta::new(File::open(&args[1])?, &index);
+    let rec = fasta.get_region(&region)?;
+
+    let mut out = FastaWriter::new(stdout().lock());
+    out.write_record(&rec)?;
+    out.flush()?;
+    Ok(())
+}
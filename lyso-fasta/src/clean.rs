@@ -0,0 +1,218 @@
+//! Assembly cleanup: drop short contigs, order by length, rename and rewrap
+//!
+//! Runs in two passes so no more than one contig is ever held in memory. The
+//! first is the `.fai` index, which gives every length without keeping a
+//! sequence; `AssemblyCleaner::order` picks and orders the contigs from it. The
+//! second fetches each kept contig through `IndexedFasta` and writes it out.
+//! Contigs are written under their index names, without descriptions.
+//!
+//! ```
+//! use std::io::Cursor;
+//! use lyso_fasta::clean::AssemblyCleaner;
+//! use lyso_fasta::indexer::{DuplicatePolicy, FastaIndex, IndexedFasta};
+//! use lyso_fasta::writer::FastaWriter;
+//!
+//! let fasta = b">a\nACG\n>b long\nACGTACGT\n>c\nA\n";
+//! let index = FastaIndex::from_fasta_file(&mut Cursor::new(&fasta[..]), DuplicatePolicy::Error)
+//!     .unwrap();
+//! let mut writer = FastaWriter::new(Vec::new()).line_width(4);
+//! let mut map = Vec::new();
+//! AssemblyCleaner::new()
+//!     .min_len(2)
+//!     .sort_by_length(true)
+//!     .run(&mut IndexedFasta::new(Cursor::new(&fasta[..]), &index), &mut writer, Some(&mut map))
+//!     .unwrap();
+//! assert_eq!(writer.into_inner(), b">b\nACGT\nACGT\n>a\nACG\n");
+//! assert_eq!(map, b"b\tb\t8\na\ta\t3\n");
+//! ```
+
+use std::io::{Read, Seek, Write};
+
+use lyso_common::rename::Renamer;
+
+use crate::indexer::{FastaIndex, IndexedFasta};
+use crate::writer::FastaWriter;
+use crate::FetchError;
+
+/// Counts of a finished cleanup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CleanSummary {
+    /// Contigs written
+    pub kept: usize,
+    /// Contigs shorter than the minimum length
+    pub dropped: usize,
+    /// Bases written
+    pub kept_bases: u64,
+}
+
+/// Filters, orders and renames the contigs of an indexed FASTA file
+#[derive(Debug, Clone, Default)]
+pub struct AssemblyCleaner {
+    min_len: u64,
+    sort_by_length: bool,
+    renamer: Option<Renamer>,
+}
+
+impl AssemblyCleaner {
+    /// Keep every contig, in file order, under its own name
+    #[must_use]
+    pub fn new() -> Self {
+        AssemblyCleaner::default()
+    }
+
+    /// Drop contigs shorter than `len` bases; a contig of exactly `len` is kept
+    #[must_use]
+    pub fn min_len(mut self, len: u64) -> Self {
+        self.min_len = len;
+        self
+    }
+
+    /// Write the longest contigs first, keeping file order between equal lengths
+    #[must_use]
+    pub fn sort_by_length(mut self, sort: bool) -> Self {
+        self.sort_by_length = sort;
+        self
+    }
+
+    /// Rename the kept contigs in output order, so `{n}` counts from the longest
+    #[must_use]
+    pub fn rename(mut self, renamer: Renamer) -> Self {
+        self.renamer = Some(renamer);
+        self
+    }
+
+    /// Index numbers (0-based, file order) of the contigs to write, in output order
+    pub fn order(&self, index: &FastaIndex) -> Vec<usize> {
+        let mut kept = index
+            .entries()
+            .enumerate()
+            .filter(|(_, e)| *e.length() >= self.min_len)
+            .map(|(i, e)| (i, *e.length()))
+            .collect::<Vec<_>>();
+        if self.sort_by_length {
+            kept.sort_by_key(|&(_, len)| std::cmp::Reverse(len));
+        }
+        kept.into_iter().map(|(i, _)| i).collect()
+    }
+
+    /// Write the kept contigs of `reader`'s file to `writer`
+    ///
+    /// With `map`, also writes one `old<TAB>new<TAB>length` line per written contig,
+    /// in output order. Without a renamer the two names are the same.
+    pub fn run<F, W>(
+        &mut self,
+        reader: &mut IndexedFasta<'_, F>,
+        writer: &mut FastaWriter<W>,
+        mut map: Option<&mut dyn Write>,
+    ) -> Result<CleanSummary, FetchError>
+    where
+        F: Read + Seek,
+        W: Write,
+    {
+        let order = self.order(reader.index());
+        let mut summary = CleanSummary {
+            dropped: reader.index().len() - order.len(),
+            ..CleanSummary::default()
+        };
+        for n in order {
+            let mut rec = reader.nth_record(n)?;
+            let old = match self.renamer.as_mut() {
+                Some(renamer) => renamer.rename(&mut rec),
+                None => rec.id().to_vec(),
+            };
+            writer.write_record(&rec)?;
+            let len = rec.seq().len() as u64;
+            if let Some(map) = map.as_mut() {
+                let len = format!("\t{len}\n");
+                map.write_all(&[&old[..], b"\t", rec.id(), len.as_bytes()].concat())?;
+            }
+            summary.kept += 1;
+            summary.kept_bases += len;
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::DuplicatePolicy;
+    use crate::reader::FastaReader;
+    use std::io::Cursor;
+
+    /// Contigs of 6, 3, 10, 2, 3 and 10 bases, some wrapped, one with a description
+    const FASTA: &[u8] = b">c1\nACGTAC\n>c2 short\nAAA\n>c3\nACGTA\nCGTAC\n>c4\nGG\n\
+                          >c5\nTTT\n>c6\nCCCCCGGGGG\n";
+
+    fn clean(mut cleaner: AssemblyCleaner, width: usize) -> (String, String, CleanSummary) {
+        let index =
+            FastaIndex::from_fasta_file(&mut Cursor::new(FASTA), DuplicatePolicy::Error).unwrap();
+        let mut reader = IndexedFasta::new(Cursor::new(FASTA), &index);
+        let mut writer = FastaWriter::new(Vec::new()).line_width(width);
+        let mut map = Vec::new();
+        let summary = cleaner
+            .run(&mut reader, &mut writer, Some(&mut map))
+            .unwrap();
+        let out = String::from_utf8(writer.into_inner()).unwrap();
+        (out, String::from_utf8(map).unwrap(), summary)
+    }
+
+    #[test]
+    fn test_min_len_keeps_exact_length() {
+        let (out, map, summary) = clean(AssemblyCleaner::new().min_len(3), 0);
+        assert_eq!(
+            out,
+            ">c1\nACGTAC\n>c2\nAAA\n>c3\nACGTACGTAC\n>c5\nTTT\n>c6\nCCCCCGGGGG\n"
+        );
+        assert_eq!(
+            map,
+            "c1\tc1\t6\nc2\tc2\t3\nc3\tc3\t10\nc5\tc5\t3\nc6\tc6\t10\n"
+        );
+        assert_eq!(
+            summary,
+            CleanSummary {
+                kept: 5,
+                dropped: 1,
+                kept_bases: 32
+            }
+        );
+    }
+
+    #[test]
+    fn test_sort_rename_wrap() {
+        let renamer = Renamer::new("contig_{n}".parse().unwrap(), "", false);
+        let cleaner = AssemblyCleaner::new()
+            .min_len(3)
+            .sort_by_length(true)
+            .rename(renamer);
+        let (out, map, _) = clean(cleaner, 4);
+        assert_eq!(
+            map,
+            "c3\tcontig_1\t10\nc6\tcontig_2\t10\nc1\tcontig_3\t6\nc2\tcontig_4\t3\n\
+             c5\tcontig_5\t3\n"
+        );
+
+        // the map lists the output's records, in order, with their lengths
+        let records = FastaReader::new(out.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let listed = map
+            .lines()
+            .map(|l| l.split('\t').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), listed.len());
+        for (rec, row) in records.iter().zip(&listed) {
+            assert_eq!(rec.id(), row[1].as_bytes());
+            assert_eq!(rec.seq().len().to_string(), row[2]);
+        }
+        assert!(out.starts_with(">contig_1\nACGT\nACGT\nAC\n>contig_2\nCCCC\n"));
+        assert!(out.lines().all(|l| l.starts_with('>') || l.len() <= 4));
+    }
+
+    #[test]
+    fn test_nothing_kept() {
+        let (out, map, summary) = clean(AssemblyCleaner::new().min_len(11), 60);
+        assert!(out.is_empty() && map.is_empty());
+        assert_eq!((summary.kept, summary.dropped), (0, 6));
+    }
+}
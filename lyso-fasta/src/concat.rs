@@ -0,0 +1,323 @@
+//! Concatenate records into one pseudo-reference, keeping a map back to the originals
+//!
+//! Records are joined in input order with a run of `N`s between each pair. The
+//! `OffsetMap` translates positions between the concatenated sequence and the
+//! records it was built from; positions inside a spacer belong to no record.
+
+use std::io::{BufRead, Write};
+
+use lyso_common::name_index::NameIndex;
+use lyso_common::pos::ZeroBased;
+use thiserror::Error;
+
+use crate::{FastaError, Record};
+
+/// Id of the record built by `concatenate`
+pub const CONCAT_ID: &str = "concat";
+
+/// Errors from `concatenate` and from reading an offset map
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ConcatError {
+    /// The input FASTA could not be read
+    #[error(transparent)]
+    Read(#[from] FastaError),
+    /// Two records share a name
+    #[error("Duplicate record name {0}")]
+    DuplicateName(String),
+    /// A line of an offset map that could not be parsed
+    #[error("Malformed offset map at line {line}: {reason}")]
+    MalformedMap {
+        /// 1-based line number
+        line: usize,
+        /// What was wrong with it
+        reason: &'static str,
+    },
+    /// Reading or writing failed
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+}
+
+/// Where one record lies in the concatenated sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetEntry {
+    name: Vec<u8>,
+    start: u64,
+    length: u64,
+}
+
+impl OffsetEntry {
+    /// Name of the record
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Offset of the record's first base in the concatenated sequence
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Length of the record
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+/// Positions of the records in a concatenated sequence, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OffsetMap {
+    entries: Vec<OffsetEntry>,
+    by_name: NameIndex,
+}
+
+impl OffsetMap {
+    /// TSV header written by `write_tsv`
+    const HEADER: &'static str = "name\tstart\tlength";
+
+    /// An empty map
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `entry`, failing if its name is taken
+    fn push(&mut self, entry: OffsetEntry) -> Result<(), ConcatError> {
+        if self.position(&entry.name).is_some() {
+            let name = String::from_utf8_lossy(&entry.name).into_owned();
+            return Err(ConcatError::DuplicateName(name));
+        }
+        self.by_name.push(&entry.name);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Position in `entries` of the record called `name`
+    fn position(&self, name: &[u8]) -> Option<usize> {
+        self.by_name.find(name, |i| &self.entries[i].name)
+    }
+
+    /// The record and position within it of `pos` in the concatenated sequence
+    ///
+    /// None inside a spacer and past the end.
+    pub fn to_original(&self, pos: ZeroBased) -> Option<(&[u8], ZeroBased)> {
+        let p = pos.get();
+        let i = self
+            .entries
+            .partition_point(|e| e.start <= p)
+            .checked_sub(1)?;
+        let e = &self.entries[i];
+        (p - e.start < e.length).then(|| (&e.name[..], ZeroBased::new(p - e.start)))
+    }
+
+    /// Position in the concatenated sequence of `pos` in record `name`
+    ///
+    /// None if there is no such record or `pos` is past its end.
+    pub fn to_concat(&self, name: impl AsRef<[u8]>, pos: ZeroBased) -> Option<ZeroBased> {
+        let e = &self.entries[self.position(name.as_ref())?];
+        (pos.get() < e.length).then(|| ZeroBased::new(e.start + pos.get()))
+    }
+
+    /// Entries in concatenation order
+    pub fn entries(&self) -> impl Iterator<Item = &OffsetEntry> {
+        self.entries.iter()
+    }
+
+    /// Number of records
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True if there are no records
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Write a header, then one `name\tstart\tlength` line per record; starts are 0-based
+    pub fn write_tsv(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "{}", Self::HEADER)?;
+        for e in &self.entries {
+            out.write_all(&e.name)?;
+            writeln!(out, "\t{}\t{}", e.start, e.length)?;
+        }
+        out.flush()
+    }
+
+    /// Read what `write_tsv` wrote
+    ///
+    /// Records must be in order and must not overlap.
+    pub fn read_tsv(input: impl BufRead) -> Result<Self, ConcatError> {
+        let mut map = OffsetMap::new();
+        for (i, line) in input.split(b'\n').enumerate() {
+            let line = line?;
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            if i == 0 && line == Self::HEADER.as_bytes() {
+                continue;
+            }
+            let malformed = |reason| ConcatError::MalformedMap {
+                line: i + 1,
+                reason,
+            };
+            let fields = line.split(|b| *b == b'\t').collect::<Vec<&[u8]>>();
+            let [name, start, length] = fields[..] else {
+                return Err(malformed("expected 3 columns"));
+            };
+            let num = |f: &[u8]| {
+                std::str::from_utf8(f)
+                    .ok()
+                    .and_then(|f| f.parse::<u64>().ok())
+            };
+            let (Some(start), Some(length)) = (num(start), num(length)) else {
+                return Err(malformed("start and length must be numbers"));
+            };
+            if start.checked_add(length).is_none() {
+                return Err(malformed("record ends past the largest position"));
+            }
+            if map
+                .entries
+                .last()
+                .is_some_and(|e| start < e.start + e.length)
+            {
+                return Err(malformed("record overlaps the one before"));
+            }
+            map.push(OffsetEntry {
+                name: name.to_vec(),
+                start,
+                length,
+            })?;
+        }
+        Ok(map)
+    }
+}
+
+/// Join all records into one named `CONCAT_ID`, with `spacer_len` N's between records
+///
+/// Record ids are cut at the first whitespace for the map, and must be unique.
+pub fn concatenate<I>(records: I, spacer_len: usize) -> Result<(Record, OffsetMap), ConcatError>
+where
+    I: IntoIterator<Item = Result<Record, FastaError>>,
+{
+    let mut map = OffsetMap::new();
+    let mut seq = String::new();
+    for rec in records {
+        let rec = rec?;
+        if !map.is_empty() {
+            seq.extend(std::iter::repeat_n('N', spacer_len));
+        }
+        let name = rec
+            .id()
+            .split(u8::is_ascii_whitespace)
+            .next()
+            .unwrap_or_default();
+        map.push(OffsetEntry {
+            name: name.to_vec(),
+            start: seq.len() as u64,
+            length: rec.seq().len() as u64,
+        })?;
+        seq.push_str(rec.seq());
+    }
+    Ok((
+        Record {
+            id: CONCAT_ID.as_bytes().to_vec(),
+            seq,
+        },
+        map,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::FastaReader;
+
+    fn concat(fa: &[u8], spacer_len: usize) -> Result<(Record, OffsetMap), ConcatError> {
+        concatenate(FastaReader::new(fa), spacer_len)
+    }
+
+    #[test]
+    fn test_concatenate() {
+        let (rec, map) = concat(b">a x\nACG\n>b\nTT\n>c\nG\n", 2).unwrap();
+        assert_eq!(rec.id(), b"concat");
+        assert_eq!(rec.seq(), "ACGNNTTNNG");
+        let starts = map
+            .entries()
+            .map(|e| (e.name(), e.start()))
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [(&b"a"[..], 0), (b"b", 5), (b"c", 9)]);
+
+        let (rec, map) = concat(b">a\nAC\n>b\nGT\n", 0).unwrap();
+        assert_eq!(rec.seq(), "ACGT");
+        assert_eq!(
+            map.to_original(ZeroBased::new(2)),
+            Some((&b"b"[..], ZeroBased::new(0)))
+        );
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let err = concat(b">a\nAC\n>b\nG\n>a dup\nT\n", 1).unwrap_err();
+        assert!(matches!(err, ConcatError::DuplicateName(ref n) if n == "a"));
+    }
+
+    #[test]
+    fn test_mapping_at_boundaries() {
+        let (_, map) = concat(b">a\nACG\n>b\nTT\n", 2).unwrap();
+        let orig = |p: u64| {
+            map.to_original(ZeroBased::new(p))
+                .map(|(n, p)| (n, p.get()))
+        };
+        // first and last base of each record
+        assert_eq!(orig(0), Some((&b"a"[..], 0)));
+        assert_eq!(orig(2), Some((&b"a"[..], 2)));
+        assert_eq!(orig(5), Some((&b"b"[..], 0)));
+        assert_eq!(orig(6), Some((&b"b"[..], 1)));
+        // the spacer and past the end
+        assert_eq!(orig(3), None);
+        assert_eq!(orig(4), None);
+        assert_eq!(orig(7), None);
+
+        let conc = |n: &str, p: u64| map.to_concat(n, ZeroBased::new(p)).map(ZeroBased::get);
+        assert_eq!(conc("a", 0), Some(0));
+        assert_eq!(conc("b", 1), Some(6));
+        assert_eq!(conc("a", 3), None);
+        assert_eq!(conc("c", 0), None);
+        for p in [0, 2, 5, 6] {
+            let (name, pos) = map.to_original(ZeroBased::new(p)).unwrap();
+            assert_eq!(map.to_concat(name, pos), Some(ZeroBased::new(p)));
+        }
+
+        assert_eq!(OffsetMap::new().to_original(ZeroBased::new(0)), None);
+    }
+
+    #[test]
+    fn test_tsv_round_trip() {
+        let (_, map) = concat(b">r\xe9ad\nACG\n>b\nTT\n>c\nA\n", 3).unwrap();
+        let mut tsv = Vec::new();
+        map.write_tsv(&mut tsv).unwrap();
+        assert!(tsv.starts_with(b"name\tstart\tlength\nr\xe9ad\t0\t3\nb\t6\t2\n"));
+        let loaded = OffsetMap::read_tsv(&tsv[..]).unwrap();
+        assert_eq!(loaded, map);
+        let mut again = Vec::new();
+        loaded.write_tsv(&mut again).unwrap();
+        assert_eq!(again, tsv);
+
+        // a record may end right at the largest position
+        let edge = OffsetMap::read_tsv(&b"a\t0\t5\nb\t18446744073709551610\t5\n"[..]).unwrap();
+        let last = ZeroBased::new(u64::MAX - 1);
+        assert_eq!(edge.to_original(last), Some((&b"b"[..], ZeroBased::new(4))));
+        assert_eq!(edge.to_original(ZeroBased::new(u64::MAX)), None);
+
+        for (bad, line) in [
+            (&b"a\t0\n"[..], 1),
+            (b"name\tstart\tlength\na\t0\t5\nb\tx\t1\n", 3),
+            (b"a\t0\t5\nb\t4\t1\n", 2),
+            (b"a\t0\t5\na\t9\t1\n", 0),
+            (b"a\t0\t5\nb\t18446744073709551610\t6\n", 2),
+        ] {
+            match OffsetMap::read_tsv(bad) {
+                Err(ConcatError::MalformedMap { line: l, .. }) => assert_eq!(l, line),
+                Err(ConcatError::DuplicateName(_)) => assert_eq!(line, 0),
+                other => panic!("{other:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Counting records without parsing them
+//!
+//! `FastaReader` starts a record at every '>' that begins a line outside a
+//! header, so counting those with memchr counts the records. `fast_count` also
+//! checks what the parser would reject (an empty header, a header cut off by the
+//! end of the input, a sequence that is not UTF-8) and fails as reading would.
+
+use std::io::{BufRead, ErrorKind};
+
+use lyso_common::detect::{find_mismatch, Format};
+use memchr::{memchr2, memchr_iter};
+
+use crate::parser::{next_record_start, starts_line};
+use crate::FastaError;
+
+/// Where the scan is in the input
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// Nothing read yet
+    Start,
+    /// Just after a '>'
+    HeaderStart,
+    /// Inside a header line
+    Header,
+    /// Inside sequence lines
+    Seq,
+}
+
+/// The number of records in `reader`, or the first error that would end reading it
+///
+/// Gives the count `FastaReader` would read, without building a record.
+///
+/// ```
+/// use lyso_fasta::count::fast_count;
+///
+/// assert_eq!(fast_count(&b">a\nAC\nGT\n>b desc\n>c\nAC"[..]).unwrap(), 3);
+/// assert!(fast_count(&b">a\nAC\n>"[..]).is_err());
+/// ```
+pub fn fast_count<R: BufRead>(mut reader: R) -> Result<u64, FastaError> {
+    let mut state = State::Start;
+    let mut count = 0;
+    // line endings read, and the line the current record starts on
+    let (mut lines, mut line) = (0, 1);
+    // sequence bytes from the first non-ASCII one, which may not be UTF-8
+    let mut unchecked: Option<Vec<u8>> = None;
+    // the last byte of the previous buffer, for a '>' at the start of this one
+    let mut last = None;
+    loop {
+        let data = match reader.fill_buf() {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if data.is_empty() {
+            break;
+        }
+        let mut pos = 0;
+        while pos < data.len() {
+            match state {
+                State::Start => {
+                    if let Some(m) = find_mismatch(data, Format::Fasta) {
+                        return Err(m.into());
+                    }
+                    if data[0] != b'>' {
+                        return Err(at_line(line, FastaError::ParserError));
+                    }
+                    (count, state, pos) = (1, State::HeaderStart, 1);
+                }
+                State::HeaderStart if matches!(data[pos], b'\r' | b'\n') => {
+                    return Err(at_line(line, FastaError::ParserError));
+                }
+                State::HeaderStart => state = State::Header,
+                State::Header => match memchr2(b'\r', b'\n', &data[pos..]) {
+                    Some(i) => (state, pos) = (State::Seq, pos + i),
+                    None => pos = data.len(),
+                },
+                State::Seq => {
+                    let prev = if pos == 0 {
+                        last.as_ref()
+                    } else {
+                        data.get(pos - 1)
+                    };
+                    let line_start = starts_line(prev);
+                    let end =
+                        next_record_start(&data[pos..], line_start).map_or(data.len(), |i| pos + i);
+                    let seq = &data[pos..end];
+                    lines += memchr_iter(b'\n', seq).count() as u64;
+                    if let Some(bytes) = &mut unchecked {
+                        bytes.extend_from_slice(seq);
+                    } else if !seq.is_ascii() {
+                        let i = seq.iter().position(|b| !b.is_ascii()).unwrap_or(0);
+                        unchecked = Some(seq[i..].to_vec());
+                    }
+                    if end < data.len() {
+                        check_utf8(unchecked.take()).map_err(|e| at_line(line, e))?;
+                        (count, state, line) = (count + 1, State::HeaderStart, lines + 1);
+                        pos = end + 1;
+                    } else {
+                        pos = end;
+                    }
+                }
+            }
+        }
+        let used = data.len();
+        last = data.last().copied();
+        reader.consume(used);
+    }
+    match state {
+        State::HeaderStart | State::Header => Err(at_line(line, FastaError::EofError)),
+        _ => check_utf8(unchecked)
+            .map(|_| count)
+            .map_err(|e| at_line(line, e)),
+    }
+}
+
+fn at_line(line: u64, err: FastaError) -> FastaError {
+    FastaError::AtLine {
+        path: None,
+        line,
+        source: Box::new(err),
+    }
+}
+
+fn check_utf8(seq: Option<Vec<u8>>) -> Result<(), FastaError> {
+    match seq.map(|s| std::str::from_utf8(&s).is_ok()) {
+        Some(false) => Err(FastaError::ParserError),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::FastaReader;
+    use lyso_common::synth::generate_fasta;
+    use std::io::BufReader;
+
+    /// What reading every record gives: the number read, or the terminal error
+    fn full_count<R: BufRead>(input: R) -> Result<u64, String> {
+        let mut n = 0;
+        for rec in FastaReader::new(input) {
+            match rec {
+                Err(e) if e.is_terminal() => return Err(e.to_string()),
+                _ => n += 1,
+            }
+        }
+        Ok(n)
+    }
+
+    fn check(input: &[u8], name: &str) {
+        let expected = full_count(input);
+        let fast = fast_count(input).map_err(|e| e.to_string());
+        assert_eq!(fast, expected, "{name}");
+        // format sniffing only sees the first buffer, as it does for the reader
+        let trickled = fast_count(BufReader::with_capacity(1, input)).map_err(|e| e.to_string());
+        let expected = full_count(BufReader::with_capacity(1, input));
+        assert_eq!(trickled, expected, "{name}, read a byte at a time");
+    }
+
+    #[test]
+    fn test_fixtures() {
+        for fixture in [
+            "consensus_ref.fa",
+            "corrupt.fa",
+            "crlf.fa",
+            "empty_records.fa",
+            "getfasta.fa",
+            "gt_in_desc.fa",
+            "line_errors.fa",
+            "masked.fa",
+            "no_final_newline.fa",
+            "test.fa",
+            "test.fastq",
+        ] {
+            let input = std::fs::read(format!("../resources/test_data/{fixture}")).unwrap();
+            check(&input, fixture);
+        }
+        let large = generate_fasta(2000, 300, 1);
+        assert_eq!(fast_count(&large[..]).unwrap(), 2000);
+    }
+
+    #[test]
+    fn test_edge_cases() {
+        for (input, name) in [
+            (&b""[..], "empty"),
+            (b"\n>a\nAC\n", "leading blank line"),
+            (b"ACGT\n>a\nAC\n", "no header"),
+            (b">\nAC\n", "empty header"),
+            (b">a\nAC\n>\r\nGT\n", "empty second header"),
+            (b">a\nAC\n>", "bare '>' at the end"),
+            (b">a\nAC\n>b", "header without a line ending"),
+            (b">a\r", "header ending in CR"),
+            (b">a>b\nAC>c\nGT\n", "'>' inside lines"),
+            (b">a\nAC\n>b>\n>c\n", "'>' ending a header"),
+            (b">a\rAC\r>b\rGT\r", "CR line endings"),
+            (b">a\xff\nAC\n", "latin1 header"),
+            (b">a\nA\xc3\xa9C\n>b\nGT\n", "UTF-8 sequence"),
+            (b">a\nA\xc3\n\xa9C\n", "UTF-8 split by a line ending"),
+            (b">a\nA\xffC\n>b\nGT\n", "invalid sequence"),
+            (b">a\nAC\n>b\nG\xff", "invalid last sequence"),
+        ] {
+            check(input, name);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Rewriting an indexed FASTA file with its records in another order
+//!
+//! `IndexedFasta::export` picks the order from the `.fai` index alone, then
+//! fetches and writes one record at a time, so no more than the largest record
+//! is ever held in memory. Headers are read back whole, descriptions included,
+//! and sequences keep the line length of the original record unless the writer
+//! was given a `line_width`.
+//!
+//! ```
+//! use std::io::Cursor;
+//! use lyso_fasta::export::ExportOrder;
+//! use lyso_fasta::indexer::{DuplicatePolicy, FastaIndex, IndexedFasta};
+//! use lyso_fasta::writer::FastaWriter;
+//!
+//! let fasta = b">chr10 x\nACGT\nA\n>chr2\nACGTAC\n";
+//! let index = FastaIndex::from_fasta_file(&mut Cursor::new(&fasta[..]), DuplicatePolicy::Error)
+//!     .unwrap();
+//! let mut writer = FastaWriter::new(Vec::new());
+//! let report = IndexedFasta::new(Cursor::new(&fasta[..]), &index)
+//!     .export(ExportOrder::NameNatural, &mut writer)
+//!     .unwrap();
+//! assert_eq!(writer.into_inner(), b">chr2\nACGTAC\n>chr10 x\nACGT\nA\n");
+//! assert_eq!((report.records, report.bytes), (2, 29));
+//! ```
+
+use std::cmp::Reverse;
+use std::fmt::{self, Display};
+use std::io::{Read, Seek, Write};
+use std::str::FromStr;
+
+use lyso_common::sort::natural_cmp;
+
+use crate::indexer::{FastaIndex, IndexedFasta};
+use crate::writer::FastaWriter;
+use crate::{FetchError, Record};
+
+/// The order `IndexedFasta::export` writes records in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportOrder {
+    /// As they appear in the file
+    #[default]
+    FileOrder,
+    /// By name, byte by byte
+    NameLexicographic,
+    /// By name, with runs of digits compared as numbers, so chr2 comes before chr10
+    NameNatural,
+    /// Longest first, keeping file order between equal lengths
+    LengthDescending,
+}
+
+impl ExportOrder {
+    /// Index numbers (0-based, file order) of the records of `index`, in this order
+    pub fn arrange(&self, index: &FastaIndex) -> Vec<usize> {
+        let mut order = (0..index.len()).collect::<Vec<_>>();
+        let entry = |n: usize| index.nth_entry(n).expect("n is below the index length");
+        match self {
+            ExportOrder::FileOrder => {}
+            ExportOrder::NameLexicographic => {
+                order.sort_by(|&a, &b| entry(a).name().cmp(entry(b).name()))
+            }
+            ExportOrder::NameNatural => {
+                order.sort_by(|&a, &b| natural_cmp(entry(a).name(), entry(b).name()));
+            }
+            ExportOrder::LengthDescending => order.sort_by_key(|&n| Reverse(*entry(n).length())),
+        }
+        order
+    }
+}
+
+impl FromStr for ExportOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(ExportOrder::FileOrder),
+            "name" => Ok(ExportOrder::NameLexicographic),
+            "natural" => Ok(ExportOrder::NameNatural),
+            "length" => Ok(ExportOrder::LengthDescending),
+            _ => Err(format!(
+                "invalid order '{s}', expected file, name, natural or length"
+            )),
+        }
+    }
+}
+
+impl Display for ExportOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportOrder::FileOrder => write!(f, "file"),
+            ExportOrder::NameLexicographic => write!(f, "name"),
+            ExportOrder::NameNatural => write!(f, "natural"),
+            ExportOrder::LengthDescending => write!(f, "length"),
+        }
+    }
+}
+
+/// Counts of a finished export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportReport {
+    /// Records written
+    pub records: usize,
+    /// Bytes written, headers and line endings included
+    pub bytes: u64,
+}
+
+impl<F> IndexedFasta<'_, F>
+where
+    F: Read + Seek,
+{
+    /// Write every record of the file to `writer` in `order`, see the module documentation
+    pub fn export<W: Write>(
+        &mut self,
+        order: ExportOrder,
+        writer: &mut FastaWriter<W>,
+    ) -> Result<ExportReport, FetchError> {
+        let start = writer.bytes_written();
+        let mut report = ExportReport::default();
+        for n in order.arrange(self.index()) {
+            let width = match writer.width() {
+                0 => self
+                    .index()
+                    .nth_entry(n)
+                    .map_or(0, |e| *e.linebases() as usize),
+                width => width,
+            };
+            let id = self.nth_header(n)?;
+            let seq = self.nth_record(n)?.seq;
+            // an entry without sequence has no line length
+            writer.write_wrapped(&Record { id, seq }, width.max(1))?;
+            report.records += 1;
+        }
+        report.bytes = writer.bytes_written() - start;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::DuplicatePolicy;
+    use crate::reader::FastaReader;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    const PATH: &str = "../resources/test_data/reorder.fa";
+
+    fn export(order: ExportOrder, wrap: usize) -> (Vec<u8>, ExportReport) {
+        let mut raw = BufReader::new(File::open(PATH).unwrap());
+        let index = FastaIndex::from_fasta_file(&mut raw, DuplicatePolicy::Error).unwrap();
+        let mut writer = FastaWriter::new(Vec::new()).line_width(wrap);
+        let report = IndexedFasta::new(File::open(PATH).unwrap(), &index)
+            .export(order, &mut writer)
+            .unwrap();
+        (writer.into_inner(), report)
+    }
+
+    fn names(out: &[u8]) -> Vec<String> {
+        FastaReader::new(out)
+            .map(|r| r.unwrap().id_str_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_orders() {
+        let cases = [
+            (
+                ExportOrder::FileOrder,
+                ["chr10 tenth", "chr2", "chr1 first", "chrX", "chr3"],
+            ),
+            (
+                ExportOrder::NameLexicographic,
+                ["chr1 first", "chr10 tenth", "chr2", "chr3", "chrX"],
+            ),
+            (
+                ExportOrder::NameNatural,
+                ["chr1 first", "chr2", "chr3", "chr10 tenth", "chrX"],
+            ),
+            (
+                ExportOrder::LengthDescending,
+                ["chrX", "chr2", "chr10 tenth", "chr3", "chr1 first"],
+            ),
+        ];
+        for (order, expected) in cases {
+            let (out, report) = export(order, 0);
+            assert_eq!(names(&out), expected, "{order}");
+            assert_eq!(report.records, 5);
+            assert_eq!(report.bytes, out.len() as u64);
+            assert_eq!(order.to_string().parse::<ExportOrder>(), Ok(order));
+        }
+    }
+
+    #[test]
+    fn test_byte_identical() {
+        let original = std::fs::read(PATH).unwrap();
+        let (out, _) = export(ExportOrder::FileOrder, 0);
+        assert_eq!(out, original);
+
+        // every record comes out as its own bytes, whatever the order
+        let records = |bytes: &[u8]| {
+            let mut recs = FastaReader::new(bytes)
+                .raw_records()
+                .map(|r| r.unwrap().bytes)
+                .collect::<Vec<_>>();
+            recs.sort();
+            recs
+        };
+        let (out, _) = export(ExportOrder::LengthDescending, 0);
+        assert_eq!(records(&out), records(&original));
+
+        let (out, _) = export(ExportOrder::NameNatural, 8);
+        let rewrapped = FastaReader::new(&out[..])
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        assert!(out
+            .split(|b| *b == b'\n')
+            .all(|line| line.starts_with(b">") || line.len() <= 8));
+        let x = rewrapped.iter().find(|r| r.id() == b"chrX").unwrap();
+        let original = FastaReader::new(&original[..])
+            .map(|r| r.unwrap())
+            .find(|r| r.id() == b"chrX")
+            .unwrap();
+        assert_eq!(x.seq(), original.seq());
+    }
+}
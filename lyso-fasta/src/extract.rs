@@ -0,0 +1,334 @@
+//! Subsequences of a reference covered by BED intervals
+//!
+//! Each interval becomes one FASTA record, named after its BED name column or
+//! `chrom:start-end` (0-based, half-open, as in the BED file) when it has none.
+//! Minus-strand intervals are reverse complemented. Records come out in BED
+//! order, as from `bedtools getfasta`.
+//!
+//! Intervals running past the end of their sequence are clamped; intervals that
+//! are empty, before or after clamping, are skipped. Neither stops extraction,
+//! and both are listed in the `ExtractReport` along with sequence names missing
+//! from the reference.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read, Seek, Write};
+
+use fxhash::{FxHashMap, FxHashSet};
+use lyso_common::intervals::{IntervalSet, NamedInterval, Strand};
+use lyso_common::region::RegionError;
+use lyso_common::search::reverse_complement;
+use thiserror::Error;
+
+use crate::reader::FastaReader;
+use crate::store::ReferenceSource;
+use crate::writer::FastaWriter;
+use crate::{FastaError, FetchError, Record};
+
+/// Errors from extracting intervals
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ExtractError {
+    /// A record or region could not be fetched
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+    /// The input FASTA could not be read
+    #[error(transparent)]
+    Read(#[from] FastaError),
+    /// An interval that is not a valid region
+    #[error(transparent)]
+    Region(#[from] RegionError),
+    /// Reading or writing failed
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+}
+
+/// What happened to the intervals that were not written as given
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractReport {
+    /// Records written
+    pub written: usize,
+    /// Intervals cut short at the end of their sequence, as given in the BED file
+    pub clamped: Vec<NamedInterval>,
+    /// Zero-length intervals, and intervals starting past the end of their sequence
+    pub skipped: Vec<NamedInterval>,
+    /// Sequence names with intervals but no record in the reference, sorted
+    pub missing: Vec<String>,
+}
+
+impl ExtractReport {
+    /// Whether every interval was found in the reference
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// The part of `interval` inside a sequence of `len` bases, noting clamps and skips
+    fn bounds(&mut self, interval: &NamedInterval, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = (interval.start.get(), interval.end.get().min(len));
+        if start >= end {
+            self.skipped.push(interval.clone());
+            return None;
+        }
+        if end < interval.end.get() {
+            self.clamped.push(interval.clone());
+        }
+        Some((start, end))
+    }
+}
+
+/// The bases of `interval` from `start` to `end` as a named and oriented record
+fn named(interval: &NamedInterval, (start, end): (u64, u64), seq: &[u8]) -> Record {
+    let mut rec = Record::new();
+    match interval.label.as_deref() {
+        Some(label) if label != "." => rec.set_id(label),
+        _ => rec.set_id(format!("{}:{start}-{end}", interval.name)),
+    }
+    rec.set_seq(match interval.strand {
+        Strand::Reverse => String::from_utf8_lossy(&reverse_complement(seq)).into_owned(),
+        _ => String::from_utf8_lossy(seq).into_owned(),
+    });
+    rec
+}
+
+/// Name and orient the bases of `interval` from `start` to `end`, and write them
+fn emit<W: Write>(
+    writer: &mut FastaWriter<W>,
+    interval: &NamedInterval,
+    bounds: (u64, u64),
+    seq: &[u8],
+) -> std::io::Result<()> {
+    writer.write_record(&named(interval, bounds, seq))
+}
+
+/// Extract every interval from a reference held in memory or fetched through an index
+pub fn extract_intervals<F, W>(
+    reference: &mut ReferenceSource<'_, F>,
+    intervals: &IntervalSet,
+    writer: &mut FastaWriter<W>,
+) -> Result<ExtractReport, ExtractError>
+where
+    F: Read + Seek,
+    W: Write,
+{
+    let mut report = ExtractReport {
+        missing: intervals
+            .names()
+            .filter(|name| !reference.contains(name))
+            .map(String::from)
+            .collect(),
+        ..Default::default()
+    };
+    report.missing.sort();
+    let lengths = reference
+        .sequences()
+        .into_iter()
+        .collect::<FxHashMap<_, _>>();
+    for interval in intervals.in_input_order() {
+        let Some(len) = lengths.get(&interval.name) else {
+            continue;
+        };
+        let Some((start, end)) = report.bounds(interval, *len) else {
+            continue;
+        };
+        let seq = reference.fetch(&interval.name, start, end)?;
+        emit(writer, interval, (start, end), seq.as_bytes())?;
+        report.written += 1;
+    }
+    Ok(report)
+}
+
+/// Extract every interval in a single pass over an unindexed reference
+///
+/// Records are matched on the first word of their header, as in a `.fai`
+/// index; only the first record of a repeated name is used. Extracted sequences
+/// are held until every interval before them in the BED file has been written,
+/// so a BED file out of reference order costs memory up to the size of the output.
+pub fn extract_intervals_streaming<R, W>(
+    reader: &mut FastaReader<R>,
+    intervals: &IntervalSet,
+    writer: &mut FastaWriter<W>,
+) -> Result<ExtractReport, ExtractError>
+where
+    R: BufRead,
+    W: Write,
+{
+    let order = intervals.in_input_order();
+    // positions in `order` of the intervals on each sequence
+    let mut by_name: FxHashMap<&str, Vec<usize>> = FxHashMap::default();
+    for (n, interval) in order.iter().enumerate() {
+        by_name.entry(&interval.name).or_default().push(n);
+    }
+    let mut report = ExtractReport::default();
+    let mut seen = FxHashSet::default();
+    // sequences extracted ahead of `next`, the first interval not yet written;
+    // None for an interval with nothing to write
+    let mut held: BTreeMap<usize, Option<Record>> = BTreeMap::new();
+    let mut next = 0;
+    for rec in reader {
+        let rec = rec?;
+        let id = rec.id_str_lossy();
+        let name = id.split_ascii_whitespace().next().unwrap_or_default();
+        if !seen.insert(name.to_string()) {
+            continue;
+        }
+        let seq = rec.seq().as_bytes();
+        for &n in by_name.get(name).map_or(&[][..], Vec::as_slice) {
+            let extracted = report
+                .bounds(order[n], seq.len() as u64)
+                .map(|(start, end)| {
+                    named(order[n], (start, end), &seq[start as usize..end as usize])
+                });
+            held.insert(n, extracted);
+        }
+        while let Some(extracted) = held.remove(&next) {
+            if let Some(out) = extracted {
+                writer.write_record(&out)?;
+                report.written += 1;
+            }
+            next += 1;
+        }
+    }
+    // what is left sits behind intervals on sequences missing from the reference
+    for out in held.into_values().flatten() {
+        writer.write_record(&out)?;
+        report.written += 1;
+    }
+    report.missing = intervals
+        .names()
+        .filter(|name| !seen.contains(*name))
+        .map(String::from)
+        .collect();
+    report.missing.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::{FastaIndex, IndexedFasta};
+    use crate::store::FastaStore;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    const FA: &str = "../resources/test_data/getfasta.fa";
+
+    fn intervals() -> IntervalSet {
+        let bed = File::open("../resources/test_data/getfasta.bed").unwrap();
+        IntervalSet::from_bed(BufReader::new(bed), false).unwrap()
+    }
+
+    fn check_report(report: &ExtractReport) {
+        assert_eq!(report.written, 5);
+        assert_eq!(report.missing, ["chrX"]);
+        let labels = |v: &[NamedInterval]| {
+            v.iter()
+                .map(|i| format!("{}:{}-{}", i.name, i.start.get(), i.end.get()))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(labels(&report.clamped), ["chr2:10-20"]);
+        assert_eq!(labels(&report.skipped), ["chr1:7-7", "chr2:30-40"]);
+        assert!(!report.is_complete());
+    }
+
+    /// Sequences as `bedtools getfasta -s` gives them; records carrying a BED name
+    /// are named by it alone, without bedtools' `::chrom:start-end(strand)` suffix
+    fn expected() -> Vec<u8> {
+        std::fs::read("../resources/test_data/getfasta.expected.fa").unwrap()
+    }
+
+    #[test]
+    fn test_indexed() {
+        let index = FastaIndex::from_fasta_file(
+            &mut BufReader::new(File::open(FA).unwrap()),
+            Default::default(),
+        )
+        .unwrap();
+        let fasta = IndexedFasta::new(File::open(FA).unwrap(), &index);
+        let mut writer = FastaWriter::new(Vec::new());
+        let report = extract_intervals(
+            &mut ReferenceSource::Indexed(fasta),
+            &intervals(),
+            &mut writer,
+        )
+        .unwrap();
+        check_report(&report);
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            String::from_utf8(expected()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_memory_matches_indexed() {
+        let store = FastaStore::from_reader(FastaReader::from_path(FA).unwrap()).unwrap();
+        let mut writer = FastaWriter::new(Vec::new());
+        let report = extract_intervals(
+            &mut ReferenceSource::<File>::Memory(store),
+            &intervals(),
+            &mut writer,
+        )
+        .unwrap();
+        check_report(&report);
+        assert_eq!(writer.into_inner(), expected());
+    }
+
+    #[test]
+    fn test_streaming_matches_indexed() {
+        let mut reader = FastaReader::from_path(FA).unwrap();
+        let mut writer = FastaWriter::new(Vec::new());
+        let report = extract_intervals_streaming(&mut reader, &intervals(), &mut writer).unwrap();
+        check_report(&report);
+        assert_eq!(writer.into_inner(), expected());
+    }
+
+    /// Against `bedtools getfasta -s` itself, when it is on PATH
+    #[test]
+    fn test_matches_bedtools() {
+        let dir = tempfile::tempdir().unwrap();
+        // bedtools writes ref.fa.fai next to the reference
+        let fa = dir.path().join("ref.fa");
+        std::fs::copy(FA, &fa).unwrap();
+        // bedtools drops intervals past the end of a sequence rather than clamping
+        // them, so only intervals both extract in full are compared
+        let bed = "chr1\t0\t10\nchr2\t2\t8\t.\t0\t+\nchr1\t5\t15\tgeneA\t0\t-\n\
+                   chr1\t28\t35\ttail\t0\t-\nchr2\t0\t12\n";
+        let bed_path = dir.path().join("regions.bed");
+        std::fs::write(&bed_path, bed).unwrap();
+        let Ok(out) = std::process::Command::new("bedtools")
+            .args(["getfasta", "-s", "-fi"])
+            .arg(&fa)
+            .arg("-bed")
+            .arg(&bed_path)
+            .output()
+        else {
+            eprintln!("bedtools not found, skipping");
+            return;
+        };
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        let intervals = IntervalSet::from_bed(bed.as_bytes(), false).unwrap();
+        let mut writer = FastaWriter::new(Vec::new());
+        let mut reader = FastaReader::from_path(&fa).unwrap();
+        extract_intervals_streaming(&mut reader, &intervals, &mut writer).unwrap();
+        // names differ by design, see `expected`
+        let seqs = |fasta: &[u8]| {
+            FastaReader::new(fasta)
+                .map(|r| r.unwrap().seq().to_string())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(seqs(&writer.into_inner()), seqs(&out.stdout));
+    }
+
+    #[test]
+    fn test_no_intervals() {
+        let mut reader = FastaReader::from_path(FA).unwrap();
+        let mut writer = FastaWriter::new(Vec::new());
+        let report =
+            extract_intervals_streaming(&mut reader, &IntervalSet::default(), &mut writer).unwrap();
+        assert_eq!(report, ExtractReport::default());
+        assert!(writer.into_inner().is_empty());
+    }
+}
@@ -1,87 +1,352 @@
-#![allow(unused)]
-use fxhash::{FxHashMap, FxHasher};
-use std::collections::HashMap;
+//! FASTA indexes (`.fai`) and random access through them
+
+use std::fmt;
 use std::fs::File;
-use std::hash::{BuildHasherDefault, Hasher};
-use std::io::{self, Seek, SeekFrom};
-use std::io::{prelude::*, ErrorKind};
-use std::marker::PhantomData;
-use std::slice::IterMut;
-use std::{default, fmt};
+use std::io::{prelude::*, Cursor, ErrorKind, Seek, SeekFrom};
+use std::path::Path;
 
 use crate::*;
-//use lyso_common::util::skip_fwd;
+use lyso_common::index_build::{self, header_name, renamed, IndexEntry, Indexer, RepeatedName};
+pub use lyso_common::index_build::{DedupMode, DuplicatePolicy, IndexBuildOptions};
+use lyso_common::index_meta::{self, StaleIndex};
+use lyso_common::io::mmap::MappedFile;
+use lyso_common::io::{fetch_in_order, read_span, span_len};
+use lyso_common::name_index::NameIndex;
+use lyso_common::pos::ZeroBased;
+use lyso_common::raw::RawRecord;
+use lyso_common::region::Region;
 
 // ****************************************** //
 //               Fasta Indexing               //
 // ****************************************** //
 
+/// Index of a FASTA file, keyed by the raw bytes of each record name
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct FastaIndex {
-    inner: FxHashMap<String, FastaIndexEntry>,
+    /// Entries in file order
+    entries: Vec<FastaIndexEntry>,
+    /// Position of each name in `entries`, empty in an offsets-only index
+    by_name: NameIndex,
+    /// Built without `by_name`, see `IndexBuildOptions::name_map`
+    offsets_only: bool,
 }
 
 impl FastaIndex {
+    /// An empty index
+    #[must_use]
     pub fn new() -> Self {
         FastaIndex {
-            inner: FxHashMap::default(),
+            entries: Vec::new(),
+            by_name: NameIndex::default(),
+            offsets_only: false,
         }
     }
 
-    pub fn from_entries<I>(entries: I) -> Self
+    /// An index of `entries`, repeated names handled by `policy`
+    pub fn from_entries<I>(entries: I, policy: DuplicatePolicy) -> Result<Self, FastaError>
     where
         I: Iterator<Item = FastaIndexEntry>,
     {
         let mut idx = Self::new();
         for e in entries {
-            idx.inner.insert(e.name.clone(), e);
+            idx.insert(e, &policy)?;
+        }
+        Ok(idx)
+    }
+
+    /// Index every record of `fasta`, repeated names handled by `policy`
+    pub fn from_fasta_file<F: BufRead + Seek>(
+        fasta: &mut F,
+        policy: DuplicatePolicy,
+    ) -> Result<Self, FastaError> {
+        Self::from_indexer(FastaIndexer::new(fasta), policy)
+    }
+
+    /// Index every record `idxr` yields, repeated names handled by `policy`
+    pub fn from_indexer<F: BufRead + Seek>(
+        idxr: FastaIndexer<'_, F>,
+        policy: DuplicatePolicy,
+    ) -> Result<Self, FastaError> {
+        let mut idx = Self::new();
+        for e in idxr {
+            idx.insert(e?, &policy)?;
         }
-        idx
+        Ok(idx)
     }
 
-    pub fn from_fasta_file<F: BufRead + Seek>(fasta: &mut F) -> Self {
-        let idxr = FastaIndexer::new(fasta);
-        idxr.into()
+    /// Index every record of `fasta` as `options` say, repeated names handled by `policy`
+    pub fn from_fasta_file_with<F: BufRead + Seek>(
+        fasta: &mut F,
+        policy: DuplicatePolicy,
+        options: IndexBuildOptions,
+    ) -> Result<Self, FastaError> {
+        Self::from_indexer_with(FastaIndexer::new(fasta), policy, options)
     }
 
+    /// Index every record `idxr` yields as `options` say, repeated names handled by `policy`
+    pub fn from_indexer_with<F: BufRead + Seek>(
+        idxr: FastaIndexer<'_, F>,
+        policy: DuplicatePolicy,
+        options: IndexBuildOptions,
+    ) -> Result<Self, FastaError> {
+        if options.name_map {
+            return Self::from_indexer(idxr, policy);
+        }
+        Ok(FastaIndex {
+            entries: index_build::offsets_only(idxr, &policy, options.dedup)?,
+            by_name: NameIndex::default(),
+            offsets_only: true,
+        })
+    }
+
+    /// Whether names are mapped to entries, see `IndexBuildOptions::name_map`
+    pub fn has_name_map(&self) -> bool {
+        !self.offsets_only
+    }
+
+    /// Add the entries of a `.fai` read from `handle`
+    ///
+    /// Fails with `InvalidData` on a name repeated in the file or already in the
+    /// index, adding nothing.
     pub fn read_index(&mut self, handle: &mut impl BufRead) -> Result<(), std::io::Error> {
-        for line in handle.lines() {
-            match line? {
-                l => {
-                    let fields = l.split('\t').collect::<Vec<&str>>();
-                    if fields.len() != 5 {
-                        return Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            "malformed index",
-                        ));
+        let malformed = || std::io::Error::new(ErrorKind::InvalidData, "malformed index");
+        let mut read = Vec::new();
+        for line in handle.split(b'\n') {
+            let l = line?;
+            let l = l.strip_suffix(b"\r").unwrap_or(&l);
+            let fields = l.split(|b| *b == b'\t').collect::<Vec<&[u8]>>();
+            if fields.len() != 5 {
+                return Err(malformed());
+            }
+            let num = |i: usize| {
+                std::str::from_utf8(fields[i])
+                    .ok()
+                    .and_then(|f| f.parse::<u64>().ok())
+                    .ok_or_else(malformed)
+            };
+            let entry = FastaIndexEntry {
+                name: fields[0].to_vec(),
+                offset: num(2)?,
+                length: num(1)?,
+                linewidth: num(4)?,
+                linebases: num(3)?,
+            };
+            if entry.checked_end().is_none() {
+                return Err(malformed());
+            }
+            read.push(entry);
+        }
+        let first = self.entries.len();
+        let repeat = match self.offsets_only {
+            true => index_build::append_unique(&mut self.entries, read).err(),
+            false => {
+                let names = read.iter().map(|e| e.name.as_slice()).collect::<Vec<_>>();
+                let old = &self.entries;
+                match self.by_name.push_unique(&names, |i| &old[i].name) {
+                    Ok(()) => {
+                        self.entries.extend(read);
+                        None
                     }
-                    self.inner.insert(
-                        String::from(fields[0]),
-                        FastaIndexEntry {
-                            name: String::from(fields[0]),
-                            offset: fields[2].parse::<u64>().unwrap(),
-                            length: fields[1].parse::<u64>().unwrap(),
-                            linewidth: fields[4].parse::<u64>().unwrap(),
-                            linebases: fields[3].parse::<u64>().unwrap(),
-                        },
-                    );
+                    Err(pos) => Some(read.swap_remove(pos - first)),
+                }
+            }
+        };
+        match repeat {
+            Some(e) => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "repeated name '{}' in index",
+                    String::from_utf8_lossy(&e.name)
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Position in `entries` of the record called `name`
+    fn position(&self, name: &[u8]) -> Option<usize> {
+        match self.offsets_only {
+            true => self.entries.iter().position(|e| e.name == name),
+            false => self.by_name.find(name, |i| &self.entries[i].name),
+        }
+    }
+
+    /// Append `entry`, replacing an earlier entry of the same name in place, as
+    /// `DuplicatePolicy::KeepLast` does
+    fn push(&mut self, entry: FastaIndexEntry) {
+        match self.position(&entry.name) {
+            Some(i) => self.entries[i] = entry,
+            None => {
+                if !self.offsets_only {
+                    self.by_name.push(&entry.name);
+                }
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    /// Append `entry`, or handle its name being taken as `policy` says
+    fn insert(
+        &mut self,
+        mut entry: FastaIndexEntry,
+        policy: &DuplicatePolicy,
+    ) -> Result<(), FastaError> {
+        if let Some(i) = self.position(&entry.name) {
+            match policy {
+                DuplicatePolicy::Error => {
+                    return Err(FastaError::DuplicateName {
+                        name: String::from_utf8_lossy(&entry.name).into_owned(),
+                        offsets: Some((self.entries[i].offset, entry.offset)),
+                    })
+                }
+                DuplicatePolicy::KeepFirst => return Ok(()),
+                DuplicatePolicy::KeepLast => {}
+                DuplicatePolicy::Rename { suffix } => {
+                    entry.name = renamed(&entry.name, suffix, |n| self.position(n).is_some());
                 }
             }
         }
+        self.push(entry);
         Ok(())
     }
 
-    pub fn get(&self, id: &str) -> Option<&FastaIndexEntry> {
-        self.inner.get(id)
+    /// Write the index in samtools faidx column order, one line per entry in file order
+    ///
+    /// Fails with `InvalidInput` for an index built without its names, see
+    /// `DedupMode::Hashed`.
+    pub fn write_index(&self, mut out: impl Write) -> std::io::Result<()> {
+        if self.entries.iter().any(|e| e.name.is_empty()) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "the index was built without its names",
+            ));
+        }
+        for e in &self.entries {
+            e.write_line(&mut out)?;
+        }
+        out.flush()
+    }
+
+    /// Check that this index, loaded from `<path>.fai`, still describes `path`
+    ///
+    /// Compares `path` with `<path>.fai.meta` when present, and checks that every
+    /// entry lies within the file.
+    pub fn validate_against(&self, path: impl AsRef<Path>) -> Result<(), StaleIndex> {
+        let path = path.as_ref();
+        let data_end = self
+            .entries
+            .iter()
+            .map(|e| e.offset.saturating_add(e.span()))
+            .max();
+        index_meta::validate(path, &index_meta::fai_path(path), data_end.unwrap_or(0))
+    }
+
+    /// Entry of the record called `id`, found by a scan in an offsets-only index
+    pub fn get(&self, id: impl AsRef<[u8]>) -> Option<&FastaIndexEntry> {
+        self.position(id.as_ref()).map(|i| &self.entries[i])
     }
 
-    pub fn inner(&self) -> &FxHashMap<String, FastaIndexEntry> {
-        &self.inner
+    /// True if a record is called `id`
+    pub fn contains(&self, id: impl AsRef<[u8]>) -> bool {
+        self.position(id.as_ref()).is_some()
+    }
+
+    /// Entries in the order their records appear in the file
+    pub fn entries(&self) -> impl Iterator<Item = &FastaIndexEntry> {
+        self.entries.iter()
+    }
+
+    /// The `n`-th (0-based) record in file order
+    pub fn nth_entry(&self, n: usize) -> Option<&FastaIndexEntry> {
+        self.entries.get(n)
+    }
+
+    /// File offset of the '>' line of the `n`-th record
+    ///
+    /// Computed from the end of the previous record, so it assumes nothing sits
+    /// between records and that the file starts with the first one.
+    /// `n == len()` gives the end of the last record.
+    pub fn record_start(&self, n: usize) -> Option<u64> {
+        match n {
+            0 => Some(0),
+            n if n <= self.len() => self.nth_entry(n - 1).map(|e| e.record_end()),
+            _ => None,
+        }
+    }
+
+    /// Number of records
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True if there are no records
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Fetch a record by id through an externally provided handle
+    ///
+    /// Takes `&self`, so one index can be shared by many readers (e.g. across threads),
+    /// each bringing its own handle to the indexed file.
+    pub fn fetch<R: Read + Seek>(
+        &self,
+        handle: &mut R,
+        id: impl AsRef<[u8]>,
+    ) -> Result<Record, FetchError> {
+        match self.get(&id) {
+            Some(entry) => read_entry(handle, entry, usize::MAX),
+            None => Err(not_found(id.as_ref())),
+        }
+    }
+
+    /// Fetch the subsequence covered by `region` through an externally provided handle
+    ///
+    /// The returned record is named after the region, as samtools faidx does.
+    /// An end past the sequence is clamped; a start past it is an error.
+    pub fn fetch_region<R: Read + Seek>(
+        &self,
+        handle: &mut R,
+        region: &Region,
+    ) -> Result<Record, FetchError> {
+        self.fetch_region_within(handle, region, usize::MAX)
+    }
+
+    /// `fetch_region`, failing with `TooLarge` past `limit` bytes
+    fn fetch_region_within<R: Read + Seek>(
+        &self,
+        handle: &mut R,
+        region: &Region,
+        limit: usize,
+    ) -> Result<Record, FetchError> {
+        let entry = self
+            .get(region.name())
+            .ok_or_else(|| FetchError::NotFound(region.name().to_string()))?;
+        let len = ZeroBased::new(entry.length);
+        let end = region.end().map_or(len, |e| e.min(len));
+        // a whole record is fetched even when it has no sequence
+        if region.start() >= len && !region.is_whole() {
+            return Err(FetchError::OutOfBounds(region.to_string()));
+        }
+        let from = entry.file_offset(region.start());
+        let to = entry.file_offset(end);
+        let id = region.to_string().into_bytes();
+        let len = span_len(&id, to - from, limit)?;
+        handle.seek(SeekFrom::Start(from))?;
+        Ok(Record {
+            seq: String::from_utf8(read_span(handle, len)?)?,
+            id,
+        })
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+fn not_found(id: &[u8]) -> FetchError {
+    FetchError::NotFound(String::from_utf8_lossy(id).into_owned())
+}
+
+/// One line of a `.fai`: where a record's sequence lies and how it is wrapped
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct FastaIndexEntry {
-    name: String,
+    name: Vec<u8>,
     offset: u64,
     length: u64,
     linewidth: u64,
@@ -93,15 +358,21 @@ impl fmt::Display for FastaIndexEntry {
         write!(
             f,
             "{}\t{}\t{}\t{}\t{}",
-            self.name, self.length, self.offset, self.linebases, self.linewidth
+            self.name_str_lossy(),
+            self.length,
+            self.offset,
+            self.linebases,
+            self.linewidth
         )
     }
 }
 
 impl FastaIndexEntry {
+    /// An empty entry
+    #[must_use]
     pub fn new() -> Self {
         FastaIndexEntry {
-            name: "".into(),
+            name: Vec::new(),
             offset: 0,
             length: 0,
             linewidth: 0,
@@ -109,6 +380,17 @@ impl FastaIndexEntry {
         }
     }
 
+    /// Write the entry as a `.fai` line, with the name as raw bytes
+    fn write_line(&self, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(&self.name)?;
+        writeln!(
+            out,
+            "\t{}\t{}\t{}\t{}",
+            self.length, self.offset, self.linebases, self.linewidth
+        )
+    }
+
+    /// Reset to an empty entry
     pub fn clear(&mut self) {
         self.name.clear();
         self.offset = 0;
@@ -117,6 +399,7 @@ impl FastaIndexEntry {
         self.linebases = 0;
     }
 
+    /// True if no field is set
     pub fn empty(&self) -> bool {
         self.name.is_empty()
             && (*self.offset() == 0)
@@ -125,47 +408,114 @@ impl FastaIndexEntry {
             && (*self.linebases() == 0)
     }
 
-    pub fn name(&self) -> &str {
-        self.name.as_ref()
+    /// Record name, up to the first whitespace of the header
+    pub fn name(&self) -> &[u8] {
+        &self.name
     }
 
+    /// The name as text, with invalid UTF-8 replaced by U+FFFD
+    pub fn name_str_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+
+    /// File offset of the first base
     pub fn offset(&self) -> &u64 {
         &self.offset
     }
 
+    /// Number of bases
     pub fn length(&self) -> &u64 {
         &self.length
     }
 
+    /// Bytes per sequence line, terminator included
     pub fn linewidth(&self) -> &u64 {
         &self.linewidth
     }
 
+    /// Bases per sequence line
     pub fn linebases(&self) -> &u64 {
         &self.linebases
     }
+
+    /// File offset of the base at `pos`, saturating for positions no file could hold
+    pub fn file_offset(&self, pos: ZeroBased) -> u64 {
+        if self.linebases == 0 {
+            return self.offset;
+        }
+        let p = pos.get();
+        self.offset
+            .saturating_add((p / self.linebases).saturating_mul(self.linewidth))
+            .saturating_add(p % self.linebases)
+    }
+
+    /// Bytes spanned by the sequence in the file, newlines included
+    ///
+    /// Entries read from a `.fai` are checked to fit in a `u64`, as are those
+    /// built from a file.
+    pub fn span(&self) -> u64 {
+        self.checked_span().unwrap_or(u64::MAX)
+    }
+
+    fn checked_span(&self) -> Option<u64> {
+        if self.linebases == 0 {
+            return Some(0);
+        }
+        (self.length / self.linebases)
+            .checked_mul(self.linewidth)?
+            .checked_add(self.length % self.linebases)
+    }
+
+    /// File offset just past the sequence lines, including the last line terminator
+    pub fn record_end(&self) -> u64 {
+        self.checked_end().unwrap_or(u64::MAX)
+    }
+
+    /// `record_end`, if the entry is consistent and its arithmetic fits in a `u64`
+    fn checked_end(&self) -> Option<u64> {
+        if self.linewidth < self.linebases || (self.linebases == 0 && self.length > 0) {
+            return None;
+        }
+        let partial = self.linebases > 0 && !self.length.is_multiple_of(self.linebases);
+        let terminator = if partial {
+            self.linewidth - self.linebases
+        } else {
+            0
+        };
+        self.offset
+            .checked_add(self.checked_span()?)?
+            .checked_add(terminator)
+    }
 }
 
+/// Iterator over the index entries of a FASTA file, read from the start of the handle
 pub struct FastaIndexer<'a, R: 'a> {
     handle: &'a mut R,
-    buffer: String,
+    buffer: Vec<u8>,
 }
 
 impl<'a, F> FastaIndexer<'a, F>
 where
     F: BufRead + Seek,
 {
+    /// Index `f` from its current position
+    #[must_use]
     pub fn new(f: &'a mut F) -> Self {
         FastaIndexer {
             handle: f,
-            buffer: "".into(),
+            buffer: Vec::new(),
         }
     }
 
+    /// Fill `record` with the entry of the next record, leaving it empty at EOF
+    ///
+    /// A record with no sequence gets a zero-length entry, as `FastaReader`
+    /// returns it with an empty sequence.
     pub fn make_index(&mut self, record: &mut FastaIndexEntry) -> Result<(), FastaError> {
-        match self.handle.read_line(&mut self.buffer) {
+        self.buffer.clear();
+        match self.handle.read_until(b'\n', &mut self.buffer) {
             Ok(0) if record.empty() => return Ok(()), // EOF
-            Ok(_) => self.buffer.retain(|c| c != '\n'),
+            Ok(_) => self.buffer.retain(|c| *c != b'\n' && *c != b'\r'),
             Err(e) => return Err(FastaError::IoError(e)),
         };
 
@@ -174,43 +524,106 @@ where
             return Ok(());
         }
 
-        if !self.buffer.starts_with("@") {
+        if !self.buffer.starts_with(b">") {
             return Err(FastaError::MissingId);
         }
 
         // assume all content after first whitespace is description
-        let mut header = self.buffer[1..].trim_end().splitn(2, char::is_whitespace);
+        let mut header = self.buffer[1..]
+            .trim_ascii_end()
+            .splitn(2, u8::is_ascii_whitespace);
         match header.next() {
-            Some(v) => record.name = v.to_string(),
-            None => return Err(FastaError::TruncatedId),
+            Some(v) if !v.is_empty() => record.name = v.to_vec(),
+            _ => return Err(FastaError::TruncatedId),
         }
-        record.offset = self.handle.stream_position()? as u64;
+        record.offset = self.handle.stream_position()?;
 
-        // read first sequence line
+        // sequence lines run until the next header or EOF
         // don't count newline for nbases
-        self.buffer.clear();
-        record.linewidth = self.handle.read_line(&mut self.buffer)? as u64;
-        record.linebases = self.buffer.trim_end().len() as u64;
-
-        let mut seq_lines = 0;
-        while !self.buffer.is_empty() && !self.buffer.starts_with("+") {
-            record.length += self.buffer.trim_end().len() as u64;
-            self.buffer.clear();
-            match self.handle.read_line(&mut self.buffer) {
-                Ok(0) => return Err(FastaError::EofError),
-                Ok(_) => (),
+        let mut first = true;
+        loop {
+            match self.handle.fill_buf() {
+                Ok([]) => break,
+                Ok([b'>', ..]) => break,
+                Ok(_) => {}
                 Err(e) => return Err(FastaError::IoError(e)),
             }
+            self.buffer.clear();
+            let width = self.handle.read_until(b'\n', &mut self.buffer)? as u64;
+            let bases = self.buffer.trim_ascii_end().len() as u64;
+            if first {
+                record.linewidth = width;
+                record.linebases = bases;
+                first = false;
+            }
+            record.length += bases;
         }
-
-        let skip_to = record.length + 1;
-        self.buffer.clear();
-        // skip to start of next FastqRecord
-        skip_fwd(&mut self.handle, skip_to);
         Ok(())
     }
 }
 
+impl<F> FastaIndexer<'_, F>
+where
+    F: BufRead + Seek,
+{
+    /// Write a `.fai` line for each record as it is indexed, without holding the index
+    ///
+    /// See `index_build::write_index`: repeated names are found through their
+    /// hashes, and `DuplicatePolicy::KeepLast` fails with `InvalidInput`.
+    /// Returns the number of lines written.
+    pub fn write_index(
+        self,
+        policy: &DuplicatePolicy,
+        out: impl Write,
+    ) -> Result<usize, FastaError> {
+        index_build::write_index(self, policy, out)
+    }
+}
+
+impl<F> Indexer for FastaIndexer<'_, F>
+where
+    F: BufRead + Seek,
+{
+    type Entry = FastaIndexEntry;
+    type Error = FastaError;
+    type Handle = F;
+
+    fn handle(&mut self) -> &mut F {
+        self.handle
+    }
+
+    fn next_entry(&mut self) -> Result<Option<FastaIndexEntry>, FastaError> {
+        self.next().transpose()
+    }
+}
+
+impl IndexEntry for FastaIndexEntry {
+    fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    fn set_name(&mut self, name: Vec<u8>) {
+        self.name = name;
+    }
+
+    fn seq_offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn write_line(&self, mut out: &mut dyn Write) -> std::io::Result<()> {
+        FastaIndexEntry::write_line(self, &mut out)
+    }
+}
+
+impl From<RepeatedName> for FastaError {
+    fn from(value: RepeatedName) -> Self {
+        FastaError::DuplicateName {
+            name: String::from_utf8_lossy(&value.name).into_owned(),
+            offsets: Some((value.first, value.second)),
+        }
+    }
+}
+
 impl<'a, F> Iterator for FastaIndexer<'a, F>
 where
     F: BufRead + Seek,
@@ -227,54 +640,750 @@ where
     }
 }
 
-impl<'a, F> Into<FastaIndex> for FastaIndexer<'a, F>
+/// Fails on a malformed record or, under the default policy, a repeated name
+impl<'a, F> TryFrom<FastaIndexer<'a, F>> for FastaIndex
 where
     F: BufRead + Seek,
 {
-    fn into(self) -> FastaIndex {
-        FastaIndex::from_entries(self.into_iter().map(|x| x.unwrap()))
+    type Error = FastaError;
+
+    fn try_from(idxr: FastaIndexer<'a, F>) -> Result<FastaIndex, FastaError> {
+        FastaIndex::from_indexer(idxr, DuplicatePolicy::default())
     }
 }
 
+/// Random access to an indexed FASTA file
+///
+/// Owns the file handle; see `FastaIndex::fetch` for sharing one index
+/// between several handles (e.g. one per thread).
+///
+/// ```
+/// use std::io::Cursor;
+/// use lyso_fasta::indexer::{DuplicatePolicy, FastaIndex, IndexedFasta};
+///
+/// let fasta = b">chr1 first\nACGTAC\nGT\n>chr2\nTTTT\n";
+/// let index = FastaIndex::from_fasta_file(&mut Cursor::new(&fasta[..]), DuplicatePolicy::Error)
+///     .unwrap();
+/// assert_eq!(index.get("chr1").map(|e| *e.length()), Some(8));
+///
+/// let mut reader = IndexedFasta::new(Cursor::new(&fasta[..]), &index);
+/// let rec = reader.get_region(&"chr1:5-8".parse().unwrap()).unwrap();
+/// assert_eq!(rec.seq(), "ACGT");
+/// ```
 pub struct IndexedFasta<'a, F> {
     index: &'a FastaIndex,
     handle: F,
+    max_record_size: usize,
 }
 
 impl<'a, F> IndexedFasta<'a, F>
 where
-    F: BufRead + Seek,
+    F: Read + Seek,
 {
+    /// Fetch records from `handle` through `index`
+    #[must_use]
     pub fn new(handle: F, index: &'a FastaIndex) -> Self {
         IndexedFasta {
             index,
             handle,
+            max_record_size: usize::MAX,
         }
     }
 
-    pub fn get(&mut self, id: &str, rec: Record) -> Result<(), std::io::Error> {
-        if let Some(idx) = self.index.get(id) {
-            rec.clear();
-            rec.set_id(idx.name.clone());
+    /// Fail fetches spanning more than `bytes` bytes of the file with `FetchError::TooLarge`
+    ///
+    /// Fetches are always limited to what fits in memory addresses, which on 32-bit
+    /// platforms is less than some chromosomes.
+    #[must_use]
+    pub fn max_record_size(mut self, bytes: usize) -> Self {
+        self.max_record_size = bytes;
+        self
+    }
 
-            self.handle.seek(SeekFrom::Start(idx.offset))?;
-            let nlines = idx.length / idx.linebases;
-            let mut buf: Vec<u8> = vec![0 as u8; (nlines * idx.linewidth) as usize];
-            self.handle.read_exact(&mut buf)?;
-            buf.retain(|c| *c != b'\n');
-            rec.(String::from_utf8_lossy(&buf).into_owned());
+    /// The index used for fetching
+    pub fn index(&self) -> &FastaIndex {
+        self.index
+    }
 
-            return Ok(());
+    /// Fetch a single record by id
+    pub fn get(&mut self, id: impl AsRef<[u8]>) -> Result<Record, FetchError> {
+        match self.index.get(&id) {
+            Some(entry) => read_entry(&mut self.handle, entry, self.max_record_size),
+            None => Err(not_found(id.as_ref())),
+        }
+    }
+
+    /// Fetch the `n`-th (0-based) record in file order
+    ///
+    /// In an index built without its names, the name is read from the header.
+    pub fn nth_record(&mut self, n: usize) -> Result<Record, FetchError> {
+        let entry = self
+            .index
+            .nth_entry(n)
+            .ok_or_else(|| FetchError::NotFound(format!("record {n}")))?;
+        let mut rec = read_entry(&mut self.handle, entry, self.max_record_size)?;
+        if rec.id.is_empty() {
+            rec.id = header_name(&mut self.handle, entry.offset)?;
+        }
+        Ok(rec)
+    }
+
+    /// The whole '>' line of the `n`-th record in file order, without the '>'
+    ///
+    /// Unlike the index name, this keeps the description. The line is read back
+    /// from the file, between the end of the previous record and the start of
+    /// this one's sequence; when no header naming the record is found there, the
+    /// index name is returned.
+    pub fn nth_header(&mut self, n: usize) -> Result<Vec<u8>, FetchError> {
+        let entry = self
+            .index
+            .nth_entry(n)
+            .ok_or_else(|| FetchError::NotFound(format!("record {n}")))?;
+        let start = self.index.record_start(n).unwrap_or(0).min(entry.offset);
+        let len = span_len(&entry.name, entry.offset - start, self.max_record_size)?;
+        self.handle.seek(SeekFrom::Start(start))?;
+        let mut bytes = vec![0; len];
+        self.handle.read_exact(&mut bytes)?;
+        let line = bytes.strip_suffix(b"\n").unwrap_or(&bytes);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = &line[line.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1)..];
+        match line.strip_prefix(b">") {
+            Some(header) if header.starts_with(&entry.name) => Ok(header.to_vec()),
+            _ => Ok(entry.name.clone()),
+        }
+    }
+
+    /// Fetch the subsequence covered by `region`
+    pub fn get_region(&mut self, region: &Region) -> Result<Record, FetchError> {
+        self.index
+            .fetch_region_within(&mut self.handle, region, self.max_record_size)
+    }
+
+    /// Fetch many records in one forward sweep through the file
+    ///
+    /// Entries are visited in file-offset order so the handle only ever seeks forward;
+    /// results are returned in the order of `ids`. Unknown ids produce
+    /// `FetchError::NotFound` in their slot without affecting the others.
+    pub fn get_many<S: AsRef<[u8]>>(&mut self, ids: &[S]) -> Vec<Result<Record, FetchError>> {
+        let (handle, limit) = (&mut self.handle, self.max_record_size);
+        let mut pos: Option<u64> = None;
+        fetch_in_order(entries(self.index, ids), |entry| {
+            let res = sweep_to(handle, pos, entry.offset)
+                .map_err(FetchError::IoError)
+                .and_then(|_| read_seq(handle, entry, limit));
+            pos = res.as_ref().ok().map(|_| entry.offset + entry.span());
+            res
+        })
+    }
+
+    /// Fetch many records as they appear in the file, from the '>' to the end of
+    /// their sequence lines
+    ///
+    /// Unlike `get_many`, this keeps the description and the line breaks. The
+    /// bytes of the last record end where the file does, with or without a final
+    /// line terminator.
+    pub fn get_many_raw<S: AsRef<[u8]>>(
+        &mut self,
+        ids: &[S],
+    ) -> Vec<Result<RawRecord, FetchError>> {
+        let (handle, limit) = (&mut self.handle, self.max_record_size);
+        fetch_in_order(entries(self.index, ids), |entry| {
+            index_build::read_raw(handle, &entry.name, entry.offset, entry.record_end(), limit)
+        })
+    }
+
+    /// Fetch many regions, visiting them in file-offset order
+    ///
+    /// Results are returned in the order of `regions`; a region that cannot be
+    /// fetched gets its error in its slot without affecting the others.
+    pub fn get_regions(&mut self, regions: &[Region]) -> Vec<Result<Record, FetchError>> {
+        let index = self.index;
+        let items = regions.iter().map(|region| match index.get(region.name()) {
+            Some(entry) => Ok(((entry.file_offset(region.start()), region.end()), region)),
+            None => Err(FetchError::NotFound(region.name().to_string())),
+        });
+        fetch_in_order(items, |region| {
+            index.fetch_region_within(&mut self.handle, region, self.max_record_size)
+        })
+    }
+
+    /// The file handle
+    pub fn into_inner(self) -> F {
+        self.handle
+    }
+}
+
+impl<'a> IndexedFasta<'a, Cursor<&MappedFile>> {
+    /// Ask the OS to read the records of `ids` ahead of fetching them
+    ///
+    /// For a memory-mapped file, each record's bytes are advised with
+    /// `Advice::WillNeed`, so the pages are read in while `get_many` works through
+    /// the earlier ones; for a buffered file this does nothing. Unknown ids are
+    /// skipped.
+    ///
+    /// ```
+    /// # use std::io::{Cursor, Write};
+    /// use lyso_common::io::mmap::{Backend, MappedFile};
+    /// use lyso_fasta::indexer::{DuplicatePolicy, FastaIndex, IndexedFasta};
+    ///
+    /// # let mut tmp = tempfile::NamedTempFile::new().unwrap();
+    /// # tmp.write_all(b">chr1\nACGT\n>chr2\nTTTT\n").unwrap();
+    /// # let path = tmp.path();
+    /// let mapped = MappedFile::open(path, Backend::Mmap).unwrap();
+    /// let index = FastaIndex::from_fasta_file(&mut mapped.cursor(), DuplicatePolicy::Error)
+    ///     .unwrap();
+    /// let mut fasta = IndexedFasta::new(Cursor::new(&mapped), &index);
+    /// fasta.prefetch(&["chr2", "chr1"]).unwrap();
+    /// let recs = fasta.get_many(&["chr2", "chr1"]);
+    /// assert_eq!(recs[0].as_ref().unwrap().seq(), "TTTT");
+    /// ```
+    pub fn prefetch<S: AsRef<[u8]>>(&self, ids: &[S]) -> std::io::Result<()> {
+        let mut spans = ids
+            .iter()
+            .filter_map(|id| self.index.get(id))
+            .map(|entry| (entry.offset, entry.offset + entry.span()))
+            .collect::<Vec<_>>();
+        spans.sort_unstable();
+        // one advice per run of records at most a page apart
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in spans {
+            match runs.last_mut() {
+                Some(run) if start <= run.1 + PREFETCH_GAP => run.1 = run.1.max(end),
+                _ => runs.push((start, end)),
+            }
+        }
+        let file = *self.handle.get_ref();
+        for (start, end) in runs {
+            // a span past usize is past the end of any mapping
+            if let (Ok(offset), Ok(len)) = (usize::try_from(start), usize::try_from(end - start)) {
+                file.will_need(offset, len)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IndexedFasta<'a, File> {
+    /// Open `path` for fetching through `index`, failing if the index is stale
+    pub fn from_path(path: impl AsRef<Path>, index: &'a FastaIndex) -> Result<Self, StaleIndex> {
+        index.validate_against(&path)?;
+        Ok(IndexedFasta::new(File::open(path)?, index))
+    }
+}
+
+/// Largest gap between records `prefetch` advises as one range
+const PREFETCH_GAP: u64 = 4096;
+
+/// Largest gap a sweep skips by reading instead of seeking,
+/// so buffered handles get to keep their buffer
+const SWEEP_SKIP_LIMIT: u64 = 1 << 16;
+
+/// Move forward from `pos` (if known) to `target`
+fn sweep_to<R: Read + Seek>(handle: &mut R, pos: Option<u64>, target: u64) -> std::io::Result<()> {
+    match pos {
+        Some(p) if target >= p && target - p <= SWEEP_SKIP_LIMIT => {
+            let gap = target - p;
+            if std::io::copy(&mut handle.by_ref().take(gap), &mut std::io::sink())? != gap {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+            }
+        }
+        _ => {
+            handle.seek(SeekFrom::Start(target))?;
         }
-        Err(std::io::Error::new(ErrorKind::NotFound, "id not found"))
     }
+    Ok(())
+}
+
+/// Read the sequence described by `entry` from `handle`, if it spans at most `limit` bytes
+fn read_entry<R: Read + Seek>(
+    handle: &mut R,
+    entry: &FastaIndexEntry,
+    limit: usize,
+) -> Result<Record, FetchError> {
+    handle.seek(SeekFrom::Start(entry.offset))?;
+    read_seq(handle, entry, limit)
 }
 
-fn skip_fwd<R: BufRead>(handle: &mut R, offset: u64) {
-    std::io::copy(&mut handle.by_ref().take(offset), &mut std::io::sink()).unwrap();
+/// The entry of each id, keyed by its offset, or `NotFound`
+fn entries<'i, S: AsRef<[u8]>>(
+    index: &'i FastaIndex,
+    ids: &'i [S],
+) -> impl Iterator<Item = Result<(u64, &'i FastaIndexEntry), FetchError>> {
+    ids.iter().map(|id| match index.get(id) {
+        Some(entry) => Ok((entry.offset, entry)),
+        None => Err(not_found(id.as_ref())),
+    })
+}
+
+/// Read the sequence described by `entry`, assuming `handle` is positioned at its offset
+fn read_seq<R: Read>(
+    handle: &mut R,
+    entry: &FastaIndexEntry,
+    limit: usize,
+) -> Result<Record, FetchError> {
+    let len = span_len(&entry.name, entry.span(), limit)?;
+    Ok(Record {
+        id: entry.name.clone(),
+        seq: String::from_utf8(read_span(handle, len)?)?,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    fn test_make_index() {}
+    use super::*;
+    use lyso_common::io::mmap::Backend;
+    use std::io::{BufReader, Cursor};
+
+    const FA_PATH: &str = "../resources/test_data/test.fa";
+
+    fn build_index() -> FastaIndex {
+        let mut b = BufReader::new(File::open(FA_PATH).unwrap());
+        FastaIndex::from_fasta_file(&mut b, Default::default()).unwrap()
+    }
+
+    #[test]
+    fn test_make_index() {
+        let mut fa = Cursor::new(b">a desc\nACGT\nAC\n>b\r\nGGGG\r\nGG\r\n".to_vec());
+        let entries = FastaIndexer::new(&mut fa)
+            .collect::<Result<Vec<FastaIndexEntry>, FastaError>>()
+            .unwrap();
+        assert_eq!(entries[0].to_string(), "a\t6\t8\t4\t5");
+        assert_eq!(entries[1].to_string(), "b\t6\t20\t4\t6");
+    }
+
+    #[test]
+    fn test_write_index_round_trip() {
+        let idx = build_index();
+        let mut first = Vec::new();
+        idx.write_index(&mut first).unwrap();
+        assert!(first.starts_with(b"SRR22092847.1.1\t74\t17\t37\t38\n"));
+        let mut loaded = FastaIndex::new();
+        loaded.read_index(&mut &first[..]).unwrap();
+        assert_eq!(loaded, idx);
+        let mut second = Vec::new();
+        loaded.write_index(&mut second).unwrap();
+        assert_eq!(first, second);
+
+        let names = loaded.entries().map(|e| e.name()).collect::<Vec<&[u8]>>();
+        assert_eq!(names[..2], [b"SRR22092847.1.1", b"SRR22092847.1.2"]);
+        assert!(loaded.contains("SRR22092847.1.2") && !loaded.contains("nope"));
+    }
+
+    #[test]
+    fn test_read_index_repeated_name() {
+        let err = FastaIndex::new()
+            .read_index(&mut "a\t4\t3\t4\t5\nb\t2\t11\t2\t3\na\t4\t17\t4\t5\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "repeated name 'a' in index");
+
+        // a name already in the index, with or without a name map
+        let fai = "SRR22092847.1.2\t1\t0\t1\t2\n";
+        let options = IndexBuildOptions {
+            name_map: false,
+            ..Default::default()
+        };
+        let mut handle = BufReader::new(File::open(FA_PATH).unwrap());
+        let light = FastaIndex::from_fasta_file_with(&mut handle, Default::default(), options);
+        for mut idx in [build_index(), light.unwrap()] {
+            let before = idx.clone();
+            assert!(idx.read_index(&mut fai.as_bytes()).is_err());
+            assert_eq!(idx, before);
+        }
+    }
+
+    #[test]
+    fn test_non_utf8_name() {
+        let fa = b">r\xe9ad x\nACGT\n>b\nGG\n".to_vec();
+        let idx =
+            FastaIndex::from_fasta_file(&mut Cursor::new(fa.clone()), Default::default()).unwrap();
+        assert_eq!(idx.nth_entry(0).unwrap().name(), b"r\xe9ad");
+        let mut indexed = IndexedFasta::new(Cursor::new(fa), &idx);
+        let rec = indexed.get(b"r\xe9ad").unwrap();
+        assert_eq!((rec.id(), rec.seq()), (&b"r\xe9ad"[..], "ACGT"));
+
+        let mut fai = Vec::new();
+        fai.extend_from_slice(b"r\xe9ad\t4\t8\t4\t5\n");
+        let mut loaded = FastaIndex::new();
+        loaded.read_index(&mut &fai[..]).unwrap();
+        assert_eq!(loaded.get(b"r\xe9ad"), idx.get(b"r\xe9ad"));
+    }
+
+    #[test]
+    fn test_get() {
+        let idx = build_index();
+        let mut fa = IndexedFasta::new(File::open(FA_PATH).unwrap(), &idx);
+        let rec = fa.get("SRR22092847.1.2").unwrap();
+        assert_eq!(rec.id(), b"SRR22092847.1.2");
+        assert_eq!(
+            rec.seq(),
+            "TTTGCCCTGGAGCGATTTGTCTTTATGTGCTTTAAGCTTTGCCCTGGAGCGATTTGTCTTTATGTGCTTTAAGC"
+        );
+        assert!(matches!(fa.get("nope"), Err(FetchError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_fetch_shared_index() {
+        let idx = build_index();
+        let mut h1 = File::open(FA_PATH).unwrap();
+        let mut h2 = File::open(FA_PATH).unwrap();
+        assert_eq!(
+            idx.fetch(&mut h1, "SRR22092847.2.1").unwrap(),
+            idx.fetch(&mut h2, "SRR22092847.2.1").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_region() {
+        let mut fa = Cursor::new(b">a\nACGTA\nCGTAC\nGT\n>b\nTT\n".to_vec());
+        let idx = FastaIndex::from_fasta_file(&mut fa, Default::default()).unwrap();
+        let mut fa = IndexedFasta::new(fa, &idx);
+        let get = |fa: &mut IndexedFasta<_>, r: &str| fa.get_region(&r.parse().unwrap());
+        assert_eq!(get(&mut fa, "a:1-1").unwrap().seq(), "A");
+        assert_eq!(get(&mut fa, "a:4-7").unwrap().seq(), "TACG");
+        assert_eq!(get(&mut fa, "a:4-7").unwrap().id(), b"a:4-7");
+        assert_eq!(get(&mut fa, "a:10").unwrap().seq(), "CGT");
+        assert_eq!(get(&mut fa, "a:11-100").unwrap().seq(), "GT");
+        assert_eq!(get(&mut fa, "a").unwrap().seq(), "ACGTACGTACGT");
+        assert_eq!(get(&mut fa, "b:2").unwrap().seq(), "T");
+        assert!(matches!(
+            get(&mut fa, "b:3-4"),
+            Err(FetchError::OutOfBounds(_))
+        ));
+        assert!(matches!(
+            get(&mut fa, "c:1-2"),
+            Err(FetchError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_many_preserves_order() {
+        let idx = build_index();
+        let mut fa = IndexedFasta::new(File::open(FA_PATH).unwrap(), &idx);
+        let ids = [
+            "SRR22092847.3.2",
+            "SRR22092847.1.1",
+            "missing",
+            "SRR22092847.2.1",
+            "SRR22092847.1.1",
+        ];
+        let many = fa.get_many(&ids);
+        assert_eq!(many.len(), ids.len());
+        for (id, res) in ids.iter().zip(many) {
+            match fa.get(id) {
+                Ok(rec) => assert_eq!(res.unwrap(), rec),
+                Err(_) => assert!(matches!(res, Err(FetchError::NotFound(ref m)) if m == id)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_many_raw() {
+        let text = b">a first\r\nACGT\r\nAC\r\n>b\nGG\nG";
+        let idx = FastaIndex::from_fasta_file(&mut Cursor::new(text), Default::default()).unwrap();
+        let mut fa = IndexedFasta::new(Cursor::new(text), &idx);
+        let many = fa.get_many_raw(&["b", "missing", "a"]);
+        let b = many[0].as_ref().unwrap();
+        assert_eq!((&b.bytes[..], b.id()), (&b">b\nGG\nG"[..], &b"b"[..]));
+        assert!(matches!(many[1], Err(FetchError::NotFound(_))));
+        let a = many[2].as_ref().unwrap();
+        assert_eq!(a.bytes, b">a first\r\nACGT\r\nAC\r\n");
+        assert_eq!(a.id(), b"a");
+
+        let mut cut = IndexedFasta::new(Cursor::new(&text[..15]), &idx);
+        assert!(matches!(
+            cut.get_many_raw(&["a"])[0],
+            Err(FetchError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let idx = build_index();
+        for backend in [Backend::Mmap, Backend::Read] {
+            let mapped = MappedFile::open(FA_PATH, backend).unwrap();
+            let mut fa = IndexedFasta::new(Cursor::new(&mapped), &idx);
+            let ids = ["SRR22092847.3.2", "missing", "SRR22092847.1.1"];
+            fa.prefetch(&ids).unwrap();
+            let many = fa.get_many(&ids);
+            let mut file = IndexedFasta::new(File::open(FA_PATH).unwrap(), &idx);
+            for (id, res) in ids.iter().zip(many) {
+                match file.get(id) {
+                    Ok(rec) => assert_eq!(res.unwrap(), rec),
+                    Err(_) => assert!(res.is_err()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_regions() {
+        let idx = build_index();
+        let mut fa = IndexedFasta::new(File::open(FA_PATH).unwrap(), &idx);
+        let regions = [
+            "SRR22092847.3.2:5-10",
+            "missing:1-3",
+            "SRR22092847.1.1",
+            "SRR22092847.1.1:2-3",
+            "SRR22092847.1.1:900",
+        ]
+        .map(|r| r.parse::<Region>().unwrap());
+        let many = fa.get_regions(&regions);
+        assert_eq!(many.len(), regions.len());
+        for (region, res) in regions.iter().zip(many) {
+            match fa.get_region(region) {
+                Ok(rec) => assert_eq!(res.unwrap(), rec),
+                Err(e) => assert_eq!(res.unwrap_err().to_string(), e.to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_huge_entries() {
+        // a 5 GiB sequence in 60-base lines, of which only the first lines exist
+        let five_gib = 5u64 << 30;
+        let fai = format!("big\t{five_gib}\t5\t60\t61\n");
+        let mut idx = FastaIndex::new();
+        idx.read_index(&mut fai.as_bytes()).unwrap();
+        let entry = idx.get("big").unwrap();
+        assert_eq!(entry.span(), five_gib / 60 * 61 + five_gib % 60);
+        let fasta = format!(">big\n{}", format!("{}\n", "ACGT".repeat(15)).repeat(4));
+        let mut fa =
+            IndexedFasta::new(Cursor::new(fasta.as_bytes()), &idx).max_record_size(1 << 30);
+        match fa.get("big") {
+            Err(FetchError::TooLarge(e)) => {
+                assert_eq!(
+                    (e.id.as_str(), e.bytes, e.limit),
+                    ("big", entry.span(), 1 << 30)
+                );
+                assert!(e.to_string().contains("too large for this platform"));
+            }
+            other => panic!("expected TooLarge, got {other:?}"),
+        }
+        assert!(matches!(
+            fa.get_many(&["big"])[0],
+            Err(FetchError::TooLarge(_))
+        ));
+        // a region of it still fits
+        let region = "big:59-62".parse::<Region>().unwrap();
+        assert_eq!(fa.get_region(&region).unwrap().seq(), "GTAC");
+        let whole = "big".parse::<Region>().unwrap();
+        assert!(matches!(
+            fa.get_region(&whole),
+            Err(FetchError::TooLarge(_))
+        ));
+
+        // without a limit, the span fails by running out of file rather than memory
+        let mut fa = IndexedFasta::new(Cursor::new(fasta.as_bytes()), &idx);
+        let err = fa.get("big").unwrap_err();
+        if usize::BITS < 64 {
+            assert!(matches!(err, FetchError::TooLarge(_)));
+        } else {
+            assert!(matches!(err, FetchError::IoError(e) if e.kind() == ErrorKind::UnexpectedEof));
+        }
+
+        // entries whose arithmetic overflows, or with more bases than bytes per line
+        for line in [
+            "x\t18446744073709551615\t0\t1\t2\n",
+            "x\t10\t18446744073709551610\t60\t61\n",
+            "x\t10\t0\t61\t60\n",
+            "x\t10\t0\t0\t0\n",
+        ] {
+            let err = FastaIndex::new()
+                .read_index(&mut line.as_bytes())
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_nth_record_matches_scan() {
+        let idx = build_index();
+        let bytes = std::fs::read(FA_PATH).unwrap();
+        let records = crate::reader::FastaReader::new(&bytes[..])
+            .map(|r| r.unwrap())
+            .collect::<Vec<Record>>();
+        assert_eq!(idx.len(), records.len());
+        let mut fa = IndexedFasta::new(File::open(FA_PATH).unwrap(), &idx);
+        for (n, rec) in records.iter().enumerate() {
+            assert_eq!(&fa.nth_record(n).unwrap(), rec);
+        }
+        assert!(fa.nth_record(records.len()).is_err());
+        for n in [0, 1, records.len() - 1] {
+            let start = idx.record_start(n).unwrap() as usize;
+            let rest = crate::reader::FastaReader::new(&bytes[start..])
+                .map(|r| r.unwrap())
+                .collect::<Vec<Record>>();
+            assert_eq!(rest, records[n..]);
+        }
+        assert_eq!(idx.record_start(records.len()), Some(bytes.len() as u64));
+    }
+
+    #[test]
+    fn test_stale_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ref.fa");
+        std::fs::copy(FA_PATH, &path).unwrap();
+        let write_index = |idx: &FastaIndex| {
+            let fai = index_meta::fai_path(&path);
+            idx.write_index(File::create(&fai).unwrap()).unwrap();
+            index_meta::write_meta(&path, &fai).unwrap();
+        };
+        let build = || {
+            FastaIndex::from_fasta_file(
+                &mut BufReader::new(File::open(&path).unwrap()),
+                Default::default(),
+            )
+            .unwrap()
+        };
+        let idx = build();
+        write_index(&idx);
+        assert!(IndexedFasta::from_path(&path, &idx).is_ok());
+
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(b">new\nACGT\n").unwrap();
+        assert!(matches!(
+            idx.validate_against(&path),
+            Err(StaleIndex::Changed("size"))
+        ));
+        assert!(IndexedFasta::from_path(&path, &idx).is_err());
+
+        let rebuilt = build();
+        write_index(&rebuilt);
+        let mut fa = IndexedFasta::from_path(&path, &rebuilt).unwrap();
+        assert_eq!(fa.get("new").unwrap().seq(), "ACGT");
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let fa = b">chr1 a\nACGT\n>chr2\nGG\n>chr1 b\nTTTTTT\n";
+        let build = |policy| FastaIndex::from_fasta_file(&mut Cursor::new(&fa[..]), policy);
+        let lengths = |idx: &FastaIndex| {
+            idx.entries()
+                .map(|e| (e.name_str_lossy().into_owned(), *e.length()))
+                .collect::<Vec<_>>()
+        };
+
+        let err = build(DuplicatePolicy::Error).unwrap_err();
+        assert!(matches!(
+            err,
+            FastaError::DuplicateName { ref name, offsets: Some((8, 30)) } if name == "chr1"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "duplicate record name chr1 at offsets 8 and 30"
+        );
+        assert!(FastaIndex::try_from(FastaIndexer::new(&mut Cursor::new(&fa[..]))).is_err());
+
+        let idx = build(DuplicatePolicy::KeepFirst).unwrap();
+        assert_eq!(lengths(&idx), [("chr1".into(), 4), ("chr2".into(), 2)]);
+        let idx = build(DuplicatePolicy::KeepLast).unwrap();
+        assert_eq!(lengths(&idx), [("chr1".into(), 6), ("chr2".into(), 2)]);
+        assert_eq!(*idx.get("chr1").unwrap().offset(), 30);
+        let rename = DuplicatePolicy::Rename { suffix: "_".into() };
+        let idx = build(rename).unwrap();
+        assert_eq!(
+            lengths(&idx),
+            [("chr1".into(), 4), ("chr2".into(), 2), ("chr1_1".into(), 6)]
+        );
+    }
+
+    #[test]
+    fn test_build_options() {
+        let fa = b">chr1 a\nACGT\n>chr2\nGG\n>chr1 b\nTTTTTT\n>chr1_1\nA\n";
+        let policies = [
+            DuplicatePolicy::Error,
+            DuplicatePolicy::KeepFirst,
+            DuplicatePolicy::KeepLast,
+            DuplicatePolicy::Rename { suffix: "_".into() },
+        ];
+        for policy in policies {
+            let build = |options| {
+                FastaIndex::from_fasta_file_with(&mut Cursor::new(&fa[..]), policy.clone(), options)
+                    .map_err(|e| e.to_string())
+            };
+            let full = build(IndexBuildOptions::default());
+            for dedup in [DedupMode::Exact, DedupMode::Hashed] {
+                let options = IndexBuildOptions {
+                    dedup,
+                    name_map: false,
+                };
+                let light = build(options);
+                match (&full, &light) {
+                    (Ok(full), Ok(light)) => {
+                        assert!(full.has_name_map() && !light.has_name_map());
+                        let offsets =
+                            |idx: &FastaIndex| idx.entries().map(|e| e.offset).collect::<Vec<_>>();
+                        assert_eq!(offsets(light), offsets(full), "{policy:?}, {dedup:?}");
+                        let mut fetch = IndexedFasta::new(Cursor::new(&fa[..]), light);
+                        for (n, e) in full.entries().enumerate() {
+                            assert_eq!(fetch.nth_record(n).unwrap().id(), e.name());
+                        }
+                        let chr2 = light.get("chr2");
+                        match dedup {
+                            DedupMode::Exact => assert_eq!(chr2, full.get("chr2")),
+                            DedupMode::Hashed => assert_eq!(chr2, None),
+                        }
+                    }
+                    _ => assert_eq!(light, full, "{policy:?}, {dedup:?}"),
+                }
+            }
+
+            // streamed, the lines match the index
+            let mut out = Vec::new();
+            let streamed = FastaIndexer::new(&mut Cursor::new(&fa[..]))
+                .write_index(&policy, &mut out)
+                .map(|_| out)
+                .map_err(|e| e.to_string());
+            let expected = full.as_ref().map(|idx| {
+                let mut fai = Vec::new();
+                idx.write_index(&mut fai).unwrap();
+                fai
+            });
+            match policy {
+                DuplicatePolicy::KeepLast => assert!(streamed.is_err()),
+                _ => assert_eq!(streamed, expected.map_err(|e| e.clone())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_records() {
+        // indexed as the reader reads them, with an empty sequence
+        const EMPTY_FA_PATH: &str = "../resources/test_data/empty_records.fa";
+        let bytes = std::fs::read(EMPTY_FA_PATH).unwrap();
+        let idx = FastaIndex::try_from(FastaIndexer::new(&mut Cursor::new(&bytes))).unwrap();
+        let mut fai = Vec::new();
+        idx.write_index(&mut fai).unwrap();
+        assert_eq!(
+            String::from_utf8(fai.clone()).unwrap(),
+            "empty_first\t0\t13\t0\t0\n\
+             a\t6\t21\t4\t5\n\
+             empty_middle\t0\t43\t0\t0\n\
+             b\t4\t46\t4\t5\n\
+             empty_last\t0\t63\t0\t0\n"
+        );
+        let mut loaded = FastaIndex::new();
+        loaded.read_index(&mut &fai[..]).unwrap();
+        assert_eq!(loaded, idx);
+
+        let records = crate::reader::FastaReader::new(&bytes[..])
+            .map(|r| r.unwrap())
+            .collect::<Vec<Record>>();
+        assert_eq!(idx.len(), records.len());
+        let mut fa = IndexedFasta::new(Cursor::new(&bytes), &idx);
+        for (n, rec) in records.iter().enumerate() {
+            assert_eq!(fa.nth_record(n).unwrap().seq(), rec.seq());
+        }
+        assert_eq!(fa.get("empty_middle").unwrap().seq(), "");
+        let get = |fa: &mut IndexedFasta<_>, r: &str| fa.get_region(&r.parse().unwrap());
+        assert_eq!(get(&mut fa, "empty_middle").unwrap().seq(), "");
+        assert!(matches!(
+            get(&mut fa, "empty_middle:1-1"),
+            Err(FetchError::OutOfBounds(_))
+        ));
+    }
 }
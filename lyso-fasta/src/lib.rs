@@ -1,51 +1,499 @@
+//! Reading, writing and indexing FASTA files
+//!
+//! `reader::FastaReader` streams `Record`s from any `BufRead`,
+//! `indexer::IndexedFasta` fetches regions through a `.fai` index, and
+//! `store::FastaStore` holds a small reference in memory.
+//!
+//! ```
+//! use lyso_fasta::reader::FastaReader;
+//!
+//! let input = b">chr1\nACGT\nAC\n>chr2\nGG\n";
+//! let records = FastaReader::new(&input[..])
+//!     .collect::<Result<Vec<_>, _>>()
+//!     .unwrap();
+//! assert_eq!(records[0].seq(), "ACGTAC");
+//! assert_eq!(records[1].id(), b"chr2");
+//! ```
+
+#![deny(missing_docs)]
+
+use lyso_common::detect::{Compression, Format, Mismatch};
+use lyso_common::diff::{CanonicalHash, FieldDiff, RecordDiff};
+use lyso_common::error::{LineLocation, LysoError};
+use lyso_common::filter::SeqRecord;
+use lyso_common::format::{gc_content, write_opt, RecordFormatter};
+use lyso_common::io::RecordTooLarge;
+use lyso_common::rename::RecordId;
+use lyso_common::runs::is_masked;
+use lyso_common::seqedit::SeqEdit;
+use lyso_common::sort::SortRecord;
+use lyso_common::synth::SimRecord;
+use lyso_common::validate::{Alphabet, IdCharset, Severity, Validator, Violation};
 use std::borrow::Cow;
-use std::fmt::{write, Display};
-use std::io::BufRead;
-use std::iter::Iterator;
+use std::fmt::Display;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
 use thiserror::Error;
 
-//pub mod indexer;
+pub mod clean;
+pub mod concat;
+pub mod count;
+pub mod export;
+pub mod extract;
+pub mod indexer;
+pub mod mask;
 pub mod parser;
 pub mod reader;
+pub mod split;
+pub mod store;
+pub mod windows;
+pub mod writer;
 
+/// Errors from reading FASTA
+///
+/// Errors from `reader::FastaReader` come wrapped in `AtLine` for the line they
+/// were found at, so matching a reader's error against a plain variant such as
+/// `EofError` no longer works; match on `root()` instead.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum FastaError {
-    #[error("Validation error")]
-    ValidationError(&'static str),
+    /// A record that failed a check, such as a non-UTF-8 id
+    #[error("Invalid record {record}: {reason}")]
+    ValidationError {
+        /// Header of the record
+        record: String,
+        /// What was wrong with it
+        reason: &'static str,
+    },
+    /// Input ended inside a record
     #[error("Unexpected end of file")]
     EofError,
+    /// A header line that does not start with '>'
     #[error("Missing id field")]
     MissingId,
-    #[error("Missing sequence")]
-    MissingSequenceError,
+    /// A header with no sequence after it, where one is required
+    #[error("Missing sequence for record {0}")]
+    MissingSequenceError(String),
+    /// Reading failed
     #[error("io error")]
     IoError(#[from] std::io::Error),
+    /// A sequence that is not UTF-8
     #[error("File encoding error")]
     EncodeError(#[from] std::string::FromUtf8Error),
+    /// A header with no name after the '>'
     #[error("Missing header")]
     TruncatedId,
+    /// Input that could not be parsed
     #[error("Parse error")]
     ParserError,
+    /// Compressed input, which readers do not decompress
+    #[error("input appears to be {0}-compressed; decompress it before reading")]
+    Compressed(Compression),
+    /// Input in another format
+    #[error("input looks like {0}, not FASTA")]
+    WrongFormat(Format),
+    /// Input that is mostly not printable text
+    #[error("input does not appear to be text")]
+    NotText,
+    /// A record name seen earlier in the input, where names must be unique
+    ///
+    /// Indexing under `DuplicatePolicy::Error` knows where both records are;
+    /// reading records into a `store::FastaStore` does not.
+    #[error("duplicate record name {name}{}", at_offsets(.offsets))]
+    DuplicateName {
+        /// The repeated name
+        name: String,
+        /// Offsets of the earlier and the later record's sequence, when indexing
+        offsets: Option<(u64, u64)>,
+    },
+    /// More sequence than a `store::FastaStore` was allowed to hold
+    #[error(
+        "reference holds more than {limit} bytes of sequence; \
+         index it with `lyso faidx` to fetch from disk instead"
+    )]
+    TooLarge {
+        /// The cap, in bytes
+        limit: u64,
+    },
+    /// A record longer than `ReaderConfig::max_record_size`
+    #[error("record longer than {limit} bytes")]
+    RecordTooLarge {
+        /// The limit, in bytes
+        limit: usize,
+    },
+    /// A record failing an error-level rule of the reader's `Validator`
+    #[error("record {} fails {}: {0}", .0.record, .0.rule)]
+    RuleViolation(Violation),
+    /// A parse or validation error at a line of the input, see `FastaReader`
+    #[error("{}: {source}", LineLocation::new(.path.as_deref(), *.line))]
+    AtLine {
+        /// The file, for a reader opened with `from_path`
+        path: Option<PathBuf>,
+        /// 1-based line number
+        line: u64,
+        /// The error on that line
+        source: Box<FastaError>,
+    },
+}
+
+/// " at offsets <first> and <second>", when the offsets are known
+fn at_offsets(offsets: &Option<(u64, u64)>) -> String {
+    offsets.map_or_else(String::new, |(first, second)| {
+        format!(" at offsets {first} and {second}")
+    })
+}
+
+impl FastaError {
+    /// The error without the line it was found at
+    pub fn root(&self) -> &FastaError {
+        match self {
+            FastaError::AtLine { source, .. } => source.root(),
+            e => e,
+        }
+    }
+
+    /// The 1-based line of the input the error was found at, when known
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            FastaError::AtLine { line, .. } => Some(*line),
+            _ => None,
+        }
+    }
+
+    /// Whether the reader can continue after this error
+    ///
+    /// Record-local errors (validation failures, a header with no sequence) leave
+    /// the reader positioned at the next record. Everything else, including IO
+    /// errors, a truncated final record and unparseable input, ends iteration.
+    pub fn is_terminal(&self) -> bool {
+        !matches!(
+            self.root(),
+            FastaError::ValidationError { .. }
+                | FastaError::MissingSequenceError(_)
+                | FastaError::RuleViolation(_)
+        )
+    }
+}
+
+/// Errors from fetching records through an index
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FetchError {
+    /// No record has this id
+    #[error("id not found: {0}")]
+    NotFound(String),
+    /// A region past the end of its sequence
+    #[error("region {0} is outside of the sequence")]
+    OutOfBounds(String),
+    /// Reading failed
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+    /// A sequence that is not UTF-8
+    #[error("File encoding error")]
+    EncodeError(#[from] std::string::FromUtf8Error),
+    /// A record or region spanning more bytes than a fetch may buffer
+    #[error(transparent)]
+    TooLarge(#[from] RecordTooLarge),
+}
+
+impl From<Mismatch> for FastaError {
+    fn from(value: Mismatch) -> Self {
+        match value {
+            Mismatch::Compressed(c) => FastaError::Compressed(c),
+            Mismatch::Format(f) => FastaError::WrongFormat(f),
+            Mismatch::Binary => FastaError::NotText,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+impl From<FastaError> for LysoError {
+    fn from(value: FastaError) -> Self {
+        LysoError::format(Format::Fasta, value)
+    }
+}
+
+impl From<FetchError> for LysoError {
+    fn from(value: FetchError) -> Self {
+        LysoError::format(Format::Fasta, value)
+    }
+}
+
+impl From<concat::ConcatError> for LysoError {
+    fn from(value: concat::ConcatError) -> Self {
+        LysoError::format(Format::Fasta, value)
+    }
+}
+
+impl From<extract::ExtractError> for LysoError {
+    fn from(value: extract::ExtractError) -> Self {
+        LysoError::format(Format::Fasta, value)
+    }
+}
+
+/// A FASTA record
+///
+/// The id is kept as the bytes found in the file, so headers that are not UTF-8
+/// survive a read and `write_to` unchanged. `Display` renders it lossily.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct Record {
-    id: String,
+    id: Vec<u8>,
     seq: String,
 }
 
-impl<'a> Record {
+impl Record {
+    /// An empty record
+    #[must_use]
     pub fn new() -> Self {
         Record {
-            id: String::from(""),
+            id: Vec::new(),
             seq: String::from(""),
         }
     }
+
+    /// A record from its header, without the '>', and its sequence
+    ///
+    /// ```
+    /// use lyso_fasta::Record;
+    ///
+    /// let rec = Record::from_parts("chr1 assembled", "ACGT");
+    /// assert_eq!(rec.id(), b"chr1 assembled");
+    /// assert_eq!(rec.to_string(), ">chr1 assembled\nACGT");
+    /// ```
+    #[must_use]
+    pub fn from_parts(id: impl Into<Vec<u8>>, seq: impl Into<String>) -> Self {
+        Record {
+            id: id.into(),
+            seq: seq.into(),
+        }
+    }
+
+    /// The whole header line, without the '>'
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// The id as text, with invalid UTF-8 replaced by U+FFFD
+    pub fn id_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.id)
+    }
+
+    /// The sequence, as it appeared with line breaks removed
+    pub fn seq(&self) -> &str {
+        self.seq.as_ref()
+    }
+
+    /// Number of soft-masked (lowercase) bases
+    pub fn masked_len(&self) -> usize {
+        self.seq.bytes().filter(|b| is_masked(*b)).count()
+    }
+
+    /// Replace the header
+    pub fn set_id(&mut self, id: impl Into<Vec<u8>>) {
+        self.id = id.into();
+    }
+
+    /// Fail with a `ValidationError` naming the record if the id is not UTF-8
+    pub fn check_utf8(&self) -> Result<(), FastaError> {
+        match std::str::from_utf8(&self.id) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(FastaError::ValidationError {
+                record: self.id_str_lossy().into_owned(),
+                reason: "id is not valid UTF-8",
+            }),
+        }
+    }
+
+    /// Replace the sequence
+    pub fn set_seq(&mut self, seq: String) {
+        self.seq = seq;
+    }
+
+    /// Empty the id and sequence
+    pub fn clear(&mut self) {
+        self.id.clear();
+        self.seq.clear();
+    }
+}
+
+impl Record {
+    /// Write the record with its id bytes unchanged, ending in a newline
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(b">")?;
+        out.write_all(&self.id)?;
+        out.write_all(b"\n")?;
+        out.write_all(self.seq.as_bytes())?;
+        out.write_all(b"\n")
+    }
 }
 
 impl Display for Record {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, ">{}\n", self.id)?;
+        writeln!(f, ">{}", self.id_str_lossy())?;
         write!(f, "{}", self.seq)
     }
 }
+
+impl RecordId for Record {
+    fn record_id(&self) -> &[u8] {
+        &self.id
+    }
+
+    fn set_record_id(&mut self, id: Vec<u8>) {
+        self.id = id;
+    }
+}
+
+impl SeqRecord for Record {
+    fn seq_bytes(&self) -> &[u8] {
+        self.seq.as_bytes()
+    }
+
+    fn qual_bytes(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// The rules `lyso check` applies to FASTA
+///
+/// Bases outside the IUPAC nucleotide codes are only warnings, since a FASTA
+/// file may hold protein. Ids are whole header lines, so may contain spaces.
+pub fn validator() -> Validator<Record> {
+    Validator::new()
+        .rule(IdCharset::HEADER, Severity::Error)
+        .rule(Alphabet::NUCLEOTIDE, Severity::Warning)
+}
+
+impl SeqEdit for Record {
+    fn seq_string_mut(&mut self) -> &mut String {
+        &mut self.seq
+    }
+}
+
+impl SortRecord for Record {
+    type Error = FastaError;
+
+    fn write_record(&self, mut out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_to(&mut out)
+    }
+
+    fn read_run(input: BufReader<File>) -> Box<dyn Iterator<Item = Result<Self, Self::Error>>> {
+        Box::new(reader::FastaReader::new(input))
+    }
+}
+
+/// FASTA has no qualities, so mean_qual is always "."; masked is the
+/// soft-masked (lowercase) fraction
+impl RecordFormatter for Record {
+    const COLUMNS: &'static [&'static str] = &["id", "length", "gc", "mean_qual", "masked"];
+
+    fn write_tsv(&self, out: &mut impl std::fmt::Write) -> std::fmt::Result {
+        write!(out, "{}\t{}\t", self.id_str_lossy(), self.seq.len())?;
+        write_opt(out, gc_content(self.seq.as_bytes()), 4)?;
+        write!(out, "\t.\t")?;
+        let masked =
+            (!self.seq.is_empty()).then(|| self.masked_len() as f64 / self.seq.len() as f64);
+        write_opt(out, masked, 4)
+    }
+}
+
+impl RecordDiff for Record {
+    fn diff(&self, other: &Self) -> Vec<FieldDiff> {
+        [
+            FieldDiff::bytes("id", &self.id, &other.id),
+            FieldDiff::bytes("seq", self.seq.as_bytes(), other.seq.as_bytes()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn label(&self) -> String {
+        String::from_utf8_lossy(&self.id).into_owned()
+    }
+}
+
+/// The derived hash, which covers the same fields as equality
+impl CanonicalHash for Record {
+    fn canonical_hash(&self) -> u64 {
+        let mut h = DefaultHasher::new();
+        self.hash(&mut h);
+        h.finish()
+    }
+}
+
+impl From<SimRecord> for Record {
+    /// The header is the name, then the description after a space if there is one
+    fn from(sim: SimRecord) -> Self {
+        let id = match sim.desc {
+            Some(desc) => format!("{} {desc}", sim.name),
+            None => sim.name,
+        };
+        Record::from_parts(id, sim.seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lyso_common::assert_records_eq;
+
+    fn record(id: &str, seq: &str) -> Record {
+        Record::from_parts(id, seq)
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = record("chr1", "ACGTACGT");
+        assert!(a.diff(&a.clone()).is_empty());
+        assert_records_eq!(a, a.clone());
+
+        let diffs = a.diff(&record("chr1", "ACGTTCGT"));
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].field, "seq");
+        assert_eq!(diffs[0].index, Some(4));
+
+        let diffs = a.diff(&record("chr2", "ACGT"));
+        assert_eq!(
+            diffs.iter().map(|d| (d.field, d.index)).collect::<Vec<_>>(),
+            vec![("id", Some(3)), ("seq", Some(4))]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "seq differs at byte 0")]
+    fn test_assert_records_eq_panics() {
+        assert_records_eq!(record("a", "A"), record("a", "C"));
+    }
+
+    #[test]
+    fn test_masked_len() {
+        let rec = record("chr1", "acgtNNNNACGTnnnTTaa");
+        assert_eq!(rec.masked_len(), 9);
+        assert_eq!(rec.to_tsv(), "chr1\t19\t0.2105\t.\t0.4737");
+        assert_eq!(record("empty", "").to_tsv(), "empty\t0\t.\t.\t.");
+    }
+
+    #[test]
+    fn test_tsv() {
+        assert_eq!(Record::tsv_header(), "id\tlength\tgc\tmean_qual\tmasked");
+        let f = std::fs::File::open("../resources/test_data/test.fa").unwrap();
+        let rows = crate::reader::FastaReader::new(std::io::BufReader::new(f))
+            .take(3)
+            .map(|r| r.unwrap().to_tsv())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            rows,
+            vec![
+                "SRR22092847.1.1\t74\t0.4054\t.\t0.0000",
+                "SRR22092847.1.2\t74\t0.4324\t.\t0.0000",
+                "SRR22092847.2.1\t502\t0.3785\t.\t0.0000",
+            ]
+        );
+        assert_eq!(
+            rows[0].split('\t').count(),
+            <Record as RecordFormatter>::COLUMNS.len()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Masking the bases of FASTA records covered by intervals
+//!
+//! Records are masked in their input bytes, so line wrapping, line endings and
+//! headers come out exactly as read; only bases inside an interval change. Soft
+//! masking lowercases them, hard masking replaces them with `N`. Intervals are
+//! matched to records by the first word of the header, and intervals running past
+//! the end of a record are masked up to its end.
+
+use std::io::{BufRead, Write};
+
+use lyso_common::intervals::IntervalSet;
+use lyso_common::raw::RawRecord;
+
+use crate::reader::FastaReader;
+use crate::FastaError;
+
+/// How masked bases are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaskMode {
+    /// Lowercase
+    #[default]
+    Soft,
+    /// `N`
+    Hard,
+}
+
+impl MaskMode {
+    fn apply(self, base: u8) -> u8 {
+        match self {
+            MaskMode::Soft => base.to_ascii_lowercase(),
+            MaskMode::Hard => b'N',
+        }
+    }
+}
+
+/// Mask the bases of `raw` covered by `intervals`; returns how many were masked
+///
+/// Bases already masked count too, so this is the number of bases the intervals
+/// cover within the record.
+pub fn mask_raw(raw: &mut RawRecord, intervals: &IntervalSet, mode: MaskMode) -> u64 {
+    let name = raw
+        .id()
+        .split(u8::is_ascii_whitespace)
+        .next()
+        .unwrap_or_default();
+    let covered = intervals.get(&String::from_utf8_lossy(name));
+    if covered.is_empty() {
+        return 0;
+    }
+    let seq_start = memchr::memchr(b'\n', &raw.bytes).map_or(raw.bytes.len(), |i| i + 1);
+    // intervals are sorted by start, so one pass over the bases visits them in turn
+    let (mut pos, mut next, mut end, mut masked) = (0, 0, 0, 0);
+    for b in &mut raw.bytes[seq_start..] {
+        if matches!(*b, b'\n' | b'\r') {
+            continue;
+        }
+        while next < covered.len() && covered[next].start.get() <= pos {
+            end = end.max(covered[next].end.get());
+            next += 1;
+        }
+        if pos < end {
+            *b = mode.apply(*b);
+            masked += 1;
+        } else if next == covered.len() {
+            break;
+        }
+        pos += 1;
+    }
+    masked
+}
+
+/// Copy every record from `reader` to `out`, masking the bases `intervals` cover
+///
+/// Returns the number of bases masked.
+pub fn mask_records<R: BufRead, W: Write>(
+    reader: &mut FastaReader<R>,
+    intervals: &IntervalSet,
+    mode: MaskMode,
+    out: &mut W,
+) -> Result<u64, FastaError> {
+    let mut masked = 0;
+    for raw in reader.raw_records() {
+        let mut raw = raw?;
+        masked += mask_raw(&mut raw, intervals, mode);
+        out.write_all(&raw.bytes)?;
+    }
+    Ok(masked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    fn mask(input: &[u8], bed: &str, mode: MaskMode) -> (Vec<u8>, u64) {
+        let intervals = IntervalSet::from_bed(BufReader::new(bed.as_bytes()), false).unwrap();
+        let mut out = Vec::new();
+        let masked =
+            mask_records(&mut FastaReader::new(input), &intervals, mode, &mut out).unwrap();
+        (out, masked)
+    }
+
+    #[test]
+    fn test_mask() {
+        let input = b">a desc\r\nACGT\r\nACGT\r\n>b\nACGTACGT\n>c\nAC";
+        // overlapping intervals, one across a line break, one past the end of c
+        let bed = "a\t1\t3\na\t2\t6\nb\t7\t8\nc\t1\t10\nmissing\t0\t5\n";
+        let (soft, masked) = mask(input, bed, MaskMode::Soft);
+        assert_eq!(soft, b">a desc\r\nAcgt\r\nacGT\r\n>b\nACGTACGt\n>c\nAc");
+        assert_eq!(masked, 7);
+        let (hard, _) = mask(input, bed, MaskMode::Hard);
+        assert_eq!(hard, b">a desc\r\nANNN\r\nNNGT\r\n>b\nACGTACGN\n>c\nAN");
+    }
+
+    #[test]
+    fn test_mask_nested() {
+        // the second interval lies inside the first, which must keep masking after it
+        let (out, masked) = mask(b">a\nACGTACGT\n", "a\t0\t6\na\t1\t2\n", MaskMode::Soft);
+        assert_eq!(out, b">a\nacgtacGT\n");
+        assert_eq!(masked, 6);
+    }
+}
@@ -1,7 +1,18 @@
+//! nom parsers for FASTA records
+//!
+//! A record starts at a '>' that begins a line, and its header runs to the first
+//! '\r' or '\n'. Everything in a header up to that byte belongs to it, '>'
+//! included, so `>seq1 len>500` is one header; a '>' in the middle of a sequence
+//! line is kept as a base, for validation to reject. A lone '\r' ends a line as
+//! '\n' does, so a description cannot contain one. The readers, `fast_count` and
+//! `FastaIndex` all split records this way.
+
+use memchr::{memchr2_iter, memchr_iter};
 use nom::{
-    bytes::complete::is_not,
-    bytes::streaming::{is_a, is_not as streaming_is_not, tag},
-    combinator::{map, map_res},
+    bytes::complete::is_a,
+    bytes::streaming::{is_not as streaming_is_not, tag},
+    combinator::{map, map_res, opt},
+    error::{Error, ErrorKind},
     sequence::{pair, preceded, terminated},
     IResult,
 };
@@ -16,21 +27,42 @@ fn not_line_ending(input: &[u8]) -> IResult<&[u8], &[u8]> {
     streaming_is_not("\r\n")(input)
 }
 
+/// Complete, so a header at the very end of the input still parses; the reader
+/// buffers whole records before parsing
 #[inline]
 fn line_ending(input: &[u8]) -> IResult<&[u8], &[u8]> {
     is_a("\r\n")(input)
 }
 
 #[inline]
-fn header(input: &[u8]) -> IResult<&[u8], String> {
-    map_res(
-        terminated(preceded(start, not_line_ending), line_ending),
-        |x| String::from_utf8(x.to_vec()),
-    )(input)
+fn raw_header(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    terminated(preceded(start, not_line_ending), line_ending)(input)
+}
+
+#[inline]
+fn header(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    map(raw_header, <[u8]>::to_vec)(input)
+}
+
+/// Where the next record starts in `input`: the first '>' at the start of a line
+///
+/// `line_start` says whether `input` itself starts a line.
+#[inline]
+pub(crate) fn next_record_start(input: &[u8], line_start: bool) -> Option<usize> {
+    memchr_iter(b'>', input).find(|&i| match i {
+        0 => line_start,
+        i => matches!(input[i - 1], b'\n' | b'\r'),
+    })
+}
+
+/// Whether a '>' after `prev`, the last byte read, would start a record
+#[inline]
+pub(crate) fn starts_line(prev: Option<&u8>) -> bool {
+    prev.is_none_or(|b| matches!(b, b'\n' | b'\r'))
 }
 
 /// !IMPORTANT!
-/// This parser uses bytes::complete::is_not.
+/// This parser is a complete (not streaming) scan to the next record start.
 /// Thus, you must be certain that you have read the entire
 /// sequence content into the input before parsing or else
 /// the sequence will be truncated. The reason for doing this
@@ -39,34 +71,69 @@ fn header(input: &[u8]) -> IResult<&[u8], String> {
 /// and just needing more data. It is the responisibility
 /// of the reader implementing these parsers to handle this.
 /// `FastaReader` in this library does so by always reading to the
-/// next '>' before parsing.
+/// next record start before parsing.
 #[inline]
 fn seq(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    is_not(">")(input)
+    // sequence lines start right after the header's line ending
+    match next_record_start(input, true).unwrap_or(input.len()) {
+        0 => Err(nom::Err::Error(Error::new(input, ErrorKind::IsNot))),
+        end => Ok((&input[end..], &input[..end])),
+    }
 }
 
+/// Copy `raw` without line endings, failing if it is not UTF-8
 #[inline]
-fn remove_newlines(s: &str) -> String {
-    s.split(['\r', '\n']).collect::<String>()
+fn remove_newlines(raw: &[u8]) -> Result<String, std::str::Utf8Error> {
+    std::str::from_utf8(raw).map(join_lines)
+}
+
+/// Copy `text` without line endings in one pass, into a string of exactly the right size
+#[inline]
+pub fn join_lines(text: &str) -> String {
+    let raw = text.as_bytes();
+    let breaks = memchr2_iter(b'\r', b'\n', raw).count();
+    let mut out = String::with_capacity(raw.len() - breaks);
+    let mut start = 0;
+    // line endings are ASCII, so every cut is on a char boundary
+    for end in memchr2_iter(b'\r', b'\n', raw) {
+        out.push_str(&text[start..end]);
+        start = end + 1;
+    }
+    out.push_str(&text[start..]);
+    out
 }
 
 #[inline]
 fn sequence(input: &[u8]) -> IResult<&[u8], String> {
-    map(seq, |x| remove_newlines(std::str::from_utf8(x).unwrap()))(input)
+    // an empty sequence parses so the reader can report it without losing its place
+    map_res(opt(seq), |x| remove_newlines(x.unwrap_or_default()))(input)
 }
 
+/// One record: the header without its '>', and the sequence without line breaks
 #[inline]
-pub fn parse_record(input: &[u8]) -> IResult<&[u8], (String, String)> {
+pub fn parse_record(input: &[u8]) -> IResult<&[u8], (Vec<u8>, String)> {
     pair(header, sequence)(input)
 }
 
+/// One record borrowed from the input: the header without its '>', and the
+/// sequence lines with their line endings
+///
+/// Fails where `parse_record` fails, including on a sequence that is not UTF-8.
+#[inline]
+pub fn parse_record_raw(input: &[u8]) -> IResult<&[u8], (&[u8], &str)> {
+    pair(
+        raw_header,
+        map_res(opt(seq), |s| std::str::from_utf8(s.unwrap_or_default())),
+    )(input)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_start() {
-        assert!(start(&[b'>',]).is_ok())
+        assert!(start(b">").is_ok())
     }
 
     #[test]
@@ -87,12 +154,22 @@ mod tests {
 
     #[test]
     fn test_header() {
-        assert!(header(b">SRR 123\n ") == Ok((b" ", String::from("SRR 123"))))
+        assert!(header(b">SRR 123\n ") == Ok((b" ", b"SRR 123".to_vec())))
     }
 
     #[test]
     fn test_seq() {
-        assert!(seq(b"ATGCN\n>") == Ok((b">", b"ATGCN\n")))
+        assert!(seq(b"ATGCN\n>") == Ok((b">", b"ATGCN\n")));
+        assert!(seq(b"AT>GC\n>") == Ok((b">", b"AT>GC\n")));
+    }
+
+    #[test]
+    fn test_next_record_start() {
+        assert_eq!(next_record_start(b">a", true), Some(0));
+        assert_eq!(next_record_start(b">a", false), None);
+        assert_eq!(next_record_start(b"a>b\n>c", false), Some(4));
+        assert_eq!(next_record_start(b"AC\r>c", false), Some(3));
+        assert!(starts_line(None) && starts_line(Some(&b'\n')) && !starts_line(Some(&b'x')));
     }
 
     #[test]
@@ -100,13 +177,38 @@ mod tests {
         assert!(sequence(b"ATGCN\nATGCN") == Ok((&[], String::from("ATGCNATGCN"))))
     }
 
+    #[test]
+    fn test_remove_newlines() {
+        assert_eq!(remove_newlines(b"AC\r\nGT\n\nN\n").unwrap(), "ACGTN");
+        let s = remove_newlines(b"ACGT").unwrap();
+        assert_eq!((s.as_str(), s.capacity()), ("ACGT", 4));
+        assert!(remove_newlines(b"AC\n\xffGT").is_err());
+        assert_eq!(sequence(b">next"), Ok((&b">next"[..], String::new())));
+    }
+
     #[test]
     fn test_parse_record() {
+        assert!(parse_record(b">A\nATGCN\n") == Ok((&[], (b"A".to_vec(), String::from("ATGCN")))));
+        assert!(parse_record(b">B\nATGCN") == Ok((&[], (b"B".to_vec(), String::from("ATGCN")))));
+        assert!(parse_record(b">C\n>D\n") == Ok((&b">D\n"[..], (b"C".to_vec(), String::new()))));
+        assert!(parse_record(b">E\n") == Ok((&[], (b"E".to_vec(), String::new()))));
         assert!(
-            parse_record(b">A\nATGCN\n") == Ok((&[], (String::from("A"), String::from("ATGCN"))))
+            parse_record(b">F len>5\nAC\n>G\n")
+                == Ok((&b">G\n"[..], (b"F len>5".to_vec(), String::from("AC"))))
         );
-        assert!(
-            parse_record(b">B\nATGCN") == Ok((&[], (String::from("B"), String::from("ATGCN"))))
+    }
+
+    #[test]
+    fn test_parse_record_raw() {
+        let input = b">A desc\r\nAC\r\nGT\r\n\r\n>B\n";
+        let (rest, (id, seq)) = parse_record_raw(input).unwrap();
+        assert_eq!(
+            (rest, id, seq),
+            (&b">B\n"[..], &b"A desc"[..], "AC\r\nGT\r\n\r\n")
         );
+        assert_eq!(join_lines(seq), "ACGT");
+        assert_eq!(parse_record_raw(b">C\n"), Ok((&[][..], (&b"C"[..], ""))));
+        assert!(parse_record_raw(b">D\nAC\xffGT\n").is_err());
+        assert!(parse_record_raw(b"ACGT\n").is_err());
     }
 }
@@ -1,18 +1,56 @@
+//! Streaming FASTA readers
+
 use crate::parser;
 use crate::FastaError;
 use crate::Record;
+use lyso_common::config::ReaderConfig;
+use lyso_common::detect::{find_mismatch, Format};
+use lyso_common::error::LysoError;
+use lyso_common::raw::RawRecord;
+use lyso_common::stream::{RecordBytes, Within};
+use lyso_common::validate::Validator;
+use memchr::{memchr, memchr2, memchr2_iter, memchr_iter};
 use nom::Err::Incomplete;
-use std::io::BufRead;
-
-const MAX_BUFFER_SIZE: usize = 10_000_000;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+/// Iteration state of a `FastaReader`
+///
+/// `Complete` means the input ended cleanly; `Failed` means a terminal error
+/// (see `FastaError::is_terminal`) was returned. In either case `next()` returns None.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum FastaReaderState {
+    /// More records may follow
     Reading,
+    /// The input ended cleanly
     Complete,
+    /// A terminal error was returned
     Failed,
 }
 
+/// A result read ahead by `FastaReader::peek_record`
+#[derive(Debug)]
+struct Peeked {
+    next: Option<Result<Record, FastaError>>,
+    /// Where the id lies in the reader's `peeked_bytes`, when `next` is a record
+    id_span: Option<std::ops::Range<usize>>,
+    /// The reader state before it was read
+    state: FastaReaderState,
+}
+
+/// Reads FASTA records one at a time from a `BufRead`
+///
+/// Records start at a '>' that begins a line, so a '>' in a description stays
+/// part of it; see the `parser` module for the full rules.
+///
+/// Parse and validation errors come wrapped in `FastaError::AtLine`, with the
+/// line of the record's header. A reader opened with `from_path` names the
+/// file as well.
+#[derive(Debug)]
 pub struct FastaReader<T>
 where
     T: BufRead,
@@ -21,20 +59,113 @@ where
     inner: T,
     buffer: Vec<u8>,
     offset: usize,
+    /// Input bytes of the records parsed so far
+    record_bytes: u64,
+    /// Line endings in those bytes
+    lines: u64,
+    /// The file read, for reporting errors
+    path: Option<PathBuf>,
+    strict_utf8: bool,
+    require_sequence: bool,
+    /// Rules every record is checked against, see `FastaReader::validator`
+    validator: Option<Arc<Validator<Record>>>,
+    peeked: Option<Peeked>,
+    /// The input bytes of the peeked record, reused from one peek to the next
+    peeked_bytes: Vec<u8>,
+    config: ReaderConfig,
 }
 
 impl<T> FastaReader<T>
 where
     T: BufRead,
 {
+    /// A reader at the start of `f`
+    #[must_use]
     pub fn new(f: T) -> Self {
-        let r = FastaReader {
+        Self::with_config(f, ReaderConfig::default())
+    }
+
+    /// A reader at the start of `f`, buffering as set by `config`
+    ///
+    /// A record is buffered up to the '>' of the next one, so `max_record_size`
+    /// also bounds the memory a single long sequence can take.
+    ///
+    /// ```
+    /// use lyso_common::config::ReaderConfig;
+    /// use lyso_fasta::{reader::FastaReader, FastaError};
+    ///
+    /// let config = ReaderConfig {
+    ///     max_record_size: 12,
+    ///     read_chunk_size: 4,
+    ///     ..ReaderConfig::default()
+    /// };
+    /// let mut reader = FastaReader::with_config(&b">a\nACGT\n>b\nACGTACGTACGT\n"[..], config);
+    /// assert_eq!(reader.next().unwrap().unwrap().seq(), "ACGT");
+    /// let err = reader.next().unwrap().unwrap_err();
+    /// assert!(matches!(err.root(), FastaError::RecordTooLarge { limit: 12 }));
+    /// assert_eq!(err.to_string(), "line 3: record longer than 12 bytes");
+    /// ```
+    #[must_use]
+    pub fn with_config(f: T, config: ReaderConfig) -> Self {
+        FastaReader {
             state: FastaReaderState::Reading,
             inner: f,
-            buffer: Vec::with_capacity(MAX_BUFFER_SIZE),
+            buffer: Vec::with_capacity(config.initial_capacity),
             offset: 0,
-        };
-        r
+            record_bytes: 0,
+            lines: 0,
+            path: None,
+            strict_utf8: false,
+            require_sequence: false,
+            validator: None,
+            peeked: None,
+            peeked_bytes: Vec::new(),
+            config,
+        }
+    }
+
+    /// Reject ids that are not UTF-8 (see `Record::check_utf8`)
+    ///
+    /// Off by default: ids are kept as raw bytes either way.
+    #[must_use]
+    pub fn strict_utf8(mut self, strict: bool) -> Self {
+        self.strict_utf8 = strict;
+        self
+    }
+
+    /// Fail records that break an error-level rule of `validator`
+    ///
+    /// A failing record comes back as a non-terminal `RuleViolation`; warnings are
+    /// left to `lyso check`. Every way of reading checks the record, including
+    /// `raw_records` and `scan_records`, which then build each record to check it.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use lyso_common::validate::{MaxHomopolymer, Severity};
+    /// use lyso_fasta::reader::FastaReader;
+    ///
+    /// let validator = lyso_fasta::validator().rule(MaxHomopolymer(4), Severity::Error);
+    /// let input = b">a\nAAAA\nC\n>b\nAAA\nAA\n";
+    /// let mut reader = FastaReader::new(&input[..]).validator(Arc::new(validator));
+    /// assert!(reader.next().unwrap().is_ok());
+    /// let err = reader.next().unwrap().unwrap_err();
+    /// assert_eq!(err.to_string(), "line 4: record b fails homopolymer: run of 5 A at base 1");
+    /// ```
+    #[must_use]
+    pub fn validator(mut self, validator: Arc<Validator<Record>>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Fail records with no sequence with `MissingSequenceError`
+    ///
+    /// Off by default: such records are returned with an empty sequence. Either
+    /// way the record that follows is read normally.
+    #[must_use]
+    pub fn require_sequence(mut self, require: bool) -> Self {
+        self.require_sequence = require;
+        self
     }
 
     /// Prevent internal buffer from growing infinitely.
@@ -53,48 +184,534 @@ where
         &self.buffer[self.offset..]
     }
 
+    /// Append input up to and including the next '>' that starts a line to the
+    /// buffer, or `max` bytes of it if that comes first
+    ///
+    /// A '>' inside a header does not stop the read. Each chunk of the underlying
+    /// reader is scanned once and copied once, so a record is never rescanned
+    /// however many chunks it spans.
     #[inline]
-    fn read_to_next_header(&mut self) -> Result<usize, std::io::Error> {
-        self.inner.read_until(b'>', &mut self.buffer)
+    fn read_to_next_header(&mut self, max: usize) -> Result<usize, std::io::Error> {
+        let mut read = 0;
+        while read < max {
+            let line_start = parser::starts_line(self.get_slice().last());
+            let available = match self.inner.fill_buf() {
+                Ok(b) => b,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                return Ok(read);
+            }
+            let available = &available[..available.len().min(max - read)];
+            let (found, used) = match parser::next_record_start(available, line_start) {
+                Some(i) => (true, i + 1),
+                None => (false, available.len()),
+            };
+            self.buffer.extend_from_slice(&available[..used]);
+            self.inner.consume(used);
+            read += used;
+            if found {
+                return Ok(read);
+            }
+        }
+        Ok(read)
     }
 
+    /// Bytes the current record may still read under `limit`, counting the '>'
+    /// that ends it, in refills of at most `read_chunk_size`
+    fn allowance(&self, limit: u64) -> usize {
+        let buffered = (self.buffer.len() - self.offset) as u64;
+        usize::try_from(limit.saturating_add(1).saturating_sub(buffered))
+            .unwrap_or(usize::MAX)
+            .min(self.config.read_chunk_size.max(1))
+    }
+
+    /// Fail once the record being buffered is longer than `max_record_size`
+    fn check_record_size(&self) -> Result<(), FastaError> {
+        let slice = self.get_slice();
+        let len = slice.len() - usize::from(ends_at_record_start(slice));
+        match self.config.max_record_size {
+            limit if len > limit => Err(FastaError::RecordTooLarge { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The state as of the last record returned; a pending peek does not change it
+    pub fn state(&self) -> FastaReaderState {
+        match &self.peeked {
+            Some(p) => p.state,
+            None => self.state,
+        }
+    }
+
+    /// Input bytes of the records read so far, failed and peeked ones included
+    ///
+    /// Divided by the number of records, this is the average record size.
+    pub fn record_bytes(&self) -> u64 {
+        self.record_bytes
+    }
+
+    /// The result the next `read_record` will return, without consuming it
+    ///
+    /// Errors are held like records, so peeking never loses one.
+    ///
+    /// ```
+    /// use lyso_fasta::reader::{FastaReader, FastaReaderState};
+    ///
+    /// let mut reader = FastaReader::new(&b">a\nAC\n>b\nGT\n"[..]);
+    /// let peeked = reader.peek_record().unwrap().as_ref().unwrap().id().to_vec();
+    /// assert_eq!(reader.next().unwrap().unwrap().id(), peeked);
+    /// assert_eq!(reader.next().unwrap().unwrap().seq(), "GT");
+    /// assert!(reader.next().is_none());
+    /// assert_eq!(reader.state(), FastaReaderState::Complete);
+    /// ```
+    pub fn peek_record(&mut self) -> Option<&Result<Record, FastaError>> {
+        if self.peeked.is_none() {
+            let state = self.state;
+            let strict = self.strict_utf8;
+            // the input bytes are kept too, for a `raw_records` read that follows
+            let mut kept = std::mem::take(&mut self.peeked_bytes);
+            kept.clear();
+            let next = self.read_with(u64::MAX, |id, seq, bytes, rules| {
+                let rec = build_record(id, seq, strict)?;
+                check_rules(rules, &rec)?;
+                kept.extend_from_slice(bytes);
+                Ok((rec, id_span(id)))
+            });
+            self.peeked_bytes = kept;
+            let (next, id_span) = match next {
+                Within::Next(Some(Ok((rec, span)))) => (Some(Ok(rec)), Some(span)),
+                Within::Next(Some(Err(e))) => (Some(Err(e)), None),
+                _ => (None, None),
+            };
+            self.peeked = Some(Peeked {
+                next,
+                id_span,
+                state,
+            });
+        }
+        self.peeked.as_ref().and_then(|p| p.next.as_ref())
+    }
+
+    /// Pass over the next `n` records
+    ///
+    /// Records failing with a non-terminal error count as skipped. Returns the
+    /// number skipped, fewer than `n` at the end of the input, or the first
+    /// terminal error.
+    pub fn skip_records(&mut self, n: usize) -> Result<usize, FastaError> {
+        for skipped in 0..n {
+            match self.read_record() {
+                None => return Ok(skipped),
+                Some(Err(e)) if e.is_terminal() => return Err(e),
+                Some(_) => {}
+            }
+        }
+        Ok(n)
+    }
+
+    /// Read the next record, moving to `Failed` after a terminal error
     #[inline]
     pub fn read_record(&mut self) -> Option<Result<Record, FastaError>> {
+        match self.read_within(u64::MAX) {
+            Within::Next(next) => next,
+            Within::Limit => None,
+        }
+    }
+
+    /// `read_record`, giving up on a record longer than `limit` bytes
+    ///
+    /// The bytes read of that record stay buffered, so the next read starts where
+    /// this one stopped.
+    fn read_within(&mut self, limit: u64) -> Within<Result<Record, FastaError>> {
+        if let Some(p) = self.peeked.take() {
+            return Within::Next(p.next);
+        }
+        let strict = self.strict_utf8;
+        self.read_with(limit, |id, seq, _, rules| {
+            let rec = build_record(id, seq, strict)?;
+            check_rules(rules, &rec)?;
+            Ok(rec)
+        })
+    }
+
+    /// Read the next record as its input bytes, see `raw_records`
+    pub fn read_raw_record(&mut self) -> Option<Result<RawRecord, FastaError>> {
+        match self.read_raw_within(u64::MAX) {
+            Within::Next(next) => next,
+            Within::Limit => None,
+        }
+    }
+
+    /// `read_raw_record`, giving up on a record longer than `limit` bytes
+    fn read_raw_within(&mut self, limit: u64) -> Within<Result<RawRecord, FastaError>> {
+        if let Some(p) = self.peeked.take() {
+            return Within::Next(p.next.map(|r| r.map(|_| self.take_peeked_raw(p.id_span))));
+        }
+        let strict = self.strict_utf8;
+        self.read_with(limit, |id, seq, bytes, rules| {
+            if strict {
+                Record::from_parts(id, "").check_utf8()?;
+            }
+            check_unbuilt(rules, id, seq)?;
+            Ok(RawRecord::new(bytes.to_vec(), id_span(id)))
+        })
+    }
+
+    /// Read the next record along with the input bytes it was built from
+    ///
+    /// For callers that decide on the record but write out the bytes, like a
+    /// filter copying the records it keeps.
+    pub fn read_record_and_raw(&mut self) -> Option<Result<(Record, RawRecord), FastaError>> {
+        if let Some(p) = self.peeked.take() {
+            return p
+                .next
+                .map(|r| r.map(|rec| (rec, self.take_peeked_raw(p.id_span))));
+        }
+        let strict = self.strict_utf8;
+        let next = self.read_with(u64::MAX, |id, seq, bytes, rules| {
+            let rec = build_record(id, seq, strict)?;
+            check_rules(rules, &rec)?;
+            Ok((rec, RawRecord::new(bytes.to_vec(), id_span(id))))
+        });
+        match next {
+            Within::Next(next) => next,
+            Within::Limit => None,
+        }
+    }
+
+    /// The peeked record's input bytes, as kept by `peek_record`
+    fn take_peeked_raw(&mut self, id_span: Option<std::ops::Range<usize>>) -> RawRecord {
+        let bytes = std::mem::take(&mut self.peeked_bytes);
+        RawRecord::new(bytes, id_span.unwrap_or_default())
+    }
+
+    /// Iterate over records as the exact bytes they were read from
+    ///
+    /// Records are parsed and checked as by `read_record`, with the same errors,
+    /// but come back unchanged: line wrapping, CRLF line endings, blank lines and a
+    /// missing final newline are all kept. Can be mixed with `next()`.
+    ///
+    /// ```
+    /// use lyso_fasta::reader::FastaReader;
+    ///
+    /// let input = b">a desc\r\nAC\r\nGT\r\n\r\n>b\nACGT";
+    /// let mut reader = FastaReader::new(&input[..]);
+    /// let out = reader.raw_records().map(|r| r.unwrap().bytes).collect::<Vec<_>>();
+    /// assert_eq!(out.concat(), input);
+    /// ```
+    pub fn raw_records(&mut self) -> RawRecords<'_, T> {
+        RawRecords { reader: self }
+    }
+
+    /// Visit the remaining records as slices of the reader's buffer
+    ///
+    /// Records are parsed and checked as by `read_record`, but never built, so
+    /// sequence lines are not joined and nothing is allocated per record.
+    /// Returning `ControlFlow::Break` stops the scan after that record; reading
+    /// continues from the next one. Returns the number of records visited, or the
+    /// first error, after which reading also continues if the error is not terminal.
+    ///
+    /// ```
+    /// use std::ops::ControlFlow;
+    ///
+    /// use lyso_fasta::reader::FastaReader;
+    ///
+    /// let mut reader = FastaReader::new(&b">a\nAC\nGT\n>b\nTT\n"[..]);
+    /// let mut lens = Vec::new();
+    /// let visited = reader.scan_records(|rec| {
+    ///     lens.push((rec.id.to_vec(), rec.seq_len()));
+    ///     ControlFlow::Break(())
+    /// });
+    /// assert_eq!(visited.unwrap(), 1);
+    /// assert_eq!(lens, [(b"a".to_vec(), 4)]);
+    /// assert_eq!(reader.next().unwrap().unwrap().id(), b"b");
+    /// ```
+    pub fn scan_records(
+        &mut self,
+        mut on_record: impl FnMut(RecordSlices<'_>) -> ControlFlow<()>,
+    ) -> Result<u64, FastaError> {
+        let mut visited = 0;
+        // a peeked record has already been built, and its bytes counted
+        if let Some(p) = self.peeked.take() {
+            let Some(rec) = p.next.transpose()? else {
+                return Ok(0);
+            };
+            let bytes = &self.peeked_bytes;
+            visited += 1;
+            // the sequence lines are kept as read, after the header line
+            let header_end = p.id_span.map_or(0, |span| span.end);
+            let seq_start =
+                memchr(b'\n', &bytes[header_end..]).map_or(bytes.len(), |i| header_end + i + 1);
+            let slices = RecordSlices {
+                offset: self.record_bytes - bytes.len() as u64,
+                id: rec.id(),
+                seq: &bytes[seq_start..],
+            };
+            if on_record(slices).is_break() {
+                return Ok(visited);
+            }
+        }
+        let strict = self.strict_utf8;
+        loop {
+            let offset = self.record_bytes;
+            let flow = self.read_with(u64::MAX, |id, seq, _, rules| {
+                if strict && std::str::from_utf8(id).is_err() {
+                    // only a failing record is built, for the error
+                    Record::from_parts(id, "").check_utf8()?;
+                }
+                check_unbuilt(rules, id, seq)?;
+                Ok(on_record(RecordSlices {
+                    offset,
+                    id,
+                    seq: seq.as_bytes(),
+                }))
+            });
+            match flow {
+                Within::Next(None) | Within::Limit => return Ok(visited),
+                Within::Next(Some(Err(e))) => return Err(e),
+                Within::Next(Some(Ok(flow))) => {
+                    visited += 1;
+                    if flow.is_break() {
+                        return Ok(visited);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read the next record with `build`, moving to `Failed` after a terminal error
+    ///
+    /// `build` is given the id, the sequence lines and the input bytes of the
+    /// record, and the rules it must check, see `check_rules`.
+    fn read_with<R>(
+        &mut self,
+        limit: u64,
+        build: impl FnOnce(&[u8], &str, &[u8], Option<&Validator<Record>>) -> Result<R, FastaError>,
+    ) -> Within<Result<R, FastaError>> {
         if self.state != FastaReaderState::Reading {
-            return None;
+            return Within::Next(None);
         }
-        match self.read_to_next_header() {
-            Ok(0) if self.offset == self.buffer.len() => {
-                self.state = FastaReaderState::Complete;
-                return None;
+        let Within::Next(res) = self.parse_within(limit, build) else {
+            return Within::Limit;
+        };
+        if let Some(Err(e)) = &res {
+            if e.is_terminal() {
+                self.state = FastaReaderState::Failed;
+            }
+        }
+        Within::Next(res)
+    }
+
+    #[inline]
+    fn parse_within<R>(
+        &mut self,
+        limit: u64,
+        build: impl FnOnce(&[u8], &str, &[u8], Option<&Validator<Record>>) -> Result<R, FastaError>,
+    ) -> Within<Result<R, FastaError>> {
+        // input in the wrong format fails before parsing, with an error naming it
+        if self.record_bytes == 0 && self.buffer.is_empty() {
+            match self.inner.fill_buf() {
+                Ok(buf) => {
+                    if let Some(m) = find_mismatch(buf, Format::Fasta) {
+                        return Within::Next(Some(Err(m.into())));
+                    }
+                }
+                Err(e) => return Within::Next(Some(Err(FastaError::IoError(e)))),
+            }
+        }
+        // the line the next record starts on
+        let line = self.lines + 1;
+        // buffer the record up to the '>' of the next one, as a capped read can stop
+        // at a line ending the parser would take for the end of the record
+        loop {
+            let max = self.allowance(limit);
+            if max == 0 {
+                return Within::Limit;
+            }
+            match self.read_to_next_header(max) {
+                Ok(0) if self.offset == self.buffer.len() => {
+                    self.state = FastaReaderState::Complete;
+                    return Within::Next(None);
+                }
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => return Within::Next(Some(Err(FastaError::IoError(e)))),
+            }
+            if let Err(e) = self.check_record_size() {
+                return Within::Next(Some(Err(self.at_line(line, e))));
+            }
+            if ends_at_record_start(self.get_slice()) {
+                break;
             }
-            Ok(_) => {}
-            Err(e) => return Some(Err(FastaError::IoError(e))),
         }
-        let mut res: Option<Result<Record, FastaError>> = None;
-        while res.is_none() {
-            match parser::parse_record(self.get_slice()) {
+        let res = loop {
+            match parser::parse_record_raw(self.get_slice()) {
                 Ok((i, (id, seq))) => {
-                    self.offset = self.buffer.len() - i.len();
-                    res = Some(Ok(Record { id, seq }))
+                    let offset = self.buffer.len() - i.len();
+                    let res = if seq.is_empty() && self.require_sequence {
+                        Err(FastaError::MissingSequenceError(
+                            String::from_utf8_lossy(id).into_owned(),
+                        ))
+                    } else {
+                        let bytes = &self.buffer[self.offset..offset];
+                        build(id, seq, bytes, self.validator.as_deref())
+                    };
+                    let res = res.map_err(|e| self.at_line(line, e));
+                    self.lines +=
+                        memchr_iter(b'\n', &self.buffer[self.offset..offset]).count() as u64;
+                    self.record_bytes += (offset - self.offset) as u64;
+                    self.offset = offset;
+                    break res;
                 }
-                Err(Incomplete(_)) => match self.read_to_next_header() {
-                    Ok(0) => {
-                        return Some(Err(FastaError::EofError));
-                    }
-                    Ok(_) => {}
-                    Err(e) => return Some(Err(FastaError::IoError(e))),
+                Err(Incomplete(_)) => match self.allowance(limit) {
+                    0 => return Within::Limit,
+                    max => match self.read_to_next_header(max) {
+                        Ok(0) => {
+                            return Within::Next(Some(Err(
+                                self.at_line(line, FastaError::EofError)
+                            )));
+                        }
+                        Ok(_) => {
+                            if let Err(e) = self.check_record_size() {
+                                return Within::Next(Some(Err(self.at_line(line, e))));
+                            }
+                        }
+                        Err(e) => return Within::Next(Some(Err(FastaError::IoError(e)))),
+                    },
                 },
                 Err(_) => {
-                    self.state = FastaReaderState::Failed;
-                    return Some(Err(FastaError::ParserError));
+                    return Within::Next(Some(Err(self.at_line(line, FastaError::ParserError))));
                 }
             }
-        }
-        if self.offset > MAX_BUFFER_SIZE {
+        };
+        if self.offset > self.config.compact_threshold {
             self.resize_buffer();
         }
-        res
+        Within::Next(Some(res))
+    }
+
+    fn at_line(&self, line: u64, err: FastaError) -> FastaError {
+        FastaError::AtLine {
+            path: self.path.clone(),
+            line,
+            source: Box::new(err),
+        }
+    }
+}
+
+/// The record of `id` and the sequence `lines`, checking the id is UTF-8 when `strict`
+fn build_record(id: &[u8], lines: &str, strict: bool) -> Result<Record, FastaError> {
+    let rec = Record {
+        id: id.to_vec(),
+        seq: parser::join_lines(lines),
+    };
+    if strict {
+        rec.check_utf8()?;
+    }
+    Ok(rec)
+}
+
+/// Fail `rec` if it breaks an error-level rule of `rules`
+fn check_rules(rules: Option<&Validator<Record>>, rec: &Record) -> Result<(), FastaError> {
+    match rules.and_then(|v| v.first_error(rec)) {
+        Some(v) => Err(FastaError::RuleViolation(v)),
+        None => Ok(()),
+    }
+}
+
+/// `check_rules` for a record read without building it, which is built only
+/// when there are rules
+fn check_unbuilt(
+    rules: Option<&Validator<Record>>,
+    id: &[u8],
+    lines: &str,
+) -> Result<(), FastaError> {
+    match rules {
+        Some(_) => check_rules(rules, &Record::from_parts(id, parser::join_lines(lines))),
+        None => Ok(()),
+    }
+}
+
+/// Whether `buffered`, the bytes of a record, ends with the '>' of the next one
+fn ends_at_record_start(buffered: &[u8]) -> bool {
+    buffered.len() > 1
+        && buffered.ends_with(b">")
+        && parser::starts_line(buffered.iter().nth_back(1))
+}
+
+/// Where `id` lies in its record's bytes, which start with '>'
+fn id_span(id: &[u8]) -> std::ops::Range<usize> {
+    1..1 + id.len()
+}
+
+/// The fields of a record, borrowed from the reader; see `FastaReader::scan_records`
+#[derive(Clone, Copy, Debug)]
+pub struct RecordSlices<'a> {
+    /// Where the record starts in the input
+    pub offset: u64,
+    /// The whole header line, without the '>'
+    pub id: &'a [u8],
+    /// The sequence lines as they are in the input, line endings included
+    ///
+    /// Use `seq_lines` or `seq_len` for the bases alone.
+    pub seq: &'a [u8],
+}
+
+impl<'a> RecordSlices<'a> {
+    /// The sequence lines without their line endings, skipping blank ones
+    pub fn seq_lines(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.seq
+            .split(|b| matches!(b, b'\r' | b'\n'))
+            .filter(|line| !line.is_empty())
+    }
+
+    /// Number of bases in the sequence
+    pub fn seq_len(&self) -> usize {
+        self.seq.len() - memchr2_iter(b'\r', b'\n', self.seq).count()
+    }
+}
+
+/// Iterator over `RawRecord`s, see `FastaReader::raw_records`
+pub struct RawRecords<'a, T>
+where
+    T: BufRead,
+{
+    reader: &'a mut FastaReader<T>,
+}
+
+impl<T> Iterator for RawRecords<'_, T>
+where
+    T: BufRead,
+{
+    type Item = Result<RawRecord, FastaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_raw_record()
+    }
+}
+
+impl<T> RecordBytes for RawRecords<'_, T>
+where
+    T: BufRead,
+{
+    fn record_bytes(&self) -> u64 {
+        self.reader.record_bytes
+    }
+
+    fn next_within(&mut self, limit: u64) -> Within<Self::Item> {
+        self.reader.read_raw_within(limit)
+    }
+}
+
+impl FastaReader<BufReader<File>> {
+    /// Open `path` for reading; errors name the file
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, LysoError> {
+        let path = path.as_ref();
+        let f = File::open(path).map_err(|e| LysoError::from_path(path, e))?;
+        let mut reader = FastaReader::new(BufReader::new(f));
+        reader.path = Some(path.to_path_buf());
+        Ok(reader)
     }
 }
 
@@ -109,16 +726,276 @@ where
     }
 }
 
+impl<T> RecordBytes for FastaReader<T>
+where
+    T: BufRead,
+{
+    fn record_bytes(&self) -> u64 {
+        self.record_bytes
+    }
+
+    /// Stops reading a record once it passes `limit` bytes, so a bound ahead of a
+    /// long sequence does not read the whole sequence
+    fn next_within(&mut self, limit: u64) -> Within<Self::Item> {
+        self.read_within(limit)
+    }
+}
+
+/// A piece of a FASTA record, as read by `FragmentReader`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fragment {
+    /// The start of a record: its header, without the '>'
+    Header(Vec<u8>),
+    /// Bases of the current record
+    Sequence {
+        /// Bases of the current record before this fragment
+        offset: usize,
+        /// Bases, without line endings
+        seq: String,
+    },
+}
+
+/// Reads FASTA records in pieces, for sequences too large to hold
+///
+/// Sequence comes out as it is buffered by the underlying reader, without line
+/// endings, so a fragment is never longer than one line. Records split where
+/// `FastaReader` splits them. Sequence must be ASCII, and every error ends
+/// iteration.
+#[derive(Debug)]
+pub struct FragmentReader<T: BufRead> {
+    inner: T,
+    config: ReaderConfig,
+    /// Header of the current record, None before the first
+    id: Option<Vec<u8>>,
+    /// Bases of the current record read so far
+    offset: usize,
+    /// Whether the next byte starts a line
+    line_start: bool,
+    done: bool,
+}
+
+impl<T: BufRead> FragmentReader<T> {
+    /// A reader at the start of `inner`
+    #[must_use]
+    pub fn new(inner: T) -> Self {
+        Self::with_config(inner, ReaderConfig::default())
+    }
+
+    /// A reader at the start of `inner`, limited as set by `config`
+    ///
+    /// No fragment is longer than `read_chunk_size` bases, and a header longer
+    /// than `max_record_size` bytes is an error. The other settings size a
+    /// record buffer, which a `FragmentReader` does not keep.
+    ///
+    /// ```
+    /// use lyso_common::config::ReaderConfig;
+    /// use lyso_fasta::reader::{Fragment, FragmentReader};
+    /// use lyso_fasta::FastaError;
+    ///
+    /// let config = ReaderConfig {
+    ///     max_record_size: 8,
+    ///     read_chunk_size: 3,
+    ///     ..ReaderConfig::default()
+    /// };
+    /// let input = &b">a\nACGTA\n>long header\nAC\n"[..];
+    /// let mut reader = FragmentReader::with_config(input, config);
+    /// assert_eq!(reader.next().unwrap().unwrap(), Fragment::Header(b"a".to_vec()));
+    /// let seq = |offset, seq: &str| Fragment::Sequence { offset, seq: seq.to_string() };
+    /// assert_eq!(reader.next().unwrap().unwrap(), seq(0, "ACG"));
+    /// assert_eq!(reader.next().unwrap().unwrap(), seq(3, "TA"));
+    /// let err = reader.next().unwrap().unwrap_err();
+    /// assert!(matches!(err, FastaError::RecordTooLarge { limit: 8 }));
+    /// ```
+    #[must_use]
+    pub fn with_config(inner: T, config: ReaderConfig) -> Self {
+        FragmentReader {
+            inner,
+            config,
+            id: None,
+            offset: 0,
+            line_start: true,
+            done: false,
+        }
+    }
+
+    /// Read the next header or piece of sequence
+    pub fn read_fragment(&mut self) -> Option<Result<Fragment, FastaError>> {
+        if self.done {
+            return None;
+        }
+        let res = self.next_fragment();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+
+    fn next_fragment(&mut self) -> Option<Result<Fragment, FastaError>> {
+        loop {
+            let available = match self.inner.fill_buf() {
+                Ok(b) => b,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(FastaError::IoError(e))),
+            };
+            if available.is_empty() {
+                return None;
+            }
+            if available[0] == b'>' && self.line_start {
+                return Some(self.read_header());
+            }
+            let Some(id) = &self.id else {
+                return Some(Err(FastaError::ParserError));
+            };
+            // a '>' only starts the next record after a line ending
+            let (end, used) = match memchr2(b'\n', b'\r', available) {
+                Some(i) if i <= self.config.read_chunk_size => (i, i + 1),
+                _ => {
+                    let end = available.len().min(self.config.read_chunk_size.max(1));
+                    (end, end)
+                }
+            };
+            self.line_start = end < used;
+            let line = &available[..end];
+            if !line.is_ascii() {
+                return Some(Err(FastaError::ValidationError {
+                    record: String::from_utf8_lossy(id).into_owned(),
+                    reason: "sequence is not ASCII",
+                }));
+            }
+            let seq = line.iter().map(|b| char::from(*b)).collect::<String>();
+            self.inner.consume(used);
+            if !seq.is_empty() {
+                let offset = self.offset;
+                self.offset += seq.len();
+                return Some(Ok(Fragment::Sequence { offset, seq }));
+            }
+        }
+    }
+
+    fn read_header(&mut self) -> Result<Fragment, FastaError> {
+        let limit = self.config.max_record_size;
+        let mut line = Vec::new();
+        // the whole header line counts, with its '>' and line ending
+        let cap = u64::try_from(limit).unwrap_or(u64::MAX).saturating_add(1);
+        (&mut self.inner).take(cap).read_until(b'\n', &mut line)?;
+        if line.len() > limit {
+            return Err(FastaError::RecordTooLarge { limit });
+        }
+        if line.pop() != Some(b'\n') {
+            return Err(FastaError::EofError);
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        line.remove(0);
+        self.id = Some(line.clone());
+        self.offset = 0;
+        self.line_start = true;
+        Ok(Fragment::Header(line))
+    }
+}
+
+impl<T: BufRead> Iterator for FragmentReader<T> {
+    type Item = Result<Fragment, FastaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_fragment()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::reader::FastaReader;
+    use crate::reader::{FastaReader, FastaReaderState, Fragment, FragmentReader};
+    use crate::FastaError;
     use std::fs::File;
     use std::io::BufReader;
 
     const FA_PATH: &str = "../resources/test_data/test.fa";
     const BAD_FA_PATH: &str = "../resources/test_data/corrupt.fa";
 
+    #[test]
+    fn test_bounded() {
+        use lyso_common::stream::{Bound, Bounded};
+        use std::time::Instant;
+
+        // records of 11, 10 and 25 bytes
+        let input = b">a\nACGT\nAC\n>bb\nGGGGG\n>c\nTTTTTTTTTT\nTTTTTTTTTT\n";
+        let ids = |b: &mut Bounded<FastaReader<&[u8]>>| {
+            b.map(|r| r.unwrap().id_str_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let mut bounded = Bounded::new(FastaReader::new(&input[..])).max_records(2);
+        assert_eq!(ids(&mut bounded), ["a", "bb"]);
+        assert_eq!(bounded.stopped_by(), Some(Bound::Records));
+        let mut reader = bounded.into_inner();
+        assert_eq!(reader.next().unwrap().unwrap().id(), b"c");
+
+        for (max, n) in [(10, 0), (11, 1), (20, 1), (21, 2), (45, 2), (46, 3)] {
+            let mut bounded = Bounded::new(FastaReader::new(&input[..])).max_bytes(max);
+            assert_eq!(ids(&mut bounded).len(), n, "max_bytes({max})");
+            assert_eq!(bounded.stopped_by(), Some(Bound::Bytes));
+        }
+        let mut bounded = Bounded::new(FastaReader::new(&input[..])).max_bytes(47);
+        assert_eq!(ids(&mut bounded).len(), 3);
+        assert_eq!((bounded.stopped_by(), bounded.bytes()), (None, Some(46)));
+
+        // a long record is not read past the bound, and is read whole afterwards
+        let mut bounded = Bounded::new(FastaReader::new(&input[..])).max_bytes(25);
+        assert_eq!(ids(&mut bounded), ["a", "bb"]);
+        assert_eq!(bounded.bytes(), Some(21));
+        let mut reader = bounded.into_inner();
+        assert!(reader.buffer.len() - reader.offset <= 5);
+        assert_eq!(reader.next().unwrap().unwrap().seq().len(), 20);
+        assert!(reader.next().is_none());
+        assert_eq!(reader.state(), FastaReaderState::Complete);
+
+        let mut bounded = Bounded::new(FastaReader::new(&input[..])).deadline(Instant::now());
+        assert!(bounded.next().is_none());
+        assert_eq!(bounded.stopped_by(), Some(Bound::Deadline));
+        assert_eq!(bounded.into_inner().count(), 3);
+    }
+
+    #[test]
+    fn test_wrong_format() {
+        use lyso_common::detect::{Compression, Format};
+
+        let first = |input: &[u8]| {
+            let mut reader = FastaReader::new(input);
+            let err = reader.next().unwrap().unwrap_err();
+            assert_eq!(reader.state(), FastaReaderState::Failed);
+            err
+        };
+        let bgzf = std::fs::read("../resources/test_data/name_sorted.bam").unwrap();
+        assert!(matches!(
+            first(&bgzf),
+            FastaError::Compressed(Compression::Bgzf)
+        ));
+        assert!(matches!(
+            first(&[0x1f, 0x8b, 8, 0, 0, 0]),
+            FastaError::Compressed(Compression::Gzip)
+        ));
+        assert!(matches!(
+            first(b"BAM\x01\0\0\0\0"),
+            FastaError::WrongFormat(Format::Bam)
+        ));
+        assert!(matches!(
+            first(b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:10\n"),
+            FastaError::WrongFormat(Format::Sam)
+        ));
+        assert!(matches!(
+            first(b"@r1\nACGT\n+\nIIII\n"),
+            FastaError::WrongFormat(Format::Fastq)
+        ));
+        let binary = (0..=255).cycle().take(2000).collect::<Vec<u8>>();
+        assert!(matches!(first(&binary), FastaError::NotText));
+        assert_eq!(
+            first(b"@HD\tVN:1.6\n").to_string(),
+            "input looks like SAM, not FASTA"
+        );
+    }
+
     #[test]
     fn test_read_fa() {
         let f = File::open(FA_PATH).unwrap();
@@ -136,7 +1013,7 @@ mod tests {
         let b = BufReader::new(f);
         let reader: FastaReader<BufReader<File>> = FastaReader::new(b);
         for r in reader {
-            eprintln!("{:?}", r);
+            eprintln!("{:?}", r.unwrap());
         }
     }
 
@@ -147,10 +1024,698 @@ mod tests {
         let mut reader: FastaReader<BufReader<File>> = FastaReader::new(b);
         let record = reader.next().unwrap();
         eprintln!("{}", record.as_ref().unwrap().seq);
-        assert!(record.as_ref().unwrap().id == "SRR22092847.1.1");
+        assert!(record.as_ref().unwrap().id == b"SRR22092847.1.1");
         assert!(
             record.unwrap().seq
                 == "GNTTAAAGCACATAAAGACAAATCGCTCCAGGGCAAAGNTTAAAGCACATAAAGACAAATCGCTCCAGGGCAAA"
         );
     }
+
+    #[test]
+    fn test_state_complete() {
+        let mut reader = FastaReader::new(&b">a\nACGT\n"[..]);
+        assert_eq!(reader.state(), FastaReaderState::Reading);
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().is_none());
+        assert_eq!(reader.state(), FastaReaderState::Complete);
+    }
+
+    #[test]
+    fn test_terminal_error_stops() {
+        for input in [&b"garbage\n>a\nACGT\n"[..], &b">a\nACGT\n>trunc"[..]] {
+            let mut reader = FastaReader::new(input);
+            let errs = reader.by_ref().filter(|r| r.is_err()).count();
+            assert_eq!(errs, 1);
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastaReaderState::Failed);
+        }
+    }
+
+    #[test]
+    fn test_peek_record() {
+        let mut reader = FastaReader::new(&b">a\n>b\nACGT\n>c\nGG\n"[..]).require_sequence(true);
+        assert!(matches!(
+            reader.peek_record().unwrap().as_ref().unwrap_err().root(),
+            FastaError::MissingSequenceError(_)
+        ));
+        assert!(reader.next().unwrap().is_err());
+        let peeked = reader.peek_record().unwrap().as_ref().unwrap().clone();
+        assert_eq!(peeked.id(), b"b");
+        assert_eq!(reader.next().unwrap().unwrap(), peeked);
+        assert_eq!(reader.next().unwrap().unwrap().id(), b"c");
+
+        // peeking at the end leaves the state alone until the end is read
+        assert!(reader.peek_record().is_none());
+        assert_eq!(reader.state(), FastaReaderState::Reading);
+        assert!(reader.next().is_none());
+        assert_eq!(reader.state(), FastaReaderState::Complete);
+    }
+
+    #[test]
+    fn test_raw_records() {
+        use lyso_common::stream::Bounded;
+
+        for fixture in [
+            "getfasta.fa",
+            "crlf.fa",
+            "no_final_newline.fa",
+            "empty_records.fa",
+            "test.fa",
+        ] {
+            let input = std::fs::read(format!("../resources/test_data/{fixture}")).unwrap();
+            let ids = FastaReader::new(&input[..])
+                .map(|r| r.unwrap().id().to_vec())
+                .collect::<Vec<_>>();
+            let raw = FastaReader::new(BufReader::with_capacity(1, &input[..]))
+                .raw_records()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(
+                raw.iter().map(|r| r.id()).collect::<Vec<_>>(),
+                ids,
+                "{fixture}"
+            );
+            let out = raw.into_iter().map(|r| r.bytes).collect::<Vec<_>>();
+            assert!(
+                out.concat() == input,
+                "{fixture} is not passed through unchanged"
+            );
+        }
+
+        let input = b">a x\r\nAC\r\nGT\r\n\r\n>b\nACGTACGT\nAC";
+        let mut reader = FastaReader::new(&input[..]);
+        assert_eq!(
+            reader.peek_record().unwrap().as_ref().unwrap().seq(),
+            "ACGT"
+        );
+        let first = reader.read_raw_record().unwrap().unwrap();
+        assert_eq!(first.bytes, b">a x\r\nAC\r\nGT\r\n\r\n");
+        assert_eq!(first.id(), b"a x");
+        // the byte bound stops before a record that would pass it
+        let mut bounded = Bounded::new(reader.raw_records()).max_bytes(10);
+        assert!(bounded.next().is_none());
+        let last = reader.read_raw_record().unwrap().unwrap();
+        assert_eq!(last.bytes, b">b\nACGTACGT\nAC");
+        assert!(reader.read_raw_record().is_none());
+
+        // the record and its bytes together, peeked or not
+        let mut reader = FastaReader::new(&input[..]);
+        reader.peek_record();
+        let (rec, raw) = reader.read_record_and_raw().unwrap().unwrap();
+        assert_eq!(
+            (rec.seq(), &raw.bytes[..]),
+            ("ACGT", &b">a x\r\nAC\r\nGT\r\n\r\n"[..])
+        );
+        let (rec, raw) = reader.read_record_and_raw().unwrap().unwrap();
+        assert_eq!(
+            (rec.id(), &raw.bytes[..]),
+            (&b"b"[..], &b">b\nACGTACGT\nAC"[..])
+        );
+        assert!(reader.read_record_and_raw().is_none());
+
+        let mut reader = FastaReader::new(&b">a\n>b\nAC\n"[..]).require_sequence(true);
+        assert!(matches!(
+            reader.read_raw_record().unwrap().unwrap_err().root(),
+            FastaError::MissingSequenceError(_)
+        ));
+        assert_eq!(reader.read_raw_record().unwrap().unwrap().id(), b"b");
+    }
+
+    #[test]
+    fn test_skip_records() {
+        let expected = FastaReader::from_path(FA_PATH)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mut reader = FastaReader::from_path(FA_PATH).unwrap();
+        assert_eq!(reader.skip_records(3).unwrap(), 3);
+        assert_eq!(reader.next().unwrap().unwrap(), expected[3]);
+        reader.peek_record();
+        assert_eq!(reader.skip_records(usize::MAX).unwrap(), expected.len() - 4);
+        assert!(reader.next().is_none());
+
+        let mut reader = FastaReader::new(&b">a\nACGT\n>trunc"[..]);
+        let err = reader.skip_records(2).unwrap_err();
+        assert!(matches!(err.root(), FastaError::EofError));
+    }
+
+    #[test]
+    fn test_small_read_chunks() {
+        use lyso_common::config::ReaderConfig;
+
+        for fixture in [
+            "consensus_ref.fa",
+            "corrupt.fa",
+            "crlf.fa",
+            "empty_records.fa",
+            "getfasta.fa",
+            "gt_in_desc.fa",
+            "line_errors.fa",
+            "masked.fa",
+            "no_final_newline.fa",
+            "test.fa",
+        ] {
+            let path = format!("../resources/test_data/{fixture}");
+            let f = BufReader::new(File::open(&path).unwrap());
+            let expected = FastaReader::new(f)
+                .map(|r| r.map_err(|e| e.to_string()))
+                .collect::<Vec<_>>();
+            for chunk in [1, 7, 64] {
+                let config = ReaderConfig {
+                    read_chunk_size: chunk,
+                    compact_threshold: 0,
+                    ..ReaderConfig::default()
+                };
+                let f = BufReader::new(File::open(&path).unwrap());
+                let chunked = FastaReader::with_config(f, config)
+                    .map(|r| r.map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>();
+                assert_eq!(chunked, expected, "{fixture}, {chunk}-byte chunks");
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_record_size() {
+        use lyso_common::config::ReaderConfig;
+
+        // a is 9 bytes, b 17
+        let input = b">a\nAC\nGT\n>b\nACGTAC\nGTACGT\n>c\nA\n";
+        for (limit, chunk) in [(9, usize::MAX), (9, 3), (16, 64)] {
+            let config = ReaderConfig {
+                max_record_size: limit,
+                read_chunk_size: chunk,
+                ..ReaderConfig::default()
+            };
+            let mut reader = FastaReader::with_config(&input[..], config);
+            assert_eq!(reader.next().unwrap().unwrap().seq(), "ACGT");
+            let err = reader.next().unwrap().unwrap_err();
+            assert!(matches!(err.root(), FastaError::RecordTooLarge { limit: l } if *l == limit));
+            assert_eq!(err.line(), Some(4));
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastaReaderState::Failed);
+        }
+        let config = ReaderConfig {
+            max_record_size: 17,
+            ..ReaderConfig::default()
+        };
+        let reader = FastaReader::with_config(&input[..], config);
+        assert_eq!(reader.collect::<Result<Vec<_>, _>>().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_scan_records() {
+        use crate::indexer::FastaIndex;
+        use std::ops::ControlFlow;
+
+        for path in [FA_PATH, "../resources/test_data/masked.fa"] {
+            let expected = FastaReader::from_path(path)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let mut offsets = Vec::new();
+            let visited = FastaReader::from_path(path)
+                .unwrap()
+                .scan_records(|rec| {
+                    let n = offsets.len();
+                    assert_eq!(
+                        rec.seq_lines().collect::<Vec<_>>().concat(),
+                        expected[n].seq().as_bytes()
+                    );
+                    assert_eq!(rec.seq_len(), expected[n].seq().len());
+                    let name = rec.id.split(|b| b.is_ascii_whitespace()).next().unwrap();
+                    offsets.push((name.to_vec(), rec.offset));
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            let mut fa = BufReader::new(File::open(path).unwrap());
+            let index = FastaIndex::from_fasta_file(&mut fa, Default::default()).unwrap();
+            assert_eq!(visited as usize, index.len(), "{path}");
+            for (n, (name, offset)) in offsets.iter().enumerate() {
+                assert_eq!(name, index.nth_entry(n).unwrap().name(), "{path}");
+                assert_eq!(Some(*offset), index.record_start(n), "{path}");
+            }
+        }
+
+        // breaking leaves the reader at the next record, a peeked one included
+        let expected = FastaReader::from_path(FA_PATH)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let mut reader = FastaReader::from_path(FA_PATH).unwrap();
+        let mut seen = 0;
+        let visited = reader.scan_records(|_| {
+            seen += 1;
+            match seen {
+                10 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        assert_eq!(visited.unwrap(), 10);
+        assert_eq!(reader.next().unwrap().unwrap(), expected[10]);
+        reader.peek_record();
+        let mut first = None;
+        reader
+            .scan_records(|rec| {
+                first = Some(rec.id.to_vec());
+                ControlFlow::Break(())
+            })
+            .unwrap();
+        assert_eq!(first.as_deref(), Some(expected[11].id()));
+        assert_eq!(reader.next().unwrap().unwrap(), expected[12]);
+
+        // a peeked record gives the same slices as one read by the scan
+        let text = b">a x\r\nAC\r\nGT\r\n>b\nT\n";
+        let slices = |peek: bool| {
+            let mut reader = FastaReader::new(&text[..]);
+            if peek {
+                reader.peek_record();
+            }
+            let mut seen = Vec::new();
+            reader
+                .scan_records(|rec| {
+                    seen.push((rec.offset, rec.id.to_vec(), rec.seq.to_vec()));
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            seen
+        };
+        assert_eq!(slices(true), slices(false));
+        assert_eq!(slices(true)[0].2, b"AC\r\nGT\r\n");
+
+        // an error ends the scan, and a local one can be read past
+        let mut reader = FastaReader::new(&b">a\n>b\nAC\n"[..]).require_sequence(true);
+        let err = reader
+            .scan_records(|_| ControlFlow::Continue(()))
+            .unwrap_err();
+        assert!(matches!(err.root(), FastaError::MissingSequenceError(_)));
+        assert_eq!(
+            reader.scan_records(|_| ControlFlow::Continue(())).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_record_bytes() {
+        let input = b">a\nACGT\n>b desc\nAC\nGT\n";
+        let mut reader = FastaReader::new(&input[..]);
+        reader.next().unwrap().unwrap();
+        assert_eq!(reader.record_bytes(), 8);
+        assert_eq!(reader.by_ref().count(), 1);
+        assert_eq!(reader.record_bytes(), input.len() as u64);
+
+        let mut reader = FastaReader::from_path(FA_PATH).unwrap();
+        reader.skip_records(usize::MAX).unwrap();
+        assert_eq!(
+            reader.record_bytes(),
+            std::fs::metadata(FA_PATH).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_local_error_continues() {
+        let mut reader = FastaReader::new(&b">a\n>b\nACGT\n"[..]).require_sequence(true);
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(matches!(err.root(), FastaError::MissingSequenceError(id) if id == "a"));
+        assert_eq!(err.line(), Some(1));
+        assert!(!err.is_terminal());
+        assert_eq!(reader.next().unwrap().unwrap().id(), b"b");
+        assert!(reader.next().is_none());
+        assert_eq!(reader.state(), FastaReaderState::Complete);
+    }
+
+    #[test]
+    fn test_validator() {
+        use lyso_common::validate::{MaxHomopolymer, Severity, Violation};
+        use std::ops::ControlFlow;
+        use std::sync::Arc;
+
+        // a custom rule next to a stock one: no sequence shorter than 4 bases
+        let validator = crate::validator()
+            .rule(MaxHomopolymer(5), Severity::Error)
+            .rule_fn("min_len", Severity::Error, |r: &crate::Record| {
+                (r.seq().len() < 4).then(|| Violation::new("under 4 bases"))
+            });
+        let validator = Arc::new(validator);
+        let input = b">a\nACGT\n>polyA\nCAAA\nAAAG\n>short\nAC\n>b\nACGTAC\n";
+        let rules = |r: Result<crate::Record, FastaError>| match r {
+            Ok(rec) => Ok(rec.id().to_vec()),
+            Err(e) => match e.root() {
+                FastaError::RuleViolation(v) => Err((e.line(), v.rule.clone())),
+                _ => panic!("{e}"),
+            },
+        };
+        let expected = [
+            Ok(b"a".to_vec()),
+            Err((Some(3), "homopolymer".to_string())),
+            Err((Some(6), "min_len".to_string())),
+            Ok(b"b".to_vec()),
+        ];
+
+        let reader = FastaReader::new(&input[..]).validator(Arc::clone(&validator));
+        assert_eq!(reader.map(rules).collect::<Vec<_>>(), expected);
+        // a peeked record is the one checked and returned
+        let mut reader = FastaReader::new(&input[..]).validator(Arc::clone(&validator));
+        reader.next().unwrap().unwrap();
+        assert!(reader.peek_record().unwrap().is_err());
+        assert_eq!(rules(reader.next().unwrap()), expected[1]);
+        // raw and scanned records are checked the same way
+        let mut reader = FastaReader::new(&input[..]).validator(Arc::clone(&validator));
+        assert_eq!(reader.raw_records().filter(Result::is_err).count(), 2);
+        let mut reader = FastaReader::new(&input[..]).validator(Arc::clone(&validator));
+        let err = reader
+            .scan_records(|_| ControlFlow::Continue(()))
+            .unwrap_err();
+        assert!(matches!(err.root(), FastaError::RuleViolation(v) if v.rule == "homopolymer"));
+        assert!(!err.is_terminal());
+    }
+
+    #[test]
+    fn test_error_lines() {
+        use lyso_common::config::ReaderConfig;
+
+        const LINE_ERRORS_PATH: &str = "../resources/test_data/line_errors.fa";
+        for chunk in [1, 7, 64] {
+            let config = ReaderConfig {
+                read_chunk_size: chunk,
+                compact_threshold: 0,
+                ..ReaderConfig::default()
+            };
+            let f = BufReader::new(File::open(LINE_ERRORS_PATH).unwrap());
+            let got = FastaReader::with_config(f, config)
+                .require_sequence(true)
+                .strict_utf8(true)
+                .map(|r| r.map(|rec| rec.id().to_vec()).map_err(|e| e.line()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                got,
+                [
+                    Ok(b"ok1".to_vec()),
+                    Err(Some(4)),
+                    Err(Some(5)),
+                    Ok(b"ok2".to_vec()),
+                    Err(Some(10))
+                ],
+                "{chunk}-byte chunks"
+            );
+        }
+
+        let mut reader = FastaReader::from_path(LINE_ERRORS_PATH)
+            .unwrap()
+            .require_sequence(true);
+        reader.next().unwrap().unwrap();
+        assert_eq!(
+            reader.next().unwrap().unwrap_err().to_string(),
+            format!("{LINE_ERRORS_PATH}:4: Missing sequence for record empty")
+        );
+        let mut reader = FastaReader::new(&b">a\nAC\n>"[..]);
+        reader.next().unwrap().unwrap();
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(err.is_terminal());
+        assert_eq!(err.to_string(), "line 3: Unexpected end of file");
+    }
+
+    #[test]
+    fn test_empty_sequence() {
+        const EMPTY_FA_PATH: &str = "../resources/test_data/empty_records.fa";
+        let read = |reader: FastaReader<BufReader<File>>| {
+            reader
+                .map(|r| match r {
+                    Ok(rec) => Ok((rec.id_str_lossy().into_owned(), rec.seq().to_string())),
+                    Err(e) => match e.root() {
+                        FastaError::MissingSequenceError(id) => Err(id.clone()),
+                        _ => panic!("{e}"),
+                    },
+                })
+                .collect::<Vec<_>>()
+        };
+        let ok = |id: &str, seq: &str| Ok((id.to_string(), seq.to_string()));
+
+        let lenient = read(FastaReader::from_path(EMPTY_FA_PATH).unwrap());
+        assert_eq!(
+            lenient,
+            [
+                ok("empty_first", ""),
+                ok("a desc", "ACGTAC"),
+                ok("empty_middle", ""),
+                ok("b", "GGGG"),
+                ok("empty_last", ""),
+            ]
+        );
+        let strict = read(
+            FastaReader::from_path(EMPTY_FA_PATH)
+                .unwrap()
+                .require_sequence(true),
+        );
+        assert_eq!(
+            strict,
+            [
+                Err("empty_first".to_string()),
+                ok("a desc", "ACGTAC"),
+                Err("empty_middle".to_string()),
+                ok("b", "GGGG"),
+                Err("empty_last".to_string()),
+            ]
+        );
+        let err = FastaError::MissingSequenceError("a".into());
+        assert_eq!(err.to_string(), "Missing sequence for record a");
+
+        // one byte at a time, blank lines and CRLF
+        let input = &b">a\r\n>b\r\nAC\r\n>c\n\n\n>d\nG\n"[..];
+        let reader = FastaReader::new(BufReader::with_capacity(1, input));
+        let recs = reader.map(|r| r.unwrap()).collect::<Vec<_>>();
+        let seqs = recs.iter().map(|r| (r.id(), r.seq())).collect::<Vec<_>>();
+        assert_eq!(
+            seqs,
+            [
+                (&b"a"[..], ""),
+                (&b"b"[..], "AC"),
+                (&b"c"[..], ""),
+                (&b"d"[..], "G")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matches_line_scan() {
+        // naive reference: join every line until the next header
+        let text = std::fs::read_to_string(FA_PATH).unwrap();
+        let mut expected: Vec<(Vec<u8>, String)> = Vec::new();
+        for line in text.lines() {
+            match line.strip_prefix('>') {
+                Some(id) => expected.push((id.as_bytes().to_vec(), String::new())),
+                None => expected.last_mut().unwrap().1.push_str(line.trim_end()),
+            }
+        }
+        // a 1-byte BufReader makes every record span many chunks
+        let reader = FastaReader::new(BufReader::with_capacity(1, File::open(FA_PATH).unwrap()));
+        let got = reader
+            .map(|r| r.map(|r| (r.id().to_vec(), r.seq().to_string())))
+            .collect::<Result<Vec<_>, FastaError>>()
+            .unwrap();
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_gt_in_description() {
+        use crate::count::fast_count;
+        use crate::indexer::{DuplicatePolicy, FastaIndex};
+        use lyso_common::config::ReaderConfig;
+        use std::ops::ControlFlow;
+
+        // '>' in the descriptions of the first, a middle and the last record
+        const PATH: &str = "../resources/test_data/gt_in_desc.fa";
+        let text = std::fs::read_to_string(PATH).unwrap();
+        let expected = [
+            ("seq1 note: len>500", "ACGTACGTACGTAC"),
+            ("seq2 plain", "TTGGCCAA"),
+            ("seq3 a>b>>c ends with>", "GGGGCCCCAA"),
+            ("seq4 x<y", "ACGT"),
+            ("seq5 last> >record", "CCCCAAAA"),
+        ]
+        .map(|(id, seq)| (id.as_bytes().to_vec(), seq.to_string()));
+        let fields = |rec: crate::Record| (rec.id().to_vec(), rec.seq().to_string());
+
+        for chunk in [1, 2, 7, 64, usize::MAX] {
+            let config = ReaderConfig {
+                read_chunk_size: chunk,
+                compact_threshold: 0,
+                ..ReaderConfig::default()
+            };
+            let got = FastaReader::with_config(text.as_bytes(), config)
+                .map(|r| r.map(fields))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(got, expected, "{chunk}-byte chunks");
+        }
+        let one_byte = BufReader::with_capacity(1, File::open(PATH).unwrap());
+        let got = FastaReader::new(one_byte)
+            .map(|r| r.map(fields))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(got, expected);
+
+        let raw = FastaReader::new(text.as_bytes())
+            .raw_records()
+            .map(|r| r.unwrap().bytes)
+            .collect::<Vec<_>>();
+        assert_eq!(raw.len(), 5);
+        assert_eq!(raw.concat(), text.as_bytes());
+
+        let mut scanned = Vec::new();
+        FastaReader::new(text.as_bytes())
+            .scan_records(|rec| {
+                scanned.push(rec.id.to_vec());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(scanned, expected.clone().map(|(id, _)| id));
+
+        assert_eq!(fast_count(text.as_bytes()).unwrap(), 5);
+        let headers = FragmentReader::new(text.as_bytes())
+            .filter_map(|f| match f.unwrap() {
+                Fragment::Header(id) => Some(id),
+                Fragment::Sequence { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(headers, expected.clone().map(|(id, _)| id));
+        let index =
+            FastaIndex::from_fasta_file(&mut std::io::Cursor::new(&text), DuplicatePolicy::Error)
+                .unwrap();
+        let names = index
+            .entries()
+            .map(|e| e.name().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [&b"seq1"[..], b"seq2", b"seq3", b"seq4", b"seq5"]);
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert!(FastaReader::from_path(FA_PATH).unwrap().next().is_some());
+
+        let err = FastaReader::from_path("../resources/test_data/missing.fa").unwrap_err();
+        assert!(err.to_string().contains("missing.fa"));
+        let mut source: &dyn std::error::Error = &err;
+        while let Some(next) = source.source() {
+            source = next;
+        }
+        let io = source.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_into_lyso_error() {
+        use lyso_common::error::LysoError;
+        let res: Result<Vec<_>, LysoError> = FastaReader::new(&b">a\nAC\n>trunc"[..])
+            .map(|r| r.map_err(LysoError::from))
+            .collect();
+        let err = res.unwrap_err();
+        assert!(matches!(err, LysoError::Format { .. }));
+        assert_eq!(err.to_string(), "FASTA: line 3: Unexpected end of file");
+    }
+
+    #[test]
+    fn test_non_utf8_id() {
+        // latin-1 e-acute
+        let input = b">r\xe9ad desc\nACGT\n>ok\nGG\n";
+        let recs = FastaReader::new(&input[..])
+            .collect::<Result<Vec<_>, FastaError>>()
+            .unwrap();
+        assert_eq!(recs[0].id(), b"r\xe9ad desc");
+        assert_eq!(recs[0].id_str_lossy(), "r\u{fffd}ad desc");
+        let mut out = Vec::new();
+        for rec in &recs {
+            rec.write_to(&mut out).unwrap();
+        }
+        assert_eq!(out, input);
+
+        let mut strict = FastaReader::new(&input[..]).strict_utf8(true);
+        let err = strict.next().unwrap().unwrap_err();
+        assert!(!err.is_terminal());
+        assert!(err.to_string().contains("r\u{fffd}ad desc"));
+        assert_eq!(strict.next().unwrap().unwrap().id(), b"ok");
+    }
+
+    #[test]
+    fn test_fragments_match_records() {
+        use lyso_common::config::ReaderConfig;
+
+        for (path, capacity, chunk) in [FA_PATH, "../resources/test_data/gt_in_desc.fa"]
+            .into_iter()
+            .flat_map(|p| [(p, 1), (p, 7), (p, 8192)])
+            .flat_map(|(p, c)| [(p, c, 3), (p, c, usize::MAX)])
+        {
+            let expected = FastaReader::from_path(path)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let f = BufReader::with_capacity(capacity, File::open(path).unwrap());
+            let mut got: Vec<(Vec<u8>, String)> = Vec::new();
+            let config = ReaderConfig {
+                read_chunk_size: chunk,
+                ..ReaderConfig::default()
+            };
+            for fragment in FragmentReader::with_config(f, config) {
+                match fragment.unwrap() {
+                    Fragment::Header(id) => got.push((id, String::new())),
+                    Fragment::Sequence { offset, seq } => {
+                        let (_, s) = got.last_mut().unwrap();
+                        assert_eq!(offset, s.len());
+                        assert!(seq.len() <= chunk);
+                        s.push_str(&seq);
+                    }
+                }
+            }
+            let expected = expected
+                .iter()
+                .map(|r| (r.id().to_vec(), r.seq().to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(got, expected);
+        }
+    }
+
+    #[test]
+    fn test_fragment_errors() {
+        let input = b">a\r\nAC\r\nGT>b\n\nT\n>c\n";
+        let fragments = FragmentReader::new(&input[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let seq = |offset, seq: &str| Fragment::Sequence {
+            offset,
+            seq: seq.to_string(),
+        };
+        assert_eq!(
+            fragments,
+            [
+                Fragment::Header(b"a".to_vec()),
+                seq(0, "AC"),
+                seq(2, "GT>b"),
+                seq(6, "T"),
+                Fragment::Header(b"c".to_vec()),
+            ]
+        );
+
+        let cases: [&[u8]; 3] = [
+            b"ACGT\n>a\nAC\n",
+            b">a\nA\xc3\xa9\n>b\nAC\n",
+            b">a\nAC\n>trunc",
+        ];
+        for input in cases {
+            let mut reader = FragmentReader::new(input);
+            assert_eq!(reader.by_ref().filter(|f| f.is_err()).count(), 1);
+            assert!(reader.next().is_none());
+        }
+
+        // a header over max_record_size, counting its '>' and line ending
+        let config = lyso_common::config::ReaderConfig {
+            max_record_size: 5,
+            ..Default::default()
+        };
+        let mut reader = FragmentReader::with_config(&b">abc\nAC\n>abcd\nAC\n"[..], config);
+        assert_eq!(
+            reader.nth(2).unwrap().unwrap_err().to_string(),
+            "record longer than 5 bytes"
+        );
+        assert!(reader.next().is_none());
+    }
 }
@@ -0,0 +1,185 @@
+//! Scaffolds split into contigs at runs of N
+//!
+//! A run of at least `min_gap` N (either case) is a gap; everything between
+//! gaps is a contig. Shorter N runs stay inside their contig, and gaps at either
+//! end of a scaffold leave no empty contig behind. Contigs are named after the
+//! scaffold's first word with a 1-based suffix: `scf1_1`, `scf1_2`, ...
+//!
+//! ```
+//! use lyso_fasta::split::split_at_gaps;
+//! use lyso_fasta::Record;
+//!
+//! let scaffold = Record::from_parts("scf1 len=16", "NNACGTNNNNGGNCCN");
+//! let contigs = split_at_gaps(&scaffold, 3);
+//! let pieces = contigs
+//!     .iter()
+//!     .map(|(rec, offset)| (rec.to_string(), *offset))
+//!     .collect::<Vec<_>>();
+//! assert_eq!(pieces, [(">scf1_1\nNNACGT".to_string(), 0), (">scf1_2\nGGNCCN".to_string(), 10)]);
+//! ```
+
+use std::ops::Range;
+
+use lyso_common::runs::{find_runs, is_gap};
+
+use crate::Record;
+
+/// A stretch of a scaffold, as a 0-based, half-open range of its sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    /// Bases kept as a contig
+    Contig(Range<usize>),
+    /// A run of N at least the minimum gap long
+    Gap(Range<usize>),
+}
+
+impl Part {
+    /// The range of the scaffold covered
+    pub fn range(&self) -> &Range<usize> {
+        match self {
+            Part::Contig(r) | Part::Gap(r) => r,
+        }
+    }
+}
+
+/// Contigs and gaps of `seq` in order, together covering all of it
+///
+/// Gaps are maximal runs of N at least `min_gap` long (0 counts as 1). A
+/// sequence of only N is one gap; an empty sequence has no parts.
+pub fn gap_layout(seq: &[u8], min_gap: usize) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut pos = 0;
+    for gap in find_runs(seq, is_gap, min_gap) {
+        if gap.start > pos {
+            parts.push(Part::Contig(pos..gap.start));
+        }
+        pos = gap.end;
+        parts.push(Part::Gap(gap));
+    }
+    if pos < seq.len() {
+        parts.push(Part::Contig(pos..seq.len()));
+    }
+    parts
+}
+
+/// The first word of `record`'s header, which contig names are built from
+pub fn scaffold_name(record: &Record) -> &[u8] {
+    record
+        .id()
+        .split(u8::is_ascii_whitespace)
+        .next()
+        .unwrap_or_default()
+}
+
+/// The contigs of `record` with their 0-based offsets in it, split at gaps of
+/// at least `min_gap` N
+///
+/// A record without gaps becomes a single contig; one of only N, none.
+pub fn split_at_gaps(record: &Record, min_gap: usize) -> Vec<(Record, u64)> {
+    layout_contigs(record, &gap_layout(record.seq().as_bytes(), min_gap))
+}
+
+/// The contigs of `record` with their 0-based offsets in it, one for each
+/// `Part::Contig` of `layout`, which `gap_layout` found for its sequence
+pub fn layout_contigs(record: &Record, layout: &[Part]) -> Vec<(Record, u64)> {
+    let name = scaffold_name(record);
+    layout
+        .iter()
+        .filter_map(|part| match part {
+            Part::Contig(r) => Some(r.clone()),
+            Part::Gap(_) => None,
+        })
+        .enumerate()
+        .map(|(i, r)| {
+            let id = [name, format!("_{}", i + 1).as_bytes()].concat();
+            let offset = r.start as u64;
+            (Record::from_parts(id, &record.seq()[r]), offset)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contigs(seq: &str, min_gap: usize) -> Vec<(String, String, u64)> {
+        split_at_gaps(&Record::from_parts("s desc", seq), min_gap)
+            .into_iter()
+            .map(|(rec, offset)| {
+                (
+                    rec.id_str_lossy().into_owned(),
+                    rec.seq().to_string(),
+                    offset,
+                )
+            })
+            .collect()
+    }
+
+    fn piece(id: &str, seq: &str, offset: u64) -> (String, String, u64) {
+        (id.to_string(), seq.to_string(), offset)
+    }
+
+    #[test]
+    fn test_edge_gaps() {
+        // leading and trailing gaps leave no empty contigs
+        assert_eq!(
+            contigs("NNNNACGTnnnnnTTGGNNNN", 4),
+            [piece("s_1", "ACGT", 4), piece("s_2", "TTGG", 13)]
+        );
+        assert!(contigs("NNNNNNNN", 3).is_empty());
+        assert!(contigs("", 3).is_empty());
+        assert_eq!(contigs("ACGT", 1), [piece("s_1", "ACGT", 0)]);
+        assert_eq!(gap_layout(b"nnnn", 2), [Part::Gap(0..4)]);
+    }
+
+    #[test]
+    fn test_gap_below_min_is_kept() {
+        assert_eq!(contigs("ACNNNGT", 4), [piece("s_1", "ACNNNGT", 0)]);
+        assert_eq!(
+            contigs("ACNNNNGT", 4),
+            [piece("s_1", "AC", 0), piece("s_2", "GT", 6)]
+        );
+        // 0 behaves as 1: every N splits
+        assert_eq!(
+            contigs("ANCNNG", 0),
+            [
+                piece("s_1", "A", 0),
+                piece("s_2", "C", 2),
+                piece("s_3", "G", 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layout_round_trip() {
+        for seq in ["NNACGTNNNNNNGGCCnnnnnnATNNNN", "ACGT", "NNNNNN", "ANNNNNT"] {
+            let rec = Record::from_parts("scf", seq);
+            let layout = gap_layout(seq.as_bytes(), 3);
+            let pieces = split_at_gaps(&rec, 3);
+
+            // the layout tiles the sequence
+            let mut pos = 0;
+            for part in &layout {
+                assert_eq!(part.range().start, pos, "{seq}");
+                pos = part.range().end;
+            }
+            assert_eq!(pos, seq.len());
+
+            // contigs and gaps put back in layout order give the scaffold, with Ns for gaps
+            let mut contigs = pieces.iter();
+            let rebuilt = layout
+                .iter()
+                .map(|part| match part {
+                    Part::Contig(r) => {
+                        let (contig, offset) = contigs.next().unwrap();
+                        assert_eq!(*offset, r.start as u64);
+                        contig.seq().to_string()
+                    }
+                    Part::Gap(r) => "N".repeat(r.len()),
+                })
+                .collect::<String>();
+            assert_eq!(rebuilt, seq.replace('n', "N"), "{seq}");
+            assert!(contigs.next().is_none());
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Whole references held in memory, for references too small to be worth indexing
+//!
+//! `FastaStore` keeps every record and looks them up by name, the first word of
+//! the header as in a `.fai` index. `ReferenceSource` lets callers fetch from a
+//! store or from an `IndexedFasta` alike.
+
+use std::borrow::Cow;
+use std::io::{Read, Seek};
+
+use lyso_common::name_index::NameIndex;
+use lyso_common::pos::ZeroBased;
+use lyso_common::region::Region;
+
+use crate::indexer::IndexedFasta;
+use crate::{FastaError, FetchError, Record};
+
+/// Sequence bytes a `FastaStore` holds by default, 64 MiB
+pub const DEFAULT_MAX_BYTES: u64 = 64 << 20;
+
+/// First word of a FASTA header
+fn record_name(id: &[u8]) -> &[u8] {
+    id.split(u8::is_ascii_whitespace).next().unwrap_or_default()
+}
+
+/// Every record of a FASTA file, in file order, looked up by name
+///
+/// ```
+/// use lyso_fasta::reader::FastaReader;
+/// use lyso_fasta::store::FastaStore;
+///
+/// let input = b">chr1 first\nACGT\nAC\n>chr2\nGG\n";
+/// let store = FastaStore::from_reader(FastaReader::new(&input[..])).unwrap();
+/// assert_eq!(store.fetch("chr1", 2, 5), Some("GTA"));
+/// assert_eq!(store.get("chr2").unwrap().seq(), "GG");
+/// assert_eq!(store.size(), 8);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FastaStore {
+    /// Records in file order
+    records: Vec<Record>,
+    /// Position of each name in `records`
+    by_name: NameIndex,
+    size: u64,
+}
+
+impl FastaStore {
+    /// Read every record of `reader`, holding at most `DEFAULT_MAX_BYTES` of sequence
+    pub fn from_reader<I>(reader: I) -> Result<Self, FastaError>
+    where
+        I: IntoIterator<Item = Result<Record, FastaError>>,
+    {
+        Self::from_reader_capped(reader, DEFAULT_MAX_BYTES)
+    }
+
+    /// Read every record of `reader`, holding at most `max_bytes` of sequence
+    ///
+    /// Fails with `FastaError::TooLarge` as soon as the cap is passed, and with
+    /// `FastaError::DuplicateName` on a repeated name.
+    pub fn from_reader_capped<I>(reader: I, max_bytes: u64) -> Result<Self, FastaError>
+    where
+        I: IntoIterator<Item = Result<Record, FastaError>>,
+    {
+        let mut store = FastaStore::default();
+        for rec in reader {
+            let rec = rec?;
+            store.size += rec.seq().len() as u64;
+            if store.size > max_bytes {
+                return Err(FastaError::TooLarge { limit: max_bytes });
+            }
+            let name = record_name(rec.id());
+            if store.position(name).is_some() {
+                return Err(FastaError::DuplicateName {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    offsets: None,
+                });
+            }
+            store.by_name.push(name);
+            store.records.push(rec);
+        }
+        Ok(store)
+    }
+
+    /// Position in `records` of the record named `name`
+    fn position(&self, name: &[u8]) -> Option<usize> {
+        self.by_name
+            .find(name, |i| record_name(self.records[i].id()))
+    }
+
+    /// The record named `name`
+    pub fn get(&self, name: impl AsRef<[u8]>) -> Option<&Record> {
+        self.position(name.as_ref()).map(|i| &self.records[i])
+    }
+
+    /// Bases `start..end` (0-based, half-open) of the record named `name`
+    ///
+    /// `None` for an unknown name or a range that is reversed or runs past the end.
+    pub fn fetch(&self, name: impl AsRef<[u8]>, start: u64, end: u64) -> Option<&str> {
+        let seq = self.get(name)?.seq();
+        seq.get(usize::try_from(start).ok()?..usize::try_from(end).ok()?)
+    }
+
+    /// Whether a record is named `name`
+    pub fn contains(&self, name: impl AsRef<[u8]>) -> bool {
+        self.position(name.as_ref()).is_some()
+    }
+
+    /// Records in file order
+    pub fn iter(&self) -> impl Iterator<Item = &Record> {
+        self.records.iter()
+    }
+
+    /// Number of records
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether the store holds no records
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Total sequence bytes held
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// A reference fetched from memory or through a `.fai` index
+pub enum ReferenceSource<'a, F> {
+    /// Every record loaded up front
+    Memory(FastaStore),
+    /// Records read from disk as they are needed
+    Indexed(IndexedFasta<'a, F>),
+}
+
+impl<F> ReferenceSource<'_, F>
+where
+    F: Read + Seek,
+{
+    /// Name and length of each sequence, in file order
+    pub fn sequences(&self) -> Vec<(String, u64)> {
+        match self {
+            ReferenceSource::Memory(store) => store
+                .iter()
+                .map(|r| {
+                    let name = String::from_utf8_lossy(record_name(r.id())).into_owned();
+                    (name, r.seq().len() as u64)
+                })
+                .collect(),
+            ReferenceSource::Indexed(fasta) => fasta
+                .index()
+                .entries()
+                .map(|e| (e.name_str_lossy().into_owned(), *e.length()))
+                .collect(),
+        }
+    }
+
+    /// Whether a sequence is named `name`
+    pub fn contains(&self, name: &str) -> bool {
+        match self {
+            ReferenceSource::Memory(store) => store.contains(name),
+            ReferenceSource::Indexed(fasta) => fasta.index().contains(name),
+        }
+    }
+
+    /// The whole record named `name`
+    pub fn get(&mut self, name: &str) -> Result<Cow<'_, Record>, FetchError> {
+        match self {
+            ReferenceSource::Memory(store) => store
+                .get(name)
+                .map(Cow::Borrowed)
+                .ok_or_else(|| FetchError::NotFound(name.to_string())),
+            ReferenceSource::Indexed(fasta) => fasta.get(name).map(Cow::Owned),
+        }
+    }
+
+    /// Bases `start..end` (0-based, half-open) of the sequence named `name`
+    ///
+    /// Unlike `IndexedFasta::get_region`, an end past the sequence is an error.
+    pub fn fetch(&mut self, name: &str, start: u64, end: u64) -> Result<Cow<'_, str>, FetchError> {
+        let out_of_bounds = || FetchError::OutOfBounds(format!("{name}:{start}-{end}"));
+        match self {
+            ReferenceSource::Memory(store) => match store.fetch(name, start, end) {
+                Some(seq) => Ok(Cow::Borrowed(seq)),
+                None if store.contains(name) => Err(out_of_bounds()),
+                None => Err(FetchError::NotFound(name.to_string())),
+            },
+            ReferenceSource::Indexed(fasta) => {
+                let len = *fasta
+                    .index()
+                    .get(name)
+                    .ok_or_else(|| FetchError::NotFound(name.to_string()))?
+                    .length();
+                if start > end || end > len {
+                    return Err(out_of_bounds());
+                }
+                if start == end {
+                    return Ok(Cow::Borrowed(""));
+                }
+                let region = Region::new(name, ZeroBased::new(start), Some(ZeroBased::new(end)))
+                    .map_err(|_| out_of_bounds())?;
+                let rec = fasta.get_region(&region)?;
+                Ok(Cow::Owned(rec.seq().to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::FastaIndex;
+    use crate::reader::FastaReader;
+    use std::io::Cursor;
+
+    const FA: &[u8] = b">chr1 desc\nACGTA\nCG\n>chr2\nTTT\n>chr3\nG\n";
+
+    fn store() -> FastaStore {
+        FastaStore::from_reader(FastaReader::new(FA)).unwrap()
+    }
+
+    #[test]
+    fn test_store() {
+        let store = store();
+        let names = store.iter().map(|r| r.id_str_lossy()).collect::<Vec<_>>();
+        assert_eq!(names, ["chr1 desc", "chr2", "chr3"]);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.size(), 11);
+        assert_eq!(store.get("chr1").unwrap().seq(), "ACGTACG");
+        assert!(store.get("chr1 desc").is_none());
+        assert!(!store.contains("chrX"));
+    }
+
+    #[test]
+    fn test_fetch_bounds() {
+        let store = store();
+        assert_eq!(store.fetch("chr1", 0, 7), Some("ACGTACG"));
+        assert_eq!(store.fetch("chr1", 6, 7), Some("G"));
+        assert_eq!(store.fetch("chr1", 7, 7), Some(""));
+        assert_eq!(store.fetch("chr1", 0, 8), None);
+        assert_eq!(store.fetch("chr1", 8, 8), None);
+        assert_eq!(store.fetch("chr1", 3, 2), None);
+        assert_eq!(store.fetch("chrX", 0, 0), None);
+    }
+
+    #[test]
+    fn test_duplicate_name() {
+        let input = b">a x\nAC\n>b\nG\n>a y\nGT\n";
+        let err = FastaStore::from_reader(FastaReader::new(&input[..])).unwrap_err();
+        assert!(
+            matches!(err, FastaError::DuplicateName { ref name, offsets: None } if name == "a")
+        );
+        assert_eq!(err.to_string(), "duplicate record name a");
+    }
+
+    #[test]
+    fn test_cap() {
+        assert_eq!(
+            FastaStore::from_reader_capped(FastaReader::new(FA), 11)
+                .unwrap()
+                .size(),
+            11
+        );
+        let err = FastaStore::from_reader_capped(FastaReader::new(FA), 10).unwrap_err();
+        assert!(matches!(err, FastaError::TooLarge { limit: 10 }));
+        assert!(err.to_string().contains("lyso faidx"));
+    }
+
+    #[test]
+    fn test_sources_agree() {
+        let index = FastaIndex::from_fasta_file(&mut Cursor::new(FA), Default::default()).unwrap();
+        let mut memory = ReferenceSource::<Cursor<&[u8]>>::Memory(store());
+        let mut indexed = ReferenceSource::Indexed(IndexedFasta::new(Cursor::new(FA), &index));
+        assert_eq!(memory.sequences(), indexed.sequences());
+        for source in [&mut memory, &mut indexed] {
+            assert!(source.contains("chr2") && !source.contains("chrX"));
+            assert_eq!(source.get("chr2").unwrap().seq(), "TTT");
+            assert_eq!(source.fetch("chr1", 4, 7).unwrap(), "ACG");
+            assert_eq!(source.fetch("chr1", 7, 7).unwrap(), "");
+            assert!(matches!(
+                source.fetch("chr1", 5, 8),
+                Err(FetchError::OutOfBounds(_))
+            ));
+            assert!(matches!(
+                source.fetch("chrX", 0, 1),
+                Err(FetchError::NotFound(_))
+            ));
+        }
+    }
+}
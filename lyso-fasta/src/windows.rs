@@ -0,0 +1,313 @@
+//! Fixed-size windows over FASTA sequences
+//!
+//! Windows of `size` bases start at 0, `step`, 2 * `step` and so on; when `step`
+//! is larger than `size`, the bases between windows are left out. By default the
+//! first window to run past the end of the sequence is cut short and emitted, so
+//! every base up to the last step is covered, and later windows, which would lie
+//! inside it, are not. With `partial(false)` it is skipped, and every window has
+//! `size` bases. A sequence shorter than `size` gives one short window, or none.
+
+use std::io::BufRead;
+use std::rc::Rc;
+
+use crate::reader::{Fragment, FragmentReader};
+use crate::{FastaError, Record};
+
+/// The windows of one record, as `(start, bases)` with 0-based starts
+#[derive(Debug, Clone)]
+pub struct WindowIter<'a> {
+    seq: &'a str,
+    size: usize,
+    step: usize,
+    partial: bool,
+    /// Start of the next window
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> WindowIter<'a> {
+    /// Windows of `window` bases every `step` bases of `record`
+    ///
+    /// Panics if either is 0.
+    #[must_use]
+    pub fn new(record: &'a Record, window: usize, step: usize) -> Self {
+        assert!(window > 0 && step > 0, "window and step must be positive");
+        WindowIter {
+            seq: record.seq(),
+            size: window,
+            step,
+            partial: true,
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Emit the window cut short by the end of the sequence; on by default
+    #[must_use]
+    pub fn partial(mut self, emit: bool) -> Self {
+        self.partial = emit;
+        self
+    }
+}
+
+impl<'a> Iterator for WindowIter<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.seq.len();
+        if self.done || self.pos >= len {
+            return None;
+        }
+        let start = self.pos;
+        let end = start.saturating_add(self.size);
+        if end >= len {
+            self.done = true;
+            if end > len && !self.partial {
+                return None;
+            }
+        }
+        self.pos = start.saturating_add(self.step);
+        // sequences are ASCII; anything else is cut at a character boundary
+        let (start, end) = (floor_char_boundary(self.seq, start), end.min(len));
+        Some((start, &self.seq[start..floor_char_boundary(self.seq, end)]))
+    }
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// A window read by `StreamWindows`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    /// Header of the record, shared by all of its windows
+    pub id: Rc<[u8]>,
+    /// 0-based start within the record
+    pub start: usize,
+    /// Bases of the window
+    pub seq: String,
+}
+
+impl Window {
+    /// 0-based, exclusive end within the record
+    pub fn end(&self) -> usize {
+        self.start + self.seq.len()
+    }
+}
+
+/// The windows of every record of a FASTA stream, holding one window at a time
+///
+/// Gives the same windows as `WindowIter` on each record, read through a
+/// `FragmentReader`, so records never have to fit in memory.
+#[derive(Debug)]
+pub struct StreamWindows<T: BufRead> {
+    fragments: FragmentReader<T>,
+    size: usize,
+    step: usize,
+    partial: bool,
+    /// Header of the current record, None before the first
+    id: Option<Rc<[u8]>>,
+    /// Bases read from `buf_start` on
+    buf: String,
+    buf_start: usize,
+    /// Start of the next window
+    next: usize,
+    /// End of the last window emitted from this record
+    last_end: usize,
+}
+
+impl<T: BufRead> StreamWindows<T> {
+    /// Windows of `window` bases every `step` bases of each record in `inner`
+    ///
+    /// Panics if either is 0.
+    #[must_use]
+    pub fn new(inner: T, window: usize, step: usize) -> Self {
+        assert!(window > 0 && step > 0, "window and step must be positive");
+        StreamWindows {
+            fragments: FragmentReader::new(inner),
+            size: window,
+            step,
+            partial: true,
+            id: None,
+            buf: String::new(),
+            buf_start: 0,
+            next: 0,
+            last_end: 0,
+        }
+    }
+
+    /// Emit the window cut short by the end of each sequence; on by default
+    #[must_use]
+    pub fn partial(mut self, emit: bool) -> Self {
+        self.partial = emit;
+        self
+    }
+
+    fn window(&self, start: usize, end: usize) -> Option<Window> {
+        Some(Window {
+            id: self.id.clone()?,
+            start,
+            seq: self.buf[start - self.buf_start..end - self.buf_start].to_string(),
+        })
+    }
+
+    /// Drop buffered bases before the next window
+    fn trim(&mut self) {
+        let n = self.next.saturating_sub(self.buf_start).min(self.buf.len());
+        // fragments are ASCII, so any cut is on a character boundary
+        self.buf.drain(..n);
+        self.buf_start += n;
+    }
+
+    /// The partial window at the end of the current record, if it gets one
+    fn finish_record(&mut self) -> Option<Window> {
+        let len = self.buf_start + self.buf.len();
+        if !self.partial || self.next >= len || self.last_end >= len {
+            return None;
+        }
+        self.window(self.next, len)
+    }
+
+    fn start_record(&mut self, id: Vec<u8>) {
+        self.id = Some(id.into());
+        self.buf.clear();
+        self.buf_start = 0;
+        self.next = 0;
+        self.last_end = 0;
+    }
+}
+
+impl<T: BufRead> Iterator for StreamWindows<T> {
+    type Item = Result<Window, FastaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let end = self.next.saturating_add(self.size);
+            if end <= self.buf_start + self.buf.len() {
+                let window = self.window(self.next, end);
+                self.last_end = end;
+                self.next = self.next.saturating_add(self.step);
+                self.trim();
+                return window.map(Ok);
+            }
+            match self.fragments.read_fragment() {
+                Some(Ok(Fragment::Sequence { seq, .. })) => {
+                    self.buf.push_str(&seq);
+                    self.trim();
+                }
+                Some(Ok(Fragment::Header(id))) => {
+                    let tail = self.finish_record();
+                    self.start_record(id);
+                    if tail.is_some() {
+                        return tail.map(Ok);
+                    }
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    let tail = self.finish_record();
+                    self.id = None;
+                    return tail.map(Ok);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use super::*;
+    use crate::reader::FastaReader;
+
+    fn record(seq: &str) -> Record {
+        Record::from_parts("r", seq)
+    }
+
+    fn windows(seq: &str, size: usize, step: usize, partial: bool) -> Vec<(usize, String)> {
+        let rec = record(seq);
+        let expected = WindowIter::new(&rec, size, step)
+            .partial(partial)
+            .map(|(start, w)| (start, w.to_string()))
+            .collect::<Vec<_>>();
+        let input = format!(">r\n{seq}\n");
+        let streamed = StreamWindows::new(input.as_bytes(), size, step)
+            .partial(partial)
+            .map(|w| w.map(|w| (w.start, w.seq)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(streamed, expected, "{seq} {size} {step} {partial}");
+        expected
+    }
+
+    fn starts(seq: &str, size: usize, step: usize, partial: bool) -> Vec<(usize, usize)> {
+        windows(seq, size, step, partial)
+            .into_iter()
+            .map(|(start, w)| (start, start + w.len()))
+            .collect()
+    }
+
+    #[test]
+    fn test_boundaries() {
+        let seq = "ACGTACGTAC";
+        assert_eq!(
+            windows(seq, 4, 4, true),
+            [(0, "ACGT".into()), (4, "ACGT".into()), (8, "AC".into())]
+        );
+        assert_eq!(starts(seq, 4, 4, false), [(0, 4), (4, 8)]);
+        // the last full window ends exactly at the end
+        assert_eq!(starts(seq, 5, 5, true), [(0, 5), (5, 10)]);
+        assert_eq!(starts(seq, 4, 3, true), [(0, 4), (3, 7), (6, 10)]);
+        // only the first window past the end is emitted
+        assert_eq!(starts(seq, 4, 2, true), [(0, 4), (2, 6), (4, 8), (6, 10)]);
+        assert_eq!(starts(seq, 8, 1, true), [(0, 8), (1, 9), (2, 10)]);
+        assert_eq!(starts(seq, 3, 4, true), [(0, 3), (4, 7), (8, 10)]);
+        assert_eq!(starts(seq, 3, 4, false), [(0, 3), (4, 7)]);
+    }
+
+    #[test]
+    fn test_short_sequences() {
+        assert_eq!(starts("ACGT", 10, 5, true), [(0, 4)]);
+        assert_eq!(starts("ACGT", 10, 5, false), []);
+        assert_eq!(starts("ACGT", 4, 10, false), [(0, 4)]);
+        assert_eq!(starts("A", 1, 1, true), [(0, 1)]);
+        assert_eq!(starts("", 3, 1, true), []);
+        // a step past the end leaves nothing for a partial window
+        assert_eq!(starts("ACGTAC", 2, 6, true), [(0, 2)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "window and step must be positive")]
+    fn test_zero_step() {
+        let _ = WindowIter::new(&record("ACGT"), 2, 0);
+    }
+
+    #[test]
+    fn test_stream_matches_records() {
+        let path = "../resources/test_data/masked.fa";
+        let records = FastaReader::from_path(path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        for (size, step) in [(4, 4), (5, 3), (3, 5), (100, 1)] {
+            let expected = records
+                .iter()
+                .flat_map(|rec| {
+                    WindowIter::new(rec, size, step)
+                        .map(|(start, w)| (rec.id().to_vec(), start, w.to_string()))
+                })
+                .collect::<Vec<_>>();
+            // a 3-byte buffer splits lines into several fragments
+            let f = BufReader::with_capacity(3, File::open(path).unwrap());
+            let streamed = StreamWindows::new(f, size, step)
+                .map(|w| w.map(|w| (w.id.to_vec(), w.start, w.seq)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(streamed, expected, "{size} {step}");
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! FASTA output
+
+use std::io::Write;
+
+use crate::Record;
+
+/// A FASTA writer
+///
+/// Writes ids as stored and sequences on a single line unless `line_width` is set.
+pub struct FastaWriter<W> {
+    inner: W,
+    line_width: usize,
+    bytes_written: u64,
+}
+
+impl<W> FastaWriter<W>
+where
+    W: Write,
+{
+    /// A writer to `inner` that does not wrap sequences
+    #[must_use]
+    pub fn new(inner: W) -> Self {
+        FastaWriter {
+            inner,
+            line_width: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Wrap sequences after this many bases; 0 (the default) does not wrap
+    #[must_use]
+    pub fn line_width(mut self, width: usize) -> Self {
+        self.line_width = width;
+        self
+    }
+
+    /// The bases per line set by `line_width`, 0 for none
+    pub fn width(&self) -> usize {
+        self.line_width
+    }
+
+    /// Bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Write `rec`, wrapping its sequence if `line_width` is set
+    pub fn write_record(&mut self, rec: &Record) -> std::io::Result<()> {
+        if self.line_width == 0 {
+            rec.write_to(&mut self.inner)?;
+            self.bytes_written += (rec.id().len() + rec.seq().len() + 3) as u64;
+            return Ok(());
+        }
+        self.write_wrapped(rec, self.line_width)
+    }
+
+    /// Write `rec` with `width` bases per line, whatever `line_width` is set to
+    ///
+    /// An empty sequence is written as the header line alone.
+    pub(crate) fn write_wrapped(&mut self, rec: &Record, width: usize) -> std::io::Result<()> {
+        self.inner.write_all(b">")?;
+        self.inner.write_all(rec.id())?;
+        self.inner.write_all(b"\n")?;
+        self.bytes_written += (rec.id().len() + 2) as u64;
+        for line in rec.seq().as_bytes().chunks(width) {
+            self.inner.write_all(line)?;
+            self.inner.write_all(b"\n")?;
+            self.bytes_written += (line.len() + 1) as u64;
+        }
+        Ok(())
+    }
+
+    /// Flush the underlying writer
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// The underlying writer
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, seq: &str) -> Record {
+        Record::from_parts(id, seq)
+    }
+
+    #[test]
+    fn test_line_width() {
+        let mut w = FastaWriter::new(Vec::new());
+        w.write_record(&record("r1 desc", "ACGTACGTAC")).unwrap();
+        assert_eq!(w.bytes_written(), 20);
+        assert_eq!(w.into_inner(), b">r1 desc\nACGTACGTAC\n");
+
+        let mut w = FastaWriter::new(Vec::new()).line_width(4);
+        w.write_record(&record("r1", "ACGTACGTAC")).unwrap();
+        w.write_record(&record("r2", "ACGT")).unwrap();
+        assert_eq!(w.bytes_written(), 26);
+        assert_eq!(w.into_inner(), b">r1\nACGT\nACGT\nAC\n>r2\nACGT\n");
+    }
+}
@@ -0,0 +1,23 @@
+//! Runs the programs in examples/ against the test data and checks their exit codes
+
+#[path = "../../resources/run_example.rs"]
+mod run_example;
+
+use run_example::run;
+
+#[test]
+fn test_fasta_index_fetch() {
+    let out = run(
+        "fasta_index_fetch",
+        &["../resources/test_data/test.fa", "SRR22092847.1.1:3-8"],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&out.stdout).ends_with("\nTTAAAG\n"));
+
+    assert_eq!(run("fasta_index_fetch", &["x.fa"]).status.code(), Some(2));
+    let out = run(
+        "fasta_index_fetch",
+        &["../resources/test_data/missing.fa", "chr1"],
+    );
+    assert_eq!(out.status.code(), Some(1));
+}
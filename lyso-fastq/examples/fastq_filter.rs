@@ -0,0 +1,41 @@
+//! Keep the reads of a FASTQ file whose mean quality reaches a threshold
+//!
+//! Usage: fastq_filter <in.fastq> <min-mean-qual> [out.fastq]
+//!
+//! Writes to stdout without an output path, and prints the kept and total
+//! read counts to stderr.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::process::exit;
+
+use lyso_common::filter::{MinMeanQuality, RecordPredicate};
+use lyso_fastq::reader::FastqReader;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<String>>();
+    if !(3..=4).contains(&args.len()) {
+        eprintln!("usage: fastq_filter <in.fastq> <min-mean-qual> [out.fastq]");
+        exit(2);
+    }
+    let filter = MinMeanQuality(args[2].parse()?);
+    let out: Box<dyn Write> = match args.get(3) {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+
+    let (mut kept, mut total) = (0, 0);
+    for rec in FastqReader::from_path(&args[1])? {
+        let rec = rec?;
+        total += 1;
+        if filter.test(&rec) {
+            kept += 1;
+            write!(out, "{rec}")?;
+        }
+    }
+    out.flush()?;
+    eprintln!("kept {kept} of {total} reads");
+    Ok(())
+}
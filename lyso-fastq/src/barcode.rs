@@ -0,0 +1,507 @@
+//! Moving sample barcodes and UMIs from reads into their headers
+//!
+//! A `BarcodePattern` such as "8B12U" lists segments at the start of a read, each a
+//! length and a kind: B for sample barcode, U for UMI, X for bases to drop. An
+//! `Extractor` trims them off the sequence and quality of a read and records the
+//! barcode and UMI in its header, either appended to the id as umi_tools does
+//! (`read1_ACGTACGT_TTGCAATTGCAA`), or as `BC:Z:` and `RX:Z:` fields in the
+//! description. The barcode can also come from the index field of a Casava 1.8
+//! description (`1:N:0:ATCACG`), for runs that demultiplexed it out of the read.
+//!
+//! ```
+//! use lyso_fastq::barcode::Extractor;
+//! use lyso_fastq::Record;
+//!
+//! let extractor = Extractor::new("4B6U".parse().unwrap());
+//! let mut rec = Record::from_parts("r1", "ACGTTTGCAAGATTACA", "ABCDEFGHIJKLMNOPQ").unwrap();
+//! let found = extractor.extract(&mut rec).unwrap();
+//! assert_eq!((found.barcode.as_str(), found.umi.as_str()), ("ACGT", "TTGCAA"));
+//! assert_eq!(rec.to_string(), "@r1_ACGT_TTGCAA\nGATTACA\n+\nKLMNOPQ\n");
+//! ```
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::{FastqError, Record};
+
+/// What the bases of a pattern segment are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// Sample barcode, `B`
+    Barcode,
+    /// Unique molecular identifier, `U`
+    Umi,
+    /// Bases to trim and drop, `X`
+    Skip,
+}
+
+impl SegmentKind {
+    fn code(self) -> char {
+        match self {
+            SegmentKind::Barcode => 'B',
+            SegmentKind::Umi => 'U',
+            SegmentKind::Skip => 'X',
+        }
+    }
+}
+
+/// Segments at the start of a read, such as "8B12U"; see the module docs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BarcodePattern {
+    segments: Vec<(SegmentKind, usize)>,
+}
+
+impl BarcodePattern {
+    /// Bases the pattern covers
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Whether the pattern covers no bases, as for a mate without a barcode
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The segments in read order
+    pub fn segments(&self) -> &[(SegmentKind, usize)] {
+        &self.segments
+    }
+
+    fn has(&self, kind: SegmentKind) -> bool {
+        self.segments.iter().any(|(k, _)| *k == kind)
+    }
+}
+
+impl FromStr for BarcodePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid barcode pattern '{s}', expected segments such as 8B12U");
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let len = rest[..digits].parse::<usize>().map_err(|_| err())?;
+            let kind = match rest[digits..].chars().next() {
+                Some('B' | 'b') => SegmentKind::Barcode,
+                Some('U' | 'u') => SegmentKind::Umi,
+                Some('X' | 'x') => SegmentKind::Skip,
+                _ => return Err(err()),
+            };
+            if len == 0 {
+                return Err(err());
+            }
+            segments.push((kind, len));
+            rest = &rest[digits + 1..];
+        }
+        if segments.is_empty() {
+            return Err(err());
+        }
+        Ok(BarcodePattern { segments })
+    }
+}
+
+impl Display for BarcodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (kind, len) in &self.segments {
+            write!(f, "{len}{}", kind.code())?;
+        }
+        Ok(())
+    }
+}
+
+/// Where `Extractor` records what it extracted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// Appended to the id as `_BARCODE_UMI`, leaving out a part the reads lack
+    #[default]
+    Id,
+    /// Appended to the description as `BC:Z:BARCODE RX:Z:UMI`
+    Desc,
+}
+
+/// The barcode and UMI of a read or pair, empty when the pattern has none
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extracted {
+    /// Bases of the B segments, after any Casava index
+    pub barcode: String,
+    /// Bases of the U segments
+    pub umi: String,
+}
+
+/// Trims barcodes and UMIs off reads and records them in the headers
+#[derive(Debug, Clone, Default)]
+pub struct Extractor {
+    read1: BarcodePattern,
+    read2: BarcodePattern,
+    placement: Placement,
+    casava: bool,
+}
+
+impl Extractor {
+    /// Trim `pattern` off single reads and first mates
+    #[must_use]
+    pub fn new(pattern: BarcodePattern) -> Self {
+        Extractor {
+            read1: pattern,
+            ..Extractor::default()
+        }
+    }
+
+    /// Trim `pattern` off second mates too; by default they are left whole
+    #[must_use]
+    pub fn read2_pattern(mut self, pattern: BarcodePattern) -> Self {
+        self.read2 = pattern;
+        self
+    }
+
+    /// Where to record the barcode and UMI, the id by default
+    #[must_use]
+    pub fn placement(mut self, placement: Placement) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Start the barcode with the index field of the (first) read's Casava 1.8
+    /// description, as in `1:N:0:ATCACG`; B segments follow it
+    #[must_use]
+    pub fn casava_barcode(mut self, casava: bool) -> Self {
+        self.casava = casava;
+        self
+    }
+
+    fn has_barcode(&self) -> bool {
+        self.casava || self.read1.has(SegmentKind::Barcode) || self.read2.has(SegmentKind::Barcode)
+    }
+
+    fn has_umi(&self) -> bool {
+        self.read1.has(SegmentKind::Umi) || self.read2.has(SegmentKind::Umi)
+    }
+
+    /// Trim the pattern off `rec` and record the barcode and UMI in its header
+    ///
+    /// Fails with a `ValidationError`, leaving `rec` unchanged, when the read is
+    /// shorter than the pattern or a Casava barcode is wanted and missing.
+    pub fn extract(&self, rec: &mut Record) -> Result<Extracted, FastqError> {
+        let mut found = self.casava_index(rec)?;
+        check_len(rec, &self.read1)?;
+        trim(rec, &self.read1, &mut found);
+        self.annotate(rec, &found);
+        Ok(found)
+    }
+
+    /// Trim the patterns off both mates and record the same barcode and UMI in both
+    ///
+    /// The barcode and UMI are those of the first mate followed by those of the
+    /// second. Fails as `extract` does, or when the reads are not mates by
+    /// `is_mate_pair`, leaving both unchanged.
+    pub fn extract_pair(&self, r1: &mut Record, r2: &mut Record) -> Result<Extracted, FastqError> {
+        if !is_mate_pair(r1.id(), r2.id()) {
+            return Err(invalid(r2, "id differs from its mate's"));
+        }
+        let mut found = self.casava_index(r1)?;
+        check_len(r1, &self.read1)?;
+        check_len(r2, &self.read2)?;
+        trim(r1, &self.read1, &mut found);
+        trim(r2, &self.read2, &mut found);
+        self.annotate(r1, &found);
+        self.annotate(r2, &found);
+        Ok(found)
+    }
+
+    /// Split an id written with `Placement::Id` into the original id and what was extracted
+    ///
+    /// None when the id lacks a part this extractor would have appended.
+    ///
+    /// ```
+    /// use lyso_fastq::barcode::Extractor;
+    ///
+    /// let extractor = Extractor::new("4B6U".parse().unwrap());
+    /// let (id, found) = extractor.split_id(b"r1_x_ACGT_TTGCAA").unwrap();
+    /// assert_eq!((id, found.barcode.as_str()), (&b"r1_x"[..], "ACGT"));
+    /// assert!(extractor.split_id(b"r1_TTGCAA").is_none());
+    /// ```
+    pub fn split_id<'a>(&self, id: &'a [u8]) -> Option<(&'a [u8], Extracted)> {
+        let mut rest = id;
+        let mut last = || {
+            let at = rest.iter().rposition(|b| *b == b'_')?;
+            let part = String::from_utf8(rest[at + 1..].to_vec()).ok()?;
+            rest = &rest[..at];
+            Some(part)
+        };
+        let umi = if self.has_umi() {
+            last()?
+        } else {
+            String::new()
+        };
+        let barcode = if self.has_barcode() {
+            last()?
+        } else {
+            String::new()
+        };
+        Some((rest, Extracted { barcode, umi }))
+    }
+
+    fn casava_index(&self, rec: &Record) -> Result<Extracted, FastqError> {
+        let mut found = Extracted::default();
+        if self.casava {
+            let index = rec
+                .desc()
+                .and_then(|d| d.split(|b| *b == b':').nth(3))
+                .filter(|index| !index.is_empty())
+                .ok_or_else(|| invalid(rec, "no Casava index in the description"))?;
+            found.barcode = String::from_utf8_lossy(index).into_owned();
+        }
+        Ok(found)
+    }
+
+    fn annotate(&self, rec: &mut Record, found: &Extracted) {
+        match self.placement {
+            Placement::Id => {
+                for (wanted, part) in [
+                    (self.has_barcode(), &found.barcode),
+                    (self.has_umi(), &found.umi),
+                ] {
+                    if wanted {
+                        rec.id.push(b'_');
+                        rec.id.extend_from_slice(part.as_bytes());
+                    }
+                }
+            }
+            Placement::Desc => {
+                let mut fields = Vec::new();
+                if self.has_barcode() {
+                    fields.push(format!("BC:Z:{}", found.barcode));
+                }
+                if self.has_umi() {
+                    fields.push(format!("RX:Z:{}", found.umi));
+                }
+                let desc = rec.desc.get_or_insert_with(Vec::new);
+                if !desc.is_empty() && !fields.is_empty() {
+                    desc.push(b' ');
+                }
+                desc.extend_from_slice(fields.join(" ").as_bytes());
+            }
+        }
+    }
+}
+
+/// Whether two ids name mates: equal once a trailing "/1" or "/2" is dropped
+///
+/// ```
+/// use lyso_fastq::barcode::is_mate_pair;
+///
+/// assert!(is_mate_pair(b"r1/1", b"r1/2"));
+/// assert!(is_mate_pair(b"r1", b"r1"));
+/// assert!(!is_mate_pair(b"r1/1", b"r2/2"));
+/// ```
+pub fn is_mate_pair(id1: &[u8], id2: &[u8]) -> bool {
+    fn name(id: &[u8]) -> &[u8] {
+        match id {
+            [name @ .., b'/', b'1' | b'2'] => name,
+            _ => id,
+        }
+    }
+    name(id1) == name(id2)
+}
+
+fn invalid(rec: &Record, reason: &'static str) -> FastqError {
+    FastqError::ValidationError {
+        record: rec.id_str_lossy().into_owned(),
+        reason,
+    }
+}
+
+fn check_len(rec: &Record, pattern: &BarcodePattern) -> Result<(), FastqError> {
+    if rec.seq.len() < pattern.len() {
+        return Err(invalid(rec, "read is shorter than the barcode pattern"));
+    }
+    Ok(())
+}
+
+/// Remove the pattern's bases from the start of `rec`, adding them to `found`
+fn trim(rec: &mut Record, pattern: &BarcodePattern, found: &mut Extracted) {
+    let mut start = 0;
+    for (kind, len) in &pattern.segments {
+        let bases = &rec.seq[start..start + len];
+        match kind {
+            SegmentKind::Barcode => found.barcode.push_str(bases),
+            SegmentKind::Umi => found.umi.push_str(bases),
+            SegmentKind::Skip => {}
+        }
+        start += len;
+    }
+    rec.seq.drain(..start);
+    // records read without quality have none to trim
+    if !rec.qual.is_empty() {
+        rec.qual.drain(..start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> BarcodePattern {
+        s.parse().unwrap()
+    }
+
+    fn record(id: &str, seq: &str) -> Record {
+        let qual = (0..seq.len())
+            .map(|i| char::from(b'!' + i as u8))
+            .collect::<String>();
+        Record::from_parts(id, seq, qual).unwrap()
+    }
+
+    #[test]
+    fn test_pattern() {
+        let p = pattern("8B12U");
+        assert_eq!(p.len(), 20);
+        assert_eq!(
+            p.segments(),
+            [(SegmentKind::Barcode, 8), (SegmentKind::Umi, 12)]
+        );
+        assert_eq!(pattern("4u2x3b").to_string(), "4U2X3B");
+        for bad in ["", "B", "8", "8Q", "0U", "8B-2U", "U8"] {
+            assert!(bad.parse::<BarcodePattern>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_trim_keeps_seq_and_qual_in_step() {
+        let extractor = Extractor::new(pattern("3B2X4U"));
+        let mut rec = record("r1", "AAACCGGGGTTTTACGT");
+        let qual_tail = rec.qual()[9..].to_string();
+        let found = extractor.extract(&mut rec).unwrap();
+        assert_eq!(
+            (found.barcode.as_str(), found.umi.as_str()),
+            ("AAA", "GGGG")
+        );
+        assert_eq!(rec.seq(), "TTTTACGT");
+        assert_eq!(rec.qual(), qual_tail);
+        assert_eq!(rec.id(), b"r1_AAA_GGGG");
+
+        // a read exactly as long as the pattern is left empty
+        let mut rec = record("r2", "AAACCGGGG");
+        extractor.extract(&mut rec).unwrap();
+        assert_eq!((rec.seq(), rec.qual()), ("", ""));
+
+        // records without quality strings
+        let mut rec = Record::new();
+        rec.set_id("r3");
+        rec.seq = String::from("AAACCGGGGT");
+        extractor.extract(&mut rec).unwrap();
+        assert_eq!((rec.seq(), rec.qual()), ("T", ""));
+    }
+
+    #[test]
+    fn test_too_short() {
+        let extractor = Extractor::new(pattern("8B12U"));
+        let mut rec = record("short", "ACGTACGTACGT");
+        let before = rec.clone();
+        let err = extractor.extract(&mut rec).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid record short: read is shorter than the barcode pattern"
+        );
+        assert!(!err.is_terminal());
+        assert_eq!(rec, before);
+
+        // neither mate changes when one is too short
+        let pair = Extractor::new(pattern("4B")).read2_pattern(pattern("8U"));
+        let (mut r1, mut r2) = (record("p", "ACGTACGT"), record("p", "ACGT"));
+        assert!(pair.extract_pair(&mut r1, &mut r2).is_err());
+        assert_eq!((r1.seq(), r2.seq()), ("ACGTACGT", "ACGT"));
+    }
+
+    #[test]
+    fn test_id_round_trip() {
+        for (p, id) in [
+            ("8B12U", "r1"),
+            ("16U", "r_2"),
+            ("6B", "lane1_tile2"),
+            ("2X4U", "x"),
+        ] {
+            let extractor = Extractor::new(pattern(p));
+            let mut rec = record(id, "ACGTACGTTTGCAATTGCAAGATTACA");
+            let found = extractor.extract(&mut rec).unwrap();
+            let (original, split) = extractor.split_id(rec.id()).unwrap();
+            assert_eq!(original, id.as_bytes(), "{p}");
+            assert_eq!(split, found, "{p}");
+        }
+        let umi_only = Extractor::new(pattern("4U"));
+        let mut rec = record("r1", "ACGTAA");
+        umi_only.extract(&mut rec).unwrap();
+        assert_eq!(rec.id(), b"r1_ACGT");
+        assert!(umi_only.split_id(b"no-umi").is_none());
+    }
+
+    #[test]
+    fn test_desc_placement() {
+        let extractor = Extractor::new(pattern("2B3U")).placement(Placement::Desc);
+        let mut rec = record("r1", "ACGTTAA");
+        extractor.extract(&mut rec).unwrap();
+        assert_eq!(rec.id(), b"r1");
+        assert_eq!(rec.desc(), Some(&b"BC:Z:AC RX:Z:GTT"[..]));
+
+        let mut rec = record("r1", "ACGTTAA");
+        rec.set_desc(Some(b"lane=1".to_vec()));
+        extractor.extract(&mut rec).unwrap();
+        assert_eq!(rec.desc(), Some(&b"lane=1 BC:Z:AC RX:Z:GTT"[..]));
+    }
+
+    #[test]
+    fn test_casava_barcode() {
+        let extractor = Extractor::new(pattern("6U")).casava_barcode(true);
+        let mut rec = record("M1:1:FC:1:1101:1000:2000", "TTGCAAGATTACA");
+        rec.set_desc(Some(b"1:N:0:ATCACG+GTTACA".to_vec()));
+        let found = extractor.extract(&mut rec).unwrap();
+        assert_eq!(found.barcode, "ATCACG+GTTACA");
+        assert_eq!(rec.id(), b"M1:1:FC:1:1101:1000:2000_ATCACG+GTTACA_TTGCAA");
+        assert_eq!(rec.seq(), "GATTACA");
+
+        // the barcode alone, with nothing trimmed
+        let header_only = Extractor::default().casava_barcode(true);
+        let mut rec = record("r1", "ACGT");
+        rec.set_desc(Some(b"2:Y:18:ACGTAC".to_vec()));
+        header_only.extract(&mut rec).unwrap();
+        assert_eq!((rec.id(), rec.seq()), (&b"r1_ACGTAC"[..], "ACGT"));
+
+        let mut rec = record("r1", "ACGTACGT");
+        let err = extractor.extract(&mut rec).unwrap_err();
+        assert!(err.to_string().contains("no Casava index"));
+    }
+
+    #[test]
+    fn test_pairs() {
+        // barcode on the first mate only
+        let r1_only = Extractor::new(pattern("4B4U"));
+        let (mut r1, mut r2) = (record("p1", "ACGTTTGCGAT"), record("p1", "CCCCGG"));
+        let found = r1_only.extract_pair(&mut r1, &mut r2).unwrap();
+        assert_eq!(
+            (found.barcode.as_str(), found.umi.as_str()),
+            ("ACGT", "TTGC")
+        );
+        assert_eq!((r1.seq(), r2.seq()), ("GAT", "CCCCGG"));
+        assert_eq!(r1.id(), r2.id());
+        assert_eq!(r2.id(), b"p1_ACGT_TTGC");
+
+        // UMI split across the mates, first mate's half first
+        let split = Extractor::new(pattern("4B3U")).read2_pattern(pattern("3U1X"));
+        let (mut r1, mut r2) = (record("p2", "ACGTAAAGG"), record("p2", "CCCNTT"));
+        let found = split.extract_pair(&mut r1, &mut r2).unwrap();
+        assert_eq!(
+            (found.barcode.as_str(), found.umi.as_str()),
+            ("ACGT", "AAACCC")
+        );
+        assert_eq!((r1.seq(), r2.seq()), ("GG", "TT"));
+        assert_eq!(r2.qual().len(), 2);
+        assert_eq!(split.split_id(r2.id()).unwrap().1, found);
+
+        // reads that are not mates are left alone
+        let (mut r1, mut r2) = (record("p3/1", "ACGTTTGC"), record("p4/2", "CCCC"));
+        let err = r1_only.extract_pair(&mut r1, &mut r2).unwrap_err();
+        assert!(err.to_string().contains("differs from its mate"));
+        assert_eq!((r1.id(), r1.seq()), (&b"p3/1"[..], "ACGTTTGC"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Counting records without parsing them
+//!
+//! `fast_count` counts the lines of a file, four to a record, checking what the
+//! parser would reject: a header without '@' or id, an empty or non-ASCII sequence
+//! line, a missing '+' line, or a lone '\r'. Qualities are checked too: the line
+//! must be as long as the sequence and printable, '!' to '~'. At the first record
+//! it cannot vouch for, it hands the rest of the input to a `FastqReader`, so
+//! multi-line records, blank lines, bad qualities and corrupt input are counted
+//! (or fail) exactly as reading them would.
+
+use std::io::{BufRead, Cursor, Read};
+
+use lyso_common::detect::{find_mismatch, Format};
+use memchr::{memchr, memchr_iter};
+
+use crate::reader::FastqReader;
+use crate::FastqError;
+
+/// Input gathered before each scan, so that short reads do not cost a scan each
+const SCAN_BYTES: usize = 1 << 16;
+
+/// The number of records in `reader`, or the first error that would end reading it
+///
+/// Records failing with a record-local error (see `FastqError::is_terminal`), such
+/// as a quality line of the wrong length, are counted, as `FastqReader::skip_records`
+/// counts them.
+///
+/// ```
+/// use lyso_fastq::count::fast_count;
+///
+/// assert_eq!(fast_count(&b"@a\nAC\n+\nII\n@b\nGT\n+\nII"[..]).unwrap(), 2);
+/// assert!(fast_count(&b"@a\nAC\n+\nII\n@b\nGT\n"[..]).is_err());
+/// ```
+pub fn fast_count<R: BufRead>(mut reader: R) -> Result<u64, FastqError> {
+    if let Some(m) = find_mismatch(reader.fill_buf()?, Format::Fastq) {
+        return Err(m.into());
+    }
+    let mut buf = Vec::with_capacity(2 * SCAN_BYTES);
+    let mut count = 0;
+    // line endings before `buf`, so a fallback reader numbers lines as a full read would
+    let mut lines = 0;
+    loop {
+        let read = (&mut reader)
+            .take(SCAN_BYTES as u64)
+            .read_to_end(&mut buf)?;
+        let (n, used, doubt) = plain_records(&buf);
+        count += n;
+        if doubt {
+            break;
+        }
+        if read == 0 {
+            // the parser accepts a final line without a line ending
+            let rest = &buf[used..];
+            let lines = rest.split(|b| *b == b'\n').collect::<Vec<_>>();
+            match lines.try_into() {
+                Ok(last) if is_plain(last) => return Ok(count + 1),
+                _ if rest.is_empty() => return Ok(count),
+                _ => break,
+            }
+        }
+        lines += memchr_iter(b'\n', &buf[..used]).count() as u64;
+        buf.drain(..used);
+    }
+    let (_, used, _) = plain_records(&buf);
+    let rest = Cursor::new(buf.split_off(used)).chain(reader);
+    lines += memchr_iter(b'\n', &buf).count() as u64;
+    let skipped = FastqReader::new(rest)
+        .after_lines(lines)
+        .skip_records(usize::MAX)?;
+    Ok(count + skipped as u64)
+}
+
+/// Records at the start of `buf` that `is_plain` vouches for, and the bytes they
+/// take up
+///
+/// The flag is set when the scan stopped at four whole lines that are not a plain
+/// record, rather than at the end of `buf`.
+fn plain_records(buf: &[u8]) -> (u64, usize, bool) {
+    let mut n = 0;
+    let mut used = 0;
+    loop {
+        let mut lines = [&buf[..0]; 4];
+        let mut pos = used;
+        for line in &mut lines {
+            let Some(len) = memchr(b'\n', &buf[pos..]) else {
+                return (n, used, false);
+            };
+            *line = &buf[pos..pos + len];
+            pos += len + 1;
+        }
+        if !is_plain(lines) {
+            return (n, used, true);
+        }
+        n += 1;
+        used = pos;
+    }
+}
+
+/// Whether four lines, without their '\n', are a record the parser reads as one
+fn is_plain(lines: [&[u8]; 4]) -> bool {
+    let [head, seq, sep, qual] = lines.map(|l| l.strip_suffix(b"\r").unwrap_or(l));
+    // a '\r' anywhere else ends a line for the parser
+    if [head, seq, sep, qual]
+        .iter()
+        .any(|l| memchr(b'\r', l).is_some())
+    {
+        return false;
+    }
+    head.first() == Some(&b'@')
+        && head.get(1).is_some_and(|b| *b != b' ')
+        && !seq.is_empty()
+        && seq.is_ascii()
+        && sep.first() == Some(&b'+')
+        && qual.len() == seq.len()
+        // one comparison a byte, for '!'..='~', so the check vectorizes
+        && qual.iter().fold(true, |ok, q| ok & (q.wrapping_sub(b'!') < 94))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::harness::for_each_parser;
+    use lyso_common::synth::generate_fastq;
+    use std::io::BufReader;
+
+    /// What reading every record gives: the number read, or the terminal error
+    fn full_count(input: &[u8]) -> Result<u64, String> {
+        let mut n = 0;
+        for rec in FastqReader::new(input) {
+            match rec {
+                Err(e) if e.is_terminal() => return Err(e.to_string()),
+                _ => n += 1,
+            }
+        }
+        Ok(n)
+    }
+
+    fn check(input: &[u8], name: &str) {
+        let expected = full_count(input);
+        let fast = fast_count(input).map_err(|e| e.to_string());
+        assert_eq!(fast, expected, "{name}");
+        let trickled = fast_count(BufReader::with_capacity(1, input)).map_err(|e| e.to_string());
+        assert_eq!(trickled, expected, "{name}, read a byte at a time");
+    }
+
+    for_each_parser! {
+        fn test_fixtures() {
+            for fixture in [
+                "ambiguous_qual.fastq",
+                "corrupt.fastq",
+                "crlf.fastq",
+                "no_final_newline.fastq",
+                "phred64.fastq",
+                "small.fastq",
+                "test.fastq",
+                "trunc.fastq",
+                "test.fa",
+            ] {
+                let input = std::fs::read(format!("../resources/test_data/{fixture}")).unwrap();
+                check(&input, fixture);
+            }
+            let large = generate_fastq(5000, 150, 1);
+            assert!(large.len() > 2 * SCAN_BYTES);
+            assert_eq!(fast_count(&large[..]).unwrap(), 5000);
+        }
+
+        fn test_fallback() {
+            for (input, name) in [
+                (&b""[..], "empty"),
+                (b"@a\nAC\n+\nII\n\n@b\nGT\n+\nII\n", "blank line"),
+                (b"@a\rAC\r+\rII\r@b\rGT\r+\rII\r", "lone CR"),
+                (b"@a\r\r\nAC\n+\nII\n", "CR run"),
+                (b"@\nAC\n+\nII\n", "empty id"),
+                (b"@ a\nAC\n+\nII\n", "space before id"),
+                (b"@a\n\n+\n\n", "empty sequence"),
+                (b"@a\nACGT\n+\nII\n@b\nGT\n+\nII\n", "length mismatch"),
+                (b"@a\nAC\n+\nI \n@b\nGT\n+\nII\n", "space in quality"),
+                (b"@a\nAC\n+\nI\x7f\n@b\nGT\n+\nII\n", "DEL in quality"),
+                (b"@a\nAC\n+\nI\xc3\xa9\n", "UTF-8 quality"),
+                (b"@a\nAC\nGT\n+\nIIII\n", "wrapped"),
+                (b"@a\nA\xc3\xa9\n+\nIII\n", "UTF-8 sequence"),
+                (b"@a\nA\xff\n+\nII\n", "invalid sequence"),
+                (b"@a\xff\nAC\n+\nII\n", "latin1 header"),
+                (b"@a\nAC\n+\nII\n@b\nGT\n+", "truncated"),
+                (b"@a\nAC\n+\nII\n@b\nGT", "truncated sequence"),
+                (b">a\nACGT\n", "FASTA"),
+            ] {
+                check(input, name);
+            }
+        }
+    }
+}
@@ -0,0 +1,620 @@
+//! Sorting reads into samples by their barcode
+//!
+//! A `Demultiplexer` matches a read's barcode against the barcodes of a sample
+//! sheet, allowing up to k mismatches (Hamming distance; N counts as a
+//! mismatch). A read goes to the sample whose barcode is closest, provided no
+//! other barcode is as close; otherwise it is ambiguous, or unmatched when no
+//! barcode is within k. Dual-index barcodes are compared with the `+` between
+//! the indexes removed.
+//!
+//! When the variants of every barcode within k mismatches fit in about a million
+//! entries, they are looked up in a table built up front; otherwise each read is
+//! compared with every barcode. `Demultiplexer::with_table_limit` sets a
+//! different limit.
+//!
+//! `OutputFiles` keeps per-sample outputs within a limit on open file handles.
+//! Each is written to a temporary file beside it and renamed into place by
+//! `OutputFiles::finish`, one file at a time: an error or panic before then
+//! leaves every destination as it was, and a failed rename leaves the outputs
+//! renamed before it replaced and the rest as they were.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::PathBuf;
+
+use lyso_common::io::atomic::PendingFile;
+use thiserror::Error;
+
+use crate::Record;
+
+/// Most table entries built before falling back to comparing reads with every barcode
+///
+/// An entry takes around 100 bytes, so the table stays near 100 MB.
+const MAX_TABLE_ENTRIES: usize = 1 << 20;
+
+/// Errors from setting up a `Demultiplexer`
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum DemuxError {
+    /// The sample sheet could not be read
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// A malformed sample sheet line, with its 1-based line number
+    #[error("line {line}: {msg}")]
+    Parse {
+        /// Line number
+        line: usize,
+        /// What was wrong with it
+        msg: String,
+    },
+    /// A sample that cannot be told apart or written out
+    #[error("sample {sample}: {msg}")]
+    InvalidSample {
+        /// Sample name
+        sample: String,
+        /// What was wrong with it
+        msg: String,
+    },
+}
+
+/// A sample name and its barcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    /// Name, used for its output files
+    pub name: String,
+    /// Barcode as given in the sample sheet
+    pub barcode: String,
+}
+
+/// Read a sample sheet of `name<TAB>barcode` lines
+///
+/// Blank lines and lines starting with '#' are skipped.
+pub fn read_sample_sheet(reader: impl BufRead) -> Result<Vec<Sample>, DemuxError> {
+    let mut samples = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+        match fields[..] {
+            [name, barcode] if !name.is_empty() && !barcode.is_empty() => samples.push(Sample {
+                name: name.to_string(),
+                barcode: barcode.to_string(),
+            }),
+            _ => {
+                return Err(DemuxError::Parse {
+                    line: i + 1,
+                    msg: format!("expected 'name<TAB>barcode', found '{line}'"),
+                })
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Where a read goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// To the sample at this index of `Demultiplexer::samples`
+    Sample(usize),
+    /// Two or more barcodes are equally close
+    Ambiguous,
+    /// No barcode is within the allowed mismatches, or the read has none
+    Unmatched,
+}
+
+/// Closest barcode seen for a table entry, None when tied
+#[derive(Debug, Clone, Copy)]
+struct Hit {
+    mismatches: usize,
+    sample: Option<usize>,
+}
+
+/// Assigns reads to samples by barcode; see the module docs
+#[derive(Debug, Clone)]
+pub struct Demultiplexer {
+    samples: Vec<Sample>,
+    barcodes: Vec<Vec<u8>>,
+    mismatches: usize,
+    table: Option<HashMap<Vec<u8>, Hit>>,
+}
+
+impl Demultiplexer {
+    /// Match against the barcodes of `samples` with up to `mismatches` mismatches
+    ///
+    /// Fails if barcodes differ in length or repeat, or if sample names repeat or
+    /// could not name files.
+    pub fn new(samples: Vec<Sample>, mismatches: usize) -> Result<Self, DemuxError> {
+        Self::with_table_limit(samples, mismatches, MAX_TABLE_ENTRIES)
+    }
+
+    /// As `new`, building a lookup table only if it has at most `max_entries` entries
+    ///
+    /// A table holds every sequence within `mismatches` of a barcode, so it grows
+    /// quickly with k; past the limit each read is compared with every barcode
+    /// instead, which routes reads the same way, only more slowly. A limit of 0
+    /// never builds one.
+    pub fn with_table_limit(
+        samples: Vec<Sample>,
+        mismatches: usize,
+        max_entries: usize,
+    ) -> Result<Self, DemuxError> {
+        let invalid = |s: &Sample, msg: String| DemuxError::InvalidSample {
+            sample: s.name.clone(),
+            msg,
+        };
+        let mut barcodes: Vec<Vec<u8>> = Vec::with_capacity(samples.len());
+        for (i, s) in samples.iter().enumerate() {
+            if s.name.contains(['/', '\\'])
+                || s.name.starts_with('.')
+                || ["ambiguous", "unmatched"].contains(&s.name.as_str())
+            {
+                return Err(invalid(s, String::from("not usable as a file name")));
+            }
+            let barcode = normalize(s.barcode.as_bytes());
+            if barcode.is_empty() || !barcode.iter().all(|b| b"ACGTN".contains(b)) {
+                return Err(invalid(s, format!("invalid barcode '{}'", s.barcode)));
+            }
+            if let Some(first) = barcodes.first().filter(|b| b.len() != barcode.len()) {
+                return Err(invalid(
+                    s,
+                    format!(
+                        "barcode has {} bases, expected {}",
+                        barcode.len(),
+                        first.len()
+                    ),
+                ));
+            }
+            for (prev, other) in samples[..i].iter().zip(&barcodes) {
+                if prev.name == s.name {
+                    return Err(invalid(s, String::from("listed twice")));
+                }
+                if *other == barcode {
+                    return Err(invalid(s, format!("same barcode as {}", prev.name)));
+                }
+            }
+            barcodes.push(barcode);
+        }
+        let len = barcodes.first().map_or(0, Vec::len);
+        let table = (table_entries(len, mismatches).saturating_mul(barcodes.len()) <= max_entries)
+            .then(|| build_table(&barcodes, mismatches));
+        Ok(Demultiplexer {
+            samples,
+            barcodes,
+            mismatches,
+            table,
+        })
+    }
+
+    /// The samples, in sample sheet order
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Route a read by the barcode in its header
+    ///
+    /// That is the `BC:Z:` field of the description, as `barcode::Extractor`
+    /// writes it, or else the index field of a Casava 1.8 description
+    /// (`1:N:0:ATCACG`). A read with neither is unmatched.
+    pub fn route(&self, rec: &Record) -> Route {
+        let Some(desc) = rec.desc() else {
+            return Route::Unmatched;
+        };
+        let fields = || desc.split(|b| *b == b' ' || *b == b'\t');
+        let tagged = fields().find_map(|f| f.strip_prefix(b"BC:Z:"));
+        let casava = || fields().next().and_then(|f| f.split(|b| *b == b':').nth(3));
+        match tagged.or_else(casava) {
+            Some(barcode) => self.route_barcode(barcode),
+            None => Route::Unmatched,
+        }
+    }
+
+    /// Route a read with this barcode
+    pub fn route_barcode(&self, barcode: &[u8]) -> Route {
+        // the table only holds ACGTN, so anything else is an N to scan as well
+        let barcode = normalize(barcode)
+            .into_iter()
+            .map(|b| if b"ACGTN".contains(&b) { b } else { b'N' })
+            .collect::<Vec<u8>>();
+        if self
+            .barcodes
+            .first()
+            .is_none_or(|b| b.len() != barcode.len())
+        {
+            return Route::Unmatched;
+        }
+        let hit = match &self.table {
+            Some(table) => table.get(&barcode).copied(),
+            None => self.scan(&barcode),
+        };
+        match hit {
+            Some(Hit {
+                sample: Some(i), ..
+            }) => Route::Sample(i),
+            Some(Hit { sample: None, .. }) => Route::Ambiguous,
+            None => Route::Unmatched,
+        }
+    }
+
+    fn scan(&self, barcode: &[u8]) -> Option<Hit> {
+        let mut best: Option<Hit> = None;
+        for (i, b) in self.barcodes.iter().enumerate() {
+            let limit = best.map_or(self.mismatches, |h| h.mismatches);
+            let Some(d) = hamming_within(b, barcode, limit) else {
+                continue;
+            };
+            best = Some(match best {
+                Some(h) if h.mismatches == d => Hit {
+                    mismatches: d,
+                    sample: None,
+                },
+                _ => Hit {
+                    mismatches: d,
+                    sample: Some(i),
+                },
+            });
+        }
+        best
+    }
+}
+
+/// Uppercase bases with the `+` or `-` between dual indexes removed
+fn normalize(barcode: &[u8]) -> Vec<u8> {
+    barcode
+        .iter()
+        .filter(|b| !matches!(b, b'+' | b'-'))
+        .map(u8::to_ascii_uppercase)
+        .collect()
+}
+
+/// The mismatches between `a` and `b`, if no more than `limit`
+fn hamming_within(a: &[u8], b: &[u8], limit: usize) -> Option<usize> {
+    let mut d = 0;
+    for (x, y) in a.iter().zip(b) {
+        if x != y {
+            d += 1;
+            if d > limit {
+                return None;
+            }
+        }
+    }
+    Some(d)
+}
+
+/// Sequences within `k` substitutions of one of `len` bases: sum of C(len, i) 4^i
+fn table_entries(len: usize, k: usize) -> usize {
+    let (mut total, mut term) = (1usize, 1usize);
+    for i in 1..=k.min(len) {
+        term = term.saturating_mul(len - i + 1) / i;
+        total = total.saturating_add(term.saturating_mul(4usize.saturating_pow(i as u32)));
+    }
+    total
+}
+
+fn build_table(barcodes: &[Vec<u8>], k: usize) -> HashMap<Vec<u8>, Hit> {
+    let mut table = HashMap::new();
+    for (i, barcode) in barcodes.iter().enumerate() {
+        let mut variant = barcode.clone();
+        add_variants(&mut table, &mut variant, barcode, i, 0, 0, k);
+    }
+    table
+}
+
+/// Add every sequence differing from `barcode` in up to `k` positions at or after `from`
+fn add_variants(
+    table: &mut HashMap<Vec<u8>, Hit>,
+    variant: &mut Vec<u8>,
+    barcode: &[u8],
+    sample: usize,
+    from: usize,
+    mismatches: usize,
+    k: usize,
+) {
+    let hit = Hit {
+        mismatches,
+        sample: Some(sample),
+    };
+    table
+        .entry(variant.clone())
+        .and_modify(|h| {
+            if mismatches < h.mismatches {
+                *h = hit;
+            } else if mismatches == h.mismatches {
+                h.sample = None;
+            }
+        })
+        .or_insert(hit);
+    if mismatches == k {
+        return;
+    }
+    for pos in from..barcode.len() {
+        for &base in b"ACGTN" {
+            if base != barcode[pos] {
+                variant[pos] = base;
+                add_variants(table, variant, barcode, sample, pos + 1, mismatches + 1, k);
+            }
+        }
+        variant[pos] = barcode[pos];
+    }
+}
+
+/// FASTQ outputs written in turn, keeping at most a given number of files open
+///
+/// Each output's temporary file is created when first written and reopened for
+/// appending when written again after being closed to make room, so any number
+/// of outputs can be written under a limit on open file handles. Existing files
+/// at the paths are replaced unless `overwrite(false)` is set.
+#[derive(Debug)]
+pub struct OutputFiles {
+    paths: Vec<PathBuf>,
+    open: Vec<Option<BufWriter<File>>>,
+    pending: Vec<Option<PendingFile>>,
+    /// Write number of each file's last write, for closing the least recently used
+    last_used: Vec<u64>,
+    writes: u64,
+    n_open: usize,
+    max_open: usize,
+    overwrite: bool,
+}
+
+impl OutputFiles {
+    /// Outputs at `paths`, with at most `max_open` (at least 1) open at once
+    pub fn new(paths: Vec<PathBuf>, max_open: usize) -> Self {
+        let n = paths.len();
+        OutputFiles {
+            paths,
+            open: (0..n).map(|_| None).collect(),
+            pending: (0..n).map(|_| None).collect(),
+            last_used: vec![0; n],
+            writes: 0,
+            n_open: 0,
+            max_open: max_open.max(1),
+            overwrite: true,
+        }
+    }
+
+    /// Whether to replace existing files; without it, an output whose file
+    /// exists when created or finished is an `AlreadyExists` error
+    #[must_use]
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Outputs currently open
+    pub fn open_files(&self) -> usize {
+        self.n_open
+    }
+
+    /// Write `rec` to output `i`
+    pub fn write(&mut self, i: usize, rec: &Record) -> std::io::Result<()> {
+        self.writes += 1;
+        self.last_used[i] = self.writes;
+        if self.open[i].is_none() {
+            if self.n_open == self.max_open {
+                self.close_least_recent()?;
+            }
+            let file = self.pending(i)?.open()?;
+            self.open[i] = Some(BufWriter::new(file));
+            self.n_open += 1;
+        }
+        rec.write_to(self.open[i].as_mut().expect("opened above"))
+    }
+
+    fn close_least_recent(&mut self) -> std::io::Result<()> {
+        let lru = (0..self.open.len())
+            .filter(|&j| self.open[j].is_some())
+            .min_by_key(|&j| self.last_used[j]);
+        if let Some(mut out) = lru.and_then(|j| self.open[j].take()) {
+            self.n_open -= 1;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The temporary file of output `i`, created on first use
+    fn pending(&mut self, i: usize) -> std::io::Result<&PendingFile> {
+        if self.pending[i].is_none() {
+            self.pending[i] = Some(PendingFile::new(&self.paths[i], self.overwrite)?);
+        }
+        Ok(self.pending[i].as_ref().expect("created above"))
+    }
+
+    /// Flush and close the outputs, and move them all into place, empty for
+    /// those never written
+    pub fn finish(mut self) -> std::io::Result<()> {
+        for i in 0..self.paths.len() {
+            if let Some(mut out) = self.open[i].take() {
+                out.flush()?;
+            }
+            self.pending(i)?;
+        }
+        for pending in self.pending.into_iter().flatten() {
+            pending.persist()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(barcodes: &[&str]) -> Vec<Sample> {
+        barcodes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| Sample {
+                name: format!("S{}", i + 1),
+                barcode: b.to_string(),
+            })
+            .collect()
+    }
+
+    /// The same matcher with and without the lookup table
+    fn both(barcodes: &[&str], k: usize) -> [Demultiplexer; 2] {
+        let table = Demultiplexer::new(samples(barcodes), k).unwrap();
+        let scan = Demultiplexer::with_table_limit(samples(barcodes), k, 0).unwrap();
+        assert!(table.table.is_some() && scan.table.is_none());
+        [table, scan]
+    }
+
+    #[test]
+    fn test_routes() {
+        for demux in both(&["ACGTACGT", "ACGTACCA", "TTTTGGGG"], 1) {
+            // exact, and one mismatch away from a unique barcode
+            assert_eq!(demux.route_barcode(b"ACGTACGT"), Route::Sample(0));
+            assert_eq!(demux.route_barcode(b"tttTGGGG"), Route::Sample(2));
+            assert_eq!(demux.route_barcode(b"TTTTGGGA"), Route::Sample(2));
+            assert_eq!(demux.route_barcode(b"NCGTACGT"), Route::Sample(0));
+            // one mismatch from the first two
+            assert_eq!(demux.route_barcode(b"ACGTACCT"), Route::Ambiguous);
+            // two mismatches from everything
+            assert_eq!(demux.route_barcode(b"AAGTACGA"), Route::Unmatched);
+            assert_eq!(demux.route_barcode(b"ACGTACG"), Route::Unmatched);
+            assert_eq!(demux.route_barcode(b""), Route::Unmatched);
+        }
+        // an exact hit wins over a one-mismatch one
+        for demux in both(&["AAAA", "AAAC"], 1) {
+            assert_eq!(demux.route_barcode(b"AAAA"), Route::Sample(0));
+            assert_eq!(demux.route_barcode(b"AAAG"), Route::Ambiguous);
+        }
+        for demux in both(&["AAAA", "CCCC"], 0) {
+            assert_eq!(demux.route_barcode(b"AAAA"), Route::Sample(0));
+            assert_eq!(demux.route_barcode(b"AAAC"), Route::Unmatched);
+        }
+        // bytes other than ACGTN count as a mismatch, like N
+        for demux in both(&["ACGTAC", "TTTTTT"], 1) {
+            assert_eq!(demux.route_barcode(b"ACGTA."), Route::Sample(0));
+            assert_eq!(demux.route_barcode(b"ACGT.."), Route::Unmatched);
+        }
+    }
+
+    #[test]
+    fn test_table_matches_scan() {
+        let barcodes = ["ACGTAC", "ACGTTT", "GGCCAA", "TGCATG", "ACCTAC"];
+        let [table, scan] = both(&barcodes, 2);
+        // every 6-mer over ACGN
+        for n in 0..4usize.pow(6) {
+            let read = (0..6)
+                .map(|i| b"ACGN"[(n >> (2 * i)) & 3])
+                .collect::<Vec<_>>();
+            assert_eq!(table.route_barcode(&read), scan.route_barcode(&read));
+        }
+        assert_eq!(table_entries(8, 1), 33);
+        assert_eq!(table_entries(8, 2), 481);
+        assert_eq!(table_entries(2, 5), 25);
+    }
+
+    #[test]
+    fn test_route_record() {
+        let demux = Demultiplexer::new(samples(&["ATCACG+GTTACA", "CGATGT+AACCGG"]), 1).unwrap();
+        let mut rec = Record::from_parts("r1", "ACGT", "IIII").unwrap();
+        assert_eq!(demux.route(&rec), Route::Unmatched);
+        rec.set_desc(Some(b"1:N:0:CGATGTAACCGG".to_vec()));
+        assert_eq!(demux.route(&rec), Route::Sample(1));
+        rec.set_desc(Some(b"1:N:0:ATCACG+GTTACT".to_vec()));
+        assert_eq!(demux.route(&rec), Route::Sample(0));
+        rec.set_desc(Some(b"1:N:0:ATCACG BC:Z:CGATGT+AACCGG RX:Z:ACGT".to_vec()));
+        assert_eq!(demux.route(&rec), Route::Sample(1));
+        rec.set_desc(Some(b"BC:Z:CGATGTAACCGG".to_vec()));
+        assert_eq!(demux.route(&rec), Route::Sample(1));
+        rec.set_desc(Some(b"1:N:0:ATCACGGTTACA RX:Z:ACGT".to_vec()));
+        assert_eq!(demux.route(&rec), Route::Sample(0));
+    }
+
+    #[test]
+    fn test_invalid_samples() {
+        let err = |barcodes: &[&str]| Demultiplexer::new(samples(barcodes), 1).unwrap_err();
+        assert_eq!(
+            err(&["ACGT", "ACG"]).to_string(),
+            "sample S2: barcode has 3 bases, expected 4"
+        );
+        assert_eq!(
+            err(&["ACGT", "acgt"]).to_string(),
+            "sample S2: same barcode as S1"
+        );
+        assert!(err(&["AXGT"]).to_string().contains("invalid barcode"));
+        let named = |name: &str| {
+            let s = Sample {
+                name: name.to_string(),
+                barcode: String::from("ACGT"),
+            };
+            Demultiplexer::new(vec![s], 0)
+        };
+        assert!(named("unmatched").is_err());
+        assert!(named("../x").is_err());
+        assert!(named("S1").is_ok());
+    }
+
+    #[test]
+    fn test_sample_sheet() {
+        let sheet = "# name\tbarcode\nS1\tACGT\r\n\nS2\t GGCC\n";
+        let samples = read_sample_sheet(sheet.as_bytes()).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(
+            (samples[1].name.as_str(), samples[1].barcode.as_str()),
+            ("S2", "GGCC")
+        );
+        let err = read_sample_sheet("S1\tACGT\nS2 GGCC\n".as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: expected 'name<TAB>barcode', found 'S2 GGCC'"
+        );
+    }
+
+    #[test]
+    fn test_many_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = (0..192)
+            .map(|i| {
+                dir.path()
+                    .join(format!("S{}_R{}.fastq", i / 2 + 1, i % 2 + 1))
+            })
+            .collect::<Vec<_>>();
+        // a leftover file is replaced, not appended to
+        std::fs::write(&paths[0], "stale\n").unwrap();
+        let mut outputs = OutputFiles::new(paths.clone(), 16);
+        let rec = |n: usize| Record::from_parts(format!("r{n}"), "ACGT", "IIII").unwrap();
+        for round in 0..3 {
+            // the last ten outputs are never written
+            for i in (0..182).rev() {
+                outputs.write(i, &rec(round * 1000 + i)).unwrap();
+                assert!(outputs.open_files() <= 16);
+            }
+        }
+        assert_eq!(outputs.open_files(), 16);
+        outputs.finish().unwrap();
+        for (i, path) in paths.iter().enumerate() {
+            let written = std::fs::read_to_string(path).unwrap();
+            let expected = if i < 182 {
+                (0..3)
+                    .map(|round| rec(round * 1000 + i).to_string())
+                    .collect()
+            } else {
+                String::new()
+            };
+            assert_eq!(written, expected, "{}", path.display());
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 192);
+    }
+
+    #[test]
+    fn test_unfinished_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = ["S1.fastq", "S2.fastq"].map(|name| dir.path().join(name));
+        std::fs::write(&paths[0], "old\n").unwrap();
+        let mut outputs = OutputFiles::new(paths.to_vec(), 1);
+        let rec = Record::from_parts("r", "ACGT", "IIII").unwrap();
+        for i in [0, 1, 0] {
+            outputs.write(i, &rec).unwrap();
+        }
+        // a run failing before finish leaves the old file and no new one
+        drop(outputs);
+        assert_eq!(std::fs::read_to_string(&paths[0]).unwrap(), "old\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
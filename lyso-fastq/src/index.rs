@@ -1,82 +1,571 @@
+//! FASTQ indexes (`.fai`) and random access through them
+
 // ****************************************** //
 //               Fastq Indexing               //
 // ****************************************** //
 
-use fxhash::FxHashMap;
 use std::fmt;
+use std::fs::File;
 use std::io::{prelude::*, ErrorKind, Seek, SeekFrom};
-use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::OnceLock;
 
 use crate::*;
-//use lyso_common::util::skip_fwd;
+use lyso_common::index_build::{self, header_name, renamed, IndexEntry, Indexer, RepeatedName};
+pub use lyso_common::index_build::{DedupMode, DuplicatePolicy, IndexBuildOptions};
+use lyso_common::index_meta::{self, StaleIndex};
+use lyso_common::io::skip_fwd;
+use lyso_common::io::{fetch_in_order, read_span, span_len};
+use lyso_common::name_index::{self, NameIndex};
+use lyso_common::raw::RawRecord;
 
+/// Index of a FASTQ file, keyed by the raw bytes of each read name
+#[derive(Debug, Clone, Default)]
 pub struct FastqIndex {
-    inner: FxHashMap<String, FastqIndexEntry>,
+    /// Entries in file order
+    entries: Vec<FastqIndexEntry>,
+    /// Position of each name in `entries`, unused in an offsets-only index and
+    /// built on the first lookup after `read_binary`
+    by_name: OnceLock<NameIndex>,
+    /// Built without `by_name`, see `IndexBuildOptions::name_map`
+    offsets_only: bool,
+}
+
+/// Equal entries, whether or not the name map was built yet
+impl PartialEq for FastqIndex {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries && self.offsets_only == other.offsets_only
+    }
 }
 
 impl FastqIndex {
+    /// An empty index
+    #[must_use]
     pub fn new() -> Self {
         FastqIndex {
-            inner: FxHashMap::default(),
+            entries: Vec::new(),
+            by_name: OnceLock::new(),
+            offsets_only: false,
         }
     }
 
-    pub fn from_entries<I>(entries: I) -> Self
+    /// An index of `entries`, repeated names handled by `policy`
+    pub fn from_entries<I>(entries: I, policy: DuplicatePolicy) -> Result<Self, FastqError>
     where
         I: Iterator<Item = FastqIndexEntry>,
     {
         let mut idx = Self::new();
         for e in entries {
-            idx.inner.insert(e.name.clone(), e);
+            idx.insert(e, &policy)?;
+        }
+        Ok(idx)
+    }
+
+    /// Index every record of `fastq`, repeated names handled by `policy`
+    pub fn from_fastq_file<F: BufRead + Seek>(
+        fastq: &mut F,
+        policy: DuplicatePolicy,
+    ) -> Result<Self, FastqError> {
+        Self::from_indexer(FastqIndexer::new(fastq), policy)
+    }
+
+    /// Index every record `idxr` yields, repeated names handled by `policy`
+    pub fn from_indexer<F: BufRead + Seek>(
+        idxr: FastqIndexer<'_, F>,
+        policy: DuplicatePolicy,
+    ) -> Result<Self, FastqError> {
+        let mut idx = Self::new();
+        for e in idxr {
+            idx.insert(e?, &policy)?;
+        }
+        Ok(idx)
+    }
+
+    /// Index every record of `fastq` as `options` say, repeated names handled by `policy`
+    pub fn from_fastq_file_with<F: BufRead + Seek>(
+        fastq: &mut F,
+        policy: DuplicatePolicy,
+        options: IndexBuildOptions,
+    ) -> Result<Self, FastqError> {
+        Self::from_indexer_with(FastqIndexer::new(fastq), policy, options)
+    }
+
+    /// Index every record `idxr` yields as `options` say, repeated names handled by `policy`
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use lyso_fastq::index::{DedupMode, DuplicatePolicy, FastqIndex, IndexBuildOptions, IndexedFastq};
+    ///
+    /// let fastq = b"@r1\nACGT\n+\nIIII\n@r2\nAC\n+\nII\n@r1\nA\n+\nI\n";
+    /// let options = IndexBuildOptions { dedup: DedupMode::Hashed, name_map: false };
+    /// let index =
+    ///     FastqIndex::from_fastq_file_with(&mut Cursor::new(&fastq[..]), DuplicatePolicy::KeepFirst, options)
+    ///         .unwrap();
+    /// assert!(!index.has_name_map());
+    /// assert_eq!(index.len(), 2);
+    /// // names are left in the file, and read from there when a record is fetched
+    /// assert_eq!(index.nth_entry(1).unwrap().name(), b"");
+    /// let mut reader = IndexedFastq::new(Cursor::new(&fastq[..]), &index);
+    /// assert_eq!(reader.nth_record(1).unwrap().id(), b"r2");
+    /// ```
+    pub fn from_indexer_with<F: BufRead + Seek>(
+        idxr: FastqIndexer<'_, F>,
+        policy: DuplicatePolicy,
+        options: IndexBuildOptions,
+    ) -> Result<Self, FastqError> {
+        if options.name_map {
+            return Self::from_indexer(idxr, policy);
         }
-        idx
+        Ok(FastqIndex {
+            entries: index_build::offsets_only(idxr, &policy, options.dedup)?,
+            by_name: OnceLock::new(),
+            offsets_only: true,
+        })
     }
 
-    pub fn from_fasta_file<F: BufRead + Seek>(fasta: &mut F) -> Self {
-        let idxr = FastqIndexer::new(fasta);
-        idxr.into()
+    /// Whether names are mapped to entries, see `IndexBuildOptions::name_map`
+    pub fn has_name_map(&self) -> bool {
+        !self.offsets_only
     }
 
+    /// Add the entries of a `.fai` read from `handle`
+    ///
+    /// Fails with `InvalidData` on a name repeated in the file or already in the
+    /// index, adding nothing.
+    ///
+    /// A handle starting with `BINARY_MAGIC` is read as a binary index; its
+    /// `BinaryIndexError` is the inner error of the returned `InvalidData` error.
     pub fn read_index(&mut self, handle: &mut impl BufRead) -> Result<(), std::io::Error> {
-        for line in handle.lines() {
-            match line? {
-                l => {
-                    let fields = l.split('\t').collect::<Vec<&str>>();
-                    if fields.len() != 6 {
-                        return Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            "malformed index",
-                        ));
+        // however little the handle buffers, the whole magic is read to compare it
+        let mut magic = Vec::with_capacity(BINARY_MAGIC.len());
+        handle
+            .by_ref()
+            .take(BINARY_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        let handle = &mut magic.as_slice().chain(handle);
+        if magic == BINARY_MAGIC {
+            return self.read_binary(handle).map_err(std::io::Error::from);
+        }
+        let malformed = || std::io::Error::new(ErrorKind::InvalidData, "malformed index");
+        let mut read = Vec::new();
+        for line in handle.split(b'\n') {
+            let l = line?;
+            let l = l.strip_suffix(b"\r").unwrap_or(&l);
+            let fields = l.split(|b| *b == b'\t').collect::<Vec<&[u8]>>();
+            if fields.len() != 6 {
+                return Err(malformed());
+            }
+            let num = |i: usize| {
+                std::str::from_utf8(fields[i])
+                    .ok()
+                    .and_then(|f| f.parse::<u64>().ok())
+                    .ok_or_else(malformed)
+            };
+            let entry = FastqIndexEntry {
+                name: fields[0].to_vec(),
+                offset: num(2)?,
+                length: num(1)?,
+                q_offset: num(5)?,
+                linewidth: num(4)?,
+                linebases: num(3)?,
+            };
+            if entry.checked_end().is_none() {
+                return Err(malformed());
+            }
+            read.push(entry);
+        }
+        let first = self.entries.len();
+        let repeat = match self.offsets_only {
+            true => index_build::append_unique(&mut self.entries, read).err(),
+            false => {
+                self.names();
+                let names = read.iter().map(|e| e.name.as_slice()).collect::<Vec<_>>();
+                let old = &self.entries;
+                match self
+                    .by_name
+                    .get_mut()
+                    .unwrap()
+                    .push_unique(&names, |i| &old[i].name)
+                {
+                    Ok(()) => {
+                        self.entries.extend(read);
+                        None
                     }
-                    self.inner.insert(
-                        String::from(fields[0]),
-                        FastqIndexEntry {
-                            name: String::from(fields[0]),
-                            offset: fields[2].parse::<u64>().unwrap(),
-                            length: fields[1].parse::<u64>().unwrap(),
-                            q_offset: fields[5].parse::<u64>().unwrap(),
-                            linewidth: fields[4].parse::<u64>().unwrap(),
-                            linebases: fields[3].parse::<u64>().unwrap(),
-                        },
-                    );
+                    Err(pos) => Some(read.swap_remove(pos - first)),
+                }
+            }
+        };
+        match repeat {
+            Some(e) => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "repeated name '{}' in index",
+                    String::from_utf8_lossy(&e.name)
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Position in `entries` of the record called `name`
+    fn position(&self, name: &[u8]) -> Option<usize> {
+        match self.offsets_only {
+            true => self.entries.iter().position(|e| e.name == name),
+            false => self.names().find(name, |i| &self.entries[i].name),
+        }
+    }
+
+    /// The name map, filled from the entries if it was left for later
+    fn names(&self) -> &NameIndex {
+        self.by_name.get_or_init(|| {
+            let mut names = NameIndex::default();
+            names.reserve(self.entries.len());
+            self.entries.iter().for_each(|e| names.push(&e.name));
+            names
+        })
+    }
+
+    /// Append `entry`, replacing an earlier entry of the same name in place, as
+    /// `DuplicatePolicy::KeepLast` does
+    fn push(&mut self, entry: FastqIndexEntry) {
+        match self.position(&entry.name) {
+            Some(i) => self.entries[i] = entry,
+            None => {
+                // `position` has built the map
+                if let Some(names) = self.by_name.get_mut() {
+                    names.push(&entry.name);
+                }
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    /// Append `entry`, or handle its name being taken as `policy` says
+    fn insert(
+        &mut self,
+        mut entry: FastqIndexEntry,
+        policy: &DuplicatePolicy,
+    ) -> Result<(), FastqError> {
+        if let Some(i) = self.position(&entry.name) {
+            match policy {
+                DuplicatePolicy::Error => {
+                    return Err(FastqError::DuplicateEntry {
+                        name: String::from_utf8_lossy(&entry.name).into_owned(),
+                        first: self.entries[i].offset,
+                        second: entry.offset,
+                    })
+                }
+                DuplicatePolicy::KeepFirst => return Ok(()),
+                DuplicatePolicy::KeepLast => {}
+                DuplicatePolicy::Rename { suffix } => {
+                    entry.name = renamed(&entry.name, suffix, |n| self.position(n).is_some());
                 }
             }
         }
+        self.push(entry);
         Ok(())
     }
 
-    pub fn get(&self, id: &str) -> Option<&FastqIndexEntry> {
-        self.inner.get(id)
+    /// Write the index in samtools fqidx column order, one line per entry in file order
+    ///
+    /// Fails with `InvalidInput` for an index built without its names, see
+    /// `DedupMode::Hashed`.
+    pub fn write_index(&self, mut out: impl Write) -> std::io::Result<()> {
+        self.check_names()?;
+        for e in &self.entries {
+            e.write_line(&mut out)?;
+        }
+        out.flush()
     }
 
-    pub fn inner(&self) -> &FxHashMap<String, FastqIndexEntry> {
-        &self.inner
+    /// Write the index in the binary format read by `read_binary`
+    ///
+    /// The header is followed by the string table of all names, then one
+    /// fixed-width entry per record, so a reader has the names in hand while
+    /// decoding entries. Fails as `write_index` does for an index without its names.
+    pub fn write_binary(&self, mut out: impl Write) -> std::io::Result<()> {
+        self.check_names()?;
+        let names_len: u64 = self.entries.iter().map(|e| e.name.len() as u64).sum();
+        out.write_all(BINARY_MAGIC)?;
+        out.write_all(&BINARY_VERSION.to_le_bytes())?;
+        out.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        out.write_all(&names_len.to_le_bytes())?;
+        for e in &self.entries {
+            out.write_all(&e.name)?;
+        }
+        let mut name_offset = 0u64;
+        for e in &self.entries {
+            let name_len = e.name.len() as u64;
+            for field in [
+                name_offset,
+                name_len,
+                e.length,
+                e.offset,
+                e.q_offset,
+                e.linewidth,
+                e.linebases,
+            ] {
+                out.write_all(&field.to_le_bytes())?;
+            }
+            name_offset += name_len;
+        }
+        out.flush()
+    }
+
+    /// Add the entries of a binary index written by `write_binary`
+    ///
+    /// Unlike `read_index`, a repeated name is an error rather than replacing
+    /// the earlier entry, as `write_binary` never writes one; nothing is added
+    /// on error. Loaded into an empty index, the names are only checked for
+    /// repeats, and mapped to their entries on the first lookup by name.
+    pub fn read_binary(&mut self, mut handle: impl Read) -> Result<(), BinaryIndexError> {
+        let mut header = [0u8; BINARY_HEADER_LEN];
+        read_section(&mut handle, &mut header, "header")?;
+        if &header[..8] != BINARY_MAGIC {
+            return Err(BinaryIndexError::BadMagic);
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != BINARY_VERSION {
+            return Err(BinaryIndexError::UnsupportedVersion(version));
+        }
+        let count = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let names_len = u64::from_le_bytes(header[20..28].try_into().unwrap());
+
+        // the table length comes from the file, so it is read through `take` rather
+        // than trusted with an allocation up front
+        let mut names = Vec::new();
+        if handle.by_ref().take(names_len).read_to_end(&mut names)? as u64 != names_len {
+            return Err(BinaryIndexError::Truncated {
+                section: "string table",
+            });
+        }
+
+        // entries are decoded a chunk at a time, never held as bytes all at once;
+        // each has a name, so the table that was read bounds how many to expect
+        let first = self.entries.len();
+        let expected = usize::try_from(count).map_or(names.len(), |n| n.min(names.len()));
+        self.entries.reserve(expected);
+        // names as slices of the table, hashed from there in one pass below
+        let mut new_names = Vec::with_capacity(expected);
+        let mut chunk = vec![0u8; BINARY_ENTRY_LEN * ENTRIES_PER_READ];
+        let mut decoded = 0;
+        while decoded < count {
+            let n = (count - decoded).min(ENTRIES_PER_READ as u64) as usize;
+            let raw = &mut chunk[..n * BINARY_ENTRY_LEN];
+            let decode = read_section(&mut handle, raw, "entries").and_then(|()| {
+                for raw in raw.chunks_exact(BINARY_ENTRY_LEN) {
+                    let (entry, name) = binary_entry(new_names.len(), raw, &names)?;
+                    self.entries.push(entry);
+                    new_names.push(name);
+                }
+                Ok(())
+            });
+            if let Err(e) = decode {
+                self.entries.truncate(first);
+                return Err(e);
+            }
+            decoded += n as u64;
+        }
+
+        let repeat = if first == 0 {
+            self.by_name = OnceLock::new();
+            name_index::first_repeat(&new_names)
+        } else {
+            self.names();
+            let (old, names) = (&self.entries[..first], self.by_name.get_mut().unwrap());
+            let pushed = names.push_unique(&new_names, |i| &old[i].name);
+            pushed.err().map(|pos| pos - first)
+        };
+        if let Some(entry) = repeat {
+            self.entries.truncate(first);
+            return Err(BinaryIndexError::DuplicateName { entry });
+        }
+        Ok(())
+    }
+
+    /// Fail with `InvalidInput` if an entry has no name to write
+    fn check_names(&self) -> std::io::Result<()> {
+        match self.entries.iter().any(|e| e.name.is_empty()) {
+            true => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "the index was built without its names",
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Check that this index, loaded from `<path>.fai`, still describes `path`
+    ///
+    /// Compares `path` with `<path>.fai.meta` when present, and checks that every
+    /// entry lies within the file.
+    pub fn validate_against(&self, path: impl AsRef<Path>) -> Result<(), StaleIndex> {
+        let path = path.as_ref();
+        let data_end = self.entries.iter().map(FastqIndexEntry::record_end).max();
+        index_meta::validate(path, &index_meta::fai_path(path), data_end.unwrap_or(0))
+    }
+
+    /// Entry of the record called `id`
+    pub fn get(&self, id: impl AsRef<[u8]>) -> Option<&FastqIndexEntry> {
+        self.position(id.as_ref()).map(|i| &self.entries[i])
+    }
+
+    /// True if a record is called `id`
+    pub fn contains(&self, id: impl AsRef<[u8]>) -> bool {
+        self.position(id.as_ref()).is_some()
+    }
+
+    /// Entries in the order their records appear in the file
+    pub fn entries(&self) -> impl Iterator<Item = &FastqIndexEntry> {
+        self.entries.iter()
+    }
+
+    /// The `n`-th (0-based) record in file order
+    pub fn nth_entry(&self, n: usize) -> Option<&FastqIndexEntry> {
+        self.entries.get(n)
+    }
+
+    /// File offset of the '@' line of the `n`-th record
+    ///
+    /// Computed from the end of the previous record, so it assumes nothing sits
+    /// between records and that the file starts with the first one.
+    /// `n == len()` gives the end of the last record.
+    pub fn record_start(&self, n: usize) -> Option<u64> {
+        match n {
+            0 => Some(0),
+            n if n <= self.len() => self.nth_entry(n - 1).map(|e| e.record_end()),
+            _ => None,
+        }
+    }
+
+    /// Number of records
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True if there are no records
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// First bytes of a binary FASTQ index
+pub const BINARY_MAGIC: &[u8; 8] = b"LYFQIDX1";
+
+/// Version of the binary layout `write_binary` writes
+const BINARY_VERSION: u32 = 2;
+
+/// Magic, version, entry count and string table length
+const BINARY_HEADER_LEN: usize = 28;
+
+/// Name offset and length into the string table, then the five `.fai` columns
+const BINARY_ENTRY_LEN: usize = 7 * 8;
+
+/// Entries `read_binary` reads at a time
+const ENTRIES_PER_READ: usize = 4096;
+
+/// A binary FASTQ index that could not be read
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BinaryIndexError {
+    /// Reading failed
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The file does not start with `BINARY_MAGIC`
+    #[error("not a binary FASTQ index")]
+    BadMagic,
+    /// A layout version this build cannot read
+    #[error("unsupported binary index version {0}")]
+    UnsupportedVersion(u32),
+    /// The file ended inside a section
+    #[error("binary index truncated in its {section}")]
+    Truncated {
+        /// "header", "entries" or "string table"
+        section: &'static str,
+    },
+    /// An entry whose name lies outside the string table
+    #[error("entry {entry}: name at {start}+{len} is outside the {table_len}-byte string table")]
+    NameOutOfRange {
+        /// 0-based entry number
+        entry: usize,
+        /// Offset of the name in the string table
+        start: u64,
+        /// Length of the name
+        len: u64,
+        /// Length of the string table
+        table_len: u64,
+    },
+    /// An entry named like an earlier one, or like a record already indexed
+    #[error("entry {entry}: repeated name")]
+    DuplicateName {
+        /// 0-based entry number
+        entry: usize,
+    },
+    /// An entry whose columns cannot describe a record
+    #[error("entry {entry}: invalid line width or span")]
+    InvalidEntry {
+        /// 0-based entry number
+        entry: usize,
+    },
+}
+
+impl From<BinaryIndexError> for std::io::Error {
+    fn from(value: BinaryIndexError) -> Self {
+        match value {
+            BinaryIndexError::Io(e) => e,
+            e => std::io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Decode entry `i` of a binary index, whose names are in `names`
+fn binary_entry<'a>(
+    i: usize,
+    raw: &[u8],
+    names: &'a [u8],
+) -> Result<(FastqIndexEntry, &'a [u8]), BinaryIndexError> {
+    let field = |n: usize| u64::from_le_bytes(raw[n * 8..n * 8 + 8].try_into().unwrap());
+    let (start, len) = (field(0), field(1));
+    let table_len = names.len() as u64;
+    let end = start
+        .checked_add(len)
+        .filter(|end| *end <= table_len)
+        .ok_or(BinaryIndexError::NameOutOfRange {
+            entry: i,
+            start,
+            len,
+            table_len,
+        })?;
+    let name = &names[start as usize..end as usize];
+    let entry = FastqIndexEntry {
+        name: name.to_vec(),
+        length: field(2),
+        offset: field(3),
+        q_offset: field(4),
+        linewidth: field(5),
+        linebases: field(6),
+    };
+    match entry.checked_end() {
+        Some(_) => Ok((entry, name)),
+        None => Err(BinaryIndexError::InvalidEntry { entry: i }),
+    }
+}
+
+/// Fill `buf`, or report `section` as truncated
+fn read_section(
+    handle: &mut impl Read,
+    buf: &mut [u8],
+    section: &'static str,
+) -> Result<(), BinaryIndexError> {
+    handle.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => BinaryIndexError::Truncated { section },
+        _ => BinaryIndexError::Io(e),
+    })
+}
+
+/// One line of a FASTQ `.fai`: where a record's sequence and quality lie
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct FastqIndexEntry {
-    name: String,
+    name: Vec<u8>,
     offset: u64,
     length: u64,
     q_offset: u64,
@@ -89,15 +578,22 @@ impl fmt::Display for FastqIndexEntry {
         write!(
             f,
             "{}\t{}\t{}\t{}\t{}\t{}",
-            self.name, self.length, self.offset, self.linebases, self.linewidth, self.q_offset
+            self.name_str_lossy(),
+            self.length,
+            self.offset,
+            self.linebases,
+            self.linewidth,
+            self.q_offset
         )
     }
 }
 
 impl FastqIndexEntry {
+    /// An empty entry
+    #[must_use]
     pub fn new() -> Self {
         FastqIndexEntry {
-            name: "".into(),
+            name: Vec::new(),
             offset: 0,
             length: 0,
             q_offset: 0,
@@ -106,6 +602,17 @@ impl FastqIndexEntry {
         }
     }
 
+    /// Write the entry as a `.fai` line, with the name as raw bytes
+    fn write_line(&self, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(&self.name)?;
+        writeln!(
+            out,
+            "\t{}\t{}\t{}\t{}\t{}",
+            self.length, self.offset, self.linebases, self.linewidth, self.q_offset
+        )
+    }
+
+    /// Reset to an empty entry
     pub fn clear(&mut self) {
         self.name.clear();
         self.offset = 0;
@@ -115,6 +622,7 @@ impl FastqIndexEntry {
         self.linebases = 0;
     }
 
+    /// True if no field is set
     pub fn empty(&self) -> bool {
         self.name.is_empty()
             && (*self.offset() == 0)
@@ -124,51 +632,105 @@ impl FastqIndexEntry {
             && (*self.linebases() == 0)
     }
 
-    pub fn name(&self) -> &str {
-        self.name.as_ref()
+    /// Record name, up to the first whitespace of the header
+    pub fn name(&self) -> &[u8] {
+        &self.name
     }
 
+    /// The name as text, with invalid UTF-8 replaced by U+FFFD
+    pub fn name_str_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+
+    /// File offset of the first base
     pub fn offset(&self) -> &u64 {
         &self.offset
     }
 
+    /// Number of bases
     pub fn length(&self) -> &u64 {
         &self.length
     }
 
+    /// File offset of the first quality character
     pub fn q_offset(&self) -> &u64 {
         &self.q_offset
     }
 
+    /// Bytes per sequence line, terminator included
     pub fn linewidth(&self) -> &u64 {
         &self.linewidth
     }
 
+    /// Bases per sequence line
     pub fn linebases(&self) -> &u64 {
         &self.linebases
     }
+
+    /// Bytes spanned by the sequence (or quality) lines, newlines included
+    ///
+    /// Entries read from a `.fai` are checked to fit in a `u64`, as are those
+    /// built from a file.
+    pub fn span(&self) -> u64 {
+        self.checked_span().unwrap_or(u64::MAX)
+    }
+
+    fn checked_span(&self) -> Option<u64> {
+        if self.linebases == 0 {
+            return Some(0);
+        }
+        let full = self.length / self.linebases;
+        let rest = self.length % self.linebases;
+        // a partial last line carries the same line terminator as full ones
+        let partial = if rest > 0 {
+            rest + self.linewidth.checked_sub(self.linebases)?
+        } else {
+            0
+        };
+        full.checked_mul(self.linewidth)?.checked_add(partial)
+    }
+
+    /// File offset just past the quality lines
+    pub fn record_end(&self) -> u64 {
+        self.checked_end().unwrap_or(u64::MAX)
+    }
+
+    /// `record_end`, if the entry is consistent and its arithmetic fits in a `u64`
+    fn checked_end(&self) -> Option<u64> {
+        if self.linewidth < self.linebases || (self.linebases == 0 && self.length > 0) {
+            return None;
+        }
+        let span = self.checked_span()?;
+        self.offset.checked_add(span)?;
+        self.q_offset.checked_add(span)
+    }
 }
 
+/// Iterator over the index entries of a FASTQ file, read from the start of the handle
 pub struct FastqIndexer<'a, R: 'a> {
     handle: &'a mut R,
-    buffer: String,
+    buffer: Vec<u8>,
 }
 
 impl<'a, F> FastqIndexer<'a, F>
 where
     F: BufRead + Seek,
 {
+    /// Index `f` from its current position
+    #[must_use]
     pub fn new(f: &'a mut F) -> Self {
         FastqIndexer {
             handle: f,
-            buffer: "".into(),
+            buffer: Vec::new(),
         }
     }
 
+    /// Fill `record` with the entry of the next record, leaving it empty at EOF
     pub fn make_index(&mut self, record: &mut FastqIndexEntry) -> Result<(), FastqError> {
-        match self.handle.read_line(&mut self.buffer) {
+        self.buffer.clear();
+        match self.handle.read_until(b'\n', &mut self.buffer) {
             Ok(0) if record.empty() => return Ok(()), // EOF
-            Ok(_) => self.buffer.retain(|c| c != '\n'),
+            Ok(_) => self.buffer.retain(|c| *c != b'\n' && *c != b'\r'),
             Err(e) => return Err(FastqError::IoError(e)),
         };
 
@@ -177,46 +739,124 @@ where
             return Ok(());
         }
 
-        if !self.buffer.starts_with("@") {
+        if !self.buffer.starts_with(b"@") {
             return Err(FastqError::MissingId);
         }
 
         // assume all content after first whitespace is description
-        let mut header = self.buffer[1..].trim_end().splitn(2, char::is_whitespace);
+        let mut header = self.buffer[1..]
+            .trim_ascii_end()
+            .splitn(2, u8::is_ascii_whitespace);
         match header.next() {
-            Some(v) => record.name = v.to_string(),
-            None => return Err(FastqError::TruncatedId),
+            Some(v) if !v.is_empty() => record.name = v.to_vec(),
+            _ => return Err(FastqError::TruncatedId),
         }
-        record.offset = self.handle.stream_position()? as u64;
+        record.offset = self.handle.stream_position()?;
 
         // read first sequence line
         // don't count newline for nbases
         self.buffer.clear();
-        record.linewidth = self.handle.read_line(&mut self.buffer)? as u64;
-        record.linebases = self.buffer.trim_end().len() as u64;
+        record.linewidth = self.handle.read_until(b'\n', &mut self.buffer)? as u64;
+        record.linebases = self.buffer.trim_ascii_end().len() as u64;
 
-        let mut seq_lines = 0;
-        while !self.buffer.is_empty() && !self.buffer.starts_with("+") {
-            record.length += self.buffer.trim_end().len() as u64;
-            self.buffer.clear();
-            match self.handle.read_line(&mut self.buffer) {
-                Ok(0) => return Err(FastqError::EofError),
-                Ok(_) => (),
-                Err(e) => return Err(FastqError::IoError(e)),
+        while !self.buffer.starts_with(b"+") {
+            if self.buffer.is_empty() {
+                return Err(FastqError::in_record(&record.name, FastqError::EofError));
             }
+            record.length += self.buffer.trim_ascii_end().len() as u64;
+            self.buffer.clear();
+            self.handle.read_until(b'\n', &mut self.buffer)?;
         }
 
-        record.q_offset = (self.handle.stream_position()?) as u64;
-        let skip_to = record.length + 1;
+        record.q_offset = self.handle.stream_position()?;
 
-        self.buffer.clear();
         // skip to start of next record without discarding buffer
-        skip_fwd(&mut self.handle, skip_to);
+        let skip = record.span();
+        // a short skip is a record truncated in its quality lines
+        if skip_fwd(&mut self.handle, skip)? != skip {
+            return Err(FastqError::in_record(&record.name, FastqError::EofError));
+        }
 
         Ok(())
     }
 }
 
+impl<F> FastqIndexer<'_, F>
+where
+    F: BufRead + Seek,
+{
+    /// Write a `.fai` line for each record as it is indexed, without holding the index
+    ///
+    /// See `index_build::write_index`: repeated names are found through their
+    /// hashes, and `DuplicatePolicy::KeepLast` fails with `InvalidInput`.
+    /// Returns the number of lines written.
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use lyso_fastq::index::{DuplicatePolicy, FastqIndexer};
+    ///
+    /// let fastq = b"@r1\nACGT\n+\nIIII\n@r1\nAC\n+\nII\n";
+    /// let mut input = Cursor::new(&fastq[..]);
+    /// let mut fai = Vec::new();
+    /// let policy = DuplicatePolicy::Rename { suffix: ".".into() };
+    /// let written = FastqIndexer::new(&mut input).write_index(&policy, &mut fai).unwrap();
+    /// assert_eq!(written, 2);
+    /// assert_eq!(fai, b"r1\t4\t4\t4\t5\t11\nr1.1\t2\t20\t2\t3\t25\n");
+    /// ```
+    pub fn write_index(
+        self,
+        policy: &DuplicatePolicy,
+        out: impl Write,
+    ) -> Result<usize, FastqError> {
+        index_build::write_index(self, policy, out)
+    }
+}
+
+impl<F> Indexer for FastqIndexer<'_, F>
+where
+    F: BufRead + Seek,
+{
+    type Entry = FastqIndexEntry;
+    type Error = FastqError;
+    type Handle = F;
+
+    fn handle(&mut self) -> &mut F {
+        self.handle
+    }
+
+    fn next_entry(&mut self) -> Result<Option<FastqIndexEntry>, FastqError> {
+        self.next().transpose()
+    }
+}
+
+impl IndexEntry for FastqIndexEntry {
+    fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    fn set_name(&mut self, name: Vec<u8>) {
+        self.name = name;
+    }
+
+    fn seq_offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn write_line(&self, mut out: &mut dyn Write) -> std::io::Result<()> {
+        FastqIndexEntry::write_line(self, &mut out)
+    }
+}
+
+impl From<RepeatedName> for FastqError {
+    fn from(value: RepeatedName) -> Self {
+        FastqError::DuplicateEntry {
+            name: String::from_utf8_lossy(&value.name).into_owned(),
+            first: value.first,
+            second: value.second,
+        }
+    }
+}
+
 impl<'a, F> Iterator for FastqIndexer<'a, F>
 where
     F: BufRead + Seek,
@@ -233,56 +873,681 @@ where
     }
 }
 
-impl<'a, F> Into<FastqIndex> for FastqIndexer<'a, F>
+/// Fails on a malformed record or, under the default policy, a repeated name
+impl<'a, F> TryFrom<FastqIndexer<'a, F>> for FastqIndex
 where
     F: BufRead + Seek,
 {
-    fn into(self) -> FastqIndex {
-        FastqIndex::from_entries(self.into_iter().map(|x| x.unwrap()))
+    type Error = FastqError;
+
+    fn try_from(idxr: FastqIndexer<'a, F>) -> Result<FastqIndex, FastqError> {
+        FastqIndex::from_indexer(idxr, DuplicatePolicy::default())
     }
 }
 
-pub struct IndexedFastq<'a, F, R> {
+/// Random access to an indexed FASTQ file
+///
+/// Records fetched through the index have no description: it is not part of the index.
+///
+/// ```
+/// use std::fs::File;
+/// use std::io::BufReader;
+/// use lyso_fastq::index::{FastqIndex, IndexedFastq};
+///
+/// let mut index = FastqIndex::new();
+/// let fai = File::open("../resources/test_data/test.fastq.fai").unwrap();
+/// index.read_index(&mut BufReader::new(fai)).unwrap();
+///
+/// let mut reader = IndexedFastq::from_path("../resources/test_data/test.fastq", &index).unwrap();
+/// let rec = reader.get("SRR22092847.1.1").unwrap();
+/// assert_eq!(rec.seq().len(), 37);
+/// assert_eq!(rec.desc(), None);
+/// ```
+pub struct IndexedFastq<'a, F> {
     index: &'a FastqIndex,
     handle: F,
-    _dtype: PhantomData<R>,
+    max_record_size: usize,
 }
 
-impl<'a, F, R> IndexedFastq<'a, F, R>
+impl<'a, F> IndexedFastq<'a, F>
 where
-    F: BufRead + Seek,
-    R: FastqRecord,
+    F: Read + Seek,
 {
+    /// Fetch records from `handle` through `index`
+    #[must_use]
     pub fn new(handle: F, index: &'a FastqIndex) -> Self {
         IndexedFastq {
             index,
             handle,
-            _dtype: PhantomData,
+            max_record_size: usize::MAX,
         }
     }
 
-    pub fn get(&mut self, id: &str, rec: &mut R) -> Result<(), std::io::Error> {
-        if let Some(idx) = self.index.get(id) {
-            rec.clear();
-            rec.set_id(idx.name.clone());
+    /// Fail fetches whose sequence lines span more than `bytes` bytes of the file
+    ///
+    /// The error is `FetchError::TooLarge`. Fetches are always limited to what fits
+    /// in memory addresses.
+    #[must_use]
+    pub fn max_record_size(mut self, bytes: usize) -> Self {
+        self.max_record_size = bytes;
+        self
+    }
 
-            self.handle.seek(SeekFrom::Start(idx.offset))?;
-            let nlines = idx.length / idx.linebases;
-            let mut buf: Vec<u8> = vec![0 as u8; (nlines * idx.linewidth) as usize];
-            self.handle.read_exact(&mut buf)?;
-            buf.retain(|c| *c != b'\n');
-            rec.set_seq(String::from_utf8_lossy(&buf).into_owned());
+    /// The index used for fetching
+    pub fn index(&self) -> &FastqIndex {
+        self.index
+    }
 
-            self.handle.seek(SeekFrom::Start(idx.q_offset))?;
-            self.handle.read_exact(&mut buf)?;
-            buf.retain(|c| *c != b'\n');
-            rec.set_qual(String::from_utf8_lossy(&buf).into_owned());
-            return Ok(());
+    /// Fetch a single record by id
+    pub fn get(&mut self, id: impl AsRef<[u8]>) -> Result<Record, FetchError> {
+        match self.index.get(&id) {
+            Some(entry) => read_entry(&mut self.handle, entry, self.max_record_size),
+            None => Err(not_found(id.as_ref())),
         }
-        Err(std::io::Error::new(ErrorKind::NotFound, "id not found"))
+    }
+
+    /// Fetch the `n`-th (0-based) record in file order
+    ///
+    /// In an index built without its names, the name is read from the header.
+    pub fn nth_record(&mut self, n: usize) -> Result<Record, FetchError> {
+        let Some(entry) = self.index.nth_entry(n) else {
+            return Err(FetchError::NotFound(format!("record {n}")));
+        };
+        let mut rec = read_entry(&mut self.handle, entry, self.max_record_size)?;
+        if rec.id.is_empty() {
+            rec.id = header_name(&mut self.handle, entry.offset)?;
+        }
+        Ok(rec)
+    }
+
+    /// Fetch many records, visiting them in file-offset order
+    ///
+    /// Results are returned in the order of `ids`. Unknown ids get a `NotFound`
+    /// error in their slot without affecting the others.
+    pub fn get_many<S: AsRef<[u8]>>(&mut self, ids: &[S]) -> Vec<Result<Record, FetchError>> {
+        let (handle, limit) = (&mut self.handle, self.max_record_size);
+        fetch_in_order(entries(self.index, ids), |entry| {
+            read_entry(handle, entry, limit)
+        })
+    }
+
+    /// Fetch many records as they appear in the file, from the '@' to the end of
+    /// their quality lines
+    ///
+    /// Unlike `get_many`, this keeps the description, the text after the '+' and
+    /// the line breaks. The last record of the file may lack its final line
+    /// terminator.
+    pub fn get_many_raw<S: AsRef<[u8]>>(
+        &mut self,
+        ids: &[S],
+    ) -> Vec<Result<RawRecord, FetchError>> {
+        let (handle, limit) = (&mut self.handle, self.max_record_size);
+        fetch_in_order(entries(self.index, ids), |entry| {
+            index_build::read_raw(handle, &entry.name, entry.offset, entry.record_end(), limit)
+        })
+    }
+
+    /// The file handle
+    pub fn into_inner(self) -> F {
+        self.handle
     }
 }
 
-fn skip_fwd<R: BufRead>(handle: &mut R, offset: u64) {
-    std::io::copy(&mut handle.by_ref().take(offset), &mut std::io::sink()).unwrap();
+impl<'a> IndexedFastq<'a, File> {
+    /// Open `path` for fetching through `index`, failing if the index is stale
+    pub fn from_path(path: impl AsRef<Path>, index: &'a FastqIndex) -> Result<Self, StaleIndex> {
+        index.validate_against(&path)?;
+        Ok(IndexedFastq::new(File::open(path)?, index))
+    }
+}
+
+fn not_found(id: &[u8]) -> FetchError {
+    FetchError::NotFound(String::from_utf8_lossy(id).into_owned())
+}
+
+/// The entry of each id, keyed by its offset, or `NotFound`
+fn entries<'i, S: AsRef<[u8]>>(
+    index: &'i FastqIndex,
+    ids: &'i [S],
+) -> impl Iterator<Item = Result<(u64, &'i FastqIndexEntry), FetchError>> {
+    ids.iter().map(|id| match index.get(id) {
+        Some(entry) => Ok((entry.offset, entry)),
+        None => Err(not_found(id.as_ref())),
+    })
+}
+
+/// Read the record described by `entry`, if its lines span at most `limit` bytes each
+fn read_entry<R: Read + Seek>(
+    handle: &mut R,
+    entry: &FastqIndexEntry,
+    limit: usize,
+) -> Result<Record, FetchError> {
+    let len = span_len(&entry.name, entry.span(), limit)?;
+    let mut read_lines = |offset: u64| -> Result<String, FetchError> {
+        handle.seek(SeekFrom::Start(offset))?;
+        Ok(String::from_utf8(read_span(handle, len)?)?)
+    };
+    Ok(Record {
+        id: entry.name.clone(),
+        desc: None,
+        seq: read_lines(entry.offset)?,
+        qual: read_lines(entry.q_offset)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::harness::for_each_parser;
+    use crate::reader::FastqReader;
+    use std::io::BufReader;
+
+    const FQ_PATH: &str = "../resources/test_data/test.fastq";
+
+    fn build_index() -> FastqIndex {
+        let mut b = BufReader::new(File::open(FQ_PATH).unwrap());
+        FastqIndex::from_fastq_file(&mut b, Default::default()).unwrap()
+    }
+
+    fn scan() -> Vec<Record> {
+        let f = BufReader::new(File::open(FQ_PATH).unwrap());
+        FastqReader::new(f).map(|r| r.unwrap()).collect()
+    }
+
+    for_each_parser! {
+        fn test_nth_record_matches_scan() {
+            let index = build_index();
+            let records = scan();
+            assert_eq!(index.len(), records.len());
+            let mut fq = IndexedFastq::new(File::open(FQ_PATH).unwrap(), &index);
+            for (n, rec) in records.iter().enumerate() {
+                let got = fq.nth_record(n).unwrap();
+                assert_eq!(
+                    (got.id(), got.seq(), got.qual()),
+                    (rec.id(), rec.seq(), rec.qual())
+                );
+            }
+            assert!(fq.nth_record(records.len()).is_err());
+            assert_eq!(fq.get(records[3].id()).unwrap().seq(), records[3].seq());
+        }
+
+        fn test_get_many_preserves_order() {
+            let index = build_index();
+            let records = scan();
+            let mut fq = IndexedFastq::new(File::open(FQ_PATH).unwrap(), &index);
+            let ids = [records[5].id(), b"missing", records[0].id(), records[5].id()];
+            let many = fq.get_many(&ids);
+            assert_eq!(many.len(), ids.len());
+            for (id, res) in ids.iter().zip(many) {
+                match fq.get(id) {
+                    Ok(rec) => assert_eq!(res.unwrap(), rec),
+                    Err(_) => {
+                        let err = res.unwrap_err();
+                        assert!(matches!(err, FetchError::NotFound(ref id) if id == "missing"));
+                        assert_eq!(err.to_string(), "id not found: missing");
+                    }
+                }
+            }
+        }
+
+        fn test_get_many_raw() {
+            let index = build_index();
+            let bytes = std::fs::read(FQ_PATH).unwrap();
+            let raws = FastqReader::new(&bytes[..])
+                .raw_records()
+                .map(|r| r.unwrap())
+                .collect::<Vec<_>>();
+            let last = raws.len() - 1;
+            let ids = [raws[last].id(), b"missing", raws[0].id()];
+            let mut fq = IndexedFastq::new(File::open(FQ_PATH).unwrap(), &index);
+            let many = fq.get_many_raw(&ids);
+            assert_eq!(many[0].as_ref().unwrap(), &raws[last]);
+            assert!(matches!(many[1], Err(FetchError::NotFound(_))));
+            assert_eq!(many[2].as_ref().unwrap(), &raws[0]);
+        }
+
+        fn test_record_start() {
+            let index = build_index();
+            let bytes = std::fs::read(FQ_PATH).unwrap();
+            let records = scan();
+            for n in [0, 1, records.len() - 1] {
+                let start = index.record_start(n).unwrap() as usize;
+                let rest = FastqReader::new(&bytes[start..])
+                    .map(|r| r.unwrap())
+                    .collect::<Vec<Record>>();
+                assert_eq!(rest, records[n..]);
+            }
+            assert_eq!(index.record_start(records.len()), Some(bytes.len() as u64));
+            assert_eq!(index.record_start(records.len() + 1), None);
+        }
+
+        fn test_matches_fai_fixture() {
+            let index = build_index();
+            let fai = std::fs::read_to_string(format!("{FQ_PATH}.fai")).unwrap();
+            let text = (0..index.len())
+                .map(|n| format!("{}\n", index.nth_entry(n).unwrap()))
+                .collect::<String>();
+            assert_eq!(text, fai);
+        }
+
+        fn test_read_index_round_trip() {
+            let index = build_index();
+            let text = (0..index.len())
+                .map(|n| format!("{}\n", index.nth_entry(n).unwrap()))
+                .collect::<String>();
+            let mut loaded = FastqIndex::new();
+            loaded.read_index(&mut text.as_bytes()).unwrap();
+            assert_eq!(loaded.len(), index.len());
+            assert_eq!(loaded.nth_entry(5), index.nth_entry(5));
+            assert!(FastqIndex::new()
+                .read_index(&mut "r1\t1\tx\t1\t2\t3\n".as_bytes())
+                .is_err());
+        }
+
+        fn test_read_index_repeated_name() {
+            let line = "r1\t4\t4\t4\t5\t11\n";
+            let err = FastqIndex::new()
+                .read_index(&mut line.repeat(2).as_bytes())
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), "repeated name 'r1' in index");
+
+            // a name already in the index, which is left as it was
+            let mut index = build_index();
+            let before = index.clone();
+            let first = format!("{}\n", index.nth_entry(0).unwrap());
+            assert!(index.read_index(&mut first.as_bytes()).is_err());
+            assert_eq!(index, before);
+        }
+
+        fn test_write_index_round_trip() {
+            let index = build_index();
+            let mut first = Vec::new();
+            index.write_index(&mut first).unwrap();
+            assert_eq!(first, std::fs::read(format!("{FQ_PATH}.fai")).unwrap());
+            let mut loaded = FastqIndex::new();
+            loaded.read_index(&mut &first[..]).unwrap();
+            assert_eq!(loaded, index);
+            let mut second = Vec::new();
+            loaded.write_index(&mut second).unwrap();
+            assert_eq!(first, second);
+
+            assert!(loaded.contains("SRR22092847.1.2") && !loaded.contains("nope"));
+            let offsets = loaded.entries().map(|e| *e.offset()).collect::<Vec<u64>>();
+            assert!(offsets.is_sorted());
+        }
+
+        fn test_non_utf8_name() {
+            let fq = b"@r\xe9ad x\nACGT\n+\nIIII\n@b\nGG\n+\nII\n".to_vec();
+            let mut handle = std::io::Cursor::new(fq.clone());
+            let index = FastqIndex::from_fastq_file(&mut handle, Default::default()).unwrap();
+            assert_eq!(index.nth_entry(0).unwrap().name(), b"r\xe9ad");
+            let mut indexed = IndexedFastq::new(std::io::Cursor::new(fq), &index);
+            let rec = indexed.get(b"r\xe9ad").unwrap();
+            assert_eq!((rec.id(), rec.qual()), (&b"r\xe9ad"[..], "IIII"));
+
+            let mut fai = Vec::new();
+            index.write_index(&mut fai).unwrap();
+            assert!(fai.starts_with(b"r\xe9ad\t4\t"));
+        }
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let index = build_index();
+        let mut bin = Vec::new();
+        index.write_binary(&mut bin).unwrap();
+        assert!(bin.starts_with(BINARY_MAGIC));
+
+        let mut loaded = FastqIndex::new();
+        loaded.read_binary(&bin[..]).unwrap();
+        assert_eq!(loaded, index);
+        // read_index sniffs the magic
+        let mut sniffed = FastqIndex::new();
+        sniffed.read_index(&mut &bin[..]).unwrap();
+        assert_eq!(sniffed, index);
+        // also from a handle buffering less than the magic
+        let mut sniffed = FastqIndex::new();
+        let mut handle = std::io::BufReader::with_capacity(3, &bin[..]);
+        sniffed.read_index(&mut handle).unwrap();
+        assert_eq!(sniffed, index);
+
+        let mut text = Vec::new();
+        sniffed.write_index(&mut text).unwrap();
+        assert_eq!(text, std::fs::read(format!("{FQ_PATH}.fai")).unwrap());
+        let mut reread = FastqIndex::new();
+        let mut handle = std::io::BufReader::with_capacity(3, &text[..]);
+        reread.read_index(&mut handle).unwrap();
+        assert_eq!(reread, index);
+
+        let mut empty = Vec::new();
+        FastqIndex::new().write_binary(&mut empty).unwrap();
+        assert_eq!(empty.len(), BINARY_HEADER_LEN);
+        let mut loaded = FastqIndex::new();
+        loaded.read_index(&mut &empty[..]).unwrap();
+        assert!(loaded.is_empty());
+    }
+
+    #[test]
+    fn test_binary_errors() {
+        let mut bin = Vec::new();
+        build_index().write_binary(&mut bin).unwrap();
+        let read = |bytes: &[u8]| FastqIndex::new().read_binary(bytes).unwrap_err();
+
+        let mut bad = bin.clone();
+        bad[0] = b'X';
+        assert!(matches!(read(&bad), BinaryIndexError::BadMagic));
+        let mut bad = bin.clone();
+        bad[8] = 9;
+        assert!(matches!(
+            read(&bad),
+            BinaryIndexError::UnsupportedVersion(9)
+        ));
+        assert!(matches!(
+            read(&bin[..20]),
+            BinaryIndexError::Truncated { section: "header" }
+        ));
+        // the string table comes before the entries
+        let field = |at: usize| u64::from_le_bytes(bin[at..at + 8].try_into().unwrap()) as usize;
+        let entries = BINARY_HEADER_LEN + field(20);
+        assert_eq!(bin.len(), entries + field(12) * BINARY_ENTRY_LEN);
+        assert!(matches!(
+            read(&bin[..entries - 1]),
+            BinaryIndexError::Truncated {
+                section: "string table"
+            }
+        ));
+        assert!(matches!(
+            read(&bin[..bin.len() - 1]),
+            BinaryIndexError::Truncated { section: "entries" }
+        ));
+
+        // the second entry's name offset points past the table
+        let mut bad = bin.clone();
+        let at = entries + BINARY_ENTRY_LEN;
+        bad[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            read(&bad),
+            BinaryIndexError::NameOutOfRange { entry: 1, .. }
+        ));
+
+        // both entries named like the first record
+        let mut bad = bin.clone();
+        let (first, second) = (entries, entries + BINARY_ENTRY_LEN);
+        let name = bad[first..first + 16].to_vec();
+        bad[second..second + 16].copy_from_slice(&name);
+        assert!(matches!(
+            read(&bad),
+            BinaryIndexError::DuplicateName { entry: 1 }
+        ));
+        let mut loaded = build_index();
+        let err = loaded.read_binary(&bin[..]).unwrap_err();
+        assert!(matches!(err, BinaryIndexError::DuplicateName { entry: 0 }));
+        assert_eq!(loaded, build_index());
+
+        // through read_index the typed error is kept as the inner error
+        let err = FastqIndex::new().read_index(&mut &bad[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let inner = err.into_inner().unwrap();
+        assert!(matches!(
+            inner.downcast_ref::<BinaryIndexError>(),
+            Some(BinaryIndexError::DuplicateName { entry: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_stale_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reads.fq");
+        std::fs::copy(FQ_PATH, &path).unwrap();
+        let write_index = |idx: &FastqIndex| {
+            let fai = index_meta::fai_path(&path);
+            idx.write_index(File::create(&fai).unwrap()).unwrap();
+            index_meta::write_meta(&path, &fai).unwrap();
+        };
+        let build = || {
+            FastqIndex::from_fastq_file(
+                &mut BufReader::new(File::open(&path).unwrap()),
+                Default::default(),
+            )
+            .unwrap()
+        };
+        let index = build();
+        write_index(&index);
+        assert!(IndexedFastq::from_path(&path, &index).is_ok());
+
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(b"@new\nACGT\n+\nIIII\n").unwrap();
+        assert!(matches!(
+            index.validate_against(&path),
+            Err(StaleIndex::Changed("size"))
+        ));
+
+        let rebuilt = build();
+        write_index(&rebuilt);
+        let mut fq = IndexedFastq::from_path(&path, &rebuilt).unwrap();
+        assert_eq!(fq.get("new").unwrap().qual(), "IIII");
+    }
+
+    #[test]
+    fn test_truncated_quality() {
+        let fq = std::fs::read(FQ_PATH).unwrap();
+        let records = scan();
+        // cut the fourth record halfway through its quality line
+        let index = build_index();
+        let cut = index.nth_entry(3).unwrap();
+        let end = *cut.q_offset() as usize + records[3].qual().len() / 2;
+        let mut truncated = std::io::Cursor::new(&fq[..end]);
+        let results = FastqIndexer::new(&mut truncated).collect::<Vec<_>>();
+        assert_eq!(results.len(), 4);
+        assert!(results[..3].iter().all(Result::is_ok));
+        let err = results[3].as_ref().unwrap_err();
+        assert!(
+            matches!(err, FastqError::InRecord { name, source }
+                if name.as_bytes() == records[3].id() && matches!(**source, FastqError::EofError)),
+            "{err:?}"
+        );
+        assert_eq!(
+            err.to_string(),
+            format!("record '{}': end of file error", records[3].id_str_lossy())
+        );
+        assert!(err.is_terminal());
+
+        // and one that ends before its '+' line
+        let mut truncated = std::io::Cursor::new(b"@a\nAC\n+\nII\n@b\nACGT\n");
+        let err = FastqIndex::from_fastq_file(&mut truncated, Default::default()).unwrap_err();
+        assert_eq!(err.to_string(), "record 'b': end of file error");
+
+        // a record named "3" is not the third record
+        let at_third = FastqError::AtRecord {
+            record: 3,
+            source: Box::new(FastqError::EofError),
+        };
+        let named = FastqError::in_record(b"3", FastqError::EofError);
+        assert_ne!(at_third.to_string(), named.to_string());
+    }
+
+    #[test]
+    fn test_huge_entries() {
+        let five_gib = 5u64 << 30;
+        let fai = format!("big\t{five_gib}\t5\t70\t71\t{}\n", five_gib + 10);
+        let mut index = FastqIndex::new();
+        index.read_index(&mut fai.as_bytes()).unwrap();
+        let fq = b"@big\nACGT\n+\nIIII\n".to_vec();
+        let mut indexed =
+            IndexedFastq::new(std::io::Cursor::new(&fq), &index).max_record_size(1 << 30);
+        match indexed.get("big") {
+            Err(FetchError::TooLarge(e)) => {
+                assert_eq!((e.id.as_str(), e.limit), ("big", 1 << 30));
+            }
+            other => panic!("expected TooLarge, got {other:?}"),
+        }
+        assert!(matches!(
+            indexed.get_many(&["big"])[0],
+            Err(FetchError::TooLarge(_))
+        ));
+
+        let mut indexed = IndexedFastq::new(std::io::Cursor::new(&fq), &index);
+        let err = indexed.get("big").unwrap_err();
+        if usize::BITS < 64 {
+            assert!(matches!(err, FetchError::TooLarge(_)));
+        } else {
+            assert!(matches!(err, FetchError::IoError(e) if e.kind() == ErrorKind::UnexpectedEof));
+        }
+
+        for line in [
+            "x\t18446744073709551615\t0\t1\t2\t0\n",
+            "x\t10\t0\t10\t11\t18446744073709551610\n",
+            "x\t10\t0\t11\t10\t20\n",
+        ] {
+            let err = FastqIndex::new()
+                .read_index(&mut line.as_bytes())
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let fq = b"@r1\nACGT\n+\nIIII\n@r1\nGG\n+\nII\n";
+        let build =
+            |policy| FastqIndex::from_fastq_file(&mut std::io::Cursor::new(&fq[..]), policy);
+        let err = build(DuplicatePolicy::Error).unwrap_err();
+        assert!(matches!(
+            err,
+            FastqError::DuplicateEntry { ref name, first: 4, second: 20 } if name == "r1"
+        ));
+        assert!(
+            FastqIndex::try_from(FastqIndexer::new(&mut std::io::Cursor::new(&fq[..]))).is_err()
+        );
+
+        let length = |idx: FastqIndex, name: &str| *idx.get(name).unwrap().length();
+        assert_eq!(length(build(DuplicatePolicy::KeepFirst).unwrap(), "r1"), 4);
+        assert_eq!(length(build(DuplicatePolicy::KeepLast).unwrap(), "r1"), 2);
+        let idx = build(DuplicatePolicy::Rename { suffix: ".".into() }).unwrap();
+        assert_eq!(idx.len(), 2);
+        assert_eq!(*idx.get("r1.1").unwrap().offset(), 20);
+    }
+
+    // r1 twice, then the name the second r1 is renamed to
+    const REPEATS: &[u8] =
+        b"@r1\nACGT\n+\nIIII\n@r2 x\nTT\n+\nII\n@r1\nGG\n+\nII\n@r1.1\nC\n+\nI\n";
+
+    #[test]
+    fn test_build_options() {
+        let policies = [
+            DuplicatePolicy::KeepFirst,
+            DuplicatePolicy::KeepLast,
+            DuplicatePolicy::Rename { suffix: ".".into() },
+        ];
+        for policy in policies {
+            let build = |options| {
+                let mut input = std::io::Cursor::new(REPEATS);
+                FastqIndex::from_fastq_file_with(&mut input, policy.clone(), options)
+            };
+            let full = build(IndexBuildOptions::default()).unwrap();
+            assert!(full.has_name_map());
+            for dedup in [DedupMode::Exact, DedupMode::Hashed] {
+                let options = IndexBuildOptions {
+                    dedup,
+                    name_map: false,
+                };
+                let light = build(options).unwrap();
+                assert!(!light.has_name_map());
+                let offsets =
+                    |idx: &FastqIndex| idx.entries().map(|e| e.offset).collect::<Vec<_>>();
+                assert_eq!(offsets(&light), offsets(&full), "{policy:?}, {dedup:?}");
+                let mut fetch = IndexedFastq::new(std::io::Cursor::new(REPEATS), &light);
+                for (n, e) in full.entries().enumerate() {
+                    assert_eq!(fetch.nth_record(n).unwrap().id(), e.name());
+                    match dedup {
+                        DedupMode::Exact => assert_eq!(light.get(e.name()), Some(e)),
+                        // only the names made up by renaming are kept
+                        DedupMode::Hashed => {
+                            let renamed = matches!(policy, DuplicatePolicy::Rename { .. });
+                            assert_eq!(light.get(e.name()).is_some(), renamed && n >= 2);
+                        }
+                    }
+                }
+                assert_eq!(
+                    light.write_index(Vec::new()).is_ok(),
+                    dedup == DedupMode::Exact
+                );
+            }
+        }
+
+        // read into an index without a name map, a repeated name fails as with one
+        let options = IndexBuildOptions {
+            dedup: DedupMode::Exact,
+            name_map: false,
+        };
+        let mut input = std::io::Cursor::new(REPEATS);
+        let mut light =
+            FastqIndex::from_fastq_file_with(&mut input, DuplicatePolicy::KeepFirst, options)
+                .unwrap();
+        let err = light
+            .read_index(&mut "r4\t1\t98\t1\t2\t100\nr2\t1\t99\t1\t2\t101\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "repeated name 'r2' in index");
+        assert_eq!(light.len(), 3);
+        assert_ne!(*light.get("r2").unwrap().offset(), 99);
+
+        let options = IndexBuildOptions {
+            dedup: DedupMode::Hashed,
+            name_map: false,
+        };
+        let mut input = std::io::Cursor::new(REPEATS);
+        let err = FastqIndex::from_fastq_file_with(&mut input, DuplicatePolicy::Error, options);
+        assert!(matches!(
+            err.unwrap_err(),
+            FastqError::DuplicateEntry { ref name, first: 4, second: 34 } if name == "r1"
+        ));
+    }
+
+    #[test]
+    fn test_write_index_streaming() {
+        let write = |policy: &DuplicatePolicy| {
+            let mut out = Vec::new();
+            let mut input = std::io::Cursor::new(REPEATS);
+            FastqIndexer::new(&mut input)
+                .write_index(policy, &mut out)
+                .map(|n| (n, out))
+        };
+        for policy in [
+            DuplicatePolicy::KeepFirst,
+            DuplicatePolicy::Rename { suffix: ".".into() },
+        ] {
+            let mut input = std::io::Cursor::new(REPEATS);
+            let idx = FastqIndex::from_fastq_file(&mut input, policy.clone()).unwrap();
+            let mut expected = Vec::new();
+            idx.write_index(&mut expected).unwrap();
+            assert_eq!(write(&policy).unwrap(), (idx.len(), expected), "{policy:?}");
+        }
+        // the fourth record, named like the rename of the third, is renamed in turn
+        let (_, fai) = write(&DuplicatePolicy::Rename { suffix: ".".into() }).unwrap();
+        let fai = String::from_utf8(fai).unwrap();
+        assert!(fai.contains("\nr1.1\t2\t34\t"));
+        assert!(fai.ends_with("\nr1.1.1\t1\t48\t1\t2\t52\n"));
+
+        let err = write(&DuplicatePolicy::Error).unwrap_err();
+        assert!(matches!(
+            err,
+            FastqError::DuplicateEntry { ref name, first: 4, second: 34 } if name == "r1"
+        ));
+        let err = write(&DuplicatePolicy::KeepLast).unwrap_err();
+        assert!(matches!(err, FastqError::IoError(ref e) if e.kind() == ErrorKind::InvalidInput));
+
+        // the whole test file streams to the index it builds in memory
+        let mut expected = Vec::new();
+        build_index().write_index(&mut expected).unwrap();
+        let mut out = Vec::new();
+        let mut input = BufReader::new(File::open(FQ_PATH).unwrap());
+        FastqIndexer::new(&mut input)
+            .write_index(&DuplicatePolicy::Error, &mut out)
+            .unwrap();
+        assert_eq!(out, expected);
+    }
 }
@@ -1,57 +1,667 @@
+//! Reading, indexing and summarizing FASTQ files
+//!
+//! `reader::FastqReader` streams `Record`s from any `BufRead`. Records failing
+//! with a non-terminal error (see `FastqError::is_terminal`) can be skipped.
+//!
+//! ```
+//! use lyso_fastq::reader::FastqReader;
+//!
+//! let input = b"@r1 lane=1\nACGT\n+\nIIII\n@r2\nGG\n+\n##\n";
+//! let mut reader = FastqReader::new(&input[..]);
+//! let rec = reader.next().unwrap().unwrap();
+//! assert_eq!((rec.id(), rec.desc()), (&b"r1"[..], Some(&b"lane=1"[..])));
+//! assert_eq!(reader.next().unwrap().unwrap().qual(), "##");
+//! assert!(reader.next().is_none());
+//! ```
+
+#![deny(missing_docs)]
+
+use lyso_common::codec::PhredEncoding;
+use lyso_common::detect::{Compression, Format, Mismatch};
+use lyso_common::diff::{CanonicalHash, FieldDiff, RecordDiff};
+use lyso_common::error::{LineLocation, LysoError};
+use lyso_common::filter::SeqRecord;
+use lyso_common::format::{gc_content, mean_qual, write_opt, RecordFormatter};
+use lyso_common::io::RecordTooLarge;
+use lyso_common::rename::RecordId;
+use lyso_common::seqedit::SeqEdit;
+use lyso_common::sort::SortRecord;
+use lyso_common::synth::SimRecord;
+use lyso_common::validate::{
+    Alphabet, IdCharset, QualRange, SeqQualLength, Severity, Validator, Violation,
+};
+use std::borrow::Cow;
 use std::fmt::Display;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
 use std::str::Utf8Error;
 use thiserror::Error;
 
+pub mod barcode;
+pub mod count;
+pub mod demux;
+pub mod index;
+pub mod merge;
+pub mod parallel;
 pub(crate) mod parser;
 pub mod reader;
-// pub mod indexer;
+pub mod requal;
+pub mod stats;
 
+/// Errors from reading FASTQ
+///
+/// Errors from `reader::FastqReader` and the indexers come wrapped in
+/// `AtLine`, `AtRecord` or `InRecord` for where they were found, so matching a
+/// reader's error against a plain variant such as `EofError` no longer works;
+/// match on `root()` instead.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum FastqError {
-    #[error("fastq validation error")]
-    ValidationError(&'static str),
+    /// A record that failed a check, such as a non-UTF-8 id
+    #[error("invalid record {record}: {reason}")]
+    ValidationError {
+        /// Header of the record
+        record: String,
+        /// What was wrong with it
+        reason: &'static str,
+    },
+    /// Input ended inside a record
     #[error("end of file error")]
     EofError,
+    /// A header line that does not start with '@'
     #[error("missing id error")]
     MissingId,
+    /// A header with no name after the '@'
     #[error("truncated id error")]
     TruncatedId,
+    /// Sequence and quality of different lengths
     #[error("sequence-quality length mismatch")]
     SeqQualMismatch,
+    /// An index that does not match its FASTQ
     #[error("index mismatch error")]
     IndexMismatch,
+    /// Reading failed
     #[error("io error")]
     IoError(#[from] std::io::Error),
+    /// A sequence or quality string that is not UTF-8
     #[error("file encoding error")]
     EncodeError(#[from] Utf8Error),
+    /// Input that could not be parsed
     #[error("Error parsing fastq record")]
     ParseError,
+    /// A character that is not a quality score
+    #[error("invalid quality character {0:?}")]
+    InvalidQuality(char),
+    /// A '+' line naming a different record
+    #[error("record {record}: '+' line does not repeat the header: {separator}")]
+    SeparatorMismatch {
+        /// Header of the record
+        record: String,
+        /// The '+' line, without the '+'
+        separator: String,
+    },
+    /// A quality character outside the given encoding
+    #[error("quality character {found:?} cannot be {encoding}")]
+    QualityEncoding {
+        /// The character
+        found: char,
+        /// The encoding it was read as
+        encoding: PhredEncoding,
+    },
+    /// `PhredEncoding::Unknown` passed to a conversion
+    #[error("cannot convert from or to an unknown quality encoding")]
+    UnknownEncoding,
+    /// Compressed input, which readers do not decompress
+    #[error("input appears to be {0}-compressed; decompress it before reading")]
+    Compressed(Compression),
+    /// Input in another format
+    #[error("input looks like {0}, not FASTQ")]
+    WrongFormat(Format),
+    /// Input that is mostly not printable text
+    #[error("input does not appear to be text")]
+    NotText,
+    /// Two records of the same name, indexed under `DuplicatePolicy::Error`
+    #[error("duplicate record name {name} at offsets {first} and {second}")]
+    DuplicateEntry {
+        /// The repeated name
+        name: String,
+        /// Offset of the earlier record's sequence
+        first: u64,
+        /// Offset of the later record's sequence
+        second: u64,
+    },
+    /// A record longer than `ReaderConfig::max_record_size`
+    #[error("record longer than {limit} bytes")]
+    RecordTooLarge {
+        /// The limit, in bytes
+        limit: usize,
+    },
+    /// An error in the `record`th record (1-based) of a stream
+    #[error("record {record}: {source}")]
+    AtRecord {
+        /// 1-based record number
+        record: u64,
+        /// The error in that record
+        source: Box<FastqError>,
+    },
+    /// An error in the record called `name`, found while indexing
+    ///
+    /// The name is quoted, so record "3" does not read as the third record of
+    /// `AtRecord`.
+    #[error("record '{name}': {source}")]
+    InRecord {
+        /// Name of the record
+        name: String,
+        /// The error in that record
+        source: Box<FastqError>,
+    },
+    /// A record failing an error-level rule of the reader's `Validator`
+    #[error("record {} fails {}: {0}", .0.record, .0.rule)]
+    RuleViolation(Violation),
+    /// A parse or validation error at a line of the input, see `FastqReader`
+    #[error("{}: {source}", LineLocation::new(.path.as_deref(), *.line))]
+    AtLine {
+        /// The file, for a reader opened with `from_path`
+        path: Option<PathBuf>,
+        /// 1-based line number
+        line: u64,
+        /// The error on that line
+        source: Box<FastqError>,
+    },
+}
+
+impl FastqError {
+    /// `err`, in the record called `name`
+    pub fn in_record(name: &[u8], err: FastqError) -> Self {
+        FastqError::InRecord {
+            name: String::from_utf8_lossy(name).into_owned(),
+            source: Box::new(err),
+        }
+    }
+
+    /// The error without the record or line it was found at
+    pub fn root(&self) -> &FastqError {
+        match self {
+            FastqError::AtRecord { source, .. }
+            | FastqError::InRecord { source, .. }
+            | FastqError::AtLine { source, .. } => source.root(),
+            e => e,
+        }
+    }
+
+    /// The 1-based line of the input the error was found at, when known
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            FastqError::AtLine { line, .. } => Some(*line),
+            FastqError::AtRecord { source, .. } | FastqError::InRecord { source, .. } => {
+                source.line()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the reader can continue after this error
+    ///
+    /// Record-local errors (validation failures, mismatched sequence and quality
+    /// lengths, a separator not matching the header) leave the reader positioned
+    /// at the next record. Everything else, including IO errors, a truncated final
+    /// record and unparseable input, ends iteration.
+    pub fn is_terminal(&self) -> bool {
+        !matches!(
+            self.root(),
+            FastqError::ValidationError { .. }
+                | FastqError::SeqQualMismatch
+                | FastqError::InvalidQuality(_)
+                | FastqError::SeparatorMismatch { .. }
+                | FastqError::QualityEncoding { .. }
+                | FastqError::RuleViolation(_)
+        )
+    }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// Errors from fetching records through an index
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FetchError {
+    /// No record has this id
+    #[error("id not found: {0}")]
+    NotFound(String),
+    /// Reading failed
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+    /// A sequence or quality string that is not UTF-8
+    #[error("File encoding error")]
+    EncodeError(#[from] std::string::FromUtf8Error),
+    /// A record spanning more bytes than a fetch may buffer
+    #[error(transparent)]
+    TooLarge(#[from] RecordTooLarge),
+}
+
+impl From<Mismatch> for FastqError {
+    fn from(value: Mismatch) -> Self {
+        match value {
+            Mismatch::Compressed(c) => FastqError::Compressed(c),
+            Mismatch::Format(f) => FastqError::WrongFormat(f),
+            Mismatch::Binary => FastqError::NotText,
+        }
+    }
+}
+
+impl From<FastqError> for LysoError {
+    fn from(value: FastqError) -> Self {
+        LysoError::format(Format::Fastq, value)
+    }
+}
+
+/// A FASTQ record
+///
+/// The id and description are kept as the bytes found in the file, so headers that
+/// are not UTF-8 survive a read and `write_to` unchanged. `Display` renders them lossily.
+///
+/// The description is everything after the first space of the header. It is None
+/// when the header has no space, and empty when the header ends in one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct Record {
-    id: String,
-    desc: String,
+    id: Vec<u8>,
+    desc: Option<Vec<u8>>,
     seq: String,
     qual: String,
 }
 
 impl Record {
+    /// An empty record
+    #[must_use]
     pub fn new() -> Self {
         Record {
-            id: String::from(""),
-            desc: String::from(""),
+            id: Vec::new(),
+            desc: None,
             seq: String::from(""),
             qual: String::from(""),
         }
     }
+
+    /// A record from its fields, without a description
+    ///
+    /// Fails with `SeqQualMismatch` unless `seq` and `qual` are the same length.
+    ///
+    /// ```
+    /// use lyso_fastq::{FastqError, Record};
+    ///
+    /// let rec = Record::from_parts("r1", "ACGT", "IIII").unwrap();
+    /// assert_eq!(rec.to_string(), "@r1\nACGT\n+\nIIII\n");
+    /// assert!(matches!(
+    ///     Record::from_parts("r2", "ACGT", "II"),
+    ///     Err(FastqError::SeqQualMismatch)
+    /// ));
+    /// ```
+    pub fn from_parts(
+        id: impl Into<Vec<u8>>,
+        seq: impl Into<String>,
+        qual: impl Into<String>,
+    ) -> Result<Self, FastqError> {
+        let (seq, qual) = (seq.into(), qual.into());
+        if seq.len() != qual.len() {
+            return Err(FastqError::SeqQualMismatch);
+        }
+        Ok(Record {
+            id: id.into(),
+            desc: None,
+            seq,
+            qual,
+        })
+    }
+
+    /// The id: the header up to its first space, without the '@'
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// Replace the id, leaving the description alone
+    pub fn set_id(&mut self, id: impl Into<Vec<u8>>) {
+        self.id = id.into();
+    }
+
+    /// The id as text, with invalid UTF-8 replaced by U+FFFD
+    pub fn id_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.id)
+    }
+
+    /// Everything after the first space of the header, None when it has none
+    pub fn desc(&self) -> Option<&[u8]> {
+        self.desc.as_deref()
+    }
+
+    /// The description as text, with invalid UTF-8 replaced by U+FFFD
+    pub fn desc_str_lossy(&self) -> Option<Cow<'_, str>> {
+        self.desc.as_deref().map(String::from_utf8_lossy)
+    }
+
+    /// Replace the description
+    pub fn set_desc(&mut self, desc: Option<Vec<u8>>) {
+        self.desc = desc;
+    }
+
+    /// The bases, one line of the record
+    pub fn seq(&self) -> &str {
+        self.seq.as_ref()
+    }
+
+    /// The quality string, one Phred+33 or Phred+64 character per base
+    ///
+    /// Empty for records read by `FastqReader::without_quality`.
+    pub fn qual(&self) -> &str {
+        self.qual.as_ref()
+    }
+
+    /// Empty every field, keeping the allocations for reuse except the description's
+    pub fn clear(&mut self) {
+        self.id.clear();
+        self.desc = None;
+        self.seq.clear();
+        self.qual.clear();
+    }
+
+    /// A record holding exactly the parsed fields, without spare capacity
+    pub(crate) fn from_raw(raw: parser::RawRecord<'_>) -> Self {
+        let mut rec = Record {
+            id: Vec::with_capacity(raw.id.len()),
+            desc: None,
+            seq: String::with_capacity(raw.seq.len()),
+            qual: String::with_capacity(raw.qual.len()),
+        };
+        rec.set_from_raw(raw);
+        rec
+    }
+
+    /// Overwrite every field with the parsed ones, reusing existing capacity
+    pub(crate) fn set_from_raw(&mut self, raw: parser::RawRecord<'_>) {
+        let desc = self.desc.take();
+        self.clear();
+        self.id.extend_from_slice(raw.id);
+        self.desc = raw.desc.map(|d| {
+            let mut buf = desc.unwrap_or_default();
+            buf.clear();
+            buf.extend_from_slice(d);
+            buf
+        });
+        self.seq.push_str(raw.seq);
+        self.qual.push_str(raw.qual);
+    }
+
+    /// Fail with a `ValidationError` naming the record if the id or description
+    /// is not UTF-8
+    pub fn check_utf8(&self) -> Result<(), FastqError> {
+        let reason = if std::str::from_utf8(&self.id).is_err() {
+            "id is not valid UTF-8"
+        } else if self.desc().is_some_and(|d| std::str::from_utf8(d).is_err()) {
+            "description is not valid UTF-8"
+        } else {
+            return Ok(());
+        };
+        Err(FastqError::ValidationError {
+            record: self.id_str_lossy().into_owned(),
+            reason,
+        })
+    }
+
+    /// Write the record with its header bytes unchanged
+    pub fn write_to(&self, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(b"@")?;
+        out.write_all(&self.id)?;
+        if let Some(desc) = &self.desc {
+            out.write_all(b" ")?;
+            out.write_all(desc)?;
+        }
+        out.write_all(b"\n")?;
+        out.write_all(self.seq.as_bytes())?;
+        out.write_all(b"\n+\n")?;
+        out.write_all(self.qual.as_bytes())?;
+        out.write_all(b"\n")
+    }
 }
 
 impl Display for Record {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "@{} {}\n", self.id, self.desc)?;
-        write!(f, "{}\n", self.seq)?;
-        write!(f, "+\n")?;
-        write!(f, "{}\n", self.qual)
+        write!(f, "@{}", self.id_str_lossy())?;
+        match self.desc_str_lossy() {
+            Some(desc) => writeln!(f, " {desc}")?,
+            None => writeln!(f)?,
+        }
+        writeln!(f, "{}", self.seq)?;
+        writeln!(f, "+")?;
+        writeln!(f, "{}", self.qual)
+    }
+}
+
+impl RecordId for Record {
+    fn record_id(&self) -> &[u8] {
+        &self.id
+    }
+
+    fn set_record_id(&mut self, id: Vec<u8>) {
+        self.id = id;
+    }
+}
+
+impl SeqRecord for Record {
+    fn seq_bytes(&self) -> &[u8] {
+        self.seq.as_bytes()
+    }
+
+    /// `None` for a record read `without_quality`, which has bases but no
+    /// qualities
+    fn qual_bytes(&self) -> Option<&[u8]> {
+        (!self.qual.is_empty() || self.seq.is_empty()).then_some(self.qual.as_bytes())
+    }
+}
+
+impl SeqEdit for Record {
+    fn seq_string_mut(&mut self) -> &mut String {
+        &mut self.seq
+    }
+}
+
+/// The rules `lyso check` applies to FASTQ, all at error level
+///
+/// '=' is BAM's "same as the reference" base and never a FASTQ base; sequences
+/// taken from BAM records must be resolved against the reference first.
+pub fn validator() -> Validator<Record> {
+    Validator::new()
+        .rule(Alphabet::NUCLEOTIDE, Severity::Error)
+        .rule(SeqQualLength, Severity::Error)
+        .rule(QualRange, Severity::Error)
+        .rule(IdCharset::NAME, Severity::Error)
+}
+
+impl SortRecord for Record {
+    type Error = FastqError;
+
+    fn write_record(&self, mut out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_to(&mut out)
+    }
+
+    fn read_run(input: BufReader<File>) -> Box<dyn Iterator<Item = Result<Self, Self::Error>>> {
+        Box::new(reader::FastqReader::new(input))
+    }
+}
+
+impl RecordFormatter for Record {
+    const COLUMNS: &'static [&'static str] = &["id", "length", "gc", "mean_qual"];
+
+    fn write_tsv(&self, out: &mut impl std::fmt::Write) -> std::fmt::Result {
+        write!(out, "{}\t{}\t", self.id_str_lossy(), self.seq.len())?;
+        write_opt(out, gc_content(self.seq.as_bytes()), 4)?;
+        out.write_char('\t')?;
+        write_opt(out, mean_qual(self.qual.as_bytes()), 1)
+    }
+}
+
+impl RecordDiff for Record {
+    fn diff(&self, other: &Self) -> Vec<FieldDiff> {
+        [
+            FieldDiff::bytes("id", &self.id, &other.id),
+            match (self.desc(), other.desc()) {
+                (Some(left), Some(right)) => FieldDiff::bytes("desc", left, right),
+                (None, None) => None,
+                (left, right) => {
+                    let show = |d: Option<&[u8]>| format!("{:?}", d.map(String::from_utf8_lossy));
+                    Some(FieldDiff::new("desc", show(left), show(right)))
+                }
+            },
+            FieldDiff::bytes("seq", self.seq.as_bytes(), other.seq.as_bytes()),
+            FieldDiff::bytes("qual", self.qual.as_bytes(), other.qual.as_bytes()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn label(&self) -> String {
+        String::from_utf8_lossy(&self.id).into_owned()
+    }
+}
+
+/// The derived hash, which covers the same fields as equality
+impl CanonicalHash for Record {
+    fn canonical_hash(&self) -> u64 {
+        let mut h = DefaultHasher::new();
+        self.hash(&mut h);
+        h.finish()
+    }
+}
+
+impl From<SimRecord> for Record {
+    /// A read without qualities, from `FastaSimulator`, gets '!' at every base
+    fn from(sim: SimRecord) -> Self {
+        let qual = sim.qual.unwrap_or_else(|| "!".repeat(sim.seq.len()));
+        Record {
+            id: sim.name.into_bytes(),
+            desc: sim.desc.map(String::into_bytes),
+            seq: sim.seq,
+            qual,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::harness::for_each_parser;
+
+    for_each_parser! {
+        fn test_diff() {
+            let a = Record {
+                id: b"r1".to_vec(),
+                desc: Some(b"1 length=4".to_vec()),
+                seq: "ACGT".to_string(),
+                qual: "FFFF".to_string(),
+            };
+            assert!(a.diff(&a.clone()).is_empty());
+
+            let mut b = a.clone();
+            b.qual = "FF#F".to_string();
+            b.set_desc(Some(Vec::new()));
+            let diffs = a.diff(&b);
+            assert_eq!(
+                diffs.iter().map(|d| (d.field, d.index)).collect::<Vec<_>>(),
+                vec![("desc", Some(0)), ("qual", Some(2))]
+            );
+
+            let (mut c, mut d) = (a.clone(), a.clone());
+            c.set_desc(None);
+            d.set_desc(Some(Vec::new()));
+            assert_ne!(c, d);
+            let diffs = c.diff(&d);
+            assert_eq!(diffs.len(), 1);
+            assert_eq!(diffs[0].to_string(), r#"desc differs: "None" != "Some(\"\")""#);
+        }
+
+        fn test_header_round_trip() {
+            for (input, desc) in [
+                (&b"@r1\nACGT\n+\nFFFF\n"[..], None),
+                (b"@r1 \nACGT\n+\nFFFF\n", Some(&b""[..])),
+                (b"@r1 d  e \nACGT\n+\nFFFF\n", Some(b"d  e ")),
+                (b"@r\xe9 \xe9\nACGT\n+\nFFFF\n", Some(b"\xe9")),
+            ] {
+                let rec = reader::FastqReader::new(input).next().unwrap().unwrap();
+                assert_eq!(rec.desc(), desc, "{input:?}");
+                let mut out = Vec::new();
+                rec.write_to(&mut out).unwrap();
+                assert_eq!(out, input);
+                if rec.check_utf8().is_ok() {
+                    assert_eq!(rec.to_string().as_bytes(), input);
+                }
+            }
+        }
+
+        fn test_simulated_round_trip() {
+            use lyso_common::synth::{FastaSimulator, FastqSimulator, QualityProfile};
+            let reads = FastqSimulator::new(1)
+                .read_length(30)
+                .quality_profile(QualityProfile::Decreasing { start: 40, end: 2 })
+                .generate(20)
+                .map(Record::from)
+                .collect::<Vec<_>>();
+            let mut sim = FastaSimulator::new(1).seq_length(100).read_length(30);
+            let reference = sim.generate(1);
+            let sampled = sim.sample_reads(&reference, 5);
+            assert_eq!(Record::from(sampled[0].clone()).qual(), "!".repeat(30));
+            let all = reads
+                .into_iter()
+                .chain(sampled.into_iter().map(Record::from))
+                .collect::<Vec<_>>();
+
+            let mut out = Vec::new();
+            for rec in &all {
+                rec.write_to(&mut out).unwrap();
+            }
+            let parsed = reader::FastqReader::new(&out[..])
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(parsed, all);
+            let validator = validator();
+            assert!(parsed.iter().all(|r| validator.check(r).is_empty()));
+            assert!(parsed[20].desc_str_lossy().unwrap().starts_with("seq0:"));
+        }
+
+        fn test_validate() {
+            let read = |input: &[u8]| reader::FastqReader::new(input).next().unwrap().unwrap();
+            let broken = |input: &[u8]| {
+                validator()
+                    .check(&read(input))
+                    .into_iter()
+                    .map(|v| format!("{}: {v}", v.rule))
+                    .collect::<Vec<_>>()
+            };
+            assert!(broken(b"@r1\nACGTN\n+\nFFFF#\n").is_empty());
+            assert_eq!(broken(b"@r1\nAC-T\n+\nFFFF\n"), ["alphabet: base '-' is not allowed at base 3"]);
+            assert_eq!(broken(b"@r1\nAC=T\n+\nFF F\n"), [
+                "alphabet: base '=' is not allowed at base 3",
+                "qual_range: quality ' ' is outside Phred+33 at base 3"
+            ]);
+        }
+
+        fn test_tsv() {
+            assert_eq!(Record::tsv_header(), "id\tlength\tgc\tmean_qual");
+            let f = std::fs::File::open("../resources/test_data/test.fastq").unwrap();
+            let rows = crate::reader::FastqReader::new(std::io::BufReader::new(f))
+                .take(3)
+                .map(|r| r.unwrap().to_tsv())
+                .collect::<Vec<String>>();
+            assert_eq!(
+                rows,
+                vec![
+                    "SRR22092847.1.1\t37\t0.4054\t36.1",
+                    "SRR22092847.1.2\t37\t0.4324\t37.0",
+                    "SRR22092847.2.1\t251\t0.3785\t36.0",
+                ]
+            );
+            assert_eq!(
+                rows[0].split('\t').count(),
+                <Record as RecordFormatter>::COLUMNS.len()
+            );
+        }
     }
 }
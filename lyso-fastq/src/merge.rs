@@ -0,0 +1,341 @@
+//! Merging overlapping read pairs into single reads
+//!
+//! When the fragment is shorter than the two reads together, the 3' end of R1
+//! and the reverse complement of R2 cover the same bases. `find_overlap` tries
+//! every placement of reverse-complemented R2 against R1 that overlaps by at
+//! least `min_overlap` bases, including placements where R2 starts before R1
+//! does, which happens when the fragment is shorter than a read and both read
+//! into adapter. A placement scores one per matching base and minus one per
+//! mismatch, with N matching nothing and costing nothing; the best-scoring one
+//! whose mismatch rate is within `max_mismatch_rate` wins, the longer overlap on
+//! a tie.
+//!
+//! `merge_pair` then writes the fragment: R1's bases up to the overlap, a
+//! consensus over it, and reverse-complemented R2's bases after it. Bases past
+//! either end of the fragment are adapter and are trimmed. In the overlap, bases
+//! that agree get the sum of their scores, capped at `max_qual`; bases that
+//! disagree give the one with the higher score (R1's on a tie) and the
+//! difference of the scores; an N gives way to the other read's base and score,
+//! and two Ns keep the lower score. Qualities are Phred+33. A pair with an empty
+//! read is never merged.
+//!
+//! ```
+//! use lyso_fastq::merge::{merge_pair, MergeOptions, MergeResult};
+//! use lyso_fastq::Record;
+//!
+//! let r1 = Record::from_parts("r", "ACGTACGTTTGCA", "IIIIIIIIIIIII").unwrap();
+//! let r2 = Record::from_parts("r", "CCATGCAAACG", "IIIIIIIIIII").unwrap();
+//! let opts = MergeOptions { min_overlap: 6, ..MergeOptions::default() };
+//! let MergeResult::Merged(merged) = merge_pair(r1, r2, &opts) else { panic!() };
+//! assert_eq!(merged.record.seq(), "ACGTACGTTTGCATGG");
+//! assert_eq!(merged.overlap.len, 8);
+//! ```
+
+use lyso_common::search::reverse_complement;
+
+use crate::Record;
+
+/// Settings for `find_overlap` and `merge_pair`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MergeOptions {
+    /// Fewest overlapping bases to merge on
+    pub min_overlap: usize,
+    /// Most mismatches allowed per overlapping base
+    pub max_mismatch_rate: f64,
+    /// Highest score given to a base where the reads agree
+    pub max_qual: u8,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            min_overlap: 10,
+            max_mismatch_rate: 0.1,
+            max_qual: 41,
+        }
+    }
+}
+
+/// Where reverse-complemented R2 lies on R1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlap {
+    /// Position on R1 of the first base of reverse-complemented R2, negative
+    /// when R2 starts before R1
+    pub offset: isize,
+    /// Overlapping bases
+    pub len: usize,
+    /// Overlapping bases that disagree, not counting Ns
+    pub mismatches: usize,
+}
+
+/// A merged pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merged {
+    /// The merged read, with R1's id and description
+    pub record: Record,
+    /// The overlap it was merged on
+    pub overlap: Overlap,
+    /// Whether the fragment is shorter than a read, so that adapter was trimmed
+    pub adapter_trimmed: bool,
+}
+
+/// What `merge_pair` made of a pair
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeResult {
+    /// The pair overlapped
+    Merged(Merged),
+    /// No overlap was found; the reads are given back unchanged
+    Unmerged(Record, Record),
+}
+
+/// The best overlap of `seq1` with `rc2`, the reverse complement of R2
+pub fn find_overlap(seq1: &[u8], rc2: &[u8], opts: &MergeOptions) -> Option<Overlap> {
+    let (len1, len2) = (seq1.len() as isize, rc2.len() as isize);
+    let min = opts.min_overlap.max(1) as isize;
+    let mut best: Option<(isize, Overlap)> = None;
+    for offset in (min - len2)..=(len1 - min) {
+        let (start, end) = (offset.max(0), (offset + len2).min(len1));
+        // a read shorter than min_overlap leaves shorter placements in the range
+        if end - start < min {
+            continue;
+        }
+        let (mut matches, mut mismatches) = (0, 0);
+        for p in start..end {
+            let (a, b) = (seq1[p as usize], rc2[(p - offset) as usize]);
+            let (a, b) = (a.to_ascii_uppercase(), b.to_ascii_uppercase());
+            if a == b'N' || b == b'N' {
+                continue;
+            }
+            if a == b {
+                matches += 1;
+            } else {
+                mismatches += 1;
+            }
+        }
+        let len = (end - start) as usize;
+        if mismatches as f64 > opts.max_mismatch_rate * len as f64 {
+            continue;
+        }
+        let score = matches - mismatches;
+        let overlap = Overlap {
+            offset,
+            len,
+            mismatches: mismatches as usize,
+        };
+        if best.is_none_or(|(s, o)| (score, len) > (s, o.len)) {
+            best = Some((score, overlap));
+        }
+    }
+    best.map(|(_, overlap)| overlap)
+}
+
+/// Merge `r1` and `r2` if they overlap, see the module documentation
+pub fn merge_pair(r1: Record, r2: Record, opts: &MergeOptions) -> MergeResult {
+    if r1.seq.is_empty() || r2.seq.is_empty() {
+        return MergeResult::Unmerged(r1, r2);
+    }
+    let rc2 = reverse_complement(r2.seq.as_bytes());
+    let Some(overlap) = find_overlap(r1.seq.as_bytes(), &rc2, opts) else {
+        return MergeResult::Unmerged(r1, r2);
+    };
+    let rq2 = r2.qual.bytes().rev().collect::<Vec<_>>();
+    let (seq1, q1) = (r1.seq.as_bytes(), r1.qual.as_bytes());
+    let (len1, end) = (seq1.len() as isize, overlap.offset + rc2.len() as isize);
+
+    let mut seq = String::with_capacity(end as usize);
+    let mut qual = String::with_capacity(end as usize);
+    for p in 0..end {
+        let first = (p < len1).then(|| (seq1[p as usize], q1[p as usize]));
+        let second = (p >= overlap.offset).then(|| {
+            let i = (p - overlap.offset) as usize;
+            (rc2[i], rq2[i])
+        });
+        let (base, q) = match (first, second) {
+            (Some(a), Some(b)) => consensus(a, b, opts.max_qual),
+            (Some(only), None) | (None, Some(only)) => only,
+            (None, None) => unreachable!("the fragment is covered by one read or both"),
+        };
+        seq.push(char::from(base));
+        qual.push(char::from(q));
+    }
+    let adapter_trimmed = overlap.offset < 0 || end < len1;
+    MergeResult::Merged(Merged {
+        record: Record {
+            id: r1.id,
+            desc: r1.desc,
+            seq,
+            qual,
+        },
+        overlap,
+        adapter_trimmed,
+    })
+}
+
+/// The base and quality character for two reads' calls at one position
+fn consensus((b1, q1): (u8, u8), (b2, q2): (u8, u8), max_qual: u8) -> (u8, u8) {
+    let (s1, s2) = (q1.saturating_sub(33), q2.saturating_sub(33));
+    let (n1, n2) = (
+        b1.eq_ignore_ascii_case(&b'N'),
+        b2.eq_ignore_ascii_case(&b'N'),
+    );
+    let (base, score) = if n1 && n2 {
+        (b1, s1.min(s2))
+    } else if n1 != n2 {
+        if n1 {
+            (b2, s2)
+        } else {
+            (b1, s1)
+        }
+    } else if b1.eq_ignore_ascii_case(&b2) {
+        (b1, s1.saturating_add(s2).min(max_qual))
+    } else if s2 > s1 {
+        (b2, s2 - s1)
+    } else {
+        (b1, s1 - s2)
+    };
+    (base, score.min(b'~' - 33) + 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTS: MergeOptions = MergeOptions {
+        min_overlap: 6,
+        max_mismatch_rate: 0.1,
+        max_qual: 41,
+    };
+
+    fn read(seq: &str, qual: &str) -> Record {
+        Record::from_parts("r", seq, qual).unwrap()
+    }
+
+    fn rc(seq: &str) -> String {
+        String::from_utf8(reverse_complement(seq.as_bytes())).unwrap()
+    }
+
+    fn merged(result: MergeResult) -> Merged {
+        match result {
+            MergeResult::Merged(m) => m,
+            MergeResult::Unmerged(..) => panic!("pair not merged"),
+        }
+    }
+
+    // a 20 base fragment read 14 bases from each end
+    const FRAGMENT: &str = "ACGTTGCAAGGCTTAGCCAT";
+
+    #[test]
+    fn test_perfect_overlap() {
+        let r1 = read(&FRAGMENT[..14], "55555555555555");
+        let r2 = read(&rc(&FRAGMENT[6..]), "IIIIIIIIIIIIII");
+        let m = merged(merge_pair(r1, r2, &OPTS));
+        assert_eq!(m.record.seq(), FRAGMENT);
+        assert_eq!(
+            m.overlap,
+            Overlap {
+                offset: 6,
+                len: 8,
+                mismatches: 0
+            }
+        );
+        assert!(!m.adapter_trimmed);
+        // 20 + 40 capped at 41 where both reads cover the base
+        assert_eq!(m.record.qual(), "555555JJJJJJJJIIIIII");
+        assert_eq!(m.record.id(), b"r");
+    }
+
+    #[test]
+    fn test_disagreement() {
+        let mut seq2 = rc(&FRAGMENT[6..]).into_bytes();
+        // position 10 of the fragment, 9 from the end of R2
+        seq2[9] = b'T';
+        let r1 = read(&FRAGMENT[..14], "5555555555#555");
+        let r2 = read(std::str::from_utf8(&seq2).unwrap(), "IIIIIIIIIIIIII");
+        let lenient = MergeOptions {
+            max_mismatch_rate: 0.2,
+            ..OPTS
+        };
+        let m = merged(merge_pair(r1.clone(), r2.clone(), &lenient));
+        assert_eq!(m.overlap.mismatches, 1);
+        // R2's A (T on its strand) at 40 beats R1's G at 2, leaving 38
+        assert_eq!(&m.record.seq()[10..11], "A");
+        assert_eq!(&m.record.qual()[10..11], "G");
+
+        // one mismatch in 8 is too many at a rate of 0.1
+        assert!(matches!(
+            merge_pair(r1, r2, &OPTS),
+            MergeResult::Unmerged(..)
+        ));
+    }
+
+    #[test]
+    fn test_no_overlap() {
+        let r1 = read("ACGTACGTAC", "IIIIIIIIII");
+        let r2 = read("GGGGGGGGGG", "IIIIIIIIII");
+        match merge_pair(r1.clone(), r2.clone(), &OPTS) {
+            MergeResult::Unmerged(a, b) => assert_eq!((a, b), (r1, r2)),
+            MergeResult::Merged(m) => panic!("merged as {}", m.record.seq()),
+        }
+        // too short to overlap by min_overlap
+        assert_eq!(find_overlap(b"ACG", b"ACG", &OPTS), None);
+    }
+
+    #[test]
+    fn test_short_reads() {
+        let r1 = read(FRAGMENT, &"I".repeat(20));
+        let opts = MergeOptions::default();
+        // an empty R2 overlaps nothing, and R1 is left whole
+        let empty = read("", "");
+        match merge_pair(r1.clone(), empty.clone(), &opts) {
+            MergeResult::Unmerged(a, b) => assert_eq!((a, b), (r1.clone(), empty.clone())),
+            MergeResult::Merged(m) => panic!("merged as {}", m.record.seq()),
+        }
+        assert!(matches!(
+            merge_pair(empty, r1.clone(), &opts),
+            MergeResult::Unmerged(..)
+        ));
+
+        // 4 bases matching R1's tail are fewer than min_overlap, not adapter
+        let r2 = read(&rc(&FRAGMENT[10..14]), "IIII");
+        assert_eq!(
+            find_overlap(r1.seq.as_bytes(), &FRAGMENT.as_bytes()[10..14], &opts),
+            None
+        );
+        assert!(matches!(
+            merge_pair(r1, r2, &opts),
+            MergeResult::Unmerged(..)
+        ));
+    }
+
+    #[test]
+    fn test_read_through() {
+        // a 12 base insert read 16 bases into the adapters
+        let insert = "GATTACAGGCTA";
+        let r1 = read(&format!("{insert}AGAT"), "IIIIIIIIIIIIIIII");
+        let r2 = read(&format!("{}CTGT", rc(insert)), "IIIIIIIIIIIIIIII");
+        let m = merged(merge_pair(r1, r2, &OPTS));
+        assert_eq!(m.record.seq(), insert);
+        assert_eq!(m.overlap.offset, -4);
+        assert_eq!(m.overlap.len, 12);
+        assert!(m.adapter_trimmed);
+        assert_eq!(m.record.qual(), "J".repeat(12));
+
+        // R2 contained in R1, with R1 reading past its start
+        let r1 = read(&format!("{insert}AGAT"), "IIIIIIIIIIIIIIII");
+        let r2 = read(&rc(insert), "IIIIIIIIIIII");
+        let m = merged(merge_pair(r1, r2, &OPTS));
+        assert_eq!((m.record.seq(), m.overlap.offset), (insert, 0));
+        assert!(m.adapter_trimmed);
+    }
+
+    #[test]
+    fn test_consensus() {
+        assert_eq!(consensus((b'A', b'I'), (b'a', b'5'), 41), (b'A', b'J'));
+        assert_eq!(consensus((b'N', b'#'), (b'C', b'5'), 41), (b'C', b'5'));
+        assert_eq!(consensus((b'G', b'5'), (b'N', b'I'), 41), (b'G', b'5'));
+        assert_eq!(consensus((b'G', b'5'), (b'T', b'5'), 41), (b'G', b'!'));
+        assert_eq!(consensus((b'A', b'~'), (b'A', b'~'), 93), (b'A', b'~'));
+        // no bonus for two reads agreeing on nothing
+        assert_eq!(consensus((b'N', b'5'), (b'n', b'I'), 41), (b'N', b'5'));
+    }
+}
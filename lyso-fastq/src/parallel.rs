@@ -0,0 +1,302 @@
+//! Parsing FASTQ on several threads
+//!
+//! The calling thread cuts the input into chunks of whole records with
+//! `FastqReader::read_raw_record`, which checks every record as reading it would.
+//! The threads of a `WorkerPool` build the records of each chunk and map them,
+//! and the results come back in input order, errors included.
+
+use std::convert::Infallible;
+use std::io::BufRead;
+use std::ops::ControlFlow;
+
+use lyso_common::config::ReaderConfig;
+use lyso_common::pool::{PoolError, WorkerPool};
+use lyso_common::raw::RawRecord;
+
+use crate::reader::FastqReader;
+use crate::{FastqError, Record};
+
+/// Input bytes handed to a worker at a time
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// Records that passed the reader's checks, and the error that ended the chunk
+struct Chunk {
+    bytes: Vec<u8>,
+    error: Option<FastqError>,
+}
+
+/// Read the rest of `reader`, passing each record through `map` on the threads of `pool`
+///
+/// `sink` gets the mapped records and the errors of `reader` in input order, as
+/// iterating over `reader` would give them; returning `ControlFlow::Break` stops
+/// reading. Records failing with a non-terminal error are reported and skipped.
+///
+/// # Panics
+///
+/// If `map` panics, once the records before the one it panicked on are passed to `sink`.
+///
+/// ```
+/// use std::num::NonZeroUsize;
+/// use std::ops::ControlFlow;
+/// use lyso_common::pool::WorkerPool;
+/// use lyso_fastq::parallel::par_map;
+/// use lyso_fastq::reader::FastqReader;
+///
+/// let input = b"@a\nACGT\n+\nIIII\n@b\nGG\n+\nII\n";
+/// let pool = WorkerPool::new(NonZeroUsize::new(2).unwrap());
+/// let mut lengths = Vec::new();
+/// par_map(&mut FastqReader::new(&input[..]), &pool, |r| r.seq().len(), |len| {
+///     lengths.push(len.unwrap());
+///     ControlFlow::Continue(())
+/// });
+/// assert_eq!(lengths, [4, 2]);
+/// ```
+pub fn par_map<R, T>(
+    reader: &mut FastqReader<R>,
+    pool: &WorkerPool,
+    map: impl Fn(Record) -> T + Sync,
+    sink: impl FnMut(Result<T, FastqError>) -> ControlFlow<()>,
+) where
+    R: BufRead,
+    T: Send,
+{
+    par_map_chunked(reader, pool, CHUNK_BYTES, map, sink)
+}
+
+/// `par_map`, with `map` also given the input bytes of each record
+///
+/// For callers that write records out unchanged, as `raw_records` would.
+///
+/// ```
+/// use std::num::NonZeroUsize;
+/// use std::ops::ControlFlow;
+/// use lyso_common::pool::WorkerPool;
+/// use lyso_fastq::parallel::par_map_raw;
+/// use lyso_fastq::reader::FastqReader;
+///
+/// let input = b"@a\r\nACGT\r\n+a\r\nIIII\r\n@b\nGG\n+\nII\n";
+/// let pool = WorkerPool::new(NonZeroUsize::new(2).unwrap());
+/// let mut out = Vec::new();
+/// let long = |rec: lyso_fastq::Record, raw: lyso_common::raw::RawRecord| {
+///     (rec.seq().len() > 2).then_some(raw.bytes)
+/// };
+/// par_map_raw(&mut FastqReader::new(&input[..]), &pool, long, |bytes| {
+///     out.extend(bytes.unwrap().unwrap_or_default());
+///     ControlFlow::Continue(())
+/// });
+/// assert_eq!(out, b"@a\r\nACGT\r\n+a\r\nIIII\r\n");
+/// ```
+pub fn par_map_raw<R, T>(
+    reader: &mut FastqReader<R>,
+    pool: &WorkerPool,
+    map: impl Fn(Record, RawRecord) -> T + Sync,
+    sink: impl FnMut(Result<T, FastqError>) -> ControlFlow<()>,
+) where
+    R: BufRead,
+    T: Send,
+{
+    let next = |records: &mut FastqReader<&[u8]>| {
+        let next = records.read_record_and_raw()?;
+        Some(next.map(|(rec, raw)| map(rec, raw)))
+    };
+    par_chunks(reader, pool, CHUNK_BYTES, next, sink)
+}
+
+fn par_map_chunked<R, T>(
+    reader: &mut FastqReader<R>,
+    pool: &WorkerPool,
+    chunk_bytes: usize,
+    map: impl Fn(Record) -> T + Sync,
+    sink: impl FnMut(Result<T, FastqError>) -> ControlFlow<()>,
+) where
+    R: BufRead,
+    T: Send,
+{
+    let next = |records: &mut FastqReader<&[u8]>| Some(records.next()?.map(&map));
+    par_chunks(reader, pool, chunk_bytes, next, sink)
+}
+
+/// Cut `reader` into chunks, and take results from each with `next` on the threads of `pool`
+fn par_chunks<R, T>(
+    reader: &mut FastqReader<R>,
+    pool: &WorkerPool,
+    chunk_bytes: usize,
+    next: impl Fn(&mut FastqReader<&[u8]>) -> Option<Result<T, FastqError>> + Sync,
+    mut sink: impl FnMut(Result<T, FastqError>) -> ControlFlow<()>,
+) where
+    R: BufRead,
+    T: Send,
+{
+    let quality = reader.copies_quality();
+    let chunks = std::iter::from_fn(|| {
+        let mut bytes = Vec::new();
+        loop {
+            match reader.read_raw_record() {
+                Some(Ok(raw)) => {
+                    bytes.extend_from_slice(&raw.bytes);
+                    if bytes.len() >= chunk_bytes {
+                        return Some(Chunk { bytes, error: None });
+                    }
+                }
+                Some(Err(e)) => {
+                    return Some(Chunk {
+                        bytes,
+                        error: Some(e),
+                    })
+                }
+                None => return (!bytes.is_empty()).then_some(Chunk { bytes, error: None }),
+            }
+        }
+    });
+    let parse = |chunk: Chunk| -> Result<Vec<Result<T, FastqError>>, Infallible> {
+        let config = ReaderConfig {
+            initial_capacity: chunk.bytes.len(),
+            ..ReaderConfig::default()
+        };
+        let mut records = FastqReader::with_config(&chunk.bytes[..], config);
+        if !quality {
+            records = records.without_quality();
+        }
+        let mut out = std::iter::from_fn(|| next(&mut records)).collect::<Vec<_>>();
+        out.extend(chunk.error.map(Err));
+        Ok(out)
+    };
+    let result = pool.map_ordered(chunks, parse, |results| {
+        for r in results {
+            sink(r)?;
+        }
+        ControlFlow::Continue(())
+    });
+    match result {
+        Ok(()) => {}
+        Err(PoolError::Job { error, .. }) => match error {},
+        Err(e @ PoolError::Panicked { .. }) => panic!("{e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::num::NonZeroUsize;
+
+    fn pool(threads: usize) -> WorkerPool {
+        WorkerPool::new(NonZeroUsize::new(threads).unwrap())
+    }
+
+    fn open(fixture: &str) -> FastqReader<BufReader<File>> {
+        let f = File::open(format!("../resources/test_data/{fixture}")).unwrap();
+        FastqReader::new(BufReader::new(f))
+    }
+
+    fn render(r: Result<Record, FastqError>) -> String {
+        r.map_or_else(|e| format!("error: {e}"), |r| r.to_string())
+    }
+
+    #[test]
+    fn test_matches_sequential() {
+        for fixture in [
+            "ambiguous_qual.fastq",
+            "corrupt.fastq",
+            "crlf.fastq",
+            "no_final_newline.fastq",
+            "phred64.fastq",
+            "small.fastq",
+            "test.fastq",
+            "trunc.fastq",
+        ] {
+            let expected = open(fixture).map(render).collect::<Vec<_>>();
+            for (threads, chunk_bytes) in [(1, CHUNK_BYTES), (3, 1), (4, 300)] {
+                let mut out = Vec::new();
+                par_map_chunked(
+                    &mut open(fixture),
+                    &pool(threads),
+                    chunk_bytes,
+                    |r| r.to_string(),
+                    |r| {
+                        out.push(r.unwrap_or_else(|e| format!("error: {e}")));
+                        ControlFlow::Continue(())
+                    },
+                );
+                assert_eq!(out, expected, "{fixture} with {threads} threads");
+            }
+        }
+    }
+
+    #[test]
+    fn test_raw_unchanged() {
+        for fixture in ["crlf.fastq", "no_final_newline.fastq", "small.fastq"] {
+            let input = std::fs::read(format!("../resources/test_data/{fixture}")).unwrap();
+            for (threads, chunk_bytes) in [(1, CHUNK_BYTES), (3, 1)] {
+                let mut out = Vec::new();
+                par_chunks(
+                    &mut FastqReader::new(&input[..]),
+                    &pool(threads),
+                    chunk_bytes,
+                    |r| Some(r.read_record_and_raw()?.map(|(_, raw)| raw.bytes)),
+                    |bytes| {
+                        out.extend(bytes.unwrap());
+                        ControlFlow::Continue(())
+                    },
+                );
+                assert!(out == input, "{fixture} with {threads} threads");
+            }
+        }
+    }
+
+    #[test]
+    fn test_reader_options() {
+        let input = b"@a\nAC\n+\nII\n@b\nACG\n+\nII\n@c\nGT\n+\n##\n";
+        let mut reader = FastqReader::new(&input[..]).without_quality();
+        let mut out = Vec::new();
+        par_map_chunked(
+            &mut reader,
+            &pool(2),
+            1,
+            |r| r.qual().len(),
+            |r| {
+                out.push(r.map_err(|e| e.to_string()));
+                ControlFlow::Continue(())
+            },
+        );
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[0], Ok(0));
+        assert!(out[1].is_err());
+        assert_eq!(out[2], Ok(0));
+    }
+
+    #[test]
+    fn test_stop_early() {
+        let mut reader = open("test.fastq");
+        let mut n = 0;
+        par_map_chunked(
+            &mut reader,
+            &pool(2),
+            100,
+            |_| (),
+            |_| {
+                n += 1;
+                if n == 5 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            },
+        );
+        assert_eq!(n, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "no records named c")]
+    fn test_map_panic() {
+        let input = b"@a\nAC\n+\nII\n@b\nGT\n+\nII\n@c\nGT\n+\nII\n";
+        par_map_chunked(
+            &mut FastqReader::new(&input[..]),
+            &pool(2),
+            1,
+            |r| assert_ne!(r.id(), b"c", "no records named c"),
+            |_| ControlFlow::Continue(()),
+        );
+    }
+}
@@ -1,72 +1,205 @@
-use nom::{
-    bytes::complete::is_a as complete_is_a,
-    bytes::streaming::{is_not, tag},
-    combinator::{map_res, opt, cut},
-    sequence::{pair, preceded, terminated, tuple},
-    IResult,
-};
+//! FASTQ record parsing
+//!
+//! There are two interchangeable implementations: the nom parser, enabled by
+//! the default `nom` feature, and a dependency-free handwritten one used with
+//! `--no-default-features`. Both see the same input and give the same results;
+//! the test suite runs against each of them.
 
-#[inline]
-fn start(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    tag(b"@")(input)
-}
+#[cfg(any(test, not(feature = "nom")))]
+pub(crate) mod handwritten;
+#[cfg(feature = "nom")]
+pub(crate) mod nom_parser;
 
-#[inline]
-fn not_line_ending(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    is_not("\r\n")(input)
+/// Fields of one record, borrowed from the input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawRecord<'a> {
+    pub id: &'a [u8],
+    /// Text after the first space of the header, None when there is no space
+    pub desc: Option<&'a [u8]>,
+    pub seq: &'a str,
+    /// Whatever follows '+' on the separator line
+    pub separator: &'a [u8],
+    pub qual: &'a str,
 }
 
-#[inline]
-/// This uses the complete form of the `is_a` parser.
-/// The reason for this is that streaming parsers make
-/// it exceptionally difficult to differentiate between true EOF
-/// and actually needing more data.
-/// It is the responsibility of the reader implementing this parser
-/// to ensure the passed buffer always ends on a newline.
-fn line_ending(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    complete_is_a("\r\n")(input)
+impl RawRecord<'_> {
+    /// Whether the separator line is bare or repeats the header exactly
+    pub fn separator_matches(&self) -> bool {
+        let sep = self.separator;
+        if sep.is_empty() {
+            return true;
+        }
+        match (sep.strip_prefix(self.id), self.desc) {
+            (Some(rest), None) => rest.is_empty(),
+            (Some(rest), Some(desc)) => rest.strip_prefix(b" ") == Some(desc),
+            (None, _) => false,
+        }
+    }
 }
 
-#[inline]
-fn not_line_ending_or_space(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    is_not("\r\n ")(input)
-}
+/// Id and description of a header line
+pub(crate) type Header<'a> = (&'a [u8], Option<&'a [u8]>);
 
-#[inline]
-fn header(input: &[u8]) -> IResult<&[u8], (&str, &str)> {
-    let (i, (id, desc)) = terminated(
-        pair(
-            preceded(start, not_line_ending_or_space),
-            opt(preceded(tag(" "), not_line_ending)),
-        ),
-        line_ending,
-    )(input)?;
-    Ok((
-        i,
-        (
-            std::str::from_utf8(id).unwrap(),
-            std::str::from_utf8(desc.unwrap_or(&[])).unwrap(),
-        ),
-    ))
+/// Outcome of parsing the start of a buffer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parsed<'a> {
+    /// A record and the input following it
+    Record(RawRecord<'a>, &'a [u8]),
+    /// The input ends inside a record; more lines are needed
+    Incomplete,
+    /// The input is not a FASTQ record
+    Invalid,
 }
 
+/// Parse one record from the start of `input`
+///
+/// Line endings may be LF, CRLF or a lone CR, and runs of them are skipped.
+/// The reader must hand over input that ends on a line ending, or at EOF.
 #[inline]
-fn line(input: &[u8]) -> IResult<&[u8], &str> {
-    map_res(terminated(not_line_ending, line_ending), |x| {
-        std::str::from_utf8(x)
-    })(input)
+pub fn parse_record(input: &[u8]) -> Parsed<'_> {
+    #[cfg(test)]
+    if let Some(parse) = harness::PARSER.get() {
+        return parse(input);
+    }
+    #[cfg(feature = "nom")]
+    return nom_parser::parse_record(input);
+    #[cfg(not(feature = "nom"))]
+    return handwritten::parse_record(input);
 }
 
-#[inline]
-fn comment(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    terminated(tag("+"), line)(input)
-}
+/// Runs tests against every parser built into the crate
+#[cfg(test)]
+pub(crate) mod harness {
+    use std::cell::Cell;
 
-#[inline]
-pub fn parse_record(input: &[u8]) -> IResult<&[u8], (&str, &str, &str, &str)> {
-    let (i, ((id, desc), seq, _, qual)) = tuple((cut(header), line, comment, line))(input)?;
-    Ok((i, (id, desc, seq, qual)))
+    use super::Parsed;
+
+    pub type ParseFn = for<'a> fn(&'a [u8]) -> Parsed<'a>;
+
+    thread_local! {
+        pub(super) static PARSER: Cell<Option<ParseFn>> = const { Cell::new(None) };
+    }
+
+    /// Run `f` with `parse` standing in for the feature-selected parser
+    pub fn with_parser(parse: ParseFn, f: impl FnOnce()) {
+        PARSER.set(Some(parse));
+        f();
+        PARSER.set(None);
+    }
+
+    /// Expand each test into one test per parser, named after the parser
+    macro_rules! for_each_parser {
+        ($($(#[$attr:meta])* fn $name:ident() $body:block)*) => {
+            $(mod $name {
+                use super::*;
+
+                #[cfg(feature = "nom")]
+                #[test]
+                $(#[$attr])*
+                fn nom() {
+                    $crate::parser::harness::with_parser(
+                        $crate::parser::nom_parser::parse_record,
+                        || $body,
+                    )
+                }
+
+                #[test]
+                $(#[$attr])*
+                fn handwritten() {
+                    $crate::parser::harness::with_parser(
+                        $crate::parser::handwritten::parse_record,
+                        || $body,
+                    )
+                }
+            })*
+        };
+    }
+    pub(crate) use for_each_parser;
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::harness::for_each_parser;
+    use super::*;
+
+    fn parse(input: &[u8]) -> RawRecord<'_> {
+        match parse_record(input) {
+            Parsed::Record(raw, rest) => {
+                assert!(rest.is_empty());
+                raw
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    for_each_parser! {
+        fn test_separator_forms() {
+            let raw = parse(b"@r1 d\r\nACGT\r\n+\r\nIIII\r\n");
+            assert_eq!(
+                (raw.id, raw.desc, raw.separator),
+                (&b"r1"[..], Some(&b"d"[..]), &b""[..])
+            );
+            assert_eq!((raw.seq, raw.qual), ("ACGT", "IIII"));
+            assert!(raw.separator_matches());
+
+            let raw = parse(b"@SRR1.1 desc two\nACGT\n+SRR1.1 desc two\nIIII\n");
+            assert_eq!(raw.separator, b"SRR1.1 desc two");
+            assert!(raw.separator_matches());
+
+            let raw = parse(b"@SRR1.1\r\nAC\r\n+SRR1.1\r\nII\r\n");
+            assert_eq!(raw.separator, b"SRR1.1");
+            assert!(raw.separator_matches());
+        }
+
+        fn test_separator_mismatch() {
+            for input in [
+                &b"@SRR1.1 desc\nAC\n+SRR1.2 desc\nII\n"[..],
+                b"@SRR1.1 desc\nAC\n+SRR1.1\nII\n",
+                b"@SRR1.1\nAC\n+SRR1.1 desc\nII\n",
+                b"@SRR1.1\nAC\n+SRR1.10\nII\n",
+            ] {
+                assert!(!parse(input).separator_matches(), "{input:?}");
+            }
+        }
+    }
+
+    /// Every prefix and every single-byte corruption of some records
+    #[cfg(feature = "nom")]
+    fn edge_cases() -> Vec<Vec<u8>> {
+        let seeds: [&[u8]; 6] = [
+            b"@r1 a b\nACGT\n+r1 a b\nIIII\n@r2\nAC\n+\nII\n",
+            b"@r1\r\nACGT\r\n+\r\nIIII\r\n",
+            b"@r1\n\nACGT\n\n+\n\nIIII\n\n\n",
+            b"@r1\rAC\r+\rII\r",
+            b"@r\xe9\nA\xe9\n+\nII\n",
+            b"@ r1\n@r1 \nAC\n+\nII\nx\n",
+        ];
+        let mut cases = Vec::new();
+        for seed in seeds {
+            for end in 0..=seed.len() {
+                cases.push(seed[..end].to_vec());
+            }
+            for i in 0..seed.len() {
+                for b in [b'\n', b'\r', b' ', b'@', b'+', b'A', 0xe9] {
+                    let mut case = seed.to_vec();
+                    case[i] = b;
+                    cases.push(case);
+                }
+            }
+        }
+        cases
+    }
+
+    #[cfg(feature = "nom")]
+    #[test]
+    fn test_parsers_agree() {
+        for case in edge_cases() {
+            assert_eq!(
+                handwritten::parse_record(&case),
+                nom_parser::parse_record(&case),
+                "{:?}",
+                String::from_utf8_lossy(&case)
+            );
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! A dependency-free parser, used when the `nom` feature is off
+//!
+//! It scans each line once for its ending and never asks for a byte count,
+//! so a short buffer is always `Incomplete` and any shape error is `Invalid`.
+
+use super::{Header, Parsed, RawRecord};
+
+/// One step of a record: the parsed value and the input after it
+type Step<'a, T> = Result<(T, &'a [u8]), Parsed<'a>>;
+
+#[inline]
+fn is_line_ending(b: &u8) -> bool {
+    matches!(b, b'\r' | b'\n')
+}
+
+/// Skip a run of one or more line ending bytes
+#[inline]
+fn line_ending(input: &[u8]) -> Step<'_, ()> {
+    match input.iter().position(|b| !is_line_ending(b)) {
+        Some(0) => Err(Parsed::Invalid),
+        Some(n) => Ok(((), &input[n..])),
+        None if input.is_empty() => Err(Parsed::Invalid),
+        None => Ok(((), &[])),
+    }
+}
+
+/// Split off text up to the first byte in `stop`; the text may not be empty
+#[inline]
+fn until(input: &[u8], stop: impl Fn(&u8) -> bool) -> Step<'_, &[u8]> {
+    match input.iter().position(stop) {
+        Some(0) => Err(Parsed::Invalid),
+        Some(n) => Ok(input.split_at(n)),
+        None => Err(Parsed::Incomplete),
+    }
+}
+
+/// Consume `b` or fail
+#[inline]
+fn byte(input: &[u8], b: u8) -> Step<'_, ()> {
+    match input.split_first() {
+        Some((first, rest)) if *first == b => Ok(((), rest)),
+        Some(_) => Err(Parsed::Invalid),
+        None => Err(Parsed::Incomplete),
+    }
+}
+
+/// '@', the id, and an optional description after a single space
+///
+/// The description is None without the space, and empty after a trailing space.
+#[inline]
+fn header(input: &[u8]) -> Step<'_, Header<'_>> {
+    let ((), i) = byte(input, b'@')?;
+    let (id, i) = until(i, |b| is_line_ending(b) || *b == b' ')?;
+    let (desc, i) = match byte(i, b' ') {
+        Ok(((), after_space)) => match until(after_space, is_line_ending) {
+            Ok((desc, i)) => (Some(desc), i),
+            Err(Parsed::Invalid) => (Some(&[][..]), after_space),
+            Err(e) => return Err(e),
+        },
+        Err(_) => (None, i),
+    };
+    let ((), i) = line_ending(i)?;
+    Ok(((id, desc), i))
+}
+
+/// A non-empty UTF-8 line
+#[inline]
+fn line(input: &[u8]) -> Step<'_, &str> {
+    let (text, i) = until(input, is_line_ending)?;
+    let ((), i) = line_ending(i)?;
+    let text = std::str::from_utf8(text).map_err(|_| Parsed::Invalid)?;
+    Ok((text, i))
+}
+
+/// The '+' line; returns the text after '+'
+#[inline]
+fn comment(input: &[u8]) -> Step<'_, &[u8]> {
+    let ((), i) = byte(input, b'+')?;
+    let (text, i) = match until(i, is_line_ending) {
+        Ok(found) => found,
+        Err(Parsed::Invalid) => (&[][..], i),
+        Err(e) => return Err(e),
+    };
+    let ((), i) = line_ending(i)?;
+    Ok((text, i))
+}
+
+#[inline]
+fn record(input: &[u8]) -> Step<'_, RawRecord<'_>> {
+    let ((id, desc), i) = header(input)?;
+    let (seq, i) = line(i)?;
+    let (separator, i) = comment(i)?;
+    let (qual, i) = line(i)?;
+    Ok((
+        RawRecord {
+            id,
+            desc,
+            seq,
+            separator,
+            qual,
+        },
+        i,
+    ))
+}
+
+#[inline]
+pub fn parse_record(input: &[u8]) -> Parsed<'_> {
+    match record(input) {
+        Ok((raw, rest)) => Parsed::Record(raw, rest),
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,93 @@
+//! The nom implementation, enabled by the `nom` feature
+
+use nom::{
+    bytes::complete::is_a as complete_is_a,
+    bytes::streaming::{is_not, tag},
+    combinator::{cut, map_res, opt},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    IResult,
+};
+
+use super::{Header, Parsed, RawRecord};
+
+#[inline]
+fn start(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    tag(b"@")(input)
+}
+
+#[inline]
+fn not_line_ending(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    is_not("\r\n")(input)
+}
+
+#[inline]
+/// This uses the complete form of the `is_a` parser.
+/// The reason for this is that streaming parsers make
+/// it exceptionally difficult to differentiate between true EOF
+/// and actually needing more data.
+/// It is the responsibility of the reader implementing this parser
+/// to ensure the passed buffer always ends on a newline.
+fn line_ending(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    complete_is_a("\r\n")(input)
+}
+
+#[inline]
+fn not_line_ending_or_space(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    is_not("\r\n ")(input)
+}
+
+#[inline]
+/// Id and description are returned as raw bytes; they need not be UTF-8
+///
+/// The description is None without a space after the id, and empty after a
+/// trailing space.
+fn header(input: &[u8]) -> IResult<&[u8], Header<'_>> {
+    let (i, (id, desc)) = terminated(
+        pair(
+            preceded(start, not_line_ending_or_space),
+            opt(preceded(tag(" "), opt(not_line_ending))),
+        ),
+        line_ending,
+    )(input)?;
+    Ok((i, (id, desc.map(Option::unwrap_or_default))))
+}
+
+#[inline]
+fn line(input: &[u8]) -> IResult<&[u8], &str> {
+    map_res(terminated(not_line_ending, line_ending), |x| {
+        std::str::from_utf8(x)
+    })(input)
+}
+
+/// The '+' line, which may repeat the header (in any encoding) or be bare
+///
+/// Returns the text after '+', without the line ending.
+#[inline]
+fn comment(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (i, text) = delimited(tag("+"), opt(not_line_ending), line_ending)(input)?;
+    Ok((i, text.unwrap_or_default()))
+}
+
+#[inline]
+fn record(input: &[u8]) -> IResult<&[u8], RawRecord<'_>> {
+    let (i, ((id, desc), seq, separator, qual)) = tuple((cut(header), line, comment, line))(input)?;
+    Ok((
+        i,
+        RawRecord {
+            id,
+            desc,
+            seq,
+            separator,
+            qual,
+        },
+    ))
+}
+
+#[inline]
+pub fn parse_record(input: &[u8]) -> Parsed<'_> {
+    match record(input) {
+        Ok((rest, raw)) => Parsed::Record(raw, rest),
+        Err(nom::Err::Incomplete(_)) => Parsed::Incomplete,
+        Err(_) => Parsed::Invalid,
+    }
+}
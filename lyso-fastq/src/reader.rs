@@ -1,48 +1,201 @@
-use nom::Err::Incomplete;
-use nom::Needed;
-use std::io::BufRead;
+//! Streaming FASTQ reader
 
-use crate::parser;
-use crate::{FastqError, Record};
+use lyso_common::config::ReaderConfig;
+use lyso_common::detect::{find_mismatch, Format};
+use lyso_common::error::LysoError;
+use lyso_common::raw::RawRecord;
+use lyso_common::stream::RecordBytes;
+use lyso_common::validate::Validator;
+use memchr::{memchr, memchr_iter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-const MAX_BUFFER_SIZE: usize = 10_000_000;
+use crate::parser::{self, Parsed};
+use crate::{FastqError, Record};
 
+/// Iteration state of a `FastqReader`
+///
+/// `Complete` means the input ended cleanly; `Failed` means a terminal error
+/// (see `FastqError::is_terminal`) was returned. In either case `next()` returns None.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum FastqReaderState {
+    /// More records may follow
     Reading,
+    /// The input ended cleanly
     Complete,
+    /// A terminal error was returned
     Failed,
 }
 
+/// A result read ahead by `FastqReader::peek_record`
+#[derive(Debug)]
+struct Peeked {
+    next: Option<Result<Record, FastqError>>,
+    /// Where the id lies in the reader's `peeked_bytes`, when `next` is a record
+    id_span: Option<std::ops::Range<usize>>,
+    /// The reader state before it was read
+    state: FastqReaderState,
+}
+
+/// Reads FASTQ records one at a time from a `BufRead`
+///
+/// Parse and validation errors come wrapped in `FastqError::AtLine`, with the
+/// line of the record's header, or of the separator or quality line when those
+/// are at fault. A reader opened with `from_path` names the file as well.
 #[derive(Debug)]
 pub struct FastqReader<T> {
     state: FastqReaderState,
     inner: T,
     buffer: Vec<u8>,
     offset: usize,
+    /// Input bytes of the records parsed so far
+    record_bytes: u64,
+    /// Line endings in those bytes
+    lines: u64,
+    /// The file read, for reporting errors
+    path: Option<PathBuf>,
+    strict_utf8: bool,
+    check_separator: bool,
+    /// Rules every record is checked against, see `FastqReader::validator`
+    validator: Option<Arc<Validator<Record>>>,
+    /// Copy quality strings into records
+    quality: bool,
+    /// A line ending was added to the end of the input, which lacked one
+    added_newline: bool,
+    peeked: Option<Peeked>,
+    /// The input bytes of the peeked record, reused from one peek to the next
+    peeked_bytes: Vec<u8>,
+    config: ReaderConfig,
 }
 
 impl<T> FastqReader<T>
 where
     T: BufRead,
 {
+    /// A reader at the start of `f`
+    #[must_use]
     pub fn new(f: T) -> Self {
+        Self::with_config(f, ReaderConfig::default())
+    }
+
+    /// A reader at the start of `f`, buffering as set by `config`
+    ///
+    /// ```
+    /// use lyso_common::config::ReaderConfig;
+    /// use lyso_fastq::{reader::FastqReader, FastqError};
+    ///
+    /// let config = ReaderConfig {
+    ///     max_record_size: 16,
+    ///     ..ReaderConfig::default()
+    /// };
+    /// let input = b"@a\nAC\n+\nII\n@long\nACGTACGT\n+\nIIIIIIII\n";
+    /// let mut reader = FastqReader::with_config(&input[..], config);
+    /// assert!(reader.next().unwrap().is_ok());
+    /// let err = reader.next().unwrap().unwrap_err();
+    /// assert!(matches!(err.root(), FastqError::RecordTooLarge { limit: 16 }));
+    /// assert_eq!(err.to_string(), "line 5: record longer than 16 bytes");
+    /// assert!(reader.next().is_none());
+    /// ```
+    #[must_use]
+    pub fn with_config(f: T, config: ReaderConfig) -> Self {
         FastqReader {
             state: FastqReaderState::Reading,
             inner: f,
-            buffer: Vec::with_capacity(MAX_BUFFER_SIZE),
+            buffer: Vec::with_capacity(config.initial_capacity),
             offset: 0,
+            record_bytes: 0,
+            lines: 0,
+            path: None,
+            strict_utf8: false,
+            check_separator: false,
+            validator: None,
+            quality: true,
+            added_newline: false,
+            peeked: None,
+            peeked_bytes: Vec::new(),
+            config,
         }
     }
 
+    /// Reject headers that are not UTF-8 (see `Record::check_utf8`)
+    ///
+    /// Off by default: ids and descriptions are kept as raw bytes either way.
+    #[must_use]
+    pub fn strict_utf8(mut self, strict: bool) -> Self {
+        self.strict_utf8 = strict;
+        self
+    }
+
+    /// Reject records whose '+' line repeats something other than their header
+    ///
+    /// A bare '+' always passes. A mismatch is reported as `SeparatorMismatch`, which
+    /// often means the lines of the file have gone out of step. Off by default.
+    #[must_use]
+    pub fn check_separator(mut self, check: bool) -> Self {
+        self.check_separator = check;
+        self
+    }
+
+    /// Fail records that break an error-level rule of `validator`
+    ///
+    /// A failing record comes back as a non-terminal `RuleViolation`; warnings are
+    /// left to `lyso check`. Every way of reading checks the record, including
+    /// `raw_records` and `scan_records`, which then build each record to check it.
+    /// Rules see the record as returned, so after `without_quality` they see no
+    /// qualities and quality rules pass.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use lyso_common::validate::{MaxHomopolymer, Severity};
+    /// use lyso_fastq::reader::FastqReader;
+    ///
+    /// let validator = lyso_fastq::validator().rule(MaxHomopolymer(4), Severity::Error);
+    /// let input = b"@a\nAAAAC\n+\nIIIII\n@b\nAAAAA\n+\nIIIII\n";
+    /// let mut reader = FastqReader::new(&input[..]).validator(Arc::new(validator));
+    /// assert!(reader.next().unwrap().is_ok());
+    /// let err = reader.next().unwrap().unwrap_err();
+    /// assert_eq!(err.to_string(), "line 5: record b fails homopolymer: run of 5 A at base 1");
+    /// ```
+    #[must_use]
+    pub fn validator(mut self, validator: Arc<Validator<Record>>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Leave `Record::qual` empty, for callers that only need ids and sequences
+    ///
+    /// Records are parsed and checked as before, including the length of the quality
+    /// line, so the same input gives the same errors; only the copy is skipped.
+    /// Records read this way do not write back out as valid FASTQ.
+    #[must_use]
+    pub fn without_quality(mut self) -> Self {
+        self.quality = false;
+        self
+    }
+
+    /// Number lines as if `lines` line endings came before the input, for a reader
+    /// picking up part way through a file
+    pub(crate) fn after_lines(mut self, lines: u64) -> Self {
+        self.lines = lines;
+        self
+    }
+
+    /// Whether records get their quality strings, see `without_quality`
+    pub(crate) fn copies_quality(&self) -> bool {
+        self.quality
+    }
+
     /// Prevent internal buffer from growing infinitely.
     /// Does not shrink capacity under the assumption that
     /// reads in a fastq tend to be of similar length.
     #[inline]
     fn resize_buffer(&mut self) {
-        {
-            self.buffer.drain(0..self.offset)
-        };
+        self.buffer.drain(0..self.offset);
         self.offset = 0;
     }
 
@@ -52,20 +205,326 @@ where
     }
 
     #[inline]
-    /// FASTQ records are always 4 lines, so try to read that much
+    /// FASTQ records are always 4 lines, so try to read that much, or
+    /// `read_chunk_size` bytes if that comes first
+    ///
+    /// A read never stops between the CR and LF of a line ending, which the parser
+    /// would take for the end of the line.
     fn read_to_buffer(&mut self) -> Result<usize, std::io::Error> {
-        let mut amt = 0;
-        for _ in 0..4 {
-            amt += (&mut self.inner).read_until(b'\n', &mut self.buffer)?;
+        let chunk = self.config.read_chunk_size.max(1);
+        let (mut amt, mut lines) = (0, 0);
+        while lines < 4 && (amt < chunk || self.buffer.last() == Some(&b'\r')) {
+            let available = match self.inner.fill_buf() {
+                Ok(b) => b,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                break;
+            }
+            let available = &available[..available.len().min(chunk.saturating_sub(amt).max(1))];
+            let used = match memchr(b'\n', available) {
+                Some(i) => {
+                    lines += 1;
+                    i + 1
+                }
+                None => available.len(),
+            };
+            self.buffer.extend_from_slice(&available[..used]);
+            self.inner.consume(used);
+            amt += used;
         }
         Ok(amt)
     }
 
+    /// The state as of the last record returned; a pending peek does not change it
+    pub fn state(&self) -> FastqReaderState {
+        match &self.peeked {
+            Some(p) => p.state,
+            None => self.state,
+        }
+    }
+
+    /// Input bytes of the records read so far, failed and peeked ones included
+    ///
+    /// Divided by the number of records, this is the average record size.
+    pub fn record_bytes(&self) -> u64 {
+        self.record_bytes
+    }
+
+    /// The result the next `read_record` will return, without consuming it
+    ///
+    /// Errors are held like records, so peeking never loses one.
+    ///
+    /// ```
+    /// use lyso_fastq::reader::{FastqReader, FastqReaderState};
+    ///
+    /// // the second record is cut off
+    /// let mut reader = FastqReader::new(&b"@a\nAC\n+\nII\n@b\nAC\n"[..]);
+    /// assert!(reader.next().unwrap().is_ok());
+    /// assert!(matches!(reader.peek_record(), Some(Err(_))));
+    /// assert_eq!(reader.state(), FastqReaderState::Reading);
+    /// assert!(reader.next().unwrap().is_err());
+    /// assert_eq!(reader.state(), FastqReaderState::Failed);
+    /// ```
+    pub fn peek_record(&mut self) -> Option<&Result<Record, FastqError>> {
+        if self.peeked.is_none() {
+            let state = self.state;
+            let strict = self.strict_utf8;
+            // the input bytes are kept too, for a `raw_records` read that follows
+            let mut kept = std::mem::take(&mut self.peeked_bytes);
+            kept.clear();
+            let next = self.read_with(|raw, bytes, rules| {
+                let rec = build_record(raw, strict)?;
+                check_rules(rules, &rec)?;
+                kept.extend_from_slice(bytes);
+                Ok((rec, id_span(&raw)))
+            });
+            self.peeked_bytes = kept;
+            let (next, id_span) = match next {
+                Some(Ok((rec, span))) => (Some(Ok(rec)), Some(span)),
+                Some(Err(e)) => (Some(Err(e)), None),
+                None => (None, None),
+            };
+            self.peeked = Some(Peeked {
+                next,
+                id_span,
+                state,
+            });
+        }
+        self.peeked.as_ref().and_then(|p| p.next.as_ref())
+    }
+
+    /// Pass over the next `n` records without building them
+    ///
+    /// Records failing with a non-terminal error count as skipped. Returns the
+    /// number skipped, fewer than `n` at the end of the input, or the first
+    /// terminal error.
+    pub fn skip_records(&mut self, n: usize) -> Result<usize, FastqError> {
+        for skipped in 0..n {
+            let next = match self.peeked.take() {
+                Some(p) => p.next.map(|r| r.map(drop)),
+                // a record breaking a rule counts as skipped anyway
+                None => self.read_with(|_, _, _| Ok(())),
+            };
+            match next {
+                None => return Ok(skipped),
+                Some(Err(e)) if e.is_terminal() => return Err(e),
+                Some(_) => {}
+            }
+        }
+        Ok(n)
+    }
+
+    /// Read the next record, moving to `Failed` after a terminal error
     #[inline]
     pub fn read_record(&mut self) -> Option<Result<Record, FastqError>> {
+        if let Some(p) = self.peeked.take() {
+            return p.next;
+        }
+        let strict = self.strict_utf8;
+        self.read_with(|raw, _, rules| {
+            let rec = build_record(raw, strict)?;
+            check_rules(rules, &rec)?;
+            Ok(rec)
+        })
+    }
+
+    /// Read the next record as its input bytes, see `raw_records`
+    pub fn read_raw_record(&mut self) -> Option<Result<RawRecord, FastqError>> {
+        if let Some(p) = self.peeked.take() {
+            return p.next.map(|r| r.map(|_| self.take_peeked_raw(p.id_span)));
+        }
+        let strict = self.strict_utf8;
+        self.read_with(|raw, bytes, rules| {
+            if strict {
+                Record::from_raw(raw).check_utf8()?;
+            }
+            check_unbuilt(rules, raw)?;
+            Ok(RawRecord::new(bytes.to_vec(), id_span(&raw)))
+        })
+    }
+
+    /// Read the next record along with the input bytes it was built from
+    ///
+    /// For callers that decide on the record but write out the bytes, like a
+    /// filter copying the records it keeps.
+    pub fn read_record_and_raw(&mut self) -> Option<Result<(Record, RawRecord), FastqError>> {
+        if let Some(p) = self.peeked.take() {
+            return p
+                .next
+                .map(|r| r.map(|rec| (rec, self.take_peeked_raw(p.id_span))));
+        }
+        let strict = self.strict_utf8;
+        self.read_with(|raw, bytes, rules| {
+            let rec = build_record(raw, strict)?;
+            check_rules(rules, &rec)?;
+            Ok((rec, RawRecord::new(bytes.to_vec(), id_span(&raw))))
+        })
+    }
+
+    /// The peeked record's input bytes, as kept by `peek_record`
+    fn take_peeked_raw(&mut self, id_span: Option<std::ops::Range<usize>>) -> RawRecord {
+        let bytes = std::mem::take(&mut self.peeked_bytes);
+        RawRecord::new(bytes, id_span.unwrap_or_default())
+    }
+
+    /// Iterate over records as the exact bytes they were read from
+    ///
+    /// Records are parsed and checked as by `read_record`, with the same errors,
+    /// but come back unchanged: CRLF line endings, the text of the '+' line and a
+    /// missing final newline are all kept. Can be mixed with `next()`.
+    ///
+    /// ```
+    /// use lyso_fastq::reader::FastqReader;
+    ///
+    /// let input = b"@a x\r\nAC\r\n+a x\r\nII\r\n@b\nGT\n+\nII";
+    /// let mut reader = FastqReader::new(&input[..]);
+    /// let out = reader.raw_records().map(|r| r.unwrap().bytes).collect::<Vec<_>>();
+    /// assert_eq!(out.concat(), input);
+    /// ```
+    pub fn raw_records(&mut self) -> RawRecords<'_, T> {
+        RawRecords { reader: self }
+    }
+
+    /// Visit the remaining records as slices of the reader's buffer
+    ///
+    /// Records are parsed and checked as by `read_record`, but never built, so
+    /// nothing is allocated per record. Returning `ControlFlow::Break` stops the
+    /// scan after that record; reading continues from the next one. Returns the
+    /// number of records visited, or the first error, after which reading also
+    /// continues if the error is not terminal.
+    ///
+    /// ```
+    /// use std::ops::ControlFlow;
+    ///
+    /// use lyso_fastq::reader::FastqReader;
+    ///
+    /// let mut reader = FastqReader::new(&b"@a\nAC\n+\nII\n@b\nGT\n+\nII\n"[..]);
+    /// let mut ids = Vec::new();
+    /// let visited = reader.scan_records(|rec| {
+    ///     ids.push((rec.id.to_vec(), rec.offset));
+    ///     ControlFlow::Break(())
+    /// });
+    /// assert_eq!(visited.unwrap(), 1);
+    /// assert_eq!(ids, [(b"a".to_vec(), 0)]);
+    /// assert_eq!(reader.next().unwrap().unwrap().id(), b"b");
+    /// ```
+    pub fn scan_records(
+        &mut self,
+        mut on_record: impl FnMut(RecordSlices<'_>) -> ControlFlow<()>,
+    ) -> Result<u64, FastqError> {
+        let mut visited = 0;
+        // a peeked record has already been built, and its bytes counted
+        if let Some(p) = self.peeked.take() {
+            let Some(rec) = p.next.transpose()? else {
+                return Ok(0);
+            };
+            let len = self.peeked_bytes.len() as u64;
+            visited += 1;
+            let slices = RecordSlices {
+                offset: self.record_bytes - len,
+                id: rec.id(),
+                desc: rec.desc(),
+                seq: rec.seq().as_bytes(),
+                qual: rec.qual().as_bytes(),
+            };
+            if on_record(slices).is_break() {
+                return Ok(visited);
+            }
+        }
+        let strict = self.strict_utf8;
+        loop {
+            let offset = self.record_bytes;
+            let flow = self.read_with(|raw, _, rules| {
+                let valid = |text: &[u8]| std::str::from_utf8(text).is_ok();
+                if strict && !(valid(raw.id) && raw.desc.is_none_or(valid)) {
+                    // only a failing record is built, for the error
+                    Record::from_raw(raw).check_utf8()?;
+                }
+                check_unbuilt(rules, raw)?;
+                Ok(on_record(RecordSlices {
+                    offset,
+                    id: raw.id,
+                    desc: raw.desc,
+                    seq: raw.seq.as_bytes(),
+                    qual: raw.qual.as_bytes(),
+                }))
+            });
+            match flow {
+                None => return Ok(visited),
+                Some(Err(e)) => return Err(e),
+                Some(Ok(flow)) => {
+                    visited += 1;
+                    if flow.is_break() {
+                        return Ok(visited);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read the next record into `rec`, reusing its allocations
+    ///
+    /// Behaves like `read_record`, but a loop over one `Record` stops allocating
+    /// once its fields have grown to the longest read. After an error `rec` may
+    /// hold the fields of the failed record or those of the previous one.
+    #[inline]
+    pub fn read_record_into(&mut self, rec: &mut Record) -> Option<Result<(), FastqError>> {
+        if let Some(p) = self.peeked.take() {
+            return p.next.map(|r| r.map(|peeked| *rec = peeked));
+        }
+        let strict = self.strict_utf8;
+        self.read_with(|raw, _, rules| {
+            rec.set_from_raw(raw);
+            if strict {
+                rec.check_utf8()?;
+            }
+            check_rules(rules, rec)
+        })
+    }
+
+    #[inline]
+    fn read_with<R>(
+        &mut self,
+        build: impl FnOnce(
+            parser::RawRecord<'_>,
+            &[u8],
+            Option<&Validator<Record>>,
+        ) -> Result<R, FastqError>,
+    ) -> Option<Result<R, FastqError>> {
         if self.state != FastqReaderState::Reading {
             return None;
         }
+        let res = self.parse_next(build);
+        if let Some(Err(e)) = &res {
+            if e.is_terminal() {
+                self.state = FastqReaderState::Failed;
+            }
+        }
+        res
+    }
+
+    #[inline]
+    fn parse_next<R>(
+        &mut self,
+        build: impl FnOnce(
+            parser::RawRecord<'_>,
+            &[u8],
+            Option<&Validator<Record>>,
+        ) -> Result<R, FastqError>,
+    ) -> Option<Result<R, FastqError>> {
+        // input in the wrong format fails before parsing, with an error naming it
+        if self.record_bytes == 0 && self.buffer.is_empty() {
+            match self.inner.fill_buf() {
+                Ok(buf) => {
+                    if let Some(m) = find_mismatch(buf, Format::Fastq) {
+                        return Some(Err(m.into()));
+                    }
+                }
+                Err(e) => return Some(Err(FastqError::IoError(e))),
+            }
+        }
         match self.read_to_buffer() {
             Ok(0) if self.offset == self.buffer.len() => {
                 self.state = FastqReaderState::Complete;
@@ -74,34 +533,176 @@ where
             Ok(_) => {}
             Err(e) => return Some(Err(FastqError::IoError(e))),
         }
-        let mut res: Option<Result<Record, FastqError>> = None;
-        while res.is_none() {
+        // the line the next record starts on
+        let line = self.lines + 1;
+        let res = loop {
             match parser::parse_record(self.get_slice()) {
-                Ok((i, (id, desc, seq, qual))) => {
-                    res = Some(Ok(Record {
-                        id: id.to_string(),
-                        desc: desc.to_string(),
-                        seq: seq.to_string(),
-                        qual: qual.to_string(),
-                    }));
-                    self.offset = self.buffer.len() - i.len();
+                Parsed::Record(raw, i) => {
+                    let offset = self.buffer.len() - i.len();
+                    let end = offset - usize::from(self.added_newline && i.is_empty());
+                    let bytes = &self.buffer[self.offset..end];
+                    let res = if bytes.len() > self.config.max_record_size {
+                        Err(self.at_line(
+                            line,
+                            FastqError::RecordTooLarge {
+                                limit: self.config.max_record_size,
+                            },
+                        ))
+                    } else if raw.seq.len() != raw.qual.len() {
+                        let qual_line = line_within(line, bytes, raw.qual.as_bytes());
+                        Err(self.at_line(qual_line, FastqError::SeqQualMismatch))
+                    } else if self.check_separator && !raw.separator_matches() {
+                        let err = FastqError::SeparatorMismatch {
+                            record: String::from_utf8_lossy(raw.id).into_owned(),
+                            separator: String::from_utf8_lossy(raw.separator).into_owned(),
+                        };
+                        Err(self.at_line(line_within(line, bytes, raw.separator), err))
+                    } else {
+                        let raw = match self.quality {
+                            true => raw,
+                            false => parser::RawRecord { qual: "", ..raw },
+                        };
+                        build(raw, bytes, self.validator.as_deref())
+                    };
+                    let res = res.map_err(|e| match e {
+                        FastqError::AtLine { .. } => e,
+                        e => self.at_line(line, e),
+                    });
+                    self.lines +=
+                        memchr_iter(b'\n', &self.buffer[self.offset..offset]).count() as u64;
+                    self.record_bytes += (offset - self.offset) as u64;
+                    self.offset = offset;
+                    break res;
                 }
-                Err(Incomplete(Needed::Size(_))) => match self.read_to_buffer() {
+                // every retry consumes input, so a truncated record ends in EofError
+                Parsed::Incomplete if self.get_slice().len() > self.config.max_record_size => {
+                    let err = FastqError::RecordTooLarge {
+                        limit: self.config.max_record_size,
+                    };
+                    return Some(Err(self.at_line(line, err)));
+                }
+                Parsed::Incomplete => match self.read_to_buffer() {
+                    // a final line without a line ending is terminated once
+                    Ok(0) if self.buffer.last().is_some_and(|b| *b != b'\n') => {
+                        self.buffer.push(b'\n');
+                        self.added_newline = true;
+                    }
                     Ok(0) => {
-                        return Some(Err(FastqError::EofError));
+                        return Some(Err(self.at_line(line, FastqError::EofError)));
                     }
                     Ok(_) => {}
                     Err(e) => return Some(Err(FastqError::IoError(e))),
                 },
-                Err(_) => {
-                    return Some(Err(FastqError::ParseError));
+                Parsed::Invalid => {
+                    return Some(Err(self.at_line(line, FastqError::ParseError)));
                 }
             }
-        }
-        if self.offset > MAX_BUFFER_SIZE {
+        };
+        if self.offset > self.config.compact_threshold {
             self.resize_buffer();
         }
-        res
+        Some(res)
+    }
+}
+
+impl<T> FastqReader<T> {
+    fn at_line(&self, line: u64, err: FastqError) -> FastqError {
+        FastqError::AtLine {
+            path: self.path.clone(),
+            line,
+            source: Box::new(err),
+        }
+    }
+}
+
+/// The line of `field`, a slice of `record`, whose first line is `first`
+fn line_within(first: u64, record: &[u8], field: &[u8]) -> u64 {
+    let start = (field.as_ptr() as usize).saturating_sub(record.as_ptr() as usize);
+    first + memchr_iter(b'\n', &record[..start.min(record.len())]).count() as u64
+}
+
+/// The record parsed from `raw`, checking its header is UTF-8 when `strict`
+fn build_record(raw: parser::RawRecord<'_>, strict: bool) -> Result<Record, FastqError> {
+    let rec = Record::from_raw(raw);
+    if strict {
+        rec.check_utf8()?;
+    }
+    Ok(rec)
+}
+
+/// Fail `rec` if it breaks an error-level rule of `rules`
+fn check_rules(rules: Option<&Validator<Record>>, rec: &Record) -> Result<(), FastqError> {
+    match rules.and_then(|v| v.first_error(rec)) {
+        Some(v) => Err(FastqError::RuleViolation(v)),
+        None => Ok(()),
+    }
+}
+
+/// `check_rules` for a record read without building it, which is built only
+/// when there are rules
+fn check_unbuilt(
+    rules: Option<&Validator<Record>>,
+    raw: parser::RawRecord<'_>,
+) -> Result<(), FastqError> {
+    match rules {
+        Some(_) => check_rules(rules, &Record::from_raw(raw)),
+        None => Ok(()),
+    }
+}
+
+/// Where the id of `raw` lies in its record's bytes, which start with '@'
+fn id_span(raw: &parser::RawRecord<'_>) -> std::ops::Range<usize> {
+    1..1 + raw.id.len()
+}
+
+/// The fields of a record, borrowed from the reader; see `FastqReader::scan_records`
+#[derive(Clone, Copy, Debug)]
+pub struct RecordSlices<'a> {
+    /// Where the record starts in the input
+    pub offset: u64,
+    /// The header up to the first space, without '@'
+    pub id: &'a [u8],
+    /// Text after the first space of the header, None when there is no space
+    pub desc: Option<&'a [u8]>,
+    /// The sequence line
+    pub seq: &'a [u8],
+    /// The quality line; empty for a reader built with `without_quality`
+    pub qual: &'a [u8],
+}
+
+/// Iterator over `RawRecord`s, see `FastqReader::raw_records`
+pub struct RawRecords<'a, T> {
+    reader: &'a mut FastqReader<T>,
+}
+
+impl<T> Iterator for RawRecords<'_, T>
+where
+    T: BufRead,
+{
+    type Item = Result<RawRecord, FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_raw_record()
+    }
+}
+
+impl<T> RecordBytes for RawRecords<'_, T>
+where
+    T: BufRead,
+{
+    fn record_bytes(&self) -> u64 {
+        self.reader.record_bytes
+    }
+}
+
+impl FastqReader<BufReader<File>> {
+    /// Open `path` for reading; errors name the file
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, LysoError> {
+        let path = path.as_ref();
+        let f = File::open(path).map_err(|e| LysoError::from_path(path, e))?;
+        let mut reader = FastqReader::new(BufReader::new(f));
+        reader.path = Some(path.to_path_buf());
+        Ok(reader)
     }
 }
 
@@ -116,95 +717,733 @@ where
     }
 }
 
+impl<T> RecordBytes for FastqReader<T>
+where
+    T: BufRead,
+{
+    fn record_bytes(&self) -> u64 {
+        self.record_bytes
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::parser::harness::for_each_parser;
+    use lyso_common::io::testing::OneByte;
     use std::fs::File;
     use std::io::BufReader;
     use std::path::PathBuf;
 
     fn init_path(s: &str) -> PathBuf {
         let mut test_data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        test_data_dir.push("..");
         test_data_dir.push(s);
         test_data_dir
     }
 
-    #[test]
-    fn test_read_fq() {
-        let fq_path = init_path("resources/test_data/test.fastq");
-        let f = File::open(fq_path).unwrap();
-        let b = BufReader::new(f);
-        let reader = FastqReader::new(b);
-        for record in reader {
-            eprintln!("{:?}", record);
+    for_each_parser! {
+        fn test_read_fq() {
+            let fq_path = init_path("resources/test_data/test.fastq");
+            let f = File::open(fq_path).unwrap();
+            let b = BufReader::new(f);
+            let reader = FastqReader::new(b);
+            for record in reader {
+                eprintln!("{:?}", record);
+            }
+        }
+
+        #[should_panic]
+        fn test_bad_fq_panics() {
+            let fq_path = init_path("resources/test_data/corrupt.fastq");
+            let f = File::open(fq_path).unwrap();
+            let b = BufReader::new(f);
+            let reader = FastqReader::new(b);
+            for r in reader {
+                r.unwrap();
+            }
+        }
+
+        fn test_get_fields() {
+            let fq_path = init_path("resources/test_data/test.fastq");
+            let f = File::open(fq_path).unwrap();
+            let b = BufReader::new(f);
+            let mut reader = FastqReader::new(b);
+            let record = reader.next().expect("bad record!").expect("bad record!");
+
+            assert!(record.id == b"SRR22092847.1.1");
+            assert!(record.desc() == Some(b"1 length=37"));
+            assert!(record.qual == "F#FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF");
+            assert!(record.seq == "GNTTAAAGCACATAAAGACAAATCGCTCCAGGGCAAA");
+        }
+
+        fn test_read_checked_fq() {
+            let fq_path = init_path("resources/test_data/test.fastq");
+            let f = File::open(fq_path).unwrap();
+            let b = BufReader::new(f);
+            let mut reader = FastqReader::new(b);
+            assert!(reader.all(|x| x.is_ok()));
+        }
+
+        fn test_wrong_format() {
+            use lyso_common::detect::{Compression, Format};
+
+            let first = |input: &[u8]| {
+                let mut reader = FastqReader::new(input);
+                let err = reader.next().unwrap().unwrap_err();
+                assert_eq!(reader.state(), FastqReaderState::Failed);
+                err
+            };
+            let bgzf = std::fs::read(init_path("resources/test_data/name_sorted.bam")).unwrap();
+            assert!(matches!(first(&bgzf), FastqError::Compressed(Compression::Bgzf)));
+            assert!(matches!(
+                first(&[0x1f, 0x8b, 8, 0, 0, 0]),
+                FastqError::Compressed(Compression::Gzip)
+            ));
+            assert!(matches!(
+                first(b"BAM\x01\0\0\0\0"),
+                FastqError::WrongFormat(Format::Bam)
+            ));
+            assert!(matches!(
+                first(b">chr1\nACGT\n"),
+                FastqError::WrongFormat(Format::Fasta)
+            ));
+            assert!(matches!(
+                first(b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:10\n"),
+                FastqError::WrongFormat(Format::Sam)
+            ));
+            let binary = (0..=255).cycle().take(2000).collect::<Vec<u8>>();
+            assert!(matches!(first(&binary), FastqError::NotText));
+            assert_eq!(
+                first(b">chr1\nACGT\n").to_string(),
+                "input looks like FASTA, not FASTQ"
+            );
+        }
+
+        fn test_bad_fq_is_recoverable() {
+            let fq_path = init_path("resources/test_data/trunc.fastq");
+            let f = File::open(fq_path).unwrap();
+            let b = BufReader::new(f);
+            let reader = FastqReader::new(b);
+            for record in reader {
+                if let Some(r) = record.into() {
+                    match r {
+                        Ok(_) => {}
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+            }
+        }
+
+        fn test_corrupt_fq_is_recoverable() {
+            let fq_path = init_path("resources/test_data/corrupt.fastq");
+            let f = File::open(fq_path).unwrap();
+            let b = BufReader::new(f);
+            let reader = FastqReader::new(b);
+            for record in reader {
+                if let Some(r) = record.into() {
+                    match r {
+                        Ok(_) => {}
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+            }
+        }
+
+        fn test_state_complete() {
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+r1\nFFFF\n"[..]);
+            assert_eq!(reader.state(), FastqReaderState::Reading);
+            assert!(reader.next().unwrap().is_ok());
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastqReaderState::Complete);
+        }
+
+        fn test_terminal_error_stops() {
+            let fq_path = init_path("resources/test_data/corrupt.fastq");
+            let mut reader = FastqReader::new(BufReader::new(File::open(fq_path).unwrap()));
+            let err = reader.find_map(|r| r.err()).unwrap();
+            assert!(err.is_terminal());
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastqReaderState::Failed);
+
+            // truncated final record
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+r1\nFFFF\n@r2\nAC"[..]);
+            assert!(reader.next().unwrap().is_ok());
+            assert!(reader.next().unwrap().unwrap_err().is_terminal());
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastqReaderState::Failed);
+        }
+
+        fn test_local_error_continues() {
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+r1\nFF\n@r2\nAC\n+r2\nFF\n"[..]);
+            let err = reader.next().unwrap().unwrap_err();
+            assert!(matches!(err.root(), FastqError::SeqQualMismatch));
+            // the quality line
+            assert_eq!(err.line(), Some(4));
+            assert!(!err.is_terminal());
+            assert_eq!(reader.next().unwrap().unwrap().id(), b"r2");
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastqReaderState::Complete);
+        }
+
+        fn test_from_path() {
+            let path = init_path("resources/test_data/test.fastq");
+            assert!(FastqReader::from_path(path).unwrap().next().is_some());
+            let err = FastqReader::from_path(init_path("resources/test_data/missing.fq")).unwrap_err();
+            assert!(err.to_string().contains("missing.fq"));
+            assert!(matches!(err, LysoError::File { .. }));
+        }
+
+        fn test_error_lines() {
+            // r2 has a short quality line, r4 a mismatched '+' line, and the
+            // final record r6 is cut short
+            let path = init_path("resources/test_data/line_errors.fastq");
+            let lines = |reader: FastqReader<BufReader<File>>| {
+                reader
+                    .check_separator(true)
+                    .filter_map(|r| r.err().map(|e| e.line()))
+                    .collect::<Vec<_>>()
+            };
+            let expected = vec![Some(8), Some(15), Some(21)];
+            assert_eq!(lines(FastqReader::from_path(&path).unwrap()), expected);
+            for chunk in [1, 7, 64] {
+                let config = ReaderConfig {
+                    read_chunk_size: chunk,
+                    compact_threshold: 0,
+                    ..ReaderConfig::default()
+                };
+                let f = BufReader::new(File::open(&path).unwrap());
+                let reader = FastqReader::with_config(f, config);
+                assert_eq!(lines(reader), expected, "{chunk}-byte chunks");
+            }
+
+            let mut reader = FastqReader::from_path(&path).unwrap();
+            let err = reader.nth(1).unwrap().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("{}:8: sequence-quality length mismatch", path.display())
+            );
+            let f = BufReader::new(File::open(&path).unwrap());
+            let err = FastqReader::new(f).last().unwrap().unwrap_err();
+            assert_eq!(err.to_string(), "line 21: end of file error");
+            assert!(matches!(err.root(), FastqError::EofError));
+        }
+
+        fn test_non_utf8_header() {
+            // latin-1 e-acute in the id and description
+            let input = b"@r\xe9ad d\xe9sc\nACGT\n+\nIIII\n@ok x\nGG\n+\nII\n";
+            let recs = FastqReader::new(&input[..])
+                .collect::<Result<Vec<Record>, FastqError>>()
+                .unwrap();
+            assert_eq!(
+                (recs[0].id(), recs[0].desc()),
+                (&b"r\xe9ad"[..], Some(&b"d\xe9sc"[..]))
+            );
+            assert_eq!(recs[0].id_str_lossy(), "r\u{fffd}ad");
+            let mut out = Vec::new();
+            for rec in &recs {
+                rec.write_to(&mut out).unwrap();
+            }
+            assert_eq!(out, input);
+
+            let mut strict = FastqReader::new(&input[..]).strict_utf8(true);
+            let err = strict.next().unwrap().unwrap_err();
+            assert!(matches!(err.root(), FastqError::ValidationError { .. }));
+            assert!(err.to_string().contains("r\u{fffd}ad"));
+            assert_eq!(strict.next().unwrap().unwrap().id(), b"ok");
         }
     }
 
-    #[test]
-    #[should_panic]
-    fn test_bad_fq_panics() {
-        let fq_path = init_path("resources/test_data/corrupt.fastq");
-        let f = File::open(fq_path).unwrap();
-        let b = BufReader::new(f);
-        let reader = FastqReader::new(b);
-        for _ in reader {
-            continue;
+    /// Every result of `read_record_into`, rendered for comparison with the iterator
+    fn read_all_into<T: BufRead>(mut reader: FastqReader<T>) -> Vec<Result<Record, String>> {
+        let mut rec = Record::new();
+        let mut out = Vec::new();
+        while let Some(res) = reader.read_record_into(&mut rec) {
+            out.push(res.map(|_| rec.clone()).map_err(|e| e.to_string()));
         }
+        out
     }
 
-    #[test]
-    fn test_get_fields() {
-        let fq_path = init_path("resources/test_data/test.fastq");
-        let f = File::open(fq_path).unwrap();
-        let b = BufReader::new(f);
-        let mut reader = FastqReader::new(b);
-        let record = reader.next().expect("bad record!").expect("bad record!");
+    for_each_parser! {
+        fn test_read_into_matches_iterator() {
+            for fixture in ["test.fastq", "trunc.fastq", "corrupt.fastq"] {
+                let path = init_path(&format!("resources/test_data/{fixture}"));
+                let expected = FastqReader::from_path(&path)
+                    .unwrap()
+                    .map(|r| r.map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>();
+                let reused = read_all_into(FastqReader::from_path(&path).unwrap());
+                assert_eq!(reused, expected, "{fixture}");
+            }
 
-        assert!(record.id == "SRR22092847.1.1");
-        assert!(record.desc == "1 length=37");
-        assert!(record.qual == "F#FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF");
-        assert!(record.seq == "GNTTAAAGCACATAAAGACAAATCGCTCCAGGGCAAA");
-    }
+            let input = b"@r\xe9ad d\xe9sc\nACGT\n+\nIIII\n@r1\nACGT\n+r1\nFF\n@ok x\nGG\n+\nII\n";
+            let expected = FastqReader::new(&input[..])
+                .strict_utf8(true)
+                .map(|r| r.map_err(|e| e.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                read_all_into(FastqReader::new(&input[..]).strict_utf8(true)),
+                expected
+            );
+            assert_eq!(expected.iter().filter(|r| r.is_err()).count(), 2);
+        }
 
-    #[test]
-    fn test_read_checked_fq() {
-        let fq_path = init_path("resources/test_data/test.fastq");
-        let f = File::open(fq_path).unwrap();
-        let b = BufReader::new(f);
-        let mut reader = FastqReader::new(b);
-        assert!(reader.all(|x| x.is_ok()));
+        fn test_read_into_reuses_capacity() {
+            let mut reader =
+                FastqReader::new(&b"@long one\nACGTACGT\n+\nIIIIIIII\n@r2\nAC\n+\nII\n"[..]);
+            let mut rec = Record::new();
+            reader.read_record_into(&mut rec).unwrap().unwrap();
+            let seq_ptr = rec.seq().as_ptr();
+            reader.read_record_into(&mut rec).unwrap().unwrap();
+            assert_eq!(
+                (rec.id(), rec.desc(), rec.seq()),
+                (&b"r2"[..], None, "AC")
+            );
+            assert_eq!(rec.seq().as_ptr(), seq_ptr);
+            assert!(reader.read_record_into(&mut rec).is_none());
+            assert_eq!(reader.state(), FastqReaderState::Complete);
+        }
     }
 
-    #[test]
-    fn test_bad_fq_is_recoverable() {
-        let fq_path = init_path("resources/test_data/trunc.fastq");
-        let f = File::open(fq_path).unwrap();
-        let b = BufReader::new(f);
-        let reader = FastqReader::new(b);
-        for record in reader {
-            if let Some(r) = record.into() {
-                match r {
-                    Ok(_) => {}
-                    Err(e) => eprintln!("{}", e),
+    for_each_parser! {
+        fn test_one_byte_reads() {
+            for fixture in ["test.fastq", "trunc.fastq", "corrupt.fastq"] {
+                let path = init_path(&format!("resources/test_data/{fixture}"));
+                let f = BufReader::new(File::open(&path).unwrap());
+                let expected = FastqReader::new(f)
+                    .map(|r| r.map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>();
+                let f = BufReader::new(File::open(&path).unwrap());
+                let trickled = FastqReader::new(OneByte(f))
+                    .map(|r| r.map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>();
+                assert_eq!(trickled, expected, "{fixture}");
+            }
+
+            let mut reader = FastqReader::new(OneByte(&b"@r1\nACGT\n+\nFFFF\n@r2\nAC"[..]));
+            assert!(reader.next().unwrap().is_ok());
+            assert!(matches!(
+                reader.next().unwrap().unwrap_err().root(),
+                FastqError::EofError
+            ));
+            assert!(reader.next().is_none());
+        }
+
+        fn test_small_read_chunks() {
+            // refills end mid-line, so every record passes through Incomplete
+            for fixture in [
+                "ambiguous_qual.fastq",
+                "corrupt.fastq",
+                "crlf.fastq",
+                "no_final_newline.fastq",
+                "phred64.fastq",
+                "small.fastq",
+                "test.fastq",
+                "trunc.fastq",
+            ] {
+                let path = init_path(&format!("resources/test_data/{fixture}"));
+                let expected = FastqReader::new(BufReader::new(File::open(&path).unwrap()))
+                    .map(|r| r.map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>();
+                for chunk in [1, 7, 64] {
+                    let config = ReaderConfig {
+                        read_chunk_size: chunk,
+                        compact_threshold: 0,
+                        ..ReaderConfig::default()
+                    };
+                    let f = BufReader::new(File::open(&path).unwrap());
+                    let chunked = FastqReader::with_config(f, config)
+                        .map(|r| r.map_err(|e| e.to_string()))
+                        .collect::<Vec<_>>();
+                    assert_eq!(chunked, expected, "{fixture}, {chunk}-byte chunks");
                 }
             }
         }
+
+        fn test_max_record_size() {
+            let input = b"@r1\nACGT\n+\nFFFF\n@r2\nACGTACGT\n+\nFFFFFFFF\n";
+            // r1 is 16 bytes, r2 24
+            for (limit, chunk) in [(16, usize::MAX), (16, 4), (20, 64)] {
+                let config = ReaderConfig {
+                    max_record_size: limit,
+                    read_chunk_size: chunk,
+                    ..ReaderConfig::default()
+                };
+                let mut reader = FastqReader::with_config(&input[..], config);
+                assert_eq!(reader.next().unwrap().unwrap().id(), b"r1");
+                assert!(matches!(
+                reader.next().unwrap().unwrap_err().root(),
+                FastqError::RecordTooLarge { limit: l } if *l == limit
+            ));
+                assert!(reader.next().is_none());
+                assert_eq!(reader.state(), FastqReaderState::Failed);
+            }
+            let config = ReaderConfig {
+                max_record_size: 15,
+                ..ReaderConfig::default()
+            };
+            let mut reader = FastqReader::with_config(&input[..], config);
+            assert!(matches!(
+                reader.next().unwrap().unwrap_err().root(),
+                FastqError::RecordTooLarge { limit: 15 }
+            ));
+        }
+
+        fn test_check_separator() {
+            let input = b"@r1 a\r\nAC\r\n+\r\nII\r\n@r2 b\nAC\n+r2 b\nII\n@r3 c\nAC\n+r2 b\nII\n@r4\nAC\n+\nII\n";
+            let ids = |reader: FastqReader<&[u8]>| {
+                reader
+                    .map(|r| r.map(|r| r.id().to_vec()).map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>()
+            };
+            assert!(ids(FastqReader::new(&input[..])).iter().all(|r| r.is_ok()));
+
+            let checked = ids(FastqReader::new(&input[..]).check_separator(true));
+            assert_eq!(checked.len(), 4);
+            assert_eq!(
+                checked[2],
+                Err(String::from(
+                    "line 11: record r3: '+' line does not repeat the header: r2 b"
+                ))
+            );
+            assert_eq!(checked[3], Ok(b"r4".to_vec()));
+
+            let mut reader = FastqReader::new(&input[..]).check_separator(true);
+            let err = reader.nth(2).unwrap().unwrap_err();
+            assert!(matches!(err.root(), FastqError::SeparatorMismatch { .. }));
+            assert!(!err.is_terminal());
+        }
+
+        fn test_validator() {
+            use lyso_common::validate::{MaxHomopolymer, Severity, ValidationReport, Violation};
+
+            // a custom rule next to a stock one: no quality below Q3
+            let validator = crate::validator()
+                .rule(MaxHomopolymer(30), Severity::Error)
+                .rule_fn("min_qual", Severity::Error, |r: &Record| {
+                    let i = r.qual().bytes().position(|q| q < b'$')?;
+                    Some(Violation::new("quality below 3").at(i))
+                });
+            let validator = Arc::new(validator);
+            let poly_a = format!("@polyA\nC{}\n+\n{}\n", "A".repeat(31), "I".repeat(32));
+            let input = format!(
+                "@a\nACGT\n+\nIIII\n{poly_a}@gap\nAC-T\n+\nIIII\n@dark\nACGT\n+\nII#I\n@b\nAC\n+\nII\n"
+            );
+
+            let results = FastqReader::new(input.as_bytes())
+                .validator(Arc::clone(&validator))
+                .map(|r| r.map(|r| r.id().to_vec()).map_err(|e| e.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                results,
+                [
+                    Ok(b"a".to_vec()),
+                    Err("line 5: record polyA fails homopolymer: run of 31 A at base 2".into()),
+                    Err("line 9: record gap fails alphabet: base '-' is not allowed at base 3".into()),
+                    Err("line 13: record dark fails min_qual: quality below 3 at base 3".into()),
+                    Ok(b"b".to_vec()),
+                ]
+            );
+            // raw, scanned and reused reads are checked the same way
+            let mut reader = FastqReader::new(input.as_bytes()).validator(Arc::clone(&validator));
+            assert_eq!(reader.raw_records().filter(Result::is_err).count(), 3);
+            let mut reader = FastqReader::new(input.as_bytes()).validator(Arc::clone(&validator));
+            let err = reader.scan_records(|_| ControlFlow::Continue(())).unwrap_err();
+            assert!(matches!(err.root(), FastqError::RuleViolation(v) if v.rule == "homopolymer"));
+            assert!(!err.is_terminal());
+            let mut reader = FastqReader::new(input.as_bytes()).validator(Arc::clone(&validator));
+            let mut rec = Record::default();
+            let mut failed = 0;
+            while let Some(res) = reader.read_record_into(&mut rec) {
+                failed += usize::from(res.is_err());
+            }
+            assert_eq!(failed, 3);
+            // rules see the record as returned, here without its qualities
+            let reader = FastqReader::new(input.as_bytes())
+                .without_quality()
+                .validator(Arc::clone(&validator));
+            assert_eq!(reader.filter(Result::is_err).count(), 2);
+
+            // the same validator, summarized as `lyso check` does
+            let mut report = ValidationReport::new(&validator);
+            for rec in FastqReader::new(input.as_bytes()) {
+                report.add(&validator.check(&rec.unwrap()));
+            }
+            assert_eq!((report.records, report.total(Severity::Error)), (5, 3));
+        }
+
+        fn test_final_line_without_newline() {
+            for input in [
+                &b"@r1\nACGT\n+\nFFFF\n@r2\nAC\n+\nFF"[..],
+                b"@r1\r\nACGT\r\n+\r\nFFFF\r\n@r2\r\nAC\r\n+\r\nFF",
+            ] {
+                let recs = FastqReader::new(OneByte(input))
+                    .collect::<Result<Vec<Record>, FastqError>>()
+                    .unwrap();
+                assert_eq!(recs.len(), 2);
+                assert_eq!((recs[1].seq(), recs[1].qual()), ("AC", "FF"));
+            }
+            // a record cut short is still truncated
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+"[..]);
+            assert!(matches!(
+                reader.next().unwrap().unwrap_err().root(),
+                FastqError::EofError
+            ));
+        }
+
+        fn test_peek_record() {
+            let path = init_path("resources/test_data/small.fastq");
+            let mut reader = FastqReader::from_path(&path).unwrap();
+            let peeked = reader.peek_record().unwrap().as_ref().unwrap().clone();
+            assert_eq!(reader.peek_record().unwrap().as_ref().unwrap(), &peeked);
+            assert_eq!(reader.next().unwrap().unwrap(), peeked);
+            assert_eq!(peeked.id(), b"SRR22092847.1.1");
+
+            let mut rec = Record::new();
+            let second = reader.peek_record().unwrap().as_ref().unwrap().clone();
+            reader.read_record_into(&mut rec).unwrap().unwrap();
+            assert_eq!(rec, second);
+
+            assert_eq!(reader.by_ref().count(), 4);
+            assert!(reader.peek_record().is_none());
+            assert!(reader.peek_record().is_none());
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastqReaderState::Complete);
+        }
+
+        fn test_raw_records() {
+            for fixture in ["crlf.fastq", "no_final_newline.fastq", "test.fastq", "small.fastq"] {
+                let input = std::fs::read(init_path(&format!("resources/test_data/{fixture}"))).unwrap();
+                let ids = FastqReader::new(&input[..])
+                    .map(|r| r.unwrap().id().to_vec())
+                    .collect::<Vec<_>>();
+                let raw = FastqReader::new(OneByte(&input[..]))
+                    .raw_records()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                assert_eq!(raw.iter().map(|r| r.id()).collect::<Vec<_>>(), ids, "{fixture}");
+                let out = raw.into_iter().map(|r| r.bytes).collect::<Vec<_>>();
+                assert!(out.concat() == input, "{fixture} is not passed through unchanged");
+            }
+
+            let input = b"@r1 a\r\nAC\r\n+r1 a\r\nII\r\n@r2\nGT\n+\nII";
+            let mut reader = FastqReader::new(&input[..]);
+            assert_eq!(reader.peek_record().unwrap().as_ref().unwrap().id(), b"r1");
+            let first = reader.read_raw_record().unwrap().unwrap();
+            assert_eq!(first.bytes, b"@r1 a\r\nAC\r\n+r1 a\r\nII\r\n");
+            assert_eq!(first.id(), b"r1");
+            assert_eq!(reader.raw_records().next().unwrap().unwrap().bytes, b"@r2\nGT\n+\nII");
+            assert!(reader.read_raw_record().is_none());
+
+            // the record and its bytes together, peeked or not
+            let mut reader = FastqReader::new(&input[..]);
+            reader.peek_record();
+            let (rec, raw) = reader.read_record_and_raw().unwrap().unwrap();
+            assert_eq!((rec.id(), &raw.bytes[..]), (&b"r1"[..], &b"@r1 a\r\nAC\r\n+r1 a\r\nII\r\n"[..]));
+            let (rec, raw) = reader.read_record_and_raw().unwrap().unwrap();
+            assert_eq!((rec.seq(), &raw.bytes[..]), ("GT", &b"@r2\nGT\n+\nII"[..]));
+            assert!(reader.read_record_and_raw().is_none());
+
+            // errors are those of the full reader
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+\nFF\n@r2\nAC\n+\nFF\n"[..]);
+            assert!(matches!(
+                reader.read_raw_record().unwrap().unwrap_err().root(),
+                FastqError::SeqQualMismatch
+            ));
+            assert_eq!(reader.read_raw_record().unwrap().unwrap().id(), b"r2");
+            let mut reader = FastqReader::new(&b"@r\xff\nAC\n+\nII\n"[..]).strict_utf8(true);
+            assert!(matches!(
+                reader.read_raw_record().unwrap().unwrap_err().root(),
+                FastqError::ValidationError { .. }
+            ));
+        }
+
+        fn test_peek_at_eof_keeps_state() {
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+\nFFFF\n"[..]);
+            assert!(reader.next().unwrap().is_ok());
+            assert!(reader.peek_record().is_none());
+            assert_eq!(reader.state(), FastqReaderState::Reading);
+            assert!(reader.next().is_none());
+            assert_eq!(reader.state(), FastqReaderState::Complete);
+        }
+
+        fn test_peek_keeps_errors() {
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+\nFF\n@r2\nAC\n+\nFF\n"[..]);
+            assert!(matches!(
+                reader.peek_record().unwrap().as_ref().unwrap_err().root(),
+                FastqError::SeqQualMismatch
+            ));
+            assert!(matches!(
+                reader.next().unwrap().unwrap_err().root(),
+                FastqError::SeqQualMismatch
+            ));
+            assert_eq!(reader.next().unwrap().unwrap().id(), b"r2");
+
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+\nFFFF\nxx\n"[..]);
+            assert!(reader.next().unwrap().is_ok());
+            assert!(matches!(
+                reader.peek_record().unwrap().as_ref().unwrap_err().root(),
+                FastqError::ParseError
+            ));
+            assert_eq!(reader.state(), FastqReaderState::Reading);
+            assert!(reader.next().unwrap().is_err());
+            assert_eq!(reader.state(), FastqReaderState::Failed);
+        }
+
+        fn test_record_bytes() {
+            let input = b"@r1\nACGT\n+\nFFFF\n@r2 d\nAC\n+\nFF\n";
+            let mut reader = FastqReader::new(&input[..]);
+            reader.next().unwrap().unwrap();
+            assert_eq!(reader.record_bytes(), 16);
+            assert_eq!(reader.by_ref().count(), 1);
+            assert_eq!(reader.record_bytes(), input.len() as u64);
+
+            let path = init_path("resources/test_data/small.fastq");
+            let mut reader = FastqReader::from_path(&path).unwrap();
+            assert_eq!(reader.skip_records(10).unwrap(), 6);
+            assert_eq!(reader.record_bytes(), std::fs::metadata(&path).unwrap().len());
+        }
+
+        fn test_skip_records() {
+            let path = init_path("resources/test_data/small.fastq");
+            let expected = FastqReader::from_path(&path)
+                .unwrap()
+                .collect::<Result<Vec<Record>, FastqError>>()
+                .unwrap();
+            let mut reader = FastqReader::from_path(&path).unwrap();
+            assert_eq!(reader.skip_records(3).unwrap(), 3);
+            assert_eq!(reader.next().unwrap().unwrap(), expected[3]);
+
+            // a peeked record is skipped first
+            let mut reader = FastqReader::from_path(&path).unwrap();
+            reader.peek_record();
+            assert_eq!(reader.skip_records(1).unwrap(), 1);
+            assert_eq!(reader.next().unwrap().unwrap(), expected[1]);
+            assert_eq!(reader.skip_records(10).unwrap(), 4);
+            assert!(reader.next().is_none());
+
+            let mut reader = FastqReader::new(&b"@r1\nACGT\n+\nFFFF\nxx\n"[..]);
+            assert!(matches!(
+                reader.skip_records(3).unwrap_err().root(),
+                FastqError::ParseError
+            ));
+        }
+
+        fn test_without_quality() {
+            // ids, sequences and errors, which must not depend on the mode
+            fn summary(reader: FastqReader<&[u8]>) -> Vec<String> {
+                reader
+                    .map(|r| match r {
+                        Ok(rec) => format!("{:?} {:?} {}", rec.id(), rec.desc(), rec.seq()),
+                        Err(e) => format!("{e:?}"),
+                    })
+                    .collect()
+            }
+            let mut inputs = ["test.fastq", "trunc.fastq", "corrupt.fastq", "ambiguous_qual.fastq"]
+                .map(|f| std::fs::read(init_path(&format!("resources/test_data/{f}"))).unwrap())
+                .to_vec();
+            for case in [
+                &b"@r1\nACGT\n+\nFF\n@r2\nAC\n+\nFF\n"[..],
+                b"@r1\nACGT\n+\n",
+                b"@r1\nACGT\nFFFF\n",
+                b"@r1\nACGT\n+\nFF\xffF\n",
+                b"@r1\nACGT\n+\nFFFF",
+                b"@r1\nACGT\n+r2\nFFFF\n",
+            ] {
+                inputs.push(case.to_vec());
+            }
+            for input in &inputs {
+                let full = summary(FastqReader::new(&input[..]));
+                assert_eq!(summary(FastqReader::new(&input[..]).without_quality()), full);
+            }
+
+            let mut reader = FastqReader::new(&inputs[0][..]).without_quality();
+            let mut rec = Record::new();
+            while let Some(res) = reader.read_record_into(&mut rec) {
+                res.unwrap();
+                assert!(rec.qual().is_empty());
+                assert_eq!(rec.qual.capacity(), 0);
+            }
+        }
+
+        fn test_scan() {
+            use crate::index::FastqIndex;
+
+            let path = init_path("resources/test_data/test.fastq");
+            let mut offsets = Vec::new();
+            let visited = FastqReader::from_path(&path)
+                .unwrap()
+                .scan_records(|rec| {
+                    offsets.push((rec.id.to_vec(), rec.offset));
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            let mut fq = BufReader::new(File::open(&path).unwrap());
+            let index = FastqIndex::from_fastq_file(&mut fq, Default::default()).unwrap();
+            assert_eq!(visited as usize, index.len());
+            for (n, (id, offset)) in offsets.iter().enumerate() {
+                assert_eq!(id, index.nth_entry(n).unwrap().name());
+                assert_eq!(Some(*offset), index.record_start(n));
+            }
+
+            // breaking leaves the reader at the next record, a peeked one included
+            let expected = FastqReader::from_path(&path)
+                .unwrap()
+                .collect::<Result<Vec<Record>, FastqError>>()
+                .unwrap();
+            let mut reader = FastqReader::from_path(&path).unwrap();
+            let mut seen = 0;
+            let visited = reader.scan_records(|rec| {
+                assert_eq!(rec.seq, expected[seen].seq().as_bytes());
+                assert_eq!(rec.qual, expected[seen].qual().as_bytes());
+                seen += 1;
+                match seen {
+                    10 => ControlFlow::Break(()),
+                    _ => ControlFlow::Continue(()),
+                }
+            });
+            assert_eq!(visited.unwrap(), 10);
+            assert_eq!(reader.next().unwrap().unwrap(), expected[10]);
+            reader.peek_record();
+            let mut first = None;
+            reader
+                .scan_records(|rec| {
+                    first = Some((rec.id.to_vec(), rec.offset));
+                    ControlFlow::Break(())
+                })
+                .unwrap();
+            assert_eq!(first, Some(offsets[11].clone()));
+            assert_eq!(reader.next().unwrap().unwrap(), expected[12]);
+
+            // an error ends the scan, and a local one can be read past
+            let mut reader = FastqReader::new(&b"@a\nAC\n+\nI\n@b\nGT\n+\nII\n"[..]);
+            assert!(matches!(
+                reader.scan_records(|_| ControlFlow::Continue(())).unwrap_err().root(),
+                FastqError::SeqQualMismatch
+            ));
+            assert_eq!(reader.scan_records(|_| ControlFlow::Continue(())).unwrap(), 1);
+        }
     }
 
+    #[cfg(feature = "nom")]
     #[test]
-    fn test_corrupt_fq_is_recoverable() {
-        let fq_path = init_path("resources/test_data/corrupt.fastq");
-        let f = File::open(fq_path).unwrap();
-        let b = BufReader::new(f);
-        let reader = FastqReader::new(b);
-        for record in reader {
-            if let Some(r) = record.into() {
-                match r {
-                    Ok(_) => {}
-                    Err(e) => eprintln!("{}", e),
-                }
-            }
+    fn test_parsers_agree_on_fixtures() {
+        use crate::parser::{handwritten, harness::with_parser, nom_parser};
+
+        for fixture in ["test.fastq", "trunc.fastq", "corrupt.fastq"] {
+            let path = init_path(&format!("resources/test_data/{fixture}"));
+            let read = || {
+                FastqReader::from_path(&path)
+                    .unwrap()
+                    .map(|r| r.map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>()
+            };
+            let (mut nom, mut handwritten) = (Vec::new(), Vec::new());
+            with_parser(nom_parser::parse_record, || nom = read());
+            with_parser(handwritten::parse_record, || handwritten = read());
+            assert!(!nom.is_empty());
+            assert_eq!(handwritten, nom, "{fixture}");
         }
     }
 }
@@ -0,0 +1,207 @@
+//! Converting quality strings between Phred offsets
+//!
+//! Phred+64 data from Illumina 1.3-1.7 still turns up. Scores are carried over
+//! unchanged, except Solexa-era negative scores, which become 0, and scores with
+//! no printable character in the target encoding, which are clamped to '~'.
+
+pub use lyso_common::codec::{guess_phred_encoding, PhredEncoding};
+
+use crate::{FastqError, Record};
+
+/// Highest quality character, in either encoding
+const MAX_CHAR: u8 = b'~';
+
+impl Record {
+    /// Rewrite the quality string from encoding `from` to `to`
+    ///
+    /// Fails on a character that cannot occur in `from`, leaving the record
+    /// unchanged.
+    pub fn reencode_quality(
+        &mut self,
+        from: PhredEncoding,
+        to: PhredEncoding,
+    ) -> Result<(), FastqError> {
+        let (Some(lowest), Some(_)) = (from.min_char(), to.min_char()) else {
+            return Err(FastqError::UnknownEncoding);
+        };
+        if let Some(found) = self
+            .qual
+            .chars()
+            .find(|c| !(char::from(lowest)..=char::from(MAX_CHAR)).contains(c))
+        {
+            return Err(FastqError::QualityEncoding {
+                found,
+                encoding: from,
+            });
+        }
+        if from == to {
+            return Ok(());
+        }
+        let (from, to) = (from as u8, to as u8);
+        // every character was checked to be ASCII
+        let qual = self
+            .qual
+            .bytes()
+            .map(|c| char::from((c.saturating_sub(from) + to).min(MAX_CHAR)))
+            .collect();
+        self.qual = qual;
+        Ok(())
+    }
+}
+
+/// Lowest and highest quality character in `records`, None if there are none
+///
+/// Stops at the first error. Pass `guess_phred_encoding(&[min, max])` the result
+/// to detect the encoding.
+pub fn quality_range<I>(records: I) -> Result<Option<(u8, u8)>, FastqError>
+where
+    I: IntoIterator<Item = Result<Record, FastqError>>,
+{
+    let mut range: Option<(u8, u8)> = None;
+    for rec in records {
+        for c in rec?.qual().bytes() {
+            range = Some(range.map_or((c, c), |(lo, hi)| (lo.min(c), hi.max(c))));
+        }
+    }
+    Ok(range)
+}
+
+/// Records with their quality re-encoded, see `reencode_records`
+pub struct Reencode<I> {
+    inner: I,
+    from: PhredEncoding,
+    to: PhredEncoding,
+    n: u64,
+}
+
+impl<I> Iterator for Reencode<I>
+where
+    I: Iterator<Item = Result<Record, FastqError>>,
+{
+    type Item = Result<Record, FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut rec = match self.inner.next()? {
+            Ok(rec) => rec,
+            Err(e) => return Some(Err(e)),
+        };
+        self.n += 1;
+        Some(match rec.reencode_quality(self.from, self.to) {
+            Ok(()) => Ok(rec),
+            Err(e) => Err(FastqError::AtRecord {
+                record: self.n,
+                source: Box::new(e),
+            }),
+        })
+    }
+}
+
+/// Re-encode every record of `records` from `from` to `to`
+///
+/// Errors from `reencode_quality` name the record by its 1-based number; reader
+/// errors pass through unchanged.
+pub fn reencode_records<I>(
+    records: I,
+    from: PhredEncoding,
+    to: PhredEncoding,
+) -> Reencode<I::IntoIter>
+where
+    I: IntoIterator<Item = Result<Record, FastqError>>,
+{
+    Reencode {
+        inner: records.into_iter(),
+        from,
+        to,
+        n: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::reader::FastqReader;
+
+    fn record(qual: &str) -> Record {
+        Record::from_parts("r", "N".repeat(qual.len()), qual).unwrap()
+    }
+
+    #[test]
+    fn test_reencode_quality() {
+        use PhredEncoding::*;
+        let mut rec = record(";?@Ah~");
+        rec.reencode_quality(Phred64, Phred33).unwrap();
+        // Solexa scores below 0 are clamped
+        assert_eq!(rec.qual(), "!!!\"I_");
+        rec.reencode_quality(Phred33, Phred64).unwrap();
+        assert_eq!(rec.qual(), "@@@Ah~");
+
+        // scores past 62 have no Phred+64 character
+        let mut rec = record("!I~");
+        rec.reencode_quality(Phred33, Phred64).unwrap();
+        assert_eq!(rec.qual(), "@h~");
+
+        let mut rec = record("IJ5");
+        let err = rec.reencode_quality(Phred64, Phred33).unwrap_err();
+        assert_eq!(err.to_string(), "quality character '5' cannot be Phred+64");
+        assert!(!err.is_terminal());
+        assert_eq!(rec.qual(), "IJ5");
+        assert!(rec.reencode_quality(Phred33, Phred33).is_ok());
+        assert!(matches!(
+            record("\u{e9}").reencode_quality(Phred33, Phred64),
+            Err(FastqError::QualityEncoding {
+                found: '\u{e9}',
+                ..
+            })
+        ));
+        assert!(matches!(
+            rec.reencode_quality(Unknown, Phred33),
+            Err(FastqError::UnknownEncoding)
+        ));
+    }
+
+    #[test]
+    fn test_phred64_file() {
+        // the expected file subtracts 31 from each quality character, with
+        // Solexa scores below 0 clamped to '!'
+        let input = fs::read("../resources/test_data/phred64.fastq").unwrap();
+        let expected = fs::read("../resources/test_data/phred64.phred33.fastq").unwrap();
+        let range = quality_range(FastqReader::new(&input[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            guess_phred_encoding(&[range.0, range.1]),
+            PhredEncoding::Phred64
+        );
+        let mut out = Vec::new();
+        let records = FastqReader::new(&input[..]);
+        for rec in reencode_records(records, PhredEncoding::Phred64, PhredEncoding::Phred33) {
+            rec.unwrap().write_to(&mut out).unwrap();
+        }
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_error_names_record() {
+        let fq = b"@a\nAC\n+\nhh\n@b\nAC\n+\nh#\n";
+        let mut recs = reencode_records(
+            FastqReader::new(&fq[..]),
+            PhredEncoding::Phred64,
+            PhredEncoding::Phred33,
+        );
+        assert_eq!(recs.next().unwrap().unwrap().qual(), "II");
+        let err = recs.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "record 2: quality character '#' cannot be Phred+64"
+        );
+        assert!(!err.is_terminal());
+
+        let ambiguous = b"@a\nAC\n+\n@I\n@b\nAC\n+\nBD\n@c\nA\n+\nH\n";
+        let range = quality_range(FastqReader::new(&ambiguous[..])).unwrap();
+        assert_eq!(range, Some((b'@', b'I')));
+        assert_eq!(guess_phred_encoding(b"@I"), PhredEncoding::Unknown);
+        assert_eq!(quality_range(FastqReader::new(&b""[..])).unwrap(), None);
+    }
+}
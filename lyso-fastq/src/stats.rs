@@ -0,0 +1,334 @@
+//! Per-cycle quality and composition profiles
+//!
+//! `CycleProfile` accumulates, for every read position ("cycle"), a histogram of
+//! Phred scores and base counts. Memory is O(max read length × 94) however many
+//! reads are added; quartiles are computed from the histograms, never from stored values.
+
+use std::fmt::{self, Display};
+
+use crate::{FastqError, Record};
+
+/// Number of representable Phred+33 scores ('!' through '~')
+pub const N_QUAL_BUCKETS: usize = 94;
+const PHRED_OFFSET: u8 = 33;
+
+/// Base composition columns, in order
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+type QualHist = [u64; N_QUAL_BUCKETS];
+
+/// Quality value at quantile `p` (0.0..=1.0) of a histogram
+///
+/// Uses the nearest-rank method: the smallest score whose cumulative count reaches
+/// `ceil(p * n)`, with rank at least 1. Returns None for an empty histogram.
+pub fn hist_quantile(hist: &QualHist, p: f64) -> Option<u8> {
+    let n = hist.iter().sum::<u64>();
+    if n == 0 {
+        return None;
+    }
+    let rank = ((p * n as f64).ceil() as u64).clamp(1, n);
+    let mut seen = 0;
+    hist.iter()
+        .position(|c| {
+            seen += c;
+            seen >= rank
+        })
+        .map(|q| q as u8)
+}
+
+/// Mean quality of a histogram, None if empty
+pub fn hist_mean(hist: &QualHist) -> Option<f64> {
+    let n = hist.iter().sum::<u64>();
+    if n == 0 {
+        return None;
+    }
+    let total = hist
+        .iter()
+        .enumerate()
+        .map(|(q, c)| q as u64 * c)
+        .sum::<u64>();
+    Some(total as f64 / n as f64)
+}
+
+/// Accumulated per-cycle statistics over a set of reads
+#[derive(Debug, Clone, Default)]
+pub struct CycleProfile {
+    n_reads: u64,
+    quals: Vec<QualHist>,
+    /// A, C, G, T, then everything else (counted as N)
+    bases: Vec<[u64; 5]>,
+}
+
+impl CycleProfile {
+    /// A profile of no reads
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add one read
+    ///
+    /// Cycles beyond the end of shorter reads are simply not counted, so each cycle
+    /// has its own denominator.
+    pub fn add(&mut self, rec: &Record) -> Result<(), FastqError> {
+        let (seq, qual) = (rec.seq().as_bytes(), rec.qual().as_bytes());
+        if seq.len() != qual.len() {
+            return Err(FastqError::SeqQualMismatch);
+        }
+        // validate first so a bad record leaves the profile untouched
+        if let Some(q) = qual
+            .iter()
+            .find(|q| !(PHRED_OFFSET..PHRED_OFFSET + N_QUAL_BUCKETS as u8).contains(q))
+        {
+            return Err(FastqError::InvalidQuality(char::from(*q)));
+        }
+        if seq.len() > self.quals.len() {
+            self.quals.resize(seq.len(), [0; N_QUAL_BUCKETS]);
+            self.bases.resize(seq.len(), [0; 5]);
+        }
+        for (i, (b, q)) in seq.iter().zip(qual).enumerate() {
+            self.quals[i][usize::from(q - PHRED_OFFSET)] += 1;
+            let base = BASES
+                .iter()
+                .position(|x| *x == b.to_ascii_uppercase())
+                .unwrap_or(4);
+            self.bases[i][base] += 1;
+        }
+        self.n_reads += 1;
+        Ok(())
+    }
+
+    /// Fold another profile into this one, e.g. from a parallel worker
+    pub fn merge(&mut self, other: &CycleProfile) {
+        if other.quals.len() > self.quals.len() {
+            self.quals.resize(other.quals.len(), [0; N_QUAL_BUCKETS]);
+            self.bases.resize(other.bases.len(), [0; 5]);
+        }
+        for (a, b) in self.quals.iter_mut().zip(&other.quals) {
+            a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+        }
+        for (a, b) in self.bases.iter_mut().zip(&other.bases) {
+            a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+        }
+        self.n_reads += other.n_reads;
+    }
+
+    /// Reads added so far
+    pub fn n_reads(&self) -> u64 {
+        self.n_reads
+    }
+
+    /// Length of the longest read seen
+    pub fn max_len(&self) -> usize {
+        self.quals.len()
+    }
+
+    /// Quality histogram for a 0-based cycle
+    pub fn qual_hist(&self, cycle: usize) -> Option<&QualHist> {
+        self.quals.get(cycle)
+    }
+
+    /// Read and base totals
+    pub fn summary(&self) -> ProfileSummary {
+        ProfileSummary {
+            reads: self.n_reads,
+            bases: self.quals.iter().flatten().sum(),
+            max_len: self.max_len(),
+        }
+    }
+
+    /// Summarize every cycle
+    pub fn cycles(&self) -> Vec<CycleStats> {
+        self.quals
+            .iter()
+            .zip(&self.bases)
+            .enumerate()
+            .map(|(i, (hist, bases))| {
+                let count = hist.iter().sum::<u64>();
+                let frac = |c: u64| c as f64 / count as f64;
+                CycleStats {
+                    cycle: i + 1,
+                    count,
+                    mean: hist_mean(hist).unwrap_or(0.0),
+                    q1: hist_quantile(hist, 0.25).unwrap_or(0),
+                    median: hist_quantile(hist, 0.5).unwrap_or(0),
+                    q3: hist_quantile(hist, 0.75).unwrap_or(0),
+                    a: frac(bases[0]),
+                    c: frac(bases[1]),
+                    g: frac(bases[2]),
+                    t: frac(bases[3]),
+                    n: frac(bases[4]),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Totals over all the reads of a `CycleProfile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProfileSummary {
+    /// Reads added
+    pub reads: u64,
+    /// Bases over all reads
+    pub bases: u64,
+    /// Length of the longest read
+    pub max_len: usize,
+}
+
+/// Summary of a single cycle
+///
+/// Base fractions are over the reads long enough to reach this cycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CycleStats {
+    /// 1-based read position
+    pub cycle: usize,
+    /// Reads covering this cycle
+    pub count: u64,
+    /// Mean Phred score
+    pub mean: f64,
+    /// First quartile of the Phred scores
+    pub q1: u8,
+    /// Median Phred score
+    pub median: u8,
+    /// Third quartile of the Phred scores
+    pub q3: u8,
+    /// Fraction of A
+    pub a: f64,
+    /// Fraction of C
+    pub c: f64,
+    /// Fraction of G
+    pub g: f64,
+    /// Fraction of T
+    pub t: f64,
+    /// Fraction of N (or any other non-ACGT symbol)
+    pub n: f64,
+}
+
+impl CycleStats {
+    /// Header row for the `Display` output
+    pub const TSV_HEADER: &'static str = "cycle\tcount\tmean\tq1\tmedian\tq3\tA\tC\tG\tT\tN";
+}
+
+/// One TSV row, matching `CycleStats::TSV_HEADER`
+impl Display for CycleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{:.2}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}",
+            self.cycle,
+            self.count,
+            self.mean,
+            self.q1,
+            self.median,
+            self.q3,
+            self.a,
+            self.c,
+            self.g,
+            self.t,
+            self.n
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: &str, qual: &str) -> Record {
+        Record::from_parts("r", seq, qual).unwrap()
+    }
+
+    fn hist(counts: &[(usize, u64)]) -> QualHist {
+        let mut h = [0; N_QUAL_BUCKETS];
+        for (q, c) in counts {
+            h[*q] = *c;
+        }
+        h
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let h = hist(&[(10, 1), (20, 1), (30, 1), (40, 1)]);
+        assert_eq!(hist_quantile(&h, 0.25), Some(10));
+        assert_eq!(hist_quantile(&h, 0.5), Some(20));
+        assert_eq!(hist_quantile(&h, 0.75), Some(30));
+        assert_eq!(hist_quantile(&h, 1.0), Some(40));
+        assert_eq!(hist_quantile(&h, 0.0), Some(10));
+        assert_eq!(hist_mean(&h), Some(25.0));
+    }
+
+    #[test]
+    fn test_quantiles_skewed() {
+        // 90 reads at Q37, 10 at Q2
+        let h = hist(&[(2, 10), (37, 90)]);
+        assert_eq!(hist_quantile(&h, 0.1), Some(2));
+        assert_eq!(hist_quantile(&h, 0.11), Some(37));
+        assert_eq!(hist_quantile(&h, 0.5), Some(37));
+        let single = hist(&[(93, 1)]);
+        assert_eq!(hist_quantile(&single, 0.25), Some(93));
+    }
+
+    #[test]
+    fn test_quantiles_empty() {
+        let h = [0; N_QUAL_BUCKETS];
+        assert_eq!(hist_quantile(&h, 0.5), None);
+        assert_eq!(hist_mean(&h), None);
+    }
+
+    #[test]
+    fn test_variable_lengths() {
+        let mut p = CycleProfile::new();
+        p.add(&record("ACGT", "IIII")).unwrap();
+        p.add(&record("AN", "#I")).unwrap();
+        assert_eq!(p.n_reads(), 2);
+        assert_eq!(p.max_len(), 4);
+        assert_eq!(
+            p.summary(),
+            ProfileSummary {
+                reads: 2,
+                bases: 6,
+                max_len: 4
+            }
+        );
+
+        let cycles = p.cycles();
+        assert_eq!(cycles[0].count, 2);
+        assert_eq!(cycles[0].a, 1.0);
+        assert_eq!(cycles[1].n, 0.5);
+        assert_eq!(cycles[0].q1, 2);
+        assert_eq!(cycles[0].q3, 40);
+        assert_eq!(cycles[3].count, 1);
+        assert_eq!(cycles[3].t, 1.0);
+        assert_eq!(
+            cycles[3].to_string(),
+            "4\t1\t40.00\t40\t40\t40\t0.0000\t0.0000\t0.0000\t1.0000\t0.0000"
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = CycleProfile::new();
+        a.add(&record("AC", "II")).unwrap();
+        let mut b = CycleProfile::new();
+        b.add(&record("GGG", "###")).unwrap();
+        a.merge(&b);
+        assert_eq!(a.n_reads(), 2);
+        assert_eq!(a.max_len(), 3);
+        assert_eq!(a.qual_hist(0).unwrap()[40], 1);
+        assert_eq!(a.qual_hist(0).unwrap()[2], 1);
+        assert_eq!(a.cycles()[2].g, 1.0);
+    }
+
+    #[test]
+    fn test_invalid_quality() {
+        let mut p = CycleProfile::new();
+        assert!(matches!(
+            p.add(&record("AC", "I ")),
+            Err(FastqError::InvalidQuality(' '))
+        ));
+        assert_eq!(p.n_reads(), 0);
+        assert_eq!(p.max_len(), 0);
+    }
+}
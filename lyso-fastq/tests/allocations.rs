@@ -0,0 +1,80 @@
+//! Allocation counts of the owned, reused and scanned record APIs, with and without quality
+//!
+//! Lives in its own test binary because it swaps in a counting global
+//! allocator, which other tests running in parallel would disturb.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lyso_common::synth::generate_fastq;
+use lyso_fastq::{reader::FastqReader, Record};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const N_RECORDS: usize = 10_000;
+
+/// Allocations made by `f`
+fn count_allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+#[test]
+fn reuse_allocates_less() {
+    let fq = generate_fastq(N_RECORDS, 150, 0);
+    let owned = count_allocations(|| {
+        for rec in FastqReader::new(&fq[..]) {
+            rec.unwrap();
+        }
+    });
+    let reused = count_allocations(|| {
+        let mut reader = FastqReader::new(&fq[..]);
+        let mut rec = Record::new();
+        while let Some(res) = reader.read_record_into(&mut rec) {
+            res.unwrap();
+        }
+    });
+    let without_quality = count_allocations(|| {
+        for rec in FastqReader::new(&fq[..]).without_quality() {
+            rec.unwrap();
+        }
+    });
+    let scanned = count_allocations(|| {
+        let mut bases = 0;
+        let visited = FastqReader::new(&fq[..]).scan_records(|rec| {
+            bases += rec.seq.len();
+            ControlFlow::Continue(())
+        });
+        assert_eq!(visited.unwrap() as usize, N_RECORDS);
+        assert_eq!(bases, N_RECORDS * 150);
+    });
+    // several fields per owned record; the reused record only grows a few times
+    assert!(owned >= 3 * N_RECORDS, "{owned}");
+    assert!(reused < 100, "{reused}");
+    assert!(scanned < 100, "{scanned}");
+    // one fewer per record: the quality string
+    assert_eq!(without_quality, owned - N_RECORDS);
+}
@@ -0,0 +1,42 @@
+//! Runs the programs in examples/ against the test data and checks their exit codes
+
+#[path = "../../resources/run_example.rs"]
+mod run_example;
+
+use run_example::run;
+
+#[test]
+fn test_fastq_filter() {
+    let out = run(
+        "fastq_filter",
+        &["../resources/test_data/small.fastq", "20"],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(out.stderr, b"kept 6 of 6 reads\n");
+    let out = run(
+        "fastq_filter",
+        &["../resources/test_data/small.fastq", "60"],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    assert!(out.stdout.is_empty());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out.fastq");
+    let out = run(
+        "fastq_filter",
+        &[
+            "../resources/test_data/small.fastq",
+            "0",
+            path.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 24);
+
+    assert_eq!(run("fastq_filter", &[]).status.code(), Some(2));
+    let out = run(
+        "fastq_filter",
+        &["../resources/test_data/missing.fastq", "20"],
+    );
+    assert_eq!(out.status.code(), Some(1));
+}
@@ -0,0 +1,37 @@
+//! BAM to FASTQ to quality-filtered FASTA, across the format crates
+//!
+//! Usage: bam_to_filtered_fasta <in.bam> <min-mean-qual>
+//!
+//! Each primary alignment is turned back into its sequenced read as `lyso
+//! bam2fq` does, skipping secondary and supplementary ones like it, reads below
+//! the mean quality are dropped, and the rest are written to stdout as FASTA.
+
+use std::error::Error;
+use std::io::stdout;
+use std::process::exit;
+
+use lyso::bam2fq::to_fastq;
+use lyso::common::filter::MinMeanQuality;
+use lyso::prelude::*;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<String>>();
+    if args.len() != 3 {
+        eprintln!("usage: bam_to_filtered_fasta <in.bam> <min-mean-qual>");
+        exit(2);
+    }
+    let filter = MinMeanQuality(args[2].parse()?);
+    let mut out = fasta::Writer::new(stdout().lock());
+    for rec in bam::Reader::from_path(&args[1])? {
+        let rec = rec?;
+        if !rec.is_primary() {
+            continue;
+        }
+        let read = to_fastq(&rec, true)?;
+        if filter.test(&read) {
+            out.write_record(&fasta::Record::from_parts(read.id(), read.seq()))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
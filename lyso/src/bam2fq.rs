@@ -0,0 +1,157 @@
+//! BAM records as FASTQ, as written by `lyso bam2fq`
+//!
+//! Follows `samtools fastq`: reads aligned to the reverse strand are reverse
+//! complemented back to their sequenced orientation, and a record without QUAL
+//! gets `DEFAULT_QUAL` for every base.
+
+use std::fmt::{self, Display};
+
+use lyso_common::search::reverse_complement;
+
+use crate::{bam, fastq};
+
+/// Phred score given to bases of records without QUAL, as in `samtools fastq -v`
+pub const DEFAULT_QUAL: u8 = 1;
+
+/// A record that cannot be written as a FASTQ read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bam2FqError {
+    /// SEQ is `*`, so there is no read to write
+    MissingSeq(String),
+    /// SEQ has `=` bases, which only the reference they match can resolve
+    RefBase(String),
+    /// QUAL and SEQ are of different lengths
+    QualLength {
+        name: String,
+        seq: usize,
+        qual: usize,
+    },
+}
+
+impl Display for Bam2FqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bam2FqError::MissingSeq(name) => write!(f, "record {name} has no SEQ"),
+            Bam2FqError::RefBase(name) => write!(
+                f,
+                "record {name} has '=' bases, which need the reference to resolve"
+            ),
+            Bam2FqError::QualLength { name, seq, qual } => {
+                write!(f, "record {name} has {seq} bases but {qual} quality scores")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Bam2FqError {}
+
+/// `rec` as a FASTQ record in sequenced orientation
+///
+/// With `suffix`, "/1" or "/2" is appended to the name of a read with the READ1
+/// or READ2 flag (but not both). Scores above 93 are written as '~'.
+pub fn to_fastq(rec: &bam::Record, suffix: bool) -> Result<fastq::Record, Bam2FqError> {
+    let name = || rec.read_name_str_lossy().into_owned();
+    let bases = rec.seq();
+    if bases.is_empty() {
+        return Err(Bam2FqError::MissingSeq(name()));
+    }
+    if bases.contains(&b'=') {
+        return Err(Bam2FqError::RefBase(name()));
+    }
+    let mut id = rec.read_name().to_vec();
+    if suffix {
+        match (rec.is_read1(), rec.is_read2()) {
+            (true, false) => id.extend_from_slice(b"/1"),
+            (false, true) => id.extend_from_slice(b"/2"),
+            _ => {}
+        }
+    }
+    let mut qual = match rec.qual() {
+        Some(q) if q.len() != bases.len() => {
+            return Err(Bam2FqError::QualLength {
+                name: name(),
+                seq: bases.len(),
+                qual: q.len(),
+            })
+        }
+        Some(q) => q
+            .iter()
+            .map(|q| char::from(q.min(&93) + 33))
+            .collect::<String>(),
+        None => char::from(DEFAULT_QUAL + 33)
+            .to_string()
+            .repeat(bases.len()),
+    };
+    let seq = if rec.is_reverse() {
+        qual = qual.chars().rev().collect();
+        reverse_complement(bases)
+    } else {
+        bases.to_vec()
+    };
+    // SEQ decodes to ASCII IUPAC codes, and QUAL was checked to match it
+    Ok(
+        fastq::Record::from_parts(id, String::from_utf8_lossy(&seq), qual)
+            .expect("SEQ and QUAL lengths match"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::builder::RecordBuilder;
+    use crate::bam::flags::{PAIRED, READ1, READ2, REVERSE};
+
+    #[test]
+    fn test_to_fastq() {
+        let rec = RecordBuilder::new("r")
+            .flag(PAIRED | READ2 | REVERSE)
+            .seq(b"AACGN")
+            .qual(vec![0, 10, 20, 30, 100])
+            .build();
+        assert_eq!(
+            to_fastq(&rec, true).unwrap().to_string(),
+            "@r/2\nNCGTT\n+\n~?5+!\n"
+        );
+        assert_eq!(to_fastq(&rec, false).unwrap().id(), b"r");
+
+        let rec = RecordBuilder::new("r")
+            .flag(PAIRED | READ1)
+            .seq(b"ACG")
+            .build();
+        assert_eq!(
+            to_fastq(&rec, true).unwrap().to_string(),
+            "@r/1\nACG\n+\n\"\"\"\n"
+        );
+        let rec = RecordBuilder::new("r")
+            .flag(READ1 | READ2)
+            .seq(b"A")
+            .build();
+        assert_eq!(to_fastq(&rec, true).unwrap().to_string(), "@r\nA\n+\n\"\n");
+    }
+
+    #[test]
+    fn test_unwritable_records() {
+        let rec = RecordBuilder::new("r").flag(READ1).build();
+        assert_eq!(
+            to_fastq(&rec, true),
+            Err(Bam2FqError::MissingSeq(String::from("r")))
+        );
+        let rec = RecordBuilder::new("r").seq(b"AC=T").build();
+        assert_eq!(
+            to_fastq(&rec, true).unwrap_err().to_string(),
+            "record r has '=' bases, which need the reference to resolve"
+        );
+        let rec = RecordBuilder::new("r")
+            .seq(b"ACGT")
+            .qual(vec![30, 30])
+            .build();
+        assert_eq!(
+            to_fastq(&rec, true),
+            Err(Bam2FqError::QualLength {
+                name: String::from("r"),
+                seq: 4,
+                qual: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! FASTQ records as unaligned BAM, as written by `lyso fq2bam`
+//!
+//! Reads become unmapped, unplaced records as `samtools import` writes them:
+//! FLAG 4, MAPQ 0, no CIGAR and no mate. Bases are upper-cased, since BAM stores
+//! them that way, and must be IUPAC codes; quality characters must be Phred+33.
+//! `bam2fq::to_fastq` turns such a record back into the read, without its
+//! description.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::bam::builder::RecordBuilder;
+use crate::bam::flags::UNMAPPED;
+use crate::{bam, fastq};
+
+/// The longest read name BAM can store, leaving a byte for its NUL
+pub const MAX_NAME_LEN: usize = 254;
+
+/// What `to_bam` does with a read's description
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DescPolicy {
+    /// Leave it out
+    Drop,
+    /// Keep it whole in a CO tag
+    #[default]
+    Comment,
+    /// Read its `TAG:TYPE:VALUE` words, such as the BC and RX tags `lyso barcode`
+    /// appends, as aux fields; any other words go into a CO tag
+    Tags,
+}
+
+impl FromStr for DescPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(DescPolicy::Drop),
+            "comment" => Ok(DescPolicy::Comment),
+            "tags" => Ok(DescPolicy::Tags),
+            _ => Err(format!(
+                "invalid description policy '{s}', expected drop, comment or tags"
+            )),
+        }
+    }
+}
+
+impl Display for DescPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescPolicy::Drop => write!(f, "drop"),
+            DescPolicy::Comment => write!(f, "comment"),
+            DescPolicy::Tags => write!(f, "tags"),
+        }
+    }
+}
+
+/// A read that cannot be stored as a BAM record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fq2BamError {
+    /// A read with a zero-length name
+    EmptyName,
+    /// A name longer than `MAX_NAME_LEN` bytes
+    NameTooLong { name: String, len: usize },
+    /// A name containing a NUL byte, which BAM uses as the terminator
+    NulInName(String),
+    /// A base BAM has no code for, or `=`, which means "same as the
+    /// reference" and so has no meaning in an unmapped record
+    InvalidBase { name: String, base: char },
+    /// A quality character outside Phred+33 `!` to `~`
+    InvalidQuality { name: String, qual: char },
+}
+
+impl Display for Fq2BamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fq2BamError::EmptyName => write!(f, "read with an empty name"),
+            Fq2BamError::NameTooLong { name, len } => write!(
+                f,
+                "read name {name} is {len} bytes, BAM allows at most {MAX_NAME_LEN}"
+            ),
+            Fq2BamError::NulInName(name) => write!(f, "read name {name} contains a NUL byte"),
+            Fq2BamError::InvalidBase { name, base } => {
+                write!(f, "read {name} has base {base:?}, which BAM cannot store")
+            }
+            Fq2BamError::InvalidQuality { name, qual } => {
+                write!(f, "read {name} has quality {qual:?}, outside Phred+33")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Fq2BamError {}
+
+/// Header text for unaligned reads: `@HD` and, given `read_group`, its `@RG`
+pub fn header(read_group: Option<&str>) -> bam::Header {
+    let mut text = String::from("@HD\tVN:1.6\tSO:unknown\n");
+    if let Some(id) = read_group {
+        text.push_str(&format!("@RG\tID:{id}\n"));
+    }
+    bam::Header::new(text.into_bytes(), 0)
+}
+
+/// `rec` as an unaligned BAM record, with `tags` after any from its description
+///
+/// A tag in `tags` replaces one of the same name read from the description.
+pub fn to_bam(
+    rec: &fastq::Record,
+    desc: DescPolicy,
+    tags: &[bam::AuxField],
+) -> Result<bam::Record, Fq2BamError> {
+    let name = || rec.id_str_lossy().into_owned();
+    match rec.id().len() {
+        0 => return Err(Fq2BamError::EmptyName),
+        len if len > MAX_NAME_LEN => return Err(Fq2BamError::NameTooLong { name: name(), len }),
+        _ if rec.id().contains(&0) => return Err(Fq2BamError::NulInName(name())),
+        _ => {}
+    }
+    let seq = rec.seq().to_ascii_uppercase();
+    if let Some(base) = seq.chars().find(|b| !"ACMGRSVTWYHKDBN".contains(*b)) {
+        return Err(Fq2BamError::InvalidBase { name: name(), base });
+    }
+    if let Some(qual) = rec.qual().chars().find(|q| !matches!(q, '!'..='~')) {
+        return Err(Fq2BamError::InvalidQuality { name: name(), qual });
+    }
+    let qual = rec.qual().bytes().map(|q| q - 33).collect();
+
+    let mut builder = RecordBuilder::new(rec.id())
+        .flag(UNMAPPED)
+        .mapq(0)
+        .seq(seq.as_bytes())
+        .qual(qual);
+    let comment = match (desc, rec.desc_str_lossy()) {
+        (DescPolicy::Drop, _) | (_, None) => None,
+        (DescPolicy::Comment, Some(d)) => Some(d.into_owned()),
+        (DescPolicy::Tags, Some(d)) => {
+            let mut rest = Vec::new();
+            for word in d.split_ascii_whitespace() {
+                match bam::AuxField::from_sam_str(word) {
+                    Ok(field) => builder = builder.aux_field(field),
+                    Err(_) => rest.push(word),
+                }
+            }
+            (!rest.is_empty()).then(|| rest.join(" "))
+        }
+    };
+    if let Some(c) = comment {
+        builder = builder.aux("CO", c);
+    }
+    for tag in tags {
+        builder = builder.aux_field(tag.clone());
+    }
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam2fq::to_fastq;
+
+    fn read(id: &str, desc: Option<&str>, seq: &str, qual: &str) -> fastq::Record {
+        let mut rec = fastq::Record::from_parts(id, seq, qual).unwrap();
+        rec.set_desc(desc.map(|d| d.as_bytes().to_vec()));
+        rec
+    }
+
+    #[test]
+    fn test_to_bam() {
+        let rg = bam::AuxField::from_sam_str("RG:Z:S1").unwrap();
+        let rec = read("r1", Some("1:N:0:ATCACG"), "acgtN", "!+5?~");
+        let bam = to_bam(&rec, DescPolicy::Comment, &[rg]).unwrap();
+        assert_eq!(
+            bam.to_string(),
+            "r1\t4\t*\t0\t0\t*\t*\t0\t0\tACGTN\t!+5?~\tCO:Z:1:N:0:ATCACG\tRG:Z:S1"
+        );
+        assert_eq!(bam.qual().unwrap(), [0, 10, 20, 30, 93]);
+        assert!(bam.validate(None).is_empty());
+
+        let back = to_fastq(&bam, false).unwrap();
+        assert_eq!(
+            (back.id(), back.seq(), back.qual()),
+            (&b"r1"[..], "ACGTN", "!+5?~")
+        );
+        assert_eq!(back.desc(), None);
+
+        let bam = to_bam(&rec, DescPolicy::Drop, &[]).unwrap();
+        assert_eq!(bam.aux(), None);
+    }
+
+    #[test]
+    fn test_desc_tags() {
+        let rec = read("r", Some("BC:Z:ACGT RX:Z:TTGCAA 1:N:0 XI:i:x"), "AC", "II");
+        let rx = bam::AuxField::from_sam_str("RX:Z:GGG").unwrap();
+        let bam = to_bam(&rec, DescPolicy::Tags, &[rx]).unwrap();
+        assert_eq!(
+            bam.to_string(),
+            "r\t4\t*\t0\t0\t*\t*\t0\t0\tAC\tII\tBC:Z:ACGT\tRX:Z:GGG\tCO:Z:1:N:0 XI:i:x"
+        );
+        let rec = read("r", Some("BC:Z:ACGT"), "AC", "II");
+        let bam = to_bam(&rec, DescPolicy::Tags, &[]).unwrap();
+        assert_eq!(bam.aux().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rejected() {
+        let err = |id: &str, seq: &str, qual: &str| {
+            to_bam(&read(id, None, seq, qual), DescPolicy::Comment, &[])
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(err("", "A", "I"), "read with an empty name");
+        assert_eq!(
+            err(&"x".repeat(255), "A", "I"),
+            format!(
+                "read name {} is 255 bytes, BAM allows at most 254",
+                "x".repeat(255)
+            )
+        );
+        assert!(to_bam(
+            &read(&"x".repeat(254), None, "A", "I"),
+            DescPolicy::Drop,
+            &[]
+        )
+        .is_ok());
+        assert_eq!(err("a\0b", "A", "I"), "read name a\0b contains a NUL byte");
+        assert_eq!(
+            err("r", "A.", "II"),
+            "read r has base '.', which BAM cannot store"
+        );
+        assert_eq!(
+            err("r", "A=", "II"),
+            "read r has base '=', which BAM cannot store"
+        );
+        assert_eq!(
+            err("r", "AC", "I "),
+            "read r has quality ' ', outside Phred+33"
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        assert_eq!(header(None).text(), b"@HD\tVN:1.6\tSO:unknown\n");
+        let header = header(Some("S1"));
+        assert_eq!(header.read_group_ids().collect::<Vec<_>>(), [b"S1"]);
+        assert_eq!(header.n_ref(), 0);
+    }
+}
@@ -0,0 +1,153 @@
+//! Bodies of the fuzz targets in `fuzz/`
+//!
+//! Each function hands arbitrary bytes to one parser and must return without
+//! panicking or looping, whatever the input. Without libFuzzer, `cargo fuzz-smoke`
+//! runs them over mutations of the seed corpora in `fuzz/corpus`.
+
+use crate::{bam, fasta, fastq};
+
+/// Readers must stop after at most one item per input byte, plus the end
+fn assert_stops<T>(data: &[u8], items: impl Iterator<Item = T>) {
+    let n = items.take(data.len() + 2).count();
+    assert!(n <= data.len() + 1, "reader did not stop");
+}
+
+/// Every FASTQ record of `data`, rendered
+pub fn fastq_records(data: &[u8]) {
+    let records = fastq::Reader::new(data).map(|r| r.map(|r| r.to_string()));
+    assert_stops(data, records);
+}
+
+/// Every FASTA record of `data`, rendered
+pub fn fasta_records(data: &[u8]) {
+    let records = fasta::Reader::new(data).map(|r| r.map(|r| r.to_string()));
+    assert_stops(data, records);
+}
+
+/// `data` as a BAM alignment block against two references, rendered and checked
+pub fn bam_alignment(data: &[u8]) {
+    let references = [
+        bam::Reference::new("chr1", 1000),
+        bam::Reference::new("chr2", 1 << 20),
+    ];
+    if let Ok((_, rec)) = lyso_bam::parser::read_alignment(data, &references) {
+        let _ = rec.to_string();
+        let _ = rec.validate(Some(&references));
+    }
+}
+
+/// `data` as a FASTA and as a FASTQ `.fai`, and as a binary FASTQ index
+pub fn fai_lines(data: &[u8]) {
+    let _ = fasta::FastaIndex::new().read_index(&mut &data[..]);
+    let _ = fastq::FastqIndex::new().read_index(&mut &data[..]);
+    let _ = fastq::FastqIndex::new().read_binary(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+
+    const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../fuzz/corpus");
+
+    /// Bytes that mean something to one of the parsers
+    const INTERESTING: &[u8] = b"\0\n\r\t@>+ACGTN=*!~\x7f\x80\xff";
+
+    /// xorshift64, so failures reproduce
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n.max(1) as u64) as usize
+        }
+    }
+
+    fn seeds(target: &str) -> Vec<Vec<u8>> {
+        let mut seeds = fs::read_dir(Path::new(CORPUS).join(target))
+            .unwrap()
+            .map(|e| fs::read(e.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        seeds.sort();
+        assert!(!seeds.is_empty(), "no seeds for {target}");
+        seeds
+    }
+
+    /// A few random edits of `input`, splicing in `other`
+    fn mutate(rng: &mut Rng, input: &mut Vec<u8>, other: &[u8]) {
+        for _ in 0..=rng.below(4) {
+            let at = rng.below(input.len() + 1);
+            match rng.below(7) {
+                0 if at < input.len() => input[at] ^= 1 << rng.below(8),
+                1 if at < input.len() => input[at] = INTERESTING[rng.below(INTERESTING.len())],
+                2 => input.insert(at, rng.next() as u8),
+                3 => input.truncate(at),
+                4 => {
+                    let end = (at + rng.below(16)).min(input.len());
+                    input.drain(at..end);
+                }
+                5 => {
+                    let start = rng.below(other.len());
+                    let end = (start + rng.below(64)).min(other.len());
+                    input.splice(at..at, other[start..end].iter().copied());
+                }
+                _ if at + 4 <= input.len() => {
+                    // lengths and counts are little-endian u32s in BAM
+                    let v = [0, 1, u32::MAX, i32::MAX as u32, 0x8000][rng.below(5)];
+                    input[at..at + 4].copy_from_slice(&v.to_le_bytes());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn smoke(target: &str, body: fn(&[u8]), runs: usize) {
+        let seeds = seeds(target);
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for seed in &seeds {
+            body(seed);
+        }
+        for len in 0..=8 {
+            body(&vec![0; len]);
+        }
+        for _ in 0..runs {
+            let mut input = seeds[rng.below(seeds.len())].clone();
+            let other = &seeds[rng.below(seeds.len())];
+            mutate(&mut rng, &mut input, other);
+            body(&input);
+        }
+    }
+
+    type Target = (&'static str, fn(&[u8]));
+
+    const TARGETS: [Target; 4] = [
+        ("fastq_records", fastq_records),
+        ("fasta_records", fasta_records),
+        ("bam_alignment", bam_alignment),
+        ("fai_lines", fai_lines),
+    ];
+
+    #[test]
+    fn test_smoke() {
+        for (target, body) in TARGETS {
+            smoke(target, body, 2_000);
+        }
+    }
+
+    /// The longer run behind `cargo fuzz-smoke`
+    #[test]
+    #[ignore]
+    fn fuzz_smoke_long() {
+        for (target, body) in TARGETS {
+            smoke(target, body, 1_000_000);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! Probes behind `lyso inspect`: what a file holds and whether it looks intact
+//!
+//! Each probe returns a plain struct, so a report can be assembled and tested
+//! without parsing its text. Record probes take readers over decompressed input;
+//! detecting the format and compression is left to `common::detect`.
+
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use lyso_bam::guess_phred_encoding;
+pub use lyso_bam::PhredEncoding;
+use lyso_common::detect::{Compression, Format};
+use lyso_common::stream::Bounded;
+
+use crate::{bam, fasta, fastq};
+
+/// Records sampled from the start of an input
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordSample {
+    /// Records read, failed ones included
+    pub records: u64,
+    /// Whether the input ended within the sample
+    pub complete: bool,
+    /// Decompressed bytes of the sampled records
+    pub record_bytes: u64,
+    /// Sequence lengths, None without records
+    pub lengths: Option<LengthSummary>,
+    /// Quality encoding guessed from the sampled scores (FASTQ only)
+    pub quality: Option<PhredEncoding>,
+    /// The first error met, which ends the sample
+    pub error: Option<String>,
+}
+
+/// Everything `lyso inspect` reports about a file
+///
+/// With the `serde` feature every field is always serialized, as null when absent.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct InspectReport {
+    /// The file inspected
+    pub file: PathBuf,
+    /// Format of the decompressed contents
+    pub format: Format,
+    /// Compression of the file itself
+    pub compression: Compression,
+    /// Records in the file; None for SAM, whose records are not read
+    pub records: Option<RecordCount>,
+    /// Lengths of the sampled records
+    pub lengths: Option<LengthSummary>,
+    /// Quality encoding guessed from the sampled records (FASTQ only)
+    pub quality_encoding: Option<PhredEncoding>,
+    /// Sort order and references (BAM only)
+    pub header: Option<BamHeaderInfo>,
+    /// The index next to the file
+    pub index: Option<IndexProbe>,
+    /// The first error met while sampling
+    pub error: Option<String>,
+}
+
+/// Distribution of sequence lengths
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LengthSummary {
+    pub min: usize,
+    pub median: usize,
+    pub mean: f64,
+    pub max: usize,
+}
+
+impl LengthSummary {
+    /// Summary of `lengths`, None when empty; the median of an even count is the lower middle
+    pub fn new(mut lengths: Vec<usize>) -> Option<Self> {
+        lengths.sort_unstable();
+        Some(LengthSummary {
+            min: *lengths.first()?,
+            median: lengths[(lengths.len() - 1) / 2],
+            mean: lengths.iter().sum::<usize>() as f64 / lengths.len() as f64,
+            max: *lengths.last()?,
+        })
+    }
+}
+
+/// A record count, exact when the whole input was read
+///
+/// Serialized as `{"kind": "exact" | "estimated" | "at_least", "value": n}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "kind", content = "value", rename_all = "snake_case")
+)]
+pub enum RecordCount {
+    Exact(u64),
+    Estimated(u64),
+    /// Reading stopped at an error, so only a lower bound is known
+    AtLeast(u64),
+}
+
+/// Estimates are prefixed with '~', lower bounds with '>='
+impl Display for RecordCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordCount::Exact(n) => write!(f, "{n}"),
+            RecordCount::Estimated(n) => write!(f, "~{n}"),
+            RecordCount::AtLeast(n) => write!(f, ">={n}"),
+        }
+    }
+}
+
+impl RecordSample {
+    /// Records in the whole input, given the decompressed bytes all its records take
+    ///
+    /// Exact if the sample read everything; otherwise the sampled count scaled by
+    /// `data_bytes` over the sampled bytes. A sample ended by an error only gives
+    /// the records read.
+    pub fn count(&self, data_bytes: u64) -> RecordCount {
+        if self.error.is_some() {
+            return RecordCount::AtLeast(self.records);
+        }
+        if self.complete || self.record_bytes == 0 {
+            return RecordCount::Exact(self.records);
+        }
+        let scaled = self.records as f64 * data_bytes as f64 / self.record_bytes as f64;
+        RecordCount::Estimated((scaled.round() as u64).max(self.records))
+    }
+}
+
+/// Accumulates a `RecordSample` one record at a time
+#[derive(Default)]
+struct Sampler {
+    sample: RecordSample,
+    lengths: Vec<usize>,
+    /// Lowest and highest quality character seen
+    qual_range: Option<(u8, u8)>,
+}
+
+impl Sampler {
+    /// Sample up to `limit` records of `records`; `visit` sees each record read
+    /// and returns its length
+    fn take<T, E: Display>(
+        records: impl Iterator<Item = Result<T, E>>,
+        limit: usize,
+        mut visit: impl FnMut(&mut Self, &T) -> usize,
+    ) -> Self {
+        let mut sampler = Sampler::default();
+        let mut records = Bounded::new(records).max_records(limit);
+        for next in records.by_ref() {
+            sampler.sample.records += 1;
+            match next {
+                Ok(rec) => {
+                    let len = visit(&mut sampler, &rec);
+                    sampler.lengths.push(len);
+                }
+                Err(e) => {
+                    sampler.sample.error = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+        sampler.sample.complete = sampler.sample.error.is_none() && records.stopped_by().is_none();
+        sampler
+    }
+
+    fn add_quals(&mut self, qual: &[u8]) {
+        let (Some(lo), Some(hi)) = (qual.iter().min(), qual.iter().max()) else {
+            return;
+        };
+        let (min, max) = self.qual_range.unwrap_or((*lo, *hi));
+        self.qual_range = Some((min.min(*lo), max.max(*hi)));
+    }
+
+    fn finish(mut self, record_bytes: u64) -> RecordSample {
+        self.sample.record_bytes = record_bytes;
+        self.sample.lengths = LengthSummary::new(self.lengths);
+        self.sample.quality = self
+            .qual_range
+            .map(|(min, max)| guess_phred_encoding(&[min, max]));
+        self.sample
+    }
+}
+
+/// Read up to `limit` FASTQ records, guessing the quality encoding on the way
+pub fn sample_fastq<R: BufRead>(reader: &mut fastq::Reader<R>, limit: usize) -> RecordSample {
+    let sampler = Sampler::take(reader.by_ref(), limit, |sampler, rec| {
+        sampler.add_quals(rec.qual().as_bytes());
+        rec.seq().len()
+    });
+    sampler.finish(reader.record_bytes())
+}
+
+/// Read up to `limit` FASTA records
+pub fn sample_fasta<R: BufRead>(reader: &mut fasta::Reader<R>, limit: usize) -> RecordSample {
+    let sampler = Sampler::take(reader.by_ref(), limit, |_, rec| rec.seq().len());
+    sampler.finish(reader.record_bytes())
+}
+
+/// Read up to `limit` BAM records; the header is read on the way (see `bam_header`)
+pub fn sample_bam<R: BufRead>(reader: &mut bam::Reader<R>, limit: usize) -> RecordSample {
+    let sampler = Sampler::take(reader.by_ref(), limit, |_, rec| rec.l_seq() as usize);
+    sampler.finish(reader.record_bytes())
+}
+
+/// What the header of a BAM file says about its contents
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BamHeaderInfo {
+    /// The SO field of the @HD line, None when absent
+    pub sort_order: Option<String>,
+    /// Reference names and lengths
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_references"))]
+    pub references: Vec<(String, u32)>,
+    /// Decompressed bytes of the header and reference list
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub header_bytes: u64,
+}
+
+/// References as `[{"name": ..., "length": ...}]`
+#[cfg(feature = "serde")]
+fn serialize_references<S: serde::Serializer>(
+    references: &[(String, u32)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(serde::Serialize)]
+    struct Reference<'a> {
+        name: &'a str,
+        length: u32,
+    }
+    serializer.collect_seq(references.iter().map(|(name, length)| Reference {
+        name,
+        length: *length,
+    }))
+}
+
+/// The header of a reader that has read at least its header, None before that
+pub fn bam_header<R: BufRead>(reader: &bam::Reader<R>) -> Option<BamHeaderInfo> {
+    let header = reader.header()?;
+    let sort_order = header
+        .lines()
+        .find(|l| l.starts_with(b"@HD\t"))
+        .and_then(|hd| {
+            hd.split(|b| *b == b'\t')
+                .find_map(|field| field.strip_prefix(b"SO:"))
+                .map(|so| String::from_utf8_lossy(so).into_owned())
+        });
+    let references = reader
+        .references()
+        .iter()
+        .map(|r| (r.name().to_string(), r.l_ref()))
+        .collect::<Vec<_>>();
+    // magic, l_text, text, n_ref, then l_name, name and NUL, l_ref per reference
+    let header_bytes = 12
+        + header.text().len() as u64
+        + references
+            .iter()
+            .map(|(name, _)| 9 + name.len() as u64)
+            .sum::<u64>();
+    Some(BamHeaderInfo {
+        sort_order,
+        references,
+        header_bytes,
+    })
+}
+
+/// What was found at the path of an index
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexState {
+    Missing,
+    /// Readable; `stale` when it was last modified before the data file
+    Valid {
+        stale: bool,
+    },
+    Invalid(String),
+}
+
+/// "missing", "ok", "stale" or "invalid: <reason>"
+impl Display for IndexState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexState::Missing => write!(f, "missing"),
+            IndexState::Valid { stale: false } => write!(f, "ok"),
+            IndexState::Valid { stale: true } => write!(f, "stale"),
+            IndexState::Invalid(reason) => write!(f, "invalid: {reason}"),
+        }
+    }
+}
+
+/// Serialized as its `Display` text
+#[cfg(feature = "serde")]
+impl serde::Serialize for IndexState {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The sibling index of a data file
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IndexProbe {
+    pub path: PathBuf,
+    pub state: IndexState,
+}
+
+/// Look for the index `lyso` and samtools would use next to `data`
+///
+/// FASTA and FASTQ use `<data>.fai`, BAM `<data>.bai`; SAM has none. A `.fai` is
+/// checked by loading it and, for uncompressed data, by its records ending
+/// within the file. A `.bai` is checked by its magic and, when `n_ref` is given,
+/// its reference count.
+pub fn probe_index(data: &Path, format: Format, n_ref: Option<usize>) -> Option<IndexProbe> {
+    let ext = match format {
+        Format::Fasta | Format::Fastq => "fai",
+        Format::Bam => "bai",
+        Format::Sam => return None,
+    };
+    let mut path = data.as_os_str().to_os_string();
+    path.push(".");
+    path.push(ext);
+    let path = PathBuf::from(path);
+    let state = match fs::metadata(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => IndexState::Missing,
+        Err(e) => IndexState::Invalid(e.to_string()),
+        Ok(meta) => {
+            let checked = match format {
+                Format::Bam => check_bai(&path, n_ref),
+                _ => check_fai(&path, data, format),
+            };
+            match checked {
+                Err(reason) => IndexState::Invalid(reason),
+                Ok(()) => {
+                    let modified = |m: fs::Metadata| m.modified().ok();
+                    let stale = match (modified(meta), fs::metadata(data).ok().and_then(modified)) {
+                        (Some(index), Some(data)) => index < data,
+                        _ => false,
+                    };
+                    IndexState::Valid { stale }
+                }
+            }
+        }
+    };
+    Some(IndexProbe { path, state })
+}
+
+fn check_fai(path: &Path, data: &Path, format: Format) -> Result<(), String> {
+    let mut input = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let ends = if format == Format::Fasta {
+        let mut idx = fasta::FastaIndex::new();
+        idx.read_index(&mut input).map_err(|e| e.to_string())?;
+        idx.entries().map(|e| e.record_end()).collect::<Vec<u64>>()
+    } else {
+        let mut idx = fastq::FastqIndex::new();
+        idx.read_index(&mut input).map_err(|e| e.to_string())?;
+        idx.entries().map(|e| e.record_end()).collect::<Vec<u64>>()
+    };
+    if ends.is_empty() {
+        return Err("no entries".into());
+    }
+    let mut data = BufReader::new(File::open(data).map_err(|e| e.to_string())?);
+    let compressed = data
+        .fill_buf()
+        .map_err(|e| e.to_string())?
+        .starts_with(&[0x1f, 0x8b]);
+    let len = data.get_ref().metadata().map_err(|e| e.to_string())?.len();
+    match ends.iter().max() {
+        Some(end) if !compressed && *end > len => Err(format!(
+            "records end at byte {end}, past the end of the data ({len} bytes)"
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_bai(path: &Path, n_ref: Option<usize>) -> Result<(), String> {
+    let mut head = [0u8; 8];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut head))
+        .map_err(|_| "shorter than a BAI header".to_string())?;
+    if &head[..4] != b"BAI\x01" {
+        return Err("missing BAI magic".into());
+    }
+    let indexed = u32::from_le_bytes([head[4], head[5], head[6], head[7]]) as usize;
+    match n_ref {
+        Some(n) if n != indexed => Err(format!("indexes {indexed} references, the BAM has {n}")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const DATA: &str = "../resources/test_data";
+
+    #[test]
+    fn test_sample_fastq() {
+        let path = format!("{DATA}/small.fastq");
+        let mut reader = fastq::Reader::from_path(&path).unwrap();
+        let sample = sample_fastq(&mut reader, 100);
+        assert_eq!(sample.records, 6);
+        assert!(sample.complete);
+        assert_eq!(sample.quality, Some(PhredEncoding::Phred33));
+        assert_eq!(sample.error, None);
+        let size = fs::metadata(&path).unwrap().len();
+        assert_eq!(sample.record_bytes, size);
+        assert_eq!(sample.count(size), RecordCount::Exact(6));
+
+        // records of one length scale to within their id lengths
+        let fq = crate::common::synth::generate_fastq(100, 50, 0);
+        let sample = sample_fastq(&mut fastq::Reader::new(&fq[..]), 10);
+        assert!(!sample.complete);
+        let count = sample.count(fq.len() as u64);
+        assert_eq!(count, RecordCount::Estimated(101));
+        assert_eq!(count.to_string(), "~101");
+    }
+
+    #[test]
+    fn test_sample_errors() {
+        let mut reader = fasta::Reader::new(&b">a\nACGT\n>b\nAC\n>trunc"[..]);
+        let sample = sample_fasta(&mut reader, 10);
+        assert_eq!(sample.records, 3);
+        assert!(!sample.complete);
+        assert_eq!(
+            sample.error.as_deref(),
+            Some("line 5: Unexpected end of file")
+        );
+        assert_eq!(sample.count(1000).to_string(), ">=3");
+        let lengths = sample.lengths.unwrap();
+        assert_eq!((lengths.min, lengths.median, lengths.max), (2, 2, 4));
+        assert_eq!(lengths.mean, 3.0);
+    }
+
+    #[test]
+    fn test_bam_header() {
+        let mut reader = bam::Reader::from_path(format!("{DATA}/stats.bam")).unwrap();
+        assert_eq!(bam_header(&reader), None);
+        let sample = sample_bam(&mut reader, 3);
+        assert_eq!(sample.records, 3);
+        let info = bam_header(&reader).unwrap();
+        assert!(!info.references.is_empty());
+        assert_eq!(info.sort_order.as_deref(), Some("coordinate"));
+    }
+
+    #[test]
+    fn test_probe_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("a.fa");
+        fs::write(&data, ">a\nACGT\n").unwrap();
+        let probe = probe_index(&data, Format::Fasta, None).unwrap();
+        assert_eq!(probe.path, dir.path().join("a.fa.fai"));
+        assert_eq!(probe.state, IndexState::Missing);
+        assert_eq!(probe_index(&data, Format::Sam, None), None);
+
+        let fai = File::create(&probe.path).unwrap();
+        writeln!(&fai, "a\t4\t3\t4\t5").unwrap();
+        fai.set_modified(std::time::SystemTime::UNIX_EPOCH).unwrap();
+        let state = |format| probe_index(&data, format, Some(1)).unwrap().state;
+        assert_eq!(state(Format::Fasta), IndexState::Valid { stale: true });
+        File::options()
+            .write(true)
+            .open(&probe.path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(state(Format::Fasta), IndexState::Valid { stale: false });
+
+        fs::write(&data, ">a\n").unwrap();
+        assert_eq!(
+            state(Format::Fasta),
+            IndexState::Invalid("records end at byte 8, past the end of the data (3 bytes)".into())
+        );
+
+        let bai = dir.path().join("a.fa.bai");
+        fs::write(&bai, b"BAI\x01\x02\0\0\0").unwrap();
+        assert_eq!(
+            state(Format::Bam),
+            IndexState::Invalid("indexes 2 references, the BAM has 1".into())
+        );
+        fs::write(&bai, b"BAM\x01").unwrap();
+        assert_eq!(
+            state(Format::Bam),
+            IndexState::Invalid("shorter than a BAI header".into())
+        );
+    }
+}
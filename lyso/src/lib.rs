@@ -0,0 +1,130 @@
+//! lyso: parsers for common genomics formats
+//!
+//! This crate re-exports the `lyso-*` crates under one roof so their types don't
+//! collide: each format gets a module with its own `Reader`, `Record` and `Error`.
+//!
+//! ```
+//! use std::fs::File;
+//! use std::io::BufReader;
+//!
+//! use lyso::prelude::*;
+//!
+//! let f = File::open("../resources/test_data/test.fastq").unwrap();
+//! let reader = fastq::Reader::new(BufReader::new(f));
+//! let records = reader.collect::<Result<Vec<fastq::Record>, fastq::Error>>().unwrap();
+//! assert_eq!(records[0].id(), b"SRR22092847.1.1");
+//! assert_eq!(records[0].to_tsv(), "SRR22092847.1.1\t37\t0.4054\t36.1");
+//! ```
+
+/// Any error from the format crates or lyso-common; every crate error converts into it
+pub use lyso_common::error::LysoError as Error;
+
+/// Shared coordinates, regions, intervals and format detection
+pub mod common {
+    pub use lyso_common::*;
+}
+
+/// FASTA reading and indexed access
+///
+/// ```
+/// use std::fs::File;
+/// use std::io::BufReader;
+///
+/// use lyso::fasta;
+///
+/// let f = File::open("../resources/test_data/test.fa").unwrap();
+/// let mut reader = fasta::Reader::new(BufReader::new(f));
+/// let rec = reader.next().unwrap().unwrap();
+/// assert_eq!(rec.id(), b"SRR22092847.1.1");
+/// assert_eq!(reader.state(), fasta::ReaderState::Reading);
+/// ```
+pub mod fasta {
+    pub use lyso_fasta::indexer::{
+        DedupMode, DuplicatePolicy, FastaIndex, FastaIndexEntry, FastaIndexer, IndexBuildOptions,
+        IndexedFasta,
+    };
+    pub use lyso_fasta::reader::{
+        FastaReader as Reader, FastaReaderState as ReaderState, Fragment, FragmentReader,
+        RawRecords, RecordSlices,
+    };
+    pub use lyso_fasta::writer::FastaWriter as Writer;
+    pub use lyso_fasta::{
+        clean, concat, count, export, extract, mask, split, store, validator, windows,
+        FastaError as Error, FetchError, Record,
+    };
+}
+
+/// FASTQ reading, indexed access and quality statistics
+pub mod fastq {
+    pub use lyso_fastq::index::{
+        BinaryIndexError, DedupMode, DuplicatePolicy, FastqIndex, FastqIndexEntry, FastqIndexer,
+        IndexBuildOptions, IndexedFastq, BINARY_MAGIC,
+    };
+    pub use lyso_fastq::reader::{
+        FastqReader as Reader, FastqReaderState as ReaderState, RawRecords, RecordSlices,
+    };
+    pub use lyso_fastq::{barcode, count, demux, merge, parallel, requal, stats};
+    pub use lyso_fastq::{validator, FastqError as Error, FetchError, Record};
+}
+
+/// BAM reading and writing, flags, mate pairing, pileups and summary statistics
+///
+/// ```
+/// use std::fs::File;
+///
+/// use lyso::bam;
+///
+/// let f = File::open("../resources/test_data/name_sorted.bam").unwrap();
+/// let reader = bam::Reader::new(bam::BgzfReader::new(f).unwrap());
+/// let pairs = bam::pairs::PairIter::new(reader);
+/// let summary = bam::pairs::insert_size_summary(pairs).unwrap();
+/// assert_eq!(summary.count, 3);
+/// ```
+pub mod bam {
+    /// BAM is BGZF-compressed; wrap the file in this before handing it to `Reader`
+    pub use bgzip::read::BGZFReader as BgzfReader;
+    pub use lyso_bam::bgzf::{BgzfWriter, VirtualOffset, VirtualReader, VirtualSeek};
+    pub use lyso_bam::lazy::LazyRecord;
+    pub use lyso_bam::reader::{
+        BamReader as Reader, BamReaderState as ReaderState, DuplicateRefPolicy, LazyRecords,
+        ReferenceIter, RepairPolicy, RepairStats,
+    };
+    pub use lyso_bam::writer::BamWriter as Writer;
+    pub use lyso_bam::{
+        builder, check, consensus, count, coverage, filter, flags, mismatch, pairs, pileup, sort,
+        split, stats,
+    };
+    pub use lyso_bam::{
+        Alignment, AuxMap, BamAuxField as AuxField, BamAuxValue as AuxValue, BamError as Error,
+        BamHeader as Header, BamReference as Reference, BamSeq as Seq, Record,
+    };
+}
+
+/// BAM records as FASTQ, as written by `lyso bam2fq`
+pub mod bam2fq;
+
+/// FASTQ records as unaligned BAM, as written by `lyso fq2bam`
+pub mod fq2bam;
+
+/// Format, integrity and index probes, as reported by `lyso inspect`
+pub mod inspect;
+
+/// BAM references checked against a reference FASTA, as reported by `lyso validate-ref`
+pub mod validate;
+
+/// Entry points for the fuzz targets in `fuzz/`; not a stable API
+#[cfg(any(test, feature = "fuzz"))]
+#[doc(hidden)]
+pub mod fuzz;
+
+/// Record traits and the format modules
+pub mod prelude {
+    pub use crate::{bam, common, fasta, fastq};
+    pub use lyso_common::assert_records_eq;
+    pub use lyso_common::diff::RecordDiff;
+    pub use lyso_common::filter::{RecordPredicate, SeqRecord};
+    pub use lyso_common::format::RecordFormatter;
+    pub use lyso_common::rename::RecordId;
+    pub use lyso_common::stream::RecordStreamExt;
+    pub use lyso_common::validate::ValidationRule;
+}
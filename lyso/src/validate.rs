@@ -0,0 +1,324 @@
+//! BAM sequence dictionaries checked against a reference FASTA, as reported by
+//! `lyso validate-ref`
+//!
+//! `check_reference` compares names, lengths and order with the FASTA's index;
+//! `check_md5` compares the M5 fields of the BAM header with a `.dict` when one
+//! is available. Mismatches come back in BAM order, then FASTA order.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use lyso_common::dict::SequenceDictionary;
+
+use crate::{bam, fasta};
+
+/// One way the BAM references and the reference FASTA disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RefMismatch {
+    /// A BAM reference with no FASTA sequence of that name
+    MissingInFasta {
+        /// Reference name
+        name: String,
+        /// Length in the BAM header
+        length: u64,
+    },
+    /// A FASTA sequence the BAM header does not declare
+    MissingInBam {
+        /// Sequence name
+        name: String,
+        /// Length in the FASTA
+        length: u64,
+    },
+    /// A sequence of different lengths in the BAM header and the FASTA
+    LengthMismatch {
+        /// Sequence name
+        name: String,
+        /// Length in the BAM header
+        bam: u64,
+        /// Length in the FASTA
+        fasta: u64,
+    },
+    /// A shared sequence at a different rank among the shared sequences;
+    /// indexes are 0-based positions in each full list
+    OrderMismatch {
+        /// Sequence name
+        name: String,
+        /// Position among the BAM references
+        bam_index: usize,
+        /// Position among the FASTA sequences
+        fasta_index: usize,
+    },
+    /// A sequence whose M5 checksums in the BAM header and the .dict differ
+    Md5Mismatch {
+        /// Sequence name
+        name: String,
+        /// M5 of the BAM header's @SQ line
+        bam: String,
+        /// M5 of the .dict's @SQ line
+        dict: String,
+    },
+}
+
+/// Mismatches `validate-ref` may let through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValidateOptions {
+    /// Accept the same sequences in a different order
+    pub ignore_order: bool,
+    /// Accept FASTA sequences missing from the BAM header
+    pub subset_ok: bool,
+}
+
+impl RefMismatch {
+    /// Whether `opts` tolerates this mismatch
+    pub fn is_allowed(&self, opts: &ValidateOptions) -> bool {
+        match self {
+            RefMismatch::OrderMismatch { .. } => opts.ignore_order,
+            RefMismatch::MissingInBam { .. } => opts.subset_ok,
+            _ => false,
+        }
+    }
+}
+
+impl Display for RefMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefMismatch::MissingInFasta { name, length } => {
+                write!(
+                    f,
+                    "{name} ({length} bp) is in the BAM header but not the FASTA"
+                )
+            }
+            RefMismatch::MissingInBam { name, length } => {
+                write!(
+                    f,
+                    "{name} ({length} bp) is in the FASTA but not the BAM header"
+                )
+            }
+            RefMismatch::LengthMismatch { name, bam, fasta } => {
+                write!(
+                    f,
+                    "{name} is {bam} bp in the BAM header but {fasta} bp in the FASTA"
+                )
+            }
+            RefMismatch::OrderMismatch {
+                name,
+                bam_index,
+                fasta_index,
+            } => write!(
+                f,
+                "{name} is reference {} in the BAM header but sequence {} in the FASTA",
+                bam_index + 1,
+                fasta_index + 1
+            ),
+            RefMismatch::Md5Mismatch { name, bam, dict } => {
+                write!(
+                    f,
+                    "{name} has M5 {bam} in the BAM header but {dict} in the dict"
+                )
+            }
+        }
+    }
+}
+
+/// Compare BAM `references` with the sequences of `fasta_index`
+///
+/// Order is compared over the names both sides share, so a missing sequence
+/// does not also make every later one out of order.
+pub fn check_reference(
+    references: &[bam::Reference],
+    fasta_index: &fasta::FastaIndex,
+) -> Vec<RefMismatch> {
+    let fasta: Vec<(String, u64)> = fasta_index
+        .entries()
+        .map(|e| (e.name_str_lossy().into_owned(), *e.length()))
+        .collect();
+    let fasta_pos: HashMap<&str, usize> = fasta
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (name.as_str(), i))
+        .collect();
+    let bam_pos: HashMap<&str, usize> = references
+        .iter()
+        .enumerate()
+        .map(|(i, r)| (r.name(), i))
+        .collect();
+
+    let mut out = Vec::new();
+    for r in references {
+        let length = u64::from(r.l_ref());
+        match fasta_pos.get(r.name()) {
+            None => out.push(RefMismatch::MissingInFasta {
+                name: r.name().to_string(),
+                length,
+            }),
+            Some(&i) if fasta[i].1 != length => out.push(RefMismatch::LengthMismatch {
+                name: r.name().to_string(),
+                bam: length,
+                fasta: fasta[i].1,
+            }),
+            Some(_) => {}
+        }
+    }
+    for (name, length) in &fasta {
+        if !bam_pos.contains_key(name.as_str()) {
+            out.push(RefMismatch::MissingInBam {
+                name: name.clone(),
+                length: *length,
+            });
+        }
+    }
+
+    let shared_bam = references
+        .iter()
+        .enumerate()
+        .filter_map(|(i, r)| fasta_pos.get(r.name()).map(|&j| (r.name(), i, j)));
+    let shared_fasta = fasta
+        .iter()
+        .filter(|(name, _)| bam_pos.contains_key(name.as_str()));
+    for ((name, bam_index, fasta_index), (expected, _)) in shared_bam.zip(shared_fasta) {
+        if name != expected {
+            out.push(RefMismatch::OrderMismatch {
+                name: name.to_string(),
+                bam_index,
+                fasta_index,
+            });
+        }
+    }
+    out
+}
+
+/// Compare the M5 fields of a BAM header's @SQ lines with those of a `.dict`
+///
+/// Sequences without M5 on either side are not compared.
+pub fn check_md5(header: &SequenceDictionary, dict: &SequenceDictionary) -> Vec<RefMismatch> {
+    header
+        .entries()
+        .iter()
+        .filter_map(|e| {
+            let bam = e.md5.as_ref()?;
+            let dict = dict.get(&e.name)?.md5.as_ref()?;
+            (bam != dict).then(|| RefMismatch::Md5Mismatch {
+                name: e.name.clone(),
+                bam: bam.clone(),
+                dict: dict.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta::FastaIndex;
+
+    fn index(seqs: &[(&str, u64)]) -> FastaIndex {
+        let fai: String = seqs
+            .iter()
+            .map(|(name, len)| format!("{name}\t{len}\t0\t60\t61\n"))
+            .collect();
+        let mut idx = FastaIndex::new();
+        idx.read_index(&mut fai.as_bytes()).unwrap();
+        idx
+    }
+
+    fn refs(seqs: &[(&str, u32)]) -> Vec<bam::Reference> {
+        seqs.iter()
+            .map(|(name, len)| bam::Reference::new(*name, *len))
+            .collect()
+    }
+
+    #[test]
+    fn test_matching() {
+        let idx = index(&[("chr1", 100), ("chr2", 50)]);
+        assert!(check_reference(&refs(&[("chr1", 100), ("chr2", 50)]), &idx).is_empty());
+    }
+
+    #[test]
+    fn test_renamed_and_truncated() {
+        let idx = index(&[("chr1", 100), ("chr2", 50)]);
+        let got = check_reference(&refs(&[("1", 100), ("chr2", 40)]), &idx);
+        assert_eq!(
+            got,
+            [
+                RefMismatch::MissingInFasta {
+                    name: "1".into(),
+                    length: 100
+                },
+                RefMismatch::LengthMismatch {
+                    name: "chr2".into(),
+                    bam: 40,
+                    fasta: 50
+                },
+                RefMismatch::MissingInBam {
+                    name: "chr1".into(),
+                    length: 100
+                },
+            ]
+        );
+        let opts = ValidateOptions {
+            ignore_order: true,
+            subset_ok: true,
+        };
+        assert_eq!(got.iter().filter(|m| !m.is_allowed(&opts)).count(), 2);
+    }
+
+    #[test]
+    fn test_reordered_and_subset() {
+        let idx = index(&[("chr1", 100), ("chr2", 50), ("chrM", 16)]);
+        let got = check_reference(&refs(&[("chr2", 50), ("chr1", 100)]), &idx);
+        assert_eq!(got.len(), 3);
+        assert_eq!(
+            got[1],
+            RefMismatch::OrderMismatch {
+                name: "chr2".into(),
+                bam_index: 0,
+                fasta_index: 1
+            }
+        );
+        let allowed = |ignore_order, subset_ok| {
+            let opts = ValidateOptions {
+                ignore_order,
+                subset_ok,
+            };
+            got.iter().filter(|m| !m.is_allowed(&opts)).count()
+        };
+        assert_eq!(allowed(false, false), 3);
+        assert_eq!(allowed(true, false), 1);
+        assert_eq!(allowed(false, true), 2);
+        assert_eq!(allowed(true, true), 0);
+    }
+
+    #[test]
+    fn test_missing_does_not_shift_order() {
+        let idx = index(&[("chr1", 100), ("chr2", 50), ("chr3", 10)]);
+        let got = check_reference(&refs(&[("chr1", 100), ("chr3", 10)]), &idx);
+        assert_eq!(
+            got,
+            [RefMismatch::MissingInBam {
+                name: "chr2".into(),
+                length: 50
+            }]
+        );
+    }
+
+    #[test]
+    fn test_md5() {
+        let header = SequenceDictionary::from_header_text(
+            "@SQ\tSN:chr1\tLN:100\tM5:aaa\n@SQ\tSN:chr2\tLN:50\tM5:bbb\n@SQ\tSN:chr3\tLN:5\n",
+        )
+        .unwrap();
+        let dict = SequenceDictionary::from_header_text(
+            "@SQ\tSN:chr1\tLN:100\tM5:AAA\n@SQ\tSN:chr2\tLN:50\tM5:ccc\n@SQ\tSN:chr3\tLN:5\tM5:d\n",
+        )
+        .unwrap();
+        assert_eq!(
+            check_md5(&header, &dict),
+            [RefMismatch::Md5Mismatch {
+                name: "chr2".into(),
+                bam: "bbb".into(),
+                dict: "ccc".into()
+            }]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Runs the programs in examples/ against the test data and checks their exit codes
+
+#[path = "../../resources/run_example.rs"]
+mod run_example;
+
+use run_example::run;
+
+#[test]
+fn test_bam_to_filtered_fasta() {
+    // the reads `lyso bam2fq` writes for stats.bam: no secondary r9 or
+    // supplementary r10
+    let out = run(
+        "bam_to_filtered_fasta",
+        &["../resources/test_data/stats.bam", "0"],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    let seq = "ACGTACGTACGTACGTACGT";
+    let expected = ["r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8"]
+        .map(|name| format!(">{name}\n{seq}\n"))
+        .concat()
+        + ">r11\nACGTACGTAC\n>r12\nACGTACGTACGTAC\n";
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), expected);
+
+    // all of stats.bam is quality 40; in mismatches.bam only clip_del
+    // averages above 30, at 30.5
+    let out = run(
+        "bam_to_filtered_fasta",
+        &["../resources/test_data/stats.bam", "41"],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    assert!(out.stdout.is_empty());
+    let out = run(
+        "bam_to_filtered_fasta",
+        &["../resources/test_data/mismatches.bam", "30.5"],
+    );
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        ">clip_del\nNNNACGTAGGTCCTGGGGTAAG\n"
+    );
+
+    assert_eq!(run("bam_to_filtered_fasta", &[]).status.code(), Some(2));
+    let out = run(
+        "bam_to_filtered_fasta",
+        &["../resources/test_data/missing.bam", "0"],
+    );
+    assert_eq!(out.status.code(), Some(1));
+}
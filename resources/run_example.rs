@@ -0,0 +1,26 @@
+//! Runs a program from the including crate's examples/, shared by each crate's
+//! tests/examples.rs through `#[path]`
+//!
+//! `cargo test` builds the examples before running the tests; with a narrower
+//! target filter, build them first with `cargo build --examples`.
+
+use std::process::{Command, Output};
+
+/// Run `example` with `args` from the crate's directory
+pub fn run(example: &str, args: &[&str]) -> Output {
+    let mut bin = std::env::current_exe().unwrap();
+    bin.pop();
+    bin.pop();
+    bin.push("examples");
+    bin.push(format!("{example}{}", std::env::consts::EXE_SUFFIX));
+    assert!(
+        bin.exists(),
+        "{} not built; run cargo build --examples",
+        bin.display()
+    );
+    Command::new(&bin)
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}